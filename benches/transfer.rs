@@ -0,0 +1,183 @@
+// 100 MiB downloads and uploads through the gateway binary, on a route that
+// streams bodies and on one whose settings make it buffer them (content
+// sniffing for responses, retries for PUTs), the way every body used to be
+// handled. Reports throughput and the gateway's peak resident memory.
+//
+//     cargo bench --bench transfer
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use std::convert::Infallible;
+use std::fs;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const SIZE: usize = 100 * 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+const ROUNDS: u32 = 3;
+
+// Serves SIZE bytes on GET and swallows whatever is sent otherwise
+async fn upstream(req: Request<Body>, payload: Bytes) -> Result<Response<Body>, Infallible> {
+    if req.method() == hyper::Method::GET {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for offset in (0..SIZE).step_by(CHUNK) {
+                let chunk = payload.slice(offset..(offset + CHUNK).min(SIZE));
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+        return Ok(Response::builder()
+            .header("content-length", SIZE)
+            .header("content-type", "application/octet-stream")
+            .body(body)
+            .unwrap());
+    }
+    let mut body = req.into_body();
+    let mut received = 0;
+    while let Some(Ok(chunk)) = body.data().await {
+        received += chunk.len();
+    }
+    Ok(Response::new(Body::from(received.to_string())))
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn gateway(dir: &Path, upstream: u16) -> (Child, u16) {
+    let port = free_port();
+    let config = format!(
+        r#"
+[server]
+max_request_body_bytes = {limit}
+
+[admin]
+state_path = ""
+audit_path = ""
+history_path = ""
+
+[[routes]]
+name = "stream"
+prefix = "/stream"
+upstream = "http://127.0.0.1:{upstream}"
+
+[[routes]]
+name = "buffered"
+prefix = "/buffered"
+upstream = "http://127.0.0.1:{upstream}"
+retries = 1
+content_type = {{ sniff = true }}
+"#,
+        limit = SIZE * 2,
+    );
+    fs::write(dir.join("config.toml"), config).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_Rust-netty-server"))
+        .current_dir(dir)
+        .env("CONFIG_PATH", dir.join("config.toml"))
+        .env("PORT", port.to_string())
+        .env("ADMIN_PORT", free_port().to_string())
+        .env_remove("API_GATEWAY_URL")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    (child, port)
+}
+
+// Peak resident memory of the process, in MiB
+fn peak_rss(pid: u32) -> f64 {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<f64>().ok())
+        .map_or(0.0, |kb| kb / 1024.0)
+}
+
+// Start the peak over, where the kernel allows it
+fn reset_peak(pid: u32) {
+    let _ = fs::write(format!("/proc/{}/clear_refs", pid), "5");
+}
+
+fn report(name: &str, elapsed: Duration, pid: u32) {
+    let mib = (SIZE as f64 * ROUNDS as f64) / (1024.0 * 1024.0);
+    println!(
+        "{:<28} {:>9.1} MiB/s   peak RSS {:>7.1} MiB",
+        name,
+        mib / elapsed.as_secs_f64(),
+        peak_rss(pid)
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let payload = Bytes::from(vec![7u8; SIZE]);
+    let upstream_port = free_port();
+    let served = payload.clone();
+    let make = make_service_fn(move |_| {
+        let served = served.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| upstream(req, served.clone()))) }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], upstream_port).into()).serve(make);
+    tokio::spawn(server);
+
+    let client = reqwest::Client::new();
+    for route in ["stream", "buffered"] {
+        // A fresh gateway each time, so one route's peak doesn't hide the other's
+        let dir = std::env::temp_dir().join(format!("transfer-bench-{}", route));
+        fs::create_dir_all(&dir).unwrap();
+        let (mut child, port) = gateway(&dir, upstream_port);
+        let pid = child.id();
+        let base = format!("http://127.0.0.1:{}", port);
+        let ready = Instant::now();
+        while client.get(&base).send().await.is_err() {
+            assert!(
+                ready.elapsed() < Duration::from_secs(30),
+                "gateway didn't start"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        reset_peak(pid);
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let mut resp = client
+                .get(format!("{}/{}/download", base, route))
+                .send()
+                .await
+                .unwrap();
+            let mut received = 0;
+            while let Some(chunk) = resp.chunk().await.unwrap() {
+                received += chunk.len();
+            }
+            assert_eq!(received, SIZE);
+        }
+        report(&format!("download/{}", route), started.elapsed(), pid);
+
+        reset_peak(pid);
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let resp = client
+                .put(format!("{}/{}/upload", base, route))
+                .body(payload.clone())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.text().await.unwrap(), SIZE.to_string());
+        }
+        report(&format!("upload/{}", route), started.elapsed(), pid);
+
+        let _ = child.kill();
+        let _ = child.wait();
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
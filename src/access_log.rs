@@ -0,0 +1,319 @@
+use crate::client_ip;
+use crate::config::AccessLogConfig;
+use crate::logfile::LogFile;
+use crate::metrics;
+use crate::redact::Redactor;
+#[cfg(unix)]
+use crate::syslog::Journald;
+use crate::syslog::Syslog;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header::{self, HeaderName};
+use actix_web::{HttpRequest, HttpResponse};
+use rand::Rng;
+use std::io::{Error, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const VARIABLES: [&str; 18] = [
+    "remote_addr",
+    "time_iso8601",
+    "msec",
+    "method",
+    "uri",
+    "path",
+    "protocol",
+    "status",
+    "bytes",
+    "bytes_sent",
+    "latency_ms",
+    "upstream",
+    "route",
+    "request_id",
+    "user_agent",
+    "referer",
+    "graphql_operation",
+    "region",
+];
+
+enum Segment {
+    Literal(String),
+    Variable(&'static str),
+    // $http_x_api_key and friends
+    Header(HeaderName),
+    // $tag_team: one of the route's tags
+    Tag(String),
+}
+
+// Route and upstream a response was served from, attached to the response by
+// the handler so the access log can pick them up
+#[derive(Clone)]
+pub struct Served {
+    pub route: String,
+    pub upstream: String,
+    pub operation: Option<String>,
+    // [geo] region picked for routes with regional upstreams
+    pub region: Option<String>,
+}
+
+enum Output {
+    Stdout,
+    File(Mutex<LogFile>),
+    Syslog(Syslog),
+    #[cfg(unix)]
+    Journald(Journald),
+}
+
+// One line per request, in a format like nginx's log_format:
+// $remote_addr "$method $uri $protocol" $status $bytes $latency_ms ...
+// $bytes is the length known when the response starts; with $bytes_sent
+// (what actually went out) the line waits until the body is done.
+pub struct AccessLog {
+    format: Vec<Segment>,
+    output: Output,
+    redactor: Redactor,
+}
+
+fn parse(format: &str) -> Result<Vec<Segment>, Error> {
+    let mut segments = Vec::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('$') {
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_string()));
+        }
+        let after = &rest[start + 1..];
+        let (name, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => return Err(Error::other("Access log format has an unclosed ${")),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        let unknown = || Error::other(format!("Unknown access log variable ${}", name));
+        if let Some(tag) = name.strip_prefix("tag_").filter(|tag| !tag.is_empty()) {
+            segments.push(Segment::Tag(tag.to_string()));
+            rest = next;
+            continue;
+        }
+        match name.strip_prefix("http_") {
+            Some(header) => {
+                let header = HeaderName::from_bytes(header.replace('_', "-").as_bytes())
+                    .map_err(|_| unknown())?;
+                segments.push(Segment::Header(header));
+            }
+            None => {
+                let variable = VARIABLES.iter().find(|v| **v == name).ok_or_else(unknown)?;
+                segments.push(Segment::Variable(variable));
+            }
+        }
+        rest = next;
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_string()));
+    }
+    Ok(segments)
+}
+
+// A rendered line short of $bytes_sent
+pub struct Entry {
+    parts: Vec<String>,
+    holes: Vec<usize>,
+}
+
+// The client's X-Request-Id, or a new random one
+pub fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::thread_rng().gen::<u64>()))
+}
+
+// UTC timestamp such as 2024-05-01T12:34:56.789Z
+pub fn iso8601(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig, redactor: Redactor) -> Result<Self, Error> {
+        let format = parse(&config.format)?;
+        let file = !matches!(config.path.as_str(), "" | "-");
+        let output = match (&config.syslog, config.journald) {
+            (Some(_), true) => {
+                return Err(Error::other(
+                    "Access log can go to syslog or journald, not both",
+                ))
+            }
+            (Some(_), _) | (_, true) if file => {
+                return Err(Error::other(
+                    "Access log path can't be combined with syslog or journald",
+                ))
+            }
+            (Some(syslog), false) => Output::Syslog(Syslog::new(syslog)?),
+            #[cfg(unix)]
+            (None, true) => Output::Journald(Journald::new("netty-server-access")?),
+            #[cfg(not(unix))]
+            (None, true) => return Err(Error::other("journald is only available on unix")),
+            (None, false) if file => Output::File(Mutex::new(LogFile::open(
+                &config.path,
+                config.rotation.clone(),
+            )?)),
+            (None, false) => Output::Stdout,
+        };
+        Ok(AccessLog {
+            format,
+            output,
+            redactor,
+        })
+    }
+
+    // Whether lines need the bytes sent, so have to wait for the body
+    pub fn waits_for_body(&self) -> bool {
+        self.format
+            .iter()
+            .any(|segment| matches!(segment, Segment::Variable("bytes_sent")))
+    }
+
+    // The line for a response whose body hasn't gone out yet. Everything
+    // but $bytes_sent is filled in.
+    pub fn entry(
+        &self,
+        req: &HttpRequest,
+        response: &HttpResponse,
+        request_id: &str,
+        elapsed: Duration,
+    ) -> Entry {
+        let served = response.extensions().get::<Served>().cloned();
+        let header = |name: &HeaderName| match req.headers().get(name) {
+            Some(value) => self.redactor.value(name, value),
+            None => "-".to_string(),
+        };
+
+        let mut entry = Entry {
+            parts: Vec::with_capacity(self.format.len()),
+            holes: Vec::new(),
+        };
+        for segment in &self.format {
+            let value = match segment {
+                Segment::Literal(text) => text.clone(),
+                Segment::Variable("bytes_sent") => {
+                    entry.holes.push(entry.parts.len());
+                    String::new()
+                }
+                Segment::Variable("remote_addr") => {
+                    client_ip::resolved(req).unwrap_or_else(|| "-".to_string())
+                }
+                Segment::Variable("time_iso8601") => iso8601(SystemTime::now()),
+                Segment::Variable("msec") => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+                }
+                Segment::Variable("method") => req.method().to_string(),
+                Segment::Variable("uri") => req.uri().to_string(),
+                Segment::Variable("path") => req.path().to_string(),
+                Segment::Variable("protocol") => format!("{:?}", req.version()),
+                Segment::Variable("status") => response.status().as_u16().to_string(),
+                Segment::Variable("bytes") => match response.body().size() {
+                    BodySize::Sized(n) => n.to_string(),
+                    // Streamed, so only the declared length is known
+                    _ => response
+                        .headers()
+                        .get(header::CONTENT_LENGTH)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("-")
+                        .to_string(),
+                },
+                Segment::Variable("latency_ms") => {
+                    format!("{:.3}", elapsed.as_secs_f64() * 1000.0)
+                }
+                Segment::Variable("upstream") => served
+                    .as_ref()
+                    .map_or("-".to_string(), |s| s.upstream.clone()),
+                Segment::Variable("route") => {
+                    served.as_ref().map_or("-".to_string(), |s| s.route.clone())
+                }
+                Segment::Variable("graphql_operation") => served
+                    .as_ref()
+                    .and_then(|s| s.operation.clone())
+                    .unwrap_or_else(|| "-".to_string()),
+                Segment::Variable("region") => served
+                    .as_ref()
+                    .and_then(|s| s.region.clone())
+                    .unwrap_or_else(|| "-".to_string()),
+                Segment::Variable("request_id") => request_id.to_string(),
+                Segment::Variable("user_agent") => header(&header::USER_AGENT),
+                Segment::Variable("referer") => header(&header::REFERER),
+                Segment::Header(name) => header(name),
+                Segment::Tag(tag) => (served.as_ref())
+                    .and_then(|s| metrics::route_tag(&s.route, tag))
+                    .unwrap_or_else(|| "-".to_string()),
+                Segment::Variable(_) => "-".to_string(),
+            };
+            entry.parts.push(value);
+        }
+        entry
+    }
+
+    pub fn write(&self, entry: Entry, bytes_sent: u64) {
+        let Entry { mut parts, holes } = entry;
+        for hole in holes {
+            parts[hole] = bytes_sent.to_string();
+        }
+        let mut line = parts.concat();
+        let written = match &self.output {
+            Output::Stdout => writeln!(std::io::stdout().lock(), "{}", line),
+            Output::File(file) => {
+                line.push('\n');
+                file.lock().unwrap().write_line(line.as_bytes())
+            }
+            Output::Syslog(syslog) => syslog.send(&line, &iso8601(SystemTime::now())),
+            #[cfg(unix)]
+            Output::Journald(journald) => journald.send(&line),
+        };
+        if let Err(e) = written {
+            eprintln!("Failed to write access log: {}", e);
+        }
+    }
+
+    // Called on SIGUSR1 so logrotate can move the file away
+    pub fn reopen(&self) {
+        if let Output::File(file) = &self.output {
+            if let Err(e) = file.lock().unwrap().reopen() {
+                eprintln!("Failed to reopen access log: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,1238 @@
+use crate::admin_auth::AdminAuth;
+use crate::audit::AuditQuery;
+use crate::cluster::{self, Update};
+use crate::config::{AdminOperation, ApiKeyRecord, RateLimitConfig, RouteConfig, UpstreamConfig};
+use crate::diagnostics;
+use crate::events::{self, Event};
+use crate::history;
+use crate::keys::{self, KeyStore};
+use crate::memory;
+use crate::metrics;
+use crate::proxy::AppState;
+use crate::rollback;
+use crate::routes::Change;
+use crate::store::Store;
+use actix_web::http::header;
+use actix_web::{rt, web, HttpRequest, HttpResponse, Responder};
+use actix_ws::Message;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const DASHBOARD: &str = include_str!("../assets/dashboard.html");
+
+// Whether the admin listeners only take connections from this machine
+static LOOPBACK_ONLY: OnceLock<bool> = OnceLock::new();
+
+// Note where the admin API listens, once it's bound
+pub fn listening_on(addresses: &[SocketAddr], auth: &AdminAuth) {
+    let loopback = addresses.iter().all(|address| address.ip().is_loopback());
+    let _ = LOOPBACK_ONLY.set(loopback);
+    if auth.is_open() && !loopback {
+        eprintln!(
+            "Admin API has no tokens or LDAP configured and listens beyond loopback; it will only answer read-only calls"
+        );
+    }
+}
+
+// Register the admin endpoints
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(metrics_handler))
+        .route("/dashboard", web::get().to(dashboard_page))
+        .route("/dashboard/stats", web::get().to(dashboard_stats))
+        .route("/events", web::get().to(events_handler))
+        .route("/inflight", web::get().to(inflight_handler))
+        .route("/streams", web::get().to(streams_handler))
+        .route("/ready", web::get().to(ready_handler))
+        .route("/drain", web::get().to(drain_status))
+        .route("/drain", web::post().to(start_drain))
+        .route("/routes", web::get().to(list_routes))
+        .route("/routes", web::post().to(create_route))
+        .route("/routes/{name}", web::get().to(get_route))
+        .route("/routes/{name}", web::put().to(update_route))
+        .route("/routes/{name}", web::delete().to(delete_route))
+        .route("/routes/{name}/mock", web::put().to(toggle_mock))
+        .route("/routes/{name}/live", web::put().to(flip_live))
+        .route("/audit", web::get().to(audit_handler))
+        .route("/config/effective", web::get().to(effective_config))
+        .route("/config/versions", web::get().to(list_versions))
+        .route("/config/versions/{version}", web::get().to(get_version))
+        .route(
+            "/config/versions/{version}/diff",
+            web::get().to(diff_version),
+        )
+        .route(
+            "/config/versions/{version}/rollback",
+            web::post().to(rollback_version),
+        )
+        .route("/upstreams", web::get().to(list_upstreams))
+        .route("/upstreams/{name}", web::put().to(put_upstream))
+        .route("/upstreams/{name}", web::delete().to(delete_upstream))
+        .route("/api-keys/{key}", web::put().to(put_api_key))
+        .route("/api-keys/{key}", web::delete().to(delete_api_key))
+        .route(
+            "/authenticators/{name}/keys",
+            web::get().to(list_partner_keys),
+        )
+        .route(
+            "/authenticators/{name}/keys",
+            web::post().to(create_partner_key),
+        )
+        .route(
+            "/authenticators/{name}/keys/{id}",
+            web::delete().to(revoke_partner_key),
+        )
+        .route("/cache/purge", web::post().to(purge_cache))
+        .route("/kv/{key}", web::get().to(get_kv))
+        .route("/kv/{key}", web::put().to(put_kv))
+        .route("/kv/{key}", web::delete().to(delete_kv));
+}
+
+// Compare without bailing out at the first differing byte
+pub fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+// Check the caller's credentials with the admin auth providers, then their
+// role. Returns who the caller is for the logs.
+async fn authorize(
+    state: &AppState,
+    req: &HttpRequest,
+    operation: AdminOperation,
+) -> Result<String, Box<HttpResponse>> {
+    let auth = &state.admin_auth;
+    if auth.is_open() {
+        // Anyone who can reach the port could change what we serve
+        if operation != AdminOperation::View && !LOOPBACK_ONLY.get().is_some_and(|only| *only) {
+            return Err(Box::new(HttpResponse::Forbidden().body(
+                "The admin API has no credentials configured; changes are only taken on a loopback-only admin listener",
+            )));
+        }
+        return Ok("anonymous".to_string());
+    }
+
+    let principal = match auth.authenticate(req).await {
+        Ok(Some(principal)) => principal,
+        Ok(None) if auth.asks_for_password() => {
+            return Err(Box::new(
+                HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"gateway admin\""))
+                    .body("Missing or unknown admin credentials"),
+            ))
+        }
+        Ok(None) => {
+            return Err(Box::new(
+                HttpResponse::Unauthorized().body("Missing or unknown admin token"),
+            ))
+        }
+        Err(e) => {
+            eprintln!("Admin: can't check credentials: {}", e);
+            return Err(Box::new(
+                HttpResponse::ServiceUnavailable().body("Admin sign-in is unavailable"),
+            ));
+        }
+    };
+    let allowed = state
+        .config
+        .admin
+        .roles
+        .get(&principal.role)
+        .is_some_and(|operations| operations.contains(&operation));
+    if allowed {
+        Ok(principal.name)
+    } else {
+        println!(
+            "Admin: denied {:?} on {} {} for {} ({})",
+            operation,
+            req.method(),
+            req.path(),
+            principal.name,
+            principal.role
+        );
+        Err(Box::new(
+            HttpResponse::Forbidden().body("Not allowed for this role"),
+        ))
+    }
+}
+
+async fn metrics_handler(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    // Counted at scrape time so the gauge reflects expired sessions too
+    if let Some(streams) = &state.streams {
+        if let Ok(active) = streams.active().await {
+            metrics::set("playback_sessions_active", &[], active as f64);
+        }
+    }
+    memory::report_process();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+// The page holds no data itself, so it's served to anyone; its stats need
+// a token when the admin API does
+async fn dashboard_page() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DASHBOARD)
+}
+
+async fn dashboard_stats(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(state.live.stats(&state.upstreams))
+}
+
+// Dump requests currently in progress and how many are waiting on each upstream
+async fn inflight_handler(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    HttpResponse::Ok().json(state.inflight.snapshot())
+}
+
+// Open playback sessions, from the same store the stream limiter uses
+async fn streams_handler(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    let streams = match &state.streams {
+        Some(streams) => streams,
+        None => return HttpResponse::NotFound().body("No [streams] section configured"),
+    };
+    match streams.active().await {
+        Ok(active) => HttpResponse::Ok().json(json!({ "active_sessions": active })),
+        Err(e) => {
+            HttpResponse::ServiceUnavailable().body(format!("Session store unavailable: {}", e))
+        }
+    }
+}
+
+// Readiness probe for the orchestrator; fails once draining has started.
+// Probes don't carry tokens, so this one is always open.
+async fn ready_handler(state: web::Data<AppState>) -> impl Responder {
+    if state.draining.load(Ordering::Relaxed) {
+        HttpResponse::ServiceUnavailable().body("draining")
+    } else if state.warming.load(Ordering::Relaxed) {
+        HttpResponse::ServiceUnavailable().body("warming up")
+    } else {
+        HttpResponse::Ok().body("ready")
+    }
+}
+
+fn drain_report(state: &AppState) -> HttpResponse {
+    let in_flight = state.inflight.count();
+    HttpResponse::Ok().json(json!({
+        "draining": state.draining.load(Ordering::Relaxed),
+        "in_flight": in_flight,
+        "drained": state.draining.load(Ordering::Relaxed) && in_flight == 0,
+    }))
+}
+
+async fn drain_status(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    drain_report(&state)
+}
+
+// Take the replica out of rotation and log once the last request finishes
+async fn start_drain(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::Drain).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    if !state.draining.swap(true, Ordering::Relaxed) {
+        println!(
+            "Admin: {} started a drain with {} requests in flight",
+            who,
+            state.inflight.count()
+        );
+        state.audit.record(
+            &who,
+            "drain",
+            "replica",
+            Some(json!({ "draining": false })),
+            Some(json!({ "draining": true })),
+        );
+
+        let state = state.clone();
+        rt::spawn(async move {
+            while state.inflight.count() > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            println!("Admin: drain complete, no requests in flight");
+        });
+    }
+
+    drain_report(&state)
+}
+
+// Stream live events as JSON text frames until the client goes away
+async fn events_handler(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return Ok(*denied);
+    }
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut events = events::subscribe();
+
+    rt::spawn(async move {
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let json = serde_json::to_string(&event).unwrap_or_default();
+                        if session.text(json).await.is_err() {
+                            return;
+                        }
+                    }
+                    // Slow clients just miss some events
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                message = messages.recv() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        let _ = session.pong(&bytes).await;
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+fn edit_failed(e: Error) -> HttpResponse {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => HttpResponse::NotFound().body(e.to_string()),
+        std::io::ErrorKind::AlreadyExists => HttpResponse::Conflict().body(e.to_string()),
+        _ => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+fn not_found(name: &str) -> Error {
+    Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No route named {}", name),
+    )
+}
+
+fn to_json(route: &RouteConfig) -> Option<serde_json::Value> {
+    serde_json::to_value(diagnostics::redact_route(route)).ok()
+}
+
+async fn list_routes(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    HttpResponse::Ok().json(diagnostics::redact_routes(&state.routes.snapshot()))
+}
+
+async fn get_route(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    match state.routes.snapshot().iter().find(|r| r.name == *name) {
+        Some(route) => HttpResponse::Ok().json(diagnostics::redact_route(route)),
+        None => HttpResponse::NotFound().body(format!("No route named {}", name)),
+    }
+}
+
+async fn create_route(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    route: web::Json<RouteConfig>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditRoutes).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let route = route.into_inner();
+    let name = route.name.clone();
+
+    let result = state.routes.update(&state.config, |routes| {
+        if routes.iter().any(|r| r.name == name) {
+            return Err(Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Route {} already exists", name),
+            ));
+        }
+        routes.push(route.clone());
+        Ok(())
+    });
+
+    match result {
+        Ok(change) => {
+            println!("Admin: {} created route {}", who, name);
+            state
+                .audit
+                .record(&who, "route_create", &name, None, to_json(&route));
+            events::publish(Event::RouteChange {
+                action: "created",
+                route: name.clone(),
+            });
+            cluster::routes_changed(&state);
+            if let Some(rollback) = state.config.admin.rollback.clone() {
+                rollback::watch(state, name, rollback, change);
+            }
+            HttpResponse::Created().finish()
+        }
+        Err(e) => edit_failed(e),
+    }
+}
+
+async fn update_route(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    route: web::Json<RouteConfig>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditRoutes).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let mut route = route.into_inner();
+    route.name = name.clone();
+
+    let mut before = None;
+    let result = state.routes.update(&state.config, |routes| {
+        let existing = routes
+            .iter_mut()
+            .find(|r| r.name == *name)
+            .ok_or_else(|| not_found(&name))?;
+        before = to_json(existing);
+        *existing = route.clone();
+        Ok(())
+    });
+
+    match result {
+        Ok(change) => {
+            println!("Admin: {} updated route {}", who, name);
+            state
+                .audit
+                .record(&who, "route_update", &name, before, to_json(&route));
+            events::publish(Event::RouteChange {
+                action: "updated",
+                route: name.clone(),
+            });
+            cluster::routes_changed(&state);
+            if let Some(rollback) = state.config.admin.rollback.clone() {
+                rollback::watch(state, name.into_inner(), rollback, change);
+            }
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => edit_failed(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct MockToggle {
+    enabled: bool,
+}
+
+// Switch a route between its mock response and its upstream
+async fn toggle_mock(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    toggle: web::Json<MockToggle>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::Chaos).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let mut before = None;
+    let mut after = None;
+    let result = state.routes.update(&state.config, |routes| {
+        let existing = routes
+            .iter_mut()
+            .find(|r| r.name == *name)
+            .ok_or_else(|| not_found(&name))?;
+        before = to_json(existing);
+        existing
+            .mock
+            .as_mut()
+            .ok_or_else(|| {
+                Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Route {} has no mock", name),
+                )
+            })?
+            .enabled = toggle.enabled;
+        after = to_json(existing);
+        Ok(())
+    });
+
+    match result {
+        Ok(_) => {
+            let state_name = if toggle.enabled { "on" } else { "off" };
+            println!(
+                "Admin: {} turned the mock for route {} {}",
+                who, name, state_name
+            );
+            state.audit.record(&who, "route_mock", &name, before, after);
+            events::publish(Event::RouteChange {
+                action: "updated",
+                route: name.into_inner(),
+            });
+            cluster::routes_changed(&state);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => edit_failed(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct Flip {
+    group: String,
+}
+
+// Point a blue-green route at one of its groups. Returns the group that was
+// live, unless it was this one already.
+fn set_live(state: &AppState, name: &str, group: &str) -> Result<Option<(String, Change)>, Error> {
+    let mut previous = String::new();
+    let change = state.routes.update(&state.config, |routes| {
+        let blue_green = routes
+            .iter_mut()
+            .find(|r| r.name == name)
+            .ok_or_else(|| not_found(name))?
+            .blue_green
+            .as_mut()
+            .ok_or_else(|| {
+                Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Route {} has no blue_green groups", name),
+                )
+            })?;
+        if !blue_green.groups.contains_key(group) {
+            return Err(Error::other(format!(
+                "Route {} has no group named {}",
+                name, group
+            )));
+        }
+        previous = std::mem::replace(&mut blue_green.live, group.to_string());
+        Ok(())
+    })?;
+    Ok((previous != group).then_some((previous, change)))
+}
+
+// Flip which group is live. With rollback configured, the new group is
+// watched for the window and the flip undone if it fails too often.
+async fn flip_live(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    flip: web::Json<Flip>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditRoutes).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let name = name.into_inner();
+    let (previous, change) = match set_live(&state, &name, &flip.group) {
+        Ok(Some(flipped)) => flipped,
+        Ok(None) => return HttpResponse::NoContent().finish(),
+        Err(e) => return edit_failed(e),
+    };
+    println!(
+        "Admin: {} flipped route {} from {} to {}",
+        who, name, previous, flip.group
+    );
+    state.audit.record(
+        &who,
+        "route_flip",
+        &name,
+        Some(json!({ "live": previous })),
+        Some(json!({ "live": flip.group })),
+    );
+    events::publish(Event::RouteChange {
+        action: "updated",
+        route: name.clone(),
+    });
+    cluster::routes_changed(&state);
+
+    // The route's own rollback settings, or the general ones
+    let rollback = change
+        .1
+        .iter()
+        .find(|r| r.name == name)
+        .and_then(|r| r.blue_green.as_ref()?.rollback.clone())
+        .or_else(|| state.config.admin.rollback.clone());
+    if let Some(rollback) = rollback {
+        rollback::watch(state, name, rollback, change);
+    }
+    HttpResponse::NoContent().finish()
+}
+
+async fn delete_route(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditRoutes).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let mut before = None;
+    let result = state.routes.update(&state.config, |routes| {
+        let index = routes
+            .iter()
+            .position(|r| r.name == *name)
+            .ok_or_else(|| not_found(&name))?;
+        before = to_json(&routes.remove(index));
+        Ok(())
+    });
+
+    match result {
+        Ok(_) => {
+            println!("Admin: {} deleted route {}", who, name);
+            state
+                .audit
+                .record(&who, "route_delete", &name, before, None);
+            events::publish(Event::RouteChange {
+                action: "deleted",
+                route: name.into_inner(),
+            });
+            cluster::routes_changed(&state);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => edit_failed(e),
+    }
+}
+
+fn no_history() -> HttpResponse {
+    HttpResponse::NotFound().body("No config history is kept (admin history_path is empty)")
+}
+
+fn version_failed(e: Error) -> HttpResponse {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => HttpResponse::NotFound().body(e.to_string()),
+        _ => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn list_versions(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    let history = match state.routes.history() {
+        Some(history) => history,
+        None => return no_history(),
+    };
+    match history.list() {
+        Ok(versions) => HttpResponse::Ok().json(json!({
+            "current": history.latest(),
+            "versions": versions,
+        })),
+        Err(e) => version_failed(e),
+    }
+}
+
+// The config being served with, secrets redacted, and its startup summary.
+// Routes are the live ones, changes made here included.
+async fn effective_config(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    let routes = state.routes.snapshot();
+    HttpResponse::Ok().json(json!({
+        "config": diagnostics::effective_config(&state.config, &routes),
+        "diagnostics": diagnostics::diagnostics(&state.config, &routes),
+    }))
+}
+
+async fn get_version(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    version: web::Path<u64>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    match state.routes.history().map(|h| h.get(*version)) {
+        Some(Ok(saved)) => HttpResponse::Ok().json(saved),
+        Some(Err(e)) => version_failed(e),
+        None => no_history(),
+    }
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    // Version to compare with; the live routes when left out
+    against: Option<u64>,
+}
+
+// What changed going from the version to the other one (or the live routes)
+async fn diff_version(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    version: web::Path<u64>,
+    query: web::Query<DiffQuery>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    let history = match state.routes.history() {
+        Some(history) => history,
+        None => return no_history(),
+    };
+    let from = match history.get(*version) {
+        Ok(saved) => saved.routes,
+        Err(e) => return version_failed(e),
+    };
+    let to = match query.against {
+        Some(against) => match history.get(against) {
+            Ok(saved) => saved.routes,
+            Err(e) => return version_failed(e),
+        },
+        None => state.routes.snapshot().as_ref().clone(),
+    };
+    HttpResponse::Ok().json(history::diff(&from, &to))
+}
+
+// Make a saved version the live route table again. That's a change like
+// any other, so it becomes the newest version itself.
+async fn rollback_version(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    version: web::Path<u64>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditRoutes).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let saved = match state.routes.history().map(|h| h.get(*version)) {
+        Some(Ok(saved)) => saved,
+        Some(Err(e)) => return version_failed(e),
+        None => return no_history(),
+    };
+    let result = state.routes.update(&state.config, |routes| {
+        *routes = saved.routes;
+        Ok(())
+    });
+    match result {
+        Ok((before, after)) => {
+            println!(
+                "Admin: {} rolled the routes back to version {}",
+                who, version
+            );
+            let changes = history::diff(&before, &after);
+            state.audit.record(
+                &who,
+                "config_rollback",
+                &format!("version {}", version),
+                None,
+                Some(changes.clone()),
+            );
+            events::publish(Event::RouteChange {
+                action: "rolled_back",
+                route: "*".to_string(),
+            });
+            cluster::routes_changed(&state);
+            HttpResponse::Ok().json(changes)
+        }
+        Err(e) => edit_failed(e),
+    }
+}
+
+// Recent admin changes, filtered by ?who=&action=&target=&since=&limit=
+async fn audit_handler(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    HttpResponse::Ok().json(state.audit.query(&query))
+}
+
+fn no_store() -> HttpResponse {
+    HttpResponse::NotFound().body("No config store configured (admin.database_path)")
+}
+
+fn store_failed(e: Error) -> HttpResponse {
+    eprintln!("Admin: {}", e);
+    HttpResponse::InternalServerError().body(e.to_string())
+}
+
+// Upstream pools saved in the config store
+async fn list_upstreams(state: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    let store: &Store = match state.store.as_deref() {
+        Some(store) => store,
+        None => return no_store(),
+    };
+    match store.upstreams() {
+        Ok(upstreams) => HttpResponse::Ok().json(upstreams),
+        Err(e) => store_failed(e),
+    }
+}
+
+// Pools are built at startup, so stored changes apply on the next restart
+async fn put_upstream(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    upstream: web::Json<UpstreamConfig>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let store: &Store = match state.store.as_deref() {
+        Some(store) => store,
+        None => return no_store(),
+    };
+
+    let upstream = upstream.into_inner();
+    let groups = std::iter::once(&upstream.targets).chain(&upstream.priority_groups);
+    for group in groups {
+        if group.is_empty()
+            || !group
+                .iter()
+                .all(|t| t.starts_with("http://") || t.starts_with("https://"))
+        {
+            return HttpResponse::BadRequest().body("Every target group needs http(s) URLs");
+        }
+    }
+
+    let before = store
+        .upstreams()
+        .ok()
+        .and_then(|mut u| u.remove(name.as_str()));
+    match store.put_upstream(&name, &upstream) {
+        Ok(()) => {
+            println!("Admin: {} saved upstream {}", who, name);
+            state.audit.record(
+                &who,
+                "upstream_put",
+                &name,
+                before.and_then(|b| serde_json::to_value(b).ok()),
+                serde_json::to_value(&upstream).ok(),
+            );
+            HttpResponse::Accepted().body("Saved; applied on the next restart")
+        }
+        Err(e) => store_failed(e),
+    }
+}
+
+async fn delete_upstream(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let store: &Store = match state.store.as_deref() {
+        Some(store) => store,
+        None => return no_store(),
+    };
+
+    // A route left pointing at a missing pool would fail the next start
+    if let Some(route) = state.routes.snapshot().iter().find(|r| r.upstream == *name) {
+        return HttpResponse::Conflict().body(format!("Route {} still uses {}", route.name, name));
+    }
+
+    let before = store
+        .upstreams()
+        .ok()
+        .and_then(|mut u| u.remove(name.as_str()));
+    match store.delete_upstream(&name) {
+        Ok(true) => {
+            println!("Admin: {} deleted upstream {}", who, name);
+            state.audit.record(
+                &who,
+                "upstream_delete",
+                &name,
+                before.and_then(|b| serde_json::to_value(b).ok()),
+                None,
+            );
+            HttpResponse::Accepted().body("Deleted; applied on the next restart")
+        }
+        Ok(false) => HttpResponse::NotFound().body(format!("No stored upstream named {}", name)),
+        Err(e) => store_failed(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeyBody {
+    tenant: String,
+}
+
+// Keys are never logged or audited in full
+fn masked(key: &str) -> String {
+    let visible: String = key.chars().take(4).collect();
+    format!("{}...", visible)
+}
+
+async fn put_api_key(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    key: web::Path<String>,
+    body: web::Json<ApiKeyBody>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let store: &Store = match state.store.as_deref() {
+        Some(store) => store,
+        None => return no_store(),
+    };
+    if !state.config.tenants.contains_key(&body.tenant) {
+        return HttpResponse::BadRequest().body(format!("Unknown tenant {}", body.tenant));
+    }
+
+    match store.put_api_key(&key, &body.tenant) {
+        Ok(()) => {
+            println!("Admin: {} saved an API key for {}", who, body.tenant);
+            state.audit.record(
+                &who,
+                "api_key_put",
+                &masked(&key),
+                None,
+                Some(json!({ "tenant": body.tenant })),
+            );
+            HttpResponse::Accepted().body("Saved; applied on the next restart")
+        }
+        Err(e) => store_failed(e),
+    }
+}
+
+async fn delete_api_key(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    key: web::Path<String>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let store: &Store = match state.store.as_deref() {
+        Some(store) => store,
+        None => return no_store(),
+    };
+
+    match store.delete_api_key(&key) {
+        Ok(true) => {
+            println!("Admin: {} deleted an API key", who);
+            state
+                .audit
+                .record(&who, "api_key_delete", &masked(&key), None, None);
+            HttpResponse::Accepted().body("Deleted; applied on the next restart")
+        }
+        Ok(false) => HttpResponse::NotFound().body("No stored API key like that"),
+        Err(e) => store_failed(e),
+    }
+}
+
+fn key_store<'a>(state: &'a AppState, name: &str) -> Result<&'a KeyStore, Box<HttpResponse>> {
+    state.middlewares.authenticators.keys(name).ok_or_else(|| {
+        Box::new(HttpResponse::NotFound().body(format!("No api_key authenticator {}", name)))
+    })
+}
+
+// Records as shown to operators, without the key hashes
+fn described(record: &ApiKeyRecord) -> serde_json::Value {
+    let mut value = serde_json::to_value(record).unwrap_or_default();
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("hash");
+    }
+    value
+}
+
+fn key_store_failed(e: String) -> HttpResponse {
+    eprintln!("Admin: API key store: {}", e);
+    HttpResponse::ServiceUnavailable().body(e)
+}
+
+async fn list_partner_keys(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::View).await {
+        return *denied;
+    }
+    let store = match key_store(&state, &name) {
+        Ok(store) => store,
+        Err(missing) => return *missing,
+    };
+    match store.list().await {
+        Ok(records) => HttpResponse::Ok().json(records.iter().map(described).collect::<Vec<_>>()),
+        Err(e) => key_store_failed(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct PartnerKeyBody {
+    partner: String,
+    id: Option<String>,
+    rate_limit: Option<RateLimitConfig>,
+}
+
+// Issue a new key for a partner. The partner's other keys stay active, so a
+// key is rotated by creating its successor and then revoking it.
+async fn create_partner_key(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<PartnerKeyBody>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let store = match key_store(&state, &name) {
+        Ok(store) => store,
+        Err(missing) => return *missing,
+    };
+    if !store.writable() {
+        return HttpResponse::Conflict()
+            .body("Keys of this authenticator are set in the config file");
+    }
+    if body.partner.is_empty() {
+        return HttpResponse::BadRequest().body("A key needs a partner");
+    }
+
+    let existing = match store.list().await {
+        Ok(records) => records,
+        Err(e) => return key_store_failed(e),
+    };
+    let id = body
+        .id
+        .clone()
+        .unwrap_or_else(|| format!("{}-{}", body.partner, existing.len() + 1));
+    if existing.iter().any(|r| r.id == id) {
+        return HttpResponse::Conflict().body(format!("Key {} already exists", id));
+    }
+
+    let (key, mut record) = match keys::generate(&id, &body.partner) {
+        Ok(generated) => generated,
+        Err(e) => return store_failed(e),
+    };
+    record.rate_limit = body.rate_limit.clone();
+    if let Err(e) = store.put(&record).await {
+        return key_store_failed(e);
+    }
+
+    println!("Admin: {} issued API key {} for {}", who, id, body.partner);
+    state.audit.record(
+        &who,
+        "partner_key_create",
+        &format!("{}/{}", name, id),
+        None,
+        Some(json!({ "partner": body.partner, "key": masked(&key) })),
+    );
+    let mut shown = described(&record);
+    shown["key"] = json!(key);
+    HttpResponse::Created().json(shown)
+}
+
+#[derive(Deserialize)]
+struct RevokeQuery {
+    // Keep the key working this much longer, for a rotation grace period
+    after_secs: Option<u64>,
+}
+
+async fn revoke_partner_key(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    query: web::Query<RevokeQuery>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let (name, id) = path.into_inner();
+    let store = match key_store(&state, &name) {
+        Ok(store) => store,
+        Err(missing) => return *missing,
+    };
+    if !store.writable() {
+        return HttpResponse::Conflict()
+            .body("Keys of this authenticator are set in the config file");
+    }
+
+    let before = match store.list().await {
+        Ok(records) => records.into_iter().find(|r| r.id == id),
+        Err(e) => return key_store_failed(e),
+    };
+    let before = match before {
+        Some(record) => record,
+        None => return HttpResponse::NotFound().body(format!("No key {}", id)),
+    };
+    let mut after = before.clone();
+    match query.after_secs {
+        Some(secs) if secs > 0 => after.expires_at = Some(keys::unix_now() + secs),
+        _ => after.revoked = true,
+    }
+    if let Err(e) = store.put(&after).await {
+        return key_store_failed(e);
+    }
+
+    println!("Admin: {} revoked API key {} of {}", who, id, name);
+    state.audit.record(
+        &who,
+        "partner_key_revoke",
+        &format!("{}/{}", name, id),
+        Some(described(&before)),
+        Some(described(&after)),
+    );
+    HttpResponse::Ok().json(described(&after))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PurgeRequest {
+    // Upstream URL and path the keys start with; empty for everything
+    prefix: String,
+}
+
+// Drop cached responses here and, with [cluster], on every other instance
+async fn purge_cache(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    purge: web::Json<PurgeRequest>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::PurgeCache).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let prefix = purge.into_inner().prefix;
+    let purged = state.cache.purge(&prefix);
+    println!(
+        "Admin: {} purged {} cache entries under \"{}\"",
+        who, purged, prefix
+    );
+    state.audit.record(
+        &who,
+        "cache_purge",
+        &prefix,
+        None,
+        Some(json!({ "purged": purged })),
+    );
+    if let Some(cluster) = &state.cluster {
+        cluster.publish(Update::CachePurge { prefix });
+    }
+    HttpResponse::Ok().json(json!({ "purged": purged }))
+}
+
+fn kv_failed(e: String) -> HttpResponse {
+    eprintln!("KV store error: {}", e);
+    HttpResponse::ServiceUnavailable().body(format!("KV store unavailable: {}", e))
+}
+
+// Entries of the shared KV store, to look into or clear a feature's state
+// (a user's quota counter, say). They can hold session data, so even
+// reading them needs the config role.
+async fn get_kv(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    key: web::Path<String>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&state, &req, AdminOperation::EditConfig).await {
+        return *denied;
+    }
+    match state.kv.get(&key).await {
+        Ok(Some(value)) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(value),
+        Ok(None) => HttpResponse::NotFound().body(format!("No key {}", key)),
+        Err(e) => kv_failed(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct PutKvQuery {
+    ttl_secs: Option<u64>,
+    // Leave a value that's already there alone, answering 409
+    #[serde(default)]
+    if_absent: bool,
+}
+
+async fn put_kv(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    key: web::Path<String>,
+    query: web::Query<PutKvQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    let ttl = query
+        .ttl_secs
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs);
+    let result = match query.if_absent {
+        true => state.kv.set_nx(&key, body.to_vec(), ttl).await,
+        false => state.kv.set(&key, body.to_vec(), ttl).await.map(|_| true),
+    };
+    match result {
+        Ok(true) => {
+            println!("Admin: {} set KV key {}", who, key);
+            state.audit.record(
+                &who,
+                "kv_put",
+                &key,
+                None,
+                Some(json!({ "bytes": body.len(), "ttl_secs": query.ttl_secs })),
+            );
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::Conflict().body(format!("Key {} is already set", key)),
+        Err(e) => kv_failed(e),
+    }
+}
+
+async fn delete_kv(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    key: web::Path<String>,
+) -> impl Responder {
+    let who = match authorize(&state, &req, AdminOperation::EditConfig).await {
+        Ok(who) => who,
+        Err(denied) => return *denied,
+    };
+    match state.kv.delete(&key).await {
+        Ok(()) => {
+            println!("Admin: {} deleted KV key {}", who, key);
+            state.audit.record(&who, "kv_delete", &key, None, None);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => kv_failed(e),
+    }
+}
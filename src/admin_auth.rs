@@ -0,0 +1,517 @@
+use crate::admin::same_token;
+use crate::config::{AdminConfig, AdminToken, LdapConfig};
+use crate::metrics;
+use crate::x509::{self, ENUMERATED, INTEGER, OCTET_STRING, SEQUENCE};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::future::BoxFuture;
+use ring::digest;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+// What a caller of the admin API presents in its Authorization header
+pub enum Credentials {
+    Bearer(String),
+    Basic { user: String, password: String },
+}
+
+// Who the caller is, for the logs, and the role their operations are
+// checked against
+pub struct Principal {
+    pub name: String,
+    pub role: String,
+}
+
+// One way of checking admin credentials. Providers are asked in turn until
+// one knows the caller; Ok(None) is "not mine, or wrong", an error is "can't
+// tell right now". The gateway has static tokens and LDAP.
+pub trait AuthProvider: Send + Sync {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Option<Principal>, String>>;
+}
+
+// [admin] tokens, as bearer tokens
+pub struct StaticTokens {
+    tokens: HashMap<String, AdminToken>,
+}
+
+impl AuthProvider for StaticTokens {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Option<Principal>, String>> {
+        let found = match credentials {
+            Credentials::Bearer(presented) => self
+                .tokens
+                .iter()
+                .find(|(_, token)| same_token(&token.token, presented))
+                .map(|(name, token)| Principal {
+                    name: name.clone(),
+                    role: token.role.clone(),
+                }),
+            Credentials::Basic { .. } => None,
+        };
+        Box::pin(async move { Ok(found) })
+    }
+}
+
+// LDAPv3 result codes (RFC 4511 4.1.9)
+const SUCCESS: u8 = 0;
+// Bigger answers to a bind aren't expected
+const MAX_RESPONSE: usize = 64 * 1024;
+
+// Users of a directory, checked with a simple bind as them. Nothing else
+// is asked of the directory; roles come from [admin.ldap].
+pub struct Ldap {
+    config: LdapConfig,
+    address: String,
+    // The host name certificates are checked against, for ldaps
+    tls: Option<(TlsConnector, String)>,
+    // Recent good sign-ins: user -> hash of the password and when it's
+    // next checked
+    remembered: Mutex<HashMap<String, (digest::Digest, Instant)>>,
+}
+
+// Escape a user name for use in a DN (RFC 4514 2.4)
+fn escape_dn(value: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let edge = i == 0 || i == value.chars().count() - 1;
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' if i == 0 => out.push_str("\\#"),
+            ' ' if edge => out.push_str("\\ "),
+            '\0' => out.push_str("\\00"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn bind_request(dn: &str, password: &str) -> Vec<u8> {
+    let bind = [
+        x509::encode(INTEGER, &[3]),
+        x509::encode(OCTET_STRING, dn.as_bytes()),
+        // [0] simple
+        x509::encode(0x80, password.as_bytes()),
+    ]
+    .concat();
+    // [APPLICATION 0] BindRequest, message 1
+    let message = [x509::encode(INTEGER, &[1]), x509::encode(0x60, &bind)].concat();
+    x509::encode(SEQUENCE, &message)
+}
+
+// The result code of a BindResponse ([APPLICATION 1]) to message 1
+fn bind_result(response: &[u8]) -> Option<u8> {
+    let (message, _) = x509::expect(response, SEQUENCE)?;
+    let (id, rest) = x509::expect(message.contents, INTEGER)?;
+    let (bind, _) = x509::expect(rest, 0x61)?;
+    let (code, _) = x509::expect(bind.contents, ENUMERATED)?;
+    (id.contents == [1]).then_some(())?;
+    code.contents.last().copied()
+}
+
+// Send a bind, read its answer and unbind
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> Result<u8, String> {
+    stream.write_all(request).await.map_err(|e| e.to_string())?;
+    let mut response = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let code = loop {
+        if x509::element(&response).is_some() {
+            break bind_result(&response).ok_or("unexpected answer to a bind")?;
+        }
+        if response.len() > MAX_RESPONSE {
+            return Err("answer to a bind is too large".to_string());
+        }
+        match stream.read(&mut chunk).await.map_err(|e| e.to_string())? {
+            0 => return Err("connection closed before the bind was answered".to_string()),
+            n => response.extend_from_slice(&chunk[..n]),
+        }
+    };
+    // UnbindRequest, message 2
+    let _ = stream
+        .write_all(&[0x30, 0x05, 0x02, 0x01, 0x02, 0x42, 0x00])
+        .await;
+    let _ = stream.shutdown().await;
+    Ok(code)
+}
+
+impl Ldap {
+    pub fn new(config: &LdapConfig) -> Result<Self, Error> {
+        let invalid = || Error::other(format!("Invalid [admin.ldap] url {}", config.url));
+        let (secure, rest) = match config.url.split_once("://") {
+            Some(("ldap", rest)) => (false, rest),
+            Some(("ldaps", rest)) => (true, rest),
+            _ => return Err(invalid()),
+        };
+        // A simple bind carries the password as it is
+        match (secure, config.allow_insecure) {
+            (false, false) => {
+                return Err(Error::other(format!(
+                    "[admin.ldap] url {} would send passwords in the clear; use ldaps:// or set allow_insecure",
+                    config.url
+                )))
+            }
+            (false, true) => eprintln!(
+                "[admin.ldap] url {} sends admin passwords in the clear",
+                config.url
+            ),
+            _ => {}
+        }
+        let authority = rest.split('/').next().filter(|a| !a.is_empty());
+        let authority = authority.ok_or_else(invalid)?;
+        let (host, address) = match authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => (host, authority.to_string()),
+            _ => {
+                let port = if secure { 636 } else { 389 };
+                (authority, format!("{}:{}", authority, port))
+            }
+        };
+        let tls = match secure {
+            true => {
+                let connector = native_tls::TlsConnector::new()
+                    .map_err(|e| Error::other(format!("[admin.ldap] TLS: {}", e)))?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                Some((TlsConnector::from(connector), host.to_string()))
+            }
+            false => None,
+        };
+        Ok(Ldap {
+            config: config.clone(),
+            address,
+            tls,
+            remembered: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The bind's result code
+    async fn bind(&self, user: &str, password: &str) -> Result<u8, String> {
+        let dn = self.config.bind_dn.replace("{user}", &escape_dn(user));
+        let request = bind_request(&dn, password);
+        let attempt = async {
+            let stream = TcpStream::connect(&self.address)
+                .await
+                .map_err(|e| e.to_string())?;
+            match &self.tls {
+                Some((connector, host)) => {
+                    let stream = connector
+                        .connect(host, stream)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    exchange(stream, &request).await
+                }
+                None => exchange(stream, &request).await,
+            }
+        };
+        let timeout = Duration::from_millis(self.config.timeout_ms);
+        match tokio::time::timeout(timeout, attempt).await {
+            Ok(result) => result.map_err(|e| format!("LDAP {}: {}", self.address, e)),
+            Err(_) => Err(format!(
+                "LDAP {}: no answer in {}ms",
+                self.address, self.config.timeout_ms
+            )),
+        }
+    }
+
+    fn role(&self, user: &str) -> Option<String> {
+        self.config
+            .users
+            .get(user)
+            .or(Some(&self.config.role).filter(|role| !role.is_empty()))
+            .cloned()
+    }
+}
+
+impl AuthProvider for Ldap {
+    fn authenticate<'a>(
+        &'a self,
+        credentials: &'a Credentials,
+    ) -> BoxFuture<'a, Result<Option<Principal>, String>> {
+        Box::pin(async move {
+            let Credentials::Basic { user, password } = credentials else {
+                return Ok(None);
+            };
+            // An empty password is an unauthenticated bind, which directories
+            // answer with success
+            let Some(role) = self.role(user).filter(|_| !password.is_empty()) else {
+                return Ok(None);
+            };
+            let hash = digest::digest(&digest::SHA256, password.as_bytes());
+            let known = self
+                .remembered
+                .lock()
+                .unwrap()
+                .get(user)
+                .is_some_and(|(known, until)| {
+                    known.as_ref() == hash.as_ref() && *until > Instant::now()
+                });
+            if !known {
+                let code = self.bind(user, password).await.inspect_err(|_| {
+                    metrics::inc("admin_ldap_binds_total", &[("result", "error")]);
+                })?;
+                let mut remembered = self.remembered.lock().unwrap();
+                if code != SUCCESS {
+                    metrics::inc("admin_ldap_binds_total", &[("result", "rejected")]);
+                    remembered.remove(user);
+                    println!("Admin: LDAP refused {} (result {})", user, code);
+                    return Ok(None);
+                }
+                metrics::inc("admin_ldap_binds_total", &[("result", "ok")]);
+                let until = Instant::now() + Duration::from_secs(self.config.cache_secs);
+                remembered.retain(|_, (_, until)| *until > Instant::now());
+                remembered.insert(user.clone(), (hash, until));
+            }
+            Ok(Some(Principal {
+                name: user.clone(),
+                role,
+            }))
+        })
+    }
+}
+
+// The providers the admin API checks callers with
+pub struct AdminAuth {
+    providers: Vec<Box<dyn AuthProvider>>,
+    // Whether to ask browsers for a user name and password
+    basic: bool,
+}
+
+impl AdminAuth {
+    pub fn new(config: &AdminConfig) -> Result<Self, Error> {
+        let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+        if !config.tokens.is_empty() {
+            providers.push(Box::new(StaticTokens {
+                tokens: config.tokens.clone(),
+            }));
+        }
+        if let Some(ldap) = &config.ldap {
+            providers.push(Box::new(Ldap::new(ldap)?));
+        }
+        Ok(AdminAuth {
+            basic: config.ldap.is_some(),
+            providers,
+        })
+    }
+
+    // With no providers the admin API is open to anyone who reaches it
+    pub fn is_open(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    pub fn asks_for_password(&self) -> bool {
+        self.basic
+    }
+
+    // The first provider's answer for the request's credentials. An error
+    // from one is only returned if no other knows the caller.
+    pub async fn authenticate(&self, req: &HttpRequest) -> Result<Option<Principal>, String> {
+        let Some(credentials) = credentials(req) else {
+            return Ok(None);
+        };
+        let mut failed = None;
+        for provider in &self.providers {
+            match provider.authenticate(&credentials).await {
+                Ok(Some(principal)) => return Ok(Some(principal)),
+                Ok(None) => {}
+                Err(e) => failed = Some(e),
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+fn credentials(req: &HttpRequest) -> Option<Credentials> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, rest) = value.split_once(' ')?;
+    match scheme {
+        s if s.eq_ignore_ascii_case("bearer") => Some(Credentials::Bearer(rest.trim().to_string())),
+        s if s.eq_ignore_ascii_case("basic") => {
+            let decoded = STANDARD.decode(rest.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            Some(Credentials::Basic {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    // A BindResponse to message `id`, with `code`
+    fn bind_response(id: u8, code: u8) -> Vec<u8> {
+        let result = [
+            x509::encode(ENUMERATED, &[code]),
+            x509::encode(OCTET_STRING, b""),
+            x509::encode(OCTET_STRING, b""),
+        ]
+        .concat();
+        let message = [x509::encode(INTEGER, &[id]), x509::encode(0x61, &result)].concat();
+        x509::encode(SEQUENCE, &message)
+    }
+
+    fn ldap(url: &str, allow_insecure: bool) -> Result<Ldap, Error> {
+        Ldap::new(&LdapConfig {
+            url: url.to_string(),
+            allow_insecure,
+            ..LdapConfig::default()
+        })
+    }
+
+    #[test]
+    fn encodes_a_simple_bind() {
+        // Long enough for the long form of the lengths
+        let password = "p".repeat(300);
+        let request = bind_request("uid=ann,dc=example", &password);
+        let (message, rest) = x509::expect(&request, SEQUENCE).unwrap();
+        assert!(rest.is_empty());
+        let (id, rest) = x509::expect(message.contents, INTEGER).unwrap();
+        assert_eq!(id.contents, [1]);
+        let (bind, _) = x509::expect(rest, 0x60).unwrap();
+        let fields = x509::elements(bind.contents).unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!((fields[0].tag, fields[0].contents), (INTEGER, &[3][..]));
+        assert_eq!(fields[1].contents, b"uid=ann,dc=example");
+        assert_eq!(
+            (fields[2].tag, fields[2].contents),
+            (0x80, password.as_bytes())
+        );
+    }
+
+    #[test]
+    fn reads_bind_results() {
+        assert_eq!(bind_result(&bind_response(1, SUCCESS)), Some(SUCCESS));
+        // invalidCredentials
+        assert_eq!(bind_result(&bind_response(1, 49)), Some(49));
+        // An answer to some other message
+        assert_eq!(bind_result(&bind_response(2, SUCCESS)), None);
+
+        let response = bind_response(1, SUCCESS);
+        for cut in 0..response.len() {
+            assert_eq!(bind_result(&response[..cut]), None, "cut at {}", cut);
+        }
+        // Not a BindResponse: a SearchResultDone
+        let mut other = response.clone();
+        other[5] = 0x65;
+        assert_eq!(bind_result(&other), None);
+        // A length with more bytes than BER lengths get here
+        assert_eq!(bind_result(&[0x30, 0x85, 0, 0, 0, 0, 1, 0]), None);
+    }
+
+    #[tokio::test]
+    async fn exchanges_a_bind() {
+        let (client, mut server) = duplex(1024);
+        let directory = tokio::spawn(async move {
+            let mut request = [0u8; 64];
+            let n = server.read(&mut request).await.unwrap();
+            assert_eq!(x509::element(&request[..n]).unwrap().0.tag, SEQUENCE);
+            // The answer arrives in two pieces
+            let response = bind_response(1, 49);
+            server.write_all(&response[..4]).await.unwrap();
+            server.flush().await.unwrap();
+            tokio::task::yield_now().await;
+            server.write_all(&response[4..]).await.unwrap();
+            let mut unbind = Vec::new();
+            server.read_to_end(&mut unbind).await.unwrap();
+            unbind
+        });
+        let code = exchange(client, &bind_request("uid=ann", "secret")).await;
+        assert_eq!(code, Ok(49));
+        let unbind = directory.await.unwrap();
+        assert_eq!(unbind, [0x30, 0x05, 0x02, 0x01, 0x02, 0x42, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_bad_answers() {
+        // Closed before answering
+        let (client, server) = duplex(1024);
+        drop(server);
+        assert!(exchange(client, &bind_request("uid=ann", "x"))
+            .await
+            .is_err());
+
+        // An answer to something else
+        let (client, mut server) = duplex(1024);
+        server.write_all(&bind_response(7, SUCCESS)).await.unwrap();
+        let result = exchange(client, &bind_request("uid=ann", "x")).await;
+        assert_eq!(result, Err("unexpected answer to a bind".to_string()));
+
+        // A length that would have us read forever
+        let (client, mut server) = duplex(MAX_RESPONSE * 2);
+        tokio::spawn(async move {
+            let _ = server
+                .write_all(&[0x30, 0x84, 0x7f, 0xff, 0xff, 0xff])
+                .await;
+            let _ = server.write_all(&vec![0u8; MAX_RESPONSE + 4096]).await;
+            let mut rest = Vec::new();
+            let _ = server.read_to_end(&mut rest).await;
+        });
+        let result = exchange(client, &bind_request("uid=ann", "x")).await;
+        assert_eq!(result, Err("answer to a bind is too large".to_string()));
+    }
+
+    #[test]
+    fn escapes_user_names() {
+        assert_eq!(escape_dn("ann"), "ann");
+        assert_eq!(escape_dn("a,b=c+d"), "a\\,b\\=c\\+d");
+        assert_eq!(escape_dn("#ann"), "\\#ann");
+        assert_eq!(escape_dn("a#b"), "a#b");
+        assert_eq!(escape_dn(" ann "), "\\ ann\\ ");
+        assert_eq!(escape_dn("a\0b"), "a\\00b");
+        assert_eq!(escape_dn("ann)(uid=*"), "ann)(uid\\=*");
+    }
+
+    #[test]
+    fn takes_ldap_urls() {
+        let secure = ldap("ldaps://ldap.example.com", false).unwrap();
+        assert_eq!(secure.address, "ldap.example.com:636");
+        assert_eq!(
+            secure.tls.map(|(_, host)| host).as_deref(),
+            Some("ldap.example.com")
+        );
+        let ipv6 = ldap("ldaps://[2001:db8::1]:1636/", false).unwrap();
+        assert_eq!(ipv6.address, "[2001:db8::1]:1636");
+        assert_eq!(
+            ipv6.tls.map(|(_, host)| host).as_deref(),
+            Some("2001:db8::1")
+        );
+
+        // Cleartext only when asked for, and then without TLS
+        assert!(ldap("ldap://127.0.0.1", false).is_err());
+        let insecure = ldap("ldap://127.0.0.1", true).unwrap();
+        assert_eq!(insecure.address, "127.0.0.1:389");
+        assert!(insecure.tls.is_none());
+
+        for url in [
+            "",
+            "ldaps://",
+            "https://ldap.example.com",
+            "ldap.example.com",
+        ] {
+            assert!(ldap(url, true).is_err(), "{}", url);
+        }
+    }
+}
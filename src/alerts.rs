@@ -0,0 +1,48 @@
+use crate::access_log;
+use crate::config::AlertsConfig;
+use crate::metrics;
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+static WEBHOOK: OnceLock<(reqwest::Client, String)> = OnceLock::new();
+
+pub fn init(config: &AlertsConfig) {
+    if config.webhook_url.is_empty() {
+        return;
+    }
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(config.timeout_ms))
+        .build()
+        .unwrap_or_default();
+    let _ = WEBHOOK.set((client, config.webhook_url.clone()));
+}
+
+// Tell whoever is on call. Always logged and counted; posted as JSON to
+// [alerts] webhook_url in the background when there is one.
+pub fn fire(alert: &str, message: String, details: Value) {
+    eprintln!("Alert {}: {}", alert, message);
+    metrics::inc("alerts_fired_total", &[("alert", alert)]);
+    let Some((client, url)) = WEBHOOK.get() else {
+        return;
+    };
+    let body = json!({
+        "alert": alert,
+        "message": message,
+        "time": access_log::iso8601(SystemTime::now()),
+        "details": details,
+    });
+    let (client, url, alert) = (client.clone(), url.clone(), alert.to_string());
+    tokio::spawn(async move {
+        let result = client
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        if let Err(e) = result {
+            eprintln!("Failed to send {} alert: {}", alert, e);
+            metrics::inc("alert_webhook_errors_total", &[("alert", &alert)]);
+        }
+    });
+}
@@ -0,0 +1,122 @@
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// One runtime change made through the admin API
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub at: u64,
+    pub who: String,
+    pub action: String,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    // Top-level fields that differ between before and after
+    pub changed: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub who: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+// Append-only JSON lines file of admin actions
+pub struct AuditLog {
+    path: String,
+    lock: Mutex<()>,
+}
+
+pub fn changed_fields(before: Option<&Value>, after: Option<&Value>) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let before = before.and_then(Value::as_object).unwrap_or(&empty);
+    let after = after.and_then(Value::as_object).unwrap_or(&empty);
+
+    let mut changed: Vec<String> = before
+        .keys()
+        .chain(after.keys())
+        .filter(|key| before.get(*key) != after.get(*key))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+impl AuditLog {
+    pub fn new(path: String) -> Self {
+        AuditLog {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn record(
+        &self,
+        who: &str,
+        action: &str,
+        target: &str,
+        before: Option<Value>,
+        after: Option<Value>,
+    ) {
+        let entry = AuditEntry {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            who: who.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            changed: changed_fields(before.as_ref(), after.as_ref()),
+            before,
+            after,
+        };
+        if self.path.is_empty() {
+            return;
+        }
+
+        let mut line = serde_json::to_string(&entry).unwrap_or_default();
+        line.push('\n');
+        let _guard = self.lock.lock().unwrap();
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            // The change itself already happened; don't fail it over the log
+            eprintln!("Failed to write audit log {}: {}", self.path, e);
+            metrics::inc("audit_write_errors_total", &[]);
+        }
+    }
+
+    // Newest entries first
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditEntry> {
+        let raw = {
+            let _guard = self.lock.lock().unwrap();
+            fs::read_to_string(&self.path).unwrap_or_default()
+        };
+
+        let matches = |entry: &AuditEntry| {
+            query.who.as_ref().is_none_or(|who| &entry.who == who)
+                && query.action.as_ref().is_none_or(|a| &entry.action == a)
+                && query.target.as_ref().is_none_or(|t| &entry.target == t)
+                && query.since.is_none_or(|since| entry.at >= since)
+        };
+
+        raw.lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .filter(matches)
+            .take(query.limit.unwrap_or(100))
+            .collect()
+    }
+}
@@ -0,0 +1,65 @@
+use crate::config::JwtConfig;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::fs;
+use std::io::Error;
+use std::str::FromStr;
+
+pub type Claims = Map<String, Value>;
+
+// Validates bearer tokens at the edge so the gateway only sees authenticated traffic
+pub struct JwtValidator {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtValidator {
+    pub fn new(config: &JwtConfig) -> Result<Self, Error> {
+        let algorithm = Algorithm::from_str(&config.algorithm)
+            .map_err(|_| Error::other(format!("Unknown JWT algorithm: {}", config.algorithm)))?;
+
+        let key = match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                DecodingKey::from_secret(config.secret.as_bytes())
+            }
+            Algorithm::ES256 | Algorithm::ES384 => {
+                DecodingKey::from_ec_pem(&fs::read(&config.public_key_path)?)
+                    .map_err(|e| Error::other(format!("Invalid JWT public key: {}", e)))?
+            }
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(&fs::read(&config.public_key_path)?)
+                .map_err(|e| Error::other(format!("Invalid JWT public key: {}", e)))?,
+            _ => DecodingKey::from_rsa_pem(&fs::read(&config.public_key_path)?)
+                .map_err(|e| Error::other(format!("Invalid JWT public key: {}", e)))?,
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = config.leeway_secs;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Ok(JwtValidator { key, validation })
+    }
+
+    pub fn validate(&self, token: &str) -> Result<Claims, String> {
+        decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Extract the token from an "Authorization: Bearer <token>" header
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
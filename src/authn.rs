@@ -0,0 +1,297 @@
+use crate::auth::{self, Claims, JwtValidator};
+use crate::config::{AuthenticatorConfig, HmacAuthConfig};
+use crate::keys::{self, KeyStore, Refusal};
+use crate::kv::KvStore;
+use crate::metrics;
+use crate::middleware;
+use crate::store::Store;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpMessage, HttpRequest};
+use ring::hmac;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+enum Authenticator {
+    // Validator plus the claim -> header map to forward
+    Jwt(JwtValidator, HashMap<String, String>),
+    // Key header, the partner header to forward, and the keys
+    ApiKey(HeaderName, Option<HeaderName>, KeyStore),
+    Hmac(HmacAuthConfig, hmac::Key),
+    None,
+}
+
+// Who a request was authenticated as
+#[derive(Default)]
+pub struct Identity {
+    pub claims: Option<Claims>,
+    // Identity headers to forward upstream
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+// The id of the API key a request was let in with, in its extensions
+#[derive(Clone)]
+pub struct ApiKeyId(pub String);
+
+// The [authenticators] instances, by name
+pub struct Authenticators {
+    instances: HashMap<String, Authenticator>,
+    // Where signed requests already seen are remembered
+    kv: Arc<dyn KvStore>,
+}
+
+// Compare secrets without leaking how much of them matched
+pub fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn header_name(auth: &str, name: &str) -> Result<HeaderName, Error> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+        Error::other(format!(
+            "Authenticator {} has an invalid header {}",
+            auth, name
+        ))
+    })
+}
+
+// HMAC requests carry a hex HMAC-SHA256 of
+//   METHOD \n path?query \n timestamp \n body
+// keyed with the shared secret, plus the Unix timestamp that was signed.
+// With a nonce_header the nonce is signed too, after the timestamp. Gives
+// what identifies the request for replay protection.
+fn verify_hmac<'a>(
+    config: &HmacAuthConfig,
+    key: &hmac::Key,
+    req: &'a HttpRequest,
+    body: &[u8],
+) -> Result<&'a str, &'static str> {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let signature = header(&config.signature_header).ok_or("Missing request signature")?;
+    let timestamp = header(&config.timestamp_header).ok_or("Missing request timestamp")?;
+    let nonce = match config.nonce_header.is_empty() {
+        true => None,
+        false => Some(header(&config.nonce_header).ok_or("Missing request nonce")?),
+    };
+    if nonce.is_some_and(|nonce| nonce.is_empty() || nonce.len() > MAX_NONCE) {
+        return Err("Invalid request nonce");
+    }
+
+    let signed_at: u64 = timestamp.parse().map_err(|_| "Invalid request timestamp")?;
+    if keys::unix_now().abs_diff(signed_at) > config.max_skew_secs {
+        return Err("Request timestamp too old");
+    }
+
+    let target = req
+        .uri()
+        .path_and_query()
+        .map_or(req.path(), |p| p.as_str());
+    let mut message = format!("{}\n{}\n{}\n", req.method(), target, timestamp).into_bytes();
+    if let Some(nonce) = nonce {
+        message.extend_from_slice(format!("{}\n", nonce).as_bytes());
+    }
+    message.extend_from_slice(body);
+    let expected = keys::hex(hmac::sign(key, &message).as_ref());
+    let normalized = signature.trim_start_matches("sha256=").to_ascii_lowercase();
+    match constant_eq(expected.as_bytes(), normalized.as_bytes()) {
+        true => Ok(nonce.unwrap_or(signature)),
+        false => Err("Invalid request signature"),
+    }
+}
+
+// Longest nonce accepted, as each one becomes a key in the store
+const MAX_NONCE: usize = 128;
+
+impl Authenticators {
+    pub async fn new(
+        configs: &HashMap<String, AuthenticatorConfig>,
+        store: Option<Arc<Store>>,
+        kv: Arc<dyn KvStore>,
+    ) -> Result<Self, Error> {
+        let mut instances = HashMap::new();
+        for (name, config) in configs {
+            let instance = match config {
+                AuthenticatorConfig::Jwt(jwt) => {
+                    for header in jwt.claim_headers.values() {
+                        header_name(name, header)?;
+                    }
+                    Authenticator::Jwt(JwtValidator::new(jwt)?, jwt.claim_headers.clone())
+                }
+                AuthenticatorConfig::ApiKey(api_key) => Authenticator::ApiKey(
+                    header_name(name, &api_key.header)?,
+                    api_key
+                        .partner_header
+                        .as_deref()
+                        .map(|header| header_name(name, header))
+                        .transpose()?,
+                    KeyStore::new(name, api_key, store.clone()).await?,
+                ),
+                AuthenticatorConfig::Hmac(config) => {
+                    if config.secret.is_empty() {
+                        return Err(Error::other(format!(
+                            "Authenticator {} needs a secret",
+                            name
+                        )));
+                    }
+                    header_name(name, &config.signature_header)?;
+                    header_name(name, &config.timestamp_header)?;
+                    if !config.nonce_header.is_empty() {
+                        header_name(name, &config.nonce_header)?;
+                    }
+                    let key = hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes());
+                    Authenticator::Hmac(config.clone(), key)
+                }
+                AuthenticatorConfig::None => Authenticator::None,
+            };
+            instances.insert(name.clone(), instance);
+        }
+        Ok(Authenticators { instances, kv })
+    }
+
+    // HMAC signatures cover the body, so it has to be read first
+    pub fn needs_body(&self, name: &str) -> bool {
+        matches!(self.instances.get(name), Some(Authenticator::Hmac(..)))
+    }
+
+    // Whether the authenticator yields JWT claims for later middlewares
+    pub fn has_claims(&self, name: &str) -> bool {
+        matches!(self.instances.get(name), Some(Authenticator::Jwt(..)))
+    }
+
+    // Headers only the authenticator may set, stripped from client requests
+    pub fn identity_headers(&self, name: &str) -> Vec<&str> {
+        match self.instances.get(name) {
+            Some(Authenticator::Jwt(_, headers)) => headers.values().map(|h| h.as_str()).collect(),
+            Some(Authenticator::ApiKey(_, Some(partner), _)) => vec![partner.as_str()],
+            _ => Vec::new(),
+        }
+    }
+
+    // The partner keys behind an api_key authenticator
+    pub fn keys(&self, name: &str) -> Option<&KeyStore> {
+        match self.instances.get(name) {
+            Some(Authenticator::ApiKey(_, _, keys)) => Some(keys),
+            _ => None,
+        }
+    }
+
+    // Check the request against the named authenticator. Errors are the
+    // status and reason given to the client.
+    pub async fn authenticate(
+        &self,
+        name: &str,
+        req: &HttpRequest,
+        body: &[u8],
+    ) -> Result<Identity, (StatusCode, &'static str)> {
+        let unauthorized = |reason| (StatusCode::UNAUTHORIZED, reason);
+        let instance = match self.instances.get(name) {
+            Some(instance) => instance,
+            None => return Err(unauthorized("Unknown authenticator")),
+        };
+        match instance {
+            Authenticator::Jwt(validator, claim_headers) => match auth::bearer_token(req) {
+                Some(token) => match validator.validate(token) {
+                    Ok(claims) => Ok(Identity {
+                        headers: claim_headers
+                            .iter()
+                            .filter_map(|(claim, header)| {
+                                middleware::claim_header(&claims, claim, header)
+                            })
+                            .collect(),
+                        claims: Some(claims),
+                    }),
+                    Err(e) => {
+                        println!("Rejected token for {}: {}", req.path(), e);
+                        Err(unauthorized("Invalid token"))
+                    }
+                },
+                None => Err(unauthorized("Missing bearer token")),
+            },
+            Authenticator::ApiKey(header, partner_header, keys) => {
+                let key = match req.headers().get(header) {
+                    Some(key) => key.as_bytes(),
+                    None => return Err(unauthorized("Missing API key")),
+                };
+                let refused = match keys.check(key).await {
+                    Ok(record) => {
+                        req.extensions_mut().insert(ApiKeyId(record.id.clone()));
+                        let headers = partner_header
+                            .iter()
+                            .filter_map(|h| {
+                                Some((h.clone(), HeaderValue::from_str(&record.partner).ok()?))
+                            })
+                            .collect();
+                        return Ok(Identity {
+                            claims: None,
+                            headers,
+                        });
+                    }
+                    Err(refused) => refused,
+                };
+                let (status, reason, result) = match refused {
+                    Refusal::Unknown => (StatusCode::UNAUTHORIZED, "Invalid API key", "unknown"),
+                    Refusal::Revoked => (StatusCode::UNAUTHORIZED, "API key revoked", "revoked"),
+                    Refusal::Expired => (StatusCode::UNAUTHORIZED, "API key expired", "expired"),
+                    Refusal::RateLimited => (
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "API key rate limit exceeded",
+                        "rate_limited",
+                    ),
+                    Refusal::Unavailable => (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "API key store unavailable",
+                        "unavailable",
+                    ),
+                };
+                println!("Refused API key for {}: {}", req.path(), reason);
+                metrics::inc(
+                    "api_key_refusals_total",
+                    &[("auth", name), ("reason", result)],
+                );
+                Err((status, reason))
+            }
+            Authenticator::Hmac(config, key) => {
+                let nonce = match verify_hmac(config, key, req, body) {
+                    Ok(nonce) => nonce,
+                    Err(reason) => {
+                        println!("Rejected signed request for {}: {}", req.path(), reason);
+                        return Err(unauthorized(reason));
+                    }
+                };
+                if config.replay_protection {
+                    self.check_replay(name, config, req, nonce).await?;
+                }
+                Ok(Identity::default())
+            }
+            Authenticator::None => Ok(Identity::default()),
+        }
+    }
+
+    // Remember the request until its timestamp would be refused anyway, and
+    // refuse it if it was already there. A timestamp is accepted for
+    // max_skew_secs either side of it, so that long after it's first seen
+    // at the most.
+    async fn check_replay(
+        &self,
+        name: &str,
+        config: &HmacAuthConfig,
+        req: &HttpRequest,
+        nonce: &str,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        let key = format!("replay:{}:{}", name, nonce);
+        let window = Duration::from_secs(2 * config.max_skew_secs.max(1));
+        match self.kv.set_nx(&key, Vec::new(), Some(window)).await {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                println!("Rejected replayed signed request for {}", req.path());
+                metrics::inc("signed_request_replays_total", &[("auth", name)]);
+                Err((StatusCode::UNAUTHORIZED, "Request already seen"))
+            }
+            Err(e) => {
+                eprintln!("Replay check for {} failed: {}", name, e);
+                Err((StatusCode::SERVICE_UNAVAILABLE, "Replay check unavailable"))
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+// A replica a balancer may send the request to, and how loaded it looks
+pub struct Candidate<'a> {
+    // For custom strategies (by zone, say); the built-in ones don't need it
+    #[allow(dead_code)]
+    pub target: &'a str,
+    // Requests sent to it that haven't been answered yet
+    pub in_flight: usize,
+    // Smoothed latency of its recent responses; None until it has answered
+    pub latency: Option<Duration>,
+}
+
+// How a pool spreads requests over its replicas. The pool has already left
+// out ejected replicas, ones the request has tried and groups that aren't
+// getting traffic; the balancer only chooses among what's left. A custom
+// strategy is an implementation of this registered under a name with
+// register(), which upstreams then name as their `balancer`.
+pub trait LoadBalancer: Send + Sync {
+    // Index of the chosen candidate; there is always at least one. `retry`
+    // is set for retries and hedges of a request already sent once.
+    fn pick(&self, candidates: &[Candidate<'_>], retry: bool) -> usize;
+}
+
+// In turn. Only first attempts move the rotation on, so retries and hedges
+// don't skew it.
+#[derive(Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, candidates: &[Candidate<'_>], retry: bool) -> usize {
+        let n = match retry {
+            true => self.next.load(Ordering::Relaxed),
+            false => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        n % candidates.len()
+    }
+}
+
+pub struct Random;
+
+impl LoadBalancer for Random {
+    fn pick(&self, candidates: &[Candidate<'_>], _retry: bool) -> usize {
+        rand::thread_rng().gen_range(0..candidates.len())
+    }
+}
+
+// Two candidates at random, and the less loaded of them: its latency times
+// the requests waiting on it. That steers traffic off a slow replica (and one
+// that has stalled with requests piling up) without the herding that always
+// taking the least loaded causes. Replicas nothing is known about yet are
+// taken as fast, so they're tried.
+pub struct PowerOfTwo;
+
+// Latency assumed before a replica has answered, and the least counted for
+// any, so the in-flight count still matters when responses are instant
+const FLOOR: Duration = Duration::from_millis(1);
+
+fn cost(candidate: &Candidate<'_>) -> f64 {
+    let latency = candidate.latency.unwrap_or(FLOOR).max(FLOOR);
+    latency.as_secs_f64() * (candidate.in_flight + 1) as f64
+}
+
+impl LoadBalancer for PowerOfTwo {
+    fn pick(&self, candidates: &[Candidate<'_>], _retry: bool) -> usize {
+        if candidates.len() == 1 {
+            return 0;
+        }
+        let mut rng = rand::thread_rng();
+        let a = rng.gen_range(0..candidates.len());
+        // A second, different one
+        let b = (a + rng.gen_range(1..candidates.len())) % candidates.len();
+        match cost(&candidates[b]) < cost(&candidates[a]) {
+            true => b,
+            false => a,
+        }
+    }
+}
+
+type Factory = fn() -> Box<dyn LoadBalancer>;
+
+fn registry() -> &'static RwLock<HashMap<String, Factory>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, Factory>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut builtin: HashMap<String, Factory> = HashMap::new();
+        builtin.insert("round_robin".to_string(), || Box::<RoundRobin>::default());
+        builtin.insert("random".to_string(), || Box::new(Random));
+        builtin.insert("p2c".to_string(), || Box::new(PowerOfTwo));
+        RwLock::new(builtin)
+    })
+}
+
+// Make a strategy available to upstreams by name. Call it before the
+// upstreams are set up; a name already taken is replaced. The gateway's own
+// strategies don't need it, so it's only called by custom builds.
+#[allow(dead_code)]
+pub fn register(name: &str, make: Factory) {
+    registry().write().unwrap().insert(name.to_string(), make);
+}
+
+// A balancer of the named strategy for one pool
+pub fn build(name: &str) -> Option<Box<dyn LoadBalancer>> {
+    registry().read().unwrap().get(name).map(|make| make())
+}
+
+// The names strategies can be asked for by, for error messages
+pub fn names() -> Vec<String> {
+    let mut names: Vec<String> = registry().read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+}
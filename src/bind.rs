@@ -0,0 +1,66 @@
+use crate::tuning;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+
+// Listener addresses from config, such as ["::1", "10.0.0.5"]. IPv6
+// sockets are IPv6 only, so "::" and "0.0.0.0" can be listed together for
+// dual-stack on any address.
+
+pub fn addrs(addresses: &[String], port: u16) -> Result<Vec<SocketAddr>, Error> {
+    if addresses.is_empty() {
+        return Err(Error::other("A listener needs at least one bind address"));
+    }
+    addresses
+        .iter()
+        .map(|address| {
+            let literal = address.trim().trim_start_matches('[').trim_end_matches(']');
+            literal
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, port))
+                .map_err(|_| Error::other(format!("Invalid bind address {}", address)))
+        })
+        .collect()
+}
+
+fn socket(addr: SocketAddr, kind: Type, protocol: Protocol) -> Result<Socket, Error> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    Ok(socket)
+}
+
+fn failed(addr: SocketAddr, e: Error) -> Error {
+    Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e))
+}
+
+pub fn tcp(addresses: &[String], port: u16) -> Result<Vec<TcpListener>, Error> {
+    addrs(addresses, port)?
+        .into_iter()
+        .map(|addr| {
+            let socket = socket(addr, Type::STREAM, Protocol::TCP)?;
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            socket.bind(&addr.into()).map_err(|e| failed(addr, e))?;
+            socket.listen(tuning::get().backlog as i32)?;
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+#[cfg(feature = "http3")]
+pub fn udp(addresses: &[String], port: u16) -> Result<Vec<std::net::UdpSocket>, Error> {
+    addrs(addresses, port)?
+        .into_iter()
+        .map(|addr| {
+            let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+            // Shared with the process we take over from until it exits
+            // (see handoff.rs); UDP sockets aren't handed over
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.bind(&addr.into()).map_err(|e| failed(addr, e))?;
+            Ok(socket.into())
+        })
+        .collect()
+}
@@ -0,0 +1,114 @@
+use crate::config::{BlockAction, BlockRule};
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use regex::Regex;
+use std::io::Error;
+
+struct Rule {
+    name: String,
+    path: Option<Regex>,
+    query: Option<Regex>,
+    user_agent: Option<Regex>,
+    action: BlockAction,
+}
+
+// Requests nobody legitimate sends, such as probes for /wp-admin or /.env,
+// refused before they cost an upstream request or even a route lookup
+pub struct Blocklist {
+    rules: Vec<Rule>,
+}
+
+// %XX escapes decoded, so /%2Eenv can't slip past a rule for /.env. Ones
+// that aren't valid escapes are left as they are.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+impl Blocklist {
+    pub fn new(config: &[BlockRule]) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+        for (i, rule) in config.iter().enumerate() {
+            let name = match rule.name.is_empty() {
+                true => format!("rule{}", i + 1),
+                false => rule.name.clone(),
+            };
+            let compile = |what: &str, pattern: &Option<String>| {
+                pattern.as_deref().map(Regex::new).transpose().map_err(|e| {
+                    Error::other(format!(
+                        "Block rule {} has an invalid {}: {}",
+                        name, what, e
+                    ))
+                })
+            };
+            let rule = Rule {
+                path: compile("path", &rule.path)?,
+                query: compile("query", &rule.query)?,
+                user_agent: compile("user_agent", &rule.user_agent)?,
+                action: rule.action,
+                name,
+            };
+            if rule.path.is_none() && rule.query.is_none() && rule.user_agent.is_none() {
+                return Err(Error::other(format!(
+                    "Block rule {} needs a path, query or user_agent pattern",
+                    rule.name
+                )));
+            }
+            rules.push(rule);
+        }
+        Ok(Blocklist { rules })
+    }
+
+    pub fn check(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let path = percent_decode(req.path());
+        let query = percent_decode(req.query_string());
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let matches = |pattern: &Option<Regex>, value: &str| {
+            pattern.as_ref().is_none_or(|p| p.is_match(value))
+        };
+        let rule = self.rules.iter().find(|rule| {
+            matches(&rule.path, &path)
+                && matches(&rule.query, &query)
+                && matches(&rule.user_agent, user_agent)
+        })?;
+        metrics::inc("block_rule_hits_total", &[("rule", &rule.name)]);
+        Some(match rule.action {
+            BlockAction::Forbidden => HttpResponse::Forbidden().force_close().body("Forbidden"),
+            // A body that fails at once makes the server drop the connection
+            // before the response head goes out
+            BlockAction::Close => {
+                HttpResponse::Forbidden()
+                    .force_close()
+                    .streaming(futures_util::stream::once(async {
+                        Err::<web::Bytes, _>(Error::other("blocked"))
+                    }))
+            }
+        })
+    }
+}
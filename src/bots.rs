@@ -0,0 +1,257 @@
+use crate::authn;
+use crate::config::{BotAction, BotsConfig};
+use crate::keys;
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use regex::{Regex, RegexBuilder};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Nonces have to be solved within this long
+const NONCE_TTL_SECS: u64 = 120;
+
+#[derive(Deserialize)]
+struct Solution {
+    nonce: String,
+    solution: String,
+}
+
+// Scores each request on its User-Agent, the browser headers it lacks and
+// how fast its IP is going, and acts on the ones that look automated
+pub struct Bots {
+    config: BotsConfig,
+    block: Vec<Regex>,
+    allow: Vec<Regex>,
+    key: hmac::Key,
+    // Client IP -> window start, requests in the window
+    clients: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+fn patterns(list: &[String]) -> Result<Vec<Regex>, Error> {
+    list.iter()
+        .map(|p| {
+            RegexBuilder::new(p)
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| Error::other(format!("Invalid bots user agent pattern {}: {}", p, e)))
+        })
+        .collect()
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+impl Bots {
+    pub fn new(config: BotsConfig) -> Result<Self, Error> {
+        let secret = match config.secret.is_empty() {
+            true => {
+                let mut bytes = [0u8; 32];
+                SystemRandom::new()
+                    .fill(&mut bytes)
+                    .map_err(|_| Error::other("No randomness for the bots secret"))?;
+                bytes.to_vec()
+            }
+            false => config.secret.as_bytes().to_vec(),
+        };
+        Ok(Bots {
+            block: patterns(&config.block_user_agents)?,
+            allow: patterns(&config.allow_user_agents)?,
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            clients: Mutex::new(HashMap::new()),
+            config,
+        })
+    }
+
+    // "{expiry}.{signature}", bound to the client's IP
+    fn sign(&self, purpose: &str, client_ip: &str, expiry: u64) -> String {
+        let message = format!("{}\n{}\n{}", purpose, client_ip, expiry);
+        let signature = keys::hex(hmac::sign(&self.key, message.as_bytes()).as_ref());
+        format!("{}.{}", expiry, signature)
+    }
+
+    fn verify(&self, purpose: &str, client_ip: &str, token: &str) -> bool {
+        let expiry = match token
+            .split_once('.')
+            .and_then(|(e, _)| e.parse::<u64>().ok())
+        {
+            Some(expiry) if expiry > keys::unix_now() => expiry,
+            _ => return false,
+        };
+        let expected = self.sign(purpose, client_ip, expiry);
+        authn::constant_eq(expected.as_bytes(), token.as_bytes())
+    }
+
+    pub fn is_challenge(&self, req: &HttpRequest) -> bool {
+        !self.config.challenge_path.is_empty() && req.path() == self.config.challenge_path
+    }
+
+    // The challenge endpoint. Without parameters it hands out a nonce; with
+    // ?nonce=..&solution=.. where SHA-256(nonce + solution) starts with
+    // challenge_difficulty zero bits, it sets the pass cookie.
+    pub fn challenge(&self, req: &HttpRequest, client_ip: &str) -> HttpResponse {
+        let attempt = match req.query_string().is_empty() {
+            true => None,
+            false => match web::Query::<Solution>::from_query(req.query_string()) {
+                Ok(attempt) => Some(attempt.into_inner()),
+                Err(_) => return HttpResponse::BadRequest().body("Expected nonce and solution"),
+            },
+        };
+        let attempt = match attempt {
+            Some(attempt) => attempt,
+            None => {
+                let nonce = self.sign("nonce", client_ip, keys::unix_now() + NONCE_TTL_SECS);
+                return HttpResponse::Ok()
+                    .insert_header((header::CACHE_CONTROL, "no-store"))
+                    .json(json!({
+                        "nonce": nonce,
+                        "difficulty": self.config.challenge_difficulty,
+                    }));
+            }
+        };
+
+        let digest = digest::digest(
+            &digest::SHA256,
+            format!("{}{}", attempt.nonce, attempt.solution).as_bytes(),
+        );
+        if !self.verify("nonce", client_ip, &attempt.nonce)
+            || leading_zero_bits(digest.as_ref()) < self.config.challenge_difficulty
+        {
+            metrics::inc("bot_challenges_total", &[("result", "failed")]);
+            return HttpResponse::Forbidden().body("Challenge not solved");
+        }
+
+        metrics::inc("bot_challenges_total", &[("result", "passed")]);
+        let pass = self.sign(
+            "pass",
+            client_ip,
+            keys::unix_now() + self.config.pass_ttl_secs,
+        );
+        HttpResponse::NoContent()
+            .insert_header((
+                header::SET_COOKIE,
+                format!(
+                    "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                    self.config.pass_cookie, pass, self.config.pass_ttl_secs
+                ),
+            ))
+            .finish()
+    }
+
+    // Requests the IP made in the current window, this one included
+    fn count(&self, client_ip: &str) -> u64 {
+        let window = Duration::from_secs(self.config.window_secs.max(1));
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > 100_000 {
+            clients.retain(|_, (start, _)| now.duration_since(*start) < window);
+        }
+        let (start, count) = clients.entry(client_ip.to_string()).or_insert((now, 0));
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count
+    }
+
+    // What set off the heuristics, and the request's score
+    fn score(&self, req: &HttpRequest, client_ip: &str) -> (u32, Vec<&'static str>) {
+        let mut score = 0;
+        let mut reasons = Vec::new();
+        let agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if self.block.iter().any(|p| p.is_match(agent)) {
+            score += self.config.user_agent_score;
+            reasons.push("user_agent");
+        }
+        let missing = self
+            .config
+            .required_headers
+            .iter()
+            .filter(|h| !req.headers().contains_key(h.as_str()))
+            .count() as u32;
+        if missing > 0 {
+            score += missing * self.config.missing_header_score;
+            reasons.push("missing_headers");
+        }
+        if self.count(client_ip) > self.config.max_requests_per_window {
+            score += self.config.rate_score;
+            reasons.push("rate");
+        }
+        (score, reasons)
+    }
+
+    // Let the request through, after a delay when tarpitting, or answer it
+    pub async fn screen(&self, req: &HttpRequest, client_ip: &str) -> Result<(), HttpResponse> {
+        let paths = &self.config.paths;
+        if !paths.is_empty() && !paths.iter().any(|p| req.path().starts_with(p.as_str())) {
+            return Ok(());
+        }
+        let agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if self.allow.iter().any(|p| p.is_match(agent)) {
+            return Ok(());
+        }
+        if let Some(pass) = req.cookie(&self.config.pass_cookie) {
+            if self.verify("pass", client_ip, pass.value()) {
+                return Ok(());
+            }
+        }
+
+        let (score, reasons) = self.score(req, client_ip);
+        if score < self.config.threshold {
+            return Ok(());
+        }
+        let action = match self.config.action {
+            BotAction::Block => "block",
+            BotAction::Tarpit => "tarpit",
+            BotAction::Challenge => "challenge",
+        };
+        println!(
+            "Suspected bot {} on {} (score {}: {}), action {}",
+            client_ip,
+            req.path(),
+            score,
+            reasons.join(","),
+            action
+        );
+        for reason in &reasons {
+            metrics::inc(
+                "bot_detections_total",
+                &[("reason", reason), ("action", action)],
+            );
+        }
+
+        match self.config.action {
+            BotAction::Block => Err(HttpResponse::Forbidden().body("Forbidden")),
+            BotAction::Tarpit => {
+                tokio::time::sleep(Duration::from_millis(self.config.tarpit_ms)).await;
+                Ok(())
+            }
+            BotAction::Challenge => Err(HttpResponse::Forbidden()
+                .insert_header((header::CACHE_CONTROL, "no-store"))
+                .json(json!({ "challenge": self.config.challenge_path }))),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::config::{BufferingConfig, BufferingMode, RouteConfig};
+use crate::metrics;
+use crate::sniff;
+use crate::tuning;
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+
+// A line longer than this goes out in pieces rather than waiting for its end
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+// The media type of a Content-Type value, lowercased and without parameters
+fn media(content_type: &str) -> Option<String> {
+    let media = content_type.split(';').next()?.trim();
+    Some(media.to_ascii_lowercase())
+}
+
+fn matches(patterns: &[String], media: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| sniff::allowed(pattern, media))
+}
+
+// What the route's mode says about a body, given its message's headers:
+// Some(true) to stream it, Some(false) to read it whole, None to leave it to
+// whatever needs it whole (auto mode, for a body it doesn't single out)
+pub fn decide(
+    config: &BufferingConfig,
+    header: impl Fn(&'static str) -> Option<String>,
+) -> Option<bool> {
+    match config.mode {
+        BufferingMode::Streaming => return Some(true),
+        BufferingMode::Buffered => return Some(false),
+        BufferingMode::Auto => {}
+    }
+    let length = header("content-length").and_then(|v| v.trim().parse::<u64>().ok());
+    let max_bytes = match config.max_bytes {
+        0 => tuning::get().stream_threshold_bytes,
+        n => n,
+    };
+    let large = max_bytes > 0 && length.is_some_and(|len| len > max_bytes);
+    let media = header("content-type").and_then(|v| media(&v));
+    let streamed_type = media.is_some_and(|media| {
+        matches(&config.stream_content_types, &media) || matches(&config.line_content_types, &media)
+    });
+    (large || streamed_type).then_some(true)
+}
+
+// Whether a streamed response of this Content-Type goes out line by line
+pub fn line_delimited(config: &BufferingConfig, content_type: Option<&str>) -> bool {
+    config.mode != BufferingMode::Buffered
+        && content_type
+            .and_then(media)
+            .is_some_and(|media| matches(&config.line_content_types, &media))
+}
+
+// Pass a body on in whole lines: each chunk ends with a newline, and a line
+// goes out as soon as its end arrives rather than waiting for more
+pub fn by_line<S>(body: S) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+where
+    S: Stream<Item = Result<Bytes, std::io::Error>> + Unpin,
+{
+    futures_util::stream::unfold(Some((body, Vec::new())), |state| async move {
+        let (mut body, mut partial) = state?;
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => {
+                    partial.extend_from_slice(&chunk);
+                    let end = match partial.iter().rposition(|&b| b == b'\n') {
+                        Some(newline) => newline + 1,
+                        None if partial.len() > MAX_LINE_BYTES => partial.len(),
+                        None => continue,
+                    };
+                    let rest = partial.split_off(end);
+                    let lines = std::mem::replace(&mut partial, rest);
+                    return Some((Ok(Bytes::from(lines)), Some((body, partial))));
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                // Whatever is left of an unterminated last line
+                None if partial.is_empty() => return None,
+                None => return Some((Ok(Bytes::from(partial)), None)),
+            }
+        }
+    })
+}
+
+pub fn mode_name(mode: BufferingMode) -> &'static str {
+    match mode {
+        BufferingMode::Auto => "auto",
+        BufferingMode::Buffered => "buffered",
+        BufferingMode::Streaming => "streaming",
+    }
+}
+
+// Count how a request or response body was handled
+pub fn record(route: &RouteConfig, direction: &str, streamed: bool) {
+    let handling = if streamed { "streamed" } else { "buffered" };
+    metrics::inc(
+        "body_handling_total",
+        &[
+            ("route", &route.name),
+            ("direction", direction),
+            ("handling", handling),
+        ],
+    );
+}
@@ -0,0 +1,336 @@
+use crate::config::{CacheConfig, CachePolicyConfig};
+use crate::memory::{Budget, Kind};
+use crate::metrics;
+use crate::ranges;
+use actix_web::web::Bytes;
+use reqwest::header::{self, HeaderMap};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// A response stored in the edge cache
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Bytes,
+    pub stored_at: Instant,
+    pub ttl: Duration,
+}
+
+impl CachedResponse {
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>()
+    }
+}
+
+pub enum Lookup {
+    Hit(CachedResponse),
+    Stale,
+    Miss,
+}
+
+struct Entry {
+    response: CachedResponse,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // Access order for LRU eviction: tick -> key
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    bytes_used: usize,
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    // Stored bytes count against the memory budget too
+    memory: Arc<Budget>,
+    inner: Mutex<Inner>,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig, memory: Arc<Budget>) -> Self {
+        metrics::set("cache_capacity_bytes", &[], config.max_bytes as f64);
+        metrics::set("cache_entries", &[], 0.0);
+        metrics::set("cache_bytes", &[], 0.0);
+
+        Cache {
+            config,
+            memory,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, route: &str, key: &str) -> Lookup {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let stale_window = Duration::from_secs(self.config.stale_secs);
+
+        let state = inner.entries.get(key).map(|entry| {
+            let age = entry.response.stored_at.elapsed();
+            (
+                age < entry.response.ttl,
+                age < entry.response.ttl + stale_window,
+            )
+        });
+
+        let result = match state {
+            Some((fresh, true)) => {
+                let entry = inner.entries.get_mut(key).unwrap();
+                let old = std::mem::replace(&mut entry.tick, tick);
+                let result = if fresh {
+                    Lookup::Hit(entry.response.clone())
+                } else {
+                    Lookup::Stale
+                };
+                inner.lru.remove(&old);
+                inner.lru.insert(tick, key.to_string());
+                result
+            }
+            Some(_) => {
+                self.remove(&mut inner, key);
+                metrics::inc("cache_evictions_total", &[("reason", "expired")]);
+                Lookup::Miss
+            }
+            None => Lookup::Miss,
+        };
+
+        let outcome = match result {
+            Lookup::Hit(_) => "hit",
+            Lookup::Stale => "stale",
+            Lookup::Miss => "miss",
+        };
+        metrics::inc(
+            "cache_requests_total",
+            &[("route", route), ("result", outcome)],
+        );
+        inner.report();
+
+        result
+    }
+
+    // A fresh entry, counted as a hit when there is one and not at all
+    // otherwise: for variants looked up ahead of the response itself
+    pub fn hit(&self, route: &str, key: &str) -> Option<CachedResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        if entry.response.stored_at.elapsed() >= entry.response.ttl {
+            return None;
+        }
+        let (old, response) = (entry.tick, entry.response.clone());
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some(entry) = inner.entries.get_mut(key) {
+            entry.tick = tick;
+        }
+        inner.lru.remove(&old);
+        inner.lru.insert(tick, key.to_string());
+        metrics::inc(
+            "cache_requests_total",
+            &[("route", route), ("result", "hit")],
+        );
+        Some(response)
+    }
+
+    // The stored 200 for the key, fresh or not, for when the upstream has
+    // failed. Not counted as a lookup.
+    pub fn last_good(&self, key: &str) -> Option<CachedResponse> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        (entry.response.status == 200).then(|| entry.response.clone())
+    }
+
+    // Length of the resource behind a fresh entry, a whole response or a
+    // chunk of one. Not counted as a lookup.
+    pub fn length(&self, key: &str) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        let response = &inner.entries.get(key)?.response;
+        if response.stored_at.elapsed() >= response.ttl {
+            return None;
+        }
+        match response.status {
+            200 => Some(response.body.len() as u64),
+            206 => ranges::chunk_total(response),
+            _ => None,
+        }
+    }
+
+    pub fn put(&self, key: String, response: CachedResponse) {
+        let size = response.size();
+        if size > self.config.max_entry_bytes || size > self.config.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        self.remove(&mut inner, &key);
+
+        // Evict least recently used entries until the new one fits
+        while inner.bytes_used + size > self.config.max_bytes {
+            let Some(oldest) = inner.oldest() else { break };
+            self.remove(&mut inner, &oldest);
+            metrics::inc("cache_evictions_total", &[("reason", "capacity")]);
+        }
+        // and then until the memory budget has room for it, which in-flight
+        // bodies get before the cache does
+        while !self.memory.try_add(Kind::Cache, size) {
+            let Some(oldest) = inner.oldest() else {
+                metrics::inc("memory_budget_rejected_total", &[("kind", "cache")]);
+                inner.report();
+                return;
+            };
+            self.remove(&mut inner, &oldest);
+            metrics::inc("cache_evictions_total", &[("reason", "memory")]);
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.lru.insert(tick, key.clone());
+        inner.bytes_used += size;
+        inner.entries.insert(key, Entry { response, tick });
+        inner.report();
+    }
+
+    // Drop the entries whose keys (upstream URL and path) start with the
+    // prefix; all of them for an empty one. Returns how many went.
+    pub fn purge(&self, prefix: &str) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<String> = (inner.entries.keys())
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in &keys {
+            self.remove(&mut inner, key);
+        }
+        metrics::add("cache_purged_total", &[], keys.len() as f64);
+        inner.report();
+        keys.len()
+    }
+
+    fn remove(&self, inner: &mut Inner, key: &str) {
+        if let Some(entry) = inner.entries.remove(key) {
+            let size = entry.response.size();
+            inner.lru.remove(&entry.tick);
+            inner.bytes_used -= size;
+            self.memory.release(Kind::Cache, size);
+        }
+    }
+}
+
+impl Inner {
+    fn oldest(&self) -> Option<String> {
+        self.lru.values().next().cloned()
+    }
+
+    fn report(&self) {
+        metrics::set("cache_entries", &[], self.entries.len() as f64);
+        metrics::set("cache_bytes", &[], self.bytes_used as f64);
+    }
+}
+
+// How long a 404/410, or a 5xx, may be cached for under the route's policy.
+// The upstream's max-age is ignored: these are kept briefly on purpose.
+// `private` is whether private responses may be kept, as they may in one
+// user's own entries.
+pub fn negative_ttl(
+    policy: &CachePolicyConfig,
+    status: u16,
+    headers: &HeaderMap,
+    private: bool,
+) -> Option<Duration> {
+    if !storable(headers) {
+        return None;
+    }
+    let cache_control = cache_control(headers);
+    let secs = match status {
+        404 | 410 => policy.negative_ttl_secs,
+        500..=599 => policy.error_ttl_secs,
+        _ => 0,
+    };
+    let refused = cache_control.is_some_and(|value| {
+        value.split(',').any(|d| {
+            let d = d.trim().to_ascii_lowercase();
+            (d == "private" && !private)
+                || ((d == "no-store" || d == "no-cache") && !policy.ignore_no_cache)
+        })
+    });
+    match secs {
+        0 => None,
+        _ if refused => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+// A response that sets a cookie is one client's, and one that varies on
+// more than the coding (which encoding::cache_key keeps apart) would be
+// served to clients it wasn't meant for, so neither is stored
+fn storable(headers: &HeaderMap) -> bool {
+    let vary = headers.get_all(header::VARY).iter();
+    !headers.contains_key(header::SET_COOKIE)
+        && vary
+            .flat_map(|value| value.to_str().unwrap_or("*").split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| name.eq_ignore_ascii_case("accept-encoding"))
+}
+
+fn cache_control(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::CACHE_CONTROL)?;
+    value.to_str().ok()
+}
+
+// Work out how long an upstream response may be cached for, if at all. The
+// default TTL is for responses without Cache-Control; one that has it but
+// gives no max-age isn't kept, unless the route forces a TTL.
+pub fn ttl_for(
+    policy: &CachePolicyConfig,
+    headers: &HeaderMap,
+    private: bool,
+    default_ttl: u64,
+) -> Option<Duration> {
+    if !storable(headers) {
+        return None;
+    }
+    let cache_control = cache_control(headers);
+    let mut ttl = None;
+
+    if let Some(value) = cache_control {
+        for directive in value.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+            if directive == "private" && !private {
+                return None;
+            }
+            if (directive == "no-store" || directive == "no-cache") && !policy.ignore_no_cache {
+                return None;
+            }
+            if let Some(secs) = directive
+                .strip_prefix("s-maxage=")
+                .or_else(|| directive.strip_prefix("max-age="))
+            {
+                if let Ok(secs) = secs.parse::<u64>() {
+                    // s-maxage takes precedence over max-age
+                    if directive.starts_with("s-maxage") || ttl.is_none() {
+                        ttl = Some(secs);
+                    }
+                }
+            }
+        }
+    }
+
+    let secs = match policy.force_ttl_secs {
+        Some(forced) => forced,
+        None if cache_control.is_some() => ttl.unwrap_or(0),
+        None => policy.default_ttl_secs.unwrap_or(default_ttl),
+    };
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::access_log;
+use crate::alerts;
+use crate::config::TlsConfig;
+use crate::metrics;
+use crate::tls;
+use crate::x509::{self, OcspStatus};
+use rustls::sign::CertifiedKey;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How often the served certificates are looked over for ones due a staple
+const STAPLE_TICK: Duration = Duration::from_secs(60);
+// A failed fetch is retried this much later
+const RETRY_SECS: i64 = 300;
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn iso8601(secs: i64) -> String {
+    access_log::iso8601(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+// Background checks on the listener's certificates: the days each has left
+// are exported and alerted on, and with ocsp_stapling their staples are
+// kept fresh. Certificates added by a reload are picked up as they appear.
+pub fn spawn(config: &TlsConfig) {
+    tokio::spawn(watch_expiry(config.clone()));
+    if config.ocsp_stapling {
+        tokio::spawn(keep_stapled(config.clone()));
+    }
+}
+
+async fn watch_expiry(config: TlsConfig) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.expiry_check_secs.max(60)));
+    // Once per certificate; a renewal brings a new expiry and so a new alert
+    // if it's short-lived too
+    let mut alerted = HashSet::new();
+    loop {
+        ticker.tick().await;
+        for (name, key) in tls::served() {
+            let Some(cert) = key.cert.first().and_then(|der| x509::parse(der)) else {
+                continue;
+            };
+            let days = (cert.not_after - now()) as f64 / 86400.0;
+            metrics::set(
+                "tls_certificate_expiry_days",
+                &[("certificate", &name)],
+                days,
+            );
+            if days > config.expiry_warn_days as f64
+                || !alerted.insert((name.clone(), cert.not_after))
+            {
+                continue;
+            }
+            let expires = iso8601(cert.not_after);
+            let message = match days < 0.0 {
+                true => format!("TLS certificate {} expired at {}", name, expires),
+                false => format!(
+                    "TLS certificate {} expires in {:.0} days, at {}",
+                    name,
+                    days.floor(),
+                    expires
+                ),
+            };
+            alerts::fire(
+                "certificate_expiring",
+                message,
+                json!({ "certificate": name, "not_after": expires, "days_left": days.floor() }),
+            );
+        }
+    }
+}
+
+enum Fetched {
+    Good(Vec<u8>, Option<i64>),
+    Revoked,
+    // No issuer in the chain or no responder named, so nothing to fetch
+    Unstapleable,
+    Failed(String),
+}
+
+async fn fetch(client: &reqwest::Client, key: &CertifiedKey) -> Fetched {
+    let parsed = (
+        key.cert.first().and_then(|der| x509::parse(der)),
+        key.cert.get(1).and_then(|der| x509::parse(der)),
+    );
+    let (Some(cert), Some(issuer)) = parsed else {
+        return Fetched::Unstapleable;
+    };
+    let Some(url) = cert.ocsp_url.clone() else {
+        return Fetched::Unstapleable;
+    };
+    let response = client
+        .post(&url)
+        .header("content-type", "application/ocsp-request")
+        .body(x509::ocsp_request(&cert, &issuer))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+    let body = match response {
+        Ok(resp) => match resp.bytes().await {
+            Ok(body) => body,
+            Err(e) => return Fetched::Failed(format!("{}: {}", url, e)),
+        },
+        Err(e) => return Fetched::Failed(format!("{}: {}", url, e)),
+    };
+    match x509::ocsp_status(&body, &cert, &issuer) {
+        Some(OcspStatus::Good { next_update }) => Fetched::Good(body.to_vec(), next_update),
+        Some(OcspStatus::Revoked) => Fetched::Revoked,
+        Some(OcspStatus::Unknown) => {
+            Fetched::Failed(format!("{} doesn't know the certificate", url))
+        }
+        None => Fetched::Failed(format!("{} sent an unusable response", url)),
+    }
+}
+
+async fn keep_stapled(config: TlsConfig) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap_or_default();
+    let refresh = config.ocsp_refresh_secs.max(60) as i64;
+    // When each certificate is next due, by name and the certificate it was
+    // due for, so a reloaded one is fetched for straight away
+    let mut due: HashMap<String, (Vec<u8>, i64)> = HashMap::new();
+    let mut ticker = tokio::time::interval(STAPLE_TICK);
+    loop {
+        ticker.tick().await;
+        let served = tls::served();
+        due.retain(|name, _| served.iter().any(|(served, _)| served == name));
+        for (name, key) in served {
+            let Some(leaf) = key.cert.first() else {
+                continue;
+            };
+            if due
+                .get(&name)
+                .is_some_and(|(cert, at)| cert.as_slice() == leaf.as_ref() && *at > now())
+            {
+                continue;
+            }
+            let (result, next) = match fetch(&client, &key).await {
+                Fetched::Good(ocsp, next_update) => {
+                    tls::staple(&name, &key, Some(ocsp));
+                    // Halfway to the response's own expiry if that's sooner
+                    let next = match next_update {
+                        Some(next_update) => (now() + refresh).min((now() + next_update) / 2),
+                        None => now() + refresh,
+                    };
+                    ("good", next.max(now() + 60))
+                }
+                Fetched::Revoked => {
+                    // A good staple from before mustn't outlive this
+                    tls::staple(&name, &key, None);
+                    alerts::fire(
+                        "certificate_revoked",
+                        format!("TLS certificate {} has been revoked", name),
+                        json!({ "certificate": name }),
+                    );
+                    ("revoked", now() + refresh)
+                }
+                Fetched::Unstapleable => {
+                    eprintln!(
+                        "Not stapling TLS certificate {}: it names no OCSP responder or its chain has no issuer",
+                        name
+                    );
+                    ("skipped", now() + refresh)
+                }
+                Fetched::Failed(e) => {
+                    eprintln!("Failed to fetch OCSP staple for {}: {}", name, e);
+                    ("error", now() + RETRY_SECS)
+                }
+            };
+            metrics::inc(
+                "ocsp_fetches_total",
+                &[("certificate", &name), ("result", result)],
+            );
+            due.insert(name, (leaf.to_vec(), next));
+        }
+    }
+}
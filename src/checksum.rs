@@ -0,0 +1,224 @@
+use crate::config::ChecksumConfig;
+use crate::metrics;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+use std::sync::OnceLock;
+
+// One digest algorithm. Adding another only takes an implementation and a
+// name in hasher().
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+impl Hasher for digest::Context {
+    fn update(&mut self, data: &[u8]) {
+        digest::Context::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> Vec<u8> {
+        digest::Context::finish(*self).as_ref().to_vec()
+    }
+}
+
+// Names as they appear in Digest and Content-Digest, lowercased
+pub const ALGORITHMS: &[&str] = &["md5", "sha", "sha-256", "sha-512"];
+
+pub fn hasher(algorithm: &str) -> Option<Box<dyn Hasher>> {
+    let context = |algorithm| Box::new(digest::Context::new(algorithm));
+    match algorithm {
+        "md5" => Some(Box::new(Md5::new())),
+        "sha" => Some(context(&digest::SHA1_FOR_LEGACY_USE_ONLY)),
+        "sha-256" => Some(context(&digest::SHA256)),
+        "sha-512" => Some(context(&digest::SHA512)),
+        _ => None,
+    }
+}
+
+struct Check {
+    algorithm: String,
+    // None when the header's value couldn't be decoded, which fails it
+    expected: Option<Vec<u8>>,
+    hasher: Box<dyn Hasher>,
+}
+
+// Checks a body against the digests its headers declare as it goes past
+pub struct Verifier {
+    direction: &'static str,
+    checks: Vec<Check>,
+}
+
+impl Verifier {
+    // None when there's nothing to check: checksums are off for this
+    // direction, or the message declares no digest we know
+    pub fn new(
+        config: Option<&ChecksumConfig>,
+        direction: &'static str,
+        header: impl Fn(&'static str) -> Option<String>,
+    ) -> Option<Self> {
+        let config = config?;
+        let enabled = match direction {
+            "request" => config.requests,
+            _ => config.responses,
+        };
+        if !enabled {
+            return None;
+        }
+
+        let mut declared = Vec::new();
+        if let Some(value) = header("content-md5") {
+            declared.push(("md5".to_string(), value));
+        }
+        // Digest (RFC 3230) is alg=base64, Content-Digest (RFC 9530) is
+        // alg=:base64:
+        for name in ["digest", "content-digest"] {
+            for item in header(name).iter().flat_map(|v| v.split(',')) {
+                if let Some((algorithm, value)) = item.split_once('=') {
+                    let value = value.trim().trim_matches(':');
+                    declared.push((algorithm.trim().to_ascii_lowercase(), value.to_string()));
+                }
+            }
+        }
+
+        let checks: Vec<Check> = declared
+            .into_iter()
+            .filter(|(algorithm, _)| config.algorithms.contains(algorithm))
+            .filter_map(|(algorithm, value)| {
+                Some(Check {
+                    hasher: hasher(&algorithm)?,
+                    expected: STANDARD.decode(value.trim()).ok(),
+                    algorithm,
+                })
+            })
+            .collect();
+        (!checks.is_empty()).then_some(Verifier { direction, checks })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for check in &mut self.checks {
+            check.hasher.update(data);
+        }
+    }
+
+    // Err with the algorithm of the first digest the body doesn't match
+    pub fn finish(self) -> Result<(), String> {
+        let mut failed = None;
+        for check in self.checks {
+            let labels = [
+                ("direction", self.direction),
+                ("algorithm", check.algorithm.as_str()),
+            ];
+            let actual = check.hasher.finish();
+            if check.expected.as_deref() == Some(actual.as_slice()) {
+                metrics::inc("checksums_verified_total", &labels);
+                continue;
+            }
+            metrics::inc("checksum_mismatches_total", &labels);
+            eprintln!(
+                "{} body doesn't match its {} digest",
+                self.direction, check.algorithm
+            );
+            failed.get_or_insert(check.algorithm);
+        }
+        match failed {
+            Some(algorithm) => Err(algorithm),
+            None => Ok(()),
+        }
+    }
+}
+
+// MD5 for Content-MD5, which ring leaves out (RFC 1321)
+struct Md5 {
+    state: [u32; 4],
+    // Bytes short of a whole block
+    pending: Vec<u8>,
+    length: u64,
+}
+
+const SHIFTS: [[u32; 4]; 4] = [
+    [7, 12, 17, 22],
+    [5, 9, 14, 20],
+    [4, 11, 16, 23],
+    [6, 10, 15, 21],
+];
+
+fn constants() -> &'static [u32; 64] {
+    static TABLE: OnceLock<[u32; 64]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0; 64];
+        for (i, k) in table.iter_mut().enumerate() {
+            *k = (((i + 1) as f64).sin().abs() * 4294967296.0) as u32;
+        }
+        table
+    })
+}
+
+impl Md5 {
+    fn new() -> Self {
+        Md5 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let k = constants();
+        let mut m = [0u32; 16];
+        for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[i / 16][i % 4]));
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+impl Hasher for Md5 {
+    fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+        if !self.pending.is_empty() {
+            let take = (64 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self: Box<Self>) -> Vec<u8> {
+        let bits = self.length.wrapping_mul(8);
+        // 0x80, then zeros up to 8 bytes short of a block
+        let mut padding = vec![0x80];
+        padding.resize(1 + ((119 - self.length % 64) % 64) as usize, 0);
+        padding.extend_from_slice(&bits.to_le_bytes());
+        self.update(&padding);
+        self.state
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+}
@@ -0,0 +1,249 @@
+use crate::access_log;
+use crate::buffering;
+use crate::config::{AuthenticatorConfig, Config, MiddlewareConfig, RouteConfig};
+use crate::credential_audit;
+use crate::middleware;
+use crate::routes;
+use crate::schedule;
+use crate::shedding;
+use crate::watermark::Watermarker;
+use actix_web::test::TestRequest;
+use std::io::Error;
+use std::time::{Duration, UNIX_EPOCH};
+
+const USAGE: &str =
+    "Usage: routes test <METHOD> <PATH> [-H 'Name: value']... [--body JSON] [--country CC]";
+
+fn upstream(config: &Config, upstream: &str) -> String {
+    match config.upstreams.get(upstream) {
+        Some(pool) => format!(
+            "{} (pool: {}; {})",
+            upstream,
+            pool.targets.join(", "),
+            pool.balancer
+        ),
+        None => upstream.to_string(),
+    }
+}
+
+fn print_route(config: &Config, route: &RouteConfig, path: &str, country: Option<&str>) {
+    println!("Route:      {} (prefix {})", route.name, route.prefix);
+    println!("Upstream:   {}", upstream(config, &route.upstream));
+    let mut regions: Vec<_> = route.regions.iter().collect();
+    regions.sort();
+    for (region, target) in regions {
+        println!("  region {}: {}", region, upstream(config, target));
+    }
+    let mut languages: Vec<_> = route.languages.iter().collect();
+    languages.sort();
+    for (language, target) in languages {
+        println!("  language {}: {}", language, upstream(config, target));
+    }
+    if let Some(blue_green) = &route.blue_green {
+        let mut groups: Vec<_> = blue_green.groups.iter().collect();
+        groups.sort();
+        for (group, target) in groups {
+            let live = if *group == blue_green.live {
+                " [live]"
+            } else {
+                ""
+            };
+            println!("  group {}: {}{}", group, upstream(config, target), live);
+        }
+    }
+    if let (Some(geo), Some(country)) = (&config.geo, country) {
+        let region = geo
+            .regions
+            .iter()
+            .find(|(_, codes)| codes.iter().any(|c| c.eq_ignore_ascii_case(country)))
+            .map_or(geo.default_region.as_str(), |(region, _)| region);
+        let target = route.regions.get(region).unwrap_or(&route.upstream);
+        println!(
+            "Country:    {} is in region {} -> {}",
+            country, region, target
+        );
+    }
+    let open = schedule::active(route).map(|(rule, _)| rule);
+    for rule in &route.schedule {
+        let mut when = Vec::new();
+        if let Some(start) = &rule.start {
+            when.push(format!("from {}", start));
+        }
+        if let Some(end) = &rule.end {
+            when.push(format!("until {}", end));
+        }
+        if let Some(cron) = &rule.cron {
+            when.push(format!("at {} for {}m", cron, rule.duration_mins));
+        }
+        let target = match &rule.upstream {
+            Some(target) => upstream(config, target),
+            None => "maintenance (503)".to_string(),
+        };
+        let now = if open.is_some_and(|open| std::ptr::eq(open, rule)) {
+            " [open now]"
+        } else {
+            ""
+        };
+        println!("  scheduled {}: {}{}", when.join(" "), target, now);
+    }
+    println!("Path:       {}", path);
+    if let Some(mock) = route.mock.as_ref().filter(|m| m.enabled) {
+        println!("Mock:       {} (the upstream isn't contacted)", mock.status);
+    }
+
+    let claims = route.auth.as_ref().is_some_and(|auth| {
+        matches!(
+            config.authenticators.get(auth),
+            Some(AuthenticatorConfig::Jwt(_))
+        )
+    });
+    let mut cached = route.cache;
+    let mut chain: Vec<String> = route
+        .auth
+        .iter()
+        .map(|auth| format!("auth:{}", auth))
+        .collect();
+    for (name, middleware) in
+        middleware::chain_for(&config.middlewares, route, claims, config.quota.is_some())
+    {
+        cached |= matches!(middleware, MiddlewareConfig::Cache);
+        let kind = serde_json::to_value(middleware)
+            .ok()
+            .and_then(|v| v["type"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        chain.push(match kind == name {
+            true => name.to_string(),
+            false => format!("{} ({})", name, kind),
+        });
+    }
+    match chain.is_empty() {
+        true => println!("Chain:      (none)"),
+        false => println!("Chain:      {}", chain.join(" -> ")),
+    }
+    let cached = cached && !route.cache_policy.never;
+    println!("Cache:      {}", if cached { "yes" } else { "no" });
+    println!("Buffering:  {}", buffering::mode_name(route.buffering.mode));
+    if config.load_shedding.is_some() {
+        println!("Priority:   {}", shedding::priority_name(route.priority));
+    }
+}
+
+// Print the route, upstream and middleware chain a request would get, so
+// routing changes can be checked before they're deployed. The config has
+// already been loaded and validated by the time this runs.
+pub fn routes_test(config: &Config, table: &[RouteConfig], args: &[String]) -> Result<(), Error> {
+    let (method, target) = match args {
+        [method, target, ..] => (method, target),
+        _ => return Err(Error::other(USAGE)),
+    };
+    let mut req = TestRequest::default()
+        .method(
+            method
+                .to_ascii_uppercase()
+                .parse()
+                .map_err(|_| Error::other(format!("Invalid method {}", method)))?,
+        )
+        .uri(target);
+    let mut body = None;
+    let mut country = None;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next().ok_or_else(|| Error::other(USAGE))?;
+        match arg.as_str() {
+            "-H" | "--header" => {
+                let (name, value) = value
+                    .split_once(':')
+                    .ok_or_else(|| Error::other(format!("Invalid header {}", value)))?;
+                req = req.insert_header((name.trim(), value.trim()));
+            }
+            "--body" => {
+                body = Some(
+                    serde_json::from_str(value)
+                        .map_err(|e| Error::other(format!("Invalid JSON body: {}", e)))?,
+                );
+            }
+            "--country" => country = Some(value.as_str()),
+            _ => return Err(Error::other(USAGE)),
+        }
+    }
+    let req = req.to_http_request();
+
+    match routes::route_for_body(table, &req, body.as_ref()) {
+        Some((route, path)) => {
+            print_route(config, route, &path, country);
+            if !routes::method_allowed(route, &req) {
+                println!(
+                    "Refused:    405, the route allows {}",
+                    route.allowed_methods.join(", ")
+                );
+            }
+            Ok(())
+        }
+        None => {
+            if body.is_none() && routes::inspects_body(table, &req) {
+                println!("Routes match on body fields here; try again with --body");
+            }
+            Err(Error::other(format!("No route for {} {}", method, target)))
+        }
+    }
+}
+
+// Open a watermark with each watermark middleware's secret in turn
+pub fn watermark_decode(config: &Config, mark: &str) -> Result<(), Error> {
+    let mut names: Vec<_> = config
+        .middlewares
+        .iter()
+        .filter_map(|(name, middleware)| match middleware {
+            MiddlewareConfig::Watermark(watermark) => Some((name, watermark)),
+            _ => None,
+        })
+        .collect();
+    if names.is_empty() {
+        return Err(Error::other("No watermark middleware is configured"));
+    }
+    names.sort_by_key(|(name, _)| name.as_str());
+    for (name, watermark) in names {
+        let Some(origin) = Watermarker::load(name, watermark)?.open(mark) else {
+            continue;
+        };
+        let issued = UNIX_EPOCH + Duration::from_millis(origin.issued_ms);
+        println!("Middleware: {}", name);
+        println!("User:       {}", origin.user);
+        println!("Issued:     {}", access_log::iso8601(issued));
+        println!("Route:      {}", origin.route);
+        println!("Client:     {}", origin.client_ip);
+        return Ok(());
+    }
+    Err(Error::other(
+        "Not a watermark made with any configured middleware's secret",
+    ))
+}
+
+// Check the credential audit log at the path given, or the configured one
+pub fn credential_audit_verify(config: &Config, path: Option<&String>) -> Result<(), Error> {
+    let path = match (path, &config.credential_audit) {
+        (Some(path), _) => path.clone(),
+        (None, Some(audit)) => audit.path.clone(),
+        (None, None) => return Err(Error::other("No [credential_audit] is configured")),
+    };
+    match credential_audit::verify(&path) {
+        Ok(count) => {
+            println!("{}: {} entries, chain intact", path, count);
+            Ok(())
+        }
+        Err(e) => Err(Error::other(format!("{}: {}", path, e))),
+    }
+}
+
+// JSON Schema for config.toml, or with `route` for a single route as the
+// admin API takes it. It's made from the config structs, so it follows them.
+pub fn config_schema(part: Option<&String>) -> Result<(), Error> {
+    let schema = match part.map(String::as_str) {
+        None => schemars::schema_for!(Config),
+        Some("route") => schemars::schema_for!(RouteConfig),
+        Some(_) => return Err(Error::other("Usage: config schema [route]")),
+    };
+    let json = serde_json::to_string_pretty(&schema).map_err(Error::other)?;
+    println!("{}", json);
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use crate::config::ClientIpConfig;
+use crate::proxy_protocol;
+use actix_web::http::header::HeaderName;
+use actix_web::{HttpMessage, HttpRequest};
+use ipnet::IpNet;
+use std::io::Error;
+use std::net::IpAddr;
+
+// An address or CIDR range, as trusted sources are given in the config
+pub fn network(source: &str) -> Option<IpNet> {
+    source
+        .parse::<IpNet>()
+        .or_else(|_| source.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+// Headers that hold every hop, the client first
+const LISTS: &[&str] = &["x-forwarded-for", "forwarded"];
+
+// The address rate limits, geo routing and the logs go by. Headers naming
+// the client are only believed when the connection comes from one of the
+// trusted proxies, and lists of hops only as far back as the first address
+// that isn't one of them.
+pub struct Resolver {
+    config: Option<(Vec<IpNet>, Vec<HeaderName>)>,
+}
+
+// What resolve() settled on, for the access log
+struct Resolved(String);
+
+impl Resolver {
+    pub fn new(config: Option<&ClientIpConfig>) -> Result<Self, Error> {
+        let Some(config) = config else {
+            return Ok(Resolver { config: None });
+        };
+        let trusted = config
+            .trusted_proxies
+            .iter()
+            .map(|source| {
+                network(source).ok_or_else(|| {
+                    Error::other(format!("Invalid client_ip trusted proxy {}", source))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|name| {
+                HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes())
+                    .map_err(|_| Error::other(format!("Invalid client_ip header {}", name)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Resolver {
+            config: Some((trusted, headers)),
+        })
+    }
+
+    pub fn resolve(&self, req: &HttpRequest) -> Option<String> {
+        let ip = match &self.config {
+            Some((trusted, headers)) => from_headers(req, trusted, headers),
+            None => proxy_protocol::client_ip(req),
+        }?;
+        req.extensions_mut().insert(Resolved(ip.clone()));
+        Some(ip)
+    }
+}
+
+fn from_headers(req: &HttpRequest, trusted: &[IpNet], headers: &[HeaderName]) -> Option<String> {
+    let trusts = |ip: &IpAddr| trusted.iter().any(|network| network.contains(ip));
+    let peer = req.peer_addr()?.ip().to_canonical();
+    if !trusts(&peer) {
+        return Some(peer.to_string());
+    }
+    for name in headers {
+        let values = req.headers().get_all(name).filter_map(|v| v.to_str().ok());
+        if !LISTS.contains(&name.as_str()) {
+            if let Some(ip) = values.into_iter().next().and_then(parse) {
+                return Some(ip.to_string());
+            }
+            continue;
+        }
+        let hops: Vec<&str> = values
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| match name.as_str() {
+                "forwarded" => hop
+                    .split(';')
+                    .find_map(|pair| pair.trim().strip_prefix("for=")),
+                _ => Some(hop),
+            })
+            .collect();
+        // The nearest hop that isn't ours; a hop we can't read ends the
+        // walk, since anything before it can't be vouched for
+        let mut client = None;
+        for hop in hops.iter().rev() {
+            match parse(hop) {
+                Some(ip) => {
+                    client = Some(ip);
+                    if !trusts(&ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        if let Some(ip) = client {
+            return Some(ip.to_string());
+        }
+    }
+    Some(peer.to_string())
+}
+
+// 192.0.2.1, 192.0.2.1:4711, 2001:db8::1, [2001:db8::1]:4711, or any of
+// them quoted as in Forwarded
+fn parse(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    let host = match value.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => value.rsplit_once(':')?.0,
+    };
+    host.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+// The client address the request was handled under
+pub fn resolved(req: &HttpRequest) -> Option<String> {
+    if let Some(Resolved(ip)) = req.extensions().get::<Resolved>() {
+        return Some(ip.clone());
+    }
+    proxy_protocol::client_ip(req)
+}
@@ -0,0 +1,380 @@
+use crate::config::{ClusterConfig, RouteConfig};
+use crate::events::{self, Event};
+use crate::keys;
+use crate::metrics;
+use crate::proxy::AppState;
+use actix_web::web;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// Keeping the instances of one deployment consistent ([cluster]): a cache
+// purge or route change made through one instance's admin API is
+// published on a NATS subject, and every other instance makes it too.
+// Route changes carry the whole table, so an instance that missed one
+// catches up on the next. Changes made while the NATS server is away go
+// out once it's back, up to QUEUE of them.
+
+const QUEUE: usize = 256;
+
+// The largest message we'll read off the subject; route tables are the big
+// ones, and well under this
+const MAX_PAYLOAD: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update {
+    CachePurge { prefix: String },
+    Routes { routes: Vec<RouteConfig> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    // The instance the change was made on
+    origin: String,
+    #[serde(flatten)]
+    update: Update,
+}
+
+pub struct Cluster {
+    config: ClusterConfig,
+    password: String,
+    // Random per process, to tell our own messages apart
+    id: String,
+    sender: mpsc::Sender<Vec<u8>>,
+    // Taken by the connection task
+    receiver: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn count(direction: &str, result: &str) {
+    metrics::inc(
+        "cluster_messages_total",
+        &[("direction", direction), ("result", result)],
+    );
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> Result<Self, Error> {
+        let password = match config.password.strip_prefix("env:") {
+            Some(var) => env::var(var).map_err(|_| {
+                Error::other(format!(
+                    "[cluster] password needs the {} environment variable",
+                    var
+                ))
+            })?,
+            None => config.password.clone(),
+        };
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        Ok(Cluster {
+            config: config.clone(),
+            password,
+            id: keys::hex(&rand::thread_rng().gen::<[u8; 8]>()),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        })
+    }
+
+    // Share a change made here with the other instances
+    pub fn publish(&self, update: Update) {
+        let message = Message {
+            origin: self.id.clone(),
+            update,
+        };
+        let payload = serde_json::to_vec(&message).unwrap_or_default();
+        if self.sender.try_send(payload).is_err() {
+            eprintln!("Cluster: too many changes waiting for the NATS server; dropping one");
+            count("out", "dropped");
+        }
+    }
+
+    // One connection to the server, until it fails or closes
+    async fn session(
+        &self,
+        state: &AppState,
+        outgoing: &mut mpsc::Receiver<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let address = self.config.url.trim_start_matches("nats://");
+        let (read, mut write) = TcpStream::connect(address).await?.into_split();
+        let mut read = BufReader::new(read);
+        let mut line = Vec::new();
+        read.read_until(b'\n', &mut line).await?;
+        if !line.starts_with(b"INFO ") {
+            return Err(invalid("not a NATS server"));
+        }
+        line.clear();
+
+        // No echo: our own messages aren't sent back to us
+        let mut connect = json!({
+            "verbose": false,
+            "pedantic": false,
+            "echo": false,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "name": format!("netty-server {}", self.id),
+            "protocol": 1,
+        });
+        if !self.config.username.is_empty() {
+            connect["user"] = json!(self.config.username);
+            connect["pass"] = json!(self.password);
+        }
+        let hello = format!(
+            "CONNECT {}\r\nSUB {} 1\r\nPING\r\n",
+            connect, self.config.subject
+        );
+        write.write_all(hello.as_bytes()).await?;
+
+        loop {
+            tokio::select! {
+                read_result = read.read_until(b'\n', &mut line) => {
+                    if read_result? == 0 {
+                        return Ok(());
+                    }
+                    let command = String::from_utf8_lossy(&line).trim_end().to_string();
+                    line.clear();
+                    self.command(state, &mut read, &mut write, &command).await?;
+                }
+                Some(payload) = outgoing.recv() => {
+                    write.write_all(&publication(&self.config.subject, &payload)).await?;
+                    count("out", "sent");
+                }
+            }
+        }
+    }
+
+    async fn command(
+        &self,
+        state: &AppState,
+        read: &mut BufReader<OwnedReadHalf>,
+        write: &mut OwnedWriteHalf,
+        command: &str,
+    ) -> Result<(), Error> {
+        match command.split_whitespace().next().unwrap_or_default() {
+            "PING" => write.write_all(b"PONG\r\n").await?,
+            "PONG" => {
+                // The answer to our first PING: CONNECT and SUB were taken
+                println!(
+                    "Cluster: connected to {} on subject {}",
+                    self.config.url, self.config.subject
+                );
+                metrics::set("cluster_connected", &[], 1.0);
+            }
+            "-ERR" => {
+                return Err(Error::other(format!(
+                    "NATS server: {}",
+                    command.trim_start_matches("-ERR").trim()
+                )))
+            }
+            "MSG" => {
+                let payload = read_payload(read, message_len(command)?).await?;
+                self.apply(state, &payload);
+            }
+            // INFO updates and +OK
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Make a change another instance published
+    fn apply(&self, state: &AppState, payload: &[u8]) {
+        let message: Message = match serde_json::from_slice(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Cluster: ignoring an unreadable message: {}", e);
+                count("in", "invalid");
+                return;
+            }
+        };
+        if message.origin == self.id {
+            return;
+        }
+        let origin = message.origin;
+        match message.update {
+            Update::CachePurge { prefix } => {
+                let purged = state.cache.purge(&prefix);
+                println!(
+                    "Cluster: purged {} cache entries under \"{}\" for instance {}",
+                    purged, prefix, origin
+                );
+            }
+            Update::Routes { routes } => {
+                let result = state.routes.update(&state.config, |table| {
+                    *table = routes;
+                    Ok(())
+                });
+                match result {
+                    Ok(_) => {
+                        println!("Cluster: took the route table from instance {}", origin);
+                        events::publish(Event::RouteChange {
+                            action: "synced",
+                            route: "*".to_string(),
+                        });
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Cluster: the route table from instance {} was refused: {}",
+                            origin, e
+                        );
+                        count("in", "refused");
+                        return;
+                    }
+                }
+            }
+        }
+        count("in", "applied");
+    }
+}
+
+// PUB <subject> <bytes>, then the payload
+fn publication(subject: &str, payload: &[u8]) -> Vec<u8> {
+    let mut frame = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+// The payload size from MSG <subject> <sid> [reply-to] <bytes>
+fn message_len(command: &str) -> Result<usize, Error> {
+    let parts: Vec<&str> = command.split_whitespace().collect();
+    if !(4..=5).contains(&parts.len()) {
+        return Err(invalid("malformed MSG"));
+    }
+    let len: usize = (parts[parts.len() - 1].parse()).map_err(|_| invalid("malformed MSG"))?;
+    if len > MAX_PAYLOAD {
+        return Err(invalid("MSG payload too large"));
+    }
+    Ok(len)
+}
+
+// The payload following a MSG, and the CRLF that ends it
+async fn read_payload<R: AsyncRead + Unpin>(read: &mut R, len: usize) -> Result<Vec<u8>, Error> {
+    let mut payload = vec![0u8; len + 2];
+    read.read_exact(&mut payload).await?;
+    if !payload.ends_with(b"\r\n") {
+        return Err(invalid("MSG payload not followed by CRLF"));
+    }
+    payload.truncate(len);
+    Ok(payload)
+}
+
+// Keep a connection to the NATS server, reconnecting while it's away
+pub fn spawn(state: web::Data<AppState>) {
+    let Some(cluster) = &state.cluster else {
+        return;
+    };
+    let Some(mut outgoing) = cluster.receiver.lock().unwrap().take() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let Some(cluster) = &state.cluster else {
+            return;
+        };
+        let wait = Duration::from_millis(cluster.config.reconnect_ms.max(100));
+        loop {
+            match cluster.session(&state, &mut outgoing).await {
+                Ok(()) => eprintln!("Cluster: the NATS server closed the connection"),
+                Err(e) => eprintln!("Cluster: NATS connection to {}: {}", cluster.config.url, e),
+            }
+            metrics::set("cluster_connected", &[], 0.0);
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+// Share the route table after it was edited here
+pub fn routes_changed(state: &AppState) {
+    if let Some(cluster) = &state.cluster {
+        let routes = state.routes.snapshot().to_vec();
+        cluster.publish(Update::Routes { routes });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_publications() {
+        assert_eq!(
+            publication("netty.cluster", b"{}"),
+            b"PUB netty.cluster 2\r\n{}\r\n"
+        );
+        assert_eq!(publication("s", b""), b"PUB s 0\r\n\r\n");
+    }
+
+    #[test]
+    fn reads_message_lengths() {
+        assert_eq!(message_len("MSG netty.cluster 1 12").unwrap(), 12);
+        assert_eq!(message_len("MSG netty.cluster 1 _INBOX.x 0").unwrap(), 0);
+        for command in [
+            "MSG",
+            "MSG 12",
+            "MSG netty.cluster 12",
+            "MSG netty.cluster 1 a b 12",
+            "MSG netty.cluster 1 twelve",
+            "MSG netty.cluster 1 -1",
+            "MSG netty.cluster 1 99999999999999999999999",
+        ] {
+            let e = message_len(command).unwrap_err();
+            assert_eq!(e.to_string(), "malformed MSG", "{}", command);
+        }
+
+        let command = format!("MSG netty.cluster 1 {}", MAX_PAYLOAD + 1);
+        let e = message_len(&command).unwrap_err();
+        assert_eq!(e.to_string(), "MSG payload too large");
+        let command = format!("MSG netty.cluster 1 {}", usize::MAX);
+        assert!(message_len(&command).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_payloads() {
+        let mut read: &[u8] = b"hello\r\nPING\r\n";
+        assert_eq!(read_payload(&mut read, 5).await.unwrap(), b"hello");
+        assert_eq!(read, b"PING\r\n");
+
+        let mut read: &[u8] = b"\r\n";
+        assert_eq!(read_payload(&mut read, 0).await.unwrap(), b"");
+
+        // Cut short, or not where the length said it would end
+        let mut read: &[u8] = b"hel";
+        let e = read_payload(&mut read, 5).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        let mut read: &[u8] = b"hello\r\n";
+        let e = read_payload(&mut read, 4).await.unwrap_err();
+        assert_eq!(e.to_string(), "MSG payload not followed by CRLF");
+    }
+
+    #[test]
+    fn shares_updates_as_json() {
+        let message = Message {
+            origin: "a1".to_string(),
+            update: Update::CachePurge {
+                prefix: "/api".to_string(),
+            },
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(
+            json,
+            json!({"origin": "a1", "type": "cache_purge", "prefix": "/api"})
+        );
+
+        let payload = br#"{"origin":"b2","type":"routes","routes":[]}"#;
+        let message: Message = serde_json::from_slice(payload).unwrap();
+        assert_eq!(message.origin, "b2");
+        assert!(matches!(message.update, Update::Routes { routes } if routes.is_empty()));
+
+        let payload = br#"{"origin":"b2","type":"restart"}"#;
+        assert!(serde_json::from_slice::<Message>(payload).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::{ComplianceAction, HeaderPolicyConfig, RouteConfig};
+use crate::metrics;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+
+// Checks on the headers of upstream responses, so a backend that stops
+// sending Cache-Control or starts announcing its framework is noticed at
+// the edge instead of by a scanner
+
+// Required by `security_headers`, and what `fix` adds
+const SECURITY_HEADERS: [(&str, &str); 4] = [
+    ("strict-transport-security", "max-age=31536000"),
+    ("x-content-type-options", "nosniff"),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "strict-origin-when-cross-origin"),
+];
+
+// A policy's header names and values, checked when the config is loaded
+pub fn check(policy: &HeaderPolicyConfig) -> Result<(), String> {
+    for (name, value) in &policy.required {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid required header {}", name))?;
+        HeaderValue::from_str(value)
+            .map_err(|_| format!("invalid value for required header {}", name))?;
+    }
+    for name in &policy.forbidden {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid forbidden header {}", name))?;
+    }
+    Ok(())
+}
+
+// Every header the policy requires and the value that fixes its absence
+fn required(policy: &HeaderPolicyConfig) -> Vec<(HeaderName, Option<HeaderValue>)> {
+    let security = SECURITY_HEADERS
+        .iter()
+        .filter(|_| policy.security_headers)
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    let mut out: Vec<(HeaderName, Option<HeaderValue>)> = Vec::new();
+    // Configured values take the place of the security defaults
+    for (name, value) in policy.required.clone().into_iter().chain(security) {
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        if out.iter().any(|(known, _)| *known == name) {
+            continue;
+        }
+        let value = HeaderValue::from_str(&value).ok().filter(|v| !v.is_empty());
+        out.push((name, value));
+    }
+    out
+}
+
+fn violation(route: &RouteConfig, header: &str, kind: &str, action: &str) {
+    metrics::inc(
+        "header_policy_violations_total",
+        &[
+            ("route", &route.name),
+            ("header", header),
+            ("kind", kind),
+            ("action", action),
+        ],
+    );
+}
+
+// Apply the route's (or the global) header policy to a response. 304s are
+// left alone, as they only repeat some of the headers of what they stand
+// for.
+pub fn enforce(
+    global: Option<&HeaderPolicyConfig>,
+    route: &RouteConfig,
+    mut response: HttpResponse,
+) -> HttpResponse {
+    let policy = match route.header_policy.as_ref().or(global) {
+        Some(policy) => policy,
+        None => return response,
+    };
+    if response.status().as_u16() == 304 {
+        return response;
+    }
+    let action = match policy.action {
+        ComplianceAction::Log => "log",
+        ComplianceAction::Fix => "fix",
+        ComplianceAction::Fail => "fail",
+    };
+
+    let mut problems = Vec::new();
+    let mut unfixed = false;
+    for (name, fix) in required(policy) {
+        if response.headers().contains_key(&name) {
+            continue;
+        }
+        let outcome = match (policy.action, fix) {
+            (ComplianceAction::Fix, Some(value)) => {
+                response.headers_mut().insert(name.clone(), value);
+                "fix"
+            }
+            (ComplianceAction::Fix, None) => {
+                unfixed = true;
+                "log"
+            }
+            _ => action,
+        };
+        violation(route, name.as_str(), "missing", outcome);
+        problems.push(format!("missing {}", name));
+    }
+    for name in &policy.forbidden {
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        if !response.headers().contains_key(&name) {
+            continue;
+        }
+        if policy.action == ComplianceAction::Fix {
+            response.headers_mut().remove(&name);
+        }
+        violation(route, name.as_str(), "forbidden", action);
+        problems.push(format!("has {}", name));
+    }
+    if problems.is_empty() {
+        return response;
+    }
+
+    let problems = problems.join(", ");
+    match policy.action {
+        ComplianceAction::Fail => {
+            println!(
+                "Route {} refused a {} response failing the header policy: {}",
+                route.name,
+                response.status().as_u16(),
+                problems
+            );
+            HttpResponse::BadGateway().body("Upstream response failed the header policy")
+        }
+        ComplianceAction::Fix if !unfixed => {
+            println!(
+                "Route {} response fixed for the header policy: {}",
+                route.name, problems
+            );
+            response
+        }
+        _ => {
+            println!(
+                "Route {} response breaks the header policy: {}",
+                route.name, problems
+            );
+            response
+        }
+    }
+}
@@ -0,0 +1,3920 @@
+use crate::bind;
+use crate::checksum;
+use crate::compliance;
+use crate::egress_policy::Policy;
+use crate::pinning;
+use crate::schedule::Window;
+use crate::store::Store;
+use crate::upstream_url::{self, Var};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::net::IpAddr;
+use std::path::Path;
+
+// Top-level proxy configuration, loaded from a TOML file (CONFIG_PATH, default
+// config.toml) with the legacy environment variables layered on top
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct Config {
+    // "production" unless set otherwise (or by ENVIRONMENT); some unsafe
+    // settings are refused in production
+    pub environment: String,
+    pub server: ServerConfig,
+    // Serve the public port over TLS
+    pub tls: Option<TlsConfig>,
+    // Take client addresses from the PROXY protocol header a TCP load
+    // balancer sends ahead of each connection to the public port
+    pub proxy_protocol: Option<ProxyProtocolConfig>,
+    // Which headers name the client, and which proxies are believed.
+    // Without it no header is, and the client is the connection's peer.
+    pub client_ip: Option<ClientIpConfig>,
+    // CONNECT tunnels to a few hosts, for QA tools using the gateway as a
+    // forward proxy, see tunnel.rs
+    pub forward_proxy: Option<ForwardProxyConfig>,
+    // Experimental HTTP/3 listener; needs [tls] and the http3 feature
+    pub http3: Option<Http3Config>,
+    // Cleartext HTTP/2 listener for native gRPC and trailers routes, see grpc.rs
+    pub grpc: Option<GrpcConfig>,
+    // Advertise an HTTP/3 endpoint to browsers
+    pub alt_svc: Option<AltSvcConfig>,
+    pub access_log: Option<AccessLogConfig>,
+    // Hash-chained log of the secret headers sent upstream, see
+    // credential_audit.rs
+    pub credential_audit: Option<CredentialAuditConfig>,
+    // An access event per request published to a Kafka topic, see kafka.rs
+    pub kafka: Option<KafkaConfig>,
+    // Cache purges and route changes shared with the other instances over
+    // NATS, see cluster.rs
+    pub cluster: Option<ClusterConfig>,
+    // Which requests get logged
+    pub sampling: SamplingConfig,
+    // Headers masked wherever requests are logged
+    pub redaction: RedactionConfig,
+    pub cache: CacheConfig,
+    pub jwt: Option<JwtConfig>,
+    // Named authenticators that routes pick with `auth`
+    pub authenticators: HashMap<String, AuthenticatorConfig>,
+    pub quota: Option<QuotaConfig>,
+    // Concurrent playback session caps, enforced by the streams middleware
+    pub streams: Option<StreamsConfig>,
+    // Scraper and bot heuristics applied before routing
+    pub bots: Option<BotsConfig>,
+    // Country lookup for routes with per-region upstreams
+    pub geo: Option<GeoConfig>,
+    // Connect to the upstreams before reporting ready
+    pub warmup: Option<WarmupConfig>,
+    // Probes sent through the proxy by `--self-test` before the public port
+    // is bound, see selftest.rs
+    pub self_test: SelfTestConfig,
+    // Record upstream responses to cassettes, or serve them back offline
+    pub vcr: Option<VcrConfig>,
+    pub metering: Option<MeteringConfig>,
+    // A summary of traffic, errors, slow routes and the cache, made on a
+    // schedule, see report.rs
+    pub report: Option<ReportConfig>,
+    pub cors: CorsConfig,
+    // robots.txt, security.txt and static assets, answered without the
+    // gateway
+    pub edge_files: EdgeFilesConfig,
+    // Scanner probes and the like, refused before anything else looks at
+    // the request, see blocklist.rs
+    pub block_rules: Vec<BlockRule>,
+    // Answered at the edge before any route is looked at; the first rule
+    // that matches applies
+    pub redirects: Vec<RedirectRule>,
+    // Trailing slash and case policy for request paths once a route has
+    // matched, unless the route has its own
+    pub normalize: Option<NormalizeConfig>,
+    // Headers upstream responses must and mustn't have, unless the route
+    // has its own policy, see compliance.rs
+    pub header_policy: Option<HeaderPolicyConfig>,
+    // Session, device and correlation IDs added to every forwarded request,
+    // see context.rs
+    pub context: Option<ContextConfig>,
+    // The only hosts and networks requests are forwarded to, see
+    // egress_policy.rs
+    pub egress: Option<EgressConfig>,
+    pub tenancy: TenancyConfig,
+    pub tenants: HashMap<String, TenantConfig>,
+    pub admin: AdminConfig,
+    // Shared state for the features that keep it, see kv.rs
+    pub kv: KvConfig,
+    // Where alerts about the gateway itself (expiring certificates and the
+    // like) are posted
+    pub alerts: AlertsConfig,
+    // Pid and log files when started with --daemon
+    pub daemon: DaemonConfig,
+    // Zero-downtime upgrades outside Kubernetes, see handoff.rs
+    pub handoff: Option<HandoffConfig>,
+    // Worker, connection, pool and compression settings, see tuning.rs
+    pub tuning: TuningConfig,
+    // Latency and availability objectives per route, alerted on when their
+    // error budget burns too fast, see slo.rs
+    pub slos: Vec<SloConfig>,
+    // Client-facing responses keyed by error kind (connect, timeout,
+    // deadline, tls, body_too_large, body_checksum, response_checksum,
+    // memory_budget, upstream_5xx, egress_refused, other) or by upstream
+    // status code ("502")
+    pub errors: HashMap<String, ErrorResponseConfig>,
+    pub retry_budget: RetryBudgetConfig,
+    pub load_shedding: Option<LoadSheddingConfig>,
+    pub checksums: Option<ChecksumConfig>,
+    pub dns: DnsConfig,
+    // Named pools of upstream replicas that routes can refer to
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    pub middlewares: HashMap<String, MiddlewareConfig>,
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub port: u16,
+    pub admin_port: u16,
+    // IP addresses the public and admin listeners bind, v4 or v6, see
+    // bind.rs
+    pub bind_addresses: Vec<String>,
+    pub admin_bind_addresses: Vec<String>,
+    pub upstream_timeout_secs: u64,
+    pub max_request_body_bytes: usize,
+    // Upstream responses larger than this are rejected with a 502, or cut
+    // off when streamed (0 = no limit). Routes can override it.
+    pub max_response_body_bytes: usize,
+    // Routes with body predicates only look at bodies up to this size
+    pub routing_body_bytes: usize,
+    // Soft cap on request and response bodies held in memory plus the cache
+    // (0 = no cap). Bodies that would go over it are refused with a 503, and
+    // the cache gives up entries to make room.
+    pub memory_budget_bytes: usize,
+    // Request smuggling and malformed framing checks
+    pub framing: FramingConfig,
+    // Clients must send the complete request head within this time
+    pub request_header_timeout_ms: u64,
+    // After the grace period, request bodies must keep arriving at this
+    // average rate or the connection is dropped (0 disables the check)
+    pub min_body_bytes_per_sec: u64,
+    pub body_grace_secs: u64,
+    // Send a Server-Timing header with every response. Requests logged in
+    // full through the sampling debug header get one regardless.
+    pub server_timing: bool,
+    // Drop a request as soon as its client hangs up, cancelling the upstream
+    // request with it, rather than waiting for the response to fail to
+    // write. Clients that half-close their side after sending lose their
+    // responses.
+    pub cancel_on_disconnect: bool,
+    // Time budget for a whole request, from its arrival to the upstream's
+    // response, that retries, hedges and upstream attempts share; upstreams
+    // are told it (0 = none). Routes can override it.
+    pub request_deadline_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            port: 8080,
+            admin_port: 9090,
+            bind_addresses: vec!["0.0.0.0".to_string()],
+            admin_bind_addresses: vec!["0.0.0.0".to_string()],
+            upstream_timeout_secs: 60,
+            max_request_body_bytes: 10 * 1024 * 1024,
+            max_response_body_bytes: 0,
+            routing_body_bytes: 64 * 1024,
+            memory_budget_bytes: 0,
+            request_header_timeout_ms: 5000,
+            min_body_bytes_per_sec: 1024,
+            body_grace_secs: 10,
+            server_timing: false,
+            cancel_on_disconnect: true,
+            request_deadline_ms: 0,
+            framing: FramingConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct FramingConfig {
+    pub strictness: Strictness,
+    pub max_headers: usize,
+    // Name plus value of a single header, and of all of them together
+    pub max_header_bytes: usize,
+    pub max_total_header_bytes: usize,
+    // Cookies across all Cookie headers (0 = no limit)
+    pub max_cookies: usize,
+    // What to do when one of single_headers is sent more than once
+    pub duplicates: DuplicatePolicy,
+    pub single_headers: Vec<String>,
+}
+
+impl Default for FramingConfig {
+    fn default() -> Self {
+        FramingConfig {
+            strictness: Strictness::Lenient,
+            max_headers: 64,
+            max_header_bytes: 8 * 1024,
+            max_total_header_bytes: 32 * 1024,
+            max_cookies: 128,
+            duplicates: DuplicatePolicy::Reject,
+            single_headers: [
+                "authorization",
+                "proxy-authorization",
+                "host",
+                "content-type",
+                "user-agent",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    // Forward every copy, as sent
+    Keep,
+    // Drop the others before anything looks at the request
+    First,
+    Last,
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strictness {
+    Off,
+    // Conflicting Content-Length/Transfer-Encoding and header limits
+    Lenient,
+    // Also only plain chunked, no repeated Content-Length or Host, no
+    // underscores in header names and only visible ASCII in values
+    Strict,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    // "1.2" or "1.3"
+    pub min_version: String,
+    pub max_version: String,
+    // IANA names; empty keeps the rustls defaults
+    pub cipher_suites: Vec<String>,
+    // Offered after h2 and http/1.1, which are always advertised
+    pub alpn_protocols: Vec<String>,
+    // <name>.crt and <name>.key pairs served by SNI name, "_.example.com"
+    // standing for *.example.com. cert_path and key_path, if set, are the
+    // fallback for other names.
+    pub certs_dir: String,
+    // How often certs_dir is rescanned for added or renewed pairs; 0 loads
+    // it only at startup
+    pub reload_secs: u64,
+    // Fetch OCSP responses from each certificate's responder and staple
+    // them to handshakes. Certificates need their issuer in the chain file.
+    pub ocsp_stapling: bool,
+    // Staples are refetched this often, or sooner if they'd expire first
+    pub ocsp_refresh_secs: u64,
+    // Alert once a certificate has this many days left
+    pub expiry_warn_days: u64,
+    pub expiry_check_secs: u64,
+    // Resumption, so reconnecting clients skip the full handshake: session
+    // tickets the client keeps, valid for up to ticket_lifetime_secs (at
+    // most 6 hours), and sessions cached here by ID (0 turns the cache off)
+    pub session_tickets: bool,
+    pub ticket_lifetime_secs: u32,
+    pub session_cache_size: usize,
+    // Take requests sent along with a resumed handshake (TLS 1.3 0-RTT).
+    // Early data can be replayed, so only idempotent requests are served
+    // before the handshake completes and the rest are answered 425. Needs
+    // the session cache and session_tickets off: cached sessions are single
+    // use, which keeps replays to other replicas.
+    pub early_data: bool,
+    pub early_data_max_bytes: u32,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert_path: String::new(),
+            key_path: String::new(),
+            min_version: "1.2".to_string(),
+            max_version: "1.3".to_string(),
+            cipher_suites: Vec::new(),
+            alpn_protocols: Vec::new(),
+            certs_dir: String::new(),
+            reload_secs: 30,
+            ocsp_stapling: false,
+            ocsp_refresh_secs: 6 * 3600,
+            expiry_warn_days: 14,
+            expiry_check_secs: 3600,
+            session_tickets: true,
+            ticket_lifetime_secs: 6 * 3600,
+            session_cache_size: 256,
+            early_data: false,
+            early_data_max_bytes: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct KvConfig {
+    // Kept in Redis when set, and so shared by every replica; in memory
+    // otherwise
+    pub redis_url: Option<String>,
+    // Put in front of every Redis key, so gateways can share a database
+    pub key_prefix: String,
+    // Most keys the in-memory store holds; writes past that fail
+    pub max_entries: usize,
+}
+
+impl Default for KvConfig {
+    fn default() -> Self {
+        KvConfig {
+            redis_url: None,
+            key_prefix: "gateway:".to_string(),
+            max_entries: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    // Alerts are POSTed here as JSON; empty only logs them
+    pub webhook_url: String,
+    pub timeout_ms: u64,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            webhook_url: String::new(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[schemars(transform = low_memory_alias)]
+pub enum TuningPreset {
+    #[default]
+    Balanced,
+    Latency,
+    Throughput,
+    #[serde(alias = "low-memory")]
+    LowMemory,
+}
+
+// The schema doesn't see serde aliases
+fn low_memory_alias(schema: &mut schemars::Schema) {
+    if let Some(serde_json::Value::Array(values)) = schema.get_mut("enum") {
+        values.push("low-memory".into());
+    }
+}
+
+// A preset, and any of its values set differently
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct TuningConfig {
+    pub preset: TuningPreset,
+    pub workers: Option<usize>,
+    pub backlog: Option<u32>,
+    pub max_connections: Option<usize>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub gzip_level: Option<u32>,
+    pub min_compress_bytes: Option<usize>,
+    pub stream_threshold_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DaemonConfig {
+    pub pid_file: String,
+    // Where output goes once detached; empty discards it
+    pub log_file: String,
+    // What the service is installed as on Windows (sc create <name>)
+    pub service_name: String,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            pid_file: "rust-netty-server.pid".to_string(),
+            log_file: String::new(),
+            service_name: "rust-netty-server".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct HandoffConfig {
+    // Where the running process waits for the one taking over
+    pub control_socket: String,
+    // What SIGUSR2 starts to take over, with our arguments; empty is the
+    // binary we were started as
+    pub binary: String,
+    // How long the new process has to start serving before we carry on
+    pub ready_timeout_secs: u64,
+}
+
+impl Default for HandoffConfig {
+    fn default() -> Self {
+        HandoffConfig {
+            control_socket: "rust-netty-server.sock".to_string(),
+            binary: String::new(),
+            ready_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct SloConfig {
+    pub name: String,
+    pub route: String,
+    // Share of requests that must be good, such as 0.99
+    pub objective: f64,
+    // Good requests are answered without a 5xx within this time (0 only
+    // looks at the status)
+    pub latency_ms: u64,
+    // The window the error budget is spent over
+    pub budget_days: u64,
+    // Burn rates aren't alerted on with fewer requests in the short window
+    pub min_requests: u64,
+    pub burn_alerts: Vec<BurnAlertConfig>,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        SloConfig {
+            name: String::new(),
+            route: String::new(),
+            objective: 0.99,
+            latency_ms: 0,
+            budget_days: 28,
+            min_requests: 10,
+            // A fast burn that would spend 2% of a 28 day budget in an
+            // hour, and a slower one that would spend 5% in six
+            burn_alerts: vec![
+                BurnAlertConfig {
+                    long_secs: 3600,
+                    short_secs: 300,
+                    burn_rate: 14.4,
+                },
+                BurnAlertConfig {
+                    long_secs: 6 * 3600,
+                    short_secs: 1800,
+                    burn_rate: 6.0,
+                },
+            ],
+        }
+    }
+}
+
+// Alert when the budget burns at least burn_rate times as fast as it can
+// be afforded, over both windows: the long one for significance, the short
+// one so the alert ends soon after the burning does
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+pub struct BurnAlertConfig {
+    pub long_secs: u64,
+    pub short_secs: u64,
+    pub burn_rate: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ProxyProtocolConfig {
+    // Balancer addresses or CIDR ranges. Connections from them must begin
+    // with a v1 or v2 header; the address in it stands in for theirs.
+    pub trusted_sources: Vec<String>,
+    // Serve connections from anywhere else under their own address instead
+    // of closing them
+    pub allow_direct: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ClientIpConfig {
+    // Addresses or CIDR ranges of the proxies in front (Cloudflare's, the
+    // load balancer's). Requests from anywhere else go by their own address.
+    pub trusted_proxies: Vec<String>,
+    // Tried in order, first usable one wins: single-address headers such as
+    // CF-Connecting-IP, or X-Forwarded-For and Forwarded, which are read from
+    // the nearest hop back past the trusted proxies
+    pub headers: Vec<String>,
+}
+
+impl Default for ClientIpConfig {
+    fn default() -> Self {
+        ClientIpConfig {
+            trusted_proxies: Vec::new(),
+            headers: vec!["x-forwarded-for".to_string()],
+        }
+    }
+}
+
+// Every pattern given has to match; at least one is needed
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct BlockRule {
+    // For the hit counters
+    pub name: String,
+    // Regexes on the percent-decoded path and query string, and the
+    // User-Agent, e.g. path = "^/(wp-admin|\\.env|\\.git)"
+    pub path: Option<String>,
+    pub query: Option<String>,
+    pub user_agent: Option<String>,
+    pub action: BlockAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockAction {
+    #[default]
+    Forbidden,
+    // Drop the connection without an answer, like nginx's 444
+    Close,
+}
+
+// So that /api/Movies/ and /api/movies reach the gateway as one path
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct NormalizeConfig {
+    pub trailing_slash: TrailingSlash,
+    pub lowercase: bool,
+    pub action: NormalizeAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    #[default]
+    Keep,
+    Strip,
+    // Except on paths whose last segment looks like a file (has a dot)
+    Add,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizeAction {
+    // Send the client a 308 to the normalized path, so it's what gets cached
+    // and bookmarked
+    #[default]
+    Redirect,
+    // Forward the normalized path without telling the client
+    Rewrite,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ContextConfig {
+    // The session ID is read from this cookie and sent as session_header
+    pub session_cookie: String,
+    pub session_header: String,
+    // The device ID is read from device_header, or failing that from this
+    // cookie
+    pub device_cookie: String,
+    pub device_header: String,
+    // Taken from the client when it sends one, otherwise the request ID
+    pub correlation_header: String,
+    // Give clients the IDs they were missing as cookies, so later requests
+    // carry the same ones; the device cookie lasts device_cookie_days
+    pub set_cookies: bool,
+    pub device_cookie_days: u64,
+    // Send the IDs back in the response headers too
+    pub echo: bool,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            session_cookie: "session_id".to_string(),
+            session_header: "x-session-id".to_string(),
+            device_cookie: "device_id".to_string(),
+            device_header: "x-device-id".to_string(),
+            correlation_header: "x-correlation-id".to_string(),
+            set_cookies: false,
+            device_cookie_days: 730,
+            echo: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct EgressConfig {
+    // Host names, as they are or "*.example.com" for any name under it
+    pub hosts: Vec<String>,
+    // Addresses or CIDR ranges; other names are allowed when everything
+    // they resolve to is in one of them
+    pub networks: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct HeaderPolicyConfig {
+    // Headers responses must have, with the value `fix` adds when one is
+    // missing; one with an empty value can only be logged or failed
+    pub required: HashMap<String, String>,
+    // Require the usual security headers too: HSTS, nosniff, a frame policy
+    // and a referrer policy, with safe values to fix with
+    pub security_headers: bool,
+    // Headers responses mustn't have, such as Server and X-Powered-By;
+    // `fix` removes them
+    pub forbidden: Vec<String>,
+    pub action: ComplianceAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComplianceAction {
+    // Only log and count what's wrong
+    #[default]
+    Log,
+    // Add what's missing and remove what's forbidden
+    Fix,
+    // Answer 502 instead
+    Fail,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RedirectRule {
+    // A path such as /signup, or a template such as /old/{rest*} as in a
+    // route's path
+    pub from: String,
+    // A raw regex with named groups, instead of `from`
+    pub from_regex: Option<String>,
+    // A path or full URL, with {name} filled in from what `from` captured
+    pub to: String,
+    // 301, 302, 303, 307 or 308
+    pub status: u16,
+    // Pass the request's query string on, after any `to` has of its own
+    pub preserve_query: bool,
+}
+
+impl Default for RedirectRule {
+    fn default() -> Self {
+        RedirectRule {
+            from: String::new(),
+            from_regex: None,
+            to: String::new(),
+            status: 302,
+            preserve_query: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct EdgeFilesConfig {
+    // Each given inline or as a file to read at startup; either way the
+    // environment's own config decides what it says. Paths left out go to
+    // the routes as before.
+    pub robots_txt: Option<String>,
+    pub robots_txt_path: Option<String>,
+    // Served at /.well-known/security.txt (RFC 9116)
+    pub security_txt: Option<String>,
+    pub security_txt_path: Option<String>,
+    pub max_age_secs: u64,
+    // Favicons, touch icons and the like, kept in browser caches for long
+    pub assets: Vec<StaticAssetConfig>,
+    pub asset_max_age_secs: u64,
+}
+
+impl Default for EdgeFilesConfig {
+    fn default() -> Self {
+        EdgeFilesConfig {
+            robots_txt: None,
+            robots_txt_path: None,
+            security_txt: None,
+            security_txt_path: None,
+            max_age_secs: 3600,
+            assets: Vec::new(),
+            asset_max_age_secs: 30 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct StaticAssetConfig {
+    pub path: String,
+    // Read at startup. Only /favicon.ico can go without, for a built-in
+    // blank icon.
+    pub file: Option<String>,
+    // Otherwise told from the contents or the file extension
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    // Variables: $remote_addr $time_iso8601 $msec $method $uri $path $protocol
+    // $status $bytes $latency_ms $upstream $route $request_id $user_agent
+    // $referer $region, $http_<header> (dashes as underscores) and
+    // $tag_<name> from the route's tags, also written as ${name}
+    pub format: String,
+    // File to append to; empty or "-" for stdout
+    pub path: String,
+    pub rotation: RotationConfig,
+    // Send lines to syslog or journald instead
+    pub syslog: Option<SyslogConfig>,
+    pub journald: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ForwardProxyConfig {
+    // Host names that can be tunneled to, "*.example.com" for subdomains
+    pub allowed_hosts: Vec<String>,
+    pub allowed_ports: Vec<u16>,
+    // Usernames and passwords accepted in Proxy-Authorization; a password
+    // of env:NAME is read from the environment
+    pub users: HashMap<String, String>,
+    pub connect_timeout_ms: u64,
+    // Tunnels with nothing passing either way this long are closed
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for ForwardProxyConfig {
+    fn default() -> Self {
+        ForwardProxyConfig {
+            allowed_hosts: Vec::new(),
+            allowed_ports: vec![443],
+            users: HashMap::new(),
+            connect_timeout_ms: 5000,
+            idle_timeout_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct CredentialAuditConfig {
+    pub path: String,
+    pub rotation: RotationConfig,
+}
+
+impl Default for CredentialAuditConfig {
+    fn default() -> Self {
+        CredentialAuditConfig {
+            path: "credential-audit.log".to_string(),
+            rotation: RotationConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    // nats://host:port of the NATS server
+    pub url: String,
+    // Every instance of one deployment publishes and listens on it
+    pub subject: String,
+    pub username: String,
+    // env:NAME is read from the environment
+    pub password: String,
+    // Wait between attempts while the server can't be reached
+    pub reconnect_ms: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            url: "nats://127.0.0.1:4222".to_string(),
+            subject: "netty-server.cluster".to_string(),
+            username: String::new(),
+            password: String::new(),
+            reconnect_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct KafkaConfig {
+    // Bootstrap brokers as host:port; the topic's leaders are found from them
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub client_id: String,
+    // 0 doesn't wait for the broker, 1 for the leader, -1 for all replicas
+    pub acks: i16,
+    // A batch goes out once it has this many events or is linger_ms old
+    pub batch_size: usize,
+    pub max_batch_bytes: usize,
+    pub linger_ms: u64,
+    // Events waiting while the broker is slow or away; past this new ones
+    // are dropped
+    pub buffer_events: usize,
+    // Sends of a batch before its events are dropped
+    pub max_attempts: u32,
+    pub timeout_ms: u64,
+}
+
+fn check_kafka(section: &str, kafka: &KafkaConfig) -> Result<(), Error> {
+    if kafka.brokers.is_empty() || kafka.topic.is_empty() {
+        return Err(Error::other(format!(
+            "{} needs brokers and a topic",
+            section
+        )));
+    }
+    if !matches!(kafka.acks, -1..=1) {
+        return Err(Error::other(format!("{} acks must be -1, 0 or 1", section)));
+    }
+    if kafka.batch_size == 0 || kafka.buffer_events < kafka.batch_size {
+        return Err(Error::other(format!(
+            "{} batch_size must be above 0 and buffer_events at least batch_size",
+            section
+        )));
+    }
+    if kafka.max_attempts == 0 {
+        return Err(Error::other(format!(
+            "{} max_attempts must be above 0",
+            section
+        )));
+    }
+    Ok(())
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            brokers: Vec::new(),
+            topic: String::new(),
+            client_id: "netty-server".to_string(),
+            acks: 1,
+            batch_size: 500,
+            max_batch_bytes: 1024 * 1024,
+            linger_ms: 100,
+            buffer_events: 10_000,
+            max_attempts: 3,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RedactionConfig {
+    pub headers: Vec<String>,
+    // Keep the last 4 characters instead of masking the whole value
+    pub partial: bool,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        RedactionConfig {
+            headers: [
+                "authorization",
+                "proxy-authorization",
+                "cookie",
+                "set-cookie",
+                "x-api-key",
+            ]
+            .iter()
+            .map(|h| h.to_string())
+            .collect(),
+            partial: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct SamplingConfig {
+    // Log every Nth request, and of those this percentage
+    pub every_n: u64,
+    pub percent: f64,
+    // Always log responses with at least this status, and requests slower
+    // than slow_ms (0 disables either)
+    pub error_status: u16,
+    pub slow_ms: u64,
+    // Sending debug_header with the secret logs that request in full,
+    // headers included. Disabled while the secret is empty.
+    pub debug_header: String,
+    pub debug_secret: String,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            every_n: 1,
+            percent: 100.0,
+            error_status: 500,
+            slow_ms: 0,
+            debug_header: "x-debug-log".to_string(),
+            debug_secret: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct SyslogConfig {
+    // udp://host:port, tcp://host:port or unix:///dev/log
+    pub address: String,
+    pub facility: String,
+    pub app_name: String,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        SyslogConfig {
+            address: "unix:///dev/log".to_string(),
+            facility: "local0".to_string(),
+            app_name: "netty-server".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RotationConfig {
+    // Rotate once the file would grow past this size, or is this old (0 = never)
+    pub max_bytes: u64,
+    pub max_age_secs: u64,
+    // Rotated files to keep
+    pub keep: usize,
+    // Gzip rotated files
+    pub compress: bool,
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        RotationConfig {
+            max_bytes: 0,
+            max_age_secs: 0,
+            keep: 7,
+            compress: false,
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            format: "$remote_addr [$time_iso8601] \"$method $uri $protocol\" $status $bytes \
+                     $latency_ms $upstream $request_id"
+                .to_string(),
+            path: String::new(),
+            rotation: RotationConfig::default(),
+            syslog: None,
+            journald: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct GrpcConfig {
+    pub port: u16,
+    // Defaults to the public listener's
+    pub bind_addresses: Vec<String>,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        GrpcConfig {
+            port: 50051,
+            bind_addresses: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct Http3Config {
+    // UDP port, defaults to the public port
+    pub port: Option<u16>,
+    // Defaults to the public listener's
+    pub bind_addresses: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct AltSvcConfig {
+    // ALPN ids to advertise, e.g. h3
+    pub protocols: Vec<String>,
+    // Defaults to the HTTP/3 listener's port, else the public port
+    pub port: Option<u16>,
+    pub max_age_secs: u64,
+    // Send Alt-Svc: clear instead, so clients forget earlier advertisements
+    pub clear: bool,
+}
+
+impl Default for AltSvcConfig {
+    fn default() -> Self {
+        AltSvcConfig {
+            protocols: vec!["h3".to_string()],
+            port: None,
+            max_age_secs: 86400,
+            clear: false,
+        }
+    }
+}
+
+// Retries per upstream may not exceed `ratio` of the requests seen in the last
+// `window_secs`, plus a small allowance so quiet upstreams can still retry
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RetryBudgetConfig {
+    pub ratio: f64,
+    pub window_secs: u64,
+    pub min_retries: u64,
+}
+
+impl Default for RetryBudgetConfig {
+    fn default() -> Self {
+        RetryBudgetConfig {
+            ratio: 0.2,
+            window_secs: 10,
+            min_retries: 3,
+        }
+    }
+}
+
+// Requests in flight past which each priority class is turned away with a
+// 503, as a share of max_in_flight for the lower classes
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct LoadSheddingConfig {
+    pub max_in_flight: usize,
+    pub normal_percent: f64,
+    pub best_effort_percent: f64,
+    pub retry_after_secs: u64,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        LoadSheddingConfig {
+            max_in_flight: 1000,
+            normal_percent: 90.0,
+            best_effort_percent: 70.0,
+            retry_after_secs: 5,
+        }
+    }
+}
+
+// Bodies are checked against the Content-MD5, Digest and Content-Digest
+// headers sent with them. A request that doesn't match gets a 400; a
+// response gets a 502, or is cut off when it's being streamed.
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ChecksumConfig {
+    pub requests: bool,
+    pub responses: bool,
+    // Digests in other algorithms are passed on unchecked
+    pub algorithms: Vec<String>,
+}
+
+impl Default for ChecksumConfig {
+    fn default() -> Self {
+        ChecksumConfig {
+            requests: true,
+            responses: true,
+            algorithms: checksum::ALGORITHMS.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ErrorResponseConfig {
+    // Defaults to the status normally used for the error kind
+    pub status: Option<u16>,
+    pub body: Option<String>,
+    pub content_type: String,
+}
+
+impl Default for ErrorResponseConfig {
+    fn default() -> Self {
+        ErrorResponseConfig {
+            status: None,
+            body: None,
+            content_type: "text/plain".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    // Routes edited through the admin API are saved here and take precedence
+    // over the config file on the next start
+    pub state_path: String,
+    // SQLite database holding routes, upstreams and API keys edited through
+    // the admin API. Replaces the state file when set.
+    pub database_path: String,
+    // Append-only JSON lines record of admin changes; empty disables it
+    pub audit_path: String,
+    // Directory keeping the last history_versions route tables, for the
+    // /config/versions endpoints; empty disables it
+    pub history_path: String,
+    pub history_versions: usize,
+    // Role name -> operations that role may perform
+    pub roles: HashMap<String, Vec<AdminOperation>>,
+    // Admin API tokens by holder name. With none configured the admin API is
+    // open to anyone who can reach the admin port.
+    pub tokens: HashMap<String, AdminToken>,
+    // Admin users signing in with HTTP Basic against a directory, as well
+    // as the tokens
+    pub ldap: Option<LdapConfig>,
+    // Undo route creates and updates whose route then fails or slows down
+    pub rollback: Option<RollbackConfig>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        let roles = [
+            ("viewer", vec![AdminOperation::View]),
+            (
+                "operator",
+                vec![
+                    AdminOperation::View,
+                    AdminOperation::EditRoutes,
+                    AdminOperation::EditConfig,
+                    AdminOperation::PurgeCache,
+                    AdminOperation::Chaos,
+                    AdminOperation::Drain,
+                ],
+            ),
+        ];
+        AdminConfig {
+            state_path: "routes.json".to_string(),
+            database_path: String::new(),
+            audit_path: "audit.log".to_string(),
+            history_path: "config-history".to_string(),
+            history_versions: 20,
+            roles: roles
+                .into_iter()
+                .map(|(name, operations)| (name.to_string(), operations))
+                .collect(),
+            tokens: HashMap::new(),
+            ldap: None,
+            rollback: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminOperation {
+    // Metrics, events, in-flight requests, routes, drain status and the audit log
+    View,
+    EditRoutes,
+    // Upstream pools and API keys in the config store, and the KV store
+    EditConfig,
+    PurgeCache,
+    // Swapping a route's upstream for its mock at runtime, to see how
+    // clients cope with the canned (often failing) response
+    Chaos,
+    Drain,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+pub struct AdminToken {
+    pub token: String,
+    pub role: String,
+}
+
+// A simple bind as the user checks their password, see admin_auth.rs
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct LdapConfig {
+    // ldaps://host[:636]. ldap://host[:389] sends passwords in the clear and
+    // is refused unless `allow_insecure` is set.
+    pub url: String,
+    // Take an ldap:// url, e.g. for a directory on the same host
+    pub allow_insecure: bool,
+    // The DN to bind as, with {user} for the user name, e.g.
+    // uid={user},ou=people,dc=example,dc=com or {user}@corp.example.com
+    pub bind_dn: String,
+    // The role of anyone who can bind; empty lets in only those in `users`
+    pub role: String,
+    // User name -> role, for users who get a role other than `role`
+    pub users: HashMap<String, String>,
+    pub timeout_ms: u64,
+    // A good password is taken on trust for this long, rather than binding
+    // on every admin request
+    pub cache_secs: u64,
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        LdapConfig {
+            url: String::new(),
+            allow_insecure: false,
+            bind_dn: String::new(),
+            role: String::new(),
+            users: HashMap::new(),
+            timeout_ms: 5000,
+            cache_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    // Total bytes of response bodies kept in memory
+    pub max_bytes: usize,
+    // Responses larger than this are never cached
+    pub max_entry_bytes: usize,
+    // TTL used when the upstream doesn't send Cache-Control (0 = don't cache)
+    pub default_ttl_secs: u64,
+    // How long expired entries are kept around (and reported as stale)
+    pub stale_secs: u64,
+    // Range requests are cached as chunks of this size, so any range can be
+    // served from them and only missing chunks are fetched (0 = off)
+    pub chunk_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchAction {
+    // Only log and count it
+    Log,
+    // Replace the declared type with the sniffed one
+    Correct,
+    // Answer 502 instead
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ContentTypeConfig {
+    // Media types the route may return, e.g. application/json or image/*;
+    // empty allows any
+    pub expected: Vec<String>,
+    // Send X-Content-Type-Options: nosniff
+    pub nosniff: bool,
+    // Compare the declared type against the body's magic bytes
+    pub sniff: bool,
+    pub on_mismatch: MismatchAction,
+}
+
+impl Default for ContentTypeConfig {
+    fn default() -> Self {
+        ContentTypeConfig {
+            expected: Vec::new(),
+            nosniff: true,
+            sniff: false,
+            on_mismatch: MismatchAction::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct BufferingConfig {
+    pub mode: BufferingMode,
+    // In auto mode, bodies declaring more than this many bytes are streamed
+    // (0 = no limit)
+    pub max_bytes: u64,
+    // In auto mode, bodies of these media types are streamed, e.g. video/*
+    pub stream_content_types: Vec<String>,
+    // Responses of these types are streamed in any mode but buffered, and
+    // passed on a line at a time: each complete line goes out as soon as it
+    // arrives, through compression too
+    pub line_content_types: Vec<String>,
+}
+
+impl Default for BufferingConfig {
+    fn default() -> Self {
+        BufferingConfig {
+            mode: BufferingMode::Auto,
+            max_bytes: 0,
+            stream_content_types: vec!["text/event-stream".to_string()],
+            line_content_types: vec![
+                "application/x-ndjson".to_string(),
+                "application/jsonl".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferingMode {
+    // Bodies are read whole only when something needs them so: request
+    // bodies for retries, responses for the cache or content sniffing. Ones
+    // over max_bytes or of a streamed type are passed on regardless.
+    Auto,
+    // Always read whole, so requests can be retried and responses are
+    // checked against their limit and digests before any of them is sent
+    Buffered,
+    // Always passed on as they arrive: requests with a body aren't retried
+    // and responses aren't cached
+    Streaming,
+}
+
+// Named upstream groups of which one is live, flipped through the admin API
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct BlueGreenConfig {
+    // Group name (say blue, green) -> upstream URL or pool
+    pub groups: HashMap<String, String>,
+    pub live: String,
+    // Flip back when the new group fails too often right after a flip
+    pub rollback: Option<RollbackConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RollbackConfig {
+    // How long to watch after a change
+    pub window_secs: u64,
+    // Fraction of 5xx responses, 0.0-1.0, above which the change is undone
+    pub max_error_rate: f64,
+    // Mean latency above which the change is undone (0 = not checked)
+    pub max_latency_ms: u64,
+    // Below this many requests in the window nothing is decided
+    pub min_requests: u64,
+}
+
+impl Default for RollbackConfig {
+    fn default() -> Self {
+        RollbackConfig {
+            window_secs: 300,
+            max_error_rate: 0.05,
+            max_latency_ms: 0,
+            min_requests: 20,
+        }
+    }
+}
+
+// A window in which a route goes somewhere else, or answers 503
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ScheduleRule {
+    // RFC 3339 times; either can be left open
+    pub start: Option<String>,
+    pub end: Option<String>,
+    // Five-field cron expression in UTC (minute hour day month weekday);
+    // each time it matches opens a window of duration_mins
+    pub cron: Option<String>,
+    pub duration_mins: u32,
+    // Upstream URL or pool while the window is open
+    pub upstream: Option<String>,
+    // Answer 503 with this message instead of forwarding
+    pub maintenance: Option<String>,
+}
+
+impl Default for ScheduleRule {
+    fn default() -> Self {
+        ScheduleRule {
+            start: None,
+            end: None,
+            cron: None,
+            duration_mins: 60,
+            upstream: None,
+            maintenance: None,
+        }
+    }
+}
+
+// A canned response served instead of forwarding, for frontend work while
+// the backend is down
+// One of file, upstream and stale
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct FallbackConfig {
+    // Upstream statuses, or the ones the gateway answers with when the
+    // upstream can't be reached, e.g. [404] or [500, 502, 503, 504]
+    pub statuses: Vec<u16>,
+    // Served with a 200, such as a placeholder poster. Read on every use.
+    pub file: Option<String>,
+    // Sniffed from the file when not given
+    pub content_type: Option<String>,
+    // The request is sent here instead, e.g. a secondary origin
+    pub upstream: Option<String>,
+    // The last good response from the cache, if it hasn't been evicted yet.
+    // Needs the route to be cached; entries outlive their ttl by [cache]
+    // stale_secs.
+    pub stale: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DeltaConfig {
+    // Versions kept per URL that a client can get a patch from
+    pub versions: usize,
+    // Larger responses are sent whole and not kept
+    pub max_body_bytes: usize,
+    // URLs (per user, on per_user routes) with versions kept; the first seen
+    // goes when there are more
+    pub max_urls: usize,
+}
+
+impl Default for DeltaConfig {
+    fn default() -> Self {
+        DeltaConfig {
+            versions: 4,
+            max_body_bytes: 8 * 1024 * 1024,
+            max_urls: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    // How long after a request the same one from the same user is taken
+    // for a duplicate
+    pub window_ms: u64,
+    pub methods: Vec<String>,
+    // Larger responses aren't kept, and their duplicates are forwarded
+    pub max_body_bytes: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            window_ms: 1000,
+            methods: vec!["POST".to_string()],
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DownloadsConfig {
+    // Path capture naming the title, such as id in /videos/{id}/{file*}
+    pub title: String,
+    // How many equal parts of a file its bytes served are reported in
+    pub segments: usize,
+}
+
+impl Default for DownloadsConfig {
+    fn default() -> Self {
+        DownloadsConfig {
+            title: "title".to_string(),
+            segments: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RangeCheckConfig {
+    // More ranges than this in one header are refused with a 416
+    pub max_ranges: usize,
+}
+
+impl Default for RangeCheckConfig {
+    fn default() -> Self {
+        RangeCheckConfig { max_ranges: 16 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct MockConfig {
+    // Can be flipped at runtime through the admin API
+    pub enabled: bool,
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+    // Read on every request, so fixtures can be edited in place. Takes the
+    // place of body when set.
+    pub body_file: Option<String>,
+    // Fill {name} placeholders in the body and header values from the path
+    // captures
+    pub template: bool,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            enabled: true,
+            status: 200,
+            headers: HashMap::new(),
+            body: String::new(),
+            body_file: None,
+            template: false,
+        }
+    }
+}
+
+// Per-route overrides for upstreams that don't send usable Cache-Control
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct CachePolicyConfig {
+    // Cache for this long whatever the upstream says
+    pub force_ttl_secs: Option<u64>,
+    // TTL when the upstream sends no Cache-Control, instead of [cache] default_ttl_secs
+    pub default_ttl_secs: Option<u64>,
+    // Cache despite no-cache and no-store. Private responses are still never
+    // cached, since they may be specific to one user.
+    pub ignore_no_cache: bool,
+    // Also cache responses to authenticated requests, each user's kept apart
+    // and keyed by a hash of their token's subject. Private responses are
+    // cached then too, as only the same user is served them. Only for routes
+    // whose responses stay the same for a user a while; requests with
+    // credentials but no subject still bypass the cache.
+    pub per_user: bool,
+    // Never cache this route, even if it's marked cacheable
+    pub never: bool,
+    // Keep 404 and 410 responses this long, so repeated requests for missing
+    // assets don't all reach the upstream (0 = don't)
+    pub negative_ttl_secs: u64,
+    // Same for 5xx responses that [errors] doesn't replace
+    pub error_ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            max_bytes: 64 * 1024 * 1024,
+            max_entry_bytes: 4 * 1024 * 1024,
+            default_ttl_secs: 0,
+            stale_secs: 60,
+            chunk_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    // HS256/HS384/HS512 use the shared secret, RS*/ES* the PEM public key
+    pub algorithm: String,
+    pub secret: String,
+    pub public_key_path: String,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub leeway_secs: u64,
+    // Claim -> header forwarded upstream once the token is validated, e.g.
+    // sub = "X-User-Id". Client-supplied values of these headers are dropped.
+    pub claim_headers: HashMap<String, String>,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            algorithm: "HS256".to_string(),
+            secret: String::new(),
+            public_key_path: String::new(),
+            issuer: None,
+            audience: None,
+            leeway_secs: 30,
+            claim_headers: HashMap::new(),
+        }
+    }
+}
+
+// One way of authenticating requests. Routes select one by name with `auth`.
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthenticatorConfig {
+    // Bearer tokens, with the same settings as [jwt]
+    Jwt(JwtConfig),
+    // One of the listed keys in a request header
+    ApiKey(ApiKeyAuthConfig),
+    // Requests signed with a shared secret, see authn.rs for the scheme
+    Hmac(HmacAuthConfig),
+    // Public, even on routes that would otherwise require a JWT
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStoreKind {
+    // The keys listed in the config file, read-only
+    Config,
+    // The [admin] database
+    Sqlite,
+    Redis,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ApiKeyAuthConfig {
+    pub header: String,
+    pub store: KeyStoreKind,
+    pub redis_url: Option<String>,
+    // Keys for the config store
+    pub keys: Vec<ApiKeyRecord>,
+    // Limit for keys that don't set their own
+    pub rate_limit: Option<RateLimitConfig>,
+    // Header telling the upstream which partner is calling
+    pub partner_header: Option<String>,
+}
+
+impl Default for ApiKeyAuthConfig {
+    fn default() -> Self {
+        ApiKeyAuthConfig {
+            header: "x-api-key".to_string(),
+            store: KeyStoreKind::Config,
+            redis_url: None,
+            keys: Vec::new(),
+            rate_limit: None,
+            partner_header: None,
+        }
+    }
+}
+
+// A partner's API key as stored. Only the key's SHA-256 is kept, so a store
+// leak doesn't reveal usable keys. A partner can have several active keys,
+// which is how keys are rotated.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ApiKeyRecord {
+    pub id: String,
+    pub partner: String,
+    // Hex SHA-256 of the key, e.g. from `printf %s "$KEY" | sha256sum`
+    pub hash: String,
+    pub rate_limit: Option<RateLimitConfig>,
+    // Unix seconds
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub revoked: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct HmacAuthConfig {
+    pub secret: String,
+    pub signature_header: String,
+    // Unix seconds, signed along with the request to stop replays
+    pub timestamp_header: String,
+    pub max_skew_secs: u64,
+    // Also refuse a request seen before while its timestamp is still
+    // accepted, remembered in the [kv] store so every replica knows
+    pub replay_protection: bool,
+    // Header with a per-request nonce, signed after the timestamp. Empty
+    // takes the signature itself, which a resent request has the same of.
+    pub nonce_header: String,
+}
+
+impl Default for HmacAuthConfig {
+    fn default() -> Self {
+        HmacAuthConfig {
+            secret: String::new(),
+            signature_header: "x-signature".to_string(),
+            timestamp_header: "x-timestamp".to_string(),
+            max_skew_secs: 300,
+            replay_protection: false,
+            nonce_header: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub window_secs: u64,
+    // Claim holding the user's tier, e.g. "plan"
+    pub tier_claim: String,
+    pub default_tier: String,
+    // Requests allowed per window for each tier (0 = unlimited)
+    pub tiers: HashMap<String, u64>,
+    // Counters are kept in this Redis when set, in the [kv] store otherwise
+    pub redis_url: Option<String>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            window_secs: 3600,
+            tier_claim: "plan".to_string(),
+            default_tier: "free".to_string(),
+            tiers: HashMap::new(),
+            redis_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct StreamsConfig {
+    // Playback sessions one user may have open at once
+    pub max_sessions: u64,
+    // Header carrying the player's session id; requests without it aren't counted
+    pub session_header: String,
+    // JWT claim identifying the account
+    pub user_claim: String,
+    // Sessions end after this long without a request, or on a DELETE
+    pub idle_secs: u64,
+    // Status for requests over the cap, 409 or 429
+    pub status: u16,
+    // Built-in endpoint players POST keep-alives to, "" to disable
+    pub heartbeat_path: String,
+    // Sessions are kept in Redis when set, in memory otherwise
+    pub redis_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct WarmupConfig {
+    // Connections opened to each upstream target; they stay in the client's
+    // pool for the first requests
+    pub connections: usize,
+    // Requested with HEAD; any response counts, only failing to connect doesn't
+    pub path: String,
+    pub timeout_ms: u64,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        WarmupConfig {
+            connections: 2,
+            path: "/".to_string(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct SelfTestConfig {
+    // None means a GET to the prefix of every plain prefix route
+    pub probes: Vec<ProbeConfig>,
+    // Per probe, for the whole response head
+    pub timeout_ms: u64,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        SelfTestConfig {
+            probes: Vec::new(),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ProbeConfig {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+    // The route the probe must end up on, when it matters
+    pub route: Option<String>,
+    // Statuses that pass (empty = anything below 500)
+    pub expect_status: Vec<u16>,
+    // Probes skip authentication unless this is set, in which case their
+    // headers have to carry credentials
+    pub authenticate: bool,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: HashMap::new(),
+            body: String::new(),
+            route: None,
+            expect_status: Vec::new(),
+            authenticate: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VcrMode {
+    // Forward as usual and save every upstream response
+    Record,
+    // Serve saved responses only; anything not recorded gets a 504
+    Replay,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    // Host names resolved to fixed addresses instead of asking the system,
+    // e.g. "gateway.internal" = ["10.20.0.5"]
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct VcrConfig {
+    pub mode: VcrMode,
+    // One subdirectory per route, two files per recorded response
+    pub directory: String,
+    // Route names; empty for all of them
+    pub routes: Vec<String>,
+    // Request headers that are part of the signature, besides the method,
+    // upstream path and query, and body
+    pub match_headers: Vec<String>,
+}
+
+impl Default for VcrConfig {
+    fn default() -> Self {
+        VcrConfig {
+            mode: VcrMode::Replay,
+            directory: "cassettes".to_string(),
+            routes: Vec::new(),
+            match_headers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct GeoConfig {
+    // CSV of network,country lines (e.g. 81.2.69.0/24,GB), as exported from
+    // a GeoIP country database
+    pub database: String,
+    // Country code set by a trusted CDN in front of us (e.g.
+    // cloudfront-viewer-country), preferred over the database
+    pub country_header: Option<String>,
+    // Region -> ISO country codes
+    pub regions: HashMap<String, Vec<String>>,
+    // Region for countries in no group and addresses we can't place
+    pub default_region: String,
+}
+
+impl Default for GeoConfig {
+    fn default() -> Self {
+        GeoConfig {
+            database: String::new(),
+            country_header: None,
+            regions: HashMap::new(),
+            default_region: "default".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BotAction {
+    Block,
+    // Hold the request for tarpit_ms, then serve it
+    Tarpit,
+    // Refuse until the client solves the proof of work at challenge_path
+    Challenge,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct BotsConfig {
+    // Path prefixes screened, empty for all
+    pub paths: Vec<String>,
+    // User-Agent regexes, matched case-insensitively. Allowed agents (say, a
+    // partner's crawler) skip the heuristics.
+    pub block_user_agents: Vec<String>,
+    pub allow_user_agents: Vec<String>,
+    // Headers every browser sends
+    pub required_headers: Vec<String>,
+    // A request's score adds up these; at threshold the action is taken
+    pub user_agent_score: u32,
+    pub missing_header_score: u32,
+    pub rate_score: u32,
+    pub threshold: u32,
+    // More requests than this from one IP within window_secs adds rate_score
+    pub window_secs: u64,
+    pub max_requests_per_window: u64,
+    pub action: BotAction,
+    pub tarpit_ms: u64,
+    pub challenge_path: String,
+    // Leading zero bits required of SHA-256(nonce + solution)
+    pub challenge_difficulty: u32,
+    // Signs challenge nonces and passes; random per start when empty
+    pub secret: String,
+    pub pass_cookie: String,
+    pub pass_ttl_secs: u64,
+}
+
+impl Default for BotsConfig {
+    fn default() -> Self {
+        BotsConfig {
+            paths: Vec::new(),
+            block_user_agents: Vec::new(),
+            allow_user_agents: Vec::new(),
+            required_headers: vec![
+                "user-agent".to_string(),
+                "accept".to_string(),
+                "accept-language".to_string(),
+            ],
+            user_agent_score: 10,
+            missing_header_score: 2,
+            rate_score: 5,
+            threshold: 5,
+            window_secs: 10,
+            max_requests_per_window: 50,
+            action: BotAction::Block,
+            tarpit_ms: 5000,
+            challenge_path: "/bot-challenge".to_string(),
+            challenge_difficulty: 16,
+            secret: String::new(),
+            pass_cookie: "bot_pass".to_string(),
+            pass_ttl_secs: 3600,
+        }
+    }
+}
+
+impl Default for StreamsConfig {
+    fn default() -> Self {
+        StreamsConfig {
+            max_sessions: 3,
+            session_header: "x-playback-session".to_string(),
+            user_claim: "sub".to_string(),
+            idle_secs: 60,
+            status: 409,
+            heartbeat_path: "/playback/heartbeat".to_string(),
+            redis_url: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct MeteringConfig {
+    // Request header identifying the billable API key
+    pub key_header: String,
+    pub flush_interval_secs: u64,
+    pub sink: SinkConfig,
+    // Distinct titles kept per period; the rest are counted as "(other)"
+    pub max_titles: usize,
+    // and the same for tenants and API keys
+    pub max_tenants: usize,
+}
+
+impl Default for MeteringConfig {
+    fn default() -> Self {
+        MeteringConfig {
+            key_header: "x-api-key".to_string(),
+            flush_interval_secs: 60,
+            sink: SinkConfig::File {
+                path: "usage.jsonl".to_string(),
+            },
+            max_titles: 10000,
+            max_tenants: 10000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ReportConfig {
+    // When reports are made, as a five-field cron expression in UTC. Each
+    // covers the time since the one before (or since startup).
+    pub cron: String,
+    pub format: ReportFormat,
+    // Reports are written here, one file each
+    pub dir: Option<String>,
+    // and/or POSTed here
+    pub webhook_url: Option<String>,
+    pub timeout_ms: u64,
+    // How many of the slowest routes (by p95 latency) are listed
+    pub slowest_routes: usize,
+    // Routes with fewer requests in the period aren't counted as slow
+    pub min_requests: u64,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            cron: "0 0 * * *".to_string(),
+            format: ReportFormat::Json,
+            dir: None,
+            webhook_url: None,
+            timeout_ms: 10_000,
+            slowest_routes: 10,
+            min_requests: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Html,
+}
+
+// Where batches of records are delivered
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    // Append JSON lines to a local file
+    File { path: String },
+    // POST a JSON array to an HTTP endpoint
+    Http { url: String },
+    // One JSON message per record on a Kafka topic; the settings are those
+    // of [kafka]
+    Kafka(KafkaConfig),
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allow_origin: String,
+    pub allow_methods: String,
+    pub allow_headers: String,
+    // Access-Control-Max-Age on preflight answers, so browsers reuse them
+    // (0 = not sent)
+    pub max_age_secs: u64,
+    // Which values win when the upstream sends Access-Control-* headers of
+    // its own, since browsers reject a response with two of them
+    pub reconcile: CorsReconcile,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorsReconcile {
+    // The proxy's policy alone; the upstream's Access-Control-* are dropped
+    PreferProxy,
+    // The upstream's headers replace the proxy's
+    #[default]
+    PreferUpstream,
+    // Lists such as allowed methods are combined; a specific origin beats *
+    Merge,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allow_origin: "*".to_string(),
+            allow_methods: "POST, GET, OPTIONS, PUT, DELETE".to_string(),
+            allow_headers: "Content-Type, Authorization, Range".to_string(),
+            max_age_secs: 0,
+            reconcile: CorsReconcile::default(),
+        }
+    }
+}
+
+// How requests are mapped onto a tenant: header first, then API key, then Host
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct TenancyConfig {
+    pub tenant_header: String,
+    pub api_key_header: String,
+}
+
+impl Default for TenancyConfig {
+    fn default() -> Self {
+        TenancyConfig {
+            tenant_header: "x-tenant".to_string(),
+            api_key_header: "x-api-key".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct TenantConfig {
+    pub hosts: Vec<String>,
+    pub api_keys: Vec<String>,
+    // Replaces the upstream of every route for this tenant...
+    pub upstream: Option<String>,
+    // ...unless a route-specific upstream is given here, keyed by route name
+    pub routes: HashMap<String, String>,
+    pub cors: Option<CorsConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    pub burst: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            requests_per_second: 100.0,
+            burst: 200.0,
+        }
+    }
+}
+
+// Named request processing steps that routes opt into, in the order listed
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MiddlewareConfig {
+    // Require a valid JWT bearer token. Optional ones let anonymous requests
+    // through, but an invalid token is still rejected.
+    Jwt {
+        #[serde(default)]
+        optional: bool,
+    },
+    // Enforce the [quota] tiers; needs a jwt middleware before it
+    Quota,
+    // Cap concurrent playback sessions per user, see [streams]; needs a jwt
+    // middleware before it
+    Streams,
+    // Token bucket shared by every route using this middleware
+    RateLimit(RateLimitConfig),
+    Cache,
+    // Custom filter compiled to WebAssembly, see wasm.rs for the host ABI
+    Wasm(WasmFilterConfig),
+    // Rhai script with on_request/on_response hooks, see script.rs
+    Script(ScriptConfig),
+    // Reject requests that don't match an OpenAPI 3 spec, see openapi.rs
+    #[serde(rename = "openapi")]
+    OpenApi(OpenApiConfig),
+    // Parse GraphQL requests and enforce operation and cost limits, see graphql.rs
+    Graphql(GraphqlConfig),
+    // Double-submit cookie CSRF protection for browser sessions, see csrf.rs
+    Csrf(CsrfConfig),
+    // Tell the upstream whether the client is a TV, phone, tablet or browser,
+    // from its User-Agent, see device.rs
+    Device(DeviceConfig),
+    // Screen query parameters and small bodies for SQL injection and XSS,
+    // see waf.rs
+    Waf(WafConfig),
+    // Mark responses with who they were served to, so a leaked one can be
+    // traced; needs a jwt middleware before it, see watermark.rs
+    Watermark(WatermarkConfig),
+    // Take the variants above the user's plan out of HLS and DASH
+    // manifests; needs a jwt middleware before it, see manifest.rs
+    Manifest(ManifestConfig),
+    // Direct-to-storage uploads: the body's type and size are checked here,
+    // and the request is signed for the storage service so clients never
+    // hold its credentials, see upload.rs
+    Upload(UploadConfig),
+    // Put an HTML fragment, such as a maintenance announcement, into pages
+    // as they stream through, just before </body>, see inject.rs
+    Inject(InjectConfig),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct InjectConfig {
+    pub fragment: String,
+    // Or read it from this file, and again whenever the file changes, so
+    // the announcement can be changed or (emptied) taken down without a
+    // restart
+    pub fragment_file: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    // Media types accepted, such as image/jpeg, or image/* for any image;
+    // empty accepts any that is given
+    pub allowed_types: Vec<String>,
+    // Largest upload by its Content-Length (0 = server.max_request_body_bytes)
+    pub max_bytes: usize,
+    // Sign the upstream request with an AWS Signature V4 pre-signed query
+    pub presign: Option<PresignConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct PresignConfig {
+    pub access_key_id: String,
+    // env:NAME reads these from the environment
+    pub secret_access_key: String,
+    // For temporary credentials
+    pub session_token: String,
+    pub region: String,
+    pub service: String,
+    // The storage host the request is signed for; empty takes it from the
+    // route's upstream URL
+    pub host: String,
+    pub expires_secs: u64,
+}
+
+impl Default for PresignConfig {
+    fn default() -> Self {
+        PresignConfig {
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            session_token: String::new(),
+            region: "us-east-1".to_string(),
+            service: "s3".to_string(),
+            host: String::new(),
+            expires_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ManifestConfig {
+    // The claim naming the user's plan
+    pub plan_claim: String,
+    // Plan -> the best variant it gets, e.g. free = { max_height = 1080 }
+    pub plans: HashMap<String, VariantLimit>,
+    // For plans not listed and users without the claim; unset leaves their
+    // manifests whole
+    pub default: Option<VariantLimit>,
+}
+
+impl Default for ManifestConfig {
+    fn default() -> Self {
+        ManifestConfig {
+            plan_claim: "plan".to_string(),
+            plans: HashMap::new(),
+            default: None,
+        }
+    }
+}
+
+// Variants over either ceiling are left out
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct VariantLimit {
+    // Bits per second, BANDWIDTH in HLS and bandwidth in DASH
+    pub max_bandwidth: Option<u64>,
+    // Lines of the picture, from RESOLUTION in HLS and height in DASH
+    pub max_height: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct WatermarkConfig {
+    pub header: String,
+    // Also added to JSON object responses under this key
+    pub json_field: Option<String>,
+    // Key the marks are sealed with; env:NAME reads it from the environment.
+    // `watermark decode` needs the same one.
+    pub secret: String,
+    // The claim naming the user
+    pub user_claim: String,
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        WatermarkConfig {
+            header: "x-watermark".to_string(),
+            json_field: None,
+            secret: String::new(),
+            user_claim: "sub".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct WafConfig {
+    pub mode: WafMode,
+    // The rule set in waf.rs; off leaves only `rules`
+    pub builtin_rules: bool,
+    // Built-in rules to leave out, by name, when one misfires on a route
+    pub disabled_rules: Vec<String>,
+    pub rules: Vec<WafRule>,
+    // Bodies declaring more than this, or no length, aren't scanned, so
+    // large uploads still stream (0 = never scan bodies)
+    pub max_body_bytes: usize,
+}
+
+impl Default for WafConfig {
+    fn default() -> Self {
+        WafConfig {
+            mode: WafMode::Monitor,
+            builtin_rules: true,
+            disabled_rules: Vec::new(),
+            rules: Vec::new(),
+            max_body_bytes: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WafMode {
+    // Log and count matches, but let the request through
+    #[default]
+    Monitor,
+    // Refuse matching requests with a 403
+    Block,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct WafRule {
+    pub name: String,
+    // Regex over each decoded query value and body field
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    pub class_header: String,
+    pub os_header: String,
+    // Checked before the built-in rules, e.g. for the apps' own User-Agents
+    pub rules: Vec<DeviceRule>,
+    // Client Hints the request came with are also sent upstream checked and
+    // under plain names: <hint_prefix>dpr, viewport-width, network,
+    // downlink, save-data, mobile, platform, model, browser and
+    // browser-version. Empty leaves them out.
+    pub hint_prefix: String,
+    // Asked for with Accept-CH on HTML pages, such as DPR, Viewport-Width,
+    // ECT or Sec-CH-UA-Model. Those also in critical_hints go in
+    // Critical-CH, for the browser to retry the page with them.
+    pub request_hints: Vec<String>,
+    pub critical_hints: Vec<String>,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            class_header: "x-device-class".to_string(),
+            os_header: "x-device-os".to_string(),
+            rules: Vec::new(),
+            hint_prefix: "x-device-".to_string(),
+            request_hints: Vec::new(),
+            critical_hints: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DeviceRule {
+    // Matched anywhere in the User-Agent, ignoring case
+    pub contains: String,
+    pub class: String,
+    // Left to the built-in rules when empty
+    pub os: String,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct CsrfConfig {
+    pub cookie_name: String,
+    pub header_name: String,
+    pub cookie_path: String,
+    pub secure: bool,
+    // Requests without any cookies can't ride on a browser session, so API
+    // clients using bearer tokens aren't asked for a token
+    pub skip_without_cookies: bool,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        CsrfConfig {
+            cookie_name: "csrf_token".to_string(),
+            header_name: "x-csrf-token".to_string(),
+            cookie_path: "/".to_string(),
+            secure: true,
+            skip_without_cookies: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct GraphqlConfig {
+    // Operation names let through; empty allows any that isn't denied
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // Limits on a single operation, 0 for none. Complexity is the number of
+    // fields selected, counting each fragment use.
+    pub max_depth: usize,
+    pub max_complexity: usize,
+    // Operations per batched request
+    pub max_batch: usize,
+}
+
+impl Default for GraphqlConfig {
+    fn default() -> Self {
+        GraphqlConfig {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_depth: 12,
+            max_complexity: 500,
+            max_batch: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct OpenApiConfig {
+    // JSON or YAML spec file
+    pub spec: String,
+    // Prefix stripped from request paths before looking them up in the spec
+    pub base_path: String,
+    // Let through paths the spec doesn't describe instead of rejecting them
+    pub allow_unknown_paths: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ScriptConfig {
+    pub path: String,
+    // Abort runaway scripts after this many operations per hook call
+    pub max_operations: u64,
+    pub max_string_bytes: usize,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        ScriptConfig {
+            path: String::new(),
+            max_operations: 100_000,
+            max_string_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct WasmFilterConfig {
+    pub path: String,
+    // Instruction budget per hook call; running out aborts the request
+    pub fuel: u64,
+    pub max_memory_bytes: usize,
+}
+
+impl Default for WasmFilterConfig {
+    fn default() -> Self {
+        WasmFilterConfig {
+            path: String::new(),
+            fuel: 10_000_000,
+            max_memory_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    // Base URLs of the replicas
+    pub targets: Vec<String>,
+    // How requests are spread over them: "p2c" (the less loaded of two at
+    // random), "round_robin", "random", or a strategy a custom build
+    // registered, see balancer.rs
+    pub balancer: String,
+    // Lower-priority replica groups (e.g. another region), in order. They only
+    // get traffic once the healthy share of the groups above them drops below
+    // `failover_threshold`.
+    pub priority_groups: Vec<Vec<String>>,
+    pub failover_threshold: f64,
+    pub outlier_detection: Option<OutlierConfig>,
+    // TLS settings for https targets, e.g. a staging gateway's internal CA
+    pub tls: Option<UpstreamTlsConfig>,
+    // Address family to connect over when a target's host has both
+    pub ip_family: IpFamily,
+    // Cap on requests in flight to the pool, tuned from its latency
+    pub adaptive_concurrency: Option<AdaptiveConcurrencyConfig>,
+    // Send HTTP/1 header names as Content-Type rather than content-type, for
+    // upstreams that care. The client's own casing can't be kept: it's lost
+    // when the request is parsed.
+    pub title_case_headers: bool,
+    // Pooled connections idle this long are closed. Keep it below the
+    // upstream's own keep-alive timeout, or a request can be sent down a
+    // connection the upstream is closing and fail with "connection closed
+    // before message completed".
+    pub idle_timeout_secs: u64,
+    // TCP keep-alive probes on pooled connections this often, so ones a
+    // firewall or NAT dropped silently are noticed before they're reused
+    // (0 leaves them off)
+    pub tcp_keepalive_secs: u64,
+    // How connections to the targets are made: straight, or through an
+    // HTTP or SOCKS5 proxy such as an SSH-forwarded tunnel
+    pub transport: TransportConfig,
+    // Take `targets` from the healthy instances of a Consul service, kept
+    // up to date as they change, see discovery.rs
+    pub discovery: Option<DiscoveryConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    // Consul's HTTP API, usually the local agent
+    pub consul: String,
+    pub service: String,
+    // Only instances with this tag, and from this datacenter rather than
+    // the agent's
+    pub tag: Option<String>,
+    pub datacenter: Option<String>,
+    // ACL token; env:NAME reads it from the environment
+    pub token: String,
+    // Scheme of the targets made from the instances' addresses and ports
+    pub scheme: String,
+    // Longest a watch waits for Consul to report a change
+    pub wait_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            consul: "http://127.0.0.1:8500".to_string(),
+            service: String::new(),
+            tag: None,
+            datacenter: None,
+            token: String::new(),
+            scheme: "http".to_string(),
+            wait_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransportConfig {
+    #[default]
+    Direct,
+    // CONNECT for https targets, absolute-form requests for http ones
+    HttpConnect(ProxyTransportConfig),
+    // Targets' host names are resolved by the proxy, see socks.rs
+    Socks5(ProxyTransportConfig),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ProxyTransportConfig {
+    // host:port of the proxy
+    pub address: String,
+    // Sent when set; env:NAME reads the password from the environment
+    pub username: String,
+    pub password: String,
+}
+
+// The limit grows by one while latency stays near the lowest seen and the
+// limit is actually being reached, and shrinks by `backoff` when latency
+// rises past `tolerance` times the lowest or requests fail
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct AdaptiveConcurrencyConfig {
+    pub initial_limit: usize,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    pub tolerance: f64,
+    pub backoff: f64,
+    // Responses per adjustment
+    pub window_requests: u32,
+    // The lowest latency is relearned this often, so a lasting change in the
+    // upstream's normal latency isn't taken for overload forever
+    pub min_latency_reset_secs: u64,
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    fn default() -> Self {
+        AdaptiveConcurrencyConfig {
+            initial_limit: 20,
+            min_limit: 2,
+            max_limit: 1000,
+            tolerance: 2.0,
+            backoff: 0.9,
+            window_requests: 20,
+            min_latency_reset_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    // The resolver's order, falling back to the other family if the first
+    // is slow to connect (Happy Eyeballs)
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    // Never connect over the other family
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            targets: Vec::new(),
+            balancer: "p2c".to_string(),
+            priority_groups: Vec::new(),
+            failover_threshold: 0.7,
+            outlier_detection: None,
+            tls: None,
+            ip_family: IpFamily::Any,
+            adaptive_concurrency: None,
+            title_case_headers: false,
+            idle_timeout_secs: 90,
+            tcp_keepalive_secs: 0,
+            transport: TransportConfig::Direct,
+            discovery: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct UpstreamTlsConfig {
+    // PEM bundle of CAs trusted for this upstream
+    pub ca_file: Option<String>,
+    // Leave out the system's CAs, trusting only ca_file
+    pub only_ca_file: bool,
+    // Check that the certificate names the target's host; turn off for
+    // gateways whose certificate is issued for another name
+    pub verify_hostname: bool,
+    // Accept any certificate at all. Refused in production.
+    pub insecure_skip_verify: bool,
+    // Only connect when a certificate in the chain has one of these public
+    // keys, as sha256/<base64 SHA-256 of its SubjectPublicKeyInfo>, see
+    // pinning.rs
+    pub pins: Vec<String>,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        UpstreamTlsConfig {
+            ca_file: None,
+            only_ca_file: false,
+            verify_hostname: true,
+            insecure_skip_verify: false,
+            pins: Vec::new(),
+        }
+    }
+}
+
+// Passive health tracking: replicas that keep failing or are consistently slow
+// are taken out of rotation for a while, longer each time it happens
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct OutlierConfig {
+    pub consecutive_errors: u32,
+    // Responses slower than this count as slow; 0 disables latency ejection
+    pub latency_threshold_ms: u64,
+    pub consecutive_slow: u32,
+    pub base_ejection_secs: u64,
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        OutlierConfig {
+            consecutive_errors: 5,
+            latency_threshold_ms: 0,
+            consecutive_slow: 10,
+            base_ejection_secs: 30,
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct HedgeConfig {
+    // Fixed delay before hedging; when unset the route's observed p95 is used
+    pub delay_ms: Option<u64>,
+    // Delay used until enough latency samples have been seen
+    pub fallback_delay_ms: u64,
+    pub max_hedges: u32,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        HedgeConfig {
+            delay_ms: None,
+            fallback_delay_ms: 100,
+            max_hedges: 1,
+        }
+    }
+}
+
+// Only idempotent requests with a body that can be sent again are retried;
+// the rest, and 429s asking for a longer wait, reach the client with their
+// Retry-After
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    // Longest Retry-After waited out
+    pub max_wait_ms: u64,
+    pub max_retries: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            max_wait_ms: 1000,
+            max_retries: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, Serialize)]
+#[serde(default)]
+pub struct RouteConfig {
+    // Stable name for metrics, logs and the admin API. Unnamed routes are
+    // named after their path template or prefix, so /api/movies/{id} is
+    // api_movies_id.
+    pub name: String,
+    // Extra labels on the route's metric series and, as $tag_<name>, in its
+    // access log lines, e.g. team = "playback"
+    pub tags: HashMap<String, String>,
+    pub prefix: String,
+    pub upstream: String,
+    // Region from [geo] -> upstream for clients in it; everyone else goes to
+    // `upstream`
+    pub regions: HashMap<String, String>,
+    // Language tag -> upstream for clients preferring it by Accept-Language,
+    // e.g. fr = "http://catalog-fr:8080"; ahead of regions
+    pub languages: HashMap<String, String>,
+    // Predicate: the route only matches clients accepting one of these
+    // languages (empty = any language)
+    pub accept_languages: Vec<String>,
+    // The language assumed for clients that send no Accept-Language, or
+    // accept none of the route's languages
+    pub default_language: Option<String>,
+    pub cache: bool,
+    pub cache_policy: CachePolicyConfig,
+    // Require a valid JWT bearer token
+    pub jwt: bool,
+    // Extra attempts for idempotent requests that fail to connect or get a 502-504
+    pub retries: u32,
+    // Race a second request against another replica for slow GETs
+    pub hedge: Option<HedgeConfig>,
+    // Wait out an upstream's 429 and try again when its Retry-After is short,
+    // rather than passing it on
+    pub throttle: Option<ThrottleConfig>,
+    // In place of server.request_deadline_ms; 0 turns it off for the route
+    pub deadline_ms: Option<u64>,
+    // In place of server.upstream_timeout_secs for each upstream attempt,
+    // reading the body included; 0 waits as long as the upstream takes
+    pub upstream_timeout_secs: Option<u64>,
+    // The upstream holds requests until it has something to send, as with
+    // long polls. Such routes are exempt from server.upstream_timeout_secs
+    // and server.request_deadline_ms, keeping only limits of their own, and
+    // their responses are always streamed. The time they're held isn't
+    // taken for slowness by outlier detection, adaptive concurrency or
+    // latency-aware balancing.
+    pub long_poll: bool,
+    // Name from [authenticators], run before the middlewares. Takes the
+    // place of the jwt flag when set.
+    pub auth: Option<String>,
+    // Names from [middlewares], run in order after the jwt/cache flags above
+    pub middlewares: Vec<String>,
+    // Extra predicates on top of the prefix; all of the given ones must match.
+    // An empty list means any method.
+    pub methods: Vec<String>,
+    // Methods the route takes once it has matched; others are refused with a
+    // 405 rather than left to later routes (empty = all)
+    pub allowed_methods: Vec<String>,
+    // Path template such as /api/movies/{id}/stream, where {rest*} also
+    // matches across segments, or a raw regex with named groups
+    pub path: Option<String>,
+    pub path_regex: Option<String>,
+    // Header name -> regex the header value must match
+    pub headers: HashMap<String, String>,
+    // JSON body field -> regex its value must match, e.g. action = "^refund$".
+    // Nested fields are dotted (order.type); array items are numbered
+    // (items.0.sku). Requests to such routes have their body read first.
+    pub body: HashMap<String, String>,
+    // Upstream path built from the path captures, e.g. /v2/streams/{id}. On
+    // plain prefix routes it replaces the prefix.
+    pub rewrite: Option<String>,
+    // Where the {name} placeholders of a templated upstream URL, such as
+    // https://{region}.gateway.internal, get their values, see
+    // upstream_url.rs. Placeholders not listed come from the path capture
+    // of the same name.
+    pub upstream_vars: HashMap<String, UpstreamVar>,
+    // Decode gzip/deflate responses for clients whose Accept-Encoding doesn't
+    // allow them, and gzip plain responses for clients that do
+    pub decompress: bool,
+    pub compress: bool,
+    // Link header values added to HTML responses, e.g.
+    // "</static/app.css>; rel=preload; as=style" or
+    // "<https://images.example.com>; rel=preconnect"
+    pub preload_links: Vec<String>,
+    // The upstream speaks gRPC over cleartext HTTP/2. Native gRPC arrives on
+    // the [grpc] listener; gRPC-Web from browsers is translated here.
+    pub grpc: bool,
+    // The upstream sends trailers (checksums and the like) and takes
+    // cleartext HTTP/2 too. Only the [grpc] listener (h2c) relays them, to
+    // clients that send TE: trailers; the HTTP listeners can't and drop them,
+    // which is logged at startup.
+    pub trailers: bool,
+    // Headers added to the upstream request only, such as the secret a DRM
+    // license server expects, so it never reaches the frontend. A value of
+    // env:NAME is read from the environment. Always redacted from logs.
+    pub secret_headers: HashMap<String, String>,
+    // Overrides [server] max_response_body_bytes for this route
+    pub max_response_body_bytes: Option<usize>,
+    // Checks on the Content-Type of successful responses, see sniff.rs
+    pub content_type: Option<ContentTypeConfig>,
+    // Whether request and response bodies are read whole or passed on as
+    // they arrive, see buffering.rs
+    pub buffering: BufferingConfig,
+    // Strict mode: only these request headers are forwarded, the rest are
+    // dropped. Secret headers and the request ID are still added.
+    pub allowed_headers: Option<Vec<String>>,
+    // Replaces the tenant's or the global CORS policy on this route
+    pub cors: Option<CorsConfig>,
+    // Replaces the global [normalize] policy on this route
+    pub normalize: Option<NormalizeConfig>,
+    // Replaces the global [header_policy] on this route
+    pub header_policy: Option<HeaderPolicyConfig>,
+    // Send preflights on to the upstream instead of answering them here
+    pub forward_preflight: bool,
+    // Serve this instead of forwarding while it's enabled
+    pub mock: Option<MockConfig>,
+    // What GETs that come back with certain statuses get instead; the first
+    // that covers the status applies, see fallback.rs
+    pub fallbacks: Vec<FallbackConfig>,
+    // JSON Patch deltas for clients polling with the ETag they have, see
+    // delta.rs
+    pub delta: Option<DeltaConfig>,
+    // Answer double submits within a short window with the first one's
+    // response, see dedup.rs
+    pub dedup: Option<DedupConfig>,
+    // Per-title download accounting for the metering export, see
+    // metering.rs
+    pub downloads: Option<DownloadsConfig>,
+    // Range headers checked and normalized before they go upstream
+    pub range_check: Option<RangeCheckConfig>,
+    // Cutovers and maintenance windows; the first open one applies
+    pub schedule: Vec<ScheduleRule>,
+    // Takes the place of upstream (and regions) while set
+    pub blue_green: Option<BlueGreenConfig>,
+    // Which requests are shed first when the proxy is overloaded, see
+    // [load_shedding]
+    pub priority: Priority,
+    #[serde(skip)]
+    pub compiled: CompiledPredicates,
+}
+
+// Where a placeholder in an upstream URL gets its value
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+#[serde(tag = "from", rename_all = "snake_case")]
+pub enum VarSource {
+    Header { name: String },
+    Cookie { name: String },
+    Query { name: String },
+    // The path capture of the same name
+    Path,
+    // The client's [geo] region
+    Region,
+}
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Serialize)]
+pub struct UpstreamVar {
+    #[serde(flatten)]
+    pub source: VarSource,
+    // Regex the value must match, on top of the checks that keep it from
+    // changing the shape of the URL
+    #[serde(default)]
+    pub pattern: Option<String>,
+    // Used when the request has no valid value; without one the request is
+    // refused
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    // Playback and the like: only shed at max_in_flight
+    Critical,
+    #[default]
+    Normal,
+    // Beacons and analytics: the first to go
+    BestEffort,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompiledPredicates {
+    pub path: Option<Regex>,
+    pub headers: Vec<(String, Regex)>,
+    pub body: Vec<(Vec<String>, Regex)>,
+    pub secret_headers: Vec<(HeaderName, HeaderValue)>,
+    pub allowed_headers: Option<Vec<HeaderName>>,
+    pub schedule: Vec<Window>,
+    pub upstream_vars: Vec<Var>,
+}
+
+impl RouteConfig {
+    // Largest upstream response body allowed on this route, if any
+    pub fn response_limit(&self, server: &ServerConfig) -> Option<usize> {
+        match self.max_response_body_bytes {
+            Some(limit) => Some(limit).filter(|l| *l > 0),
+            None => Some(server.max_response_body_bytes).filter(|l| *l > 0),
+        }
+    }
+
+    // Check and compile the method, path and header predicates
+    fn compile(&mut self) -> Result<(), Error> {
+        let invalid = |what: &str, e: regex::Error| {
+            Error::other(format!(
+                "Route {} has an invalid {}: {}",
+                self.name, what, e
+            ))
+        };
+
+        let pattern = match (&self.path, &self.path_regex) {
+            (Some(_), Some(_)) => {
+                return Err(Error::other(format!(
+                    "Route {} can't have both path and path_regex",
+                    self.name
+                )))
+            }
+            (Some(template), None) => Some(template_regex(template)),
+            (None, Some(pattern)) => Some(pattern.clone()),
+            (None, None) => None,
+        };
+        let path = match pattern {
+            Some(pattern) => Some(Regex::new(&pattern).map_err(|e| invalid("path", e))?),
+            None => None,
+        };
+
+        if let Some(rewrite) = &self.rewrite {
+            let names: Vec<&str> = path
+                .iter()
+                .flat_map(|p| p.capture_names().flatten())
+                .collect();
+            for name in template_names(rewrite) {
+                if !names.contains(&name) {
+                    return Err(Error::other(format!(
+                        "Route {} rewrite uses {{{}}}, which the path doesn't capture",
+                        self.name, name
+                    )));
+                }
+            }
+        }
+
+        if let Some(downloads) = &self.downloads {
+            let captured = path
+                .iter()
+                .flat_map(|p| p.capture_names().flatten())
+                .any(|name| name == downloads.title);
+            if !captured {
+                return Err(Error::other(format!(
+                    "Route {} downloads title {} isn't captured by the path",
+                    self.name, downloads.title
+                )));
+            }
+            if !(1..=1000).contains(&downloads.segments) {
+                return Err(Error::other(format!(
+                    "Route {} downloads segments must be between 1 and 1000",
+                    self.name
+                )));
+            }
+        }
+        if self.throttle.as_ref().is_some_and(|t| t.max_retries == 0) {
+            return Err(Error::other(format!(
+                "Route {} throttle max_retries must be at least 1",
+                self.name
+            )));
+        }
+        if self.range_check.as_ref().is_some_and(|r| r.max_ranges == 0) {
+            return Err(Error::other(format!(
+                "Route {} range_check max_ranges must be at least 1",
+                self.name
+            )));
+        }
+
+        let upstream_vars = upstream_url::compile(self, path.as_ref())
+            .map_err(|e| Error::other(format!("Route {} {}", self.name, e)))?;
+
+        let mut headers = Vec::new();
+        for (name, pattern) in &self.headers {
+            let regex = Regex::new(pattern).map_err(|e| invalid("header pattern", e))?;
+            headers.push((name.to_ascii_lowercase(), regex));
+        }
+
+        let mut body = Vec::new();
+        for (field, pattern) in &self.body {
+            let regex = Regex::new(pattern).map_err(|e| invalid("body pattern", e))?;
+            body.push((field.split('.').map(str::to_string).collect(), regex));
+        }
+
+        for link in &self.preload_links {
+            if HeaderValue::from_str(link).is_err() {
+                return Err(Error::other(format!(
+                    "Route {} has an invalid preload link {}",
+                    self.name, link
+                )));
+            }
+        }
+
+        let mut secret_headers = Vec::new();
+        for (name, value) in &self.secret_headers {
+            let value = match value.strip_prefix("env:") {
+                Some(var) => env::var(var).map_err(|_| {
+                    Error::other(format!(
+                        "Route {} secret header {} needs the {} environment variable",
+                        self.name, name, var
+                    ))
+                })?,
+                None => value.clone(),
+            };
+            let invalid_header = || {
+                Error::other(format!(
+                    "Route {} has an invalid secret header {}",
+                    self.name, name
+                ))
+            };
+            let header = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid_header())?;
+            let mut value = HeaderValue::from_str(&value).map_err(|_| invalid_header())?;
+            value.set_sensitive(true);
+            secret_headers.push((header, value));
+        }
+
+        let allowed_headers = match &self.allowed_headers {
+            Some(names) => Some(
+                names
+                    .iter()
+                    .map(|name| {
+                        HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                            Error::other(format!(
+                                "Route {} has an invalid allowed header {}",
+                                self.name, name
+                            ))
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+
+        if let Some(policy) = &self.header_policy {
+            compliance::check(policy).map_err(|e| {
+                Error::other(format!(
+                    "Route {} has an invalid header policy: {}",
+                    self.name, e
+                ))
+            })?;
+        }
+
+        if let Some(mock) = &self.mock {
+            if !(100..=599).contains(&mock.status) {
+                return Err(Error::other(format!(
+                    "Route {} has an invalid mock status {}",
+                    self.name, mock.status
+                )));
+            }
+            if let Some(name) = mock
+                .headers
+                .keys()
+                .find(|name| HeaderName::from_bytes(name.as_bytes()).is_err())
+            {
+                return Err(Error::other(format!(
+                    "Route {} has an invalid mock header {}",
+                    self.name, name
+                )));
+            }
+        }
+
+        let schedule = self
+            .schedule
+            .iter()
+            .map(|rule| {
+                Window::parse(rule).map_err(|e| {
+                    Error::other(format!(
+                        "Route {} has an invalid schedule: {}",
+                        self.name, e
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for method in self.methods.iter_mut().chain(&mut self.allowed_methods) {
+            method.make_ascii_uppercase();
+        }
+        self.compiled = CompiledPredicates {
+            path,
+            headers,
+            body,
+            secret_headers,
+            allowed_headers,
+            schedule,
+            upstream_vars,
+        };
+        Ok(())
+    }
+
+    fn has_pattern(&self) -> bool {
+        self.compiled.path.is_some()
+    }
+
+    // What an unnamed route is called: its methods and path, with the
+    // parameters as their names
+    fn derived_name(&self) -> String {
+        let path = self.path.as_deref().unwrap_or(&self.prefix);
+        let mut name = String::new();
+        let methods = self.methods.iter().map(String::as_str);
+        for part in methods.chain(std::iter::once(path)) {
+            for c in part.chars() {
+                if c.is_ascii_alphanumeric() {
+                    name.push(c.to_ascii_lowercase());
+                } else if !name.is_empty() && !name.ends_with('_') {
+                    name.push('_');
+                }
+            }
+            if !name.is_empty() && !name.ends_with('_') {
+                name.push('_');
+            }
+        }
+        match name.trim_end_matches('_') {
+            "" => "root".to_string(),
+            name => name.to_string(),
+        }
+    }
+
+    fn is_constrained(&self) -> bool {
+        !self.methods.is_empty() || !self.headers.is_empty() || !self.body.is_empty()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+// Turn a path template into an anchored regex: {name} matches one segment,
+// {name*} the rest of the path
+pub fn template_regex(template: &str) -> String {
+    let mut out = String::from("^");
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&regex::escape(&rest[..open]));
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => {
+                rest = &rest[open..];
+                break;
+            }
+        };
+        let name = &rest[open + 1..close];
+        match name.strip_suffix('*') {
+            Some(name) => out.push_str(&format!("(?P<{}>.*)", name)),
+            None => out.push_str(&format!("(?P<{}>[^/]+)", name)),
+        }
+        rest = &rest[close + 1..];
+    }
+    out.push_str(&regex::escape(rest));
+    out.push('$');
+    out
+}
+
+fn template_names(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+}
+
+impl Config {
+    pub fn load() -> Result<Config, Error> {
+        let path = env::var("CONFIG_PATH").unwrap_or_else(|_| "config.toml".to_string());
+
+        let mut config = if Path::new(&path).exists() {
+            let raw = fs::read_to_string(&path)?;
+            toml::from_str(&raw)
+                .map_err(|e| Error::other(format!("Invalid config {}: {}", path, e)))?
+        } else {
+            Config::default()
+        };
+
+        if let Ok(environment) = env::var("ENVIRONMENT") {
+            config.environment = environment;
+        }
+        if config.environment.is_empty() {
+            config.environment = "production".to_string();
+        }
+
+        // Use PORT from Render, fallback to SERVER_PORT
+        if let Ok(port) = env::var("PORT").or_else(|_| env::var("SERVER_PORT")) {
+            config.server.port = port
+                .parse()
+                .map_err(|_| Error::other(format!("Invalid port: {}", port)))?;
+        }
+        if let Ok(port) = env::var("ADMIN_PORT") {
+            config.server.admin_port = port
+                .parse()
+                .map_err(|_| Error::other(format!("Invalid admin port: {}", port)))?;
+        }
+
+        // API_GATEWAY_URL becomes the catch-all route
+        if let Ok(url) = env::var("API_GATEWAY_URL") {
+            if !config.routes.iter().any(|r| r.prefix == "/") {
+                config.routes.push(RouteConfig {
+                    name: "default".to_string(),
+                    prefix: "/".to_string(),
+                    upstream: url,
+                    cache: config.cache.enabled,
+                    jwt: config.jwt.is_some(),
+                    ..Default::default()
+                });
+            }
+        }
+
+        if let Some(jwt) = &config.jwt {
+            for header in jwt.claim_headers.values() {
+                if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    return Err(Error::other(format!(
+                        "Invalid claim header name {}",
+                        header
+                    )));
+                }
+            }
+        }
+
+        for header in &config.redaction.headers {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(Error::other(format!(
+                    "Invalid redacted header name {}",
+                    header
+                )));
+            }
+        }
+
+        if HeaderName::from_bytes(config.sampling.debug_header.as_bytes()).is_err() {
+            return Err(Error::other(format!(
+                "Invalid sampling debug header {}",
+                config.sampling.debug_header
+            )));
+        }
+
+        if config.cache.chunk_bytes > config.cache.max_entry_bytes {
+            return Err(Error::other(
+                "Cache chunk_bytes can't be larger than max_entry_bytes",
+            ));
+        }
+
+        if let Some(streams) = &config.streams {
+            if HeaderName::from_bytes(streams.session_header.as_bytes()).is_err() {
+                return Err(Error::other(format!(
+                    "Invalid streams session header {}",
+                    streams.session_header
+                )));
+            }
+            if !matches!(streams.status, 409 | 429) {
+                return Err(Error::other("Streams status must be 409 or 429"));
+            }
+            if !streams.heartbeat_path.is_empty() && config.jwt.is_none() {
+                return Err(Error::other(
+                    "The streams heartbeat endpoint needs a [jwt] section",
+                ));
+            }
+        }
+
+        bind::addrs(&config.server.bind_addresses, config.server.port)?;
+        bind::addrs(
+            &config.server.admin_bind_addresses,
+            config.server.admin_port,
+        )?;
+        let listeners = [
+            config.grpc.as_ref().map(|grpc| &grpc.bind_addresses),
+            config.http3.as_ref().map(|http3| &http3.bind_addresses),
+        ];
+        for addresses in listeners.into_iter().flatten() {
+            if !addresses.is_empty() {
+                bind::addrs(addresses, 0)?;
+            }
+        }
+
+        if config.http3.is_some() {
+            if cfg!(not(feature = "http3")) {
+                return Err(Error::other(
+                    "[http3] is configured but this build lacks the http3 feature",
+                ));
+            }
+            if config.tls.is_none() {
+                return Err(Error::other("[http3] needs a [tls] section"));
+            }
+        }
+
+        if let Some(alt_svc) = &config.alt_svc {
+            let token = |p: &String| {
+                !p.is_empty()
+                    && p.bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b))
+            };
+            if !alt_svc.clear
+                && (alt_svc.protocols.is_empty() || !alt_svc.protocols.iter().all(token))
+            {
+                return Err(Error::other(
+                    "alt_svc needs one or more protocol ids such as h3",
+                ));
+            }
+        }
+
+        // Pools and API keys saved through the admin API
+        let mut stored_routes = false;
+        if !config.admin.database_path.is_empty() {
+            let store = Store::open(&config.admin.database_path)?;
+            store.apply(&mut config)?;
+            stored_routes = store.routes()?.is_some();
+        }
+
+        if config.routes.is_empty() && !stored_routes {
+            return Err(Error::other(
+                "API_GATEWAY_URL not found in .env and no routes configured",
+            ));
+        }
+
+        // Host names are matched case-insensitively
+        let mut overrides = HashMap::new();
+        for (host, addrs) in std::mem::take(&mut config.dns.overrides) {
+            if addrs.is_empty() {
+                return Err(Error::other(format!(
+                    "DNS override for {} needs at least one address",
+                    host
+                )));
+            }
+            overrides.insert(host.trim_end_matches('.').to_ascii_lowercase(), addrs);
+        }
+        config.dns.overrides = overrides;
+
+        if let Some(shedding) = &config.load_shedding {
+            let percent = 0.0..=100.0;
+            if shedding.max_in_flight == 0
+                || !percent.contains(&shedding.normal_percent)
+                || !percent.contains(&shedding.best_effort_percent)
+            {
+                return Err(Error::other(
+                    "[load_shedding] needs max_in_flight > 0 and percentages between 0 and 100",
+                ));
+            }
+        }
+
+        if let Some(checksums) = &mut config.checksums {
+            for algorithm in &mut checksums.algorithms {
+                *algorithm = algorithm.to_ascii_lowercase();
+                if !checksum::ALGORITHMS.contains(&algorithm.as_str()) {
+                    return Err(Error::other(format!(
+                        "Unknown checksum algorithm {} (expected one of {})",
+                        algorithm,
+                        checksum::ALGORITHMS.join(", ")
+                    )));
+                }
+            }
+        }
+
+        for (name, pool) in &config.upstreams {
+            if (pool.targets.is_empty() && pool.discovery.is_none())
+                || pool.priority_groups.iter().any(|g| g.is_empty())
+            {
+                return Err(Error::other(format!(
+                    "Upstream {} has an empty target group",
+                    name
+                )));
+            }
+            if let Some(discovery) = &pool.discovery {
+                if discovery.service.is_empty()
+                    || !["http", "https"].contains(&discovery.scheme.as_str())
+                    || !discovery.consul.starts_with("http")
+                {
+                    return Err(Error::other(format!(
+                        "Upstream {} discovery needs a service, an http(s) Consul URL \
+                         and a scheme of http or https",
+                        name
+                    )));
+                }
+                if discovery.wait_secs == 0 {
+                    return Err(Error::other(format!(
+                        "Upstream {} discovery wait_secs must be above 0",
+                        name
+                    )));
+                }
+            }
+            if let Some(limits) = &pool.adaptive_concurrency {
+                if limits.min_limit == 0
+                    || !(limits.min_limit..=limits.max_limit).contains(&limits.initial_limit)
+                    || !(limits.backoff > 0.0 && limits.backoff < 1.0)
+                    || limits.tolerance < 1.0
+                    || limits.window_requests == 0
+                {
+                    return Err(Error::other(format!(
+                        "Upstream {} adaptive_concurrency needs 0 < min_limit <= initial_limit \
+                         <= max_limit, 0 < backoff < 1, tolerance >= 1 and window_requests > 0",
+                        name
+                    )));
+                }
+            }
+            if let Some(tls) = &pool.tls {
+                if let Some(pin) = tls.pins.iter().find(|pin| pinning::parse(pin).is_none()) {
+                    return Err(Error::other(format!(
+                        "Upstream {} pin {} must be sha256/ and the base64 of a SHA-256 hash",
+                        name, pin
+                    )));
+                }
+                if !tls.pins.is_empty() && tls.insecure_skip_verify {
+                    return Err(Error::other(format!(
+                        "Upstream {} can't pin certificates and skip verifying them",
+                        name
+                    )));
+                }
+                if !tls.pins.is_empty() && pool.targets.iter().any(|t| t.starts_with("http://")) {
+                    return Err(Error::other(format!(
+                        "Upstream {} pins certificates but has plain http targets",
+                        name
+                    )));
+                }
+            }
+            if let Some(tls) = pool.tls.as_ref().filter(|tls| tls.insecure_skip_verify) {
+                if config.environment == "production" {
+                    return Err(Error::other(format!(
+                        "Upstream {} sets insecure_skip_verify, which is refused in production",
+                        name
+                    )));
+                }
+                if tls.ca_file.is_some() || !tls.verify_hostname {
+                    eprintln!(
+                        "Upstream {}: insecure_skip_verify makes ca_file and verify_hostname moot",
+                        name
+                    );
+                }
+            }
+        }
+
+        for (name, token) in &config.admin.tokens {
+            if token.token.is_empty() || !config.admin.roles.contains_key(&token.role) {
+                return Err(Error::other(format!(
+                    "Admin token {} needs a token and one of the configured roles",
+                    name
+                )));
+            }
+        }
+
+        if let Some(policy) = &config.header_policy {
+            compliance::check(policy)
+                .map_err(|e| Error::other(format!("Invalid [header_policy]: {}", e)))?;
+        }
+
+        if let Some(context) = &config.context {
+            let headers = [
+                &context.session_header,
+                &context.device_header,
+                &context.correlation_header,
+            ];
+            if let Some(name) = headers
+                .iter()
+                .find(|name| HeaderName::from_bytes(name.as_bytes()).is_err())
+            {
+                return Err(Error::other(format!("Invalid [context] header {}", name)));
+            }
+        }
+
+        if let Some(egress) = &config.egress {
+            Policy::new(egress)?;
+            for (name, pool) in &config.upstreams {
+                for target in pool
+                    .targets
+                    .iter()
+                    .chain(pool.priority_groups.iter().flatten())
+                {
+                    config.check_egress(&format!("Upstream {}", name), target)?;
+                }
+            }
+        }
+
+        if let Some(forward) = &config.forward_proxy {
+            if forward.allowed_hosts.is_empty() || forward.users.is_empty() {
+                return Err(Error::other(
+                    "[forward_proxy] needs allowed_hosts and at least one user",
+                ));
+            }
+            if forward.idle_timeout_secs == 0 {
+                return Err(Error::other(
+                    "[forward_proxy] idle_timeout_secs must be above 0",
+                ));
+            }
+        }
+
+        if let Some(kafka) = &config.kafka {
+            check_kafka("[kafka]", kafka)?;
+        }
+        if let Some(MeteringConfig {
+            sink: SinkConfig::Kafka(kafka),
+            ..
+        }) = &config.metering
+        {
+            check_kafka("[metering.sink]", kafka)?;
+        }
+
+        if let Some(cluster) = &config.cluster {
+            if !cluster.url.starts_with("nats://") {
+                return Err(Error::other("[cluster] url must be nats://host:port"));
+            }
+            let subject = &cluster.subject;
+            if subject.is_empty() || subject.contains([' ', '*', '>']) {
+                return Err(Error::other(
+                    "[cluster] subject must be a plain NATS subject, without spaces or wildcards",
+                ));
+            }
+        }
+
+        let mut slo_names = HashSet::new();
+        for slo in &config.slos {
+            if slo.name.is_empty() || slo.route.is_empty() {
+                return Err(Error::other("SLOs need a name and a route"));
+            }
+            if !slo_names.insert(&slo.name) {
+                return Err(Error::other(format!("SLO {} is defined twice", slo.name)));
+            }
+            if !(slo.objective > 0.0 && slo.objective < 1.0) {
+                return Err(Error::other(format!(
+                    "SLO {} objective must be between 0 and 1",
+                    slo.name
+                )));
+            }
+            if slo.budget_days == 0 {
+                return Err(Error::other(format!(
+                    "SLO {} budget_days must be at least 1",
+                    slo.name
+                )));
+            }
+            for alert in &slo.burn_alerts {
+                if alert.short_secs == 0
+                    || alert.short_secs >= alert.long_secs
+                    || alert.long_secs > slo.budget_days * 86400
+                    || alert.burn_rate <= 0.0
+                {
+                    return Err(Error::other(format!(
+                        "SLO {} burn alerts need a short window shorter than the long one, which fits in the budget window, and a positive burn rate",
+                        slo.name
+                    )));
+                }
+            }
+        }
+
+        if let Some(ldap) = &config.admin.ldap {
+            if !ldap.bind_dn.contains("{user}") {
+                return Err(Error::other(
+                    "[admin.ldap] bind_dn needs a {user} placeholder",
+                ));
+            }
+            let roles = ldap
+                .users
+                .values()
+                .chain(Some(&ldap.role).filter(|r| !r.is_empty()));
+            if let Some(role) = roles
+                .into_iter()
+                .find(|r| !config.admin.roles.contains_key(*r))
+            {
+                return Err(Error::other(format!(
+                    "[admin.ldap] names role {}, which isn't configured",
+                    role
+                )));
+            }
+        }
+
+        let mut routes = std::mem::take(&mut config.routes);
+        config.prepare_routes(&mut routes)?;
+        config.routes = routes;
+
+        Ok(config)
+    }
+
+    pub fn is_upstream(&self, upstream: &str) -> bool {
+        upstream.starts_with("http://")
+            || upstream.starts_with("https://")
+            || self.upstreams.contains_key(upstream)
+    }
+
+    // Upstream URLs outside the [egress] allowlist are refused when the
+    // config is loaded or routes are edited, not just when they're used
+    fn check_egress(&self, what: &str, upstream: &str) -> Result<(), Error> {
+        let Some(egress) = &self.egress else {
+            return Ok(());
+        };
+        // Templates are checked once they're filled in
+        if self.upstreams.contains_key(upstream) || upstream.contains('{') {
+            return Ok(());
+        }
+        Policy::new(egress)?.check(upstream).map_err(|e| {
+            Error::other(format!(
+                "{} upstream {} is outside the [egress] allowlist: {}",
+                what, upstream, e
+            ))
+        })
+    }
+
+    // Fill in defaults and check a routing table against the rest of the config.
+    // Used both at startup and when routes are edited through the admin API.
+    fn check_middlewares(&self, route: &RouteConfig) -> Result<(), Error> {
+        let mut authenticated = match &route.auth {
+            Some(auth) => matches!(
+                self.authenticators.get(auth),
+                Some(AuthenticatorConfig::Jwt(_))
+            ),
+            None => route.jwt,
+        };
+        for name in &route.middlewares {
+            let problem = match self.middlewares.get(name) {
+                None => "isn't defined in [middlewares]",
+                Some(MiddlewareConfig::Jwt { .. }) if self.jwt.is_none() => "needs a [jwt] section",
+                Some(MiddlewareConfig::Quota) if self.quota.is_none() => "needs a [quota] section",
+                Some(MiddlewareConfig::Quota) if !authenticated => {
+                    "must come after a jwt middleware"
+                }
+                Some(MiddlewareConfig::Streams) if self.streams.is_none() => {
+                    "needs a [streams] section"
+                }
+                Some(MiddlewareConfig::Streams) if !authenticated => {
+                    "must come after a jwt middleware"
+                }
+                Some(MiddlewareConfig::Device(device))
+                    if [&device.class_header, &device.os_header]
+                        .iter()
+                        .any(|name| HeaderName::from_bytes(name.as_bytes()).is_err()) =>
+                {
+                    "has an invalid header name"
+                }
+                Some(MiddlewareConfig::Device(device))
+                    if device.rules.iter().any(|rule| {
+                        HeaderValue::from_str(&rule.class).is_err()
+                            || HeaderValue::from_str(&rule.os).is_err()
+                    }) =>
+                {
+                    "has a rule with an invalid class or os"
+                }
+                Some(MiddlewareConfig::Device(device))
+                    if (device.request_hints.iter())
+                        .chain(&device.critical_hints)
+                        .chain(Some(&format!("{}dpr", device.hint_prefix)))
+                        .any(|name| HeaderName::from_bytes(name.as_bytes()).is_err()) =>
+                {
+                    "has an invalid hint or hint_prefix"
+                }
+                Some(MiddlewareConfig::Device(device))
+                    if device.critical_hints.iter().any(|hint| {
+                        !(device.request_hints.iter()).any(|h| h.eq_ignore_ascii_case(hint))
+                    }) =>
+                {
+                    "has critical_hints that aren't in request_hints"
+                }
+                Some(MiddlewareConfig::Watermark(_)) if !authenticated => {
+                    "must come after a jwt middleware"
+                }
+                Some(MiddlewareConfig::Watermark(watermark))
+                    if HeaderName::from_bytes(watermark.header.as_bytes()).is_err() =>
+                {
+                    "has an invalid header name"
+                }
+                Some(MiddlewareConfig::Manifest(_)) if !authenticated => {
+                    "must come after a jwt middleware"
+                }
+                Some(MiddlewareConfig::Upload(upload))
+                    if upload.max_bytes > self.server.max_request_body_bytes =>
+                {
+                    "has max_bytes over server.max_request_body_bytes"
+                }
+                Some(MiddlewareConfig::Upload(UploadConfig {
+                    presign: Some(presign),
+                    ..
+                })) if presign.host.is_empty() && !route.upstream.contains("://") => {
+                    "needs presign.host when the route's upstream is a pool"
+                }
+                Some(MiddlewareConfig::Jwt { .. }) => {
+                    authenticated = true;
+                    continue;
+                }
+                Some(_) => continue,
+            };
+            return Err(Error::other(format!(
+                "Route {} middleware {} {}",
+                route.name, name, problem
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn prepare_routes(&self, routes: &mut [RouteConfig]) -> Result<(), Error> {
+        let mut taken: HashSet<String> = routes.iter().map(|r| r.name.clone()).collect();
+        for (i, route) in routes.iter_mut().enumerate() {
+            if route.name.is_empty() {
+                let mut name = route.derived_name();
+                if taken.contains(&name) {
+                    name = format!("{}_{}", name, i);
+                }
+                taken.insert(name.clone());
+                route.name = name;
+            }
+        }
+        for route in routes.iter_mut() {
+            for tag in route.tags.keys() {
+                let valid = tag.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                    && (tag.chars())
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+                if !valid || tag == "route" {
+                    return Err(Error::other(format!(
+                        "Route {} tag {} must be a lowercase label name other than route",
+                        route.name, tag
+                    )));
+                }
+            }
+            // Routes matched on their path don't need a prefix
+            if route.prefix.is_empty() && (route.path.is_some() || route.path_regex.is_some()) {
+                route.prefix = "/".to_string();
+            }
+            if !route.prefix.starts_with('/') {
+                return Err(Error::other(format!(
+                    "Route {} prefix must start with /",
+                    route.name
+                )));
+            }
+            if !self.is_upstream(&route.upstream) {
+                return Err(Error::other(format!(
+                    "Route {} upstream must be an http(s) URL or a configured upstream pool",
+                    route.name
+                )));
+            }
+            route.upstream = route.upstream.trim_end_matches('/').to_string();
+            if route.trailers && route.grpc {
+                return Err(Error::other(format!(
+                    "Route {} is a gRPC route, which relays trailers already",
+                    route.name
+                )));
+            }
+            if route.long_poll {
+                // Every hedge would be held as long as the first
+                if route.hedge.is_some() {
+                    return Err(Error::other(format!(
+                        "Route {} is a long_poll route and can't hedge",
+                        route.name
+                    )));
+                }
+                if route.buffering.mode == BufferingMode::Buffered {
+                    return Err(Error::other(format!(
+                        "Route {} is a long_poll route and can't use buffered mode",
+                        route.name
+                    )));
+                }
+                route.buffering.mode = BufferingMode::Streaming;
+            }
+            if !route.regions.is_empty() {
+                let geo = self.geo.as_ref().ok_or_else(|| {
+                    Error::other(format!(
+                        "Route {} has regions but no [geo] section is configured",
+                        route.name
+                    ))
+                })?;
+                for (region, upstream) in &mut route.regions {
+                    if !geo.regions.contains_key(region) && *region != geo.default_region {
+                        return Err(Error::other(format!(
+                            "Route {} region {} isn't defined in [geo] regions",
+                            route.name, region
+                        )));
+                    }
+                    if !self.is_upstream(upstream) {
+                        return Err(Error::other(format!(
+                            "Route {} region {} upstream must be an http(s) URL or a configured upstream pool",
+                            route.name, region
+                        )));
+                    }
+                    *upstream = upstream.trim_end_matches('/').to_string();
+                }
+            }
+            let from_region = route
+                .upstream_vars
+                .values()
+                .any(|var| matches!(var.source, VarSource::Region));
+            if route.downloads.is_some() && self.metering.is_none() {
+                return Err(Error::other(format!(
+                    "Route {} has downloads but no [metering] section is configured",
+                    route.name
+                )));
+            }
+            if from_region && self.geo.is_none() {
+                return Err(Error::other(format!(
+                    "Route {} takes an upstream variable from the region but no [geo] section is configured",
+                    route.name
+                )));
+            }
+            for (language, upstream) in &mut route.languages {
+                if !self.is_upstream(upstream) {
+                    return Err(Error::other(format!(
+                        "Route {} language {} upstream must be an http(s) URL or a configured upstream pool",
+                        route.name, language
+                    )));
+                }
+                *upstream = upstream.trim_end_matches('/').to_string();
+            }
+            if let Some(blue_green) = &mut route.blue_green {
+                if !blue_green.groups.contains_key(&blue_green.live) {
+                    return Err(Error::other(format!(
+                        "Route {} live group {} isn't one of its blue_green groups",
+                        route.name, blue_green.live
+                    )));
+                }
+                for (group, upstream) in &mut blue_green.groups {
+                    if !self.is_upstream(upstream) {
+                        return Err(Error::other(format!(
+                            "Route {} group {} upstream must be an http(s) URL or a configured upstream pool",
+                            route.name, group
+                        )));
+                    }
+                    *upstream = upstream.trim_end_matches('/').to_string();
+                }
+            }
+            for rule in &mut route.schedule {
+                if let Some(upstream) = &mut rule.upstream {
+                    if !self.is_upstream(upstream) {
+                        return Err(Error::other(format!(
+                            "Route {} scheduled upstream {} must be an http(s) URL or a configured upstream pool",
+                            route.name, upstream
+                        )));
+                    }
+                    *upstream = upstream.trim_end_matches('/').to_string();
+                }
+            }
+            for fallback in &mut route.fallbacks {
+                let actions = [
+                    fallback.file.is_some(),
+                    fallback.upstream.is_some(),
+                    fallback.stale,
+                ];
+                if actions.iter().filter(|set| **set).count() != 1 {
+                    return Err(Error::other(format!(
+                        "Route {} fallbacks need one of file, upstream and stale",
+                        route.name
+                    )));
+                }
+                if let Some(status) = fallback
+                    .statuses
+                    .iter()
+                    .find(|status| !(100..=599).contains(*status))
+                {
+                    return Err(Error::other(format!(
+                        "Route {} has a fallback for an invalid status {}",
+                        route.name, status
+                    )));
+                }
+                if let Some(upstream) = &mut fallback.upstream {
+                    if !self.is_upstream(upstream) {
+                        return Err(Error::other(format!(
+                            "Route {} fallback upstream {} must be an http(s) URL or a configured upstream pool",
+                            route.name, upstream
+                        )));
+                    }
+                    *upstream = upstream.trim_end_matches('/').to_string();
+                }
+            }
+            let upstreams = std::iter::once(&route.upstream)
+                .chain(route.regions.values())
+                .chain(route.languages.values())
+                .chain(route.blue_green.iter().flat_map(|b| b.groups.values()))
+                .chain(route.schedule.iter().filter_map(|r| r.upstream.as_ref()))
+                .chain(route.fallbacks.iter().filter_map(|f| f.upstream.as_ref()));
+            for upstream in upstreams {
+                self.check_egress(&format!("Route {}", route.name), upstream)?;
+            }
+            route.compile()?;
+            match &route.auth {
+                Some(auth) if !self.authenticators.contains_key(auth) => {
+                    return Err(Error::other(format!(
+                        "Route {} auth {} isn't defined in [authenticators]",
+                        route.name, auth
+                    )));
+                }
+                None if route.jwt && self.jwt.is_none() => {
+                    return Err(Error::other(format!(
+                        "Route {} requires a JWT but no [jwt] section is configured",
+                        route.name
+                    )));
+                }
+                _ => {}
+            }
+            self.check_middlewares(route)?;
+        }
+
+        for (i, route) in routes.iter().enumerate() {
+            if routes[..i].iter().any(|r| r.name == route.name) {
+                return Err(Error::other(format!("Duplicate route name {}", route.name)));
+            }
+        }
+
+        for (name, tenant) in &self.tenants {
+            for upstream in tenant.upstream.iter().chain(tenant.routes.values()) {
+                if !self.is_upstream(upstream) {
+                    return Err(Error::other(format!(
+                        "Tenant {} upstream {} is neither a URL nor a configured pool",
+                        name, upstream
+                    )));
+                }
+                self.check_egress(&format!("Tenant {}", name), upstream)?;
+            }
+            for route in tenant.routes.keys() {
+                if !routes.iter().any(|r| &r.name == route) {
+                    return Err(Error::other(format!(
+                        "Tenant {} overrides unknown route {}",
+                        name, route
+                    )));
+                }
+            }
+        }
+
+        // Path patterns first, in config order, then the longest prefix; on the
+        // same prefix, routes with method, header or body conditions go first
+        routes.sort_by_key(|r| {
+            (
+                !r.has_pattern(),
+                std::cmp::Reverse(r.prefix.len()),
+                !r.is_constrained(),
+            )
+        });
+
+        Ok(())
+    }
+}
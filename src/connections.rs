@@ -0,0 +1,190 @@
+use crate::metrics;
+use hyper::client::connect::HttpInfo;
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+// How often an upstream address gets a timing probe
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Tells new upstream connections from reused ones by the local and remote
+// address pair each response arrived on. The client doesn't expose how long
+// its own connections took to open, so each new connection triggers (at most
+// once a minute per address) a probe that times a TCP connect and TLS
+// handshake to the same address.
+#[derive(Default)]
+pub struct Connections {
+    // When each connection was last seen and how long its pool keeps idle
+    // ones: one not seen for that long is gone and its local port may be
+    // handed out again
+    seen: Mutex<HashMap<(SocketAddr, SocketAddr), (Instant, Duration)>>,
+    // Upstreams already warned about closing connections before the pool does
+    warned: Mutex<HashSet<String>>,
+    probed: Mutex<HashMap<SocketAddr, Instant>>,
+    // What the last probe of each address took to connect and handshake
+    setup: Arc<Mutex<HashMap<SocketAddr, Duration>>>,
+}
+
+impl Connections {
+    // Count the connection the response came over, from a pool keeping idle
+    // connections for `idle`; true if it's a new one
+    pub fn observe(
+        &self,
+        upstream: &str,
+        target: &str,
+        response: &reqwest::Response,
+        idle: Duration,
+    ) -> bool {
+        let info = match response.extensions().get::<HttpInfo>() {
+            Some(info) => info,
+            None => return false,
+        };
+        self.check_keep_alive(upstream, response, idle);
+        let key = (info.local_addr(), info.remote_addr());
+        let now = Instant::now();
+        let new = {
+            let mut seen = self.seen.lock().unwrap();
+            let new = seen
+                .insert(key, (now, idle))
+                .is_none_or(|(last, idle)| now - last > idle);
+            if new {
+                seen.retain(|_, (last, idle)| now - *last <= *idle);
+            }
+            new
+        };
+        let kind = if new { "new" } else { "reused" };
+        metrics::inc(
+            "upstream_connections_total",
+            &[("upstream", upstream), ("kind", kind)],
+        );
+        if new {
+            self.probe(upstream, target, info.remote_addr());
+        }
+        new
+    }
+
+    // An upstream announcing (Keep-Alive: timeout=N) that it drops idle
+    // connections before the pool would is where requests on stale
+    // connections come from; say so once
+    fn check_keep_alive(&self, upstream: &str, response: &reqwest::Response, idle: Duration) {
+        let timeout = response
+            .headers()
+            .get("keep-alive")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.split(',')
+                    .find_map(|param| param.trim().strip_prefix("timeout="))
+                    .and_then(|secs| secs.trim().parse::<u64>().ok())
+            });
+        let Some(timeout) = timeout else {
+            return;
+        };
+        if Duration::from_secs(timeout) > idle
+            || !self.warned.lock().unwrap().insert(upstream.to_string())
+        {
+            return;
+        }
+        eprintln!(
+            "WARNING: upstream {} closes idle connections after {}s, but they're kept for {}s; lower its idle_timeout_secs",
+            upstream,
+            timeout,
+            idle.as_secs()
+        );
+    }
+
+    // How long opening a connection to the address took when last probed
+    pub fn setup(&self, addr: SocketAddr) -> Option<Duration> {
+        self.setup.lock().unwrap().get(&addr).copied()
+    }
+
+    fn probe(&self, upstream: &str, target: &str, addr: SocketAddr) {
+        let now = Instant::now();
+        {
+            let mut probed = self.probed.lock().unwrap();
+            if probed
+                .get(&addr)
+                .is_some_and(|last| now - *last < PROBE_INTERVAL)
+            {
+                return;
+            }
+            probed.retain(|_, last| now - *last < PROBE_INTERVAL);
+            probed.insert(addr, now);
+            self.setup
+                .lock()
+                .unwrap()
+                .retain(|addr, _| probed.contains_key(addr));
+        }
+        let url = match Url::parse(target) {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let upstream = upstream.to_string();
+        let setup = self.setup.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(PROBE_TIMEOUT, time_connection(&upstream, &url, addr)).await
+            {
+                Ok(Some(took)) => {
+                    setup.lock().unwrap().insert(addr, took);
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    metrics::inc("upstream_probe_timeouts_total", &[("upstream", &upstream)]);
+                }
+            }
+        });
+    }
+}
+
+// How long the connect (and handshake, for https) took, if they succeeded
+async fn time_connection(upstream: &str, url: &Url, addr: SocketAddr) -> Option<Duration> {
+    let labels = [("upstream", upstream)];
+    let started = Instant::now();
+    let stream = match TcpStream::connect(addr).await {
+        Ok(stream) => stream,
+        Err(_) => {
+            metrics::inc("upstream_tcp_connect_errors_total", &labels);
+            return None;
+        }
+    };
+    let connected = started.elapsed();
+    metrics::add(
+        "upstream_tcp_connect_seconds_total",
+        &labels,
+        connected.as_secs_f64(),
+    );
+    metrics::inc("upstream_tcp_connects_total", &labels);
+    if url.scheme() != "https" {
+        return Some(connected);
+    }
+
+    // Only the handshake is timed and nothing is sent over it, so the
+    // certificate isn't checked here; the real connections check it
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build();
+    let connector = TlsConnector::from(connector.ok()?);
+    let started = Instant::now();
+    let host = url.host_str().unwrap_or_default();
+    match connector.connect(host, stream).await {
+        Ok(_) => {
+            let handshake = started.elapsed();
+            metrics::add(
+                "upstream_tls_handshake_seconds_total",
+                &labels,
+                handshake.as_secs_f64(),
+            );
+            metrics::inc("upstream_tls_handshakes_total", &labels);
+            Some(connected + handshake)
+        }
+        Err(_) => {
+            metrics::inc("upstream_tls_handshake_errors_total", &labels);
+            None
+        }
+    }
+}
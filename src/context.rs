@@ -0,0 +1,161 @@
+use crate::config::ContextConfig;
+use crate::keys;
+use crate::metrics;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse};
+use ring::rand::{SecureRandom, SystemRandom};
+
+// The IDs backend logs are joined by: the browser session, the device and
+// the chain of calls one client action started. Whatever the client doesn't
+// send (or sends garbled) is made up here, so every forwarded request has
+// all three.
+
+// One ID and whether the client lacked it
+struct Id {
+    value: String,
+    generated: bool,
+}
+
+// The IDs of one request
+pub struct Context {
+    session: Id,
+    device: Id,
+    correlation: Id,
+    // Cookies to hand out for IDs the client had no cookie for at all (one
+    // it can't use may be the application's own), with their Max-Age
+    cookies: Vec<(String, String, Option<u64>)>,
+}
+
+// Only IDs that are safe to put in headers and logs are taken as they are
+fn usable(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 128
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    keys::hex(&bytes)
+}
+
+fn pick(name: &'static str, found: Option<String>, fallback: impl FnOnce() -> String) -> Id {
+    let (id, source) = match found.filter(|v| usable(v)) {
+        Some(value) => (
+            Id {
+                value,
+                generated: false,
+            },
+            "client",
+        ),
+        None => (
+            Id {
+                value: fallback(),
+                generated: true,
+            },
+            "generated",
+        ),
+    };
+    metrics::inc("context_ids_total", &[("id", name), ("source", source)]);
+    id
+}
+
+fn header_value(req: &HttpRequest, name: &str) -> Option<String> {
+    let value = req.headers().get(name)?.to_str().ok()?;
+    Some(value.trim().to_string())
+}
+
+fn cookie_value(req: &HttpRequest, name: &str) -> Option<String> {
+    Some(req.cookie(name)?.value().to_string())
+}
+
+fn insert(headers: &mut HeaderMap, name: &str, value: &str) {
+    if let (Ok(name), Ok(value)) = (
+        HeaderName::from_bytes(name.as_bytes()),
+        HeaderValue::from_str(value),
+    ) {
+        headers.insert(name, value);
+    }
+}
+
+// Work out the request's IDs and put them on the headers going upstream,
+// replacing any the client sent
+pub fn decorate(
+    config: &ContextConfig,
+    req: &HttpRequest,
+    request_id: &str,
+    headers: &mut HeaderMap,
+) -> Context {
+    let session = pick(
+        "session",
+        cookie_value(req, &config.session_cookie),
+        generate,
+    );
+    let device = header_value(req, &config.device_header)
+        .filter(|v| usable(v))
+        .or_else(|| cookie_value(req, &config.device_cookie));
+    let device = pick("device", device, generate);
+    let correlation = pick(
+        "correlation",
+        header_value(req, &config.correlation_header),
+        || request_id.to_string(),
+    );
+    insert(headers, &config.session_header, &session.value);
+    insert(headers, &config.device_header, &device.value);
+    insert(headers, &config.correlation_header, &correlation.value);
+
+    let mut cookies = Vec::new();
+    if config.set_cookies {
+        let missing = |name: &str, id: &Id| id.generated && req.cookie(name).is_none();
+        if missing(&config.session_cookie, &session) {
+            cookies.push((config.session_cookie.clone(), session.value.clone(), None));
+        }
+        if missing(&config.device_cookie, &device) {
+            let max_age = config.device_cookie_days * 86400;
+            cookies.push((
+                config.device_cookie.clone(),
+                device.value.clone(),
+                Some(max_age),
+            ));
+        }
+    }
+    Context {
+        session,
+        device,
+        correlation,
+        cookies,
+    }
+}
+
+impl Context {
+    // Echo the IDs to the client and hand out cookies for the ones it
+    // lacked, as configured. A cookie the upstream set itself wins.
+    pub fn finish(&self, config: &ContextConfig, response: &mut HttpResponse) {
+        let headers = response.headers_mut();
+        if config.echo {
+            insert(headers, &config.session_header, &self.session.value);
+            insert(headers, &config.device_header, &self.device.value);
+            insert(headers, &config.correlation_header, &self.correlation.value);
+        }
+        let already_set = |headers: &HeaderMap, name: &str| {
+            let prefix = format!("{}=", name);
+            headers
+                .get_all(header::SET_COOKIE)
+                .any(|v| v.as_bytes().starts_with(prefix.as_bytes()))
+        };
+        for (name, value, max_age) in &self.cookies {
+            if already_set(headers, name) {
+                continue;
+            }
+            let mut cookie = format!("{}={}; Path=/; HttpOnly; Secure; SameSite=Lax", name, value);
+            if let Some(max_age) = max_age {
+                cookie.push_str(&format!("; Max-Age={}", max_age));
+            }
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                headers.append(header::SET_COOKIE, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::config::{CorsConfig, CorsReconcile};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{HttpResponse, HttpResponseBuilder};
+
+pub fn headers(cors: &CorsConfig) -> [(&'static str, &str); 3] {
+    [
+        ("access-control-allow-origin", cors.allow_origin.as_str()),
+        ("access-control-allow-methods", cors.allow_methods.as_str()),
+        ("access-control-allow-headers", cors.allow_headers.as_str()),
+    ]
+}
+
+// Access-Control-* headers holding a comma-separated list
+const LISTS: &[&str] = &[
+    "access-control-allow-methods",
+    "access-control-allow-headers",
+    "access-control-expose-headers",
+];
+
+pub fn is_cors(name: &str) -> bool {
+    name.get(..15)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("access-control-"))
+}
+
+// Add the CORS headers of the given policy to a response
+pub fn apply(cors: &CorsConfig, response: &mut HttpResponseBuilder) {
+    for header in headers(cors) {
+        response.insert_header(header);
+    }
+}
+
+// The same, for a response that has already been built. Access-Control-*
+// headers it already has are taken to be the upstream's.
+pub fn insert(cors: &CorsConfig, response: &mut HeaderMap) {
+    let theirs: Vec<(String, Vec<u8>)> = response
+        .iter()
+        .filter(|(name, _)| is_cors(name.as_str()))
+        .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+        .collect();
+    let settled = reconcile(
+        cors.reconcile,
+        &headers(cors),
+        theirs
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_slice())),
+    );
+    for (name, _) in &theirs {
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            response.remove(name);
+        }
+    }
+    for (name, value) in settled {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            response.insert(name, value);
+        }
+    }
+}
+
+// Put the upstream's Access-Control-* headers on a response that already
+// has the proxy's from apply(), settling both into one of each. The caller
+// copies the upstream's other headers itself, skipping these.
+pub fn copy_upstream<'a>(
+    cors: &CorsConfig,
+    response: &mut HttpResponseBuilder,
+    upstream: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+) {
+    for header in reconcile(cors.reconcile, &headers(cors), upstream) {
+        response.insert_header(header);
+    }
+}
+
+// One value per Access-Control-* header from the proxy's (`ours`) and the
+// upstream's. Headers only one side sent are kept as they are, except that
+// prefer_proxy drops all of the upstream's; repeats from the upstream are
+// folded together.
+pub fn reconcile<'a>(
+    policy: CorsReconcile,
+    ours: &[(&str, &str)],
+    upstream: impl IntoIterator<Item = (&'a str, &'a [u8])>,
+) -> Vec<(String, String)> {
+    let mut theirs: Vec<(String, Vec<&str>)> = Vec::new();
+    for (name, value) in upstream {
+        let (name, Ok(value)) = (name.to_ascii_lowercase(), std::str::from_utf8(value)) else {
+            continue;
+        };
+        if !is_cors(&name) {
+            continue;
+        }
+        match theirs.iter_mut().find(|(seen, _)| *seen == name) {
+            Some((_, values)) => values.push(value),
+            None => theirs.push((name, vec![value])),
+        }
+    }
+
+    let mut settled: Vec<(String, String)> = ours
+        .iter()
+        .map(|(name, ours)| {
+            let value = match theirs.iter().find(|(seen, _)| seen == name) {
+                None => ours.to_string(),
+                Some((_, values)) => match policy {
+                    CorsReconcile::PreferProxy => ours.to_string(),
+                    CorsReconcile::PreferUpstream => combine(name, values),
+                    CorsReconcile::Merge => merge(name, ours, values),
+                },
+            };
+            (name.to_string(), value)
+        })
+        .collect();
+    if policy == CorsReconcile::PreferProxy {
+        return settled;
+    }
+    for (name, values) in &theirs {
+        if !settled.iter().any(|(seen, _)| seen == name) {
+            settled.push((name.clone(), combine(name, values)));
+        }
+    }
+    settled
+}
+
+// A single value out of several: lists are joined without repeats, other
+// headers keep the first
+pub fn combine(name: &str, values: &[&str]) -> String {
+    if !LISTS.contains(&name) {
+        return values[0].trim().to_string();
+    }
+    let mut items: Vec<&str> = Vec::new();
+    for item in values.iter().flat_map(|v| v.split(',')).map(str::trim) {
+        if !item.is_empty() && !items.iter().any(|seen| seen.eq_ignore_ascii_case(item)) {
+            items.push(item);
+        }
+    }
+    items.join(", ")
+}
+
+fn merge(name: &str, ours: &str, theirs: &[&str]) -> String {
+    if LISTS.contains(&name) {
+        let all: Vec<&str> = std::iter::once(ours)
+            .chain(theirs.iter().copied())
+            .collect();
+        return combine(name, &all);
+    }
+    // Only one origin can be sent; a specific one is what credentialed
+    // requests need, so it wins over *
+    let theirs = combine(name, theirs);
+    match name {
+        "access-control-allow-origin" if theirs == "*" => ours.to_string(),
+        _ => theirs,
+    }
+}
+
+// Answer a preflight without going to the upstream
+pub fn preflight(cors: &CorsConfig) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    apply(cors, &mut response);
+    if cors.max_age_secs > 0 {
+        response.insert_header((header::ACCESS_CONTROL_MAX_AGE, cors.max_age_secs));
+    }
+    response.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OURS: &[(&str, &str)] = &[
+        ("access-control-allow-origin", "*"),
+        ("access-control-allow-methods", "GET, POST"),
+    ];
+
+    const THEIRS: &[(&str, &[u8])] = &[
+        ("Access-Control-Allow-Origin", b"https://www.netflix.com"),
+        ("access-control-allow-methods", b"get, PUT"),
+        ("access-control-allow-credentials", b"true"),
+        ("content-type", b"text/plain"),
+    ];
+
+    fn settled(policy: CorsReconcile) -> Vec<(String, String)> {
+        let mut headers = reconcile(policy, OURS, THEIRS.iter().copied());
+        headers.sort();
+        headers
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        let mut pairs: Vec<(String, String)> = expected
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn prefer_proxy_keeps_only_the_proxy_values() {
+        assert_eq!(
+            settled(CorsReconcile::PreferProxy),
+            pairs(&[
+                ("access-control-allow-origin", "*"),
+                ("access-control-allow-methods", "GET, POST"),
+            ])
+        );
+    }
+
+    #[test]
+    fn prefer_upstream_keeps_the_upstream_values() {
+        assert_eq!(
+            settled(CorsReconcile::PreferUpstream),
+            pairs(&[
+                ("access-control-allow-origin", "https://www.netflix.com"),
+                ("access-control-allow-methods", "get, PUT"),
+                ("access-control-allow-credentials", "true"),
+            ])
+        );
+    }
+
+    #[test]
+    fn merge_combines_lists_and_prefers_a_specific_origin() {
+        assert_eq!(
+            settled(CorsReconcile::Merge),
+            pairs(&[
+                ("access-control-allow-origin", "https://www.netflix.com"),
+                ("access-control-allow-methods", "GET, POST, PUT"),
+                ("access-control-allow-credentials", "true"),
+            ])
+        );
+
+        let wildcard: &[(&str, &[u8])] = &[("access-control-allow-origin", b"*")];
+        let ours = &[("access-control-allow-origin", "https://partner.example")];
+        assert_eq!(
+            reconcile(CorsReconcile::Merge, ours, wildcard.iter().copied()),
+            pairs(&[("access-control-allow-origin", "https://partner.example")])
+        );
+    }
+
+    #[test]
+    fn repeated_upstream_headers_are_folded() {
+        let repeated: &[(&str, &[u8])] = &[
+            ("access-control-allow-origin", b"https://a.example"),
+            ("access-control-allow-origin", b"https://b.example"),
+            ("access-control-expose-headers", b"ETag"),
+            ("access-control-expose-headers", b"etag, X-Request-Id"),
+        ];
+        let mut headers = reconcile(CorsReconcile::PreferUpstream, &[], repeated.iter().copied());
+        headers.sort();
+        assert_eq!(
+            headers,
+            pairs(&[
+                ("access-control-allow-origin", "https://a.example"),
+                ("access-control-expose-headers", "ETag, X-Request-Id"),
+            ])
+        );
+    }
+
+    #[test]
+    fn insert_leaves_one_of_each_header() {
+        let cors = CorsConfig {
+            reconcile: CorsReconcile::PreferProxy,
+            ..CorsConfig::default()
+        };
+        let mut response = HeaderMap::new();
+        let origin = header::ACCESS_CONTROL_ALLOW_ORIGIN;
+        response.append(
+            origin.clone(),
+            HeaderValue::from_static("https://a.example"),
+        );
+        response.append(
+            origin.clone(),
+            HeaderValue::from_static("https://b.example"),
+        );
+        insert(&cors, &mut response);
+        let values: Vec<_> = response.get_all(origin).collect();
+        assert_eq!(values, vec![HeaderValue::from_static("*")]);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::config::{CredentialAuditConfig, RouteConfig};
+use crate::keys;
+use crate::logfile::LogFile;
+use crate::metrics;
+use actix_web::HttpRequest;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Which of the gateway's own credentials (secret_headers) went out with
+// which request, for security to trace their use. Secrets are never
+// written, only a fingerprint that tells one value of a header from
+// another, such as before and after a rotation. Each line carries the hash
+// of the line before it, so an edited, removed or reordered entry breaks
+// the chain; `credential-audit verify` checks it.
+pub struct CredentialAudit {
+    chain: Mutex<Chain>,
+}
+
+struct Chain {
+    file: LogFile,
+    // Hash of the last entry written
+    prev: String,
+}
+
+// Where no entry came before
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Credential {
+    pub header: String,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub at_ms: u64,
+    pub request_id: String,
+    pub route: String,
+    pub method: String,
+    pub path: String,
+    pub upstream: String,
+    pub credentials: Vec<Credential>,
+    pub prev: String,
+    // SHA-256 of prev and the entry without this field
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+pub fn fingerprint(value: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, value);
+    keys::hex(&hash.as_ref()[..8])
+}
+
+fn hash(entry: &Entry) -> String {
+    let line = serde_json::to_string(entry).unwrap_or_default();
+    keys::hash(format!("{}\n{}", entry.prev, line).as_bytes())
+}
+
+impl CredentialAudit {
+    // Carries on the chain of the entries already in the file
+    pub fn open(config: &CredentialAuditConfig) -> Result<Self, Error> {
+        let existing = fs::read_to_string(&config.path).unwrap_or_default();
+        let prev = match existing.lines().rev().find(|line| !line.trim().is_empty()) {
+            None => GENESIS.to_string(),
+            Some(line) => serde_json::from_str::<Entry>(line)
+                .map(|entry| entry.hash)
+                .map_err(|e| {
+                    Error::other(format!(
+                        "Credential audit log {} ends in an unreadable entry: {}",
+                        config.path, e
+                    ))
+                })?,
+        };
+        let file = LogFile::open(&config.path, config.rotation.clone())
+            .map_err(|e| Error::other(format!("Credential audit log {}: {}", config.path, e)))?;
+        Ok(CredentialAudit {
+            chain: Mutex::new(Chain { file, prev }),
+        })
+    }
+
+    // Note the route's secret headers going upstream with the request
+    pub fn record(&self, route: &RouteConfig, req: &HttpRequest, request_id: &str, upstream: &str) {
+        if route.compiled.secret_headers.is_empty() {
+            return;
+        }
+        let credentials = (route.compiled.secret_headers)
+            .iter()
+            .map(|(name, value)| Credential {
+                header: name.to_string(),
+                fingerprint: fingerprint(value.as_bytes()),
+            })
+            .collect();
+        let mut entry = Entry {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            request_id: request_id.to_string(),
+            route: route.name.clone(),
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            upstream: upstream.to_string(),
+            credentials,
+            prev: String::new(),
+            hash: String::new(),
+        };
+
+        let mut chain = self.chain.lock().unwrap();
+        entry.prev = chain.prev.clone();
+        entry.hash = hash(&entry);
+        let mut line = serde_json::to_vec(&entry).unwrap_or_default();
+        line.push(b'\n');
+        match chain.file.write_line(&line) {
+            Ok(()) => chain.prev = entry.hash,
+            Err(e) => {
+                // The request goes on; the gap shows up as a failed write
+                eprintln!("Failed to write credential audit log: {}", e);
+                metrics::inc("credential_audit_write_errors_total", &[]);
+            }
+        }
+    }
+
+    pub fn reopen(&self) {
+        if let Err(e) = self.chain.lock().unwrap().file.reopen() {
+            eprintln!("Failed to reopen credential audit log: {}", e);
+        }
+    }
+}
+
+// Check a log file's chain. Ok has the number of entries; Err says where
+// it breaks. A file of its own after rotation starts from its first
+// entry's prev.
+pub fn verify(path: &str) -> Result<usize, String> {
+    let raw = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut prev: Option<String> = None;
+    let mut count = 0;
+    for (i, line) in raw.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_no = i + 1;
+        let mut entry: Entry = serde_json::from_str(line)
+            .map_err(|e| format!("line {}: unreadable entry: {}", line_no, e))?;
+        if prev.as_ref().is_some_and(|prev| *prev != entry.prev) {
+            return Err(format!(
+                "line {}: doesn't follow the entry before it",
+                line_no
+            ));
+        }
+        let claimed = std::mem::take(&mut entry.hash);
+        if hash(&entry) != claimed {
+            return Err(format!("line {}: entry was altered", line_no));
+        }
+        prev = Some(claimed);
+        count += 1;
+    }
+    Ok(count)
+}
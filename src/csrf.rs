@@ -0,0 +1,66 @@
+use crate::authn;
+use crate::config::CsrfConfig;
+use crate::keys;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse};
+use ring::rand::{SecureRandom, SystemRandom};
+
+// Double-submit cookie: the edge hands the browser a random token in a
+// cookie that page scripts can read, and state-changing requests must echo
+// it in a header. Another origin can make the browser send the cookie but
+// can't read it, so it can't produce the header.
+
+fn safe(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// Reject state-changing requests whose header doesn't match the cookie
+pub fn check(config: &CsrfConfig, req: &HttpRequest) -> Result<(), &'static str> {
+    if safe(req.method()) {
+        return Ok(());
+    }
+    if config.skip_without_cookies && !req.headers().contains_key(header::COOKIE) {
+        return Ok(());
+    }
+    let cookie = req
+        .cookie(&config.cookie_name)
+        .ok_or("Missing CSRF cookie")?;
+    let presented = req
+        .headers()
+        .get(config.header_name.as_str())
+        .ok_or("Missing CSRF token")?;
+    match !cookie.value().is_empty()
+        && authn::constant_eq(cookie.value().as_bytes(), presented.as_bytes())
+    {
+        true => Ok(()),
+        false => Err("CSRF token mismatch"),
+    }
+}
+
+// Give browsers that don't have a token yet a fresh one
+pub fn issue(config: &CsrfConfig, req: &HttpRequest, response: &mut HttpResponse) {
+    if req.cookie(&config.cookie_name).is_some() {
+        return;
+    }
+    let mut bytes = [0u8; 32];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return;
+    }
+    // Not HttpOnly: page scripts have to read it to send the header
+    let mut cookie = format!(
+        "{}={}; Path={}; SameSite=Lax",
+        config.cookie_name,
+        keys::hex(&bytes),
+        config.cookie_path
+    );
+    if config.secure {
+        cookie.push_str("; Secure");
+    }
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().append(header::SET_COOKIE, value);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::config::DaemonConfig;
+use std::fs;
+use std::io::Error;
+
+// `--daemon`: detach from the terminal and run in the background, with the
+// pid in [daemon] pid_file for init scripts and the like. Happens before the
+// runtime starts, as forking takes only the calling thread along.
+//
+// On Windows it runs as a service instead, started by the Service Control
+// Manager once installed with
+// `sc create rust-netty-server binPath= "C:\path\to\Rust-netty-server.exe --daemon"`.
+// The SCM gives the service a thread of its own, so the runtime starts there.
+
+// The process a pid file names, if it's still running
+#[cfg(unix)]
+fn running(pid_file: &str) -> Option<i32> {
+    let pid: i32 = fs::read_to_string(pid_file).ok()?.trim().parse().ok()?;
+    // Signal 0 only checks the process exists; EPERM means it does
+    let alive = unsafe { libc::kill(pid, 0) } == 0
+        || Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    alive.then_some(pid)
+}
+
+#[cfg(unix)]
+pub fn detach(config: &DaemonConfig) -> Result<(), Error> {
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+
+    if let Some(pid) = running(&config.pid_file) {
+        return Err(Error::other(format!(
+            "Already running with pid {} (from {})",
+            pid, config.pid_file
+        )));
+    }
+    // Opened first so a bad path is still reported on the terminal
+    let log = match config.log_file.is_empty() {
+        true => OpenOptions::new().write(true).open("/dev/null")?,
+        false => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.log_file)
+            .map_err(|e| Error::other(format!("Failed to open {}: {}", config.log_file, e)))?,
+    };
+    let null = File::open("/dev/null")?;
+
+    // Twice, so the daemon is no session leader and can't get a terminal back
+    for fork in 0..2 {
+        match unsafe { libc::fork() } {
+            -1 => return Err(Error::last_os_error()),
+            0 => {}
+            _ => unsafe { libc::_exit(0) },
+        }
+        if fork == 0 && unsafe { libc::setsid() } == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    unsafe {
+        libc::dup2(null.as_raw_fd(), 0);
+        libc::dup2(log.as_raw_fd(), 1);
+        libc::dup2(log.as_raw_fd(), 2);
+    }
+    fs::write(&config.pid_file, format!("{}\n", std::process::id())).map_err(|e| {
+        Error::other(format!(
+            "Failed to write pid file {}: {}",
+            config.pid_file, e
+        ))
+    })
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn detach(_config: &DaemonConfig) -> Result<(), Error> {
+    Err(Error::other(
+        "--daemon is only supported on Unix and Windows",
+    ))
+}
+
+#[cfg(windows)]
+pub mod service {
+    use crate::config::DaemonConfig;
+    use std::ffi::OsString;
+    use std::fs::{self, OpenOptions};
+    use std::io::Error;
+    use std::os::windows::io::IntoRawHandle;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{
+        self, ServiceControlHandlerResult, ServiceStatusHandle,
+    };
+    use windows_service::{define_windows_service, service_dispatcher};
+    use windows_sys::Win32::System::Console::{SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE};
+
+    type Serve = Box<dyn FnOnce() -> Result<(), Error> + Send>;
+
+    // Handed from run() to the service thread, and its result back
+    static SERVE: Mutex<Option<(String, Serve)>> = Mutex::new(None);
+    static RESULT: Mutex<Option<Result<(), Error>>> = Mutex::new(None);
+    static STATUS: OnceLock<ServiceStatusHandle> = OnceLock::new();
+    static STOP: Notify = Notify::const_new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    // Run serve as the service, returning once the SCM has stopped it
+    pub fn run<F>(config: &DaemonConfig, serve: F) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error> + Send + 'static,
+    {
+        // Services have no console, so output only goes to the log file
+        if !config.log_file.is_empty() {
+            let log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.log_file)
+                .map_err(|e| Error::other(format!("Failed to open {}: {}", config.log_file, e)))?;
+            let handle = log.into_raw_handle();
+            unsafe {
+                SetStdHandle(STD_OUTPUT_HANDLE, handle);
+                SetStdHandle(STD_ERROR_HANDLE, handle);
+            }
+        }
+        fs::write(&config.pid_file, format!("{}\n", std::process::id())).map_err(|e| {
+            Error::other(format!(
+                "Failed to write pid file {}: {}",
+                config.pid_file, e
+            ))
+        })?;
+        *SERVE.lock().unwrap() = Some((config.service_name.clone(), Box::new(serve)));
+        service_dispatcher::start(&config.service_name, ffi_service_main).map_err(|e| {
+            Error::other(format!(
+                "Can't start as a service (is it run by sc?): {}",
+                e
+            ))
+        })?;
+        RESULT.lock().unwrap().take().unwrap_or(Ok(()))
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let result = serve_service();
+        if let Err(e) = &result {
+            eprintln!("{}", e);
+        }
+        *RESULT.lock().unwrap() = Some(result);
+    }
+
+    fn serve_service() -> Result<(), Error> {
+        let (name, serve) = SERVE
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| Error::other("Service started twice"))?;
+        let handler = |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                STOP.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let status = service_control_handler::register(&name, handler).map_err(Error::other)?;
+        let _ = STATUS.set(status);
+        report(ServiceState::StartPending, ServiceExitCode::Win32(0));
+        let result = serve();
+        let exit_code = match &result {
+            Ok(()) => ServiceExitCode::Win32(0),
+            Err(_) => ServiceExitCode::ServiceSpecific(1),
+        };
+        report(ServiceState::Stopped, exit_code);
+        result
+    }
+
+    fn report(state: ServiceState, exit_code: ServiceExitCode) {
+        let Some(status) = STATUS.get() else {
+            return;
+        };
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let pending = matches!(
+            state,
+            ServiceState::StartPending | ServiceState::StopPending
+        );
+        let status_update = ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            // Warm-up and draining can take a while
+            wait_hint: match pending {
+                true => Duration::from_secs(60),
+                false => Duration::default(),
+            },
+            process_id: None,
+        };
+        if let Err(e) = status.set_service_status(status_update) {
+            eprintln!("Can't report the service status: {}", e);
+        }
+    }
+
+    // Serving, like READY=1 to systemd
+    pub fn ready() {
+        report(ServiceState::Running, ServiceExitCode::Win32(0));
+    }
+
+    // Draining connections, like STOPPING=1 to systemd
+    pub fn stopping() {
+        report(ServiceState::StopPending, ServiceExitCode::Win32(0));
+    }
+
+    // Once the SCM has asked the service to stop
+    pub async fn stop_requested() {
+        STOP.notified().await;
+    }
+}
+
+// Remove the pid file on the way out, unless another process has taken it
+pub fn finish(config: &DaemonConfig) {
+    let ours = fs::read_to_string(&config.pid_file)
+        .is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+    if ours {
+        let _ = fs::remove_file(&config.pid_file);
+    }
+}
+
+// Point the pid file at the process that took over from us
+pub fn handed_over(config: &DaemonConfig, pid: u32) {
+    let ours = fs::read_to_string(&config.pid_file)
+        .is_ok_and(|found| found.trim() == std::process::id().to_string());
+    if ours {
+        if let Err(e) = fs::write(&config.pid_file, format!("{}\n", pid)) {
+            eprintln!("Failed to write pid file {}: {}", config.pid_file, e);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::report::{self, Histogram};
+use crate::upstream::Upstreams;
+use actix_web::http::StatusCode;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// What the admin dashboard shows: the last minute of traffic, second by
+// second, and how the upstreams and the cache are doing now
+
+const WINDOW_SECS: usize = 60;
+
+#[derive(Default, Clone, Copy)]
+struct Second {
+    // Seconds since startup this slot is counting; older slots are stale
+    at: u64,
+    requests: u64,
+    client_errors: u64,
+    server_errors: u64,
+    latency: Histogram,
+}
+
+pub struct Live {
+    started: Instant,
+    seconds: Mutex<[Second; WINDOW_SECS]>,
+    // cache_requests_total as it was at recent polls, for the hit ratio
+    // over the last minute rather than since startup
+    cache: Mutex<VecDeque<(Instant, BTreeMap<String, f64>)>>,
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        Live {
+            started: Instant::now(),
+            seconds: Mutex::new([Second::default(); WINDOW_SECS]),
+            cache: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+fn hit_ratio(counts: &BTreeMap<String, f64>, before: Option<&BTreeMap<String, f64>>) -> Value {
+    let count = |result: &str| {
+        counts.get(result).copied().unwrap_or(0.0)
+            - before.and_then(|b| b.get(result)).copied().unwrap_or(0.0)
+    };
+    let lookups: f64 = counts.keys().map(|result| count(result)).sum();
+    match lookups > 0.0 {
+        true => json!(count("hit") / lookups),
+        false => Value::Null,
+    }
+}
+
+impl Live {
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn observe(&self, status: StatusCode, latency: Duration) {
+        let now = self.now();
+        let mut seconds = self.seconds.lock().unwrap();
+        let second = &mut seconds[now as usize % WINDOW_SECS];
+        if second.at != now {
+            *second = Second {
+                at: now,
+                ..Default::default()
+            };
+        }
+        second.requests += 1;
+        if status.is_client_error() {
+            second.client_errors += 1;
+        } else if status.is_server_error() {
+            second.server_errors += 1;
+        }
+        second.latency.record(latency);
+    }
+
+    // The numbers the dashboard polls for
+    pub fn stats(&self, upstreams: &Upstreams) -> Value {
+        let now = self.now();
+        // The current second is still filling up, so it's left out
+        let window: Vec<Second> = {
+            let seconds = self.seconds.lock().unwrap();
+            (1..=WINDOW_SECS as u64)
+                .rev()
+                .filter(|back| *back <= now)
+                .map(|back| {
+                    let at = now - back;
+                    let second = seconds[at as usize % WINDOW_SECS];
+                    match second.at == at {
+                        true => second,
+                        false => Second {
+                            at,
+                            ..Default::default()
+                        },
+                    }
+                })
+                .collect()
+        };
+        let mut latency = Histogram::default();
+        let (mut requests, mut client_errors, mut server_errors) = (0, 0, 0);
+        for second in &window {
+            latency.merge(&second.latency);
+            requests += second.requests;
+            client_errors += second.client_errors;
+            server_errors += second.server_errors;
+        }
+        let secs = window.len().max(1) as f64;
+
+        let counts = report::cache_counts();
+        let minute_ago = {
+            let mut cache = self.cache.lock().unwrap();
+            let cutoff = Instant::now().checked_sub(Duration::from_secs(WINDOW_SECS as u64));
+            while cache.len() > 1 && cutoff.is_some_and(|cutoff| cache[1].0 <= cutoff) {
+                cache.pop_front();
+            }
+            let oldest = cache.front().map(|(_, counts)| counts.clone());
+            cache.push_back((Instant::now(), counts.clone()));
+            oldest
+        };
+
+        json!({
+            "uptime_secs": now,
+            "window_secs": window.len(),
+            "requests_per_second": requests as f64 / secs,
+            "error_rate": server_errors as f64 / requests.max(1) as f64,
+            "client_error_rate": client_errors as f64 / requests.max(1) as f64,
+            "p50_ms": latency.quantile_ms(0.5),
+            "p95_ms": latency.quantile_ms(0.95),
+            "p99_ms": latency.quantile_ms(0.99),
+            // Oldest first, one per second
+            "requests": window.iter().map(|s| s.requests).collect::<Vec<_>>(),
+            "server_errors": window.iter().map(|s| s.server_errors).collect::<Vec<_>>(),
+            "cache": {
+                "hit_ratio": hit_ratio(&counts, minute_ago.as_ref()),
+                "hit_ratio_since_start": hit_ratio(&counts, None),
+            },
+            "upstreams": upstreams.health(),
+        })
+    }
+}
@@ -0,0 +1,121 @@
+use crate::config::{RouteConfig, ServerConfig};
+use crate::metrics;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// One time budget for the whole request ([server] request_deadline_ms, or
+// the route's deadline_ms), counted from its arrival. Each upstream attempt
+// gets what's left of it, and so do retries, hedges and 429 waits, rather
+// than timeouts of their own that add up. Upstreams are told when it runs
+// out: X-Request-Deadline carries the Unix time in milliseconds, and gRPC
+// routes get grpc-timeout as well. A caller that sends either with a closer
+// deadline has that one kept. Routes without a deadline leave both headers
+// as the client sent them.
+
+const HEADER: &str = "x-request-deadline";
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+#[derive(Clone, Copy)]
+struct Deadline(Instant);
+
+// Unix milliseconds, as X-Request-Deadline gives them
+fn unix_ms(header: &str) -> Option<Duration> {
+    let at = UNIX_EPOCH.checked_add(Duration::from_millis(header.trim().parse().ok()?))?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// Up to 8 digits and a unit, e.g. 250m or 5S
+fn grpc_timeout(header: &str) -> Option<Duration> {
+    let header = header.trim();
+    let unit = header.chars().last()?;
+    let value: u64 = header[..header.len() - unit.len_utf8()].parse().ok()?;
+    match unit {
+        'H' => Some(Duration::from_secs(value.checked_mul(3600)?)),
+        'M' => Some(Duration::from_secs(value.checked_mul(60)?)),
+        'S' => Some(Duration::from_secs(value)),
+        'm' => Some(Duration::from_millis(value)),
+        'u' => Some(Duration::from_micros(value)),
+        'n' => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+// Set the request's deadline once its route is known
+pub fn start(req: &HttpRequest, server: &ServerConfig, route: &RouteConfig, arrived: Instant) {
+    // Long polls are held by design; only a deadline of their own applies
+    let budget = match route.long_poll {
+        true => route.deadline_ms.unwrap_or(0),
+        false => route.deadline_ms.unwrap_or(server.request_deadline_ms),
+    };
+    if budget == 0 {
+        return;
+    }
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let callers = [
+        header(HEADER).and_then(unix_ms),
+        header(GRPC_TIMEOUT).and_then(grpc_timeout),
+    ];
+    let mut deadline = arrived + Duration::from_millis(budget);
+    // Ones too far off to represent can't be any closer
+    for theirs in callers.into_iter().flatten() {
+        if let Some(at) = arrived.checked_add(theirs) {
+            deadline = deadline.min(at);
+        }
+    }
+    req.extensions_mut().insert(Deadline(deadline));
+}
+
+// What's left of the request's deadline, if it has one
+pub fn remaining(req: &HttpRequest) -> Option<Duration> {
+    let deadline = *req.extensions().get::<Deadline>()?;
+    Some(deadline.0.saturating_duration_since(Instant::now()))
+}
+
+pub fn expired(req: &HttpRequest) -> bool {
+    remaining(req).is_some_and(|left| left.is_zero())
+}
+
+// The timeout for one upstream attempt: its own, cut short by the deadline
+pub fn timeout(req: &HttpRequest, own: Duration) -> Duration {
+    match remaining(req) {
+        Some(left) => own.min(left),
+        None => own,
+    }
+}
+
+// Tell the upstream how long it has
+pub fn propagate(req: &HttpRequest, route: &RouteConfig, headers: &mut HeaderMap) {
+    let Some(left) = remaining(req) else {
+        return;
+    };
+    let at = SystemTime::now() + left;
+    let ms = at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    headers.insert(
+        HeaderName::from_static(HEADER),
+        HeaderValue::from(ms as u64),
+    );
+    if route.grpc {
+        // grpc-timeout allows 8 digits
+        let ms = left.as_millis() as u64;
+        let value = match ms < 100_000_000 {
+            true => format!("{}m", ms),
+            false => format!("{}S", left.as_secs()),
+        };
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(GRPC_TIMEOUT), value);
+        }
+    }
+}
+
+// A request given up on because its deadline passed; stage says whether
+// that was before it went upstream or while it was there
+pub fn exceeded(route: &RouteConfig, stage: &str) {
+    metrics::inc(
+        "request_deadline_exceeded_total",
+        &[("route", &route.name), ("stage", stage)],
+    );
+}
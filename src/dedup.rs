@@ -0,0 +1,165 @@
+use crate::config::{DedupConfig, RouteConfig};
+use crate::delta::read_up_to;
+use crate::keys;
+use crate::metrics;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use ring::digest;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+// Double submits, such as a double-clicked "Add to my list": the same user
+// sending the same request with the same body again within the route's
+// window gets the first one's response instead of a second upstream call.
+// A duplicate that arrives while the first is still in flight waits for it.
+
+// The first response, as duplicates get it
+pub struct Shared {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+// None while the first request is in flight. The sender going away without
+// a response means it couldn't be shared.
+pub type Answer = watch::Receiver<Option<Arc<Shared>>>;
+
+struct Slot {
+    started: Instant,
+    answer: Answer,
+}
+
+// Recent requests on routes with `dedup`, by user, request and body
+#[derive(Default)]
+pub struct Dedup {
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+pub enum Turn {
+    // Forward it, and hand the response to finish()
+    First(Lead),
+    // Answer with wait()
+    Duplicate(Answer),
+}
+
+pub struct Lead {
+    route: String,
+    max_body_bytes: usize,
+    sender: watch::Sender<Option<Arc<Shared>>>,
+}
+
+pub fn covers(config: &DedupConfig, method: &Method) -> bool {
+    (config.methods)
+        .iter()
+        .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+}
+
+// Whose request it is: the token's subject when the route's auth gives
+// one, otherwise the credentials or cookies it came with, otherwise the
+// client's address
+fn user(req: &HttpRequest, subject: Option<&str>, client_ip: &str) -> String {
+    if let Some(subject) = subject {
+        return format!("sub:{}", subject);
+    }
+    for name in [header::AUTHORIZATION, header::COOKIE] {
+        if let Some(value) = req.headers().get(&name) {
+            let hash = digest::digest(&digest::SHA256, value.as_bytes());
+            return format!("{}:{}", name, keys::hex(&hash.as_ref()[..16]));
+        }
+    }
+    format!("ip:{}", client_ip)
+}
+
+impl Dedup {
+    // Whether the request is the first of its kind in the window; None when
+    // the route doesn't deduplicate its method
+    pub fn begin(
+        &self,
+        config: &DedupConfig,
+        route: &RouteConfig,
+        req: &HttpRequest,
+        subject: Option<&str>,
+        client_ip: &str,
+        body: &[u8],
+    ) -> Option<Turn> {
+        if !covers(config, req.method()) {
+            return None;
+        }
+        let body = digest::digest(&digest::SHA256, body);
+        let key = format!(
+            "{} {} {} {} {}",
+            route.name,
+            user(req, subject, client_ip),
+            req.method(),
+            req.uri(),
+            keys::hex(body.as_ref())
+        );
+        let window = Duration::from_millis(config.window_ms);
+        let mut slots = self.slots.lock().unwrap();
+        slots.retain(|_, slot| slot.started.elapsed() < window);
+        if let Some(slot) = slots.get(&key) {
+            metrics::inc(
+                "dedup_requests_total",
+                &[("route", &route.name), ("result", "duplicate")],
+            );
+            return Some(Turn::Duplicate(slot.answer.clone()));
+        }
+        let (sender, answer) = watch::channel(None);
+        slots.insert(
+            key,
+            Slot {
+                started: Instant::now(),
+                answer,
+            },
+        );
+        metrics::inc(
+            "dedup_requests_total",
+            &[("route", &route.name), ("result", "first")],
+        );
+        Some(Turn::First(Lead {
+            route: route.name.clone(),
+            max_body_bytes: config.max_body_bytes,
+            sender,
+        }))
+    }
+}
+
+impl Lead {
+    // Keep the first request's response for its duplicates and send it on.
+    // One too large to keep isn't shared, and duplicates are forwarded.
+    pub async fn finish(self, response: HttpResponse) -> HttpResponse {
+        let (head, body) = response.into_parts();
+        let body = match read_up_to(body, self.max_body_bytes).await {
+            Ok(body) => body,
+            Err(whole) => {
+                metrics::inc(
+                    "dedup_requests_total",
+                    &[("route", &self.route), ("result", "too_large")],
+                );
+                return head.set_body(whole);
+            }
+        };
+        let shared = Shared {
+            status: head.status(),
+            headers: head.headers().clone(),
+            body: body.clone(),
+        };
+        let _ = self.sender.send(Some(Arc::new(shared)));
+        head.set_body(body).map_into_boxed_body()
+    }
+}
+
+// The first request's response, once it's back; None when it couldn't be
+// shared, for the duplicate to be forwarded after all
+pub async fn wait(mut answer: Answer) -> Option<HttpResponse> {
+    let shared = answer.wait_for(Option::is_some).await.ok()?.clone()?;
+    let mut response = HttpResponse::build(shared.status);
+    for (name, value) in &shared.headers {
+        response.append_header((name.clone(), value.clone()));
+    }
+    Some(response.body(shared.body.clone()))
+}
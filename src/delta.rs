@@ -0,0 +1,270 @@
+use crate::config::{DeltaConfig, RouteConfig};
+use crate::keys;
+use crate::metrics;
+use actix_web::body::{BodySize, BodyStream, BoxBody, MessageBody};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use ring::digest;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+// RFC 3229 delta encoding with JSON Patch (RFC 6902). A client that has a
+// response sends its ETag in If-None-Match along with `A-IM: json-patch`,
+// and gets a 226 with the patch that turns it into the current one, as long
+// as that version is still remembered and the patch is the smaller.
+
+const JSON_PATCH: &str = "json-patch";
+
+#[derive(Default)]
+struct Inner {
+    // Recent versions of each response by ETag, newest last
+    versions: HashMap<String, VecDeque<(String, Arc<Value>)>>,
+    // Keys in the order they were first seen, to drop the oldest
+    order: VecDeque<String>,
+}
+
+// Recent versions of the JSON responses on routes with `delta`
+#[derive(Default)]
+pub struct Deltas {
+    inner: Mutex<Inner>,
+}
+
+fn header_str(headers: &header::HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|v| v.split(';').next())
+        .map(|media| media.trim().to_ascii_lowercase())
+        .is_some_and(|media| media == "application/json" || media.ends_with("+json"))
+}
+
+// Read a body of up to `limit` bytes. A larger one is given back whole,
+// as a stream, for sending on as it is.
+pub async fn read_up_to(mut body: BoxBody, limit: usize) -> Result<Bytes, BoxBody> {
+    let mut read = Vec::new();
+    loop {
+        let next = std::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await;
+        match next {
+            Some(Ok(chunk)) if read.len() + chunk.len() <= limit => read.extend_from_slice(&chunk),
+            Some(Ok(chunk)) => {
+                read.extend_from_slice(&chunk);
+                let rest = futures_util::stream::poll_fn(move |cx| {
+                    Pin::new(&mut body).poll_next(cx).map(|next| {
+                        next.map(|r| r.map_err(|e| std::io::Error::other(e.to_string())))
+                    })
+                });
+                let whole =
+                    futures_util::stream::once(async move { Ok(Bytes::from(read)) }).chain(rest);
+                return Err(BoxBody::new(BodyStream::new(whole)));
+            }
+            Some(Err(e)) => {
+                let e = std::io::Error::other(e.to_string());
+                let failed = futures_util::stream::once(async move { Err::<Bytes, _>(e) });
+                return Err(BoxBody::new(BodyStream::new(failed)));
+            }
+            None => return Ok(Bytes::from(read)),
+        }
+    }
+}
+
+// A JSON Pointer segment
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+// The operations that turn `old` into `new`. Arrays are compared index by
+// index, so an insertion near the front costs a replace per element after
+// it; the size check in apply() sends the full body then.
+fn diff(path: &str, old: &Value, new: &Value, ops: &mut Vec<Value>) {
+    match (old, new) {
+        _ if old == new => {}
+        (Value::Object(old), Value::Object(new)) => {
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                let path = format!("{}/{}", path, escape(key));
+                ops.push(json!({ "op": "remove", "path": path }));
+            }
+            for (key, value) in new {
+                let path = format!("{}/{}", path, escape(key));
+                match old.get(key) {
+                    Some(was) => diff(&path, was, value, ops),
+                    None => ops.push(json!({ "op": "add", "path": path, "value": value })),
+                }
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            let common = old.len().min(new.len());
+            for i in 0..common {
+                diff(&format!("{}/{}", path, i), &old[i], &new[i], ops);
+            }
+            // From the end, so the indexes still to go stay put
+            for i in (common..old.len()).rev() {
+                ops.push(json!({ "op": "remove", "path": format!("{}/{}", path, i) }));
+            }
+            for value in &new[common..] {
+                let path = format!("{}/-", path);
+                ops.push(json!({ "op": "add", "path": path, "value": value }));
+            }
+        }
+        _ => ops.push(json!({ "op": "replace", "path": path, "value": new })),
+    }
+}
+
+impl Deltas {
+    // Remember the version and find the one the client says it has
+    fn exchange(
+        &self,
+        config: &DeltaConfig,
+        key: &str,
+        etag: &str,
+        body: &Arc<Value>,
+        base: &[&str],
+    ) -> Option<(String, Arc<Value>)> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.versions.contains_key(key) {
+            if inner.versions.len() >= config.max_urls.max(1) {
+                if let Some(oldest) = inner.order.pop_front() {
+                    inner.versions.remove(&oldest);
+                }
+            }
+            inner.order.push_back(key.to_string());
+        }
+        let versions = inner.versions.entry(key.to_string()).or_default();
+        let found = versions
+            .iter()
+            .rev()
+            .find(|(tag, _)| tag != etag && base.contains(&tag.as_str()))
+            .cloned();
+        if versions.back().is_none_or(|(tag, _)| tag != etag) {
+            versions.push_back((etag.to_string(), body.clone()));
+            while versions.len() > config.versions.max(1) {
+                versions.pop_front();
+            }
+        }
+        found
+    }
+
+    // For responses to GETs on the route: tag them, remember them, and send
+    // a patch instead to clients that can take one
+    pub async fn apply(
+        &self,
+        config: &DeltaConfig,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        partition: Option<&str>,
+        response: HttpResponse,
+    ) -> HttpResponse {
+        let headers = response.headers();
+        // Kept apart per user like the cache is, so no one is sent a patch
+        // against someone else's response
+        let anonymous = partition.is_some() || !req.headers().contains_key(header::AUTHORIZATION);
+        if req.method() != Method::GET
+            || response.status() != StatusCode::OK
+            || !anonymous
+            || headers.contains_key(header::CONTENT_ENCODING)
+            || !is_json(header_str(headers, header::CONTENT_TYPE))
+        {
+            return response;
+        }
+        let declared = header_str(headers, header::CONTENT_LENGTH).and_then(|v| v.parse().ok());
+        if let (Some(len), _) | (None, BodySize::Sized(len)) = (declared, response.body().size()) {
+            if len > config.max_body_bytes as u64 {
+                return response;
+            }
+        }
+
+        let (mut head, body) = response.into_parts();
+        let body = match read_up_to(body, config.max_body_bytes).await {
+            Ok(body) => body,
+            Err(whole) => return head.set_body(whole),
+        };
+        let Ok(value) = serde_json::from_slice::<Value>(&body) else {
+            return head.set_body(body).map_into_boxed_body();
+        };
+        let etag = match header_str(head.headers(), header::ETAG) {
+            Some(etag) => etag.to_string(),
+            None => {
+                let hash = digest::digest(&digest::SHA256, &body);
+                let etag = format!("\"{}\"", keys::hex(&hash.as_ref()[..16]));
+                if let Ok(value) = HeaderValue::from_str(&etag) {
+                    head.headers_mut().insert(header::ETAG, value);
+                }
+                etag
+            }
+        };
+
+        let key = format!(
+            "{} {} {}",
+            route.name,
+            req.uri()
+                .path_and_query()
+                .map_or(req.path(), |p| p.as_str()),
+            partition.unwrap_or("-")
+        );
+        let wants_patch =
+            header_str(req.headers(), HeaderName::from_static("a-im")).is_some_and(|im| {
+                im.split(',')
+                    .any(|im| im.trim().eq_ignore_ascii_case(JSON_PATCH))
+            });
+        let base: Vec<&str> = header_str(req.headers(), header::IF_NONE_MATCH)
+            .map(|tags| tags.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        let value = Arc::new(value);
+        let found = self.exchange(config, &key, &etag, &value, &base);
+
+        let result = match found {
+            _ if !wants_patch => "full",
+            _ if base.contains(&etag.as_str()) => "unchanged",
+            None if base.is_empty() => "full",
+            None => "unknown_base",
+            Some((base, old)) => {
+                let mut ops = Vec::new();
+                diff("", &old, &value, &mut ops);
+                let patch = serde_json::to_vec(&ops).unwrap_or_default();
+                if patch.len() < body.len() {
+                    metrics::inc(
+                        "delta_responses_total",
+                        &[("route", &route.name), ("result", "patched")],
+                    );
+                    metrics::add(
+                        "delta_bytes_saved_total",
+                        &[("route", &route.name)],
+                        (body.len() - patch.len()) as f64,
+                    );
+                    *head.status_mut() = StatusCode::IM_USED;
+                    let headers = head.headers_mut();
+                    headers.remove(header::CONTENT_LENGTH);
+                    headers.insert(
+                        header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/json-patch+json"),
+                    );
+                    headers.insert(
+                        HeaderName::from_static("im"),
+                        HeaderValue::from_static(JSON_PATCH),
+                    );
+                    if let Ok(base) = HeaderValue::from_str(&base) {
+                        headers.insert(HeaderName::from_static("delta-base"), base);
+                    }
+                    return head.set_body(Bytes::from(patch)).map_into_boxed_body();
+                }
+                "larger"
+            }
+        };
+        metrics::inc(
+            "delta_responses_total",
+            &[("route", &route.name), ("result", result)],
+        );
+        if result == "unchanged" {
+            *head.status_mut() = StatusCode::NOT_MODIFIED;
+            head.headers_mut().remove(header::CONTENT_LENGTH);
+            return head.set_body(BoxBody::new(())).map_into_boxed_body();
+        }
+        head.set_body(body).map_into_boxed_body()
+    }
+}
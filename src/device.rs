@@ -0,0 +1,275 @@
+use crate::config::{DeviceConfig, RouteConfig};
+use crate::metrics;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse};
+
+// Markers of living-room devices: smart TVs, streaming sticks and consoles
+const TV: &[&str] = &[
+    "smart-tv",
+    "smarttv",
+    "googletv",
+    "android tv",
+    "appletv",
+    "apple tv",
+    "tvos",
+    "hbbtv",
+    "bravia",
+    "tizen",
+    "web0s",
+    "webos",
+    "roku",
+    "crkey",
+    "playstation",
+    "xbox",
+    "nintendo",
+];
+
+// Fire TV sticks only say so in their model name (AFTMM, AFTKA and so on)
+fn fire_tv(user_agent: &str) -> bool {
+    user_agent
+        .split([';', ' ', ')', '('])
+        .any(|token| token.len() > 3 && token.starts_with("AFT"))
+}
+
+fn class(user_agent: &str, ua: &str) -> &'static str {
+    let has = |marker: &str| ua.contains(marker);
+    if TV.iter().any(|marker| has(marker)) || fire_tv(user_agent) {
+        "tv"
+    } else if has("ipad") || has("tablet") || has("kindle") || has("silk") {
+        "tablet"
+    } else if has("iphone") || has("ipod") || has("mobi") || has("windows phone") {
+        "mobile"
+    } else if has("android") {
+        // Android phones say Mobile; tablets don't
+        "tablet"
+    } else if has("mozilla") {
+        "web"
+    } else {
+        "other"
+    }
+}
+
+fn os(user_agent: &str, ua: &str) -> &'static str {
+    let has = |marker: &str| ua.contains(marker);
+    // Consoles and TVs first: their User-Agents often claim Windows, Linux
+    // or Android as well
+    if has("playstation") {
+        "playstation"
+    } else if has("xbox") {
+        "xbox"
+    } else if has("tizen") {
+        "tizen"
+    } else if has("web0s") || has("webos") {
+        "webos"
+    } else if has("roku") {
+        "roku"
+    } else if fire_tv(user_agent) {
+        "fire_os"
+    } else if has("appletv") || has("apple tv") || has("tvos") {
+        "tvos"
+    } else if has("iphone") || has("ipad") || has("ipod") {
+        "ios"
+    } else if has("android") {
+        "android"
+    } else if has("cros") {
+        "chromeos"
+    } else if has("windows") {
+        "windows"
+    } else if has("macintosh") || has("mac os x") {
+        "macos"
+    } else if has("linux") {
+        "linux"
+    } else {
+        "other"
+    }
+}
+
+// Device class and OS for a User-Agent, the configured rules first
+pub fn classify<'a>(config: &'a DeviceConfig, user_agent: &str) -> (&'a str, &'a str) {
+    if user_agent.is_empty() {
+        return ("unknown", "unknown");
+    }
+    let ua = user_agent.to_ascii_lowercase();
+    match config
+        .rules
+        .iter()
+        .find(|rule| ua.contains(&rule.contains.to_ascii_lowercase()))
+    {
+        Some(rule) if rule.os.is_empty() => (&rule.class, os(user_agent, &ua)),
+        Some(rule) => (&rule.class, &rule.os),
+        None => (class(user_agent, &ua), os(user_agent, &ua)),
+    }
+}
+
+// Send the classification upstream in place of anything the client claimed,
+// and count the request by it
+pub fn enrich(
+    config: &DeviceConfig,
+    route: &RouteConfig,
+    req: &HttpRequest,
+    headers: &mut HeaderMap,
+) {
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let (class, os) = classify(config, user_agent);
+    for (name, value) in [(&config.class_header, class), (&config.os_header, os)] {
+        // Both checked when the config was loaded
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    metrics::inc(
+        "device_requests_total",
+        &[("route", &route.name), ("class", class), ("os", os)],
+    );
+    if !config.hint_prefix.is_empty() {
+        forward_hints(config, req, headers);
+    }
+}
+
+// A quoted sf-string, such as "Android"
+fn unquote(value: &str) -> Option<&str> {
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    (!value.contains('"')).then_some(value)
+}
+
+// The browser in Sec-CH-UA, such as "Chromium";v="118", "Google
+// Chrome";v="118", "Not=A?Brand";v="99": the brand that's neither
+// Chromium nor made up to keep servers from matching on the list, and its
+// version
+fn browser(value: &str) -> Option<(String, String)> {
+    let brands: Vec<(&str, &str)> = value
+        .split(',')
+        .filter_map(|item| {
+            let (brand, params) = item.split_once(';').unwrap_or((item, ""));
+            let version = params
+                .split(';')
+                .find_map(|param| param.trim().strip_prefix("v="))
+                .and_then(unquote)
+                .unwrap_or_default();
+            Some((unquote(brand)?, version))
+        })
+        .filter(|(brand, _)| !(brand.contains("Not") && brand.contains("Brand")))
+        .collect();
+    let (brand, version) = brands
+        .iter()
+        .find(|(brand, _)| *brand != "Chromium")
+        .or(brands.first())?;
+    Some((brand.to_ascii_lowercase(), version.to_string()))
+}
+
+// Normalized values of the Client Hints the request came with, by the name
+// they go upstream under; hints that don't parse are left out
+fn hints(req: &HttpRequest) -> Vec<(&'static str, String)> {
+    let get = |names: &[&str]| {
+        names.iter().find_map(|name| {
+            let value = req.headers().get(*name)?.to_str().ok()?.trim();
+            (!value.is_empty()).then_some(value)
+        })
+    };
+    let mut hints = Vec::new();
+    if let Some(dpr) = get(&["sec-ch-dpr", "dpr"]).and_then(|v| v.parse::<f64>().ok()) {
+        if (0.5..=8.0).contains(&dpr) {
+            hints.push(("dpr", format!("{}", (dpr * 100.0).round() / 100.0)));
+        }
+    }
+    let width = get(&["sec-ch-viewport-width", "viewport-width"]);
+    if let Some(width) = width.and_then(|v| v.parse::<u32>().ok()) {
+        if (1..=10000).contains(&width) {
+            hints.push(("viewport-width", width.to_string()));
+        }
+    }
+    if let Some(ect) = get(&["ect"]).map(str::to_ascii_lowercase) {
+        if ["slow-2g", "2g", "3g", "4g"].contains(&ect.as_str()) {
+            hints.push(("network", ect));
+        }
+    }
+    if let Some(downlink) = get(&["downlink"]).and_then(|v| v.parse::<f64>().ok()) {
+        if (0.0..=10000.0).contains(&downlink) {
+            hints.push(("downlink", format!("{}", downlink)));
+        }
+    }
+    if get(&["save-data"]).is_some_and(|v| v.eq_ignore_ascii_case("on")) {
+        hints.push(("save-data", "true".to_string()));
+    }
+    match get(&["sec-ch-ua-mobile"]) {
+        Some("?1") => hints.push(("mobile", "true".to_string())),
+        Some("?0") => hints.push(("mobile", "false".to_string())),
+        _ => {}
+    }
+    if let Some(platform) = get(&["sec-ch-ua-platform"]).and_then(unquote) {
+        hints.push(("platform", platform.to_ascii_lowercase()));
+    }
+    if let Some(model) = get(&["sec-ch-ua-model"]).and_then(unquote) {
+        if !model.is_empty() {
+            hints.push(("model", model.to_string()));
+        }
+    }
+    if let Some((brand, version)) = get(&["sec-ch-ua"]).and_then(browser) {
+        hints.push(("browser", brand));
+        if !version.is_empty() {
+            hints.push(("browser-version", version));
+        }
+    }
+    hints
+}
+
+// Client Hints under <hint_prefix> names, in place of anything the client
+// sent under them itself
+fn forward_hints(config: &DeviceConfig, req: &HttpRequest, headers: &mut HeaderMap) {
+    const NAMES: [&str; 10] = [
+        "dpr",
+        "viewport-width",
+        "network",
+        "downlink",
+        "save-data",
+        "mobile",
+        "platform",
+        "model",
+        "browser",
+        "browser-version",
+    ];
+    for name in NAMES {
+        headers.remove(format!("{}{}", config.hint_prefix, name));
+    }
+    for (name, value) in hints(req) {
+        // The prefix was checked when the config was loaded
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(format!("{}{}", config.hint_prefix, name).as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+// Ask browsers for the configured hints on successful HTML pages
+pub fn request_hints(config: &DeviceConfig, response: &mut HttpResponse) {
+    if config.request_hints.is_empty() || !response.status().is_success() {
+        return;
+    }
+    let html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if !html {
+        return;
+    }
+    for (name, hints) in [
+        ("accept-ch", &config.request_hints),
+        ("critical-ch", &config.critical_hints),
+    ] {
+        if let (false, Ok(value)) = (hints.is_empty(), HeaderValue::from_str(&hints.join(", "))) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(name), value);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::metrics;
+
+// A client hanging up makes actix drop whatever was serving it: the handler
+// while the upstream is still working on the response head, or the response
+// body once it's streaming. Either way the upstream request or body goes
+// with it and its connection is closed, so there's nothing to cancel by
+// hand; this only tells the drops apart from finished requests.
+pub fn cancelled(route: &str, stage: &str) {
+    metrics::inc(
+        "requests_cancelled_total",
+        &[("route", route), ("stage", stage)],
+    );
+}
+
+// Armed until the upstream's response head is back
+pub struct Watch<'a> {
+    route: &'a str,
+    armed: bool,
+}
+
+impl<'a> Watch<'a> {
+    pub fn new(route: &'a str) -> Self {
+        Watch { route, armed: true }
+    }
+
+    pub fn done(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            cancelled(self.route, "upstream");
+        }
+    }
+}
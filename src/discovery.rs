@@ -0,0 +1,193 @@
+use crate::config::DiscoveryConfig;
+use crate::metrics;
+use crate::proxy::AppState;
+use actix_web::web;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::env;
+use std::io::Error;
+use std::time::Duration;
+
+// Upstream pools whose targets come from Consul ([upstreams.x.discovery]).
+// Each is watched with a blocking query on the service's health endpoint,
+// asking only for instances whose checks pass, so a change reaches the pool
+// as soon as Consul sees it. While Consul can't be reached, or reports no
+// healthy instance at all, the pool keeps the targets it last had.
+
+#[derive(Deserialize)]
+struct Entry {
+    #[serde(rename = "Node")]
+    node: Node,
+    #[serde(rename = "Service")]
+    service: Service,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    #[serde(rename = "Address")]
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct Service {
+    #[serde(rename = "Address", default)]
+    address: String,
+    #[serde(rename = "Port")]
+    port: u16,
+}
+
+// Longest pause between attempts while Consul is failing
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+struct Watch {
+    upstream: String,
+    config: DiscoveryConfig,
+    token: String,
+    client: Client,
+}
+
+// Start a watch for each pool with discovery
+pub fn spawn(state: web::Data<AppState>) -> Result<(), Error> {
+    for (name, pool) in &state.config.upstreams {
+        let Some(config) = &pool.discovery else {
+            continue;
+        };
+        let token = match config.token.strip_prefix("env:") {
+            Some(var) => env::var(var).map_err(|_| {
+                Error::other(format!(
+                    "Upstream {} discovery token needs the {} environment variable",
+                    name, var
+                ))
+            })?,
+            None => config.token.clone(),
+        };
+        // Long enough for Consul to hold the query the whole wait, plus
+        // the jitter it adds
+        let client = Client::builder()
+            .timeout(Duration::from_secs(
+                config.wait_secs + config.wait_secs / 16 + 10,
+            ))
+            .build()
+            .map_err(|e| Error::other(format!("Upstream {} discovery: {}", name, e)))?;
+        let watch = Watch {
+            upstream: name.clone(),
+            config: config.clone(),
+            token,
+            client,
+        };
+        println!(
+            "Upstream {} takes its targets from Consul service {}",
+            name, config.service
+        );
+        let state = state.clone();
+        tokio::spawn(async move { watch.run(&state).await });
+    }
+    Ok(())
+}
+
+impl Watch {
+    async fn run(&self, state: &AppState) {
+        let mut index = 0u64;
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match self.query(index).await {
+                Ok((next, targets)) => {
+                    backoff = Duration::from_secs(1);
+                    // The index going backwards means Consul's state was
+                    // reset; start over rather than wait on a stale one
+                    index = if next < index { 0 } else { next };
+                    self.update(state, targets);
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Upstream {} discovery: Consul query failed: {}",
+                        self.upstream, e
+                    );
+                    metrics::inc("discovery_errors_total", &[("upstream", &self.upstream)]);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    // The service's passing instances as targets, once the index moves past
+    // the one given (at once for 0)
+    async fn query(&self, index: u64) -> Result<(u64, Vec<String>), String> {
+        let base = self.config.consul.trim_end_matches('/');
+        let mut url = Url::parse(&format!("{}/v1/health/service/", base))
+            .and_then(|url| url.join(&self.config.service))
+            .map_err(|e| e.to_string())?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("passing", "true");
+            query.append_pair("index", &index.to_string());
+            query.append_pair("wait", &format!("{}s", self.config.wait_secs));
+            if let Some(tag) = &self.config.tag {
+                query.append_pair("tag", tag);
+            }
+            if let Some(datacenter) = &self.config.datacenter {
+                query.append_pair("dc", datacenter);
+            }
+        }
+        let mut request = self.client.get(url);
+        if !self.token.is_empty() {
+            request = request.header("X-Consul-Token", &self.token);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("status {}", response.status()));
+        }
+        let next = response
+            .headers()
+            .get("x-consul-index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .ok_or("no X-Consul-Index in the response")?;
+        let entries: Vec<Entry> = response.json().await.map_err(|e| e.to_string())?;
+
+        let mut targets: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let address = match entry.service.address.as_str() {
+                    "" => entry.node.address.as_str(),
+                    address => address,
+                };
+                match address.contains(':') {
+                    true => format!(
+                        "{}://[{}]:{}",
+                        self.config.scheme, address, entry.service.port
+                    ),
+                    false => format!(
+                        "{}://{}:{}",
+                        self.config.scheme, address, entry.service.port
+                    ),
+                }
+            })
+            .collect();
+        targets.sort();
+        targets.dedup();
+        Ok((next, targets))
+    }
+
+    fn update(&self, state: &AppState, targets: Vec<String>) {
+        if targets.is_empty() {
+            eprintln!(
+                "Upstream {} discovery: no healthy instance of {}; keeping the current targets",
+                self.upstream, self.config.service
+            );
+            return;
+        }
+        let count = targets.len();
+        let listed = targets.join(", ");
+        if state.upstreams.set_targets(&self.upstream, targets) {
+            println!("Upstream {} targets are now {}", self.upstream, listed);
+            metrics::inc("discovery_updates_total", &[("upstream", &self.upstream)]);
+        }
+        metrics::set(
+            "discovery_targets",
+            &[("upstream", &self.upstream)],
+            count as f64,
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::config::{DnsConfig, IpFamily};
+use crate::egress_policy::Policy;
+use crate::metrics;
+use crate::tuning;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{Client, ClientBuilder};
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Resolves the overridden host names to their fixed addresses and the rest
+// through the system, then orders or filters the addresses by family. The
+// connector tries the first address's family first and races the other one
+// after a short delay, so ordering is enough to prefer one. Lookups are
+// timed per host, and the answers held to the [egress] allowlist.
+pub struct Resolver {
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    family: IpFamily,
+    egress: Option<Arc<Policy>>,
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let overrides = self.overrides.clone();
+        let family = self.family;
+        let egress = self.egress.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let mut addrs: Vec<SocketAddr> =
+                match overrides.get(host.trim_end_matches('.').to_ascii_lowercase().as_str()) {
+                    Some(ips) => ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect(),
+                    None => {
+                        let started = Instant::now();
+                        let resolved = tokio::net::lookup_host((host.as_str(), 0)).await;
+                        let labels = [("host", host.as_str())];
+                        metrics::add(
+                            "upstream_dns_seconds_total",
+                            &labels,
+                            started.elapsed().as_secs_f64(),
+                        );
+                        metrics::inc("upstream_dns_lookups_total", &labels);
+                        if resolved.is_err() {
+                            metrics::inc("upstream_dns_errors_total", &labels);
+                        }
+                        resolved?.collect()
+                    }
+                };
+            match family {
+                IpFamily::Any => {}
+                IpFamily::PreferIpv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+                IpFamily::PreferIpv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+                IpFamily::Ipv4Only => addrs.retain(|a| a.is_ipv4()),
+                IpFamily::Ipv6Only => addrs.retain(|a| a.is_ipv6()),
+            }
+            if addrs.is_empty() {
+                let family = match family {
+                    IpFamily::Ipv6Only => "IPv6 ",
+                    IpFamily::Ipv4Only => "IPv4 ",
+                    _ => "",
+                };
+                return Err(Error::other(format!("{} has no {}address", host, family)).into());
+            }
+            if let Some(egress) = &egress {
+                egress.filter(&host, &mut addrs)?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// A client builder resolving through the overrides and the family, and
+// connecting only where the egress policy allows when there is one
+pub fn builder(dns: &DnsConfig, family: IpFamily, egress: Option<Arc<Policy>>) -> ClientBuilder {
+    Client::builder()
+        .dns_resolver(Arc::new(Resolver {
+            overrides: Arc::new(dns.overrides.clone()),
+            family,
+            egress,
+        }))
+        .pool_max_idle_per_host(tuning::get().pool_max_idle_per_host)
+}
+
+// The client for routes that go straight to a URL, and pools without
+// settings of their own
+pub fn client(dns: &DnsConfig, egress: Option<Arc<Policy>>) -> Result<Client, Error> {
+    let mut hosts: Vec<_> = dns.overrides.iter().collect();
+    hosts.sort();
+    for (host, addrs) in hosts {
+        let addrs: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+        println!("DNS override: {} -> {}", host, addrs.join(", "));
+    }
+    builder(dns, IpFamily::Any, egress)
+        .build()
+        .map_err(|e| Error::other(format!("HTTP client: {}", e)))
+}
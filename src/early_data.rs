@@ -0,0 +1,230 @@
+use actix_web::HttpRequest;
+use rustls::{ServerConfig, ServerConnection};
+use std::future::poll_fn;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+// TLS for listeners that take 0-RTT early data ([tls] early_data).
+// tokio-rustls finishes the handshake before it hands over the stream and
+// never reads early data, so the request a resuming client sends along
+// with its ClientHello would be lost. This stream is handed over as soon
+// as early data arrives, and says whether the handshake is still going:
+// requests read before the client's Finished may be replays.
+
+pub struct TlsStream {
+    io: TcpStream,
+    conn: ServerConnection,
+    // Early data read out of the connection and not yet passed on
+    early: Vec<u8>,
+    handshaking: Handshaking,
+}
+
+// Connection data, set while requests may still come out of early data:
+// until the client sends something after its Finished. Clearing it on
+// Finished itself could let a request read from early data just before be
+// taken for a safe one; holding it a little longer only costs a retry.
+#[derive(Clone)]
+pub struct Handshaking(Arc<AtomicBool>);
+
+// Whether the request came in early data, before the handshake completed
+pub fn received(req: &HttpRequest) -> bool {
+    req.conn_data::<Handshaking>()
+        .is_some_and(|handshaking| handshaking.0.load(Ordering::Relaxed))
+}
+
+// Blocking IO for rustls on top of the async socket, WouldBlock standing
+// for Pending
+struct SyncIo<'a, 'b> {
+    io: &'a mut TcpStream,
+    cx: &'a mut Context<'b>,
+}
+
+impl Read for SyncIo<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for SyncIo<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+fn pending<T>(result: Result<T, Error>) -> Poll<Result<T, Error>> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+// Run the handshake until it's done or early data has arrived
+pub async fn accept(config: Arc<ServerConfig>, io: TcpStream) -> Result<TlsStream, Error> {
+    let mut tls = TlsStream {
+        io,
+        conn: ServerConnection::new(config).map_err(Error::other)?,
+        early: Vec::new(),
+        handshaking: Handshaking(Arc::new(AtomicBool::new(true))),
+    };
+    poll_fn(|cx| tls.poll_handshake(cx)).await?;
+    if tls.early.is_empty() {
+        tls.handshaking.0.store(false, Ordering::Relaxed);
+    }
+    Ok(tls)
+}
+
+impl TlsStream {
+    pub fn handshaking(&self) -> Handshaking {
+        self.handshaking.clone()
+    }
+
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        loop {
+            while self.conn.wants_write() {
+                ready!(self.write_io(cx))?;
+            }
+            if !self.conn.is_handshaking() || !self.early.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            if ready!(self.read_io(cx))? == 0 {
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    // Read and process what the client sent, setting early data aside
+    fn read_io(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        let n = ready!(pending(self.conn.read_tls(&mut io)))?;
+        if let Err(e) = self.conn.process_new_packets() {
+            // Let the client know why, if the socket takes it
+            let _ = self.conn.write_tls(&mut io);
+            return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, e)));
+        }
+        if let Some(mut early) = self.conn.early_data() {
+            early.read_to_end(&mut self.early)?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn write_io(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        pending(self.conn.write_tls(&mut io))
+    }
+
+    // Send what rustls has queued, as far as the socket takes it now
+    fn write_some(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        while self.conn.wants_write() {
+            match self.write_io(cx) {
+                Poll::Ready(Ok(0)) => return Err(ErrorKind::WriteZero.into()),
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        loop {
+            if !this.early.is_empty() {
+                let n = this.early.len().min(buf.remaining());
+                buf.put_slice(&this.early[..n]);
+                this.early.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    if n > 0 {
+                        this.handshaking.0.store(false, Ordering::Relaxed);
+                    }
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                // Closed without close_notify, which clients commonly do
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Poll::Ready(Ok(())),
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Poll::Ready(Err(e)),
+                Err(_) => {}
+            }
+            // The rest of the handshake and session tickets go out on the way
+            this.write_some(cx)?;
+            if ready!(this.read_io(cx))? == 0 && this.conn.is_handshaking() {
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        let n = this.conn.writer().write(buf)?;
+        this.write_some(cx)?;
+        if n > 0 || buf.is_empty() {
+            return Poll::Ready(Ok(n));
+        }
+        // rustls's buffer is full; wait for the socket to take some
+        ready!(this.write_io(cx))?;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        while this.conn.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        this.conn.send_close_notify();
+        while this.conn.wants_write() {
+            ready!(this.write_io(cx))?;
+        }
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,125 @@
+use crate::config::{EdgeFilesConfig, StaticAssetConfig};
+use crate::metrics;
+use crate::sniff;
+use actix_web::http::{header, Method};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+
+// A blank 16x16 icon, so browsers asking for one stop getting 404s
+const FAVICON: &[u8] = include_bytes!("../assets/favicon.ico");
+
+struct File {
+    path: String,
+    body: Bytes,
+    content_type: String,
+    max_age_secs: u64,
+}
+
+// Small files the edge answers for itself, so they never make the trip to
+// the gateway. Contents are read once at startup.
+pub struct EdgeFiles {
+    files: Vec<File>,
+}
+
+// From the config itself or from a file, not both
+fn contents(
+    name: &str,
+    inline: &Option<String>,
+    path: &Option<String>,
+) -> Result<Option<Bytes>, Error> {
+    match (inline, path) {
+        (Some(_), Some(_)) => Err(Error::other(format!(
+            "edge_files: give {} or {}_path, not both",
+            name, name
+        ))),
+        (Some(text), None) => Ok(Some(Bytes::from(text.clone()))),
+        (None, Some(path)) => fs::read(path)
+            .map(|data| Some(Bytes::from(data)))
+            .map_err(|e| Error::other(format!("edge_files {} {}: {}", name, path, e))),
+        (None, None) => Ok(None),
+    }
+}
+
+fn asset(asset: &StaticAssetConfig, max_age_secs: u64) -> Result<File, Error> {
+    let body = match (&asset.file, asset.path.as_str()) {
+        (Some(file), _) => fs::read(file)
+            .map(Bytes::from)
+            .map_err(|e| Error::other(format!("edge_files asset {}: {}", file, e)))?,
+        (None, "/favicon.ico") => Bytes::from_static(FAVICON),
+        (None, path) => {
+            return Err(Error::other(format!(
+                "edge_files asset {} needs a file",
+                path
+            )))
+        }
+    };
+    let name = asset.file.as_deref().unwrap_or(&asset.path);
+    let extension = Path::new(name).extension().and_then(|e| e.to_str());
+    let content_type = asset
+        .content_type
+        .as_deref()
+        .or_else(|| sniff::sniff(&body))
+        .or(match extension.map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("ico") => Some("image/x-icon"),
+            Some("svg") => Some("image/svg+xml"),
+            Some("png") => Some("image/png"),
+            Some("webmanifest") => Some("application/manifest+json"),
+            _ => None,
+        })
+        .unwrap_or("application/octet-stream");
+    Ok(File {
+        path: asset.path.clone(),
+        content_type: content_type.to_string(),
+        body,
+        max_age_secs,
+    })
+}
+
+impl EdgeFiles {
+    pub fn new(config: &EdgeFilesConfig) -> Result<Self, Error> {
+        let mut files = Vec::new();
+        let mut text = |path: &str, body| {
+            files.push(File {
+                path: path.to_string(),
+                body,
+                content_type: "text/plain; charset=utf-8".to_string(),
+                max_age_secs: config.max_age_secs,
+            })
+        };
+        if let Some(robots) = contents("robots_txt", &config.robots_txt, &config.robots_txt_path)? {
+            text("/robots.txt", robots);
+        }
+        let security = contents(
+            "security_txt",
+            &config.security_txt,
+            &config.security_txt_path,
+        )?;
+        if let Some(security) = security {
+            text("/.well-known/security.txt", security);
+        }
+        for config_asset in &config.assets {
+            files.push(asset(config_asset, config.asset_max_age_secs)?);
+        }
+        Ok(EdgeFiles { files })
+    }
+
+    pub fn respond(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return None;
+        }
+        let file = self.files.iter().find(|file| file.path == req.path())?;
+        metrics::inc("edge_files_served_total", &[("path", &file.path)]);
+        Some(
+            HttpResponse::Ok()
+                .content_type(file.content_type.as_str())
+                .insert_header((
+                    header::CACHE_CONTROL,
+                    format!("public, max-age={}", file.max_age_secs),
+                ))
+                .body(file.body.clone()),
+        )
+    }
+}
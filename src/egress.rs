@@ -0,0 +1,99 @@
+use crate::disconnect;
+use crate::metrics;
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use std::error::Error as StdError;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// What a response is counted under
+pub struct Labels {
+    pub route: String,
+    pub tenant: String,
+    pub status: u16,
+    pub head: bool,
+}
+
+// A response body that counts the bytes actually handed to the connection,
+// which for streamed bodies is only known once they're done. `done` gets the
+// total when the body ends or is dropped, the client having gone away.
+struct Counted {
+    body: BoxBody,
+    sent: u64,
+    ended: bool,
+    labels: Labels,
+    done: Option<Box<dyn FnOnce(u64)>>,
+}
+
+impl Counted {
+    fn finish(&mut self) {
+        let Some(done) = self.done.take() else {
+            return;
+        };
+        let class = format!("{}xx", self.labels.status / 100);
+        metrics::add(
+            "response_bytes_total",
+            &[
+                ("route", &self.labels.route),
+                ("status_class", &class),
+                ("tenant", &self.labels.tenant),
+            ],
+            self.sent as f64,
+        );
+        done(self.sent);
+    }
+}
+
+impl MessageBody for Counted {
+    type Error = Box<dyn StdError>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let next = Pin::new(&mut this.body).poll_next(cx);
+        match &next {
+            Poll::Ready(Some(Ok(chunk))) => this.sent += chunk.len() as u64,
+            Poll::Ready(_) => {
+                this.ended = true;
+                this.finish();
+            }
+            Poll::Pending => {}
+        }
+        next
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        // Empty bodies are never read; any other left unfinished was cut
+        // off by the client
+        let empty = matches!(self.body.size(), BodySize::None | BodySize::Sized(0));
+        if !self.ended && !empty && !self.labels.head {
+            disconnect::cancelled(&self.labels.route, "body");
+        }
+        self.finish();
+    }
+}
+
+pub fn count(
+    response: HttpResponse,
+    labels: Labels,
+    done: impl FnOnce(u64) + 'static,
+) -> HttpResponse {
+    response.map_body(|_, body| {
+        BoxBody::new(Counted {
+            body,
+            sent: 0,
+            ended: false,
+            labels,
+            done: Some(Box::new(done)),
+        })
+    })
+}
@@ -0,0 +1,158 @@
+use crate::client_ip;
+use crate::config::EgressConfig;
+use crate::metrics;
+use ipnet::IpNet;
+use reqwest::Url;
+use std::fmt;
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr};
+
+// Where requests may be forwarded to, so a rewrite, script or admin edit
+// can't point the gateway at the metadata service or the admin port of
+// something internal. Upstream URLs are checked before anything is sent;
+// host names that aren't allowed by name are checked again on every
+// address they resolve to, which also covers DNS answers that change
+// after the check.
+pub struct Policy {
+    hosts: Vec<String>,
+    networks: Vec<IpNet>,
+}
+
+// A refusal by the resolver, told apart from other connect errors by
+// refusal()
+#[derive(Debug)]
+pub struct Refused(pub String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+// The resolver's refusal somewhere in the sources of a client error
+pub fn refusal<'a>(e: &'a (dyn std::error::Error + 'static)) -> Option<&'a Refused> {
+    let mut source = e.source();
+    while let Some(inner) = source {
+        if let Some(refused) = inner.downcast_ref::<Refused>() {
+            return Some(refused);
+        }
+        source = inner.source();
+    }
+    None
+}
+
+impl Policy {
+    pub fn new(config: &EgressConfig) -> Result<Self, Error> {
+        let networks = config
+            .networks
+            .iter()
+            .map(|source| {
+                client_ip::network(source)
+                    .ok_or_else(|| Error::other(format!("Invalid [egress] network {}", source)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Policy {
+            hosts: config
+                .hosts
+                .iter()
+                .map(|h| h.to_ascii_lowercase())
+                .collect(),
+            networks,
+        })
+    }
+
+    // Listed as it is, or under a "*." entry for its parent domain
+    fn named(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(parent) => host.ends_with(&format!(".{}", parent)),
+                None => *allowed == host,
+            })
+    }
+
+    fn covers(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    // Whether an upstream URL may be connected to. Host names outside
+    // `hosts` pass here when there are networks, for the resolver to check.
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .ok_or_else(|| format!("{} has no host", url))?;
+        if self.named(&host) {
+            return Ok(());
+        }
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        match literal.parse::<IpAddr>() {
+            Ok(ip) if self.covers(ip) => Ok(()),
+            Ok(ip) => Err(format!("{} isn't in an allowed network", ip)),
+            Err(_) if !self.networks.is_empty() => Ok(()),
+            Err(_) => Err(format!("{} isn't an allowed host", host)),
+        }
+    }
+
+    // check(), then the addresses the host name resolves to, for clients
+    // that don't resolve through dns::Resolver
+    pub async fn check_resolved(&self, url: &str) -> Result<(), String> {
+        self.check(url)?;
+        let Some(url) = Url::parse(url).ok() else {
+            return Ok(());
+        };
+        let host = url.host_str().unwrap_or_default();
+        if self.named(host) || host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+            return Ok(());
+        }
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("{}: {}", host, e))?
+            .collect();
+        match addrs.iter().find(|addr| !self.covers(addr.ip())) {
+            Some(addr) => Err(format!(
+                "{} resolves to {}, which isn't in an allowed network",
+                host,
+                addr.ip()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // Drop the addresses a host name resolved to that aren't in an allowed
+    // network, refusing the connection when none are left
+    pub fn filter(&self, host: &str, addrs: &mut Vec<SocketAddr>) -> Result<(), Refused> {
+        if self.named(host) {
+            return Ok(());
+        }
+        let before = addrs.len();
+        addrs.retain(|addr| self.covers(addr.ip()));
+        if addrs.is_empty() {
+            return Err(Refused(format!(
+                "{} resolves to no address in an allowed network",
+                host
+            )));
+        }
+        if addrs.len() < before {
+            println!(
+                "Egress: ignoring {} of the addresses {} resolves to, being outside the allowed networks",
+                before - addrs.len(),
+                host
+            );
+        }
+        Ok(())
+    }
+}
+
+// Log and count a request the policy kept from being forwarded
+pub fn refused(route: &str, url: &str, reason: &str) {
+    metrics::inc("egress_refused_total", &[("route", route)]);
+    println!(
+        "Egress: refused to forward route {} to {}: {}",
+        route, url, reason
+    );
+}
@@ -0,0 +1,349 @@
+use crate::buffering;
+use crate::cache::CachedResponse;
+use crate::config::RouteConfig;
+use crate::metrics;
+use crate::tuning;
+use actix_web::body::{BodySize, BodyStream, BoxBody, MessageBody};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{self as streaming, GzEncoder};
+use flate2::Compression;
+use std::io::{Read, Write};
+use std::pin::Pin;
+
+// Whether the client's Accept-Encoding allows `coding`
+fn accepts(req: &HttpRequest, coding: &str) -> bool {
+    let accept = match req
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    let mut wildcard = false;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("").to_ascii_lowercase();
+        let refused = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        let name = if name == "x-gzip" {
+            "gzip".to_string()
+        } else {
+            name
+        };
+        if name == coding {
+            return !refused;
+        }
+        if name == "*" {
+            wildcard = !refused;
+        }
+    }
+    wildcard
+}
+
+pub fn decode(coding: &str, body: &[u8]) -> Option<std::io::Result<Vec<u8>>> {
+    let mut decoded = Vec::new();
+    let result = match coding {
+        "gzip" | "x-gzip" => GzDecoder::new(body).read_to_end(&mut decoded),
+        // HTTP's deflate is the zlib format
+        "deflate" => ZlibDecoder::new(body).read_to_end(&mut decoded),
+        _ => return None,
+    };
+    Some(result.map(|_| decoded))
+}
+
+fn level() -> Compression {
+    Compression::new(tuning::get().gzip_level)
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), level());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+fn compressible(response: &HttpResponse) -> bool {
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    compressible_type(content_type)
+}
+
+fn compressible_type(content_type: &str) -> bool {
+    !["image/", "video/", "audio/", "font/woff"]
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix))
+}
+
+// Codings a cached response may be kept in besides none, in the order a
+// client that takes several is served them
+const CODINGS: [&str; 4] = ["br", "zstd", "gzip", "deflate"];
+
+// Whether a Vary value already covers Accept-Encoding
+fn varies(value: &[u8]) -> bool {
+    (String::from_utf8_lossy(value).split(','))
+        .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case("accept-encoding"))
+}
+
+// Mark the response as one that differs by Accept-Encoding, once
+fn vary(headers: &mut HeaderMap) {
+    if !headers.get_all(header::VARY).any(|v| varies(v.as_bytes())) {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+// Where a response in the given Content-Encoding is kept in the cache: an
+// unencoded one under the key itself and an encoded one as a variant of it,
+// so a client only ever gets a coding it takes. None for codings no client
+// is asked about.
+pub fn cache_key(key: &str, coding: Option<&str>) -> Option<String> {
+    match coding.map(|c| c.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("identity") => Some(key.to_string()),
+        Some("x-gzip") => Some(format!("{}#gzip", key)),
+        Some(coding) if CODINGS.contains(&coding) => Some(format!("{}#{}", key, coding)),
+        Some(_) => None,
+    }
+}
+
+// The cached variants the client could be served, best first
+pub fn variants<'a>(req: &'a HttpRequest, key: &'a str) -> impl Iterator<Item = String> + 'a {
+    (CODINGS.into_iter())
+        .filter(|coding| accepts(req, coding))
+        .map(move |coding| format!("{}#{}", key, coding))
+}
+
+// A gzip variant of a cached response, for a client that takes it on a
+// route that compresses. It's made once and kept next to the response,
+// expiring with it, instead of compressing the same body on every hit.
+pub fn gzip_variant(
+    req: &HttpRequest,
+    route: &RouteConfig,
+    cached: &CachedResponse,
+) -> Option<CachedResponse> {
+    let header = |name: &str| {
+        let (_, value) = cached
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))?;
+        std::str::from_utf8(value).ok()
+    };
+    let wanted = route.compress
+        && accepts(req, "gzip")
+        && cached.status == 200
+        && header("content-encoding").is_none()
+        && cached.body.len() >= tuning::get().min_compress_bytes
+        && compressible_type(header("content-type").unwrap_or(""));
+    if !wanted {
+        return None;
+    }
+    let body = gzip(&cached.body).ok()?;
+
+    let mut headers: Vec<(String, Vec<u8>)> = (cached.headers.iter())
+        .filter(|(k, _)| !k.eq_ignore_ascii_case("content-length"))
+        .cloned()
+        .collect();
+    headers.push(("content-encoding".to_string(), b"gzip".to_vec()));
+    if !(headers.iter()).any(|(k, v)| k.eq_ignore_ascii_case("vary") && varies(v)) {
+        headers.push(("vary".to_string(), b"accept-encoding".to_vec()));
+    }
+    metrics::inc(
+        "responses_recoded_total",
+        &[("route", &route.name), ("action", "compressed")],
+    );
+    Some(CachedResponse {
+        status: cached.status,
+        headers,
+        body: Bytes::from(body),
+        stored_at: cached.stored_at,
+        ttl: cached.ttl,
+    })
+}
+
+// Make the response's Content-Encoding something the client can take. Bodies
+// that are already encoded are never compressed again.
+pub async fn negotiate(
+    req: &HttpRequest,
+    route: &RouteConfig,
+    response: HttpResponse,
+) -> HttpResponse {
+    if !(route.decompress || route.compress)
+        || req.method() == Method::HEAD
+        // Coding applies to the whole representation; recoding one range
+        // of it, or the parts of a multipart/byteranges, would corrupt it
+        || matches!(
+            response.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+        )
+    {
+        return response;
+    }
+
+    let coding = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase());
+    let decompress = match &coding {
+        Some(coding) if coding == "identity" => false,
+        Some(coding) => route.decompress && !accepts(req, coding),
+        None => false,
+    };
+    let compress =
+        coding.is_none() && route.compress && accepts(req, "gzip") && compressible(&response);
+    if !decompress && !compress {
+        // Another client may well get it compressed
+        let mut response = response;
+        if coding.is_none() && route.compress && compressible(&response) {
+            vary(response.headers_mut());
+        }
+        return response;
+    }
+
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let lines = buffering::line_delimited(&route.buffering, content_type);
+    // Smaller bodies aren't worth compressing
+    let small = matches!(
+        response.body().size(),
+        BodySize::Sized(n) if n < tuning::get().min_compress_bytes as u64
+    );
+    if compress && small && !lines {
+        let mut response = response;
+        vary(response.headers_mut());
+        return response;
+    }
+    recode(
+        route,
+        response,
+        coding.as_deref().filter(|_| decompress),
+        lines,
+    )
+}
+
+// Something that turns written bytes into recoded ones, a chunk at a time
+trait Recoder: Write + Send + 'static {
+    // What has been recoded so far
+    fn take(&mut self) -> Vec<u8>;
+    fn end(self: Box<Self>) -> std::io::Result<Vec<u8>>;
+}
+
+impl Recoder for GzEncoder<Vec<u8>> {
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+    fn end(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        self.finish()
+    }
+}
+
+impl Recoder for streaming::GzDecoder<Vec<u8>> {
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+    fn end(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        self.finish()
+    }
+}
+
+impl Recoder for streaming::ZlibDecoder<Vec<u8>> {
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.get_mut())
+    }
+    fn end(self: Box<Self>) -> std::io::Result<Vec<u8>> {
+        self.finish()
+    }
+}
+
+// Compress (or, given the coding, decode) a body as it streams, so neither
+// a large nor an endless one is held whole. Line-delimited bodies are
+// flushed after every chunk so no line waits on the next.
+fn recode(
+    route: &RouteConfig,
+    response: HttpResponse,
+    decode: Option<&str>,
+    lines: bool,
+) -> HttpResponse {
+    let (recoder, action): (Box<dyn Recoder>, _) = match decode {
+        None => (Box::new(GzEncoder::new(Vec::new(), level())), "compressed"),
+        Some("gzip" | "x-gzip") => (
+            Box::new(streaming::GzDecoder::new(Vec::new())),
+            "decompressed",
+        ),
+        Some("deflate") => (
+            Box::new(streaming::ZlibDecoder::new(Vec::new())),
+            "decompressed",
+        ),
+        // Nothing we can decode; the client gets it as it is
+        Some(_) => return response,
+    };
+    let (mut head, body) = response.into_parts();
+    match decode {
+        Some(_) => head.headers_mut().remove(header::CONTENT_ENCODING),
+        None => head
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip")),
+    };
+    head.headers_mut().remove(header::CONTENT_LENGTH);
+    vary(head.headers_mut());
+    metrics::inc(
+        "responses_recoded_total",
+        &[("route", &route.name), ("action", action)],
+    );
+
+    let state = Some((body, recoder, route.name.clone()));
+    let recoded = futures_util::stream::unfold(state, move |state| {
+        async move {
+            let (mut body, mut recoder, route): (BoxBody, _, _) = state?;
+            loop {
+                let next = std::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await;
+                let out = match next {
+                    Some(Ok(chunk)) => recoder
+                        .write_all(&chunk)
+                        .and_then(|_| if lines { recoder.flush() } else { Ok(()) })
+                        .map(|_| recoder.take()),
+                    Some(Err(e)) => Err(std::io::Error::other(e.to_string())),
+                    None => {
+                        return match recoder.end() {
+                            Ok(last) if last.is_empty() => None,
+                            Ok(last) => Some((Ok(Bytes::from(last)), None)),
+                            Err(e) => {
+                                eprintln!("Route {}: failed to recode the response: {}", route, e);
+                                Some((Err(e), None))
+                            }
+                        }
+                    }
+                };
+                match out {
+                    // An empty chunk would end the body early
+                    Ok(out) if out.is_empty() => continue,
+                    Ok(out) => return Some((Ok(Bytes::from(out)), Some((body, recoder, route)))),
+                    Err(e) => {
+                        eprintln!("Route {}: failed to recode the response: {}", route, e);
+                        return Some((Err(e), None));
+                    }
+                }
+            }
+        }
+    });
+    match decode {
+        Some(_) if lines => {
+            let lines = buffering::by_line(Box::pin(recoded));
+            head.set_body(BodyStream::new(lines)).map_into_boxed_body()
+        }
+        _ => head
+            .set_body(BodyStream::new(recoded))
+            .map_into_boxed_body(),
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::ErrorResponseConfig;
+use crate::egress_policy;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+
+// Everything that can go wrong while proxying a request
+#[derive(Debug)]
+pub enum ProxyError {
+    Connect(String),
+    Timeout,
+    // The request's deadline passed, see deadline.rs
+    Deadline,
+    Tls(String),
+    BodyTooLarge { limit: usize },
+    ResponseTooLarge { limit: usize },
+    // A body that doesn't match the digest sent with it, by algorithm
+    BodyChecksum(String),
+    ResponseChecksum(String),
+    // Buffering the body would go over the memory budget
+    MemoryBudget,
+    // The upstream answered, but with a 5xx
+    Upstream(StatusCode),
+    // The upstream is outside the [egress] allowlist
+    Egress(String),
+    Other(String),
+}
+
+impl ProxyError {
+    // Name used in logs, metrics and as the key of the [errors] table
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProxyError::Connect(_) => "connect",
+            ProxyError::Timeout => "timeout",
+            ProxyError::Deadline => "deadline",
+            ProxyError::Tls(_) => "tls",
+            ProxyError::BodyTooLarge { .. } => "body_too_large",
+            ProxyError::ResponseTooLarge { .. } => "response_too_large",
+            ProxyError::BodyChecksum(_) => "body_checksum",
+            ProxyError::ResponseChecksum(_) => "response_checksum",
+            ProxyError::MemoryBudget => "memory_budget",
+            ProxyError::Upstream(_) => "upstream_5xx",
+            ProxyError::Egress(_) => "egress_refused",
+            ProxyError::Other(_) => "other",
+        }
+    }
+
+    fn default_response(&self) -> (StatusCode, &'static str) {
+        match self {
+            ProxyError::Connect(_) | ProxyError::Tls(_) | ProxyError::Other(_) => {
+                (StatusCode::BAD_GATEWAY, "Bad gateway")
+            }
+            ProxyError::ResponseTooLarge { .. } => {
+                (StatusCode::BAD_GATEWAY, "Upstream response too large")
+            }
+            ProxyError::Timeout | ProxyError::Deadline => {
+                (StatusCode::GATEWAY_TIMEOUT, "Gateway timeout")
+            }
+            ProxyError::MemoryBudget => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Overloaded, try again later",
+            ),
+            ProxyError::BodyTooLarge { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large")
+            }
+            ProxyError::BodyChecksum(_) => (
+                StatusCode::BAD_REQUEST,
+                "Request body doesn't match its digest",
+            ),
+            ProxyError::ResponseChecksum(_) => (
+                StatusCode::BAD_GATEWAY,
+                "Upstream response doesn't match its digest",
+            ),
+            ProxyError::Upstream(status) => (*status, "Service unavailable"),
+            ProxyError::Egress(_) => (StatusCode::BAD_GATEWAY, "Upstream not allowed"),
+        }
+    }
+
+    pub fn from_reqwest(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            return ProxyError::Timeout;
+        }
+
+        // The resolver's refusals come back as connect errors
+        if let Some(refused) = egress_policy::refusal(&e) {
+            return ProxyError::Egress(refused.0.clone());
+        }
+
+        // reqwest doesn't expose TLS failures directly, so look through the
+        // source chain for the TLS backend's error
+        let chain = error_chain(&e);
+        let lower = chain.to_ascii_lowercase();
+        if lower.contains("certificate") || lower.contains("tls") || lower.contains("ssl") {
+            ProxyError::Tls(chain)
+        } else if e.is_connect() {
+            ProxyError::Connect(chain)
+        } else {
+            ProxyError::Other(chain)
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Connect(e) => write!(f, "connect failed: {}", e),
+            ProxyError::Timeout => write!(f, "upstream timed out"),
+            ProxyError::Deadline => write!(f, "request deadline passed"),
+            ProxyError::Tls(e) => write!(f, "TLS error: {}", e),
+            ProxyError::BodyTooLarge { limit } => write!(f, "body exceeds {} bytes", limit),
+            ProxyError::ResponseTooLarge { limit } => {
+                write!(f, "upstream response exceeds {} bytes", limit)
+            }
+            ProxyError::BodyChecksum(algorithm) => {
+                write!(f, "request body doesn't match its {} digest", algorithm)
+            }
+            ProxyError::ResponseChecksum(algorithm) => {
+                write!(
+                    f,
+                    "upstream response doesn't match its {} digest",
+                    algorithm
+                )
+            }
+            ProxyError::MemoryBudget => write!(f, "memory budget exceeded"),
+            ProxyError::Upstream(status) => write!(f, "upstream returned {}", status),
+            ProxyError::Egress(e) => write!(f, "egress refused: {}", e),
+            ProxyError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn error_chain(e: &dyn StdError) -> String {
+    let mut out = e.to_string();
+    let mut source = e.source();
+    while let Some(inner) = source {
+        // Many wrappers already repeat their source in their own message
+        let message = inner.to_string();
+        if !out.contains(&message) {
+            out.push_str(": ");
+            out.push_str(&message);
+        }
+        source = inner.source();
+    }
+    out
+}
+
+// Maps error kinds and upstream statuses to what the client actually sees
+pub struct ErrorMapper {
+    table: HashMap<String, ErrorResponseConfig>,
+}
+
+impl ErrorMapper {
+    pub fn new(table: HashMap<String, ErrorResponseConfig>) -> Self {
+        ErrorMapper { table }
+    }
+
+    fn lookup(&self, error: &ProxyError) -> Option<&ErrorResponseConfig> {
+        // An exact upstream status beats the generic upstream_5xx entry
+        if let ProxyError::Upstream(status) = error {
+            if let Some(mapped) = self.table.get(status.as_str()) {
+                return Some(mapped);
+            }
+        }
+        self.table.get(error.kind())
+    }
+
+    // Whether an upstream 5xx should be replaced rather than passed through
+    pub fn maps_upstream(&self, status: StatusCode) -> bool {
+        self.lookup(&ProxyError::Upstream(status)).is_some()
+    }
+
+    pub fn response(&self, error: &ProxyError) -> HttpResponse {
+        let (default_status, default_body) = error.default_response();
+
+        let mut response = match self.lookup(error) {
+            Some(mapped) => {
+                let status = mapped
+                    .status
+                    .and_then(|s| StatusCode::from_u16(s).ok())
+                    .unwrap_or(default_status);
+                HttpResponse::build(status)
+                    .content_type(mapped.content_type.as_str())
+                    .body(
+                        mapped
+                            .body
+                            .clone()
+                            .unwrap_or_else(|| default_body.to_string()),
+                    )
+            }
+            None => HttpResponse::build(default_status)
+                .content_type("text/plain")
+                .body(default_body),
+        };
+        // Buffers free up quickly, so it's worth trying again soon
+        if let ProxyError::MemoryBudget = error {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        response
+    }
+}
@@ -0,0 +1,66 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+// Real-time operational events streamed to admin WebSocket clients
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Rps {
+        requests_per_second: u64,
+    },
+    Error {
+        route: String,
+        status: u16,
+        message: String,
+    },
+    RouteChange {
+        action: &'static str,
+        route: String,
+    },
+    UpstreamHealth {
+        upstream: String,
+        target: String,
+        healthy: bool,
+    },
+}
+
+static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+fn bus() -> &'static broadcast::Sender<Event> {
+    BUS.get_or_init(|| broadcast::channel(1024).0)
+}
+
+pub fn publish(event: Event) {
+    // Nobody listening is the common case; don't bother queueing then
+    if bus().receiver_count() > 0 {
+        let _ = bus().send(event);
+    }
+}
+
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().subscribe()
+}
+
+pub fn record_request() {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Publish the request rate once a second
+pub fn spawn_rate_reporter() {
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        let mut last = REQUESTS.load(Ordering::Relaxed);
+        loop {
+            ticker.tick().await;
+            let now = REQUESTS.load(Ordering::Relaxed);
+            publish(Event::Rps {
+                requests_per_second: now - last,
+            });
+            last = now;
+        }
+    });
+}
@@ -0,0 +1,77 @@
+use crate::config::{CorsConfig, FallbackConfig, RouteConfig};
+use crate::cors;
+use crate::metrics;
+use crate::sniff;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::HttpResponse;
+
+// Stand-ins for responses a route's upstream failed to give: a placeholder
+// file, the same request to another upstream, or the last good response
+// from the cache. Only GETs and HEADs get them, as only they can safely be
+// sent a second time.
+pub fn find<'a>(
+    route: &'a RouteConfig,
+    method: &Method,
+    status: StatusCode,
+) -> Option<&'a FallbackConfig> {
+    if !matches!(*method, Method::GET | Method::HEAD) {
+        return None;
+    }
+    route
+        .fallbacks
+        .iter()
+        .find(|fallback| fallback.statuses.contains(&status.as_u16()))
+}
+
+pub fn kind(fallback: &FallbackConfig) -> &'static str {
+    match (&fallback.file, &fallback.upstream) {
+        (Some(_), _) => "file",
+        (_, Some(_)) => "upstream",
+        _ => "stale",
+    }
+}
+
+// The fallback file as a 200; None if it can't be read, so the failed
+// response stands
+pub async fn file(
+    route: &RouteConfig,
+    fallback: &FallbackConfig,
+    path: &str,
+    cors: &CorsConfig,
+) -> Option<HttpResponse> {
+    let body = match tokio::fs::read(path).await {
+        Ok(body) => body,
+        Err(e) => {
+            eprintln!("Route {} fallback file {}: {}", route.name, path, e);
+            return None;
+        }
+    };
+    let content_type = fallback
+        .content_type
+        .as_deref()
+        .or_else(|| sniff::sniff(&body))
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let mut response = HttpResponse::Ok();
+    cors::apply(cors, &mut response);
+    Some(
+        response
+            .insert_header((header::CONTENT_TYPE, content_type))
+            // Not worth keeping once the real one is back
+            .insert_header((header::CACHE_CONTROL, "no-store"))
+            .body(body),
+    )
+}
+
+pub fn record(route: &RouteConfig, fallback: &FallbackConfig, status: StatusCode, served: bool) {
+    let result = if served { "served" } else { "unavailable" };
+    metrics::inc(
+        "fallbacks_total",
+        &[
+            ("route", &route.name),
+            ("kind", kind(fallback)),
+            ("status", status.as_str()),
+            ("result", result),
+        ],
+    );
+}
@@ -0,0 +1,146 @@
+use crate::config::{DuplicatePolicy, FramingConfig, Strictness};
+use crate::metrics;
+use actix_web::http::header::{self, HeaderMap, HeaderName};
+use actix_web::HttpRequest;
+
+// Requests whose framing an upstream could read differently from us are
+// rejected before they get anywhere near it. Returns the reason to log.
+pub fn check(config: &FramingConfig, req: &HttpRequest) -> Result<(), &'static str> {
+    if config.strictness == Strictness::Off {
+        return Ok(());
+    }
+    let strict = config.strictness == Strictness::Strict;
+    let headers = req.headers();
+
+    let lengths: Vec<_> = headers.get_all(header::CONTENT_LENGTH).collect();
+    let encodings: Vec<_> = headers.get_all(header::TRANSFER_ENCODING).collect();
+    if !lengths.is_empty() && !encodings.is_empty() {
+        return Err("content_length_with_transfer_encoding");
+    }
+    if lengths.len() > 1 && (strict || lengths.windows(2).any(|w| w[0] != w[1])) {
+        return Err("conflicting_content_length");
+    }
+    if lengths
+        .iter()
+        .any(|v| v.is_empty() || !v.as_bytes().iter().all(u8::is_ascii_digit))
+    {
+        return Err("invalid_content_length");
+    }
+    if !encodings.is_empty() {
+        let codings: Vec<String> = encodings
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).to_ascii_lowercase())
+            .flat_map(|v| {
+                v.split(',')
+                    .map(|coding| coding.trim().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        // chunked has to be the final coding, and strictly the only one
+        let valid = match codings.as_slice() {
+            [only] => only == "chunked",
+            [.., last] => !strict && last == "chunked",
+            [] => false,
+        };
+        if !valid {
+            return Err("invalid_transfer_encoding");
+        }
+    }
+
+    if headers.len() > config.max_headers {
+        return Err("too_many_headers");
+    }
+    let mut total = 0;
+    for (name, value) in headers {
+        let size = name.as_str().len() + value.len();
+        if size > config.max_header_bytes {
+            return Err("header_too_large");
+        }
+        total += size;
+        if strict {
+            // Some servers treat X_Foo and X-Foo as the same header
+            if name.as_str().contains('_') {
+                return Err("invalid_header_name");
+            }
+            if !value
+                .as_bytes()
+                .iter()
+                .all(|b| *b == b'\t' || (b' '..=b'~').contains(b))
+            {
+                return Err("invalid_header_value");
+            }
+        }
+    }
+    if total > config.max_total_header_bytes {
+        return Err("headers_too_large");
+    }
+    if strict && headers.get_all(header::HOST).count() > 1 {
+        return Err("duplicate_host");
+    }
+    if config.duplicates == DuplicatePolicy::Reject
+        && config
+            .single_headers
+            .iter()
+            .any(|name| headers.get_all(name.as_str()).nth(1).is_some())
+    {
+        return Err("duplicate_header");
+    }
+    let cookies: usize = headers
+        .get_all(header::COOKIE)
+        .map(|v| v.as_bytes().split(|b| *b == b';').count())
+        .sum();
+    if config.max_cookies > 0 && cookies > config.max_cookies {
+        return Err("too_many_cookies");
+    }
+
+    Ok(())
+}
+
+// Collapse repeats of the single-valued headers to their first or last copy.
+// This runs before the handler, so authentication, the middlewares and the
+// upstream all see the same value.
+pub fn normalize(config: &FramingConfig, headers: &mut HeaderMap) {
+    let keep_last = match config.duplicates {
+        DuplicatePolicy::First => false,
+        DuplicatePolicy::Last => true,
+        DuplicatePolicy::Keep | DuplicatePolicy::Reject => return,
+    };
+    for name in &config.single_headers {
+        let name = match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if headers.get_all(&name).nth(1).is_none() {
+            continue;
+        }
+        let value = match keep_last {
+            true => headers.get_all(&name).last(),
+            false => headers.get(&name),
+        };
+        if let Some(value) = value.cloned() {
+            let action = if keep_last { "last" } else { "first" };
+            metrics::inc(
+                "duplicate_headers_total",
+                &[("header", name.as_str()), ("action", action)],
+            );
+            headers.insert(name, value);
+        }
+    }
+}
+
+// The request body is re-framed when it is forwarded, so the client's framing
+// headers must not go along with it. Neither does Expect: the 100 Continue
+// was already sent to the client, and the body is complete by the time the
+// upstream request starts, so there's nothing left to wait for.
+pub fn strip(headers: &mut HeaderMap) {
+    headers.remove(header::CONTENT_LENGTH);
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.remove(header::EXPECT);
+}
+
+// 100-continue is the only expectation HTTP defines; anything else gets a 417
+pub fn expectation_met(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(header::EXPECT)
+        .all(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
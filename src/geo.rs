@@ -0,0 +1,109 @@
+use crate::config::GeoConfig;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::net::IpAddr;
+
+// Country lookups from a range database, and countries grouped into regions
+pub struct Geo {
+    config: GeoConfig,
+    // First and last address of each network, sorted, with its country
+    v4: Vec<(u32, u32, String)>,
+    v6: Vec<(u128, u128, String)>,
+    // Country code -> region
+    countries: HashMap<String, String>,
+}
+
+fn find<T: Ord + Copy>(ranges: &[(T, T, String)], addr: T) -> Option<&str> {
+    let i = ranges.partition_point(|(first, _, _)| *first <= addr);
+    let (_, last, country) = ranges.get(i.checked_sub(1)?)?;
+    (addr <= *last).then_some(country.as_str())
+}
+
+impl Geo {
+    pub fn new(config: &GeoConfig) -> Result<Self, Error> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        if !config.database.is_empty() {
+            let raw = fs::read_to_string(&config.database)
+                .map_err(|e| Error::other(format!("GeoIP database {}: {}", config.database, e)))?;
+            for line in raw.lines() {
+                let mut fields = line.split(',').map(str::trim);
+                let (network, country) = match (fields.next(), fields.next()) {
+                    (Some(network), Some(country)) if !country.is_empty() => (network, country),
+                    _ => continue,
+                };
+                // Header rows and comments don't parse as networks
+                let network: IpNet = match network.parse() {
+                    Ok(network) => network,
+                    Err(_) => continue,
+                };
+                let country = country.trim_matches('"').to_ascii_uppercase();
+                match network {
+                    IpNet::V4(net) => {
+                        v4.push((net.network().into(), net.broadcast().into(), country))
+                    }
+                    IpNet::V6(net) => {
+                        v6.push((net.network().into(), net.broadcast().into(), country))
+                    }
+                }
+            }
+            v4.sort();
+            v6.sort();
+            println!(
+                "Loaded {} GeoIP networks from {}",
+                v4.len() + v6.len(),
+                config.database
+            );
+        }
+
+        let mut countries = HashMap::new();
+        for (region, codes) in &config.regions {
+            for code in codes {
+                let code = code.to_ascii_uppercase();
+                if let Some(other) = countries.insert(code.clone(), region.clone()) {
+                    return Err(Error::other(format!(
+                        "Country {} is in both the {} and {} regions",
+                        code, other, region
+                    )));
+                }
+            }
+        }
+
+        Ok(Geo {
+            config: config.clone(),
+            v4,
+            v6,
+            countries,
+        })
+    }
+
+    // The client's country, from the CDN's header or the database
+    pub fn country(&self, req: &HttpRequest, client_ip: &str) -> Option<String> {
+        if let Some(header) = &self.config.country_header {
+            let country = req
+                .headers()
+                .get(header.as_str())
+                .and_then(|v| v.to_str().ok());
+            if let Some(country) = country.filter(|c| !c.is_empty()) {
+                return Some(country.to_ascii_uppercase());
+            }
+        }
+        let country = match client_ip.parse::<IpAddr>().ok()? {
+            IpAddr::V4(addr) => find(&self.v4, u32::from(addr)),
+            IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+                Some(addr) => find(&self.v4, u32::from(addr)),
+                None => find(&self.v6, u128::from(addr)),
+            },
+        };
+        country.map(str::to_string)
+    }
+
+    pub fn region(&self, req: &HttpRequest, client_ip: &str) -> &str {
+        self.country(req, client_ip)
+            .and_then(|country| self.countries.get(&country))
+            .map_or(&self.config.default_region, |region| region)
+    }
+}
@@ -0,0 +1,499 @@
+use crate::config::GraphqlConfig;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+// Operations without a name are reported (and allowlisted) as this
+pub const ANONYMOUS: &str = "anonymous";
+
+// Selection sets and fragments nest at most this deep before we give up
+// parsing, whatever max_depth is, to keep recursion bounded
+const MAX_NESTING: usize = 128;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Name(String),
+    Punct(char),
+    Spread,
+    // Numbers and strings only ever appear in values, which we skip
+    Value,
+}
+
+enum Selection {
+    Field(Vec<Selection>),
+    Spread(String),
+    Inline(Vec<Selection>),
+}
+
+struct Operation {
+    name: Option<String>,
+    selections: Vec<Selection>,
+}
+
+struct Document {
+    operations: Vec<Operation>,
+    fragments: HashMap<String, Vec<Selection>>,
+}
+
+// Why a request was turned away, for the client, logs and metrics
+pub struct Rejection {
+    pub reason: &'static str,
+    pub operation: String,
+    pub message: String,
+}
+
+impl Rejection {
+    fn new(reason: &'static str, operation: &str, message: impl Into<String>) -> Self {
+        Rejection {
+            reason,
+            operation: operation.to_string(),
+            message: message.into(),
+        }
+    }
+
+    // Errors in the shape GraphQL clients expect
+    pub fn response(&self) -> HttpResponse {
+        let status = match self.reason {
+            "denied" => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        HttpResponse::build(status).json(json!({
+            "errors": [{ "message": self.message, "extensions": { "code": self.reason } }],
+        }))
+    }
+}
+
+fn lex(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // Commas are insignificant in GraphQL
+            c if c.is_whitespace() || c == ',' || c == '\u{feff}' => {}
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' || c == '\r' {
+                        break;
+                    }
+                }
+            }
+            '.' => {
+                if chars.next() != Some('.') || chars.next() != Some('.') {
+                    return Err("unexpected .".to_string());
+                }
+                tokens.push(Token::Spread);
+            }
+            '{' | '}' | '(' | ')' | '[' | ']' | ':' | '=' | '@' | '$' | '!' | '|' | '&' => {
+                tokens.push(Token::Punct(c))
+            }
+            '"' => {
+                // Block strings run to the next unescaped """
+                let block = if chars.peek() == Some(&'"') {
+                    chars.next();
+                    if chars.peek() != Some(&'"') {
+                        // Just an empty string
+                        tokens.push(Token::Value);
+                        continue;
+                    }
+                    chars.next();
+                    true
+                } else {
+                    false
+                };
+                let mut quotes = 0;
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".to_string()),
+                        Some('\\') => {
+                            chars.next();
+                            quotes = 0;
+                        }
+                        Some('"') if !block => break,
+                        Some('"') => {
+                            quotes += 1;
+                            if quotes == 3 {
+                                break;
+                            }
+                        }
+                        Some('\n') if !block => return Err("unterminated string".to_string()),
+                        Some(_) => quotes = 0,
+                    }
+                }
+                tokens.push(Token::Value);
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                while chars
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-'))
+                {
+                    chars.next();
+                }
+                tokens.push(Token::Value);
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = c.to_string();
+                while let Some(&c) = chars
+                    .peek()
+                    .filter(|c| **c == '_' || c.is_ascii_alphanumeric())
+                {
+                    name.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Name(name));
+            }
+            c => return Err(format!("unexpected character {:?}", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+// Just enough of a parser to find operations, fragments and the shape of
+// their selections; arguments, variables and directives are skipped over
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    nesting: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(format!("expected {}", c))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name.clone()),
+            _ => Err("expected a name".to_string()),
+        }
+    }
+
+    // Skip a bracketed group such as arguments or variable definitions
+    fn skip_group(&mut self, open: char, close: char) -> Result<(), String> {
+        if !self.eat(open) {
+            return Ok(());
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next() {
+                None => return Err(format!("expected {}", close)),
+                Some(Token::Punct(c)) if *c == open => depth += 1,
+                Some(Token::Punct(c)) if *c == close => depth -= 1,
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_directives(&mut self) -> Result<(), String> {
+        while self.eat('@') {
+            self.name()?;
+            self.skip_group('(', ')')?;
+        }
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.expect('{')?;
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err("query is nested too deeply".to_string());
+        }
+        let mut selections = Vec::new();
+        while !self.eat('}') {
+            if self.peek() == Some(&Token::Spread) {
+                self.pos += 1;
+                match self.peek() {
+                    Some(Token::Name(name)) if name != "on" => {
+                        let name = self.name()?;
+                        self.skip_directives()?;
+                        selections.push(Selection::Spread(name));
+                    }
+                    _ => {
+                        if self.peek() == Some(&Token::Name("on".to_string())) {
+                            self.pos += 1;
+                            self.name()?;
+                        }
+                        self.skip_directives()?;
+                        selections.push(Selection::Inline(self.selection_set()?));
+                    }
+                }
+                continue;
+            }
+
+            self.name()?;
+            // An alias
+            if self.eat(':') {
+                self.name()?;
+            }
+            self.skip_group('(', ')')?;
+            self.skip_directives()?;
+            let children = if self.peek() == Some(&Token::Punct('{')) {
+                self.selection_set()?
+            } else {
+                Vec::new()
+            };
+            selections.push(Selection::Field(children));
+        }
+        if selections.is_empty() {
+            return Err("empty selection set".to_string());
+        }
+        self.nesting -= 1;
+        Ok(selections)
+    }
+
+    fn document(mut self) -> Result<Document, String> {
+        let mut document = Document {
+            operations: Vec::new(),
+            fragments: HashMap::new(),
+        };
+        while let Some(token) = self.peek() {
+            match token {
+                // Query shorthand
+                Token::Punct('{') => document.operations.push(Operation {
+                    name: None,
+                    selections: self.selection_set()?,
+                }),
+                Token::Name(keyword) if keyword == "fragment" => {
+                    self.pos += 1;
+                    let name = self.name()?;
+                    if self.name()? != "on" {
+                        return Err("expected on".to_string());
+                    }
+                    self.name()?;
+                    self.skip_directives()?;
+                    let selections = self.selection_set()?;
+                    if document
+                        .fragments
+                        .insert(name.clone(), selections)
+                        .is_some()
+                    {
+                        return Err(format!("fragment {} is defined twice", name));
+                    }
+                }
+                Token::Name(keyword)
+                    if matches!(keyword.as_str(), "query" | "mutation" | "subscription") =>
+                {
+                    self.pos += 1;
+                    let name = match self.peek() {
+                        Some(Token::Name(_)) => Some(self.name()?),
+                        _ => None,
+                    };
+                    self.skip_group('(', ')')?;
+                    self.skip_directives()?;
+                    document.operations.push(Operation {
+                        name,
+                        selections: self.selection_set()?,
+                    });
+                }
+                _ => return Err("expected an operation or fragment".to_string()),
+            }
+        }
+        Ok(document)
+    }
+}
+
+impl Document {
+    // Depth and number of fields of a selection set, following fragment
+    // spreads. Fragment costs are remembered so fragments spreading others
+    // several times can't make this exponential; cycles are an error.
+    fn cost<'a>(
+        &'a self,
+        selections: &'a [Selection],
+        visiting: &mut HashSet<&'a str>,
+        known: &mut HashMap<&'a str, (usize, usize)>,
+    ) -> Result<(usize, usize), String> {
+        let (mut depth, mut fields) = (0, 0usize);
+        for selection in selections {
+            let (d, f) = match selection {
+                Selection::Field(children) => {
+                    let (d, f) = self.cost(children, visiting, known)?;
+                    (d + 1, f.saturating_add(1))
+                }
+                Selection::Inline(children) => self.cost(children, visiting, known)?,
+                Selection::Spread(name) => match known.get(name.as_str()) {
+                    Some(cost) => *cost,
+                    None => {
+                        let fragment = self
+                            .fragments
+                            .get(name)
+                            .ok_or_else(|| format!("unknown fragment {}", name))?;
+                        if !visiting.insert(name) {
+                            return Err(format!("fragment {} spreads itself", name));
+                        }
+                        if visiting.len() > MAX_NESTING {
+                            return Err("fragments are nested too deeply".to_string());
+                        }
+                        let cost = self.cost(fragment, visiting, known)?;
+                        visiting.remove(name.as_str());
+                        known.insert(name, cost);
+                        cost
+                    }
+                },
+            };
+            depth = depth.max(d);
+            fields = fields.saturating_add(f);
+        }
+        Ok((depth, fields))
+    }
+}
+
+// The query and operation name of each operation in the request: GET query
+// parameters, an application/graphql body, or a JSON body that may be a batch
+fn requests(req: &HttpRequest, body: &[u8]) -> Result<Vec<(String, Option<String>)>, String> {
+    if req.method() == Method::GET {
+        let params = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .map_err(|_| "malformed query string".to_string())?
+            .into_inner();
+        let query = params.get("query").ok_or("missing query")?;
+        return Ok(vec![(query.clone(), params.get("operationName").cloned())]);
+    }
+
+    let media = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    if media == "application/graphql" {
+        let query = std::str::from_utf8(body).map_err(|_| "query isn't UTF-8".to_string())?;
+        return Ok(vec![(query.to_string(), None)]);
+    }
+
+    let body: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let entries = match body {
+        Value::Array(entries) => entries,
+        single => vec![single],
+    };
+    entries
+        .iter()
+        .map(|entry| {
+            let query = entry
+                .get("query")
+                .and_then(Value::as_str)
+                .ok_or("missing query")?;
+            let name = entry
+                .get("operationName")
+                .and_then(Value::as_str)
+                .map(str::to_string);
+            Ok((query.to_string(), name))
+        })
+        .collect()
+}
+
+// Check one query document and return the name of the operation it runs
+fn check_one(
+    config: &GraphqlConfig,
+    query: &str,
+    requested: Option<&str>,
+) -> Result<String, Rejection> {
+    let parse_error = |e: String| Rejection::new("parse", requested.unwrap_or(ANONYMOUS), e);
+    let tokens = lex(query).map_err(parse_error)?;
+    let document = Parser {
+        tokens,
+        pos: 0,
+        nesting: 0,
+    }
+    .document()
+    .map_err(parse_error)?;
+
+    let operation = match requested {
+        Some(name) => document
+            .operations
+            .iter()
+            .find(|op| op.name.as_deref() == Some(name))
+            .ok_or_else(|| parse_error(format!("operation {} isn't in the query", name)))?,
+        None if document.operations.len() == 1 => &document.operations[0],
+        None => {
+            return Err(parse_error(
+                "operationName is required with several operations".to_string(),
+            ))
+        }
+    };
+    let name = operation.name.as_deref().unwrap_or(ANONYMOUS);
+
+    let listed = |list: &[String]| list.iter().any(|n| n == name);
+    if listed(&config.deny) || !config.allow.is_empty() && !listed(&config.allow) {
+        return Err(Rejection::new(
+            "denied",
+            name,
+            format!("operation {} isn't allowed", name),
+        ));
+    }
+
+    let (depth, complexity) = document
+        .cost(
+            &operation.selections,
+            &mut HashSet::new(),
+            &mut HashMap::new(),
+        )
+        .map_err(parse_error)?;
+    if config.max_depth > 0 && depth > config.max_depth {
+        return Err(Rejection::new(
+            "depth",
+            name,
+            format!(
+                "query depth {} exceeds the limit of {}",
+                depth, config.max_depth
+            ),
+        ));
+    }
+    if config.max_complexity > 0 && complexity > config.max_complexity {
+        return Err(Rejection::new(
+            "complexity",
+            name,
+            format!(
+                "query selects {} fields, more than the limit of {}",
+                complexity, config.max_complexity
+            ),
+        ));
+    }
+    Ok(name.to_string())
+}
+
+// The operation names of an allowed request, in batch order
+pub fn check(
+    config: &GraphqlConfig,
+    req: &HttpRequest,
+    body: &[u8],
+) -> Result<Vec<String>, Rejection> {
+    let requests = requests(req, body).map_err(|e| Rejection::new("parse", ANONYMOUS, e))?;
+    if config.max_batch > 0 && requests.len() > config.max_batch {
+        return Err(Rejection::new(
+            "batch",
+            ANONYMOUS,
+            format!(
+                "batch of {} operations exceeds the limit of {}",
+                requests.len(),
+                config.max_batch
+            ),
+        ));
+    }
+    requests
+        .iter()
+        .map(|(query, name)| check_one(config, query, name.as_deref()))
+        .collect()
+}
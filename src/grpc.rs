@@ -0,0 +1,465 @@
+use crate::access_log::REQUEST_ID_HEADER;
+use crate::bind;
+use crate::config::{CorsConfig, RouteConfig};
+use crate::cors;
+use crate::egress_policy;
+use crate::error::ProxyError;
+use crate::handoff;
+use crate::metrics;
+use crate::proxy::{cut_off, AppState};
+use crate::routes;
+use crate::systemd::InheritedSockets;
+use crate::trailers;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::TestRequest;
+use actix_web::{web, HttpResponse, HttpResponseBuilder};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use std::convert::Infallible;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub type Client = hyper::Client<HttpConnector, Body>;
+
+// gRPC status codes we answer with ourselves
+const UNAVAILABLE: u16 = 14;
+const DEADLINE_EXCEEDED: u16 = 4;
+const UNIMPLEMENTED: u16 = 12;
+const PERMISSION_DENIED: u16 = 7;
+const INVALID_ARGUMENT: u16 = 3;
+const RESOURCE_EXHAUSTED: u16 = 8;
+
+// Not forwarded in either direction: hop-by-hop headers, and the ones we set
+// ourselves for the upstream leg
+const SKIPPED: [&str; 9] = [
+    "host",
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+    "content-length",
+    "content-type",
+    "x-grpc-web",
+];
+
+// gRPC needs trailers, which reqwest can't read and actix can't send, so the
+// upstream leg uses hyper with HTTP/2 prior knowledge (h2c). So do trailers
+// routes, see trailers.rs.
+pub fn client() -> Client {
+    hyper::Client::builder().http2_only(true).build_http()
+}
+
+// application/grpc-web+proto, application/grpc-web-text, ...
+pub fn is_grpc_web(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc-web"))
+}
+
+fn forwarded_headers<'a>(
+    headers: impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)>,
+) -> impl Iterator<Item = (&'a HeaderName, &'a HeaderValue)> {
+    headers.filter(|(name, _)| !SKIPPED.contains(&name.as_str()))
+}
+
+// Send a gRPC request to one of the route's upstream replicas
+pub async fn send(
+    state: &AppState,
+    route: &str,
+    upstream: &str,
+    request: hyper::http::request::Builder,
+    path: &str,
+    body: Body,
+) -> Result<hyper::Response<Body>, (u16, String)> {
+    let target = state.upstreams.pick(upstream, &[]);
+    // The h2c client resolves on its own, so the addresses are checked here
+    if let Some(policy) = &state.egress {
+        if let Err(e) = policy.check_resolved(&target).await {
+            egress_policy::refused(route, &target, &e);
+            return Err((PERMISSION_DENIED, "upstream not allowed".to_string()));
+        }
+    }
+    let request = request
+        .uri(format!("{}{}", target, path))
+        .body(body)
+        .map_err(|e| (INVALID_ARGUMENT, e.to_string()))?;
+
+    let started = Instant::now();
+    let timeout = Duration::from_secs(state.config.server.upstream_timeout_secs);
+    let outstanding = state.upstreams.begin(upstream, &target);
+    let result = tokio::time::timeout(timeout, state.grpc.request(request)).await;
+    drop(outstanding);
+    let latency = started.elapsed();
+    state.upstreams.report(
+        upstream,
+        &target,
+        matches!(result, Ok(Ok(_))),
+        Some(latency),
+    );
+    match result {
+        Ok(Ok(response)) => {
+            state.latency.record(route, latency);
+            Ok(response)
+        }
+        Ok(Err(e)) => {
+            eprintln!("HTTP/2 upstream {} failed: {}", target, e);
+            Err((UNAVAILABLE, "upstream unavailable".to_string()))
+        }
+        Err(_) => Err((DEADLINE_EXCEEDED, "upstream timed out".to_string())),
+    }
+}
+
+// Browsers only let gRPC-Web clients read the status headers if told so
+const EXPOSED: &str = "grpc-status, grpc-message";
+
+fn web_response(cors: &CorsConfig, status: StatusCode, content_type: &str) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    cors::apply(cors, &mut response);
+    response
+        .insert_header(("Access-Control-Expose-Headers", EXPOSED))
+        .content_type(content_type);
+    response
+}
+
+// A gRPC-Web error: status and message go in the headers of an empty 200
+fn web_error(cors: &CorsConfig, content_type: &str, code: u16, message: &str) -> HttpResponse {
+    web_response(cors, StatusCode::OK, content_type)
+        .insert_header(("grpc-status", code.to_string()))
+        .insert_header(("grpc-message", message))
+        .finish()
+}
+
+// For a gRPC-Web request that ran out of time before the upstream answered
+pub fn deadline_exceeded(headers: &HeaderMap, cors: &CorsConfig) -> HttpResponse {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/grpc-web");
+    web_error(cors, content_type, DEADLINE_EXCEEDED, "deadline exceeded")
+}
+
+// gRPC-Web sends the trailers as a final length-prefixed message with the
+// high bit of the flags byte set
+fn trailer_frame(trailers: &hyper::HeaderMap) -> web::Bytes {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.extend_from_slice(b": ");
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut frame = vec![0x80];
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&block);
+    frame.into()
+}
+
+// Translate a browser's gRPC-Web request into native gRPC, and the response
+// back, trailers included. The -text variants are base64 in both directions.
+pub async fn forward_web(
+    state: &AppState,
+    route: &RouteConfig,
+    upstream: &str,
+    path: &str,
+    headers: &HeaderMap,
+    cors: &CorsConfig,
+    body: web::Bytes,
+) -> HttpResponse {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/grpc-web")
+        .to_string();
+    let text = content_type.starts_with("application/grpc-web-text");
+    // The message format, e.g. +proto, carries over to the upstream
+    let format = content_type
+        .trim_start_matches("application/grpc-web-text")
+        .trim_start_matches("application/grpc-web");
+    metrics::inc(
+        "grpc_requests_total",
+        &[("route", &route.name), ("protocol", "grpc-web")],
+    );
+
+    let body = if text {
+        let encoded: Vec<u8> = body
+            .iter()
+            .copied()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        match STANDARD.decode(encoded) {
+            Ok(body) => body.into(),
+            Err(_) => {
+                return web_error(cors, &content_type, INVALID_ARGUMENT, "invalid base64 body")
+            }
+        }
+    } else {
+        body
+    };
+
+    let mut request = hyper::Request::builder()
+        .method(Method::POST)
+        .header(header::CONTENT_TYPE, format!("application/grpc{}", format))
+        .header(header::TE, "trailers");
+    for (name, value) in forwarded_headers(headers.iter()) {
+        request = request.header(name, value);
+    }
+    let response = match send(state, &route.name, upstream, request, path, body.into()).await {
+        Ok(response) => response,
+        Err((code, message)) => return web_error(cors, &content_type, code, &message),
+    };
+
+    let (parts, body) = response.into_parts();
+    let mut builder = web_response(cors, parts.status, &content_type);
+    let forwarded = forwarded_headers(parts.headers.iter());
+    for (name, value) in forwarded.filter(|(name, _)| !cors::is_cors(name.as_str())) {
+        builder.append_header((name.clone(), value.clone()));
+    }
+    let theirs = parts
+        .headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_bytes()));
+    for (name, value) in cors::reconcile(cors.reconcile, &cors::headers(cors), theirs) {
+        let value = match name.as_str() {
+            // The status headers stay exposed whatever the upstream exposes
+            "access-control-expose-headers" => cors::combine(&name, &[EXPOSED, &value]),
+            _ => value,
+        };
+        builder.insert_header((name, value));
+    }
+
+    // Messages as they arrive, then the trailers. Past the route's limit the
+    // messages stop and the client gets RESOURCE_EXHAUSTED trailers instead.
+    let encode = move |bytes: web::Bytes| match text {
+        true => web::Bytes::from(STANDARD.encode(bytes)),
+        false => bytes,
+    };
+    let limit = route
+        .response_limit(&state.config.server)
+        .unwrap_or(usize::MAX);
+    let name = route.name.clone();
+    let stream = futures_util::stream::unfold(Some((body, 0)), move |body| {
+        let name = name.clone();
+        async move {
+            let (mut body, sent) = body?;
+            match body.data().await {
+                Some(Ok(chunk)) if sent + chunk.len() > limit => {
+                    cut_off(&name, limit);
+                    let trailers = limit_trailers();
+                    Some((Ok(encode(trailer_frame(&trailers))), None))
+                }
+                Some(Ok(chunk)) => {
+                    let sent = sent + chunk.len();
+                    Some((Ok(encode(chunk)), Some((body, sent))))
+                }
+                Some(Err(e)) => Some((Err(e), None)),
+                None => match body.trailers().await {
+                    Ok(Some(trailers)) => Some((Ok(encode(trailer_frame(&trailers))), None)),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(e), None)),
+                },
+            }
+        }
+    });
+    builder.streaming(stream)
+}
+
+fn limit_trailers() -> hyper::HeaderMap {
+    let mut trailers = hyper::HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(RESOURCE_EXHAUSTED));
+    trailers.insert(
+        "grpc-message",
+        HeaderValue::from_static("upstream response too large"),
+    );
+    trailers
+}
+
+// Relay a response body, trailers included, until more than `limit` bytes
+// have gone through, then end it with RESOURCE_EXHAUSTED trailers
+fn limit_body(route: String, mut body: Body, limit: usize) -> Body {
+    let (mut sender, limited) = Body::channel();
+    tokio::spawn(async move {
+        let mut sent = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            sent += chunk.len();
+            if sent > limit {
+                cut_off(&route, limit);
+                let _ = sender.send_trailers(limit_trailers()).await;
+                return;
+            }
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    limited
+}
+
+// A "trailers-only" gRPC response, used for errors before reaching the upstream
+fn native_error(code: u16, message: &str) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert("grpc-status", HeaderValue::from(code));
+    if let Ok(message) = HeaderValue::from_str(message) {
+        headers.insert("grpc-message", message);
+    }
+    response
+}
+
+// Pass a native gRPC call through to the route's upstream. Bodies stream in
+// both directions and trailers are preserved, so all four call types work.
+async fn native(
+    state: web::Data<AppState>,
+    peer: SocketAddr,
+    req: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |p| p.as_str());
+
+    // Route the call the way the HTTP listeners would
+    let table = state.routes.snapshot();
+    let found = {
+        let mut test = TestRequest::default()
+            .method(parts.method.clone())
+            .uri(path)
+            .peer_addr(peer);
+        for (name, value) in &parts.headers {
+            test = test.append_header((name.clone(), value.clone()));
+        }
+        routes::route_for(&table, &test.to_http_request())
+    };
+    let (route, upstream_path) = match found {
+        Some((route, upstream_path)) if route.grpc => (route, upstream_path),
+        // The App decides everything else about it, as for the HTTP listeners
+        Some((route, _)) if route.trailers => {
+            return Ok(trailers::relay(peer, parts, body).await);
+        }
+        _ => return Ok(native_error(UNIMPLEMENTED, "no gRPC route for this method")),
+    };
+    metrics::inc(
+        "grpc_requests_total",
+        &[("route", &route.name), ("protocol", "grpc")],
+    );
+
+    // The middleware chain needs the whole body, which would break streaming
+    if route.jwt || route.auth.is_some() || !route.middlewares.is_empty() {
+        println!(
+            "Refusing native gRPC call to route {} with middlewares, use gRPC-Web",
+            route.name
+        );
+        return Ok(native_error(
+            PERMISSION_DENIED,
+            "route isn't available over native gRPC",
+        ));
+    }
+
+    let mut request = hyper::Request::builder()
+        .method(parts.method)
+        .header(
+            header::CONTENT_TYPE,
+            parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .cloned()
+                .unwrap_or(HeaderValue::from_static("application/grpc")),
+        )
+        .header(header::TE, "trailers")
+        .header("x-forwarded-for", peer.ip().to_string());
+    for (name, value) in forwarded_headers(parts.headers.iter()) {
+        request = request.header(name, value);
+    }
+    if !parts.headers.contains_key(REQUEST_ID_HEADER) {
+        request = request.header(REQUEST_ID_HEADER, format!("{:016x}", rand::random::<u64>()));
+    }
+
+    match send(
+        state.get_ref(),
+        &route.name,
+        &route.upstream,
+        request,
+        &upstream_path,
+        body,
+    )
+    .await
+    {
+        Ok(response) => match route.response_limit(&state.config.server) {
+            Some(limit) => Ok(response.map(|body| limit_body(route.name.clone(), body, limit))),
+            None => Ok(response),
+        },
+        Err((code, message)) => Ok(native_error(code, &message)),
+    }
+}
+
+// send()'s errors, for requests that went through the proxy pipeline. A
+// refusal by the egress policy has been logged and counted already.
+pub fn proxy_error((code, message): (u16, String)) -> ProxyError {
+    match code {
+        DEADLINE_EXCEEDED => ProxyError::Timeout,
+        UNAVAILABLE => ProxyError::Connect(message),
+        _ => ProxyError::Other(message),
+    }
+}
+
+// Start the [grpc] listener, which only accepts HTTP/2 without TLS
+pub fn spawn(
+    state: web::Data<AppState>,
+    inherited: &mut InheritedSockets,
+    handed: &mut handoff::Listeners,
+) -> Result<(), Error> {
+    let Some(grpc) = &state.config.grpc else {
+        return Ok(());
+    };
+    let addresses = match grpc.bind_addresses.is_empty() {
+        true => &state.config.server.bind_addresses,
+        false => &grpc.bind_addresses,
+    };
+    let mut listeners = inherited.take("grpc", None);
+    if listeners.is_empty() {
+        listeners = bind::tcp(addresses, grpc.port)?;
+    }
+    handed.keep("grpc", &listeners)?;
+    println!("gRPC listener running on port: {}", grpc.port);
+    trailers::spawn(state.clone());
+
+    for listener in listeners {
+        listener.set_nonblocking(true)?;
+        let state = state.clone();
+        let service = make_service_fn(move |conn: &AddrStream| {
+            let state = state.clone();
+            let peer = conn.remote_addr();
+            async move { Ok::<_, Infallible>(service_fn(move |req| native(state.clone(), peer, req))) }
+        });
+        let server = hyper::Server::from_tcp(listener)
+            .map_err(Error::other)?
+            .http2_only(true)
+            .serve(service);
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                eprintln!("gRPC listener failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
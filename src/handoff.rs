@@ -0,0 +1,316 @@
+use crate::config::HandoffConfig;
+use crate::systemd::InheritedSockets;
+use std::io::{Error, Write};
+use std::net::TcpListener;
+
+// Zero-downtime upgrades without a scheduler ([handoff]). The running
+// process keeps a Unix control socket. A new one started with --takeover,
+// by hand or by sending the running one SIGUSR2 (which starts [handoff]
+// binary with the same arguments), connects to it and is passed the
+// listening sockets themselves (SCM_RIGHTS), so no connection is refused
+// while they change hands. Once the new process is serving it says so, and
+// the old one stops accepting, finishes the requests it has and exits. If
+// the new process fails before then, the old one carries on as it was.
+//
+// On the control socket the new process sends "HANDOFF <pid>\n", the old
+// one answers with the socket names, colon separated, and the sockets
+// attached, and the new process sends "READY\n" once it's serving.
+
+// Most sockets one handoff carries
+#[cfg(unix)]
+const MAX_SOCKETS: usize = 64;
+
+// Copies of this process's listening sockets, by name, to hand over
+#[derive(Default)]
+pub struct Listeners(Vec<(&'static str, TcpListener)>);
+
+impl Listeners {
+    pub fn keep(&mut self, name: &'static str, listeners: &[TcpListener]) -> Result<(), Error> {
+        for listener in listeners {
+            self.0.push((name, listener.try_clone()?));
+        }
+        Ok(())
+    }
+}
+
+// The connection to the process being taken over, until we're serving
+pub struct Takeover {
+    #[cfg(unix)]
+    stream: std::os::unix::net::UnixStream,
+}
+
+impl Takeover {
+    // Let the old process go; Err means it already gave up on us
+    pub fn ready(self) -> Result<(), Error> {
+        #[cfg(unix)]
+        (&self.stream).write_all(b"READY\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod fds {
+    use super::MAX_SOCKETS;
+    use std::io::Error;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use std::ptr;
+
+    const FD: usize = mem::size_of::<RawFd>();
+
+    pub fn send(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> Result<(), Error> {
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE((fds.len() * FD) as u32) } as usize;
+        let mut control = vec![0u8; space];
+        // SAFETY: msghdr is plain data, and the control buffer has room for
+        // the one header and its descriptors
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = space as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN((fds.len() * FD) as u32) as _;
+            let target = libc::CMSG_DATA(cmsg);
+            ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), target, fds.len() * FD);
+            if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    // The message and the descriptors that came with it, which are ours to
+    // close from here on
+    pub fn recv(stream: &UnixStream) -> Result<(Vec<u8>, Vec<RawFd>), Error> {
+        let mut data = vec![0u8; 4096];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let space = unsafe { libc::CMSG_SPACE((MAX_SOCKETS * FD) as u32) } as usize;
+        let mut control = vec![0u8; space];
+        let mut fds = Vec::new();
+        // SAFETY: as in send; the kernel fills in no more than it was given
+        // room for
+        let read = unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = space as _;
+            let read = libc::recvmsg(stream.as_raw_fd(), &mut msg, 0);
+            if read < 0 {
+                return Err(Error::last_os_error());
+            }
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let len = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / FD;
+                    let first = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                    for i in 0..len {
+                        let fd = ptr::read_unaligned(first.add(i));
+                        // Not to be passed on to anything we start
+                        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                        fds.push(fd);
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+                for fd in &fds {
+                    libc::close(*fd);
+                }
+                return Err(Error::other("more sockets than one handoff carries"));
+            }
+            read as usize
+        };
+        data.truncate(read);
+        Ok((data, fds))
+    }
+}
+
+// Connect to the running process and take its listening sockets
+#[cfg(unix)]
+pub fn take_over(config: &HandoffConfig) -> Result<(InheritedSockets, Takeover), Error> {
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let stream = UnixStream::connect(&config.control_socket).map_err(|e| {
+        Error::other(format!(
+            "Nothing to take over at {}: {}",
+            config.control_socket, e
+        ))
+    })?;
+    (&stream).write_all(format!("HANDOFF {}\n", std::process::id()).as_bytes())?;
+    let (names, fds) = fds::recv(&stream)?;
+    // SAFETY: the descriptors were just passed to us and nothing else owns them
+    let listeners: Vec<TcpListener> = fds
+        .iter()
+        .map(|fd| unsafe { TcpListener::from_raw_fd(*fd) })
+        .collect();
+    let names = String::from_utf8_lossy(&names);
+    let names: Vec<&str> = names.trim().split(':').filter(|n| !n.is_empty()).collect();
+    if names.len() != listeners.len() {
+        return Err(Error::other(format!(
+            "The running process sent {} sockets for {} names",
+            listeners.len(),
+            names.len()
+        )));
+    }
+    let mut sockets = Vec::new();
+    for (name, listener) in names.into_iter().zip(listeners) {
+        listener.set_nonblocking(true)?;
+        sockets.push((name.to_string(), listener));
+    }
+    println!(
+        "Took over {} socket(s) from the process at {}",
+        sockets.len(),
+        config.control_socket
+    );
+    Ok((InheritedSockets::handed_over(sockets), Takeover { stream }))
+}
+
+#[cfg(not(unix))]
+pub fn take_over(_config: &HandoffConfig) -> Result<(InheritedSockets, Takeover), Error> {
+    Err(Error::other("--takeover is only supported on Unix"))
+}
+
+// One process asking for our sockets; its pid once it's serving with them
+#[cfg(unix)]
+fn hand_over(
+    listeners: &Listeners,
+    stream: std::os::unix::net::UnixStream,
+    timeout: std::time::Duration,
+) -> Result<u32, Error> {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::io::AsRawFd;
+
+    stream.set_read_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let pid: u32 = (line.trim().strip_prefix("HANDOFF "))
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(|| Error::other(format!("unexpected request {:?}", line.trim())))?;
+    println!(
+        "Handing {} socket(s) over to pid {}",
+        listeners.0.len(),
+        pid
+    );
+    let names: Vec<&str> = listeners.0.iter().map(|(name, _)| *name).collect();
+    let raw: Vec<i32> = (listeners.0.iter())
+        .map(|(_, listener)| listener.as_raw_fd())
+        .collect();
+    fds::send(&stream, names.join(":").as_bytes(), &raw)?;
+
+    line.clear();
+    match reader.read_line(&mut line) {
+        Ok(_) if line.trim() == "READY" => Ok(pid),
+        Ok(_) => Err(Error::other(format!(
+            "pid {} went away before it was serving",
+            pid
+        ))),
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err(Error::other(format!(
+                "pid {} wasn't serving after {:?}",
+                pid, timeout
+            )))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Wait for a process to take over, on a thread of its own. handed_over gets
+// its pid once it has.
+#[cfg(unix)]
+pub fn serve(
+    config: &HandoffConfig,
+    listeners: Listeners,
+    handed_over: impl FnOnce(u32) + Send + 'static,
+) -> Result<(), Error> {
+    use std::os::unix::net::UnixListener;
+
+    // Left behind by a process that has exited, or that we took over from
+    let _ = std::fs::remove_file(&config.control_socket);
+    let control = UnixListener::bind(&config.control_socket).map_err(|e| {
+        Error::other(format!(
+            "Failed to bind the handoff socket {}: {}",
+            config.control_socket, e
+        ))
+    })?;
+    let timeout = std::time::Duration::from_secs(config.ready_timeout_secs);
+    std::thread::spawn(move || {
+        for stream in control.incoming() {
+            let result = stream.and_then(|stream| hand_over(&listeners, stream, timeout));
+            match result {
+                Ok(pid) => {
+                    println!("Handed over to pid {}; draining", pid);
+                    return handed_over(pid);
+                }
+                Err(e) => eprintln!("Handoff failed, still serving: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(
+    _config: &HandoffConfig,
+    _listeners: Listeners,
+    _handed_over: impl FnOnce(u32) + Send + 'static,
+) -> Result<(), Error> {
+    Ok(())
+}
+
+// SIGUSR2 starts the new binary to take over from us
+#[cfg(unix)]
+pub fn upgrade_on_sigusr2(config: &HandoffConfig, args: &[String]) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr2 = match signal(SignalKind::user_defined2()) {
+        Ok(usr2) => usr2,
+        Err(e) => {
+            eprintln!("Failed to install SIGUSR2 handler: {}", e);
+            return;
+        }
+    };
+    let binary = match config.binary.is_empty() {
+        true => std::env::args().next().unwrap_or_default(),
+        false => config.binary.clone(),
+    };
+    // Already detached, and the pid file is passed on once it's serving
+    let args: Vec<String> = (args.iter())
+        .filter(|arg| *arg != "--daemon" && *arg != "--takeover")
+        .cloned()
+        .chain(std::iter::once("--takeover".to_string()))
+        .collect();
+    tokio::spawn(async move {
+        while usr2.recv().await.is_some() {
+            println!("Starting {} to take over", binary);
+            match tokio::process::Command::new(&binary).args(&args).spawn() {
+                Ok(mut child) => {
+                    tokio::spawn(async move {
+                        if let Ok(status) = child.wait().await {
+                            eprintln!("The process started to take over exited ({})", status);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Failed to start {}: {}", binary, e),
+            }
+        }
+    });
+}
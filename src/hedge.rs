@@ -0,0 +1,44 @@
+use crate::config::HedgeConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SAMPLES: usize = 500;
+const MIN_SAMPLES: usize = 20;
+
+// Recent upstream latencies per route, used to derive the hedging delay
+#[derive(Default)]
+pub struct LatencyTracker {
+    routes: Mutex<HashMap<String, VecDeque<u64>>>,
+}
+
+impl LatencyTracker {
+    pub fn record(&self, route: &str, latency: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let samples = routes.entry(route.to_string()).or_default();
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency.as_millis() as u64);
+    }
+
+    fn p95(&self, route: &str) -> Option<u64> {
+        let routes = self.routes.lock().unwrap();
+        let samples = routes.get(route)?;
+        if samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let mut sorted: Vec<u64> = samples.iter().copied().collect();
+        sorted.sort_unstable();
+        Some(sorted[(sorted.len() * 95 / 100).min(sorted.len() - 1)])
+    }
+
+    // How long to wait for the first response before firing a hedge
+    pub fn hedge_delay(&self, route: &str, config: &HedgeConfig) -> Duration {
+        let ms = config
+            .delay_ms
+            .or_else(|| self.p95(route))
+            .unwrap_or(config.fallback_delay_ms);
+        Duration::from_millis(ms)
+    }
+}
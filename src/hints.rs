@@ -0,0 +1,45 @@
+use crate::config::{Config, RouteConfig};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::HttpResponse;
+
+// Add the route's preload/preconnect Link headers to successful HTML pages so
+// browsers can start fetching critical assets early
+pub fn apply(route: &RouteConfig, response: &mut HttpResponse) {
+    if route.preload_links.is_empty() || !response.status().is_success() {
+        return;
+    }
+    let html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    if !html {
+        return;
+    }
+
+    for link in &route.preload_links {
+        if let Ok(value) = HeaderValue::from_str(link) {
+            response.headers_mut().append(header::LINK, value);
+        }
+    }
+}
+
+// The Alt-Svc header for every public response, e.g. h3=":443"; ma=86400
+pub fn alt_svc(config: &Config) -> Option<HeaderValue> {
+    let alt_svc = config.alt_svc.as_ref()?;
+    if alt_svc.clear {
+        return Some(HeaderValue::from_static("clear"));
+    }
+
+    let port = alt_svc
+        .port
+        .or(config.http3.as_ref().and_then(|http3| http3.port))
+        .unwrap_or(config.server.port);
+    let value = alt_svc
+        .protocols
+        .iter()
+        .map(|protocol| format!("{}=\":{}\"; ma={}", protocol, port, alt_svc.max_age_secs))
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&value).ok()
+}
@@ -0,0 +1,149 @@
+use crate::audit;
+use crate::config::{AdminConfig, RouteConfig};
+use crate::diagnostics;
+use crate::keys;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// One effective route table, as saved in {history_path}/{version}.json,
+// secret header values redacted
+#[derive(Serialize, Deserialize)]
+pub struct Version {
+    pub version: u64,
+    pub at: u64,
+    pub routes: Vec<RouteConfig>,
+}
+
+// The last history_versions route tables, so a bad edit can be undone
+pub struct History {
+    dir: PathBuf,
+    keep: usize,
+    // The latest version number
+    latest: Mutex<u64>,
+}
+
+impl History {
+    pub fn new(config: &AdminConfig) -> Result<Option<Self>, Error> {
+        if config.history_path.is_empty() || config.history_versions == 0 {
+            return Ok(None);
+        }
+        let dir = PathBuf::from(&config.history_path);
+        fs::create_dir_all(&dir)
+            .map_err(|e| Error::other(format!("Config history {}: {}", dir.display(), e)))?;
+        let history = History {
+            dir,
+            keep: config.history_versions,
+            latest: Mutex::new(0),
+        };
+        *history.latest.lock().unwrap() = history.versions()?.last().copied().unwrap_or(0);
+        Ok(Some(history))
+    }
+
+    fn file(&self, version: u64) -> PathBuf {
+        self.dir.join(format!("{:08}.json", version))
+    }
+
+    // Saved version numbers, oldest first
+    fn versions(&self) -> Result<Vec<u64>, Error> {
+        let mut versions: Vec<u64> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".json")?.parse().ok()
+            })
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    pub fn get(&self, version: u64) -> Result<Version, Error> {
+        let raw = fs::read(self.file(version)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No config version {}", version),
+            ),
+            _ => e,
+        })?;
+        serde_json::from_slice(&raw).map_err(Error::other)
+    }
+
+    pub fn latest(&self) -> u64 {
+        *self.latest.lock().unwrap()
+    }
+
+    // Save a new table as the next version, unless it's the same as the
+    // latest one, and drop the versions past the ones kept
+    pub fn record(&self, routes: &[RouteConfig]) -> Result<(), Error> {
+        let routes = diagnostics::redact_routes(routes);
+        let mut latest = self.latest.lock().unwrap();
+        if *latest > 0 {
+            if let Ok(previous) = self.get(*latest) {
+                if serde_json::to_value(&previous.routes).ok() == serde_json::to_value(&routes).ok()
+                {
+                    return Ok(());
+                }
+            }
+        }
+        let version = Version {
+            version: *latest + 1,
+            at: keys::unix_now(),
+            routes,
+        };
+        let raw = serde_json::to_vec_pretty(&version).map_err(Error::other)?;
+        let tmp = self.dir.join("next.tmp");
+        fs::write(&tmp, raw)?;
+        fs::rename(&tmp, self.file(version.version))?;
+        *latest = version.version;
+
+        let versions = self.versions()?;
+        for old in &versions[..versions.len().saturating_sub(self.keep)] {
+            fs::remove_file(self.file(*old))?;
+        }
+        Ok(())
+    }
+
+    // The saved versions, oldest first, without their routes
+    pub fn list(&self) -> Result<Vec<Value>, Error> {
+        let mut list = Vec::new();
+        for version in self.versions()? {
+            let saved = self.get(version)?;
+            list.push(json!({
+                "version": saved.version,
+                "at": saved.at,
+                "routes": saved.routes.len(),
+            }));
+        }
+        Ok(list)
+    }
+}
+
+// Routes added, removed and changed (with the fields that changed) going
+// from one table to another
+pub fn diff(from: &[RouteConfig], to: &[RouteConfig]) -> Value {
+    let find = |table: &[RouteConfig], name: &str| {
+        let route = table.iter().find(|r| r.name == name)?;
+        serde_json::to_value(route).ok()
+    };
+    let added: Vec<&str> = to
+        .iter()
+        .filter(|r| find(from, &r.name).is_none())
+        .map(|r| r.name.as_str())
+        .collect();
+    let removed: Vec<&str> = from
+        .iter()
+        .filter(|r| find(to, &r.name).is_none())
+        .map(|r| r.name.as_str())
+        .collect();
+    let mut changed = serde_json::Map::new();
+    for route in from {
+        let (before, after) = (find(from, &route.name), find(to, &route.name));
+        if after.is_some() && before != after {
+            let fields = audit::changed_fields(before.as_ref(), after.as_ref());
+            changed.insert(route.name.clone(), json!(fields));
+        }
+    }
+    json!({ "added": added, "removed": removed, "changed": changed })
+}
@@ -0,0 +1,201 @@
+use crate::bind;
+use crate::proxy::{self, AppState};
+use crate::tls;
+use actix_http::error::PayloadError;
+use actix_http::{BoxedPayloadStream, Payload, Request};
+use actix_service::IntoServiceFactory;
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, Service, ServiceFactory, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::{Method, Version};
+use actix_web::web::{self, Bytes};
+use bytes::Buf;
+use futures_util::stream;
+use h3::server::RequestResolver;
+use quinn::crypto::rustls::QuicServerConfig;
+use std::error::Error as StdError;
+use std::future::poll_fn;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+type Resolver = RequestResolver<h3_quinn::Connection, Bytes>;
+
+// Connection-specific headers have no meaning in HTTP/3 and are rejected by
+// clients
+const CONNECTION_HEADERS: [header::HeaderName; 4] = [
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::HeaderName::from_static("keep-alive"),
+];
+
+// Experimental HTTP/3 listener. Requests go through the same App as the
+// TCP listeners, so framing, routing and middlewares behave identically. QUIC runs on
+// its own thread because actix requests aren't Send.
+pub fn spawn(state: web::Data<AppState>) -> Result<(), Error> {
+    let config = &state.config;
+    let http3 = match &config.http3 {
+        Some(http3) => http3,
+        None => return Ok(()),
+    };
+    let tls_config = config
+        .tls
+        .as_ref()
+        .ok_or_else(|| Error::other("[http3] needs a [tls] section"))?;
+
+    let mut crypto = tls::server_config(tls_config)?;
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    // QUIC has 0-RTT of its own, which this listener doesn't take
+    crypto.max_early_data_size = 0;
+    let crypto = QuicServerConfig::try_from(crypto)
+        .map_err(|e| Error::other(format!("HTTP/3 TLS config: {}", e)))?;
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    // Bind here so a taken port fails startup instead of the thread
+    let port = http3.port.unwrap_or(config.server.port);
+    let addresses = match http3.bind_addresses.is_empty() {
+        true => &config.server.bind_addresses,
+        false => &http3.bind_addresses,
+    };
+    let sockets = bind::udp(addresses, port)?;
+    println!("HTTP/3 listener running on UDP port: {}", port);
+
+    for socket in sockets {
+        let (state, server_config) = (state.clone(), server_config.clone());
+        std::thread::spawn(move || {
+            actix_rt::System::new().block_on(async move {
+                let endpoint = match quinn::Endpoint::new(
+                    quinn::EndpointConfig::default(),
+                    Some(server_config),
+                    socket,
+                    Arc::new(quinn::TokioRuntime),
+                ) {
+                    Ok(endpoint) => endpoint,
+                    Err(e) => {
+                        eprintln!("Failed to start HTTP/3 listener: {}", e);
+                        return;
+                    }
+                };
+
+                let service = proxy::app(state)
+                    .into_factory()
+                    .new_service(AppConfig::default())
+                    .await;
+                let Ok(service) = service.map(Rc::new) else {
+                    eprintln!("Failed to start HTTP/3 listener: the app didn't start");
+                    return;
+                };
+                while let Some(incoming) = endpoint.accept().await {
+                    let service = service.clone();
+                    actix_rt::spawn(async move {
+                        if let Err(e) = connection(service, incoming).await {
+                            eprintln!("HTTP/3 connection failed: {}", e);
+                        }
+                    });
+                }
+            })
+        });
+    }
+    Ok(())
+}
+
+async fn connection<S, B>(
+    service: Rc<S>,
+    incoming: quinn::Incoming,
+) -> Result<(), Box<dyn StdError>>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+    B::Error: Into<Box<dyn StdError>>,
+{
+    let conn = incoming.await?;
+    let peer = conn.remote_address();
+    let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+
+    loop {
+        match conn.accept().await {
+            Ok(Some(resolver)) => {
+                let service = service.clone();
+                actix_rt::spawn(async move {
+                    if let Err(e) = request(&service, resolver, peer).await {
+                        eprintln!("HTTP/3 request from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_h3_no_error() => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// One request, handed to the same App as the TCP listeners' requests with
+// its body streaming in as the handler reads it, and the response streamed
+// back a chunk at a time
+async fn request<S, B>(
+    service: &S,
+    resolver: Resolver,
+    peer: SocketAddr,
+) -> Result<(), Box<dyn StdError>>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+    B::Error: Into<Box<dyn StdError>>,
+{
+    let (head, stream) = resolver.resolve_request().await?;
+    let (mut sender, receiver) = stream.split();
+
+    let body = stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        match receiver.recv_data().await {
+            Ok(Some(mut chunk)) => {
+                Some((Ok(chunk.copy_to_bytes(chunk.remaining())), Some(receiver)))
+            }
+            Ok(None) => None,
+            Err(e) => Some((Err(PayloadError::Io(Error::other(e))), None)),
+        }
+    });
+    let body: BoxedPayloadStream = Box::pin(body);
+    let mut req = Request::with_payload(Payload::from(body));
+    let uri = head.uri();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let req_head = req.head_mut();
+    req_head.method = Method::from_bytes(head.method().as_str().as_bytes())?;
+    req_head.uri = path.parse()?;
+    req_head.version = Version::HTTP_3;
+    req_head.peer_addr = Some(peer);
+    if let Some(authority) = uri.authority() {
+        req_head
+            .headers
+            .insert(header::HOST, HeaderValue::from_str(authority.as_str())?);
+    }
+    for (name, value) in head.headers() {
+        req_head.headers.append(
+            HeaderName::from_bytes(name.as_str().as_bytes())?,
+            HeaderValue::from_bytes(value.as_bytes())?,
+        );
+    }
+
+    let (_, response) = service.call(req).await?.into_parts();
+    let (mut head, body) = response.into_parts();
+    for name in CONNECTION_HEADERS {
+        head.headers_mut().remove(name);
+    }
+
+    let mut builder = http::Response::builder().status(head.status().as_u16());
+    for (name, value) in head.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    sender.send_response(builder.body(())?).await?;
+    let mut body = Box::pin(body);
+    while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        let chunk = chunk.map_err(Into::into)?;
+        if !chunk.is_empty() {
+            sender.send_data(chunk).await?;
+        }
+    }
+    sender.finish().await?;
+    Ok(())
+}
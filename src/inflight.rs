@@ -0,0 +1,106 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct Request {
+    method: String,
+    path: String,
+    client_ip: String,
+    upstream: Option<String>,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+pub struct RequestInfo {
+    id: u64,
+    method: String,
+    path: String,
+    client_ip: String,
+    upstream: Option<String>,
+    elapsed_ms: u128,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    requests: Vec<RequestInfo>,
+    // Requests currently waiting on each upstream
+    upstreams: BTreeMap<String, usize>,
+}
+
+// Tracks every request currently being handled so ops can see what's stuck
+#[derive(Default)]
+pub struct InFlight {
+    next_id: AtomicU64,
+    requests: Mutex<HashMap<u64, Request>>,
+}
+
+// Removes the request from the table when it finishes (or is dropped)
+pub struct Guard<'a> {
+    table: &'a InFlight,
+    id: u64,
+}
+
+impl InFlight {
+    pub fn start(&self, method: &str, path: &str, client_ip: &str) -> Guard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.requests.lock().unwrap().insert(
+            id,
+            Request {
+                method: method.to_string(),
+                path: path.to_string(),
+                client_ip: client_ip.to_string(),
+                upstream: None,
+                started: Instant::now(),
+            },
+        );
+        Guard { table: self, id }
+    }
+
+    pub fn count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let requests = self.requests.lock().unwrap();
+        let mut upstreams = BTreeMap::new();
+        let mut list: Vec<RequestInfo> = requests
+            .iter()
+            .map(|(id, r)| {
+                if let Some(upstream) = &r.upstream {
+                    *upstreams.entry(upstream.clone()).or_default() += 1;
+                }
+                RequestInfo {
+                    id: *id,
+                    method: r.method.clone(),
+                    path: r.path.clone(),
+                    client_ip: r.client_ip.clone(),
+                    upstream: r.upstream.clone(),
+                    elapsed_ms: r.started.elapsed().as_millis(),
+                }
+            })
+            .collect();
+
+        // Longest running first
+        list.sort_by_key(|r| std::cmp::Reverse(r.elapsed_ms));
+        Snapshot {
+            requests: list,
+            upstreams,
+        }
+    }
+}
+
+impl Guard<'_> {
+    pub fn set_upstream(&self, upstream: &str) {
+        if let Some(request) = self.table.requests.lock().unwrap().get_mut(&self.id) {
+            request.upstream = Some(upstream.to_string());
+        }
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.table.requests.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::config::{InjectConfig, RouteConfig};
+use crate::metrics;
+use actix_web::body::{BodyStream, BoxBody, MessageBody};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::Stream;
+use std::fs;
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// Puts an HTML fragment, such as a maintenance announcement, into a route's
+// pages just before their </body>. Pages aren't held back for it: they go
+// out chunk by chunk as they arrive, less the few bytes at the end of a
+// chunk that might be the start of the tag. Only 200 text/html responses
+// are touched, and only their first </body>; ones without it, such as the
+// partials a page fetches for itself, go out as they came. The upstream is
+// asked for pages unencoded, and ones that come back compressed anyway are
+// left alone.
+
+const TAG: &[u8] = b"</body";
+// How often fragment_file is looked at for changes
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+
+pub struct Injector {
+    file: Option<String>,
+    fragment: Mutex<Fragment>,
+}
+
+struct Fragment {
+    html: Bytes,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+fn read(path: &str) -> Result<(Bytes, Option<SystemTime>), Error> {
+    let modified = fs::metadata(path)?.modified().ok();
+    Ok((fs::read(path)?.into(), modified))
+}
+
+fn count(route: &str, result: &str) {
+    metrics::inc(
+        "html_injections_total",
+        &[("route", route), ("result", result)],
+    );
+}
+
+impl Injector {
+    pub fn load(name: &str, config: &InjectConfig) -> Result<Self, Error> {
+        if config.fragment.is_empty() == config.fragment_file.is_empty() {
+            return Err(Error::other(format!(
+                "Inject middleware {} needs either fragment or fragment_file",
+                name
+            )));
+        }
+        let file = (!config.fragment_file.is_empty()).then(|| config.fragment_file.clone());
+        let (html, modified) = match &file {
+            Some(path) => read(path).map_err(|e| {
+                Error::other(format!(
+                    "Inject middleware {} fragment_file {}: {}",
+                    name, path, e
+                ))
+            })?,
+            None => (Bytes::from(config.fragment.clone()), None),
+        };
+        Ok(Injector {
+            file,
+            fragment: Mutex::new(Fragment {
+                html,
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    // The fragment as it is now; a file that can't be read keeps the last one
+    fn fragment(&self) -> Bytes {
+        let mut fragment = self.fragment.lock().unwrap();
+        let Some(path) = &self.file else {
+            return fragment.html.clone();
+        };
+        if fragment.checked.elapsed() >= CHECK_EVERY {
+            fragment.checked = Instant::now();
+            let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            if modified != fragment.modified {
+                match read(path) {
+                    Ok((html, modified)) => {
+                        println!("Inject fragment {} changed ({} bytes)", path, html.len());
+                        fragment.html = html;
+                        fragment.modified = modified;
+                    }
+                    Err(e) => eprintln!("Can't read inject fragment {}: {}", path, e),
+                }
+            }
+        }
+        fragment.html.clone()
+    }
+
+    pub fn apply(
+        &self,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        response: HttpResponse,
+    ) -> HttpResponse {
+        if response.status() != StatusCode::OK || req.method() == Method::HEAD {
+            return response;
+        }
+        let html = (response.headers().get(header::CONTENT_TYPE))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .is_some_and(|v| v == "text/html" || v == "application/xhtml+xml");
+        if !html {
+            return response;
+        }
+        let encoded = (response.headers().get(header::CONTENT_ENCODING))
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| !v.trim().eq_ignore_ascii_case("identity"));
+        if encoded {
+            count(&route.name, "encoded");
+            return response;
+        }
+        // An emptied file takes the announcement down
+        let fragment = self.fragment();
+        if fragment.iter().all(u8::is_ascii_whitespace) {
+            return response;
+        }
+
+        let (mut head, body) = response.into_parts();
+        head.headers_mut().remove(header::CONTENT_LENGTH);
+        // No longer the bytes a strong validator was made for
+        let weak = (head.headers().get(header::ETAG))
+            .and_then(|v| v.to_str().ok())
+            .filter(|etag| !etag.starts_with("W/"))
+            .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+        if let Some(weak) = weak {
+            head.headers_mut().insert(header::ETAG, weak);
+        }
+        let body = inject(body, fragment, route.name.clone());
+        head.set_body(BodyStream::new(body)).map_into_boxed_body()
+    }
+}
+
+// Where the tag starts in data, in any case
+fn find_tag(data: &[u8]) -> Option<usize> {
+    data.windows(TAG.len())
+        .position(|window| window.eq_ignore_ascii_case(TAG))
+}
+
+// How many bytes at the end of data could be the start of the tag
+fn partial_tag(data: &[u8]) -> usize {
+    (1..TAG.len())
+        .rev()
+        .find(|n| data.len() >= *n && data[data.len() - n..].eq_ignore_ascii_case(&TAG[..*n]))
+        .unwrap_or(0)
+}
+
+struct Injecting {
+    body: BoxBody,
+    // The end of the last chunk, when it might be the start of the tag
+    held: Vec<u8>,
+    // Until it's been put in
+    fragment: Option<Bytes>,
+    route: String,
+}
+
+fn inject(
+    body: BoxBody,
+    fragment: Bytes,
+    route: String,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    let state = Injecting {
+        body,
+        held: Vec::new(),
+        fragment: Some(fragment),
+        route,
+    };
+    futures_util::stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            let next = std::future::poll_fn(|cx| Pin::new(&mut state.body).poll_next(cx)).await;
+            let chunk = match next {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => return Some((Err(Error::other(e.to_string())), None)),
+                None => {
+                    if state.fragment.is_some() {
+                        count(&state.route, "no_body_tag");
+                    }
+                    let held = std::mem::take(&mut state.held);
+                    return (!held.is_empty()).then(|| (Ok(Bytes::from(held)), None));
+                }
+            };
+            let Some(fragment) = &state.fragment else {
+                // An empty chunk would end the body early
+                if chunk.is_empty() {
+                    continue;
+                }
+                return Some((Ok(chunk), Some(state)));
+            };
+            let mut data = std::mem::take(&mut state.held);
+            data.extend_from_slice(&chunk);
+            if let Some(at) = find_tag(&data) {
+                let mut out = Vec::with_capacity(data.len() + fragment.len());
+                out.extend_from_slice(&data[..at]);
+                out.extend_from_slice(fragment);
+                out.extend_from_slice(&data[at..]);
+                state.fragment = None;
+                count(&state.route, "injected");
+                return Some((Ok(Bytes::from(out)), Some(state)));
+            }
+            state.held = data.split_off(data.len() - partial_tag(&data));
+            if data.is_empty() {
+                continue;
+            }
+            return Some((Ok(Bytes::from(data)), Some(state)));
+        }
+    })
+}
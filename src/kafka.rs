@@ -0,0 +1,851 @@
+use crate::access_log::Served;
+use crate::client_ip;
+use crate::config::KafkaConfig;
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+// Access events for the analytics pipeline, one JSON message per request
+// on a Kafka topic. Requests only queue their event; a background task
+// sends the queue in batches with the produce API, so a slow or missing
+// broker never holds a request up. While it's away the queue fills, and
+// past buffer_events new events are dropped and counted.
+
+// Bumped whenever a field changes meaning or goes away; new fields keep it
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct AccessEvent {
+    pub schema_version: u32,
+    pub at_ms: u64,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    pub bytes_sent: u64,
+    pub route: Option<String>,
+    pub upstream: Option<String>,
+    pub region: Option<String>,
+    pub tenant: Option<String>,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// The event for a response about to go out; bytes_sent is filled in once
+// the body has
+pub fn event(
+    req: &HttpRequest,
+    response: &HttpResponse,
+    request_id: &str,
+    elapsed: Duration,
+    tenant: Option<String>,
+) -> AccessEvent {
+    let served = response.extensions().get::<Served>().cloned();
+    AccessEvent {
+        schema_version: SCHEMA_VERSION,
+        at_ms: now_ms(),
+        request_id: request_id.to_string(),
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        protocol: format!("{:?}", req.version()),
+        status: response.status().as_u16(),
+        latency_ms: elapsed.as_secs_f64() * 1000.0,
+        bytes_sent: 0,
+        route: served.as_ref().map(|served| served.route.clone()),
+        upstream: served.as_ref().map(|served| served.upstream.clone()),
+        region: served.and_then(|served| served.region),
+        tenant,
+        client_ip: client_ip::resolved(req),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn dropped(reason: &str, count: usize) {
+    metrics::add(
+        "kafka_events_dropped_total",
+        &[("reason", reason)],
+        count as f64,
+    );
+}
+
+pub struct KafkaSink {
+    config: KafkaConfig,
+    // Serialized events waiting for the next batch
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    // Woken when a full batch is waiting
+    ready: Notify,
+}
+
+impl KafkaSink {
+    pub fn new(config: KafkaConfig) -> Self {
+        println!(
+            "Publishing access events to Kafka topic {} via {}",
+            config.topic,
+            config.brokers.join(", ")
+        );
+        KafkaSink {
+            config,
+            queue: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+        }
+    }
+
+    // Queue an event; never waits
+    pub fn publish(&self, event: &AccessEvent) {
+        let value = serde_json::to_vec(event).unwrap_or_default();
+        if value.len() + RECORD_OVERHEAD > self.config.max_batch_bytes {
+            dropped("too_large", 1);
+            return;
+        }
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.config.buffer_events {
+            drop(queue);
+            dropped("buffer_full", 1);
+            return;
+        }
+        queue.push_back(value);
+        let full = queue.len() >= self.config.batch_size;
+        drop(queue);
+        if full {
+            self.ready.notify_one();
+        }
+    }
+
+    pub fn spawn(self: &Arc<Self>) {
+        let sink = self.clone();
+        tokio::spawn(async move {
+            let linger = Duration::from_millis(sink.config.linger_ms);
+            let mut producer = Producer::new(&sink.config);
+            loop {
+                let _ = timeout(linger, sink.ready.notified()).await;
+                loop {
+                    let batch = sink.take();
+                    if batch.is_empty() {
+                        break;
+                    }
+                    sink.send(&mut producer, &batch).await;
+                }
+            }
+        });
+    }
+
+    // The oldest events, up to a batch
+    fn take(&self) -> Vec<Vec<u8>> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut batch = Vec::new();
+        let mut bytes = BATCH_OVERHEAD;
+        while let Some(value) = queue.front() {
+            if batch.len() >= self.config.batch_size
+                || bytes + value.len() + RECORD_OVERHEAD > self.config.max_batch_bytes
+            {
+                break;
+            }
+            bytes += value.len() + RECORD_OVERHEAD;
+            batch.extend(queue.pop_front());
+        }
+        metrics::set("kafka_queued_events", &[], queue.len() as f64);
+        batch
+    }
+
+    // Send a batch, trying again after a pause up to max_attempts times
+    async fn send(&self, producer: &mut Producer, batch: &[Vec<u8>]) {
+        let wait = Duration::from_millis(self.config.timeout_ms);
+        let mut backoff = Duration::from_millis(100);
+        for attempt in 1..=self.config.max_attempts {
+            let result = match timeout(wait, producer.produce(batch)).await {
+                Ok(result) => result,
+                Err(_) => Err(ErrorKind::TimedOut.into()),
+            };
+            match result {
+                Ok(()) => {
+                    metrics::inc("kafka_batches_total", &[("result", "sent")]);
+                    metrics::add("kafka_events_sent_total", &[], batch.len() as f64);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Kafka: sending {} events failed (attempt {} of {}): {}",
+                        batch.len(),
+                        attempt,
+                        self.config.max_attempts,
+                        e
+                    );
+                    metrics::inc("kafka_batches_total", &[("result", "failed")]);
+                    // Leaders may have moved; look them up again
+                    producer.reset();
+                }
+            }
+            if attempt < self.config.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(5));
+            }
+        }
+        dropped("send_failed", batch.len());
+    }
+}
+
+// Sends records as soon as it's given them, for callers that keep what
+// couldn't be sent and try again themselves, such as the usage export. A
+// failure partway leaves the batches before it sent.
+pub struct Publisher {
+    config: KafkaConfig,
+    producer: tokio::sync::Mutex<Producer>,
+}
+
+impl Publisher {
+    pub fn new(config: KafkaConfig) -> Self {
+        Publisher {
+            producer: tokio::sync::Mutex::new(Producer::new(&config)),
+            config,
+        }
+    }
+
+    pub async fn send(&self, values: Vec<Vec<u8>>) -> Result<(), Error> {
+        let mut batches: Vec<Vec<Vec<u8>>> = Vec::new();
+        let mut bytes = 0;
+        for value in values {
+            let size = value.len() + RECORD_OVERHEAD;
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.len() < self.config.batch_size
+                        && bytes + size <= self.config.max_batch_bytes =>
+                {
+                    bytes += size;
+                    batch.push(value);
+                }
+                _ => {
+                    bytes = BATCH_OVERHEAD + size;
+                    batches.push(vec![value]);
+                }
+            }
+        }
+        let wait = Duration::from_millis(self.config.timeout_ms);
+        let mut producer = self.producer.lock().await;
+        for batch in batches {
+            let result = match timeout(wait, producer.produce(&batch)).await {
+                Ok(result) => result,
+                Err(_) => Err(ErrorKind::TimedOut.into()),
+            };
+            if let Err(e) = result {
+                // Leaders may have moved; look them up again next time
+                producer.reset();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+// What a record and a batch add to the events in them, near enough
+const RECORD_OVERHEAD: usize = 24;
+const BATCH_OVERHEAD: usize = 128;
+
+// API keys and the versions used: Produce v3 is the oldest that takes
+// record batches (magic 2), which every broker since 0.11 understands
+const PRODUCE: (i16, i16) = (0, 3);
+const METADATA: (i16, i16) = (3, 1);
+
+// The topic's partitions and connections to their leaders
+struct Producer {
+    config: KafkaConfig,
+    correlation_id: i32,
+    // Partitions with a leader, as (partition, leader node)
+    partitions: Vec<(i32, i32)>,
+    // host:port by node ID
+    brokers: HashMap<i32, String>,
+    connections: HashMap<i32, TcpStream>,
+    // Batches go round the partitions in turn
+    next: usize,
+}
+
+impl Producer {
+    fn new(config: &KafkaConfig) -> Self {
+        Producer {
+            config: config.clone(),
+            correlation_id: 0,
+            partitions: Vec::new(),
+            brokers: HashMap::new(),
+            connections: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.partitions.clear();
+        self.connections.clear();
+    }
+
+    async fn produce(&mut self, batch: &[Vec<u8>]) -> Result<(), Error> {
+        if self.partitions.is_empty() {
+            self.refresh().await?;
+        }
+        let (partition, leader) = self.partitions[self.next % self.partitions.len()];
+        self.next = self.next.wrapping_add(1);
+
+        let mut body = Writer::default();
+        body.i16(-1); // No transactional ID
+        body.i16(self.config.acks);
+        body.i32(self.config.timeout_ms.min(i32::MAX as u64) as i32);
+        body.i32(1);
+        body.string(&self.config.topic);
+        body.i32(1);
+        body.i32(partition);
+        body.bytes(&record_batch(batch, now_ms() as i64));
+
+        let expect_response = self.config.acks != 0;
+        let address = (self.brokers.get(&leader))
+            .cloned()
+            .ok_or_else(|| Error::other(format!("leader {} isn't a known broker", leader)))?;
+        let id = self.next_id();
+        let request = request(PRODUCE, id, &self.config.client_id, &body.0);
+        let io = match self.connections.entry(leader) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let io = TcpStream::connect(&address).await?;
+                io.set_nodelay(true)?;
+                entry.insert(io)
+            }
+        };
+        let Some(response) = exchange(io, &request, id, expect_response).await? else {
+            return Ok(());
+        };
+        match produced(&response)? {
+            Some((partition, code)) => Err(Error::other(format!(
+                "broker {} refused the batch for partition {}: error {}",
+                address, partition, code
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    // Ask the bootstrap brokers in turn for the topic's partition leaders
+    async fn refresh(&mut self) -> Result<(), Error> {
+        let mut last = Error::other("no brokers");
+        for broker in self.config.brokers.clone() {
+            match self.metadata(&broker).await {
+                Ok(()) => return Ok(()),
+                Err(e) => last = Error::other(format!("metadata from {}: {}", broker, e)),
+            }
+        }
+        Err(last)
+    }
+
+    async fn metadata(&mut self, broker: &str) -> Result<(), Error> {
+        let mut body = Writer::default();
+        body.i32(1);
+        body.string(&self.config.topic);
+        let id = self.next_id();
+        let request = request(METADATA, id, &self.config.client_id, &body.0);
+        let mut io = TcpStream::connect(broker).await?;
+        let response = exchange(&mut io, &request, id, true)
+            .await?
+            .unwrap_or_default();
+        let (brokers, partitions) = metadata(&response, &self.config.topic)?;
+        self.partitions = partitions;
+        self.brokers = brokers;
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> i32 {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        self.correlation_id
+    }
+}
+
+// A Metadata v1 response: the brokers by node ID, and the topic's
+// partitions that have a leader, as (partition, leader node)
+type Metadata = (HashMap<i32, String>, Vec<(i32, i32)>);
+
+fn metadata(response: &[u8], topic: &str) -> Result<Metadata, Error> {
+    let mut reader = Reader::new(response);
+    let mut brokers = HashMap::new();
+    for _ in 0..reader.i32()? {
+        let node = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        reader.nullable_string()?; // Rack
+        brokers.insert(node, format!("{}:{}", host, port));
+    }
+    reader.i32()?; // Controller
+    let mut partitions = Vec::new();
+    for _ in 0..reader.i32()? {
+        let code = reader.i16()?;
+        let name = reader.string()?;
+        reader.i8()?; // Internal
+        if code != 0 {
+            return Err(Error::other(format!("topic {}: error {}", name, code)));
+        }
+        for _ in 0..reader.i32()? {
+            reader.i16()?;
+            let partition = reader.i32()?;
+            let leader = reader.i32()?;
+            for _ in 0..2 {
+                // Replicas and in-sync replicas
+                for _ in 0..reader.i32()? {
+                    reader.i32()?;
+                }
+            }
+            if leader >= 0 {
+                partitions.push((partition, leader));
+            }
+        }
+    }
+    if partitions.is_empty() {
+        return Err(Error::other(format!(
+            "topic {} has no partition with a leader",
+            topic
+        )));
+    }
+    partitions.sort();
+    Ok((brokers, partitions))
+}
+
+// A Produce v3 response: the first partition that refused the batch, with
+// its error code
+fn produced(response: &[u8]) -> Result<Option<(i32, i16)>, Error> {
+    let mut reader = Reader::new(response);
+    for _ in 0..reader.i32()? {
+        reader.string()?;
+        for _ in 0..reader.i32()? {
+            let partition = reader.i32()?;
+            let code = reader.i16()?;
+            reader.i64()?; // Base offset
+            reader.i64()?; // Log append time
+            if code != 0 {
+                return Ok(Some((partition, code)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// A request with its size and header (v1)
+fn request((api_key, version): (i16, i16), id: i32, client_id: &str, body: &[u8]) -> Vec<u8> {
+    let mut out = Writer::default();
+    out.i32(0);
+    out.i16(api_key);
+    out.i16(version);
+    out.i32(id);
+    out.string(client_id);
+    out.0.extend_from_slice(body);
+    let size = (out.0.len() - 4) as i32;
+    out.0[..4].copy_from_slice(&size.to_be_bytes());
+    out.0
+}
+
+// Send a request and read its response, after the correlation ID
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut S,
+    request: &[u8],
+    id: i32,
+    expect_response: bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    io.write_all(request).await?;
+    if !expect_response {
+        return Ok(None);
+    }
+    let size = io.read_i32().await?;
+    if !(4..=64 * 1024 * 1024).contains(&size) {
+        return Err(Error::new(ErrorKind::InvalidData, "bad response size"));
+    }
+    let mut response = vec![0u8; size as usize];
+    io.read_exact(&mut response).await?;
+    if response[..4] != id.to_be_bytes() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "response to another request",
+        ));
+    }
+    response.drain(..4);
+    Ok(Some(response))
+}
+
+// A record batch (magic 2) of values without keys or headers
+fn record_batch(values: &[Vec<u8>], timestamp: i64) -> Vec<u8> {
+    let mut records = Writer::default();
+    for (i, value) in values.iter().enumerate() {
+        let mut record = Writer::default();
+        record.i8(0); // Attributes
+        record.varint(0); // Timestamp delta
+        record.varint(i as i64);
+        record.varint(-1); // No key
+        record.varint(value.len() as i64);
+        record.0.extend_from_slice(value);
+        record.varint(0); // No headers
+        records.varint(record.0.len() as i64);
+        records.0.extend_from_slice(&record.0);
+    }
+
+    // What the CRC covers: attributes to the end
+    let mut tail = Writer::default();
+    tail.i16(0); // Attributes: no compression, create time
+    tail.i32(values.len() as i32 - 1); // Last offset delta
+    tail.i64(timestamp);
+    tail.i64(timestamp);
+    tail.i64(-1); // Producer ID
+    tail.i16(-1); // Producer epoch
+    tail.i32(-1); // Base sequence
+    tail.i32(values.len() as i32);
+    tail.0.extend_from_slice(&records.0);
+
+    let mut batch = Writer::default();
+    batch.i64(0); // Base offset, set by the broker
+    batch.i32((4 + 1 + 4 + tail.0.len()) as i32);
+    batch.i32(-1); // Partition leader epoch
+    batch.i8(2); // Magic
+    batch.0.extend_from_slice(&crc32c(&tail.0).to_be_bytes());
+    batch.0.extend_from_slice(&tail.0);
+    batch.0
+}
+
+// CRC-32C (Castagnoli), which record batches use
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0x82f6_3b78 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC32C: [u32; 256] = crc32c_table();
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32C[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Big-endian fields as the protocol has them
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn i8(&mut self, v: i8) {
+        self.0.push(v as u8);
+    }
+
+    fn i16(&mut self, v: i16) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn i32(&mut self, v: i32) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn i64(&mut self, v: i64) {
+        self.0.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn string(&mut self, v: &str) {
+        self.i16(v.len() as i16);
+        self.0.extend_from_slice(v.as_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.i32(v.len() as i32);
+        self.0.extend_from_slice(v);
+    }
+
+    // Zigzag varint, as inside record batches
+    fn varint(&mut self, v: i64) {
+        let mut v = ((v << 1) ^ (v >> 63)) as u64;
+        while v >= 0x80 {
+            self.0.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() < n {
+            return Err(Error::new(ErrorKind::InvalidData, "response cut short"));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn i8(&mut self) -> Result<i8, Error> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>, Error> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let raw = self.take(len as usize)?;
+        Ok(Some(String::from_utf8_lossy(raw).into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_like_castagnoli() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn writes_zigzag_varints() {
+        for (v, encoded) in [
+            (0, &[0x00][..]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (63, &[0x7e]),
+            (64, &[0x80, 0x01]),
+            (-65, &[0x81, 0x01]),
+        ] {
+            let mut out = Writer::default();
+            out.varint(v);
+            assert_eq!(out.0, encoded, "{}", v);
+        }
+    }
+
+    #[test]
+    fn frames_requests() {
+        let request = request(METADATA, 7, "netty", b"body");
+        let mut reader = Reader::new(&request);
+        assert_eq!(reader.i32().unwrap() as usize, request.len() - 4);
+        assert_eq!(reader.i16().unwrap(), 3);
+        assert_eq!(reader.i16().unwrap(), 1);
+        assert_eq!(reader.i32().unwrap(), 7);
+        assert_eq!(reader.string().unwrap(), "netty");
+        assert_eq!(reader.take(4).unwrap(), b"body");
+        assert!(reader.take(1).is_err());
+    }
+
+    #[test]
+    fn builds_record_batches() {
+        let values = vec![b"one".to_vec(), b"two".to_vec()];
+        let batch = record_batch(&values, 1_000);
+        let mut reader = Reader::new(&batch);
+        assert_eq!(reader.i64().unwrap(), 0);
+        // Batch length covers everything after it
+        assert_eq!(reader.i32().unwrap() as usize, batch.len() - 12);
+        assert_eq!(reader.i32().unwrap(), -1);
+        assert_eq!(reader.i8().unwrap(), 2);
+        let crc = reader.i32().unwrap() as u32;
+        assert_eq!(crc, crc32c(reader.buf));
+        reader.i16().unwrap();
+        assert_eq!(reader.i32().unwrap(), 1); // Last offset delta
+        assert_eq!(reader.i64().unwrap(), 1_000);
+    }
+
+    #[test]
+    fn reads_fields() {
+        let mut out = Writer::default();
+        out.i16(-1);
+        out.string("topic");
+        out.i64(-2);
+        let mut reader = Reader::new(&out.0);
+        assert_eq!(reader.nullable_string().unwrap(), None);
+        assert_eq!(reader.string().unwrap(), "topic");
+        assert_eq!(reader.i64().unwrap(), -2);
+
+        // Lengths past the end of the response
+        let mut reader = Reader::new(&[0, 9, b'a']);
+        let e = reader.string().unwrap_err();
+        assert_eq!(e.to_string(), "response cut short");
+        assert!(Reader::new(&[0, 0, 0]).i32().is_err());
+    }
+
+    fn metadata_response(leader: i32) -> Vec<u8> {
+        let mut out = Writer::default();
+        out.i32(2);
+        for (node, host) in [(1, "a"), (2, "b")] {
+            out.i32(node);
+            out.string(host);
+            out.i32(9092);
+            out.i16(-1);
+        }
+        out.i32(1); // Controller
+        out.i32(1);
+        out.i16(0);
+        out.string("access");
+        out.i8(0);
+        out.i32(2);
+        for (partition, leader) in [(1, leader), (0, 2)] {
+            out.i16(0);
+            out.i32(partition);
+            out.i32(leader);
+            out.i32(1);
+            out.i32(leader);
+            out.i32(0);
+        }
+        out.0
+    }
+
+    #[test]
+    fn reads_metadata() {
+        let (brokers, partitions) = metadata(&metadata_response(1), "access").unwrap();
+        assert_eq!(brokers[&1], "a:9092");
+        assert_eq!(brokers[&2], "b:9092");
+        assert_eq!(partitions, vec![(0, 2), (1, 1)]);
+
+        // Partitions without a leader are left out
+        let (_, partitions) = metadata(&metadata_response(-1), "access").unwrap();
+        assert_eq!(partitions, vec![(0, 2)]);
+
+        let response = metadata_response(1);
+        for len in 0..response.len() {
+            assert!(metadata(&response[..len], "access").is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn refuses_topic_errors() {
+        let mut out = Writer::default();
+        out.i32(0);
+        out.i32(-1);
+        out.i32(1);
+        out.i16(3);
+        out.string("access");
+        out.i8(0);
+        out.i32(0);
+        let e = metadata(&out.0, "access").unwrap_err();
+        assert_eq!(e.to_string(), "topic access: error 3");
+
+        let mut out = Writer::default();
+        out.i32(0);
+        out.i32(-1);
+        out.i32(0);
+        let e = metadata(&out.0, "access").unwrap_err();
+        assert_eq!(e.to_string(), "topic access has no partition with a leader");
+    }
+
+    #[test]
+    fn reads_produce_responses() {
+        let response = |code: i16| {
+            let mut out = Writer::default();
+            out.i32(1);
+            out.string("access");
+            out.i32(2);
+            for (partition, code) in [(0, 0), (1, code)] {
+                out.i32(partition);
+                out.i16(code);
+                out.i64(10);
+                out.i64(-1);
+            }
+            out.0
+        };
+        assert_eq!(produced(&response(0)).unwrap(), None);
+        assert_eq!(produced(&response(6)).unwrap(), Some((1, 6)));
+        let response = response(0);
+        assert!(produced(&response[..response.len() - 1]).is_err());
+    }
+
+    async fn answer(response: &[u8], id: i32) -> Result<Option<Vec<u8>>, Error> {
+        let (mut io, mut broker) = tokio::io::duplex(1024);
+        broker.write_all(response).await.unwrap();
+        broker.shutdown().await.unwrap();
+        exchange(&mut io, b"request", id, true).await
+    }
+
+    #[tokio::test]
+    async fn exchanges_requests() {
+        let (mut io, mut broker) = tokio::io::duplex(1024);
+        let mut response = Writer::default();
+        response.i32(8);
+        response.i32(7);
+        response.0.extend_from_slice(b"body");
+        broker.write_all(&response.0).await.unwrap();
+        let body = exchange(&mut io, b"request", 7, true).await.unwrap();
+        assert_eq!(body.unwrap(), b"body");
+        let mut sent = [0u8; 7];
+        broker.read_exact(&mut sent).await.unwrap();
+        assert_eq!(&sent, b"request");
+
+        // acks=0 has no response to wait for
+        assert_eq!(exchange(&mut io, b"request", 8, false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_responses() {
+        for size in [0, 3, -1, 64 * 1024 * 1024 + 1, i32::MAX] {
+            let e = answer(&size.to_be_bytes(), 7).await.unwrap_err();
+            assert_eq!(e.to_string(), "bad response size", "{}", size);
+        }
+
+        let mut response = Writer::default();
+        response.i32(4);
+        response.i32(6);
+        let e = answer(&response.0, 7).await.unwrap_err();
+        assert_eq!(e.to_string(), "response to another request");
+
+        // The broker hung up partway through
+        let mut response = Writer::default();
+        response.i32(100);
+        response.i32(7);
+        let e = answer(&response.0, 7).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        let e = answer(&[0, 0], 7).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::config::{ApiKeyAuthConfig, ApiKeyRecord, KeyStoreKind, RateLimitConfig};
+use crate::store::Store;
+use crate::tenant::RateLimiter;
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use ring::digest;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+enum Backend {
+    Config(Vec<ApiKeyRecord>),
+    Sqlite(Arc<Store>),
+    // Hash apikeys:{authenticator}, key hash -> record JSON
+    Redis(ConnectionManager),
+}
+
+// Why a key was refused
+pub enum Refusal {
+    Unknown,
+    Revoked,
+    Expired,
+    RateLimited,
+    Unavailable,
+}
+
+// The partner API keys of one api_key authenticator. Keys are looked up by
+// their SHA-256, and each key gets its own token bucket.
+pub struct KeyStore {
+    name: String,
+    backend: Backend,
+    default_limit: Option<RateLimitConfig>,
+    // Key hash -> bucket, created on the key's first request
+    limiters: Mutex<HashMap<String, RateLimiter>>,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hash(key: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, key).as_ref())
+}
+
+// A new key and its record; the key itself is only ever shown once
+pub fn generate(id: &str, partner: &str) -> Result<(String, ApiKeyRecord), Error> {
+    let mut bytes = [0u8; 24];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::other("No randomness for a new API key"))?;
+    let key = format!("pk_{}", hex(&bytes));
+    let record = ApiKeyRecord {
+        id: id.to_string(),
+        partner: partner.to_string(),
+        hash: hash(key.as_bytes()),
+        created_at: unix_now(),
+        ..Default::default()
+    };
+    Ok((key, record))
+}
+
+impl KeyStore {
+    pub async fn new(
+        name: &str,
+        config: &ApiKeyAuthConfig,
+        store: Option<Arc<Store>>,
+    ) -> Result<Self, Error> {
+        let backend = match config.store {
+            KeyStoreKind::Config => {
+                for record in &config.keys {
+                    let valid = record.hash.len() == 64
+                        && record.hash.bytes().all(|b| b.is_ascii_hexdigit());
+                    if !valid || record.id.is_empty() {
+                        return Err(Error::other(format!(
+                            "Authenticator {} keys need an id and a hex SHA-256 hash",
+                            name
+                        )));
+                    }
+                }
+                let mut keys = config.keys.clone();
+                for record in &mut keys {
+                    record.hash = record.hash.to_ascii_lowercase();
+                }
+                Backend::Config(keys)
+            }
+            KeyStoreKind::Sqlite => match store {
+                Some(store) => Backend::Sqlite(store),
+                None => {
+                    return Err(Error::other(format!(
+                    "Authenticator {} keeps its keys in SQLite, which needs [admin] database_path",
+                    name
+                )))
+                }
+            },
+            KeyStoreKind::Redis => {
+                let url = config.redis_url.as_deref().ok_or_else(|| {
+                    Error::other(format!("Authenticator {} needs a redis_url", name))
+                })?;
+                let client = redis::Client::open(url)
+                    .map_err(|e| Error::other(format!("Invalid redis_url for {}: {}", name, e)))?;
+                let manager = ConnectionManager::new(client).await.map_err(|e| {
+                    Error::other(format!("API key Redis for {} unavailable: {}", name, e))
+                })?;
+                Backend::Redis(manager)
+            }
+        };
+
+        Ok(KeyStore {
+            name: name.to_string(),
+            backend,
+            default_limit: config.rate_limit.clone(),
+            limiters: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Keys in the config file change with the config file only
+    pub fn writable(&self) -> bool {
+        !matches!(self.backend, Backend::Config(_))
+    }
+
+    fn redis_key(&self) -> String {
+        format!("apikeys:{}", self.name)
+    }
+
+    async fn lookup(&self, hash: &str) -> Result<Option<ApiKeyRecord>, String> {
+        match &self.backend {
+            Backend::Config(keys) => Ok(keys.iter().find(|k| k.hash == hash).cloned()),
+            Backend::Sqlite(store) => store
+                .partner_key(&self.name, hash)
+                .map_err(|e| e.to_string()),
+            Backend::Redis(manager) => {
+                let mut conn = manager.clone();
+                let raw: Option<String> = conn
+                    .hget(self.redis_key(), hash)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                raw.map(|raw| serde_json::from_str(&raw).map_err(|e| e.to_string()))
+                    .transpose()
+            }
+        }
+    }
+
+    pub async fn list(&self) -> Result<Vec<ApiKeyRecord>, String> {
+        match &self.backend {
+            Backend::Config(keys) => Ok(keys.clone()),
+            Backend::Sqlite(store) => store.partner_keys(&self.name).map_err(|e| e.to_string()),
+            Backend::Redis(manager) => {
+                let mut conn = manager.clone();
+                let raw: HashMap<String, String> = conn
+                    .hgetall(self.redis_key())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut records = raw
+                    .values()
+                    .map(|raw| serde_json::from_str(raw).map_err(|e| e.to_string()))
+                    .collect::<Result<Vec<ApiKeyRecord>, _>>()?;
+                records.sort_by_key(|r| r.created_at);
+                Ok(records)
+            }
+        }
+    }
+
+    pub async fn put(&self, record: &ApiKeyRecord) -> Result<(), String> {
+        match &self.backend {
+            Backend::Config(_) => Err("Keys in the config file are read-only".to_string()),
+            Backend::Sqlite(store) => store
+                .put_partner_key(&self.name, record)
+                .map_err(|e| e.to_string()),
+            Backend::Redis(manager) => {
+                let raw = serde_json::to_string(record).map_err(|e| e.to_string())?;
+                let mut conn = manager.clone();
+                conn.hset::<_, _, _, ()>(self.redis_key(), &record.hash, raw)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    // The record of a presented key, if it's active and within its rate limit
+    pub async fn check(&self, key: &[u8]) -> Result<ApiKeyRecord, Refusal> {
+        let hash = hash(key);
+        let record = match self.lookup(&hash).await {
+            Ok(Some(record)) => record,
+            Ok(None) => return Err(Refusal::Unknown),
+            Err(e) => {
+                eprintln!("API key store for {} unavailable: {}", self.name, e);
+                return Err(Refusal::Unavailable);
+            }
+        };
+        if record.revoked {
+            return Err(Refusal::Revoked);
+        }
+        if record.expires_at.is_some_and(|at| at <= unix_now()) {
+            return Err(Refusal::Expired);
+        }
+
+        if let Some(limit) = record.rate_limit.as_ref().or(self.default_limit.as_ref()) {
+            let mut limiters = self.limiters.lock().unwrap();
+            let limiter = limiters
+                .entry(hash)
+                .or_insert_with(|| RateLimiter::new(limit.clone()));
+            if !limiter.allow() {
+                return Err(Refusal::RateLimited);
+            }
+        }
+        Ok(record)
+    }
+}
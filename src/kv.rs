@@ -0,0 +1,402 @@
+use crate::config::KvConfig;
+use crate::metrics;
+use futures_util::future::BoxFuture;
+use redis::aio::ConnectionManager;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Keys and values with optional expiry, for features that keep state
+// across requests. Memory is fine for a single replica; Redis shares the
+// state between replicas. Adding a backend only takes an implementation and
+// a case in open().
+pub trait KvStore: Send + Sync {
+    // None when the key is missing or has expired
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>>;
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), String>>;
+    // Set only if the key is absent; false if it was already there. Nonces,
+    // idempotency keys and locks are this.
+    fn set_nx<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<bool, String>>;
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>>;
+    // Add to a counter and return its new value. The ttl starts when the
+    // counter is created, so a window ends on time however busy it is.
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, String>>;
+}
+
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+}
+
+impl Entry {
+    fn live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+// One replica's state, lost on restart
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    max_entries: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_entries: usize) -> Self {
+        MemoryStore {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    // Make room for one more key, dropping expired ones first
+    fn room(&self, entries: &mut HashMap<String, Entry>, key: &str) -> Result<(), String> {
+        if entries.len() < self.max_entries || entries.contains_key(key) {
+            return Ok(());
+        }
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.live(now));
+        match entries.len() < self.max_entries {
+            true => Ok(()),
+            false => Err(format!("memory store is full ({} keys)", self.max_entries)),
+        }
+    }
+
+    fn put(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        only_new: bool,
+    ) -> Result<bool, String> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        if only_new && entries.get(key).is_some_and(|entry| entry.live(now)) {
+            return Ok(false);
+        }
+        self.room(&mut entries, key)?;
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                expires: ttl.map(|ttl| now + ttl),
+            },
+        );
+        Ok(true)
+    }
+}
+
+impl KvStore for MemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+        let entries = self.entries.lock().unwrap();
+        let value = entries
+            .get(key)
+            .filter(|entry| entry.live(Instant::now()))
+            .map(|entry| entry.value.clone());
+        Box::pin(async move { Ok(value) })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        let result = self.put(key, value, ttl, false).map(|_| ());
+        Box::pin(async move { result })
+    }
+
+    fn set_nx<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<bool, String>> {
+        let result = self.put(key, value, ttl, true);
+        Box::pin(async move { result })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        self.entries.lock().unwrap().remove(key);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, String>> {
+        let result = (|| {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            // Counters are kept as decimal text, as Redis keeps them
+            let current = match entries.get(key).filter(|entry| entry.live(now)) {
+                Some(entry) => Some(
+                    std::str::from_utf8(&entry.value)
+                        .ok()
+                        .and_then(|v| v.parse::<i64>().ok())
+                        .ok_or_else(|| format!("{} is not a counter", key))?,
+                ),
+                None => None,
+            };
+            let value = current.unwrap_or(0) + by;
+            match entries.get_mut(key).filter(|_| current.is_some()) {
+                Some(entry) => entry.value = value.to_string().into_bytes(),
+                None => {
+                    self.room(&mut entries, key)?;
+                    entries.insert(
+                        key.to_string(),
+                        Entry {
+                            value: value.to_string().into_bytes(),
+                            expires: ttl.map(|ttl| now + ttl),
+                        },
+                    );
+                }
+            }
+            Ok(value)
+        })();
+        Box::pin(async move { result })
+    }
+}
+
+// Add to a counter, giving it the ttl if it has no expiry yet (so it was
+// just created)
+const INCR: &str = r"
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+if ARGV[2] ~= '' and redis.call('PTTL', KEYS[1]) == -1 then
+  redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return value
+";
+
+// State shared by every replica using the same Redis
+pub struct RedisStore {
+    manager: ConnectionManager,
+    prefix: String,
+    incr: redis::Script,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str, prefix: &str) -> Result<Self, Error> {
+        let client = redis::Client::open(url)
+            .map_err(|e| Error::other(format!("Invalid kv redis_url: {}", e)))?;
+        let manager = ConnectionManager::new(client)
+            .await
+            .map_err(|e| Error::other(format!("KV store Redis unavailable: {}", e)))?;
+        Ok(RedisStore {
+            manager,
+            prefix: prefix.to_string(),
+            incr: redis::Script::new(INCR),
+        })
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+fn millis(ttl: Duration) -> u64 {
+    (ttl.as_millis() as u64).max(1)
+}
+
+impl KvStore for RedisStore {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+        Box::pin(async move {
+            redis::cmd("GET")
+                .arg(self.key(key))
+                .query_async(&mut self.manager.clone())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(self.key(key)).arg(value);
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(millis(ttl));
+            }
+            cmd.query_async(&mut self.manager.clone())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn set_nx<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move {
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(self.key(key)).arg(value).arg("NX");
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(millis(ttl));
+            }
+            // OK when set, nil when the key was there
+            let set: Option<String> = cmd
+                .query_async(&mut self.manager.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(set.is_some())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            redis::cmd("DEL")
+                .arg(self.key(key))
+                .query_async(&mut self.manager.clone())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, String>> {
+        Box::pin(async move {
+            let ttl = ttl.map(|ttl| millis(ttl).to_string()).unwrap_or_default();
+            self.incr
+                .key(self.key(key))
+                .arg(by)
+                .arg(ttl)
+                .invoke_async(&mut self.manager.clone())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+// Counts and times every operation on the store it wraps
+struct Metered {
+    store: Box<dyn KvStore>,
+    backend: &'static str,
+}
+
+impl Metered {
+    fn record<T>(&self, op: &str, started: Instant, result: &Result<T, String>) {
+        let outcome = match result {
+            Ok(_) => "ok",
+            Err(_) => "error",
+        };
+        let labels = [("backend", self.backend), ("op", op)];
+        metrics::inc(
+            "kv_operations_total",
+            &[labels[0], labels[1], ("result", outcome)],
+        );
+        metrics::add(
+            "kv_operation_seconds_total",
+            &labels,
+            started.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+impl KvStore for Metered {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.store.get(key).await;
+            self.record("get", started, &result);
+            if let Ok(found) = &result {
+                let outcome = if found.is_some() { "hit" } else { "miss" };
+                metrics::inc(
+                    "kv_lookups_total",
+                    &[("backend", self.backend), ("result", outcome)],
+                );
+            }
+            result
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.store.set(key, value, ttl).await;
+            self.record("set", started, &result);
+            result
+        })
+    }
+
+    fn set_nx<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.store.set_nx(key, value, ttl).await;
+            self.record("set_nx", started, &result);
+            result
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.store.delete(key).await;
+            self.record("delete", started, &result);
+            result
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, Result<i64, String>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = self.store.incr(key, by, ttl).await;
+            self.record("incr", started, &result);
+            result
+        })
+    }
+}
+
+// The store [kv] describes, with metrics. A feature with its own redis_url
+// passes that in place of the shared one.
+pub async fn open(config: &KvConfig, redis_url: Option<&str>) -> Result<Arc<dyn KvStore>, Error> {
+    let (store, backend): (Box<dyn KvStore>, _) = match redis_url.or(config.redis_url.as_deref()) {
+        Some(url) => (
+            Box::new(RedisStore::connect(url, &config.key_prefix).await?),
+            "redis",
+        ),
+        None => (Box::new(MemoryStore::new(config.max_entries)), "memory"),
+    };
+    Ok(Arc::new(Metered { store, backend }))
+}
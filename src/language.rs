@@ -0,0 +1,110 @@
+use crate::config::RouteConfig;
+use crate::metrics;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{HttpRequest, HttpResponse};
+
+// The language ranges of an Accept-Language header, most preferred first.
+// Ranges with q=0 are refused rather than preferred, so they're left out.
+fn ranges(header: &str) -> Vec<&str> {
+    let mut ranges: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!range.is_empty() && q > 0.0).then_some((range, q))
+        })
+        .collect();
+    // Stable, so equal weights keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+// Which of the offered languages suits the client best. Each range is tried
+// as it is, then shortened (de-CH-1996, de-CH, de), then as the start of a
+// longer offer (en for en-US). Clients that don't say, or whose languages
+// aren't offered, get the default if that's one of the offers.
+pub fn negotiate<'a>(
+    accept_language: Option<&str>,
+    offered: impl IntoIterator<Item = &'a String>,
+    default: Option<&str>,
+) -> Option<&'a str> {
+    let mut offered: Vec<&str> = offered.into_iter().map(String::as_str).collect();
+    offered.sort();
+    let find = |tag: &str| {
+        offered
+            .iter()
+            .find(|o| o.eq_ignore_ascii_case(tag))
+            .copied()
+    };
+    for range in accept_language.map(ranges).unwrap_or_default() {
+        if range == "*" {
+            break;
+        }
+        let mut tag = range;
+        loop {
+            if let Some(found) = find(tag) {
+                return Some(found);
+            }
+            match tag.rsplit_once('-') {
+                Some((shorter, _)) => tag = shorter,
+                None => break,
+            }
+        }
+        let longer = offered.iter().find(|o| {
+            o.len() > range.len()
+                && o.as_bytes()[range.len()] == b'-'
+                && o.as_bytes()[..range.len()].eq_ignore_ascii_case(range.as_bytes())
+        });
+        if let Some(found) = longer {
+            return Some(found);
+        }
+    }
+    default.and_then(find)
+}
+
+// The language a route's Accept-Language predicate or upstream choice
+// settled on for the request
+pub fn pick<'a>(
+    req: &HttpRequest,
+    offered: impl IntoIterator<Item = &'a String>,
+    route: &RouteConfig,
+) -> Option<&'a str> {
+    let accept_language = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok());
+    negotiate(accept_language, offered, route.default_language.as_deref())
+}
+
+// Tell caches the response depends on the client's languages, and count
+// it by the Content-Language it came back with. Values the route doesn't
+// offer are counted together, since upstreams can send anything there.
+pub fn finish(route: &RouteConfig, response: &mut HttpResponse) {
+    if route.languages.is_empty() && route.accept_languages.is_empty() {
+        return;
+    }
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-language"));
+    let content_language = response
+        .headers()
+        .get(header::CONTENT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').next().unwrap_or_default().trim());
+    let label = match content_language {
+        None => "none",
+        Some(language) => route
+            .languages
+            .keys()
+            .chain(&route.accept_languages)
+            .find(|offered| offered.eq_ignore_ascii_case(language))
+            .map_or("other", String::as_str),
+    };
+    metrics::inc(
+        "responses_by_language_total",
+        &[("route", &route.name), ("content_language", label)],
+    );
+}
@@ -0,0 +1,130 @@
+use crate::config::AdaptiveConcurrencyConfig;
+use crate::metrics;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    // The lowest latency seen since the last reset, taken as the upstream's
+    // latency when it isn't loaded
+    min_latency: Option<Duration>,
+    reset_at: Instant,
+    // The window being collected
+    samples: u32,
+    total: Duration,
+    failed: bool,
+    peak: usize,
+}
+
+// Requests allowed in flight to one upstream, adjusted as its latency moves
+pub struct Limiter {
+    upstream: String,
+    config: AdaptiveConcurrencyConfig,
+    state: Mutex<State>,
+}
+
+// A request's place under the limit, given back when it's dropped
+pub struct Permit<'a> {
+    limiter: Option<&'a Limiter>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.limiter {
+            limiter.state.lock().unwrap().in_flight -= 1;
+        }
+    }
+}
+
+impl Permit<'_> {
+    // For upstreams without a limit
+    pub fn unlimited() -> Self {
+        Permit { limiter: None }
+    }
+}
+
+impl Limiter {
+    pub fn new(upstream: &str, config: AdaptiveConcurrencyConfig) -> Self {
+        let limit = config.initial_limit as f64;
+        metrics::set(
+            "upstream_concurrency_limit",
+            &[("upstream", upstream)],
+            limit,
+        );
+        Limiter {
+            upstream: upstream.to_string(),
+            state: Mutex::new(State {
+                limit,
+                in_flight: 0,
+                min_latency: None,
+                reset_at: Instant::now() + Duration::from_secs(config.min_latency_reset_secs),
+                samples: 0,
+                total: Duration::ZERO,
+                failed: false,
+                peak: 0,
+            }),
+            config,
+        }
+    }
+
+    // None when the upstream already has as many requests as it's allowed
+    pub fn acquire(&self) -> Option<Permit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit as usize {
+            return None;
+        }
+        state.in_flight += 1;
+        state.peak = state.peak.max(state.in_flight);
+        Some(Permit {
+            limiter: Some(self),
+        })
+    }
+
+    pub fn sample(&self, success: bool, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.samples += 1;
+        state.total += latency;
+        state.failed |= !success;
+        if success {
+            state.min_latency = Some(state.min_latency.map_or(latency, |min| min.min(latency)));
+        }
+        if state.samples < self.config.window_requests {
+            return;
+        }
+
+        let mean = state.total / state.samples;
+        let previous = state.limit;
+        let overloaded = state.failed
+            || state
+                .min_latency
+                .is_some_and(|min| mean.as_secs_f64() > min.as_secs_f64() * self.config.tolerance);
+        if overloaded {
+            state.limit *= self.config.backoff;
+        } else if state.peak + 1 >= state.limit as usize {
+            // Only grow a limit that's in the way
+            state.limit += 1.0;
+        }
+        state.limit = state
+            .limit
+            .clamp(self.config.min_limit as f64, self.config.max_limit as f64);
+
+        let now = Instant::now();
+        if now >= state.reset_at {
+            state.min_latency = (!state.failed).then_some(mean);
+            state.reset_at = now + Duration::from_secs(self.config.min_latency_reset_secs);
+        }
+        state.samples = 0;
+        state.total = Duration::ZERO;
+        state.failed = false;
+        state.peak = state.in_flight;
+
+        if state.limit as usize != previous as usize {
+            metrics::set(
+                "upstream_concurrency_limit",
+                &[("upstream", &self.upstream)],
+                (state.limit as usize) as f64,
+            );
+        }
+    }
+}
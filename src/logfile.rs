@@ -0,0 +1,129 @@
+use crate::config::RotationConfig;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// An append-only log file that rotates itself by size and/or age. Rotated
+// files are renamed to <path>.<unix time>[-n], optionally gzipped in the
+// background, and only the newest `keep` are kept.
+pub struct LogFile {
+    path: PathBuf,
+    rotation: RotationConfig,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".gz");
+    let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+impl LogFile {
+    pub fn open(path: &str, rotation: RotationConfig) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let (file, size) = open(&path)?;
+        Ok(LogFile {
+            path,
+            rotation,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let too_big =
+            self.rotation.max_bytes > 0 && self.size + line.len() as u64 > self.rotation.max_bytes;
+        let too_old = self.rotation.max_age_secs > 0
+            && self.opened.elapsed() >= Duration::from_secs(self.rotation.max_age_secs);
+        if (too_big || too_old) && self.size > 0 {
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // Start over on whatever is at the path now, for external rotation such
+    // as logrotate moving the file away
+    pub fn reopen(&mut self) -> io::Result<()> {
+        let (file, size) = open(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let base = format!("{}.{}", self.path.display(), stamp);
+        let mut rotated = PathBuf::from(&base);
+        let mut n = 1;
+        while rotated.exists() || Path::new(&format!("{}.gz", rotated.display())).exists() {
+            rotated = PathBuf::from(format!("{}-{}", base, n));
+            n += 1;
+        }
+
+        fs::rename(&self.path, &rotated)?;
+        self.reopen()?;
+
+        if self.rotation.compress {
+            std::thread::spawn(move || {
+                if let Err(e) = gzip(&rotated) {
+                    eprintln!("Failed to compress {}: {}", rotated.display(), e);
+                }
+            });
+        }
+        self.prune()
+    }
+
+    // Delete the oldest rotated files beyond the retention count
+    fn prune(&self) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path.file_name().unwrap_or_default().to_string_lossy()
+        );
+
+        // Names start with the rotation time, so they sort oldest first
+        let mut rotated: Vec<(u64, PathBuf)> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let stamp = name.strip_prefix(&prefix)?;
+                let digits: String = stamp.chars().take_while(char::is_ascii_digit).collect();
+                Some((digits.parse().ok()?, entry.path()))
+            })
+            .collect();
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.rotation.keep);
+        for (_, path) in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
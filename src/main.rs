@@ -1,109 +1,586 @@
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+mod access_log;
+mod admin;
+mod admin_auth;
+mod alerts;
+mod audit;
+mod auth;
+mod authn;
+mod balancer;
+mod bind;
+mod blocklist;
+mod bots;
+mod buffering;
+mod cache;
+mod certs;
+mod checksum;
+mod cli;
+mod client_ip;
+mod cluster;
+mod compliance;
+mod config;
+mod connections;
+mod context;
+mod cors;
+mod credential_audit;
+mod csrf;
+mod daemon;
+mod dashboard;
+mod deadline;
+mod dedup;
+mod delta;
+mod device;
+mod diagnostics;
+mod disconnect;
+mod discovery;
+mod dns;
+mod early_data;
+mod edge_files;
+mod egress;
+mod egress_policy;
+mod encoding;
+mod error;
+mod events;
+mod fallback;
+mod framing;
+mod geo;
+mod graphql;
+mod grpc;
+mod handoff;
+mod hedge;
+mod hints;
+mod history;
+#[cfg(feature = "http3")]
+mod http3;
+mod inflight;
+mod inject;
+mod kafka;
+mod keys;
+mod kv;
+mod language;
+mod limiter;
+mod logfile;
+mod manifest;
+mod memory;
+mod metering;
+mod metrics;
+mod middleware;
+mod mock;
+mod normalize;
+mod openapi;
+mod pinning;
+mod proxy;
+mod proxy_protocol;
+mod quota;
+mod ranges;
+mod redact;
+mod redirects;
+mod report;
+mod retry;
+mod rollback;
+mod routes;
+mod sampling;
+mod schedule;
+mod script;
+mod selftest;
+mod shedding;
+mod slo;
+mod sniff;
+mod socks;
+mod store;
+mod streams;
+mod syslog;
+mod systemd;
+mod tenant;
+mod timing;
+mod tls;
+mod trailers;
+mod tuning;
+mod tunnel;
+mod upload;
+mod upstream;
+mod upstream_url;
+mod vcr;
+mod waf;
+mod warmup;
+mod wasm;
+mod watermark;
+mod x509;
+
+use access_log::AccessLog;
+use actix_web::{web, App, HttpServer};
+use admin_auth::AdminAuth;
+use audit::AuditLog;
+use auth::JwtValidator;
+use blocklist::Blocklist;
+use bots::Bots;
+use cache::Cache;
+use client_ip::Resolver;
+use cluster::Cluster;
+use config::{Config, IpFamily};
+use credential_audit::CredentialAudit;
 use dotenv::dotenv;
-use reqwest::Client;
-use std::env;
+use edge_files::EdgeFiles;
+use egress_policy::Policy;
+use error::ErrorMapper;
+use geo::Geo;
+use kafka::KafkaSink;
+use memory::Budget;
+use metering::Metering;
+use middleware::Middlewares;
+use proxy::AppState;
+use proxy_protocol::ProxyProtocol;
+use quota::Quota;
+use redact::Redactor;
+use redirects::Redirects;
+use report::Reporter;
+use retry::RetryBudget;
+use routes::RouteTable;
+use sampling::Sampler;
+use serde_json::json;
+use slo::Slos;
 use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use store::Store;
+use streams::Streams;
+use systemd::InheritedSockets;
+use tenant::Tenants;
+use tunnel::ForwardProxy;
+use upstream::Upstreams;
+use vcr::Vcr;
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+fn main() -> Result<(), Error> {
     // Load environment variables from .env file
     dotenv().ok();
 
-    // Load API Gateway URL from environment variables
-    let api_gateway_url = env::var("API_GATEWAY_URL")
-        .expect("API_GATEWAY_URL not found in .env");
+    // `config schema [route]` needs no config of its own
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() >= 2 && args[0] == "config" && args[1] == "schema" {
+        return cli::config_schema(args.get(2));
+    }
+
+    // Load routes and settings from config.toml and the environment
+    let config = Config::load()?;
+
+    // Detach before the runtime starts its threads
+    let daemon = args.iter().any(|arg| arg == "--daemon");
+    #[cfg(not(windows))]
+    if daemon {
+        daemon::detach(&config.daemon)?;
+    }
+    let daemon_config = config.daemon.clone();
+    let takeover = args.iter().any(|arg| arg == "--takeover");
+    let tuning = tuning::init(&config.tuning);
+    let serve = move || {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(tuning.workers)
+            .enable_all()
+            .build()?
+            .block_on(run(config, args))
+    };
+    #[cfg(windows)]
+    let result = match daemon {
+        true => daemon::service::run(&daemon_config, serve),
+        false => serve(),
+    };
+    #[cfg(not(windows))]
+    let result = serve();
+    // A process that took over from a daemon has its pid file
+    if daemon || takeover {
+        daemon::finish(&daemon_config);
+    }
+    result
+}
+
+async fn run(config: Config, args: Vec<String>) -> Result<(), Error> {
+    let store = if config.admin.database_path.is_empty() {
+        None
+    } else {
+        Some(Arc::new(Store::open(&config.admin.database_path)?))
+    };
+    let routes = RouteTable::load(&config, store.clone())?;
+
+    // `routes test <METHOD> <PATH> ...` shows where a request would go
+    if args.len() >= 2 && args[0] == "routes" && args[1] == "test" {
+        return cli::routes_test(&config, &routes.snapshot(), &args[2..]);
+    }
+    // `credential-audit verify [PATH]` checks the log's hash chain
+    if args.len() >= 2 && args[0] == "credential-audit" && args[1] == "verify" {
+        return cli::credential_audit_verify(&config, args.get(2));
+    }
+    // `watermark decode <MARK>` says who a leaked response was served to
+    if args.len() >= 3 && args[0] == "watermark" && args[1] == "decode" {
+        return cli::watermark_decode(&config, &args[2]);
+    }
+
+    // `--print-effective-config` shows what we'd run with, secrets redacted
+    if args.iter().any(|arg| arg == "--print-effective-config") {
+        let routes = routes.snapshot();
+        let effective = json!({
+            "config": diagnostics::effective_config(&config, &routes),
+            "diagnostics": diagnostics::diagnostics(&config, &routes),
+        });
+        println!("{}", serde_json::to_string_pretty(&effective)?);
+        return Ok(());
+    }
 
-    // Use PORT from Render, fallback to SERVER_PORT, default to 8080
-    let server_port = env::var("PORT")
-        .or_else(|_| env::var("SERVER_PORT"))
-        .unwrap_or_else(|_| "8080".to_string());
+    diagnostics::print(&config, &routes.snapshot());
+    trailers::warn(&config, &routes.snapshot());
+    tuning::print(tuning::get());
+    println!("Server running on port: {}", config.server.port);
+    println!("Admin server running on port: {}", config.server.admin_port);
+
+    let server_port = config.server.port;
+    let server_config = config.server.clone();
+    let admin_port = config.server.admin_port;
+    alerts::init(&config.alerts);
+    let tls = config.tls.as_ref().map(tls::server_config).transpose()?;
+    if let Some(tls) = &config.tls {
+        certs::spawn(tls);
+    }
+    let proxy_protocol = config
+        .proxy_protocol
+        .as_ref()
+        .map(ProxyProtocol::new)
+        .transpose()?;
+
+    let jwt = config.jwt.as_ref().map(JwtValidator::new).transpose()?;
+    let kv = kv::open(&config.kv, None).await?;
+    let quota = match config.quota.clone() {
+        Some(quota) => {
+            let store = match &quota.redis_url {
+                Some(url) => kv::open(&config.kv, Some(url)).await?,
+                None => kv.clone(),
+            };
+            Some(Quota::new(quota, store))
+        }
+        None => None,
+    };
+    let streams = match config.streams.clone() {
+        Some(streams) => Some(Streams::new(streams).await?),
+        None => None,
+    };
+
+    let egress = config
+        .egress
+        .as_ref()
+        .map(Policy::new)
+        .transpose()?
+        .map(Arc::new);
+    let client = dns::client(&config.dns, egress.clone())?; // Reqwest client for forwarding requests
+
+    // Usage and report webhooks aren't upstreams, so the egress policy
+    // doesn't apply to them
+    let webhooks = match &egress {
+        Some(_) => dns::builder(&config.dns, IpFamily::Any, None)
+            .build()
+            .map_err(|e| Error::other(format!("HTTP client: {}", e)))?,
+        None => client.clone(),
+    };
+
+    let metering = config
+        .metering
+        .clone()
+        .map(|metering| Arc::new(Metering::new(metering, webhooks.clone())));
+    if let Some(metering) = &metering {
+        metering.spawn_flusher();
+    }
+    let kafka = config
+        .kafka
+        .clone()
+        .map(|kafka| Arc::new(KafkaSink::new(kafka)));
+    if let Some(kafka) = &kafka {
+        kafka.spawn();
+    }
+    let slos = Arc::new(Slos::new(&config.slos));
+    slos.spawn();
+    let reporter = config
+        .report
+        .clone()
+        .map(|report| Reporter::new(report, webhooks.clone()).map(Arc::new))
+        .transpose()?;
+    if let Some(reporter) = &reporter {
+        reporter.spawn();
+    }
+    events::spawn_rate_reporter();
+
+    let redactor = Redactor::new(&config.redaction, &routes.snapshot());
+    let memory = Arc::new(Budget::new(config.server.memory_budget_bytes));
+    let state = web::Data::new(AppState {
+        client,
+        grpc: grpc::client(),
+        cache: Cache::new(config.cache.clone(), memory.clone()),
+        memory,
+        routes,
+        jwt,
+        quota,
+        kv: kv.clone(),
+        streams,
+        bots: config.bots.clone().map(Bots::new).transpose()?,
+        geo: config.geo.as_ref().map(Geo::new).transpose()?,
+        vcr: config.vcr.clone().map(Vcr::new).transpose()?,
+        metering: metering.clone(),
+        reporter,
+        tenants: Tenants::new(&config),
+        client_ip: Resolver::new(config.client_ip.as_ref())?,
+        blocklist: Blocklist::new(&config.block_rules)?,
+        edge_files: EdgeFiles::new(&config.edge_files)?,
+        redirects: Redirects::new(&config.redirects)?,
+        inflight: Default::default(),
+        draining: Default::default(),
+        errors: ErrorMapper::new(config.errors.clone()),
+        retry_budget: RetryBudget::new(config.retry_budget.clone()),
+        upstreams: Upstreams::new(&config, egress.clone())?,
+        egress,
+        dedup: Default::default(),
+        latency: Default::default(),
+        middlewares: Middlewares::new(&config, store.clone(), kv.clone()).await?,
+        audit: AuditLog::new(config.admin.audit_path.clone()),
+        admin_auth: AdminAuth::new(&config.admin)?,
+        store,
+        alt_svc: hints::alt_svc(&config),
+        access_log: config
+            .access_log
+            .as_ref()
+            .map(|access_log| AccessLog::new(access_log, redactor.clone()))
+            .transpose()?,
+        credential_audit: config
+            .credential_audit
+            .as_ref()
+            .map(CredentialAudit::open)
+            .transpose()?,
+        kafka,
+        cluster: config.cluster.as_ref().map(Cluster::new).transpose()?,
+        forward_proxy: config
+            .forward_proxy
+            .as_ref()
+            .map(ForwardProxy::new)
+            .transpose()?,
+        sampler: Sampler::new(config.sampling.clone()),
+        redactor,
+        warming: AtomicBool::new(config.warmup.is_some()),
+        watches: Default::default(),
+        slos: slos.clone(),
+        live: Default::default(),
+        deltas: Default::default(),
+        connections: Default::default(),
+        config,
+    });
+
+    // `--self-test` sends the [self_test] probes through the proxy first and
+    // doesn't start if any fail
+    if args.iter().any(|arg| arg == "--self-test") {
+        selftest::run(state.clone()).await?;
+    }
 
-    println!("API_GATEWAY_URL: {}", api_gateway_url);
-    println!("Server running on port: {}", server_port);
+    #[cfg(unix)]
+    spawn_reopen_on_sigusr1(state.clone());
+
+    // Sockets passed in by systemd socket activation, or by the process
+    // we're taking over from, replace our own binds
+    let (mut inherited, takeover) = match args.iter().any(|arg| arg == "--takeover") {
+        true => {
+            let Some(handoff) = &state.config.handoff else {
+                return Err(Error::other("--takeover needs a [handoff] section"));
+            };
+            let (inherited, takeover) = handoff::take_over(handoff)?;
+            (inherited, Some(takeover))
+        }
+        false => (InheritedSockets::from_env(), None),
+    };
+    // and what we bind ours to is kept to hand over in turn
+    let mut handed = handoff::Listeners::default();
+
+    #[cfg(feature = "http3")]
+    http3::spawn(state.clone())?;
+    grpc::spawn(state.clone(), &mut inherited, &mut handed)?;
+    cluster::spawn(state.clone());
+    discovery::spawn(state.clone())?;
 
     // Start the HTTP server
-    HttpServer::new(move || {
+    let admin_state = state.clone();
+    let state_for_warmup = state.clone();
+    let app = move || proxy::app(state.clone());
+    let mut public = inherited.take("public", Some(0));
+    if public.is_empty() {
+        public = bind::tcp(&server_config.bind_addresses, server_port)?;
+    }
+    handed.keep("public", &public)?;
+    let early_data = tls.as_ref().is_some_and(|tls| tls.max_early_data_size > 0);
+    let server = match (proxy_protocol, early_data) {
+        (None, false) => {
+            let mut server = HttpServer::new(app)
+                // Slowloris: don't hold connections open for clients
+                // trickling headers
+                .client_request_timeout(Duration::from_millis(
+                    server_config.request_header_timeout_ms,
+                ))
+                .h1_allow_half_closed(!server_config.cancel_on_disconnect)
+                .workers(tuning::get().workers)
+                .max_connections(tuning::get().max_connections)
+                .disable_signals();
+            for listener in public {
+                server = match &tls {
+                    Some(tls) => server.listen_rustls_0_23(listener, tls.clone())?,
+                    None => server.listen(listener)?,
+                };
+            }
+            server.run()
+        }
+        (proxy, _) => proxy_protocol::serve(proxy, public, tls, &server_config, app)?,
+    };
+
+    // Metrics and operational endpoints live on a separate port
+    let admin = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(Client::new())) // Reqwest client for forwarding requests
-            .app_data(web::Data::new(api_gateway_url.clone())) // Store API gateway URL
-            .service(web::resource("/{tail:.*}").to(proxy_handler)) // Route all requests
+            .app_data(admin_state.clone())
+            .configure(admin::configure)
     })
-    .bind(format!("0.0.0.0:{}", server_port))?
-    .run()
-    .await
-}
+    .workers(1)
+    .disable_signals();
+    let mut admin_listeners = inherited.take("admin", Some(1));
+    if admin_listeners.is_empty() {
+        admin_listeners = bind::tcp(&server_config.admin_bind_addresses, admin_port)?;
+    }
+    handed.keep("admin", &admin_listeners)?;
+    let admin_addresses: Vec<_> = (admin_listeners.iter())
+        .filter_map(|listener| listener.local_addr().ok())
+        .collect();
+    admin::listening_on(&admin_addresses, &state_for_warmup.admin_auth);
+    let admin = admin_listeners
+        .into_iter()
+        .try_fold(admin, |admin, listener| admin.listen(listener))?
+        .run();
+
+    // Handle shutdown signals ourselves so systemd hears about it first
+    let (server_handle, admin_handle) = (server.handle(), admin.handle());
+    let (handoff_server, handoff_admin) = (server_handle.clone(), admin_handle.clone());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        println!("Shutting down");
+        systemd::notify("STOPPING=1");
+        #[cfg(windows)]
+        daemon::service::stopping();
+        tokio::join!(server_handle.stop(true), admin_handle.stop(true));
+    });
+
+    // Once we're serving: tell systemd, let the process we took over from
+    // go, and wait to be taken over ourselves. A process taken over from
+    // stops accepting and drains like on SIGTERM.
+    let handoff_config = state_for_warmup.config.handoff.clone();
+    let daemon_config = state_for_warmup.config.daemon.clone();
+    let runtime = tokio::runtime::Handle::current();
+    #[cfg(unix)]
+    if let Some(handoff) = &handoff_config {
+        handoff::upgrade_on_sigusr2(handoff, &args);
+    }
+    let ready = move || {
+        systemd::notify("READY=1");
+        #[cfg(windows)]
+        daemon::service::ready();
+        if let Some(takeover) = takeover {
+            if let Err(e) = takeover.ready() {
+                eprintln!("The process we took over from is gone: {}", e);
+                std::process::exit(1);
+            }
+        }
+        let Some(handoff) = handoff_config else {
+            return;
+        };
+        let handed_over = move |pid: u32| {
+            daemon::handed_over(&daemon_config, pid);
+            systemd::notify(&format!("MAINPID={}", pid));
+            runtime.block_on(async {
+                // Connections accepted just now get to send their request
+                // before the workers wind down, as idle ones are closed
+                tokio::join!(handoff_server.pause(), handoff_admin.pause());
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                tokio::join!(handoff_server.stop(true), handoff_admin.stop(true));
+            });
+        };
+        if let Err(e) = handoff::serve(&handoff, handed, handed_over) {
+            eprintln!("{}", e);
+        }
+    };
 
-// Proxy handler function to forward requests or return custom responses
-async fn proxy_handler(
-    client: web::Data<Client>,
-    api_gateway_url: web::Data<String>,
-    req: HttpRequest,
-    body: web::Payload,  // Removed 'mut' since it's not needed
-) -> impl Responder {
-    let path = req.match_info().query("tail");
-    let url = format!("{}/{}", api_gateway_url.get_ref(), path);
-
-    println!("Received {} request for {}", req.method(), path);
-
-    // Handle root endpoint
-    if path.is_empty() {
-        return HttpResponse::Ok()
-            .content_type("text/plain")
-            .body("Netty server deployed by Mujahid in Rust");
-    }
-
-    // Handle CORS preflight requests
-    if req.method() == actix_web::http::Method::OPTIONS {
-        return HttpResponse::Ok()
-            .append_header(("Access-Control-Allow-Origin", "*"))
-            .append_header((
-                "Access-Control-Allow-Methods",
-                "POST, GET, OPTIONS, PUT, DELETE",
-            ))
-            .append_header((
-                "Access-Control-Allow-Headers",
-                "Content-Type, Authorization, Range",
-            ))
-            .finish();
-    }
-
-    // Forward request to API Gateway
-    let forwarded_req = client
-        .request(req.method().clone(), &url)
-        .headers(req.headers().clone().into()) // Convert headers to reqwest's HeaderMap
-        .send()
-        .await;
-
-    // Inside the proxy_handler function, when setting the response headers
-match forwarded_req {
-    Ok(resp) => {
-        let mut response = HttpResponse::build(resp.status());
-
-        // Ensure CORS headers are inserted only once
-        response
-            .insert_header(("Access-Control-Allow-Origin", "*"))
-            .insert_header((
-                "Access-Control-Allow-Methods",
-                "POST, GET, OPTIONS, PUT, DELETE",
-            ))
-            .insert_header((
-                "Access-Control-Allow-Headers",
-                "Content-Type, Authorization, Range",
-            ));
-
-        // Copy all headers from the forwarded response
-        for (key, value) in resp.headers() {
-            response.insert_header((key.clone(), value.clone()));
+    // With warm-up configured, readiness is reported once it's done
+    match state_for_warmup.config.warmup.clone() {
+        Some(warmup) => {
+            let targets = warmup::targets(
+                &state_for_warmup.config,
+                &state_for_warmup.routes.snapshot(),
+            );
+            tokio::spawn(async move {
+                warmup::run(
+                    &state_for_warmup.upstreams,
+                    &state_for_warmup.client,
+                    &warmup,
+                    &targets,
+                )
+                .await;
+                state_for_warmup.warming.store(false, Ordering::Relaxed);
+                ready();
+            });
         }
+        None => ready(),
+    }
+    let result = tokio::try_join!(server, admin);
 
-        // Stream the response body
-        let body = resp.bytes().await.unwrap_or_default();
-        response.body(body)
+    // Don't lose the last partial period of usage on shutdown
+    if let Some(metering) = &metering {
+        metering.flush().await;
     }
-    Err(e) => {
-        eprintln!("Error forwarding request: {}", e);
-        HttpResponse::ServiceUnavailable().body("Service unavailable")
+
+    result.map(|_| ())
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+}
+
+// logrotate moves the file and then signals us to start a new one
+#[cfg(unix)]
+fn spawn_reopen_on_sigusr1(state: web::Data<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(usr1) => usr1,
+        Err(e) => {
+            eprintln!("Failed to install SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            println!("Reopening log files");
+            if let Some(access_log) = &state.access_log {
+                access_log.reopen();
+            }
+            if let Some(audit) = &state.credential_audit {
+                audit.reopen();
+            }
+        }
+    });
+}
+
+// Or the Service Control Manager's stop, when running as a service
+#[cfg(windows)]
+async fn shutdown_signal() {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = daemon::service::stop_requested() => {}
     }
 }
 
+#[cfg(not(any(unix, windows)))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
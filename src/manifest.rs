@@ -0,0 +1,330 @@
+use crate::auth::Claims;
+use crate::config::{ManifestConfig, RouteConfig, VariantLimit};
+use crate::encoding;
+use crate::metrics;
+use actix_web::body;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use serde_json::Value;
+
+// Manifests are read whole to be filtered; one bigger than this is refused
+const MAX_MANIFEST_BYTES: usize = 4 * 1024 * 1024;
+
+// Cuts HLS master playlists and DASH MPDs down to the variants the user's
+// plan allows, by bitrate and picture height, so a free-tier player is
+// never even offered the 4K ones. Once a response is known to be a
+// manifest it never gets through unfiltered: one that can't be read is a
+// 502. Variants that don't state a bitrate or height, such as audio-only
+// ones, are kept.
+
+// Ceilings found for the request by each manifest middleware, by name
+#[derive(Default)]
+struct Limits(Vec<(String, VariantLimit)>);
+
+#[derive(Clone, Copy)]
+enum Format {
+    Hls,
+    Dash,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Hls => "hls",
+            Format::Dash => "dash",
+        }
+    }
+}
+
+// Find the user's ceiling once the chain has its claims; the manifest is
+// cut down to it in filter()
+pub fn limit(name: &str, config: &ManifestConfig, req: &HttpRequest, claims: Option<&Claims>) {
+    let plan = match claims.and_then(|claims| claims.get(&config.plan_claim)) {
+        Some(Value::String(plan)) => Some(plan.clone()),
+        Some(other) => Some(other.to_string()),
+        None => None,
+    };
+    let limit = plan.and_then(|plan| config.plans.get(&plan).copied());
+    let Some(limit) = limit.or(config.default) else {
+        return;
+    };
+    let mut extensions = req.extensions_mut();
+    if !extensions.contains::<Limits>() {
+        extensions.insert(Limits::default());
+    }
+    if let Some(limits) = extensions.get_mut::<Limits>() {
+        limits.0.push((name.to_string(), limit));
+    }
+}
+
+pub async fn filter(
+    name: &str,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    mut response: HttpResponse,
+) -> HttpResponse {
+    if !response.status().is_success() {
+        return response;
+    }
+    let Some(format) = format(req, &response) else {
+        return response;
+    };
+    // What's in it depends on who asked
+    private(response.headers_mut());
+    let limit = req.extensions().get::<Limits>().and_then(|limits| {
+        (limits.0.iter())
+            .find(|(found_by, _)| found_by == name)
+            .map(|(_, limit)| *limit)
+    });
+    let Some(limit) = limit else {
+        return response;
+    };
+
+    let refuse = |reason: &str| {
+        eprintln!(
+            "Route {}: refusing a {} manifest that can't be filtered ({})",
+            route.name,
+            format.name(),
+            reason
+        );
+        metrics::inc(
+            "manifest_filter_errors_total",
+            &[("route", &route.name), ("reason", reason)],
+        );
+        HttpResponse::BadGateway().body("Bad gateway")
+    };
+    let (mut head, body) = response.into_parts();
+    let bytes = match body::to_bytes_limited(body, MAX_MANIFEST_BYTES).await {
+        Ok(Ok(bytes)) => bytes,
+        Ok(Err(_)) => return refuse("read"),
+        Err(_) => return refuse("too_large"),
+    };
+    // Cached variants can be compressed; the manifest goes out plain and is
+    // compressed again later if the route does that
+    let coding = (head.headers().get(header::CONTENT_ENCODING))
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|coding| coding != "identity");
+    let bytes = match coding {
+        Some(coding) => match encoding::decode(&coding, &bytes) {
+            Some(Ok(decoded)) => decoded,
+            _ => return refuse("encoded"),
+        },
+        None => bytes.to_vec(),
+    };
+    let Ok(text) = String::from_utf8(bytes) else {
+        return refuse("not_utf8");
+    };
+
+    let (filtered, removed) = match format {
+        Format::Hls => hls(&text, &limit),
+        Format::Dash => dash(&text, &limit),
+    };
+    head.headers_mut().remove(header::CONTENT_ENCODING);
+    head.headers_mut().remove(header::CONTENT_LENGTH);
+    metrics::inc(
+        "manifests_filtered_total",
+        &[("route", &route.name), ("format", format.name())],
+    );
+    metrics::add(
+        "manifest_variants_removed_total",
+        &[("route", &route.name)],
+        removed as f64,
+    );
+    head.set_body(filtered).map_into_boxed_body()
+}
+
+// By its Content-Type, or by the path for upstreams that send manifests as
+// text/plain or octet-stream
+fn format(req: &HttpRequest, response: &HttpResponse) -> Option<Format> {
+    let content_type = (response.headers().get(header::CONTENT_TYPE))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    match content_type.as_deref() {
+        Some(
+            "application/vnd.apple.mpegurl"
+            | "application/x-mpegurl"
+            | "audio/mpegurl"
+            | "audio/x-mpegurl",
+        ) => return Some(Format::Hls),
+        Some("application/dash+xml") => return Some(Format::Dash),
+        _ => {}
+    }
+    let path = req.path().to_ascii_lowercase();
+    if path.ends_with(".m3u8") {
+        Some(Format::Hls)
+    } else if path.ends_with(".mpd") {
+        Some(Format::Dash)
+    } else {
+        None
+    }
+}
+
+// Shared caches beyond us mustn't hand one user's manifest to another
+fn private(headers: &mut HeaderMap) {
+    let current = (headers.get(header::CACHE_CONTROL))
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let kept = current.split(',').map(str::trim).filter(|directive| {
+        let directive = directive.to_ascii_lowercase();
+        !directive.is_empty()
+            && directive != "public"
+            && directive != "private"
+            && !directive.starts_with("s-maxage")
+    });
+    let value: Vec<&str> = std::iter::once("private").chain(kept).collect();
+    if let Ok(value) = HeaderValue::from_str(&value.join(", ")) {
+        headers.insert(header::CACHE_CONTROL, value);
+    }
+}
+
+fn over(limit: &VariantLimit, bandwidth: Option<u64>, height: Option<u64>) -> bool {
+    let above = |max: Option<u64>, value: Option<u64>| max.zip(value).is_some_and(|(m, v)| v > m);
+    above(limit.max_bandwidth, bandwidth) || above(limit.max_height, height)
+}
+
+// NAME=value from an HLS attribute list, where values may be quoted strings
+// with commas in them
+fn hls_attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                let next = &quoted[end + 1..];
+                (&quoted[..end], next.strip_prefix(',').unwrap_or(next))
+            }
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        if key.trim() == name {
+            return Some(value.trim());
+        }
+        if next.is_empty() {
+            return None;
+        }
+        rest = next;
+    }
+}
+
+// The master playlist without the variant streams (the tag and the URI line
+// after it) and I-frame streams over the limit
+fn hls(text: &str, limit: &VariantLimit) -> (String, usize) {
+    let over_limit = |attributes: &str| {
+        let bandwidth = hls_attribute(attributes, "BANDWIDTH").and_then(|b| b.parse().ok());
+        let height = hls_attribute(attributes, "RESOLUTION")
+            .and_then(|r| r.split_once('x'))
+            .and_then(|(_, h)| h.parse().ok());
+        over(limit, bandwidth, height)
+    };
+    let mut filtered = String::with_capacity(text.len());
+    let mut removed = 0;
+    // Dropping a variant's tag, until its URI
+    let mut dropping = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if dropping {
+            if trimmed.is_empty() {
+                continue;
+            }
+            dropping = false;
+            if !trimmed.starts_with('#') {
+                continue;
+            }
+        }
+        if let Some(attributes) = trimmed.strip_prefix("#EXT-X-STREAM-INF:") {
+            if over_limit(attributes) {
+                removed += 1;
+                dropping = true;
+                continue;
+            }
+        }
+        if let Some(attributes) = trimmed.strip_prefix("#EXT-X-I-FRAME-STREAM-INF:") {
+            if over_limit(attributes) {
+                removed += 1;
+                continue;
+            }
+        }
+        filtered.push_str(line);
+    }
+    (filtered, removed)
+}
+
+// name="value" (or 'value') from an XML start tag
+fn xml_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let preceded = rest[..at].ends_with(char::is_whitespace);
+        let after = &rest[at + name.len()..];
+        rest = after;
+        let Some(value) = after.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        if !preceded {
+            continue;
+        }
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|q| *q == '"' || *q == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+// The MPD without the Representations over the limit. A height given on the
+// AdaptationSet holds for the Representations in it that don't give their own.
+fn dash(text: &str, limit: &VariantLimit) -> (String, usize) {
+    let number = |tag: &str, name: &str| xml_attribute(tag, name).and_then(|v| v.parse().ok());
+    let mut filtered = String::with_capacity(text.len());
+    let mut removed = 0;
+    let mut set_height = None;
+    let mut pos = 0;
+    while let Some(offset) = text[pos..].find('<') {
+        let start = pos + offset;
+        let Some(length) = text[start..].find('>') else {
+            break;
+        };
+        let tag_end = start + length + 1;
+        let tag = &text[start..tag_end];
+        let element = tag[1..]
+            .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .next()
+            .unwrap_or("");
+        // Namespaced or not
+        match element.rsplit(':').next().unwrap_or("") {
+            "AdaptationSet" => set_height = number(tag, "height"),
+            "Representation" => {
+                let bandwidth = number(tag, "bandwidth");
+                let height = number(tag, "height").or(set_height);
+                if over(limit, bandwidth, height) {
+                    let end = match tag.ends_with("/>") {
+                        true => Some(tag_end),
+                        false => {
+                            let close = format!("</{}>", element);
+                            (text[tag_end..].find(&close)).map(|at| tag_end + at + close.len())
+                        }
+                    };
+                    if let Some(end) = end {
+                        // Along with its indentation and line
+                        let before = text[pos..start].trim_end_matches([' ', '\t']);
+                        filtered.push_str(before);
+                        pos = end;
+                        if text[pos..].starts_with("\r\n") {
+                            pos += 2;
+                        } else if text[pos..].starts_with('\n') {
+                            pos += 1;
+                        }
+                        removed += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => {}
+        }
+        filtered.push_str(&text[pos..tag_end]);
+        pos = tag_end;
+    }
+    filtered.push_str(&text[pos..]);
+    (filtered, removed)
+}
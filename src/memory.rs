@@ -0,0 +1,155 @@
+use crate::metrics;
+use actix_web::{HttpMessage, HttpRequest};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Request,
+    Response,
+    Cache,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Request => "request",
+            Kind::Response => "response",
+            Kind::Cache => "cache",
+        }
+    }
+}
+
+// Bytes held in memory for in-flight bodies and the cache, against the
+// [server] memory_budget_bytes. Past the budget, new buffering is refused
+// rather than letting the proxy grow until the container is killed.
+pub struct Budget {
+    // 0 = only counted, never refused
+    limit: usize,
+    used: AtomicUsize,
+    kinds: [AtomicUsize; 3],
+}
+
+impl Budget {
+    pub fn new(limit: usize) -> Self {
+        metrics::set("memory_budget_bytes", &[], limit as f64);
+        for kind in [Kind::Request, Kind::Response, Kind::Cache] {
+            metrics::set("memory_buffered_bytes", &[("kind", kind.name())], 0.0);
+        }
+        Budget {
+            limit,
+            used: AtomicUsize::new(0),
+            kinds: Default::default(),
+        }
+    }
+
+    // Whether `bytes` more would still be under the budget
+    pub fn fits(&self, bytes: usize) -> bool {
+        self.limit == 0 || self.used.load(Ordering::Relaxed) + bytes <= self.limit
+    }
+
+    // Count `bytes` more, unless they'd take it over the budget
+    pub fn try_add(&self, kind: Kind, bytes: usize) -> bool {
+        let added = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                (self.limit == 0 || used + bytes <= self.limit).then_some(used + bytes)
+            })
+            .is_ok();
+        if added {
+            let held = self.kinds[kind as usize].fetch_add(bytes, Ordering::Relaxed) + bytes;
+            metrics::set(
+                "memory_buffered_bytes",
+                &[("kind", kind.name())],
+                held as f64,
+            );
+        }
+        added
+    }
+
+    pub fn release(&self, kind: Kind, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+        let held = self.kinds[kind as usize].fetch_sub(bytes, Ordering::Relaxed) - bytes;
+        metrics::set(
+            "memory_buffered_bytes",
+            &[("kind", kind.name())],
+            held as f64,
+        );
+    }
+
+    // Room for a body being buffered, grown as it arrives and handed back
+    // when dropped. None when even `bytes` doesn't fit.
+    pub fn reserve(self: &Arc<Self>, kind: Kind, bytes: usize) -> Option<Reservation> {
+        let mut reservation = Reservation {
+            budget: self.clone(),
+            kind,
+            bytes: 0,
+        };
+        reservation.ensure(bytes).then_some(reservation)
+    }
+}
+
+pub struct Reservation {
+    budget: Arc<Budget>,
+    kind: Kind,
+    bytes: usize,
+}
+
+impl Reservation {
+    // Make room for `total` bytes in all, counting a refusal
+    pub fn ensure(&mut self, total: usize) -> bool {
+        let bytes = total.saturating_sub(self.bytes);
+        if bytes == 0 {
+            return true;
+        }
+        if !self.budget.try_add(self.kind, bytes) {
+            metrics::inc(
+                "memory_budget_rejected_total",
+                &[("kind", self.kind.name())],
+            );
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.kind, self.bytes);
+    }
+}
+
+// Reservations kept until the request they were made for is finished with
+struct Held(Vec<Reservation>);
+
+pub fn hold(req: &HttpRequest, reservation: Reservation) {
+    let mut extensions = req.extensions_mut();
+    match extensions.get_mut::<Held>() {
+        Some(held) => held.0.push(reservation),
+        None => {
+            extensions.insert(Held(vec![reservation]));
+        }
+    }
+}
+
+// The process's resident and peak resident memory, to set the buffered
+// bytes against. Linux only; elsewhere they're left out.
+pub fn report_process() {
+    let Ok(status) = fs::read_to_string("/proc/self/status") else {
+        return;
+    };
+    let kb = |field: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<f64>().ok())
+    };
+    if let Some(rss) = kb("VmRSS:") {
+        metrics::set("process_resident_memory_bytes", &[], rss * 1024.0);
+    }
+    if let Some(peak) = kb("VmHWM:") {
+        metrics::set("process_resident_memory_peak_bytes", &[], peak * 1024.0);
+    }
+}
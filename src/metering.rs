@@ -0,0 +1,395 @@
+use crate::authn::ApiKeyId;
+use crate::config::{DownloadsConfig, MeteringConfig, RouteConfig, SinkConfig};
+use crate::kafka::Publisher;
+use crate::keys;
+use crate::metrics;
+use crate::ranges;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Default)]
+struct Usage {
+    requests: u64,
+    egress_bytes: u64,
+}
+
+// One billing record per tenant and flush period
+#[derive(Serialize)]
+struct UsageRecord<'a> {
+    tenant: &'a str,
+    period_start: u64,
+    period_end: u64,
+    requests: u64,
+    egress_bytes: u64,
+}
+
+// How much of a title went out in a period. Only totals are kept, never
+// who downloaded what.
+#[derive(Default)]
+struct TitleUsage {
+    requests: u64,
+    // 200s and ranges covering the whole file, sent to the end
+    full_downloads: u64,
+    // Seeks and resumed downloads
+    partial_requests: u64,
+    // Responses the client stopped reading before the end
+    cut_off: u64,
+    bytes: u64,
+    partial_bytes: u64,
+    // Bytes sent from each equal part of the file, front to back
+    segment_bytes: Vec<u64>,
+}
+
+// One record per route, title and flush period
+#[derive(Serialize)]
+struct TitleRecord<'a> {
+    route: &'a str,
+    title: &'a str,
+    period_start: u64,
+    period_end: u64,
+    requests: u64,
+    full_downloads: u64,
+    partial_requests: u64,
+    cut_off: u64,
+    bytes: u64,
+    partial_bytes: u64,
+    segment_bytes: &'a [u64],
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Record<'a> {
+    Usage(UsageRecord<'a>),
+    Title(TitleRecord<'a>),
+}
+
+// The part of a file a response to a downloads route carries, taken
+// before its body goes out and recorded once it has
+pub struct Download {
+    route: String,
+    title: String,
+    segments: usize,
+    partial: bool,
+    // First byte and size of the whole file, when the response tells;
+    // multipart ranges don't
+    start: Option<u64>,
+    size: Option<u64>,
+    // What the response declares it carries
+    expected: Option<u64>,
+}
+
+struct Period {
+    start: u64,
+    usage: HashMap<String, Usage>,
+    titles: HashMap<(String, String), TitleUsage>,
+}
+
+// Aggregates per-tenant traffic and periodically flushes it to a sink for billing
+pub struct Metering {
+    config: MeteringConfig,
+    client: Client,
+    // For a Kafka sink
+    kafka: Option<Publisher>,
+    period: Mutex<Period>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Metering {
+    pub fn new(config: MeteringConfig, client: Client) -> Self {
+        let kafka = match &config.sink {
+            SinkConfig::Kafka(kafka) => Some(Publisher::new(kafka.clone())),
+            _ => None,
+        };
+        Metering {
+            config,
+            client,
+            kafka,
+            period: Mutex::new(Period {
+                start: now_secs(),
+                usage: HashMap::new(),
+                titles: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn key_header(&self) -> &str {
+        &self.config.key_header
+    }
+
+    pub fn record(&self, tenant: &str, egress_bytes: u64) {
+        let mut period = self.period.lock().unwrap();
+        let mut tenant = tenant.to_string();
+        if !period.usage.contains_key(&tenant) && period.usage.len() >= self.config.max_tenants {
+            tenant = "(other)".to_string();
+        }
+        let usage = period.usage.entry(tenant).or_default();
+        usage.requests += 1;
+        usage.egress_bytes += egress_bytes;
+    }
+
+    // Count a download once its body is out, `sent` being the bytes that
+    // made it to the client
+    pub fn record_download(&self, download: &Download, sent: u64) {
+        let cut_off = download.expected.is_some_and(|expected| sent < expected);
+        let full = !download.partial && !cut_off;
+        let kind = match (cut_off, download.partial) {
+            (true, _) => "cut_off",
+            (false, true) => "partial",
+            (false, false) => "full",
+        };
+        metrics::inc(
+            "download_requests_total",
+            &[("route", &download.route), ("kind", kind)],
+        );
+        metrics::add(
+            "download_bytes_total",
+            &[("route", &download.route), ("kind", kind)],
+            sent as f64,
+        );
+
+        let mut period = self.period.lock().unwrap();
+        let mut key = (download.route.clone(), download.title.clone());
+        if !period.titles.contains_key(&key) && period.titles.len() >= self.config.max_titles {
+            key.1 = "(other)".to_string();
+        }
+        let usage = period.titles.entry(key).or_default();
+        usage.requests += 1;
+        usage.bytes += sent;
+        if full {
+            usage.full_downloads += 1;
+        }
+        if download.partial {
+            usage.partial_requests += 1;
+            usage.partial_bytes += sent;
+        }
+        if cut_off {
+            usage.cut_off += 1;
+        }
+        if let (Some(start), Some(size)) = (download.start, download.size) {
+            spread(
+                &mut usage.segment_bytes,
+                download.segments,
+                start,
+                sent,
+                size,
+            );
+        }
+    }
+
+    // Flush on a timer until the process exits
+    pub fn spawn_flusher(self: &Arc<Self>) {
+        let metering = self.clone();
+        let interval = Duration::from_secs(self.config.flush_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                metering.flush().await;
+            }
+        });
+    }
+
+    // Write out the current period and start a new one
+    pub async fn flush(&self) {
+        let (start, usage, titles) = {
+            let mut period = self.period.lock().unwrap();
+            let start = std::mem::replace(&mut period.start, now_secs());
+            (
+                start,
+                std::mem::take(&mut period.usage),
+                std::mem::take(&mut period.titles),
+            )
+        };
+        if usage.is_empty() && titles.is_empty() {
+            return;
+        }
+
+        let end = now_secs();
+        let mut records: Vec<Record> = usage
+            .iter()
+            .map(|(tenant, usage)| {
+                Record::Usage(UsageRecord {
+                    tenant,
+                    period_start: start,
+                    period_end: end,
+                    requests: usage.requests,
+                    egress_bytes: usage.egress_bytes,
+                })
+            })
+            .collect();
+        records.extend(titles.iter().map(|((route, title), usage)| {
+            Record::Title(TitleRecord {
+                route,
+                title,
+                period_start: start,
+                period_end: end,
+                requests: usage.requests,
+                full_downloads: usage.full_downloads,
+                partial_requests: usage.partial_requests,
+                cut_off: usage.cut_off,
+                bytes: usage.bytes,
+                partial_bytes: usage.partial_bytes,
+                segment_bytes: &usage.segment_bytes,
+            })
+        }));
+
+        let result = match &self.config.sink {
+            SinkConfig::File { path } => write_lines(path, &records),
+            SinkConfig::Kafka(_) => match &self.kafka {
+                Some(kafka) => {
+                    let values = (records.iter())
+                        .map(|record| serde_json::to_vec(record).unwrap_or_default())
+                        .collect();
+                    kafka.send(values).await.map_err(|e| e.to_string())
+                }
+                None => Err("no Kafka producer".to_string()),
+            },
+            SinkConfig::Http { url } => self
+                .client
+                .post(url)
+                .json(&records)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        };
+
+        match result {
+            Ok(()) => metrics::add("metering_records_flushed_total", &[], records.len() as f64),
+            Err(e) => {
+                eprintln!("Failed to flush usage records: {}", e);
+                metrics::inc("metering_flush_errors_total", &[]);
+
+                // Keep the numbers so the next flush retries them
+                drop(records);
+                let mut period = self.period.lock().unwrap();
+                period.start = period.start.min(start);
+                for (tenant, lost) in usage {
+                    let current = period.usage.entry(tenant).or_default();
+                    current.requests += lost.requests;
+                    current.egress_bytes += lost.egress_bytes;
+                }
+                for (key, lost) in titles {
+                    let current = period.titles.entry(key).or_default();
+                    current.requests += lost.requests;
+                    current.full_downloads += lost.full_downloads;
+                    current.partial_requests += lost.partial_requests;
+                    current.cut_off += lost.cut_off;
+                    current.bytes += lost.bytes;
+                    current.partial_bytes += lost.partial_bytes;
+                    if current.segment_bytes.len() < lost.segment_bytes.len() {
+                        current.segment_bytes.resize(lost.segment_bytes.len(), 0);
+                    }
+                    for (current, lost) in current.segment_bytes.iter_mut().zip(lost.segment_bytes)
+                    {
+                        *current += lost;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Who a request without a tenant is billed to: the API key it was let in
+// with, by id, or else a hash of the one it sent, as records mustn't carry
+// keys themselves
+pub fn account(req: &HttpRequest, key_header: &str) -> String {
+    if let Some(ApiKeyId(id)) = req.extensions().get::<ApiKeyId>() {
+        return format!("key:{}", id);
+    }
+    match req.headers().get(key_header) {
+        Some(key) => format!("sha256:{}", &keys::hash(key.as_bytes())[..16]),
+        None => "anonymous".to_string(),
+    }
+}
+
+// Add bytes start..start+sent of a file to the parts of it they fall in.
+// Parts are fractions of the file, so renditions of different sizes add up.
+fn spread(segment_bytes: &mut Vec<u64>, segments: usize, start: u64, sent: u64, size: u64) {
+    if size == 0 || sent == 0 {
+        return;
+    }
+    segment_bytes.resize(segments.max(segment_bytes.len()), 0);
+    let (n, end) = (segments as u64, start.saturating_add(sent).min(size));
+    for (i, bytes) in segment_bytes.iter_mut().enumerate().take(segments) {
+        let (from, to) = (size * i as u64 / n, size * (i as u64 + 1) / n);
+        *bytes += to.min(end).saturating_sub(from.max(start));
+    }
+}
+
+// What a response on a downloads route carries, for record_download().
+// None for anything but a GET answered with content.
+pub fn download(
+    config: &DownloadsConfig,
+    route: &RouteConfig,
+    req: &HttpRequest,
+    response: &HttpResponse,
+) -> Option<Download> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    let captures = route.compiled.path.as_ref()?.captures(req.path())?;
+    let title: String = captures
+        .name(&config.title)?
+        .as_str()
+        .chars()
+        .take(128)
+        .collect();
+    let headers = response.headers();
+    let value = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let expected = match response.body().size() {
+        BodySize::Sized(n) => Some(n),
+        _ => value(header::CONTENT_LENGTH).and_then(|v| v.parse().ok()),
+    };
+    let (partial, start, size) = match response.status() {
+        StatusCode::OK => (false, Some(0), expected),
+        StatusCode::PARTIAL_CONTENT => {
+            match value(header::CONTENT_RANGE).and_then(ranges::content_range) {
+                // A range that happens to cover the whole file is a full download
+                Some((start, end, size)) => (start > 0 || end + 1 < size, Some(start), Some(size)),
+                None => (true, None, None),
+            }
+        }
+        _ => return None,
+    };
+    Some(Download {
+        route: route.name.clone(),
+        title,
+        segments: config.segments,
+        partial,
+        start,
+        size,
+        expected,
+    })
+}
+
+fn write_lines(path: &str, records: &[Record]) -> Result<(), String> {
+    let mut out = String::new();
+    for record in records {
+        out.push_str(&serde_json::to_string(record).map_err(|e| e.to_string())?);
+        out.push('\n');
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(out.as_bytes()))
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,125 @@
+use crate::config::RouteConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock, RwLock};
+
+// Minimal Prometheus-style registry shared by the whole process. Series that
+// end in _total are exported as counters, everything else as gauges.
+type Labels = Vec<(String, String)>;
+
+static REGISTRY: OnceLock<Mutex<BTreeMap<&'static str, BTreeMap<Labels, f64>>>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<BTreeMap<&'static str, BTreeMap<Labels, f64>>> {
+    REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+// Each route's tags, added to every series labelled with the route
+static ROUTE_TAGS: OnceLock<RwLock<HashMap<String, Labels>>> = OnceLock::new();
+
+fn route_tags() -> &'static RwLock<HashMap<String, Labels>> {
+    ROUTE_TAGS.get_or_init(Default::default)
+}
+
+// Called whenever the routing table changes
+pub fn set_route_tags(routes: &[RouteConfig]) {
+    let tags = routes
+        .iter()
+        .filter(|route| !route.tags.is_empty())
+        .map(|route| {
+            let tags = (route.tags.iter())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            (route.name.clone(), tags)
+        })
+        .collect();
+    *route_tags().write().unwrap() = tags;
+}
+
+pub fn route_tag(route: &str, tag: &str) -> Option<String> {
+    let tags = route_tags().read().unwrap();
+    let found = tags.get(route)?.iter().find(|(k, _)| k == tag)?;
+    Some(found.1.clone())
+}
+
+fn to_labels(labels: &[(&str, &str)]) -> Labels {
+    let mut labels: Labels = labels
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let route = labels.iter().find(|(k, _)| k == "route").map(|(_, v)| v);
+    if let Some(route) = route {
+        let tags = route_tags().read().unwrap();
+        if let Some(tags) = tags.get(route) {
+            // The metric's own labels win over tags of the same name
+            let extra: Labels = (tags.iter())
+                .filter(|(tag, _)| !labels.iter().any(|(k, _)| k == tag))
+                .cloned()
+                .collect();
+            labels.extend(extra);
+        }
+    }
+    labels.sort();
+    labels
+}
+
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+pub fn add(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut registry = registry().lock().unwrap();
+    *registry
+        .entry(name)
+        .or_default()
+        .entry(to_labels(labels))
+        .or_insert(0.0) += value;
+}
+
+pub fn set(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let mut registry = registry().lock().unwrap();
+    registry
+        .entry(name)
+        .or_default()
+        .insert(to_labels(labels), value);
+}
+
+// Each series of one metric and its value, for reading the numbers back
+pub fn series(name: &str) -> Vec<(Labels, f64)> {
+    let registry = registry().lock().unwrap();
+    registry
+        .get(name)
+        .map(|series| series.iter().map(|(k, v)| (k.clone(), *v)).collect())
+        .unwrap_or_default()
+}
+
+// Render every series in the Prometheus text exposition format
+pub fn render() -> String {
+    let registry = registry().lock().unwrap();
+    let mut out = String::new();
+
+    for (name, series) in registry.iter() {
+        let kind = if name.ends_with("_total") {
+            "counter"
+        } else {
+            "gauge"
+        };
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+        for (labels, value) in series {
+            if labels.is_empty() {
+                let _ = writeln!(out, "{} {}", name, value);
+            } else {
+                let labels = labels
+                    .iter()
+                    .map(|(k, v)| {
+                        format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\""))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        }
+    }
+
+    out
+}
@@ -0,0 +1,849 @@
+use crate::access_log::Served;
+use crate::auth::{self, Claims};
+use crate::authn::Authenticators;
+use crate::checksum::Verifier;
+use crate::config::{Config, MiddlewareConfig, RouteConfig, ServerConfig};
+use crate::csrf;
+use crate::device;
+use crate::error::ProxyError;
+use crate::framing;
+use crate::graphql;
+use crate::inject::Injector;
+use crate::keys;
+use crate::kv::KvStore;
+use crate::manifest;
+use crate::memory::{self, Kind};
+use crate::metrics;
+use crate::openapi::{self, Spec};
+use crate::proxy::AppState;
+use crate::quota::QuotaStatus;
+use crate::script::Script;
+use crate::selftest;
+use crate::store::Store;
+use crate::tenant::RateLimiter;
+use crate::upload::Uploader;
+use crate::waf::Waf;
+use crate::wasm::WasmFilter;
+use crate::watermark::Watermarker;
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{rt, web, HttpRequest, HttpResponse};
+use futures_util::StreamExt;
+use ring::digest;
+use std::collections::HashMap;
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The legacy route flags behave like these middlewares at the front of the chain
+static JWT: MiddlewareConfig = MiddlewareConfig::Jwt { optional: false };
+static QUOTA: MiddlewareConfig = MiddlewareConfig::Quota;
+
+// The request or response as a WASM filter or script sees and changes it
+pub struct Message {
+    pub method: String,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub status: u16,
+    // Only meaningful for requests
+    pub upstream: String,
+}
+
+// The request as it should be forwarded, after the chain has run
+pub struct Outcome {
+    pub quota: Option<QuotaStatus>,
+    pub upstream: String,
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: web::Bytes,
+    // Still unread when nothing in the chain needed the body, so it can be
+    // streamed upstream; `body` is empty then
+    pub payload: Option<web::Payload>,
+    // GraphQL operation names, comma separated for batches
+    pub operation: Option<String>,
+    // Hash of the token subject, when the route's auth gives one
+    pub subject: Option<String>,
+    // The subject, for routes caching per user
+    pub cache_partition: Option<String>,
+}
+
+// Runs each route's middleware chain
+pub struct Middlewares {
+    config: HashMap<String, MiddlewareConfig>,
+    limiters: HashMap<String, RateLimiter>,
+    filters: HashMap<String, WasmFilter>,
+    scripts: HashMap<String, Script>,
+    specs: HashMap<String, Spec>,
+    wafs: HashMap<String, Waf>,
+    watermarkers: HashMap<String, Watermarker>,
+    uploaders: HashMap<String, Uploader>,
+    injectors: HashMap<String, Injector>,
+    pub authenticators: Authenticators,
+    quota_enabled: bool,
+}
+
+// The request body, read lazily unless routing already needed it
+pub enum RequestBody {
+    Unread(web::Payload),
+    Read(web::Bytes),
+}
+
+// The declared body length. One over the limit is refused before reading
+// any of the body.
+pub fn check_length(
+    state: &AppState,
+    req: &HttpRequest,
+) -> Result<Option<usize>, Box<HttpResponse>> {
+    let limit = state.config.server.max_request_body_bytes;
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        let error = ProxyError::BodyTooLarge { limit };
+        eprintln!("Rejected request body: {}", error);
+        return Err(Box::new(state.errors.response(&error)));
+    }
+    Ok(declared)
+}
+
+// The next chunk of the body, or Err once the client has been sending it
+// too slowly: after the grace period it has to keep up the minimum rate
+async fn next_chunk(
+    server: &ServerConfig,
+    payload: &mut web::Payload,
+    started: Instant,
+    received: usize,
+) -> Result<Option<Result<web::Bytes, PayloadError>>, ()> {
+    if server.min_body_bytes_per_sec == 0 {
+        return Ok(payload.next().await);
+    }
+    // Enough time for what we have so far at the minimum rate
+    let allowed = Duration::from_secs(server.body_grace_secs)
+        + Duration::from_secs_f64(received as f64 / server.min_body_bytes_per_sec as f64);
+    match tokio::time::timeout_at((started + allowed).into(), payload.next()).await {
+        Ok(chunk) => Ok(chunk),
+        Err(_) => {
+            println!(
+                "Dropping slow client after {} body bytes in {:?}",
+                received,
+                started.elapsed()
+            );
+            metrics::inc("slow_clients_dropped_total", &[("phase", "body")]);
+            Err(())
+        }
+    }
+}
+
+// Read the whole request body, dropping clients that send it too slowly
+pub async fn read_body(
+    state: &AppState,
+    req: &HttpRequest,
+    mut payload: web::Payload,
+) -> Result<web::Bytes, HttpResponse> {
+    let declared = check_length(state, req).map_err(|response| *response)?;
+    let server = &state.config.server;
+    let limit = server.max_request_body_bytes;
+    let started = Instant::now();
+    // Sized up front when the length is known, so it's never copied to grow
+    let capacity = declared.unwrap_or(0).min(limit);
+    let over_budget = || {
+        eprintln!("Rejected request body: {}", ProxyError::MemoryBudget);
+        state.errors.response(&ProxyError::MemoryBudget)
+    };
+    let mut reservation = state
+        .memory
+        .reserve(Kind::Request, capacity)
+        .ok_or_else(over_budget)?;
+    let mut body = web::BytesMut::with_capacity(capacity);
+    let verifier = request_verifier(state, req);
+
+    let error = loop {
+        let chunk = match next_chunk(server, &mut payload, started, body.len()).await {
+            Ok(chunk) => chunk,
+            Err(()) => {
+                return Err(HttpResponse::RequestTimeout()
+                    .force_close()
+                    .body("Request body too slow"))
+            }
+        };
+        match chunk {
+            Some(Ok(chunk)) if body.len() + chunk.len() > limit => {
+                break ProxyError::BodyTooLarge { limit };
+            }
+            Some(Ok(chunk)) => {
+                if !reservation.ensure(body.len() + chunk.len()) {
+                    return Err(over_budget());
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Some(Err(e)) => break ProxyError::Other(format!("reading request body: {}", e)),
+            None => {
+                if let Some(mut verifier) = verifier {
+                    verifier.update(&body);
+                    if let Err(algorithm) = verifier.finish() {
+                        break ProxyError::BodyChecksum(algorithm);
+                    }
+                }
+                memory::hold(req, reservation);
+                return Ok(body.freeze());
+            }
+        }
+    };
+    eprintln!("Rejected request body: {}", error);
+    Err(state.errors.response(&error))
+}
+
+// Checks for the request's body, when it declares a digest
+pub fn request_verifier(state: &AppState, req: &HttpRequest) -> Option<Verifier> {
+    Verifier::new(state.config.checksums.as_ref(), "request", |name| {
+        let value = req.headers().get(name)?;
+        value.to_str().ok().map(str::to_string)
+    })
+}
+
+// Relay the request body upstream chunk by chunk as the client sends it,
+// under the same size, slow-client and checksum rules as read_body. The
+// chunks are handed on as they are, without copying. A client that breaks a
+// rule has its upstream request aborted, and the error is left in the
+// returned slot.
+pub fn stream_body<B: From<hyper::Body>>(
+    server: &ServerConfig,
+    mut payload: web::Payload,
+    mut verifier: Option<Verifier>,
+) -> (B, Arc<Mutex<Option<ProxyError>>>) {
+    let (mut sender, body) = hyper::Body::channel();
+    let server = server.clone();
+    let aborted = Arc::new(Mutex::new(None));
+    let reason = aborted.clone();
+    rt::spawn(async move {
+        let limit = server.max_request_body_bytes;
+        let started = Instant::now();
+        let mut sent = 0;
+        // With a digest to check, the last chunk is held back until it has
+        // been, so the upstream never sees a corrupt body complete
+        let mut held: Option<web::Bytes> = None;
+        loop {
+            let chunk = match next_chunk(&server, &mut payload, started, sent).await {
+                Ok(chunk) => chunk,
+                Err(()) => {
+                    let error = ProxyError::Other("request body too slow".to_string());
+                    *reason.lock().unwrap() = Some(error);
+                    return sender.abort();
+                }
+            };
+            match chunk {
+                Some(Ok(chunk)) if sent + chunk.len() > limit => {
+                    *reason.lock().unwrap() = Some(ProxyError::BodyTooLarge { limit });
+                    return sender.abort();
+                }
+                Some(Ok(chunk)) => {
+                    sent += chunk.len();
+                    let chunk = match &mut verifier {
+                        Some(verifier) => {
+                            verifier.update(&chunk);
+                            match held.replace(chunk) {
+                                Some(chunk) => chunk,
+                                None => continue,
+                            }
+                        }
+                        None => chunk,
+                    };
+                    // The upstream request is gone
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Err(e)) => {
+                    let error = ProxyError::Other(format!("reading request body: {}", e));
+                    *reason.lock().unwrap() = Some(error);
+                    return sender.abort();
+                }
+                None => {
+                    if let Some(Err(algorithm)) = verifier.take().map(Verifier::finish) {
+                        *reason.lock().unwrap() = Some(ProxyError::BodyChecksum(algorithm));
+                        return sender.abort();
+                    }
+                    if let Some(chunk) = held.take() {
+                        let _ = sender.send_data(chunk).await;
+                    }
+                    return;
+                }
+            }
+        }
+    });
+    (body.into(), aborted)
+}
+
+pub fn claim_header(
+    claims: &Claims,
+    claim: &str,
+    header: &str,
+) -> Option<(HeaderName, HeaderValue)> {
+    let value = match claims.get(claim)? {
+        serde_json::Value::String(value) => value.clone(),
+        other => other.to_string(),
+    };
+    Some((
+        HeaderName::from_bytes(header.as_bytes()).ok()?,
+        HeaderValue::from_str(&value).ok()?,
+    ))
+}
+
+// Who the request is from, for per-user cache entries and the like: a hash
+// of the token's subject, so the subject itself never ends up in keys
+fn subject_hash(claims: &Claims) -> Option<String> {
+    let subject = match claims.get("sub")? {
+        serde_json::Value::String(subject) => subject.clone(),
+        other => other.to_string(),
+    };
+    let hash = digest::digest(&digest::SHA256, subject.as_bytes());
+    Some(keys::hex(&hash.as_ref()[..16]))
+}
+
+// The middlewares a route's requests go through, in order. `claims` is
+// whether the route's auth yields JWT claims.
+pub fn chain_for<'a>(
+    middlewares: &'a HashMap<String, MiddlewareConfig>,
+    route: &'a RouteConfig,
+    claims: bool,
+    quota_enabled: bool,
+) -> Vec<(&'a str, &'a MiddlewareConfig)> {
+    let mut chain = Vec::new();
+    // A route's auth runs ahead of the chain, see run()
+    let authenticated = match &route.auth {
+        Some(_) => claims,
+        None => route.jwt,
+    };
+    if route.jwt && route.auth.is_none() {
+        chain.push(("jwt", &JWT));
+    }
+    if authenticated && quota_enabled {
+        chain.push(("quota", &QUOTA));
+    }
+    chain.extend(
+        route
+            .middlewares
+            .iter()
+            .filter_map(|name| Some((name.as_str(), middlewares.get(name)?))),
+    );
+    chain
+}
+
+// A response body read whole for the WASM and script filters and the VCR,
+// as far as the route's response limit and the memory budget allow. `mode`
+// is what the limit metric counts it as.
+pub async fn read_response(
+    state: &AppState,
+    route: &RouteConfig,
+    mut body: BoxBody,
+    mode: &str,
+) -> Result<(web::Bytes, memory::Reservation), Box<HttpResponse>> {
+    let limit = route
+        .response_limit(&state.config.server)
+        .unwrap_or(usize::MAX);
+    let too_large = || {
+        metrics::inc(
+            "upstream_response_limit_total",
+            &[("route", &route.name), ("mode", mode)],
+        );
+        Box::new(HttpResponse::BadGateway().body("Upstream response too large"))
+    };
+    let overloaded =
+        || Box::new(HttpResponse::ServiceUnavailable().body("Overloaded, try again later"));
+    let capacity = match body.size() {
+        BodySize::Sized(n) if n > limit as u64 => return Err(too_large()),
+        BodySize::Sized(n) => n as usize,
+        _ => 0,
+    };
+    let mut reservation = (state.memory)
+        .reserve(Kind::Response, capacity)
+        .ok_or_else(overloaded)?;
+    let mut bytes = web::BytesMut::with_capacity(capacity);
+    while let Some(chunk) = std::future::poll_fn(|cx| Pin::new(&mut body).poll_next(cx)).await {
+        let chunk = chunk.map_err(|_| Box::new(HttpResponse::BadGateway().body("Bad gateway")))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        if !reservation.ensure(bytes.len() + chunk.len()) {
+            return Err(overloaded());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok((bytes.freeze(), reservation))
+}
+
+fn filter_failed(name: &str, route: &RouteConfig, e: String) -> HttpResponse {
+    eprintln!("Filter {} failed on route {}: {}", name, route.name, e);
+    metrics::inc(
+        "filter_errors_total",
+        &[("route", &route.name), ("middleware", name)],
+    );
+    HttpResponse::InternalServerError().body("Filter failed")
+}
+
+impl Middlewares {
+    pub async fn new(
+        config: &Config,
+        store: Option<Arc<Store>>,
+        kv: Arc<dyn KvStore>,
+    ) -> Result<Self, Error> {
+        let mut filters = HashMap::new();
+        let mut scripts = HashMap::new();
+        let mut specs = HashMap::new();
+        let mut wafs = HashMap::new();
+        let mut watermarkers = HashMap::new();
+        let mut uploaders = HashMap::new();
+        let mut injectors = HashMap::new();
+        for (name, middleware) in &config.middlewares {
+            match middleware {
+                MiddlewareConfig::Wasm(filter) => {
+                    filters.insert(name.clone(), WasmFilter::load(name, filter)?);
+                }
+                MiddlewareConfig::Script(script) => {
+                    scripts.insert(name.clone(), Script::load(name, script)?);
+                }
+                MiddlewareConfig::OpenApi(openapi) => {
+                    specs.insert(name.clone(), Spec::load(name, openapi)?);
+                }
+                MiddlewareConfig::Waf(waf) => {
+                    wafs.insert(name.clone(), Waf::load(name, waf)?);
+                }
+                MiddlewareConfig::Watermark(watermark) => {
+                    watermarkers.insert(name.clone(), Watermarker::load(name, watermark)?);
+                }
+                MiddlewareConfig::Upload(upload) => {
+                    uploaders.insert(name.clone(), Uploader::load(name, upload)?);
+                }
+                MiddlewareConfig::Inject(inject) => {
+                    injectors.insert(name.clone(), Injector::load(name, inject)?);
+                }
+                _ => {}
+            }
+        }
+
+        let limiters = config
+            .middlewares
+            .iter()
+            .filter_map(|(name, middleware)| match middleware {
+                MiddlewareConfig::RateLimit(limit) => {
+                    Some((name.clone(), RateLimiter::new(limit.clone())))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Ok(Middlewares {
+            config: config.middlewares.clone(),
+            limiters,
+            filters,
+            scripts,
+            specs,
+            wafs,
+            watermarkers,
+            uploaders,
+            injectors,
+            authenticators: Authenticators::new(&config.authenticators, store, kv).await?,
+            quota_enabled: config.quota.is_some(),
+        })
+    }
+
+    fn chain<'a>(&'a self, route: &'a RouteConfig) -> Vec<(&'a str, &'a MiddlewareConfig)> {
+        let claims = route
+            .auth
+            .as_ref()
+            .is_some_and(|auth| self.authenticators.has_claims(auth));
+        chain_for(&self.config, route, claims, self.quota_enabled)
+    }
+
+    // Whether responses of this route go through the cache
+    pub fn caches(&self, route: &RouteConfig) -> bool {
+        let cached = route.cache
+            || route
+                .middlewares
+                .iter()
+                .any(|name| matches!(self.config.get(name), Some(MiddlewareConfig::Cache)));
+        cached && !route.cache_policy.never
+    }
+
+    // Run the request side of the route's chain; an Err is the response to
+    // send instead of forwarding. The body is only read once a step needs it,
+    // so earlier steps can reject the request first.
+    pub async fn run(
+        &self,
+        state: &AppState,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        upstream: &str,
+        path: String,
+        body: RequestBody,
+    ) -> Result<Outcome, HttpResponse> {
+        let (mut payload, body) = match body {
+            RequestBody::Unread(payload) => (Some(payload), web::Bytes::new()),
+            RequestBody::Read(body) => (None, body),
+        };
+        let mut outcome = Outcome {
+            quota: None,
+            upstream: upstream.to_string(),
+            path,
+            headers: req.headers().clone(),
+            body,
+            payload: None,
+            operation: None,
+            subject: None,
+            cache_partition: None,
+        };
+        framing::strip(&mut outcome.headers);
+        let mut claims: Option<Claims> = None;
+        let skip_auth = selftest::skips_auth(req);
+
+        // Identity headers may only come from a validated token
+        let claim_headers = state.config.jwt.iter().flat_map(|jwt| &jwt.claim_headers);
+        for (_, header) in claim_headers.clone() {
+            outcome.headers.remove(header.as_str());
+        }
+
+        // The route's authenticator stands in for the jwt flag
+        if let Some(auth) = route.auth.as_ref().filter(|_| !skip_auth) {
+            for header in self.authenticators.identity_headers(auth) {
+                outcome.headers.remove(header);
+            }
+            if self.authenticators.needs_body(auth) {
+                if let Some(payload) = payload.take() {
+                    outcome.body = read_body(state, req, payload).await?;
+                }
+            }
+            match self
+                .authenticators
+                .authenticate(auth, req, &outcome.body)
+                .await
+            {
+                Ok(identity) => {
+                    for (name, value) in identity.headers {
+                        outcome.headers.insert(name, value);
+                    }
+                    claims = identity.claims;
+                }
+                Err((status, reason)) => {
+                    metrics::inc(
+                        "auth_rejections_total",
+                        &[("route", &route.name), ("auth", auth)],
+                    );
+                    return Err(HttpResponse::build(status).body(reason));
+                }
+            }
+        }
+
+        for (name, middleware) in self.chain(route) {
+            match middleware {
+                // Self-test probes have no credentials to show
+                MiddlewareConfig::Jwt { .. } | MiddlewareConfig::Csrf(_) if skip_auth => {}
+                MiddlewareConfig::Jwt { optional } => {
+                    if claims.is_some() {
+                        continue;
+                    }
+                    let validator = state.jwt.as_ref().expect("JWT routes require a validator");
+                    match auth::bearer_token(req).map(|token| validator.validate(token)) {
+                        Some(Ok(valid)) => {
+                            for (claim, header) in claim_headers.clone() {
+                                if let Some((name, value)) = claim_header(&valid, claim, header) {
+                                    outcome.headers.insert(name, value);
+                                }
+                            }
+                            claims = Some(valid);
+                        }
+                        Some(Err(e)) => {
+                            println!("Rejected token for {}: {}", req.path(), e);
+                            return Err(HttpResponse::Unauthorized().body("Invalid token"));
+                        }
+                        None if *optional => {}
+                        None => {
+                            return Err(HttpResponse::Unauthorized().body("Missing bearer token"))
+                        }
+                    }
+                }
+                // Enforce per-user quotas once we know who is calling
+                MiddlewareConfig::Quota => {
+                    if let (Some(quota), Some(claims)) = (&state.quota, &claims) {
+                        outcome.quota = quota.check(claims).await?;
+                    }
+                }
+                MiddlewareConfig::Streams => {
+                    if let (Some(streams), Some(claims)) = (&state.streams, &claims) {
+                        streams.check(req, claims).await?;
+                    }
+                }
+                MiddlewareConfig::RateLimit(_) => {
+                    if !self.limiters[name].allow() {
+                        metrics::inc(
+                            "route_rate_limited_total",
+                            &[("route", &route.name), ("middleware", name)],
+                        );
+                        return Err(HttpResponse::TooManyRequests().body("Rate limit exceeded"));
+                    }
+                }
+                // Applied when forwarding, see caches()
+                MiddlewareConfig::Cache => {}
+                MiddlewareConfig::Device(config) => {
+                    device::enrich(config, route, req, &mut outcome.headers);
+                }
+                MiddlewareConfig::Watermark(_) => {
+                    self.watermarkers[name].mark(name, req, route, claims.as_ref());
+                }
+                MiddlewareConfig::Upload(_) => {
+                    (self.uploaders[name].check(req, route, &mut outcome))
+                        .map_err(|response| *response)?;
+                }
+                MiddlewareConfig::Manifest(config) => {
+                    manifest::limit(name, config, req, claims.as_ref());
+                    // Manifests have to come back in a coding we can read
+                    outcome.headers.remove(header::ACCEPT_ENCODING);
+                }
+                MiddlewareConfig::Inject(_) => {
+                    // Pages too, to have the fragment put in
+                    outcome.headers.remove(header::ACCEPT_ENCODING);
+                }
+                MiddlewareConfig::Waf(_) => {
+                    let waf = &self.wafs[name];
+                    if waf.scans_body(req) {
+                        if let Some(payload) = payload.take() {
+                            outcome.body = read_body(state, req, payload).await?;
+                        }
+                    }
+                    if let Some(found) = waf.scan(req, &outcome.body) {
+                        let mode = if waf.blocks() { "block" } else { "monitor" };
+                        println!(
+                            "WAF rule {} matched the {} of {} {} on route {} ({})",
+                            found.rule,
+                            found.location,
+                            req.method(),
+                            req.path(),
+                            route.name,
+                            mode
+                        );
+                        metrics::inc(
+                            "waf_matches_total",
+                            &[("route", &route.name), ("rule", found.rule), ("mode", mode)],
+                        );
+                        if waf.blocks() {
+                            return Err(HttpResponse::Forbidden().body("Request blocked"));
+                        }
+                    }
+                }
+                MiddlewareConfig::Csrf(config) => {
+                    if let Err(reason) = csrf::check(config, req) {
+                        println!(
+                            "Rejected {} {} on route {}: {}",
+                            req.method(),
+                            req.path(),
+                            route.name,
+                            reason
+                        );
+                        metrics::inc("csrf_rejections_total", &[("route", &route.name)]);
+                        return Err(HttpResponse::Forbidden().body(reason));
+                    }
+                }
+                MiddlewareConfig::OpenApi(_) => {
+                    if let Some(payload) = payload.take() {
+                        outcome.body = read_body(state, req, payload).await?;
+                    }
+                    let violations = self.specs[name].validate(req, &outcome.body);
+                    if !violations.is_empty() {
+                        println!(
+                            "Rejected invalid request {} {}: {} {}",
+                            req.method(),
+                            req.path(),
+                            violations[0].location,
+                            violations[0].message
+                        );
+                        metrics::inc(
+                            "requests_invalid_total",
+                            &[("route", &route.name), ("middleware", name)],
+                        );
+                        return Err(openapi::rejection(&violations));
+                    }
+                }
+                MiddlewareConfig::Graphql(config) => {
+                    if let Some(payload) = payload.take() {
+                        outcome.body = read_body(state, req, payload).await?;
+                    }
+                    match graphql::check(config, req, &outcome.body) {
+                        Ok(operations) => {
+                            for operation in &operations {
+                                metrics::inc(
+                                    "graphql_operations_total",
+                                    &[
+                                        ("route", &route.name),
+                                        ("operation", operation),
+                                        ("result", "allowed"),
+                                    ],
+                                );
+                            }
+                            outcome.operation = Some(operations.join(","));
+                        }
+                        Err(rejection) => {
+                            println!(
+                                "Rejected GraphQL operation {} on route {}: {}",
+                                rejection.operation, route.name, rejection.message
+                            );
+                            metrics::inc(
+                                "graphql_operations_total",
+                                &[
+                                    ("route", &route.name),
+                                    ("operation", &rejection.operation),
+                                    ("result", rejection.reason),
+                                ],
+                            );
+                            let mut response = rejection.response();
+                            response.extensions_mut().insert(Served {
+                                route: route.name.clone(),
+                                upstream: "-".to_string(),
+                                operation: Some(rejection.operation),
+                                region: None,
+                            });
+                            return Err(response);
+                        }
+                    }
+                }
+                MiddlewareConfig::Wasm(_) | MiddlewareConfig::Script(_) => {
+                    if let Some(payload) = payload.take() {
+                        outcome.body = read_body(state, req, payload).await?;
+                    }
+                    let message = Message {
+                        method: req.method().to_string(),
+                        path: outcome.path,
+                        headers: outcome.headers,
+                        body: outcome.body.to_vec(),
+                        status: 0,
+                        upstream: outcome.upstream,
+                    };
+                    let (message, result) = self
+                        .call_filter(name, "on_request", message)
+                        .map_err(|e| filter_failed(name, route, e))?;
+                    if result != 0 {
+                        let status = u16::try_from(result)
+                            .ok()
+                            .and_then(|s| StatusCode::from_u16(s).ok())
+                            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                        return Err(HttpResponse::build(status).body(message.body));
+                    }
+                    if !state.config.is_upstream(&message.upstream) {
+                        let e = format!("unknown upstream {}", message.upstream);
+                        return Err(filter_failed(name, route, e));
+                    }
+                    outcome.path = message.path;
+                    outcome.headers = message.headers;
+                    outcome.body = message.body.into();
+                    outcome.upstream = message.upstream.trim_end_matches('/').to_string();
+                }
+            }
+        }
+
+        outcome.subject = claims.as_ref().and_then(subject_hash);
+        if route.cache_policy.per_user {
+            outcome.cache_partition = outcome.subject.clone();
+        }
+        outcome.payload = payload;
+        Ok(outcome)
+    }
+
+    fn call_filter(
+        &self,
+        name: &str,
+        hook: &str,
+        message: Message,
+    ) -> Result<(Message, i32), String> {
+        match self.filters.get(name) {
+            Some(filter) => filter.call(hook, message).map_err(|e| format!("{:#}", e)),
+            None => self.scripts[name].call(hook, message),
+        }
+    }
+
+    // Let the route's filters and scripts see and change the response,
+    // innermost first
+    pub async fn respond(
+        &self,
+        state: &AppState,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        mut response: HttpResponse,
+    ) -> HttpResponse {
+        let chain = self.chain(route);
+        for (name, middleware) in &chain {
+            match middleware {
+                MiddlewareConfig::Csrf(config) => csrf::issue(config, req, &mut response),
+                MiddlewareConfig::Device(config) => device::request_hints(config, &mut response),
+                MiddlewareConfig::Watermark(_) => {
+                    response = self.watermarkers[*name]
+                        .apply(name, req, route, response)
+                        .await;
+                }
+                MiddlewareConfig::Manifest(_) => {
+                    response = manifest::filter(name, req, route, response).await;
+                }
+                MiddlewareConfig::Inject(_) => {
+                    response = self.injectors[*name].apply(req, route, response);
+                }
+                _ => {}
+            }
+        }
+        let filters: Vec<&str> = chain
+            .iter()
+            .rev()
+            .filter(|(_, middleware)| {
+                matches!(
+                    middleware,
+                    MiddlewareConfig::Wasm(_) | MiddlewareConfig::Script(_)
+                )
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        if filters.is_empty() {
+            return response;
+        }
+
+        let (head, body) = response.into_parts();
+        // Held until the filters are done with the body
+        let (body, _reservation) = match read_response(state, route, body, "filtered").await {
+            Ok(read) => read,
+            Err(response) => return *response,
+        };
+        let mut message = Message {
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            headers: head.headers().clone(),
+            body: body.to_vec(),
+            status: head.status().as_u16(),
+            upstream: String::new(),
+        };
+
+        for name in filters {
+            let result = match self.call_filter(name, "on_response", message) {
+                Ok((next, result)) => {
+                    message = next;
+                    result
+                }
+                Err(e) => return filter_failed(name, route, e),
+            };
+            if let Some(status) = u16::try_from(result)
+                .ok()
+                .filter(|s| *s != 0)
+                .and_then(|s| StatusCode::from_u16(s).ok())
+            {
+                message.status = status.as_u16();
+            }
+        }
+
+        let mut response =
+            HttpResponse::build(StatusCode::from_u16(message.status).unwrap_or(StatusCode::OK));
+        for (key, value) in message.headers.iter() {
+            response.append_header((key.clone(), value.clone()));
+        }
+        response.body(message.body)
+    }
+}
@@ -0,0 +1,46 @@
+use crate::config::{CorsConfig, MockConfig, RouteConfig};
+use crate::cors;
+use crate::metrics;
+use crate::routes;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+
+// The route's fixture, in place of the upstream's response
+pub async fn respond(
+    route: &RouteConfig,
+    mock: &MockConfig,
+    req: &HttpRequest,
+    cors: &CorsConfig,
+) -> HttpResponse {
+    let fill = |text: &str| match mock.template {
+        true => routes::render(route, req, text),
+        false => text.to_string(),
+    };
+    let body = match &mock.body_file {
+        Some(path) => match tokio::fs::read(path).await {
+            Ok(body) if mock.template => fill(&String::from_utf8_lossy(&body)).into_bytes(),
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Route {} mock fixture {}: {}", route.name, path, e);
+                return HttpResponse::InternalServerError().body("Mock fixture unavailable");
+            }
+        },
+        None => fill(&mock.body).into_bytes(),
+    };
+
+    metrics::inc("mock_responses_total", &[("route", &route.name)]);
+    let status = StatusCode::from_u16(mock.status).unwrap_or(StatusCode::OK);
+    let mut response = HttpResponse::build(status);
+    cors::apply(cors, &mut response);
+    // Names were checked when the config loaded
+    for (name, value) in &mock.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&fill(value)),
+        ) {
+            response.insert_header((name, value));
+        }
+    }
+    response.body(body)
+}
@@ -0,0 +1,75 @@
+use crate::config::{CorsConfig, NormalizeAction, NormalizeConfig, RouteConfig, TrailingSlash};
+use crate::cors;
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+
+// The path as the policy wants it; the query string isn't part of it
+pub fn normalize(policy: &NormalizeConfig, path: &str) -> String {
+    let mut path = match policy.lowercase {
+        true => path.to_lowercase(),
+        false => path.to_string(),
+    };
+    match policy.trailing_slash {
+        TrailingSlash::Keep => {}
+        TrailingSlash::Strip => {
+            while path.len() > 1 && path.ends_with('/') {
+                path.pop();
+            }
+        }
+        TrailingSlash::Add => {
+            let last = path.rsplit('/').next().unwrap_or_default();
+            if !path.ends_with('/') && !last.contains('.') {
+                path.push('/');
+            }
+        }
+    }
+    path
+}
+
+// The upstream path to forward, normalized when the policy rewrites, or the
+// redirect to send the client to its normalized path instead
+pub fn apply(
+    policy: Option<&NormalizeConfig>,
+    route: &RouteConfig,
+    req: &HttpRequest,
+    upstream_path: String,
+    cors: &CorsConfig,
+) -> Result<String, Box<HttpResponse>> {
+    let Some(policy) = route.normalize.as_ref().or(policy) else {
+        return Ok(upstream_path);
+    };
+    match policy.action {
+        NormalizeAction::Rewrite => {
+            let normalized = normalize(policy, &upstream_path);
+            if normalized != upstream_path {
+                metrics::inc(
+                    "paths_normalized_total",
+                    &[("route", &route.name), ("action", "rewrite")],
+                );
+            }
+            Ok(normalized)
+        }
+        NormalizeAction::Redirect => {
+            let mut location = normalize(policy, req.path());
+            if location == req.path() {
+                return Ok(upstream_path);
+            }
+            metrics::inc(
+                "paths_normalized_total",
+                &[("route", &route.name), ("action", "redirect")],
+            );
+            if !req.query_string().is_empty() {
+                location.push('?');
+                location.push_str(req.query_string());
+            }
+            let mut response = HttpResponse::PermanentRedirect();
+            cors::apply(cors, &mut response);
+            Err(Box::new(
+                response
+                    .insert_header((header::LOCATION, location))
+                    .finish(),
+            ))
+        }
+    }
+}
@@ -0,0 +1,614 @@
+use crate::config::OpenApiConfig;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+// Deeply nested or recursive schemas stop being checked past this depth
+const MAX_DEPTH: usize = 64;
+
+// One thing wrong with a request, e.g. {"location": "query.limit",
+// "message": "expected integer"}
+#[derive(Serialize)]
+pub struct Violation {
+    pub location: String,
+    pub message: String,
+}
+
+#[derive(Clone, PartialEq)]
+enum In {
+    Path,
+    Query,
+    Header,
+}
+
+#[derive(Clone)]
+struct Param {
+    name: String,
+    location: In,
+    required: bool,
+    schema: Value,
+}
+
+struct Operation {
+    method: String,
+    params: Vec<Param>,
+    body_required: bool,
+    // Media type and schema for each accepted request body type
+    content: Option<Vec<(String, Value)>>,
+}
+
+struct PathItem {
+    template: String,
+    pattern: Regex,
+    // Template parameter names, in capture group order
+    names: Vec<String>,
+    operations: Vec<Operation>,
+}
+
+// An OpenAPI 3 spec compiled for checking requests: the path and method must
+// exist, required path/query/header parameters must be present and of the
+// right type, and JSON bodies must match their schema. Only local $refs are
+// supported; cookie parameters and string formats aren't checked.
+pub struct Spec {
+    root: Value,
+    paths: Vec<PathItem>,
+    patterns: HashMap<String, Regex>,
+    base_path: String,
+    allow_unknown_paths: bool,
+}
+
+fn violation(location: impl Into<String>, message: impl Into<String>) -> Violation {
+    Violation {
+        location: location.into(),
+        message: message.into(),
+    }
+}
+
+// Follow $refs to the schema (or parameter, or request body) they point at
+fn resolve<'a>(root: &'a Value, mut value: &'a Value) -> &'a Value {
+    for _ in 0..MAX_DEPTH {
+        match value.get("$ref").and_then(Value::as_str) {
+            Some(pointer) => match root.pointer(pointer.trim_start_matches('#')) {
+                Some(target) => value = target,
+                None => return &Value::Null,
+            },
+            None => break,
+        }
+    }
+    value
+}
+
+// Check every $ref resolves locally and compile every pattern up front, so
+// a broken spec fails at startup rather than on requests
+fn prepare(
+    root: &Value,
+    value: &Value,
+    patterns: &mut HashMap<String, Regex>,
+) -> Result<(), String> {
+    match value {
+        Value::Object(map) => {
+            if let Some(pointer) = map.get("$ref").and_then(Value::as_str) {
+                if !pointer.starts_with('#') {
+                    return Err(format!("only local $refs are supported, not {}", pointer));
+                }
+                if root.pointer(pointer.trim_start_matches('#')).is_none() {
+                    return Err(format!("$ref {} doesn't resolve", pointer));
+                }
+            }
+            if let Some(pattern) = map.get("pattern").and_then(Value::as_str) {
+                if !patterns.contains_key(pattern) {
+                    let regex = Regex::new(pattern)
+                        .map_err(|e| format!("invalid pattern {}: {}", pattern, e))?;
+                    patterns.insert(pattern.to_string(), regex);
+                }
+            }
+            map.values().try_for_each(|v| prepare(root, v, patterns))
+        }
+        Value::Array(items) => items.iter().try_for_each(|v| prepare(root, v, patterns)),
+        _ => Ok(()),
+    }
+}
+
+// /users/{id}/files/{name}.json as an anchored regex plus its parameter names
+fn compile_template(template: &str) -> Result<(Regex, Vec<String>), String> {
+    let mut pattern = String::from("^");
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed {{ in path {}", template))?;
+        pattern.push_str(&regex::escape(&rest[..start]));
+        pattern.push_str("([^/]+)");
+        names.push(rest[start + 1..start + end].to_string());
+        rest = &rest[start + end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+    let regex = Regex::new(&pattern).map_err(|e| e.to_string())?;
+    Ok((regex, names))
+}
+
+fn parse_params(root: &Value, list: Option<&Value>, params: &mut Vec<Param>) {
+    for param in list.and_then(Value::as_array).into_iter().flatten() {
+        let param = resolve(root, param);
+        let location = match param.get("in").and_then(Value::as_str) {
+            Some("path") => In::Path,
+            Some("query") => In::Query,
+            Some("header") => In::Header,
+            _ => continue,
+        };
+        let Some(name) = param.get("name").and_then(Value::as_str) else {
+            continue;
+        };
+        let name = match location {
+            In::Header => name.to_ascii_lowercase(),
+            _ => name.to_string(),
+        };
+        // Operation parameters override path-level ones with the same name
+        params.retain(|p| !(p.name == name && p.location == location));
+        params.push(Param {
+            required: location == In::Path
+                || param.get("required").and_then(Value::as_bool) == Some(true),
+            name,
+            location,
+            schema: param.get("schema").cloned().unwrap_or(Value::Null),
+        });
+    }
+}
+
+fn parse_body(root: &Value, body: &Value) -> (bool, Vec<(String, Value)>) {
+    let body = resolve(root, body);
+    let content = body
+        .get("content")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(media, content)| {
+            let schema = content.get("schema").cloned().unwrap_or(Value::Null);
+            (media.to_ascii_lowercase(), schema)
+        })
+        .collect();
+    let required = body.get("required").and_then(Value::as_bool) == Some(true);
+    (required, content)
+}
+
+fn type_matches(value: &Value, name: &str) -> bool {
+    match name {
+        "string" => value.is_string(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+// Turn the raw text of a parameter into the JSON value its schema expects,
+// leaving it a string when it doesn't parse so the type check reports it
+fn coerce(schema: &Value, raw: &[&str], root: &Value) -> Value {
+    let scalar = |schema: &Value, raw: &str| {
+        let parsed = match schema.get("type").and_then(Value::as_str) {
+            Some("integer") => raw.parse::<i64>().ok().map(Value::from),
+            Some("number") => raw
+                .parse::<f64>()
+                .ok()
+                .and_then(serde_json::Number::from_f64)
+                .map(Value::Number),
+            Some("boolean") => raw.parse::<bool>().ok().map(Value::from),
+            _ => None,
+        };
+        parsed.unwrap_or_else(|| Value::from(raw))
+    };
+
+    let schema = resolve(root, schema);
+    if schema.get("type").and_then(Value::as_str) == Some("array") {
+        let items = resolve(root, schema.get("items").unwrap_or(&Value::Null));
+        // Repeated keys, or a single comma separated value
+        let values: Vec<&str> = match raw {
+            [single] => single.split(',').collect(),
+            _ => raw.to_vec(),
+        };
+        Value::Array(values.iter().map(|v| scalar(items, v)).collect())
+    } else {
+        scalar(schema, raw[0])
+    }
+}
+
+fn number(schema: &serde_json::Map<String, Value>, key: &str) -> Option<f64> {
+    schema.get(key).and_then(Value::as_f64)
+}
+
+impl Spec {
+    pub fn load(name: &str, config: &OpenApiConfig) -> Result<Self, Error> {
+        let fail =
+            |e: String| Error::other(format!("OpenAPI spec {} ({}): {}", name, config.spec, e));
+        let source = fs::read_to_string(&config.spec)?;
+        let root: Value = match serde_json::from_str(&source) {
+            Ok(root) => root,
+            Err(_) => serde_yaml::from_str(&source).map_err(|e| fail(e.to_string()))?,
+        };
+        let version = root.get("openapi").and_then(Value::as_str).unwrap_or("");
+        if !version.starts_with("3.") {
+            return Err(fail("only OpenAPI 3 specs are supported".to_string()));
+        }
+        let mut patterns = HashMap::new();
+        prepare(&root, &root, &mut patterns).map_err(fail)?;
+
+        let mut paths = Vec::new();
+        for (template, item) in root
+            .get("paths")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            let (pattern, names) = compile_template(template).map_err(fail)?;
+            let item = resolve(&root, item);
+            let mut shared = Vec::new();
+            parse_params(&root, item.get("parameters"), &mut shared);
+
+            let mut operations = Vec::new();
+            for method in METHODS {
+                let Some(operation) = item.get(method) else {
+                    continue;
+                };
+                let mut params = shared.clone();
+                parse_params(&root, operation.get("parameters"), &mut params);
+                let (body_required, content) = match operation.get("requestBody") {
+                    Some(body) => {
+                        let (required, content) = parse_body(&root, body);
+                        (required, Some(content))
+                    }
+                    None => (false, None),
+                };
+                operations.push(Operation {
+                    method: method.to_ascii_uppercase(),
+                    params,
+                    body_required,
+                    content,
+                });
+            }
+            paths.push(PathItem {
+                template: template.clone(),
+                pattern,
+                names,
+                operations,
+            });
+        }
+        // Concrete paths win over templated ones, e.g. /users/me over /users/{id}
+        paths.sort_by_key(|p| p.names.len());
+
+        println!(
+            "Loaded OpenAPI spec {} with {} paths from {}",
+            name,
+            paths.len(),
+            config.spec
+        );
+        Ok(Spec {
+            root,
+            paths,
+            patterns,
+            base_path: config.base_path.trim_end_matches('/').to_string(),
+            allow_unknown_paths: config.allow_unknown_paths,
+        })
+    }
+
+    // Everything wrong with the request; empty if it's valid
+    pub fn validate(&self, req: &HttpRequest, body: &[u8]) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let path = match req.path().strip_prefix(&self.base_path) {
+            Some(path) if path.is_empty() || path.starts_with('/') => path,
+            _ => "",
+        };
+        let path = if path.is_empty() { "/" } else { path };
+
+        let Some((item, captures)) = self
+            .paths
+            .iter()
+            .find_map(|item| Some((item, item.pattern.captures(path)?)))
+        else {
+            if !self.allow_unknown_paths {
+                violations.push(violation(
+                    "path",
+                    format!("{} isn't in the API", req.path()),
+                ));
+            }
+            return violations;
+        };
+        let Some(operation) = item
+            .operations
+            .iter()
+            .find(|o| o.method == req.method().as_str())
+        else {
+            violations.push(violation(
+                "method",
+                format!("{} isn't allowed on {}", req.method(), item.template),
+            ));
+            return violations;
+        };
+
+        let query = match web::Query::<Vec<(String, String)>>::from_query(req.query_string()) {
+            Ok(query) => query.into_inner(),
+            Err(_) => {
+                violations.push(violation("query", "malformed query string"));
+                Vec::new()
+            }
+        };
+
+        for param in &operation.params {
+            let (location, raw): (&str, Vec<&str>) = match param.location {
+                In::Path => {
+                    let index = item.names.iter().position(|n| *n == param.name);
+                    let value = index.and_then(|i| captures.get(i + 1)).map(|m| m.as_str());
+                    ("path", value.into_iter().collect())
+                }
+                In::Query => (
+                    "query",
+                    query
+                        .iter()
+                        .filter(|(k, _)| *k == param.name)
+                        .map(|(_, v)| v.as_str())
+                        .collect(),
+                ),
+                In::Header => (
+                    "header",
+                    req.headers()
+                        .get_all(param.name.as_str())
+                        .filter_map(|v| v.to_str().ok())
+                        .collect(),
+                ),
+            };
+            let at = format!("{}.{}", location, param.name);
+            if raw.is_empty() {
+                if param.required {
+                    violations.push(violation(at, "required parameter is missing"));
+                }
+                continue;
+            }
+            let value = coerce(&param.schema, &raw, &self.root);
+            self.check(&param.schema, &value, &at, &mut violations, 0);
+        }
+
+        if let Some(content) = &operation.content {
+            self.check_body(req, body, operation.body_required, content, &mut violations);
+        }
+        violations
+    }
+
+    fn check_body(
+        &self,
+        req: &HttpRequest,
+        body: &[u8],
+        required: bool,
+        content: &[(String, Value)],
+        violations: &mut Vec<Violation>,
+    ) {
+        if body.is_empty() {
+            if required {
+                violations.push(violation("body", "request body is required"));
+            }
+            return;
+        }
+
+        let media = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let kind = media.split('/').next().unwrap_or("");
+        let Some((matched, schema)) = content.iter().find(|(accepted, _)| {
+            *accepted == media || *accepted == "*/*" || *accepted == format!("{}/*", kind)
+        }) else {
+            let message = match media.as_str() {
+                "" => "Content-Type is missing".to_string(),
+                media => format!("content type {} isn't accepted", media),
+            };
+            violations.push(violation("body", message));
+            return;
+        };
+
+        // Only JSON bodies are checked against their schema
+        let json = media == "application/json" || media.ends_with("+json");
+        if !json || matched == "*/*" && schema.is_null() {
+            return;
+        }
+        match serde_json::from_slice::<Value>(body) {
+            Ok(value) => self.check(schema, &value, "body", violations, 0),
+            Err(e) => violations.push(violation("body", format!("invalid JSON: {}", e))),
+        }
+    }
+
+    // Check a value against a JSON schema (the subset OpenAPI 3 uses)
+    fn check(
+        &self,
+        schema: &Value,
+        value: &Value,
+        at: &str,
+        violations: &mut Vec<Violation>,
+        depth: usize,
+    ) {
+        let Some(schema) = resolve(&self.root, schema).as_object() else {
+            return;
+        };
+        if depth > MAX_DEPTH {
+            return;
+        }
+        if value.is_null() && schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+            return;
+        }
+        let passes = |schema: &Value| {
+            let mut found = Vec::new();
+            self.check(schema, value, at, &mut found, depth + 1);
+            found.is_empty()
+        };
+
+        for sub in schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            self.check(sub, value, at, violations, depth + 1);
+        }
+        if let Some(any) = schema.get("anyOf").and_then(Value::as_array) {
+            if !any.iter().any(passes) {
+                violations.push(violation(at, "doesn't match any of the allowed schemas"));
+            }
+        }
+        if let Some(one) = schema.get("oneOf").and_then(Value::as_array) {
+            let matched = one.iter().filter(|s| passes(s)).count();
+            if matched != 1 {
+                violations.push(violation(
+                    at,
+                    format!(
+                        "matches {} of the oneOf schemas instead of exactly one",
+                        matched
+                    ),
+                ));
+            }
+        }
+
+        // A single type, or a list of them in OpenAPI 3.1
+        let types: Vec<&str> = match schema.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| type_matches(value, t)) {
+            violations.push(violation(at, format!("expected {}", types.join(" or "))));
+            return;
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+                violations.push(violation(
+                    at,
+                    format!("must be one of {}", allowed.join(", ")),
+                ));
+            }
+        }
+
+        match value {
+            Value::Number(n) => {
+                let n = n.as_f64().unwrap_or_default();
+                // exclusiveMinimum is a flag on minimum in 3.0 and a bound of its own in 3.1
+                let exclusive = |key: &str| schema.get(key).and_then(Value::as_bool) == Some(true);
+                if let Some(min) = number(schema, "minimum") {
+                    if exclusive("exclusiveMinimum") {
+                        if n <= min {
+                            violations.push(violation(at, format!("must be greater than {}", min)));
+                        }
+                    } else if n < min {
+                        violations.push(violation(at, format!("must be at least {}", min)));
+                    }
+                }
+                if let Some(max) = number(schema, "maximum") {
+                    if exclusive("exclusiveMaximum") {
+                        if n >= max {
+                            violations.push(violation(at, format!("must be less than {}", max)));
+                        }
+                    } else if n > max {
+                        violations.push(violation(at, format!("must be at most {}", max)));
+                    }
+                }
+                if let Some(min) = number(schema, "exclusiveMinimum") {
+                    if n <= min {
+                        violations.push(violation(at, format!("must be greater than {}", min)));
+                    }
+                }
+                if let Some(max) = number(schema, "exclusiveMaximum") {
+                    if n >= max {
+                        violations.push(violation(at, format!("must be less than {}", max)));
+                    }
+                }
+            }
+            Value::String(s) => {
+                let length = s.chars().count() as f64;
+                if let Some(min) = number(schema, "minLength").filter(|min| length < *min) {
+                    violations.push(violation(
+                        at,
+                        format!("must be at least {} characters", min),
+                    ));
+                }
+                if let Some(max) = number(schema, "maxLength").filter(|max| length > *max) {
+                    violations.push(violation(at, format!("must be at most {} characters", max)));
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                    if self.patterns.get(pattern).is_some_and(|re| !re.is_match(s)) {
+                        violations.push(violation(at, format!("must match {}", pattern)));
+                    }
+                }
+            }
+            Value::Array(items) => {
+                let length = items.len() as f64;
+                if let Some(min) = number(schema, "minItems").filter(|min| length < *min) {
+                    violations.push(violation(at, format!("must have at least {} items", min)));
+                }
+                if let Some(max) = number(schema, "maxItems").filter(|max| length > *max) {
+                    violations.push(violation(at, format!("must have at most {} items", max)));
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        let at = format!("{}[{}]", at, i);
+                        self.check(item_schema, item, &at, violations, depth + 1);
+                    }
+                }
+            }
+            Value::Object(fields) => {
+                for name in schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                {
+                    if let Some(name) = name.as_str().filter(|n| !fields.contains_key(*n)) {
+                        violations.push(violation(
+                            format!("{}.{}", at, name),
+                            "required property is missing",
+                        ));
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, field) in fields {
+                    let at = format!("{}.{}", at, name);
+                    match properties.and_then(|p| p.get(name)) {
+                        Some(field_schema) => {
+                            self.check(field_schema, field, &at, violations, depth + 1)
+                        }
+                        None => match schema.get("additionalProperties") {
+                            Some(Value::Bool(false)) => {
+                                violations.push(violation(at, "unknown property"))
+                            }
+                            Some(extra) => self.check(extra, field, &at, violations, depth + 1),
+                            None => {}
+                        },
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// 400 listing every violation, for clients to act on
+pub fn rejection(violations: &[Violation]) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({
+        "error": "invalid_request",
+        "violations": violations,
+    }))
+}
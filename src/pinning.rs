@@ -0,0 +1,151 @@
+use crate::alerts;
+use crate::config::UpstreamTlsConfig;
+use crate::metrics;
+use crate::x509;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls_021 as tls;
+use serde_json::json;
+use std::collections::HashSet;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+
+// Upstreams that must only ever be one peer, such as a payment gateway
+// ([upstreams.<name>.tls] pins). On top of the usual checks, a certificate
+// in the chain the upstream presents has to carry one of the pinned public
+// keys, given as HPKP pins are: sha256/ and the base64 SHA-256 of the
+// SubjectPublicKeyInfo, e.g. from
+//   openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+//   openssl dgst -sha256 -binary | base64
+// Anything else fails the handshake, before any of the request is sent. A
+// key not seen before raises an alert, as it's either an attack or a
+// rotation nobody told us about; the alert names it so it can be pinned.
+//
+// The client's own TLS backend has no say in how certificates are checked,
+// so pinned upstreams get a rustls one.
+
+// A pin in its canonical form, or None if it isn't one
+pub fn parse(pin: &str) -> Option<String> {
+    let hash = STANDARD.decode(pin.trim().strip_prefix("sha256/")?).ok()?;
+    (hash.len() == 32).then(|| format!("sha256/{}", STANDARD.encode(hash)))
+}
+
+// The pin for a DER certificate's public key
+fn pin_of(der: &[u8]) -> Option<String> {
+    let spki = x509::parse(der)?.subject_public_key_info;
+    let hash = digest::digest(&digest::SHA256, &spki);
+    Some(format!("sha256/{}", STANDARD.encode(hash)))
+}
+
+struct PinnedVerifier {
+    upstream: String,
+    pins: Vec<String>,
+    roots: Arc<tls::RootCertStore>,
+    // Checks the name as well as the chain; None without verify_hostname
+    webpki: Option<WebPkiVerifier>,
+    // Keys already alerted about
+    alerted: Mutex<HashSet<String>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &tls::Certificate,
+        intermediates: &[tls::Certificate],
+        server_name: &tls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, tls::Error> {
+        match &self.webpki {
+            Some(webpki) => {
+                webpki.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    scts,
+                    ocsp_response,
+                    now,
+                )?;
+            }
+            None => {
+                let certificate = tls::server::ParsedCertificate::try_from(end_entity)?;
+                tls::client::verify_server_cert_signed_by_trust_anchor(
+                    &certificate,
+                    &self.roots,
+                    intermediates,
+                    now,
+                )?;
+            }
+        }
+        let presented: Vec<String> = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|certificate| pin_of(&certificate.0))
+            .collect();
+        if presented.iter().any(|pin| self.pins.contains(pin)) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        metrics::inc(
+            "upstream_pin_failures_total",
+            &[("upstream", &self.upstream)],
+        );
+        let key = presented.first().cloned().unwrap_or_default();
+        if self.alerted.lock().unwrap().insert(key.clone()) {
+            alerts::fire(
+                "upstream_pin_mismatch",
+                format!(
+                    "Upstream {} presented a certificate with none of its pinned keys (its key is {}); refusing to connect",
+                    self.upstream, key
+                ),
+                json!({
+                    "upstream": self.upstream,
+                    "server_name": format!("{:?}", server_name),
+                    "presented": presented,
+                    "pins": self.pins,
+                }),
+            );
+        }
+        Err(tls::Error::General(format!(
+            "certificate of upstream {} matches none of its pinned keys",
+            self.upstream
+        )))
+    }
+}
+
+// TLS for a pinned upstream, trusting the same CAs the unpinned client would
+pub fn client_config(name: &str, config: &UpstreamTlsConfig) -> Result<tls::ClientConfig, Error> {
+    let mut roots = tls::RootCertStore::empty();
+    if !config.only_ca_file {
+        for certificate in rustls_native_certs::load_native_certs().certs {
+            // The system's store has the odd CA rustls won't parse
+            let _ = roots.add(&tls::Certificate(certificate.to_vec()));
+        }
+    }
+    if let Some(ca_file) = &config.ca_file {
+        let failed = |e: &dyn std::fmt::Display| {
+            Error::other(format!("Upstream {} CA file {}: {}", name, ca_file, e))
+        };
+        for certificate in CertificateDer::pem_file_iter(ca_file).map_err(|e| failed(&e))? {
+            let certificate = certificate.map_err(|e| failed(&e))?;
+            (roots.add(&tls::Certificate(certificate.to_vec()))).map_err(|e| failed(&e))?;
+        }
+    }
+    let roots = Arc::new(roots);
+    let verifier = PinnedVerifier {
+        upstream: name.to_string(),
+        pins: config.pins.iter().filter_map(|pin| parse(pin)).collect(),
+        roots: roots.clone(),
+        webpki: (config.verify_hostname).then(|| WebPkiVerifier::new(roots, None)),
+        alerted: Mutex::new(HashSet::new()),
+    };
+    Ok(tls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
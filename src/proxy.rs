@@ -0,0 +1,1800 @@
+use crate::access_log::{self, AccessLog, Served};
+use crate::admin_auth::AdminAuth;
+use crate::audit::AuditLog;
+use crate::auth::JwtValidator;
+use crate::blocklist::Blocklist;
+use crate::bots::Bots;
+use crate::buffering;
+use crate::cache::{self, Cache, CachedResponse, Lookup};
+use crate::checksum::Verifier;
+use crate::client_ip::Resolver;
+use crate::cluster::Cluster;
+use crate::compliance;
+use crate::config::{Config, CorsConfig, HedgeConfig, RangeCheckConfig, RouteConfig};
+use crate::connections::Connections;
+use crate::context;
+use crate::cors;
+use crate::credential_audit::CredentialAudit;
+use crate::dashboard::Live;
+use crate::deadline;
+use crate::dedup::{self, Dedup, Turn};
+use crate::delta::Deltas;
+use crate::disconnect;
+use crate::early_data;
+use crate::edge_files::EdgeFiles;
+use crate::egress;
+use crate::egress_policy::{self, Policy};
+use crate::encoding;
+use crate::error::{ErrorMapper, ProxyError};
+use crate::events::{self, Event};
+use crate::fallback;
+use crate::framing;
+use crate::geo::Geo;
+use crate::grpc;
+use crate::hedge::LatencyTracker;
+use crate::hints;
+use crate::inflight::InFlight;
+use crate::kafka::{self, KafkaSink};
+use crate::keys;
+use crate::kv::KvStore;
+use crate::language;
+use crate::memory::{self, Budget, Kind, Reservation};
+use crate::metering::{self, Download, Metering};
+use crate::metrics;
+use crate::middleware::{self, Middlewares, Outcome, RequestBody};
+use crate::mock;
+use crate::normalize;
+use crate::quota::Quota;
+use crate::ranges::{self, ByteRange, Normalized};
+use crate::redact::Redactor;
+use crate::redirects::Redirects;
+use crate::report::Reporter;
+use crate::retry::{self, RetryBudget};
+use crate::rollback::Watches;
+use crate::routes::{self, RouteTable};
+use crate::sampling::{Decision, Sampler};
+use crate::schedule;
+use crate::shedding;
+use crate::slo::Slos;
+use crate::sniff;
+use crate::store::Store;
+use crate::streams::Streams;
+use crate::tenant::Tenants;
+use crate::timing;
+use crate::trailers;
+use crate::tunnel::ForwardProxy;
+use crate::upstream::Upstreams;
+use crate::upstream_url;
+use crate::vcr::Vcr;
+use actix_web::body::{BodySize, MessageBody, SizedStream};
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::{ConnectionType, Method, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Shared state handed to every request handler
+pub struct AppState {
+    pub client: Client,
+    pub grpc: grpc::Client,
+    pub config: Config,
+    pub cache: Cache,
+    // Bytes buffered for bodies and the cache, see memory.rs
+    pub memory: Arc<Budget>,
+    pub routes: RouteTable,
+    pub jwt: Option<JwtValidator>,
+    pub quota: Option<Quota>,
+    // Shared state for features that keep it between requests
+    pub kv: Arc<dyn KvStore>,
+    pub streams: Option<Streams>,
+    pub bots: Option<Bots>,
+    pub geo: Option<Geo>,
+    pub vcr: Option<Vcr>,
+    pub metering: Option<Arc<Metering>>,
+    pub reporter: Option<Arc<Reporter>>,
+    pub tenants: Tenants,
+    pub client_ip: Resolver,
+    pub blocklist: Blocklist,
+    pub edge_files: EdgeFiles,
+    pub redirects: Redirects,
+    pub inflight: InFlight,
+    pub errors: ErrorMapper,
+    pub retry_budget: RetryBudget,
+    pub upstreams: Upstreams,
+    // Where requests may be forwarded to, when restricted
+    pub egress: Option<Arc<Policy>>,
+    // Recent requests on routes that deduplicate them
+    pub dedup: Dedup,
+    pub latency: LatencyTracker,
+    pub middlewares: Middlewares,
+    pub audit: AuditLog,
+    pub admin_auth: AdminAuth,
+    pub store: Option<Arc<Store>>,
+    pub alt_svc: Option<HeaderValue>,
+    pub access_log: Option<AccessLog>,
+    pub credential_audit: Option<CredentialAudit>,
+    pub kafka: Option<Arc<KafkaSink>>,
+    // Shares cache purges and route changes with the other instances
+    pub cluster: Option<Cluster>,
+    // CONNECT tunnels, when [forward_proxy] is set
+    pub forward_proxy: Option<ForwardProxy>,
+    pub sampler: Sampler,
+    pub redactor: Redactor,
+    // Set by the admin drain endpoint before the replica is shut down
+    pub draining: AtomicBool,
+    // Until the upstream warm-up has finished
+    pub warming: AtomicBool,
+    // Routes on probation after a change through the admin API
+    pub watches: Watches,
+    // Objectives tracked for their error budgets
+    pub slos: Arc<Slos>,
+    // The last minute of traffic, for the admin dashboard
+    pub live: Live,
+    // Recent versions of responses on routes with `delta`
+    pub deltas: Deltas,
+    pub connections: Connections,
+}
+
+// The public App, with the framing fix-ups every request gets first. The
+// HTTP listeners, the HTTP/3 listener and the self-test all serve through it.
+pub fn app(
+    state: web::Data<AppState>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let framing = state.config.server.framing.clone();
+    let forward_proxy = state.forward_proxy.is_some();
+    let app = App::new()
+        .app_data(state)
+        .wrap_fn(move |mut req, srv| {
+            framing::normalize(&framing, req.headers_mut());
+            srv.call(req)
+        })
+        .service(web::resource("/{tail:.*}").to(proxy_handler)); // Route all requests
+
+    // CONNECT's authority-form target matches no path
+    match forward_proxy {
+        true => app.default_service(web::to(proxy_handler)),
+        false => app,
+    }
+}
+
+pub async fn proxy_handler(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> HttpResponse {
+    let started = Instant::now();
+    let request_id = access_log::request_id(&req);
+    let sampled = state.sampler.decide(&req);
+    if state.config.server.server_timing || sampled == Decision::Debug {
+        timing::start(&req, started);
+    }
+    if sampled != Decision::Skip {
+        println!(
+            "Received {} request for {}",
+            req.method(),
+            req.match_info().query("tail")
+        );
+    }
+    if sampled == Decision::Debug {
+        let secret = state.config.sampling.debug_header.as_str();
+        for (name, value) in req.headers().iter().filter(|(name, _)| *name != secret) {
+            println!(
+                "  [{}] {}: {}",
+                request_id,
+                name,
+                state.redactor.value(name, value)
+            );
+        }
+    }
+    let mut response = handle(&state, &req, body, &request_id, started).await;
+    if let Some(timing) = timing::header(&req) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("server-timing"), timing);
+    }
+    if let Some(alt_svc) = &state.alt_svc {
+        response
+            .headers_mut()
+            .insert(header::ALT_SVC, alt_svc.clone());
+    }
+
+    // While draining, stop clients from reusing their connections
+    if state.draining.load(Ordering::Relaxed) {
+        response
+            .head_mut()
+            .set_connection_type(ConnectionType::Close);
+    }
+    let elapsed = started.elapsed();
+    if let Some(served) = response.extensions().get::<Served>() {
+        state
+            .watches
+            .observe(&served.route, response.status(), elapsed);
+        state
+            .slos
+            .observe(&served.route, response.status(), elapsed);
+    }
+    state.live.observe(response.status(), elapsed);
+    if let Some(reporter) = &state.reporter {
+        let route = response
+            .extensions()
+            .get::<Served>()
+            .map(|s| s.route.clone());
+        reporter.observe(route.as_deref().unwrap_or("-"), response.status(), elapsed);
+    }
+    let mut entry = None;
+    if let Some(access_log) = &state.access_log {
+        if state
+            .sampler
+            .keep(sampled, response.status().as_u16(), elapsed)
+        {
+            let line = access_log.entry(&req, &response, &request_id, elapsed);
+            match access_log.waits_for_body() {
+                true => entry = Some(line),
+                false => access_log.write(line, 0),
+            }
+        }
+    }
+
+    // Egress is counted as the body goes out
+    let labels = egress::Labels {
+        route: response
+            .extensions()
+            .get::<Served>()
+            .map_or("-".to_string(), |served| served.route.clone()),
+        tenant: state
+            .tenants
+            .resolve(&req)
+            .map_or("-".to_string(), |tenant| tenant.name.clone()),
+        status: response.status().as_u16(),
+        // Their bodies are dropped unread
+        head: req.method() == Method::HEAD,
+    };
+    let event = state.kafka.as_ref().map(|_| {
+        let tenant = (labels.tenant != "-").then(|| labels.tenant.clone());
+        kafka::event(&req, &response, &request_id, elapsed, tenant)
+    });
+    let download = response.extensions_mut().remove::<Download>();
+    let done_state = state.clone();
+    egress::count(response, labels, move |sent| {
+        if let (Some(access_log), Some(entry)) = (&done_state.access_log, entry) {
+            access_log.write(entry, sent);
+        }
+        if let (Some(kafka), Some(mut event)) = (&done_state.kafka, event) {
+            event.bytes_sent = sent;
+            kafka.publish(&event);
+        }
+        if let (Some(metering), Some(download)) = (&done_state.metering, download) {
+            metering.record_download(&download, sent);
+        }
+    })
+}
+
+fn method_not_allowed(route: &RouteConfig, cors: &CorsConfig) -> HttpResponse {
+    metrics::inc(
+        "requests_rejected_total",
+        &[("reason", "method_not_allowed")],
+    );
+    let mut response = HttpResponse::MethodNotAllowed();
+    cors::apply(cors, &mut response);
+    response
+        .insert_header((header::ALLOW, route.allowed_methods.join(", ")))
+        .body("Method not allowed")
+}
+
+// Forward requests or return custom responses
+async fn handle(
+    state: &AppState,
+    req: &HttpRequest,
+    body: web::Payload,
+    request_id: &str,
+    arrived: Instant,
+) -> HttpResponse {
+    let path = req.match_info().query("tail");
+    events::record_request();
+    let client_ip = state
+        .client_ip
+        .resolve(req)
+        .unwrap_or_else(|| "unknown".to_string());
+    let inflight = state
+        .inflight
+        .start(req.method().as_str(), req.path(), &client_ip);
+
+    if let Some(response) = state.blocklist.check(req) {
+        return response;
+    }
+    if let Err(reason) = framing::check(&state.config.server.framing, req) {
+        println!("Rejecting request from {}: {}", client_ip, reason);
+        metrics::inc("requests_rejected_total", &[("reason", reason)]);
+        return HttpResponse::BadRequest()
+            .force_close()
+            .body("Malformed request");
+    }
+
+    if !framing::expectation_met(req) {
+        println!("Rejecting request from {}: unsupported Expect", client_ip);
+        metrics::inc(
+            "requests_rejected_total",
+            &[("reason", "unsupported_expectation")],
+        );
+        return HttpResponse::ExpectationFailed()
+            .force_close()
+            .body("Unsupported expectation");
+    }
+    // Early data can be replayed by whoever captured it; the client sends
+    // again after the handshake (RFC 8470)
+    let early = early_data::received(req);
+    if early && !idempotent(req.method()) {
+        println!("Rejecting request from {}: early data", client_ip);
+        metrics::inc("requests_rejected_total", &[("reason", "too_early")]);
+        return HttpResponse::build(StatusCode::from_u16(425).unwrap()).body("Too early");
+    }
+    if req.method() == Method::CONNECT {
+        if let Some(forward_proxy) = &state.forward_proxy {
+            let egress = state.egress.as_deref();
+            return forward_proxy.connect(req, body, egress, &client_ip).await;
+        }
+    }
+
+    // Handle root endpoint
+    if path.is_empty() {
+        return HttpResponse::Ok()
+            .content_type("text/plain")
+            .body("Netty server deployed by Mujahid in Rust");
+    }
+    if let Some(response) = state.edge_files.respond(req) {
+        return response;
+    }
+    if let Some(response) = state.redirects.respond(req) {
+        return response;
+    }
+
+    // White-label tenants get their own upstreams, CORS policy and rate limit
+    let tenant = state.tenants.resolve(req);
+    let cors = tenant.and_then(|t| t.cors()).unwrap_or(&state.config.cors);
+
+    // Handle CORS preflight requests, ahead of rate limits and bot checks.
+    // Preflights carry no body, so routes that match on one never get them.
+    if req.method() == Method::OPTIONS {
+        match routes::route_for_body(&state.routes.snapshot(), req, None) {
+            Some((route, _)) if !routes::method_allowed(route, req) => {
+                return method_not_allowed(route, route.cors.as_ref().unwrap_or(cors));
+            }
+            Some((route, _)) if route.forward_preflight => {}
+            Some((route, _)) => {
+                metrics::inc("preflights_answered_total", &[("route", &route.name)]);
+                return cors::preflight(route.cors.as_ref().unwrap_or(cors));
+            }
+            None => return cors::preflight(cors),
+        }
+    }
+
+    if let Some(tenant) = tenant {
+        if !tenant.allow() {
+            return HttpResponse::TooManyRequests().body("Rate limit exceeded");
+        }
+    }
+
+    if let Some(bots) = &state.bots {
+        if bots.is_challenge(req) {
+            return bots.challenge(req, &client_ip);
+        }
+        if let Err(response) = bots.screen(req, &client_ip).await {
+            return response;
+        }
+    }
+
+    // Playback keep-alives are answered here rather than by a route
+    if let Some(streams) = &state.streams {
+        if !streams.heartbeat_path().is_empty() && req.path() == streams.heartbeat_path() {
+            let mut response = streams.heartbeat(req, state.jwt.as_ref()).await;
+            cors::insert(cors, response.headers_mut());
+            return response;
+        }
+    }
+
+    // Routes that look into the body need it read before one can be picked
+    let table = state.routes.snapshot();
+    let (body, json) = if routes::inspects_body(&table, req) {
+        let body = match middleware::read_body(state, req, body).await {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let json = match body.len() <= state.config.server.routing_body_bytes {
+            true => serde_json::from_slice(&body).ok(),
+            false => None,
+        };
+        (RequestBody::Read(body), json)
+    } else {
+        (RequestBody::Unread(body), None)
+    };
+    let (route, upstream_path) = match routes::route_for_body(&table, req, json.as_ref()) {
+        Some(found) => found,
+        None => return HttpResponse::NotFound().body("No route"),
+    };
+    timing::mark(req, "route");
+    deadline::start(req, &state.config.server, route, arrived);
+    let cors = route.cors.as_ref().unwrap_or(cors);
+    if !routes::method_allowed(route, req) {
+        return method_not_allowed(route, cors);
+    }
+    let normalize = state.config.normalize.as_ref();
+    let upstream_path = match normalize::apply(normalize, route, req, upstream_path, cors) {
+        Ok(path) => path,
+        Err(redirect) => return *redirect,
+    };
+    if let Some(mock) = route.mock.as_ref().filter(|m| m.enabled) {
+        return mock::respond(route, mock, req, cors).await;
+    }
+    if let Some(shedding) = &state.config.load_shedding {
+        if let Some(response) = shedding::check(shedding, route, state.inflight.count(), cors) {
+            return response;
+        }
+    }
+    // Regional catalogs: clients in a [geo] region go to that region's upstream
+    let region = match &state.geo {
+        Some(geo) if !route.regions.is_empty() => Some(geo.region(req, &client_ip)),
+        _ => None,
+    };
+    // Localized catalogs: clients go to the service for their language
+    let language = match route.languages.is_empty() {
+        true => None,
+        false => language::pick(req, route.languages.keys(), route),
+    };
+    // Scheduled cutovers and maintenance windows
+    let scheduled = schedule::active(route);
+    if let Some((rule, remaining)) = scheduled {
+        let action = if rule.maintenance.is_some() {
+            "maintenance"
+        } else {
+            "switch"
+        };
+        metrics::inc(
+            "scheduled_routing_total",
+            &[("route", &route.name), ("action", action)],
+        );
+        if let Some(message) = &rule.maintenance {
+            let mut response = HttpResponse::ServiceUnavailable();
+            cors::apply(cors, &mut response);
+            if let Some(remaining) = remaining {
+                response.insert_header((header::RETRY_AFTER, remaining));
+            }
+            return response.body(message.clone());
+        }
+    }
+    let upstream = match (
+        tenant,
+        scheduled.and_then(|(rule, _)| rule.upstream.as_ref()),
+    ) {
+        (Some(tenant), _) => tenant.upstream_for(route),
+        (None, Some(upstream)) => upstream,
+        (None, None) => match &route.blue_green {
+            Some(blue_green) => &blue_green.groups[&blue_green.live],
+            None => language
+                .and_then(|language| route.languages.get(language))
+                .or_else(|| region.and_then(|region| route.regions.get(region)))
+                .unwrap_or(&route.upstream),
+        },
+    };
+    // Templated upstreams are filled in from the request
+    let templated: String;
+    let (upstream, upstream_path) = match upstream.contains('{') {
+        true => {
+            let region = match (region, &state.geo) {
+                (None, Some(geo)) if upstream_url::uses_region(route) => {
+                    Some(geo.region(req, &client_ip))
+                }
+                (region, _) => region,
+            };
+            match upstream_url::expand(route, req, region, upstream, upstream_path) {
+                Ok((url, path)) => {
+                    templated = url;
+                    (templated.as_str(), path)
+                }
+                Err(name) => {
+                    let mut response = HttpResponse::BadRequest();
+                    cors::apply(cors, &mut response);
+                    return response.body(format!("Missing or invalid {}", name));
+                }
+            }
+        }
+        false => (upstream, upstream_path),
+    };
+    // The query goes upstream as the client sent it, and so is part of the
+    // cache key
+    let upstream_path = match req.query_string() {
+        "" => upstream_path,
+        query => format!("{}?{}", upstream_path, query),
+    };
+    if let Some(region) = region {
+        metrics::inc(
+            "geo_routed_total",
+            &[("route", &route.name), ("region", region)],
+        );
+    }
+    if let Some(language) = language {
+        metrics::inc(
+            "language_routed_total",
+            &[("route", &route.name), ("language", language)],
+        );
+    }
+
+    // Authentication, quotas, rate limits and so on, as configured per route
+    let mut outcome = match state
+        .middlewares
+        .run(state, req, route, upstream, upstream_path, body)
+        .await
+    {
+        Ok(outcome) => outcome,
+        Err(response) => return response,
+    };
+    timing::mark(req, "queue");
+    outcome
+        .headers
+        .remove(state.config.sampling.debug_header.as_str());
+    if let Some(allowed) = &route.compiled.allowed_headers {
+        let dropped: Vec<HeaderName> = outcome
+            .headers
+            .keys()
+            .filter(|name| !allowed.contains(name))
+            .cloned()
+            .collect();
+        // Client-chosen names would make the header a label without bound
+        for name in dropped {
+            metrics::inc("request_headers_dropped_total", &[("route", &route.name)]);
+            outcome.headers.remove(name);
+        }
+    }
+    // After the chain, so filters and scripts never see the secrets
+    for (name, value) in &route.compiled.secret_headers {
+        outcome.headers.insert(name.clone(), value.clone());
+    }
+    if let Some(audit) = &state.credential_audit {
+        audit.record(route, req, request_id, &outcome.upstream);
+    }
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        outcome.headers.insert(
+            header::HeaderName::from_static(access_log::REQUEST_ID_HEADER),
+            value,
+        );
+    }
+    if early {
+        outcome.headers.insert(
+            HeaderName::from_static("early-data"),
+            HeaderValue::from_static("1"),
+        );
+    }
+    let context = (state.config.context.as_ref())
+        .map(|config| context::decorate(config, req, request_id, &mut outcome.headers));
+    // Behind a PROXY protocol balancer the upstream can't see the client's
+    // address any other way; it goes last, after whatever the client claims
+    if let (Some(_), Some(peer)) = (&state.config.proxy_protocol, req.peer_addr()) {
+        let forwarded = match outcome.headers.get(header::X_FORWARDED_FOR) {
+            Some(value) => format!("{}, {}", value.to_str().unwrap_or_default(), peer.ip()),
+            None => peer.ip().to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&forwarded) {
+            outcome.headers.insert(header::X_FORWARDED_FOR, value);
+        }
+    }
+    inflight.set_upstream(&outcome.upstream);
+
+    // Sent on as it arrives unless something still needs the whole body
+    let stream = match outcome.payload.take() {
+        Some(payload) if streams_request(state, req, route) => {
+            match middleware::check_length(state, req) {
+                // Passed on so the upstream isn't sent a chunked body for it
+                Ok(Some(len)) => {
+                    outcome.headers.insert(header::CONTENT_LENGTH, len.into());
+                }
+                Ok(None) => {}
+                Err(response) => return *response,
+            }
+            Some(payload)
+        }
+        Some(payload) => {
+            outcome.body = match middleware::read_body(state, req, payload).await {
+                Ok(body) => body,
+                Err(response) => return response,
+            };
+            None
+        }
+        None => None,
+    };
+
+    if let Some(config) = &route.range_check {
+        if let Err(response) = check_range(state, req, route, config, &mut outcome, cors) {
+            return *response;
+        }
+    }
+
+    // Auth, quotas and the body may have used up the request's time already
+    if deadline::expired(req) {
+        deadline::exceeded(route, "local");
+        let url = format!("{}{}", outcome.upstream, outcome.path);
+        return failed(state, req, route, &url, ProxyError::Deadline);
+    }
+    deadline::propagate(req, route, &mut outcome.headers);
+
+    let dest = Destination {
+        upstream: &outcome.upstream,
+        path: &outcome.path,
+        headers: &outcome.headers,
+        partition: outcome.cache_partition.as_deref(),
+    };
+    let send = async {
+        if let Some(sink) = trailers::sink(req, route) {
+            let body = match stream {
+                Some(payload) => RequestBody::Unread(payload),
+                None => RequestBody::Read(outcome.body.clone()),
+            };
+            let forward = trailers::forward(state, req, route, dest, cors, body, sink);
+            forward.await.unwrap_or_else(|e| {
+                let url = format!("{}{}", dest.upstream, dest.path);
+                failed(state, req, route, &url, e)
+            })
+        } else if route.grpc && grpc::is_grpc_web(req.headers()) {
+            let (upstream, path) = (&outcome.upstream, &outcome.path);
+            let forward = grpc::forward_web(
+                state,
+                route,
+                upstream,
+                path,
+                &outcome.headers,
+                cors,
+                outcome.body.clone(),
+            );
+            match deadline::remaining(req) {
+                Some(left) => match tokio::time::timeout(left, forward).await {
+                    Ok(response) => response,
+                    Err(_) => {
+                        deadline::exceeded(route, "upstream");
+                        grpc::deadline_exceeded(&outcome.headers, cors)
+                    }
+                },
+                None => forward.await,
+            }
+        } else {
+            let vcr = state.vcr.as_ref().filter(|vcr| vcr.applies(route));
+            let (method, headers) = (req.method(), &outcome.headers);
+            let path = outcome.path.as_str();
+            match vcr {
+                Some(vcr) if vcr.replaying() => {
+                    let mut response = vcr
+                        .replay(route, method, path, headers, &outcome.body)
+                        .await;
+                    cors::insert(cors, response.headers_mut());
+                    response
+                }
+                Some(vcr) => {
+                    let body = RequestBody::Read(outcome.body.clone());
+                    let response = forward(state, req, route, dest, cors, body).await;
+                    let signature = vcr.signature(method, path, headers, &outcome.body);
+                    vcr.record(state, route, method, path, &signature, response)
+                        .await
+                }
+                None => {
+                    let body = match stream {
+                        Some(payload) => RequestBody::Unread(payload),
+                        None => RequestBody::Read(outcome.body.clone()),
+                    };
+                    forward(state, req, route, dest, cors, body).await
+                }
+            }
+        }
+    };
+    // Double submits within the route's window get the first one's response
+    let turn = route.dedup.as_ref().and_then(|config| {
+        let subject = outcome.subject.as_deref();
+        (state.dedup).begin(config, route, req, subject, &client_ip, &outcome.body)
+    });
+    let response = match turn {
+        Some(Turn::First(lead)) => lead.finish(send.await).await,
+        Some(Turn::Duplicate(first)) => match dedup::wait(first).await {
+            Some(response) => response,
+            None => send.await,
+        },
+        None => send.await,
+    };
+    let body = outcome.body.clone();
+    let response = with_fallback(state, req, route, dest, cors, body, response).await;
+    let response = state.middlewares.respond(state, req, route, response).await;
+    let policy = state.config.header_policy.as_ref();
+    let response = compliance::enforce(policy, route, response);
+    // Before compression, which would hide the magic bytes
+    let response = sniff::enforce(route, response).await;
+    let response = match &route.delta {
+        Some(delta) => {
+            let partition = outcome.cache_partition.as_deref();
+            (state.deltas)
+                .apply(delta, req, route, partition, response)
+                .await
+        }
+        None => response,
+    };
+    let mut response = encoding::negotiate(req, route, response).await;
+    if let (Some(config), Some(context)) = (&state.config.context, &context) {
+        context.finish(config, &mut response);
+    }
+    hints::apply(route, &mut response);
+    language::finish(route, &mut response);
+    if let Some(quota) = outcome.quota {
+        quota.apply(&mut response);
+    }
+
+    // Account the request and egress bytes against the tenant or API key
+    if let Some(metering) = &state.metering {
+        let tenant = match tenant {
+            Some(tenant) => tenant.name.clone(),
+            None => metering::account(req, metering.key_header()),
+        };
+        // Streamed bodies are counted by their declared length
+        let bytes = match response.body().size() {
+            BodySize::Sized(n) => n,
+            _ => response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+                .unwrap_or(0),
+        };
+        metering.record(&tenant, bytes);
+        if let Some(download) = (route.downloads.as_ref())
+            .and_then(|config| metering::download(config, route, req, &response))
+        {
+            response.extensions_mut().insert(download);
+        }
+    }
+
+    response.extensions_mut().insert(Served {
+        route: route.name.clone(),
+        upstream: outcome.upstream.clone(),
+        operation: outcome.operation.clone(),
+        region: region.map(str::to_string),
+    });
+    response
+}
+
+// The route's fallback in place of a response with a status it covers.
+// Whatever another upstream answers is served; a missing file or cache
+// entry leaves the response as it was.
+async fn with_fallback(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    cors: &CorsConfig,
+    body: web::Bytes,
+    response: HttpResponse,
+) -> HttpResponse {
+    let status = response.status();
+    let Some(fallback) = fallback::find(route, req.method(), status) else {
+        return response;
+    };
+    let replacement = match (&fallback.file, &fallback.upstream) {
+        (Some(path), _) => fallback::file(route, fallback, path, cors).await,
+        (_, Some(upstream)) => {
+            let dest = Destination { upstream, ..dest };
+            let body = RequestBody::Read(body);
+            Some(forward(state, req, route, dest, cors, body).await)
+        }
+        _ => {
+            let cached = state.cache.last_good(&cache_key(dest));
+            cached.map(|cached| cached_response(cached, cors))
+        }
+    };
+    fallback::record(route, fallback, status, replacement.is_some());
+    match replacement {
+        Some(replacement) => {
+            println!(
+                "Served {} fallback for {} {} on route {} after a {}",
+                fallback::kind(fallback),
+                req.method(),
+                req.path(),
+                route.name,
+                status.as_u16()
+            );
+            replacement
+        }
+        None => response,
+    }
+}
+
+fn idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    )
+}
+
+// Whether the request body can go upstream as it arrives. Recordings and
+// gRPC-Web translation need it whole whatever the route's buffering mode;
+// GETs and HEADs rarely have one and may be hedged. Otherwise the mode
+// decides, and in auto mode the body is only read whole for a retry.
+fn streams_request(state: &AppState, req: &HttpRequest, route: &RouteConfig) -> bool {
+    let method = req.method();
+    if *method == Method::GET
+        || *method == Method::HEAD
+        || (route.grpc && grpc::is_grpc_web(req.headers()))
+        || state.vcr.as_ref().is_some_and(|vcr| vcr.applies(route))
+        || (route.dedup.as_ref()).is_some_and(|dedup| dedup::covers(dedup, method))
+    {
+        return false;
+    }
+    let header = |name| {
+        let value = req.headers().get(name)?;
+        value.to_str().ok().map(str::to_string)
+    };
+    let streams = buffering::decide(&route.buffering, header)
+        .unwrap_or(route.retries == 0 || !idempotent(method));
+    let headers = req.headers();
+    if headers.contains_key(header::CONTENT_LENGTH)
+        || headers.contains_key(header::TRANSFER_ENCODING)
+    {
+        buffering::record(route, "request", streams);
+    }
+    streams
+}
+
+// Refuse a malformed Range header, or one with too many ranges once merged,
+// with a 416 and send the rest upstream sorted and merged. Ranges the cached length
+// shows can't be met are answered here too.
+fn check_range(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    config: &RangeCheckConfig,
+    outcome: &mut Outcome,
+    cors: &CorsConfig,
+) -> Result<(), Box<HttpResponse>> {
+    if *req.method() != Method::GET {
+        return Ok(());
+    }
+    let Some(value) = outcome.headers.get(header::RANGE) else {
+        return Ok(());
+    };
+    let value = value.to_str().unwrap_or_default().to_string();
+    let rejected = |reason: &str, total: Option<u64>| {
+        println!(
+            "Rejecting Range {:?} on route {}: {}",
+            value, route.name, reason
+        );
+        metrics::inc(
+            "ranges_rejected_total",
+            &[("route", &route.name), ("reason", reason)],
+        );
+        let mut response = HttpResponse::RangeNotSatisfiable();
+        cors::apply(cors, &mut response);
+        if let Some(total) = total {
+            response.insert_header((header::CONTENT_RANGE, format!("bytes */{}", total)));
+        }
+        Box::new(response.finish())
+    };
+    let Some(ranges) = ranges::parse_all(&value) else {
+        return Err(rejected("invalid", None));
+    };
+
+    let total = match state.middlewares.caches(route) {
+        true => {
+            let dest = Destination {
+                upstream: &outcome.upstream,
+                path: &outcome.path,
+                headers: &outcome.headers,
+                partition: outcome.cache_partition.as_deref(),
+            };
+            let key = cache_key(dest);
+            (state.cache.length(&key)).or_else(|| state.cache.length(&ranges::chunk_key(&key, 0)))
+        }
+        false => None,
+    };
+    match ranges::normalize(&ranges, total) {
+        Normalized::Unsatisfiable => Err(rejected("unsatisfiable", total)),
+        Normalized::Ranges(specs) if specs.len() > config.max_ranges => {
+            Err(rejected("too_many", None))
+        }
+        Normalized::Ranges(specs) => {
+            let normalized = format!("bytes={}", specs.join(","));
+            if normalized == value {
+                return Ok(());
+            }
+            metrics::inc("ranges_normalized_total", &[("route", &route.name)]);
+            if let Ok(normalized) = HeaderValue::from_str(&normalized) {
+                outcome.headers.insert(header::RANGE, normalized);
+            }
+            Ok(())
+        }
+    }
+}
+
+// Where a request goes: an upstream URL or pool, and the path and headers to
+// send there
+#[derive(Clone, Copy)]
+pub struct Destination<'a> {
+    pub upstream: &'a str,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap,
+    // Whose cache entries the response belongs with, on per-user routes
+    pub partition: Option<&'a str>,
+}
+
+// Where a response is kept in the cache: by URL, and apart from everyone
+// else's when it's one user's
+fn cache_key(dest: Destination<'_>) -> String {
+    let url = format!("{}{}", dest.upstream, dest.path);
+    match dest.partition {
+        Some(partition) => format!("{} user={}", url, partition),
+        None => url,
+    }
+}
+
+// Forward the request to the route's upstream, going through the cache when enabled
+async fn forward(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    cors: &CorsConfig,
+    body: RequestBody,
+) -> HttpResponse {
+    let url = format!("{}{}", dest.upstream, dest.path);
+
+    // Pools were checked target by target when the config was loaded.
+    // Host names are checked again as they resolve.
+    if let (Some(policy), false) = (
+        &state.egress,
+        state.config.upstreams.contains_key(dest.upstream),
+    ) {
+        if let Err(e) = policy.check(dest.upstream) {
+            return failed(state, req, route, &url, ProxyError::Egress(e));
+        }
+    }
+
+    // Held until the response head is back; streamed bodies don't count
+    let _permit = match state.upstreams.acquire(dest.upstream) {
+        Some(permit) => permit,
+        None => {
+            metrics::inc(
+                "upstream_concurrency_rejected_total",
+                &[("upstream", dest.upstream)],
+            );
+            let mut response = HttpResponse::ServiceUnavailable();
+            cors::apply(cors, &mut response);
+            return response
+                .insert_header((header::RETRY_AFTER, 1))
+                .body("Upstream overloaded");
+        }
+    };
+
+    let watch = disconnect::Watch::new(&route.name);
+    let key = cache_key(dest);
+    let result = send_upstream(state, req, route, dest, &key, cors, body).await;
+    watch.done();
+    match result {
+        Ok(response) => response,
+        Err(e) => failed(state, req, route, &url, e),
+    }
+}
+
+fn failed(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    url: &str,
+    e: ProxyError,
+) -> HttpResponse {
+    match &e {
+        ProxyError::Egress(reason) => egress_policy::refused(&route.name, url, reason),
+        _ => eprintln!(
+            "Proxy error: kind={} route={} method={} path={} upstream={}: {}",
+            e.kind(),
+            route.name,
+            req.method(),
+            req.path(),
+            url,
+            e
+        ),
+    }
+    let response = state.errors.response(&e);
+    events::publish(Event::Error {
+        route: route.name.clone(),
+        status: response.status().as_u16(),
+        message: e.to_string(),
+    });
+    response
+}
+
+async fn send_upstream(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    // Where the response is kept in the cache
+    key: &str,
+    cors: &CorsConfig,
+    body: RequestBody,
+) -> Result<HttpResponse, ProxyError> {
+    // Only plain GETs without credentials (cookies included) are served from
+    // the cache, unless the route keeps each user's entries apart. HEADs are
+    // answered from a cached GET too, but never stored.
+    let credentials = [header::AUTHORIZATION, header::COOKIE];
+    let cached_route = state.middlewares.caches(route)
+        && (dest.partition.is_some()
+            || !credentials
+                .iter()
+                .any(|name| req.headers().contains_key(name)));
+    let cacheable = cached_route && req.method() == Method::GET;
+
+    if cacheable && req.headers().contains_key(header::RANGE) {
+        if let Some(result) = send_ranged(state, req, route, dest, key, cors).await {
+            return result;
+        }
+    }
+
+    if cacheable || (cached_route && req.method() == Method::HEAD) {
+        // A variant in a coding the client takes first, then the response
+        // as it is
+        let variant =
+            encoding::variants(req, key).find_map(|key| state.cache.hit(&route.name, &key));
+        if let Some(cached) = variant {
+            return Ok(cached_response(cached, cors));
+        }
+        if let Lookup::Hit(cached) = state.cache.get(&route.name, key) {
+            let cached = match encoding::gzip_variant(req, route, &cached) {
+                Some(gzipped) => {
+                    if let Some(key) = encoding::cache_key(key, Some("gzip")) {
+                        state.cache.put(key, gzipped.clone());
+                    }
+                    gzipped
+                }
+                None => cached,
+            };
+            return Ok(cached_response(cached, cors));
+        }
+    }
+
+    // Forward request to API Gateway, retrying idempotent requests on
+    // connection failures and gateway errors while the retry budget allows
+    let idempotent = idempotent(req.method());
+    // A streamed body can only be sent once
+    let (body, mut stream) = match body {
+        RequestBody::Read(body) => (body, None),
+        RequestBody::Unread(payload) => (web::Bytes::new(), Some(payload)),
+    };
+    let streamed = stream.is_some();
+    let mut aborted = None;
+    let hedge = route
+        .hedge
+        .as_ref()
+        .filter(|_| matches!(*req.method(), Method::GET | Method::HEAD));
+    let mut attempt = 0;
+    // 429s waited out so far
+    let mut throttled = 0;
+    let mut tried = Vec::new();
+    let upstream = dest.upstream;
+    let forwarded_req = loop {
+        state.retry_budget.record_request(upstream);
+        let result = match hedge {
+            Some(hedge) => send_hedged(state, req, route, dest, hedge, &body, &mut tried).await,
+            None => {
+                let target = state.upstreams.pick(upstream, &tried);
+                tried.push(target.clone());
+                let body = match stream.take() {
+                    Some(payload) => {
+                        let (body, reason) = middleware::stream_body(
+                            &state.config.server,
+                            payload,
+                            middleware::request_verifier(state, req),
+                        );
+                        aborted = Some(reason);
+                        body
+                    }
+                    None => body.clone().into(),
+                };
+                send_once(state, req, route, dest, &target, body).await
+            }
+        };
+
+        let retryable = match &result {
+            Ok(resp) => matches!(resp.status().as_u16(), 502..=504),
+            // Refused by the egress policy, which won't change its mind
+            Err(e) if egress_policy::refusal(e).is_some() => false,
+            Err(e) => e.is_connect() || e.is_timeout(),
+        };
+        // A short Retry-After on a 429 is waited out here, up to the
+        // route's number of times
+        let wait = match (&result, &route.throttle) {
+            (Ok(resp), Some(throttle))
+                if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    && idempotent
+                    && !streamed
+                    && throttled < throttle.max_retries =>
+            {
+                retry::retry_after(resp.headers())
+                    .filter(|wait| *wait <= Duration::from_millis(throttle.max_wait_ms))
+            }
+            _ => None,
+        };
+        // Nor is there any point waiting past the deadline
+        let wait = wait.filter(|wait| deadline::remaining(req).is_none_or(|left| *wait < left));
+        if let Some(wait) = wait {
+            if state.retry_budget.try_retry(upstream) {
+                throttled += 1;
+                metrics::inc("upstream_throttle_waits_total", &[("route", &route.name)]);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+            println!("Retry budget exhausted for {}, not retrying", upstream);
+            break result;
+        }
+        if !retryable || !idempotent || streamed || attempt >= route.retries {
+            break result;
+        }
+        if deadline::expired(req) {
+            break result;
+        }
+        if !state.retry_budget.try_retry(upstream) {
+            println!("Retry budget exhausted for {}, not retrying", upstream);
+            break result;
+        }
+        attempt += 1;
+    };
+
+    match forwarded_req {
+        Ok(resp) => {
+            let status = resp.status();
+            if status.is_server_error() && state.errors.maps_upstream(status) {
+                return Err(ProxyError::Upstream(status));
+            }
+
+            let mut response = HttpResponse::build(status);
+
+            // Ensure CORS headers are inserted only once
+            cors::apply(cors, &mut response);
+
+            // Copy all headers from the forwarded response. Trailers can't be
+            // relayed here (trailers routes relay them on the [grpc]
+            // listener), so they aren't announced either.
+            for (key, value) in resp.headers() {
+                if key != header::TRAILER && !cors::is_cors(key.as_str()) {
+                    response.insert_header((key.clone(), value.clone()));
+                }
+            }
+            let upstream_headers = resp.headers().iter();
+            cors::copy_upstream(
+                cors,
+                &mut response,
+                upstream_headers.map(|(k, v)| (k.as_str(), v.as_bytes())),
+            );
+
+            let headers = resp.headers().clone();
+
+            if status.is_server_error() {
+                events::publish(Event::Error {
+                    route: route.name.clone(),
+                    status: status.as_u16(),
+                    message: format!("Upstream returned {}", status),
+                });
+            }
+
+            // A HEAD has no body, but keeps the length the GET would have
+            if req.method() == Method::HEAD {
+                let length = headers
+                    .get(header::CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok());
+                if let Some(len) = length {
+                    response.no_chunking(len);
+                    let empty = futures_util::stream::empty::<Result<web::Bytes, std::io::Error>>();
+                    return Ok(response.streaming(empty));
+                }
+            }
+            // Encoded responses are kept apart from plain ones
+            let coding = headers
+                .get(header::CONTENT_ENCODING)
+                .and_then(|v| v.to_str().ok());
+            let stored_key = encoding::cache_key(key, coding);
+            let ttl = match cacheable {
+                _ if stored_key.is_none() => None,
+                false => None,
+                true if status == reqwest::StatusCode::OK => cache::ttl_for(
+                    &route.cache_policy,
+                    &headers,
+                    dest.partition.is_some(),
+                    state.config.cache.default_ttl_secs,
+                ),
+                true => cache::negative_ttl(
+                    &route.cache_policy,
+                    status.as_u16(),
+                    &headers,
+                    dest.partition.is_some(),
+                ),
+            };
+            // Bodies are relayed chunk by chunk as they arrive, without being
+            // copied, unless the route buffers them or (in auto mode) the
+            // cache or content sniffing needs all of it
+            let header = |name| {
+                let value = headers.get(name)?;
+                value.to_str().ok().map(str::to_string)
+            };
+            let decided = buffering::decide(&route.buffering, header);
+            let sniffed =
+                route.content_type.as_ref().is_some_and(|c| c.sniff) && decided != Some(true);
+            // Rather than refuse a cacheable response the memory budget has
+            // no room for, pass it on without keeping it
+            let ttl = ttl.filter(|_| decided != Some(true)).filter(|_| {
+                let len = resp.content_length().unwrap_or(0) as usize;
+                let fits = sniffed || state.memory.fits(len);
+                if !fits {
+                    metrics::inc("memory_budget_rejected_total", &[("kind", "cache")]);
+                }
+                fits
+            });
+            let streamed = ttl.is_none() && !sniffed && decided != Some(false);
+            buffering::record(route, "response", streamed);
+            if streamed {
+                if let Some(len) = resp.content_length() {
+                    let limit = route.response_limit(&state.config.server);
+                    if limit.is_some_and(|limit| len > limit as u64) {
+                        return Err(response_too_large(route, limit.unwrap_or_default()));
+                    }
+                    response.no_chunking(len);
+                }
+                // Encoded bodies are split into lines once decoded, if at all
+                let content_type =
+                    header("content-type").filter(|_| header("content-encoding").is_none());
+                if buffering::line_delimited(&route.buffering, content_type.as_deref()) {
+                    let body = Box::pin(stream_body(state, route, resp));
+                    return Ok(response.streaming(buffering::by_line(body)));
+                }
+                return Ok(response.streaming(stream_body(state, route, resp)));
+            }
+
+            let (body, reservation) = read_body(state, route, resp).await?;
+
+            if let (Some(ttl), Some(stored_key)) = (ttl, stored_key) {
+                // The cache counts the same bytes from here on
+                drop(reservation);
+                if status != reqwest::StatusCode::OK {
+                    metrics::inc(
+                        "cache_negative_stored_total",
+                        &[("route", &route.name), ("status", status.as_str())],
+                    );
+                }
+                state.cache.put(
+                    stored_key,
+                    CachedResponse {
+                        status: status.as_u16(),
+                        headers: headers
+                            .iter()
+                            .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                            .collect(),
+                        body: body.clone(),
+                        stored_at: Instant::now(),
+                        ttl,
+                    },
+                );
+            } else {
+                memory::hold(req, reservation);
+            }
+
+            Ok(response.body(body))
+        }
+        // The client's fault when it broke off the body it was streaming
+        Err(e) => match aborted.and_then(|reason| reason.lock().unwrap().take()) {
+            Some(error) => Err(error),
+            None if e.is_timeout() && deadline::expired(req) => {
+                deadline::exceeded(route, "upstream");
+                Err(ProxyError::Deadline)
+            }
+            None => Err(ProxyError::from_reqwest(e)),
+        },
+    }
+}
+
+// What one upstream range request for a run of chunks came back with
+enum Fetched {
+    Chunks(Vec<(u64, CachedResponse)>),
+    // Anything but a 200 or 206, handed to the client as is
+    Response(Box<HttpResponse>),
+}
+
+// Serve a ranged GET from fixed-size cached chunks, fetching only the
+// missing ones. Several ranges make a multipart/byteranges response. None
+// means the request is forwarded as usual.
+async fn send_ranged(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    url: &str,
+    cors: &CorsConfig,
+) -> Option<Result<HttpResponse, ProxyError>> {
+    let chunk = state.config.cache.chunk_bytes as u64;
+    if chunk == 0 || req.headers().contains_key(header::IF_RANGE) {
+        return None;
+    }
+    let ranges = ranges::parse_all(dest.headers.get(header::RANGE)?.to_str().ok()?)?;
+
+    let mut chunks = BTreeMap::new();
+    // Those of the ranges that lie within the resource
+    let mut spans = Vec::new();
+    for range in ranges {
+        // Those of the ranges before this one
+        let held = chunks.len() as u64;
+        // Until the length is known, open-ended ranges start with their first
+        // chunk and suffix ranges with chunk 0; both then tell us the length
+        let (mut first, mut last) = match range {
+            ByteRange::From(start, end) => (start / chunk, end.unwrap_or(start) / chunk),
+            ByteRange::Suffix(_) => (0, 0),
+        };
+        loop {
+            // Huge ranges would be buffered whole, leave those to plain forwarding
+            let wanted = (last - first + 1).saturating_add(held);
+            if wanted.saturating_mul(chunk) > state.config.cache.max_bytes as u64 {
+                return None;
+            }
+
+            let mut missing = Vec::new();
+            for index in first..=last {
+                if chunks.contains_key(&index) {
+                    continue;
+                }
+                match state.cache.get(&route.name, &ranges::chunk_key(url, index)) {
+                    Lookup::Hit(cached) => {
+                        chunks.insert(index, cached);
+                    }
+                    _ => missing.push(index),
+                }
+            }
+            metrics::add(
+                "cache_chunks_total",
+                &[("route", &route.name), ("result", "hit")],
+                (last - first + 1 - missing.len() as u64) as f64,
+            );
+            metrics::add(
+                "cache_chunks_total",
+                &[("route", &route.name), ("result", "miss")],
+                missing.len() as f64,
+            );
+
+            for run in ranges::runs(&missing) {
+                match fetch_chunks(state, req, route, dest, url, cors, run).await {
+                    Ok(Fetched::Chunks(fetched)) => chunks.extend(fetched),
+                    Ok(Fetched::Response(response)) => return Some(Ok(*response)),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            // Chunks from different versions of the resource can't be combined
+            let total = ranges::consistent_total(&chunks)?;
+            let Some((start, end)) = range.resolve(total) else {
+                break;
+            };
+            if start / chunk >= first && end / chunk <= last {
+                spans.push((start, end));
+                break;
+            }
+            (first, last) = (start / chunk, end / chunk);
+        }
+    }
+    let total = ranges::consistent_total(&chunks)?;
+
+    match spans.as_slice() {
+        [] => {
+            let mut response = HttpResponse::RangeNotSatisfiable();
+            cors::apply(cors, &mut response);
+            let content_range = format!("bytes */{}", total);
+            Some(Ok(response
+                .insert_header((header::CONTENT_RANGE, content_range))
+                .finish()))
+        }
+        // A chunk can still be missing if the upstream sent less than asked for
+        [(start, end)] => {
+            let assembled = ranges::assemble(&chunks, *start, *end, total, chunk)?;
+            Some(Ok(cached_response(assembled, cors)))
+        }
+        // Streamed a part at a time, straight from the chunks
+        spans => {
+            let boundary = keys::hex(&rand::random::<[u8; 12]>());
+            let (head, pieces) = ranges::multipart(&chunks, spans, total, chunk, &boundary)?;
+            let len = pieces.iter().map(|piece| piece.len() as u64).sum();
+            let pieces = pieces.into_iter().map(Ok::<_, std::io::Error>);
+            let body = SizedStream::new(len, futures_util::stream::iter(pieces));
+            metrics::inc("ranges_multipart_total", &[("route", &route.name)]);
+            let response = cached_response(head, cors).set_body(body);
+            Some(Ok(response.map_into_boxed_body()))
+        }
+    }
+}
+
+// Fetch a run of chunks with one upstream range request, caching them when
+// the upstream allows it
+async fn fetch_chunks(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    url: &str,
+    cors: &CorsConfig,
+    (first, last): (u64, u64),
+) -> Result<Fetched, ProxyError> {
+    let chunk = state.config.cache.chunk_bytes as u64;
+    let mut headers = dest.headers.clone();
+    let range = format!("bytes={}-{}", first * chunk, (last + 1) * chunk - 1);
+    headers.insert(
+        header::RANGE,
+        HeaderValue::from_str(&range).map_err(|e| ProxyError::Other(e.to_string()))?,
+    );
+    let dest = Destination {
+        headers: &headers,
+        ..dest
+    };
+
+    let target = state.upstreams.pick(dest.upstream, &[]);
+    let resp = send_once(state, req, route, dest, &target, web::Bytes::new().into())
+        .await
+        .map_err(ProxyError::from_reqwest)?;
+    let status = resp.status();
+    if status.is_server_error() && state.errors.maps_upstream(status) {
+        return Err(ProxyError::Upstream(status));
+    }
+    let resp_headers = resp.headers().clone();
+    let (body, reservation) = read_body(state, route, resp).await?;
+
+    let content_range = resp_headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(ranges::content_range);
+    let (start, total) = match (status.as_u16(), content_range) {
+        (206, Some((start, _, total))) => (start, total),
+        // The upstream ignored the range and sent everything
+        (200, _) => (0, body.len() as u64),
+        _ => {
+            let mut response = HttpResponse::build(status);
+            cors::apply(cors, &mut response);
+            for (key, value) in &resp_headers {
+                if !cors::is_cors(key.as_str()) {
+                    response.insert_header((key.clone(), value.clone()));
+                }
+            }
+            let upstream_headers = resp_headers.iter();
+            cors::copy_upstream(
+                cors,
+                &mut response,
+                upstream_headers.map(|(k, v)| (k.as_str(), v.as_bytes())),
+            );
+            memory::hold(req, reservation);
+            return Ok(Fetched::Response(Box::new(response.body(body))));
+        }
+    };
+    // Chunks have to start on a chunk boundary to be reused
+    if start % chunk != 0 {
+        return Ok(Fetched::Chunks(Vec::new()));
+    }
+
+    let ttl = cache::ttl_for(
+        &route.cache_policy,
+        &resp_headers,
+        dest.partition.is_some(),
+        state.config.cache.default_ttl_secs,
+    );
+    let stored: Vec<(String, Vec<u8>)> = resp_headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+        .collect();
+    let fetched = ranges::split(&body, start, total, chunk, &stored, ttl.unwrap_or_default());
+    if ttl.is_none() {
+        memory::hold(req, reservation);
+    } else {
+        drop(reservation);
+        for (index, cached) in &fetched {
+            state
+                .cache
+                .put(ranges::chunk_key(url, *index), cached.clone());
+        }
+    }
+    Ok(Fetched::Chunks(fetched))
+}
+
+// Buffer the upstream body, giving up as soon as it's over the route's limit
+// or the memory budget so an endless or huge response can't exhaust memory.
+// The body's share of the budget comes back with it.
+async fn read_body(
+    state: &AppState,
+    route: &RouteConfig,
+    mut resp: reqwest::Response,
+) -> Result<(web::Bytes, Reservation), ProxyError> {
+    let limit = route
+        .response_limit(&state.config.server)
+        .unwrap_or(usize::MAX);
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(response_too_large(route, limit));
+    }
+
+    // Sized up front when the length is known, so it's never copied to grow
+    let capacity = resp.content_length().unwrap_or(0).min(limit as u64) as usize;
+    let mut reservation = state
+        .memory
+        .reserve(Kind::Response, capacity)
+        .ok_or(ProxyError::MemoryBudget)?;
+    let mut body = web::BytesMut::with_capacity(capacity);
+    let verifier = response_verifier(state, &resp);
+    while let Some(chunk) = resp.chunk().await.map_err(ProxyError::from_reqwest)? {
+        if body.len() + chunk.len() > limit {
+            return Err(response_too_large(route, limit));
+        }
+        if !reservation.ensure(body.len() + chunk.len()) {
+            return Err(ProxyError::MemoryBudget);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    if let Some(mut verifier) = verifier {
+        verifier.update(&body);
+        verifier.finish().map_err(ProxyError::ResponseChecksum)?;
+    }
+    Ok((body.freeze(), reservation))
+}
+
+// A response refused before any of it was sent
+fn response_too_large(route: &RouteConfig, limit: usize) -> ProxyError {
+    metrics::inc(
+        "upstream_response_limit_total",
+        &[("route", &route.name), ("mode", "buffered")],
+    );
+    ProxyError::ResponseTooLarge { limit }
+}
+
+// A chunked upstream body as a stream, cut off once it's over the route's
+// limit. The client then sees the response end without its last chunk.
+fn stream_body(
+    state: &AppState,
+    route: &RouteConfig,
+    resp: reqwest::Response,
+) -> impl futures_util::Stream<Item = Result<web::Bytes, std::io::Error>> {
+    let limit = route
+        .response_limit(&state.config.server)
+        .unwrap_or(usize::MAX);
+    let name = route.name.clone();
+    let relay = Relay {
+        verifier: response_verifier(state, &resp),
+        resp,
+        sent: 0,
+        held: None,
+    };
+    futures_util::stream::unfold(Some(relay), move |relay| {
+        let name = name.clone();
+        async move {
+            let mut relay = relay?;
+            loop {
+                match relay.resp.chunk().await {
+                    Ok(Some(chunk)) if relay.sent + chunk.len() > limit => {
+                        cut_off(&name, limit);
+                        let error = ProxyError::ResponseTooLarge { limit };
+                        return Some((Err(std::io::Error::other(error.to_string())), None));
+                    }
+                    Ok(Some(chunk)) => {
+                        relay.sent += chunk.len();
+                        let Some(verifier) = &mut relay.verifier else {
+                            return Some((Ok(chunk), Some(relay)));
+                        };
+                        // The last chunk waits for the digest to be checked,
+                        // so a corrupt body never reaches the client whole
+                        verifier.update(&chunk);
+                        if let Some(held) = relay.held.replace(chunk) {
+                            return Some((Ok(held), Some(relay)));
+                        }
+                    }
+                    Ok(None) => {
+                        if let Some(Err(algorithm)) = relay.verifier.take().map(Verifier::finish) {
+                            let error = ProxyError::ResponseChecksum(algorithm);
+                            eprintln!("Cut off streamed response on route {}: {}", name, error);
+                            return Some((Err(std::io::Error::other(error.to_string())), None));
+                        }
+                        let held = relay.held.take()?;
+                        return Some((Ok(held), Some(relay)));
+                    }
+                    Err(e) => return Some((Err(std::io::Error::other(e)), None)),
+                }
+            }
+        }
+    })
+}
+
+// Where stream_body is in relaying a response
+struct Relay {
+    resp: reqwest::Response,
+    sent: usize,
+    verifier: Option<Verifier>,
+    held: Option<web::Bytes>,
+}
+
+// Checks for an upstream response's body, when it declares a digest.
+// Partial content is left alone, since a digest may be of the whole thing.
+fn response_verifier(state: &AppState, resp: &reqwest::Response) -> Option<Verifier> {
+    if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    Verifier::new(state.config.checksums.as_ref(), "response", |name| {
+        let value = resp.headers().get(name)?;
+        value.to_str().ok().map(str::to_string)
+    })
+}
+
+pub fn cut_off(route: &str, limit: usize) {
+    eprintln!(
+        "Cut off streamed response on route {} after {} bytes",
+        route, limit
+    );
+    metrics::inc(
+        "upstream_response_limit_total",
+        &[("route", route), ("mode", "streamed")],
+    );
+}
+
+async fn send_once(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    target: &str,
+    body: reqwest::Body,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let own = match route.long_poll {
+        true => route.upstream_timeout_secs.unwrap_or(0),
+        false => (route.upstream_timeout_secs).unwrap_or(state.config.server.upstream_timeout_secs),
+    };
+    let timeout = match own {
+        0 => deadline::remaining(req),
+        secs => Some(deadline::timeout(req, Duration::from_secs(secs))),
+    };
+    let started = Instant::now();
+    let outstanding = state.upstreams.begin(dest.upstream, target);
+    let mut request = state
+        .upstreams
+        .client(dest.upstream, &state.client)
+        .request(req.method().clone(), format!("{}{}", target, dest.path))
+        .headers(dest.headers.clone().into()); // Convert headers to reqwest's HeaderMap
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let result = request.body(body).send().await;
+    drop(outstanding);
+
+    let latency = started.elapsed();
+    // How long a long poll was held says nothing about the upstream
+    let observed = (!route.long_poll).then_some(latency);
+    if let Ok(resp) = &result {
+        if !route.long_poll {
+            state.latency.record(&route.name, latency);
+        }
+        let idle = state.upstreams.idle_timeout(dest.upstream);
+        if state.connections.observe(dest.upstream, target, resp, idle) {
+            let setup = resp.remote_addr().and_then(|a| state.connections.setup(a));
+            timing::record(req, "upstream-connect", setup);
+        } else {
+            timing::record(req, "upstream-connect", Some(Duration::ZERO));
+        }
+        timing::record(req, "upstream-ttfb", Some(latency));
+    }
+    let success = matches!(&result, Ok(resp) if !resp.status().is_server_error());
+    if matches!(&result, Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS) {
+        state.upstreams.throttled(dest.upstream, latency);
+    } else {
+        state
+            .upstreams
+            .report(dest.upstream, target, success, observed);
+    }
+    result
+}
+
+async fn send_indexed(
+    index: u32,
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    target: String,
+    body: web::Bytes,
+) -> (u32, Result<reqwest::Response, reqwest::Error>) {
+    (
+        index,
+        send_once(state, req, route, dest, &target, body.into()).await,
+    )
+}
+
+// Send to one replica and, if it hasn't answered within the hedge delay, to
+// another one as well. The first successful response wins; the rest are dropped.
+async fn send_hedged(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    hedge: &HedgeConfig,
+    body: &web::Bytes,
+    tried: &mut Vec<String>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let delay = state.latency.hedge_delay(&route.name, hedge);
+    let started = tokio::time::Instant::now();
+
+    let target = state.upstreams.pick(dest.upstream, tried);
+    tried.push(target.clone());
+    let mut pending = FuturesUnordered::new();
+    pending.push(send_indexed(
+        0,
+        state,
+        req,
+        route,
+        dest,
+        target,
+        body.clone(),
+    ));
+
+    let mut hedges = 0;
+    // The latest attempt that failed or got a server error, in case none
+    // does better
+    let mut last = None;
+    loop {
+        // With every attempt so far failed there's no point waiting
+        let next_hedge = match pending.is_empty() {
+            true => tokio::time::Instant::now(),
+            false => started + delay * (hedges + 1),
+        };
+        let can_hedge = hedges < hedge.max_hedges && !deadline::expired(req);
+        tokio::select! {
+            Some((index, result)) = pending.next() => {
+                if matches!(&result, Ok(resp) if !resp.status().is_server_error()) {
+                    if index > 0 {
+                        metrics::inc("hedge_wins_total", &[("route", &route.name)]);
+                    }
+                    return result;
+                }
+                last = Some(result);
+            },
+            _ = tokio::time::sleep_until(next_hedge), if can_hedge => {
+                hedges += 1;
+                let target = state.upstreams.pick(dest.upstream, tried);
+                tried.push(target.clone());
+                metrics::inc("hedges_total", &[("route", &route.name)]);
+                pending.push(send_indexed(hedges, state, req, route, dest, target, body.clone()));
+            }
+            // Every attempt is done and no more may be made
+            else => return last.expect("the first attempt has finished"),
+        }
+    }
+}
+
+fn cached_response(cached: CachedResponse, cors: &CorsConfig) -> HttpResponse {
+    let mut response = HttpResponse::build(
+        actix_web::http::StatusCode::from_u16(cached.status)
+            .unwrap_or(actix_web::http::StatusCode::OK),
+    );
+
+    cors::apply(cors, &mut response);
+    let stored = cached.headers.iter();
+    cors::copy_upstream(
+        cors,
+        &mut response,
+        stored.map(|(k, v)| (k.as_str(), v.as_slice())),
+    );
+
+    for (key, value) in cached.headers {
+        if !cors::is_cors(&key) {
+            response.insert_header((key, value));
+        }
+    }
+
+    let age = cached.stored_at.elapsed().as_secs().to_string();
+    response.insert_header((header::AGE, age));
+    response.body(cached.body)
+}
@@ -0,0 +1,542 @@
+use crate::client_ip;
+use crate::config::{ProxyProtocolConfig, ServerConfig};
+use crate::early_data;
+use crate::metrics;
+use crate::tuning;
+use actix_http::body::MessageBody;
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol, Request, Response};
+use actix_server::Server;
+use actix_service::{
+    fn_service, map_config, IntoServiceFactory, ServiceFactory, ServiceFactoryExt,
+};
+use actix_web::dev::AppConfig;
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+use std::fmt;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// The longest a v1 header can be, CRLF included
+const V1_MAX_BYTES: usize = 107;
+
+// Which connections must open with a PROXY protocol header naming the
+// client they carry: those from the load balancers in trusted_sources. Their
+// own address is only the balancer's; anyone else could claim any address.
+pub struct ProxyProtocol {
+    trusted: Vec<IpNet>,
+    allow_direct: bool,
+}
+
+impl ProxyProtocol {
+    pub fn new(config: &ProxyProtocolConfig) -> Result<Self, Error> {
+        let trusted = config
+            .trusted_sources
+            .iter()
+            .map(|source| {
+                client_ip::network(source).ok_or_else(|| {
+                    Error::other(format!("Invalid proxy_protocol trusted source {}", source))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if trusted.is_empty() && !config.allow_direct {
+            return Err(Error::other(
+                "proxy_protocol needs trusted_sources or allow_direct",
+            ));
+        }
+        Ok(ProxyProtocol {
+            trusted,
+            allow_direct: config.allow_direct,
+        })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted.iter().any(|network| network.contains(&ip))
+    }
+
+    // The client's address: from the PROXY header when the connection comes
+    // from a trusted source, otherwise the connection's own. Err with the
+    // reason the connection is refused.
+    async fn accept(&self, io: &mut TcpStream) -> Result<SocketAddr, &'static str> {
+        let peer = io.peer_addr().map_err(|_| "disconnected")?;
+        if !self.trusts(peer.ip()) {
+            if self.allow_direct {
+                metrics::inc("proxy_protocol_connections_total", &[("version", "none")]);
+                return Ok(peer);
+            }
+            return Err("untrusted");
+        }
+        let (version, client) = read_header(io).await?;
+        metrics::inc("proxy_protocol_connections_total", &[("version", version)]);
+        // LOCAL and UNKNOWN are the balancer's own connections, health
+        // checks mostly
+        Ok(client.unwrap_or(peer))
+    }
+
+    async fn client(
+        &self,
+        io: &mut TcpStream,
+        limit: Duration,
+    ) -> Result<SocketAddr, DispatchError> {
+        let reason = match timeout(limit, self.accept(io)).await {
+            Ok(Ok(client)) => return Ok(client),
+            Ok(Err(reason)) => reason,
+            Err(_) => "timeout",
+        };
+        let peer = io.peer_addr().map(|addr| addr.to_string());
+        println!(
+            "Refusing connection from {}: PROXY protocol {}",
+            peer.as_deref().unwrap_or("unknown"),
+            reason
+        );
+        metrics::inc("proxy_protocol_rejected_total", &[("reason", reason)]);
+        Err(DispatchError::Io(Error::other(reason)))
+    }
+}
+
+// The protocol version and, for proxied connections, the client address
+async fn read_header<R: AsyncRead + Unpin>(
+    io: &mut R,
+) -> Result<(&'static str, Option<SocketAddr>), &'static str> {
+    // Read no further than the header, whose length only shows as it's read:
+    // what follows belongs to the HTTP or TLS layer
+    let mut start = [0u8; 6];
+    io.read_exact(&mut start).await.map_err(|_| "incomplete")?;
+    if &start == b"PROXY " {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_BYTES {
+                return Err("malformed");
+            }
+            line.push(io.read_u8().await.map_err(|_| "incomplete")?);
+        }
+        return parse_v1(&line).map(|client| ("v1", client));
+    }
+    if start != V2_SIGNATURE[..6] {
+        return Err("missing");
+    }
+    let mut head = [0u8; 16];
+    head[..6].copy_from_slice(&start);
+    io.read_exact(&mut head[6..])
+        .await
+        .map_err(|_| "incomplete")?;
+    if head[..12] != V2_SIGNATURE[..] {
+        return Err("missing");
+    }
+    let mut body = vec![0u8; u16::from_be_bytes([head[14], head[15]]) as usize];
+    io.read_exact(&mut body).await.map_err(|_| "incomplete")?;
+    parse_v2(head[12], head[13], &body).map(|client| ("v2", client))
+}
+
+// "PROXY TCP4 <source> <destination> <source port> <destination port>\r\n"
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, &'static str> {
+    let line = std::str::from_utf8(line).map_err(|_| "malformed")?;
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| "malformed")?;
+            let port: u16 = port.parse().map_err(|_| "malformed")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err("malformed"),
+    }
+}
+
+// Binary header: version and command, address family and transport, then
+// the addresses and ports (source first) and any TLVs, which are skipped
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    body: &[u8],
+) -> Result<Option<SocketAddr>, &'static str> {
+    if version_command >> 4 != 2 {
+        return Err("malformed");
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None), // LOCAL
+        1 => {}               // PROXY
+        _ => return Err("malformed"),
+    }
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // Unspecified and Unix sockets carry no address of use
+        0 | 3 => Ok(None),
+        _ => Err("malformed"),
+    }
+}
+
+// Who sent the request: the connection's peer, which on connections that
+// went through the PROXY header is the address it gave. Forwarded and
+// X-Forwarded-For only say what the client claims; client_ip.rs believes
+// them when they come from a trusted proxy.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_canonical().to_string())
+}
+
+// Serve the public listener with the PROXY header read off each connection
+// first, before TLS when there is any. HttpServer has no step for that, so
+// this puts together what it would: the settings from [server] and
+// h2 and http/1.1 ahead of the configured ALPN protocols. The app config is
+// actix's default, which only the Host-less fallbacks of ConnectionInfo
+// read. Also serves TLS that takes early data, with or without the PROXY
+// header, which HttpServer's acceptor can't either.
+pub fn serve<F, I, S, B>(
+    proxy: Option<ProxyProtocol>,
+    listeners: Vec<TcpListener>,
+    tls: Option<rustls::ServerConfig>,
+    server: &ServerConfig,
+    factory: F,
+) -> Result<Server, Error>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<actix_web::Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as actix_service::Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let proxy = Arc::new(proxy);
+    let header_timeout = Duration::from_millis(server.request_header_timeout_ms);
+    let half_closed = !server.cancel_on_disconnect;
+    let tls = tls.map(|mut config| {
+        let mut alpn = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        alpn.append(&mut config.alpn_protocols);
+        config.alpn_protocols = alpn;
+        match config.max_early_data_size {
+            0 => Acceptor::Tls(TlsAcceptor::from(Arc::new(config))),
+            _ => Acceptor::Early(Arc::new(config)),
+        }
+    });
+
+    let tuning = tuning::get();
+    let mut server = Server::build()
+        .disable_signals()
+        .workers(tuning.workers)
+        .max_concurrent_connections(tuning.max_connections);
+    for listener in listeners {
+        let local = listener.local_addr()?;
+        let (factory, proxy, tls) = (factory.clone(), proxy.clone(), tls.clone());
+        server = server.listen("public", listener, move || {
+            let app = factory()
+                .into_factory()
+                .map_err(|err| err.into().error_response());
+            let http = HttpService::build()
+                .client_request_timeout(header_timeout)
+                .h1_allow_half_closed(half_closed)
+                .local_addr(local)
+                .on_connect_ext(move |io: &Stream, extensions| {
+                    if let Stream::Early(io) = io {
+                        extensions.insert(io.handshaking());
+                    }
+                })
+                .finish(map_config(app, |_| AppConfig::default()));
+            let (proxy, tls) = (proxy.clone(), tls.clone());
+            fn_service(move |mut io: TcpStream| {
+                let (proxy, tls) = (proxy.clone(), tls.clone());
+                async move {
+                    let client = match proxy.as_ref() {
+                        Some(proxy) => proxy.client(&mut io, header_timeout).await?,
+                        None => io.peer_addr().map_err(DispatchError::Io)?,
+                    };
+                    let timed_out = |_| DispatchError::Io(ErrorKind::TimedOut.into());
+                    let (io, alpn_h2) = match tls {
+                        None => return Ok((Stream::Plain(io), Protocol::Http1, Some(client))),
+                        Some(Acceptor::Tls(acceptor)) => {
+                            let io = timeout(header_timeout, acceptor.accept(io))
+                                .await
+                                .map_err(timed_out)?
+                                .map_err(DispatchError::Io)?;
+                            let h2 = io.get_ref().1.alpn_protocol() == Some(b"h2");
+                            (Stream::Tls(Box::new(io)), h2)
+                        }
+                        Some(Acceptor::Early(config)) => {
+                            let io = timeout(header_timeout, early_data::accept(config, io))
+                                .await
+                                .map_err(timed_out)?
+                                .map_err(DispatchError::Io)?;
+                            let h2 = io.alpn_protocol() == Some(b"h2");
+                            (Stream::Early(Box::new(io)), h2)
+                        }
+                    };
+                    let protocol = match alpn_h2 {
+                        true => Protocol::Http2,
+                        false => Protocol::Http1,
+                    };
+                    Ok((io, protocol, Some(client)))
+                }
+            })
+            .and_then(http)
+        })?;
+    }
+    Ok(server.run())
+}
+
+#[derive(Clone)]
+enum Acceptor {
+    Tls(TlsAcceptor),
+    Early(Arc<rustls::ServerConfig>),
+}
+
+// A connection after its PROXY header, with or without TLS
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Early(Box<early_data::TlsStream>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_read(cx, buf),
+            Stream::Tls(io) => Pin::new(io).poll_read(cx, buf),
+            Stream::Early(io) => Pin::new(io).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_write(cx, buf),
+            Stream::Tls(io) => Pin::new(io).poll_write(cx, buf),
+            Stream::Early(io) => Pin::new(io).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            Stream::Tls(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+            Stream::Early(io) => Pin::new(io).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Plain(io) => io.is_write_vectored(),
+            Stream::Tls(io) => io.is_write_vectored(),
+            Stream::Early(io) => io.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_flush(cx),
+            Stream::Tls(io) => Pin::new(io).poll_flush(cx),
+            Stream::Early(io) => Pin::new(io).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Stream::Plain(io) => Pin::new(io).poll_shutdown(cx),
+            Stream::Tls(io) => Pin::new(io).poll_shutdown(cx),
+            Stream::Early(io) => Pin::new(io).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted: &[&str], allow_direct: bool) -> ProxyProtocolConfig {
+        ProxyProtocolConfig {
+            trusted_sources: trusted.iter().map(|s| s.to_string()).collect(),
+            allow_direct,
+        }
+    }
+
+    // A v2 header for TCP over IPv4, with `tlvs` after the addresses
+    fn v2(command: u8, source: [u8; 4], port: u16, tlvs: &[u8]) -> Vec<u8> {
+        let mut body = source.to_vec();
+        body.extend_from_slice(&[10, 0, 0, 1]);
+        body.extend_from_slice(&port.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        body.extend_from_slice(tlvs);
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, 0x11]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(&body);
+        header
+    }
+
+    async fn read(mut input: &[u8]) -> Result<(&'static str, Option<SocketAddr>), &'static str> {
+        read_header(&mut input).await
+    }
+
+    #[tokio::test]
+    async fn reads_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 10.0.0.1 4711 443\r\nGET / HTTP/1.1\r\n";
+        let client = "192.0.2.1:4711".parse().ok();
+        assert_eq!(read(header).await, Ok(("v1", client)));
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n";
+        let client = "[2001:db8::1]:4711".parse().ok();
+        assert_eq!(read(header).await, Ok(("v1", client)));
+
+        // The balancer's own connection
+        let header = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(read(header).await, Ok(("v1", None)));
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_v1() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 10.0.0.1 4711\r\n"[..],
+            b"PROXY TCP4 192.0.2.300 10.0.0.1 4711 443\r\n",
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 70000 443\r\n",
+            b"PROXY UDP4 192.0.2.1 10.0.0.1 4711 443\r\n",
+        ] {
+            assert_eq!(read(header).await, Err("malformed"));
+        }
+        // Never ends within the 107 bytes a v1 header may have
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.extend(std::iter::repeat_n(b'1', 200));
+        long.extend_from_slice(b"\r\n");
+        assert_eq!(read(&long).await, Err("malformed"));
+        // Cut off before its CRLF
+        assert_eq!(read(b"PROXY TCP4 192.0.2.1").await, Err("incomplete"));
+        assert_eq!(read(b"PROX").await, Err("incomplete"));
+    }
+
+    #[tokio::test]
+    async fn reads_v2() {
+        let client = "192.0.2.1:4711".parse().ok();
+        let header = v2(1, [192, 0, 2, 1], 4711, &[]);
+        assert_eq!(read(&header).await, Ok(("v2", client)));
+
+        // TLVs (here ALPN and an authority) are skipped
+        let tlvs = [
+            0x01, 0x00, 0x02, b'h', b'2', 0x02, 0x00, 0x03, b'a', b'.', b'b',
+        ];
+        let mut header = v2(1, [192, 0, 2, 1], 4711, &tlvs);
+        header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        assert_eq!(read(&header).await, Ok(("v2", client)));
+
+        // LOCAL carries no client, whatever the addresses say
+        let header = v2(0, [192, 0, 2, 1], 4711, &[]);
+        assert_eq!(read(&header).await, Ok(("v2", None)));
+
+        let mut body = vec![0u8; 36];
+        body[15] = 1;
+        body[32..34].copy_from_slice(&4711u16.to_be_bytes());
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        header.extend_from_slice(&body);
+        let client = "[::1]:4711".parse().ok();
+        assert_eq!(read(&header).await, Ok(("v2", client)));
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_v2() {
+        // Longer than what follows
+        let mut header = v2(1, [192, 0, 2, 1], 4711, &[]);
+        header[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(read(&header).await, Err("incomplete"));
+        // Too short for the addresses its family has
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 8]);
+        header.extend_from_slice(&[0; 8]);
+        assert_eq!(read(&header).await, Err("malformed"));
+        // Version 1 in a v2 signature, and an unknown command
+        let mut header = v2(1, [192, 0, 2, 1], 4711, &[]);
+        header[12] = 0x11;
+        assert_eq!(read(&header).await, Err("malformed"));
+        let header = v2(2, [192, 0, 2, 1], 4711, &[]);
+        assert_eq!(read(&header).await, Err("malformed"));
+        // Cut off in the fixed part, and half a signature
+        assert_eq!(read(&V2_SIGNATURE[..10]).await, Err("incomplete"));
+        let mut header = V2_SIGNATURE[..8].to_vec();
+        header.extend_from_slice(b"QUIZ\x21\x11\x00\x00");
+        assert_eq!(read(&header).await, Err("missing"));
+        assert_eq!(read(b"GET / HTTP/1.1\r\n").await, Err("missing"));
+    }
+
+    #[test]
+    fn trusts_only_its_sources() {
+        let proxy = ProxyProtocol::new(&config(&["10.0.0.0/8", "192.0.2.7"], false)).unwrap();
+        assert!(proxy.trusts("10.1.2.3".parse().unwrap()));
+        assert!(proxy.trusts("192.0.2.7".parse().unwrap()));
+        // IPv4-mapped addresses, as dual-stack sockets report them
+        assert!(proxy.trusts("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!proxy.trusts("192.0.2.8".parse().unwrap()));
+        assert!(!proxy.trusts("11.0.0.1".parse().unwrap()));
+
+        assert!(ProxyProtocol::new(&config(&[], false)).is_err());
+        assert!(ProxyProtocol::new(&config(&["10.0.0.0/33"], true)).is_err());
+        assert!(ProxyProtocol::new(&config(&[], true)).is_ok());
+    }
+
+    // What accept() makes of a connection from loopback opening with `sent`
+    async fn accept(proxy: &ProxyProtocol, sent: &[u8]) -> Result<SocketAddr, &'static str> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut client, sent)
+            .await
+            .unwrap();
+        drop(client);
+        let (mut io, _) = listener.accept().await.unwrap();
+        proxy.accept(&mut io).await
+    }
+
+    #[tokio::test]
+    async fn untrusted_sources_go_by_their_own_address() {
+        let header = b"PROXY TCP4 192.0.2.1 10.0.0.1 4711 443\r\n";
+        let closed = ProxyProtocol::new(&config(&["10.0.0.0/8"], false)).unwrap();
+        assert_eq!(accept(&closed, header).await, Err("untrusted"));
+
+        // The header isn't believed from them, and isn't consumed either
+        let direct = ProxyProtocol::new(&config(&["10.0.0.0/8"], true)).unwrap();
+        let peer = accept(&direct, header).await.unwrap();
+        assert!(peer.ip().is_loopback());
+
+        let trusted = ProxyProtocol::new(&config(&["127.0.0.0/8"], false)).unwrap();
+        let client = "192.0.2.1:4711".parse().unwrap();
+        assert_eq!(accept(&trusted, header).await, Ok(client));
+        assert_eq!(
+            accept(&trusted, b"GET / HTTP/1.1\r\n").await,
+            Err("missing")
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use crate::auth::Claims;
+use crate::config::QuotaConfig;
+use crate::kv::KvStore;
+use crate::metrics;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Outcome of a quota check, turned into X-RateLimit-* headers on the response
+pub struct QuotaStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset_secs: u64,
+}
+
+impl QuotaStatus {
+    pub fn apply(&self, response: &mut HttpResponse) {
+        let headers = response.headers_mut();
+        for (name, value) in [
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+            ("x-ratelimit-reset", self.reset_secs),
+        ] {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+// Fixed-window per-user request quotas keyed by the JWT `sub` claim
+pub struct Quota {
+    config: QuotaConfig,
+    store: Arc<dyn KvStore>,
+}
+
+impl Quota {
+    pub fn new(config: QuotaConfig, store: Arc<dyn KvStore>) -> Self {
+        Quota { config, store }
+    }
+
+    // Count one request against the caller's quota. Returns the 429 response
+    // when the tier limit has been reached.
+    pub async fn check(&self, claims: &Claims) -> Result<Option<QuotaStatus>, HttpResponse> {
+        let user = match claims.get("sub").and_then(|v| v.as_str()) {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        let tier = claims
+            .get(&self.config.tier_claim)
+            .and_then(|v| v.as_str())
+            .unwrap_or(&self.config.default_tier);
+        let limit = match self.config.tiers.get(tier) {
+            Some(limit) if *limit > 0 => *limit,
+            _ => return Ok(None),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let window = self.config.window_secs.max(1);
+        let window_start = now - now % window;
+        let reset_secs = window_start + window - now;
+
+        let used = match self.increment(user, window_start, window).await {
+            Ok(used) => used,
+            Err(e) => {
+                // Fail open rather than locking everyone out when Redis is down
+                eprintln!("Quota backend error: {}", e);
+                return Ok(None);
+            }
+        };
+
+        let status = QuotaStatus {
+            limit,
+            remaining: limit.saturating_sub(used),
+            reset_secs,
+        };
+
+        if used > limit {
+            metrics::inc("quota_rejections_total", &[("tier", tier)]);
+            let mut response = HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", reset_secs.to_string()))
+                .body("Request quota exceeded");
+            status.apply(&mut response);
+            return Err(response);
+        }
+
+        Ok(Some(status))
+    }
+
+    async fn increment(&self, user: &str, window_start: u64, window: u64) -> Result<u64, String> {
+        let key = format!("quota:{}:{}", user, window_start);
+        let used = self
+            .store
+            .incr(&key, 1, Some(Duration::from_secs(window)))
+            .await?;
+        Ok(used.max(0) as u64)
+    }
+}
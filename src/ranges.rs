@@ -0,0 +1,508 @@
+use crate::cache::CachedResponse;
+use actix_web::http::header;
+use actix_web::web::{Bytes, BytesMut};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Headers that describe one particular response body rather than the resource
+const BODY_HEADERS: [&str; 4] = [
+    "content-length",
+    "content-range",
+    "transfer-encoding",
+    "connection",
+];
+
+// A single range from a Range header: bytes=0-499, bytes=500- or bytes=-500
+#[derive(Clone, Copy)]
+pub enum ByteRange {
+    From(u64, Option<u64>),
+    Suffix(u64),
+}
+
+// One range; several are taken apart by parse_all
+pub fn parse(value: &str) -> Option<ByteRange> {
+    let spec = value.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    match (start.trim(), end.trim()) {
+        ("", suffix) => Some(ByteRange::Suffix(suffix.parse().ok()?)),
+        (start, "") => Some(ByteRange::From(start.parse().ok()?, None)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end).then_some(ByteRange::From(start, Some(end)))
+        }
+    }
+}
+
+// Every range of a Range header, None if any of it is malformed
+pub fn parse_all(value: &str) -> Option<Vec<ByteRange>> {
+    let specs = value.trim().strip_prefix("bytes=")?;
+    let ranges: Option<Vec<ByteRange>> = specs
+        .split(',')
+        .map(|spec| parse(&format!("bytes={}", spec)))
+        .collect();
+    ranges.filter(|ranges| !ranges.is_empty())
+}
+
+// What a Range header comes to once clamped, sorted and merged
+pub enum Normalized {
+    // Specs such as 0-499, for after bytes=
+    Ranges(Vec<String>),
+    // Nothing in it lies within the body
+    Unsatisfiable,
+}
+
+// Overlapping and adjacent ranges become one and duplicates go. With the
+// body's length, every range is clamped to it and written out in full;
+// without it, suffix ranges can't be placed, so only the longest is kept.
+pub fn normalize(ranges: &[ByteRange], total: Option<u64>) -> Normalized {
+    let mut spans: Vec<(u64, Option<u64>)> = Vec::new();
+    let mut suffix: Option<u64> = None;
+    for range in ranges {
+        match (total, range) {
+            (Some(total), range) => {
+                if let Some((start, end)) = range.resolve(total) {
+                    spans.push((start, Some(end)));
+                }
+            }
+            (None, ByteRange::From(start, end)) => spans.push((*start, *end)),
+            (None, ByteRange::Suffix(n)) => suffix = suffix.max(Some(*n)),
+        }
+    }
+    spans.sort_by_key(|(start, _)| *start);
+    let mut merged: Vec<(u64, Option<u64>)> = Vec::new();
+    for (start, end) in spans {
+        match merged.last_mut() {
+            // None is open-ended, and takes in everything after it
+            Some((_, last)) if last.is_none_or(|last| start <= last.saturating_add(1)) => {
+                *last = match (*last, end) {
+                    (Some(last), Some(end)) => Some(last.max(end)),
+                    _ => None,
+                };
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    let mut specs: Vec<String> = merged
+        .iter()
+        .map(|(start, end)| match end {
+            Some(end) => format!("{}-{}", start, end),
+            None => format!("{}-", start),
+        })
+        .collect();
+    specs.extend(suffix.map(|n| format!("-{}", n)));
+    match specs.is_empty() {
+        true => Normalized::Unsatisfiable,
+        false => Normalized::Ranges(specs),
+    }
+}
+
+impl ByteRange {
+    // First and last byte within a body of the given length, None if the
+    // range can't be satisfied
+    pub fn resolve(self, total: u64) -> Option<(u64, u64)> {
+        match self {
+            _ if total == 0 => None,
+            ByteRange::From(start, _) if start >= total => None,
+            ByteRange::From(start, end) => {
+                Some((start, end.map_or(total - 1, |e| e.min(total - 1))))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(n) => Some((total.saturating_sub(n), total - 1)),
+        }
+    }
+}
+
+// bytes 0-1023/4096 -> (0, 1023, 4096)
+pub fn content_range(value: &str) -> Option<(u64, u64, u64)> {
+    let (span, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = span.split_once('-')?;
+    Some((start.parse().ok()?, end.parse().ok()?, total.parse().ok()?))
+}
+
+pub fn chunk_key(url: &str, index: u64) -> String {
+    format!("{}#chunk-{}", url, index)
+}
+
+fn header<'a>(cached: &'a CachedResponse, name: &str) -> Option<&'a [u8]> {
+    cached
+        .headers
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_slice())
+}
+
+// Length of the whole resource a cached chunk belongs to
+pub fn chunk_total(cached: &CachedResponse) -> Option<u64> {
+    let value = std::str::from_utf8(header(cached, "content-range")?).ok()?;
+    content_range(value).map(|(_, _, total)| total)
+}
+
+// The total length shared by all the chunks, None if they disagree on it or
+// on the ETag (the resource changed between fetches)
+pub fn consistent_total(chunks: &BTreeMap<u64, CachedResponse>) -> Option<u64> {
+    let first = chunks.values().next()?;
+    let (total, etag) = (chunk_total(first)?, header(first, "etag"));
+    chunks
+        .values()
+        .all(|c| chunk_total(c) == Some(total) && header(c, "etag") == etag)
+        .then_some(total)
+}
+
+// Runs of consecutive chunk indexes, so each gap is one upstream request
+pub fn runs(missing: &[u64]) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for &index in missing {
+        match runs.last_mut() {
+            Some((_, last)) if *last + 1 == index => *last = index,
+            _ => runs.push((index, index)),
+        }
+    }
+    runs
+}
+
+// Cut an upstream body starting at byte `start` into cacheable chunks. A
+// short piece is only kept if it's the end of the resource.
+pub fn split(
+    body: &Bytes,
+    start: u64,
+    total: u64,
+    chunk: u64,
+    headers: &[(String, Vec<u8>)],
+    ttl: Duration,
+) -> Vec<(u64, CachedResponse)> {
+    let mut chunks = Vec::new();
+    let mut offset = 0u64;
+    while offset < body.len() as u64 {
+        let from = start + offset;
+        let len = chunk.min(body.len() as u64 - offset);
+        if len < chunk && from + len != total {
+            break;
+        }
+        let mut chunk_headers: Vec<(String, Vec<u8>)> = headers
+            .iter()
+            .filter(|(k, _)| !BODY_HEADERS.contains(&k.as_str()))
+            .cloned()
+            .collect();
+        let range = format!("bytes {}-{}/{}", from, from + len - 1, total);
+        chunk_headers.push(("content-range".to_string(), range.into_bytes()));
+        chunks.push((
+            from / chunk,
+            CachedResponse {
+                status: 206,
+                headers: chunk_headers,
+                body: body.slice(offset as usize..(offset + len) as usize),
+                stored_at: Instant::now(),
+                ttl,
+            },
+        ));
+        offset += len;
+    }
+    chunks
+}
+
+// Bytes start..=end as slices of the chunks, without copying them
+fn slices(
+    chunks: &BTreeMap<u64, CachedResponse>,
+    start: u64,
+    end: u64,
+    chunk: u64,
+) -> Option<Vec<Bytes>> {
+    let mut slices = Vec::new();
+    for index in start / chunk..=end / chunk {
+        let cached = chunks.get(&index)?;
+        let base = index * chunk;
+        let from = start.max(base) - base;
+        let to = (end + 1).min(base + cached.body.len() as u64) - base;
+        if from > to || to > cached.body.len() as u64 {
+            return None;
+        }
+        slices.push(cached.body.slice(from as usize..to as usize));
+    }
+    Some(slices)
+}
+
+// The 206 for bytes start..=end, cut from the chunks
+pub fn assemble(
+    chunks: &BTreeMap<u64, CachedResponse>,
+    start: u64,
+    end: u64,
+    total: u64,
+    chunk: u64,
+) -> Option<CachedResponse> {
+    let mut body = BytesMut::with_capacity((end - start + 1) as usize);
+    for slice in slices(chunks, start, end, chunk)? {
+        body.extend_from_slice(&slice);
+    }
+
+    let first = chunks.get(&(start / chunk))?;
+    let mut headers: Vec<(String, Vec<u8>)> = first
+        .headers
+        .iter()
+        .filter(|(k, _)| !BODY_HEADERS.contains(&k.as_str()))
+        .cloned()
+        .collect();
+    let range = format!("bytes {}-{}/{}", start, end, total);
+    headers.push((header::CONTENT_RANGE.to_string(), range.into_bytes()));
+    headers.push((header::ACCEPT_RANGES.to_string(), b"bytes".to_vec()));
+    Some(CachedResponse {
+        status: 206,
+        headers,
+        body: body.freeze(),
+        stored_at: first.stored_at,
+        ttl: first.ttl,
+    })
+}
+
+// The multipart/byteranges 206 for several spans, cut from the chunks: its
+// head, with an empty body, and the pieces of the body to stream in order
+pub fn multipart(
+    chunks: &BTreeMap<u64, CachedResponse>,
+    spans: &[(u64, u64)],
+    total: u64,
+    chunk: u64,
+    boundary: &str,
+) -> Option<(CachedResponse, Vec<Bytes>)> {
+    let first = chunks.values().next()?;
+    let content_type = (first.headers.iter())
+        .find(|(k, _)| k == "content-type")
+        .map(|(_, v)| String::from_utf8_lossy(v).to_string());
+
+    let mut pieces = Vec::new();
+    for &(start, end) in spans {
+        let mut part = format!("\r\n--{}\r\n", boundary);
+        if let Some(content_type) = &content_type {
+            part.push_str(&format!("content-type: {}\r\n", content_type));
+        }
+        part.push_str(&format!(
+            "content-range: bytes {}-{}/{}\r\n\r\n",
+            start, end, total
+        ));
+        pieces.push(Bytes::from(part));
+        pieces.extend(slices(chunks, start, end, chunk)?);
+    }
+    pieces.push(Bytes::from(format!("\r\n--{}--\r\n", boundary)));
+
+    let mut headers: Vec<(String, Vec<u8>)> = first
+        .headers
+        .iter()
+        .filter(|(k, _)| !BODY_HEADERS.contains(&k.as_str()) && k != "content-type")
+        .cloned()
+        .collect();
+    let content_type = format!("multipart/byteranges; boundary={}", boundary);
+    headers.push((header::CONTENT_TYPE.to_string(), content_type.into_bytes()));
+    headers.push((header::ACCEPT_RANGES.to_string(), b"bytes".to_vec()));
+    let head = CachedResponse {
+        status: 206,
+        headers,
+        body: Bytes::new(),
+        stored_at: first.stored_at,
+        ttl: first.ttl,
+    };
+    Some((head, pieces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(value: &str, total: Option<u64>) -> Option<Vec<String>> {
+        match normalize(&parse_all(value)?, total) {
+            Normalized::Ranges(specs) => Some(specs),
+            Normalized::Unsatisfiable => None,
+        }
+    }
+
+    fn specs(specs: &[&str]) -> Option<Vec<String>> {
+        Some(specs.iter().map(|s| s.to_string()).collect())
+    }
+
+    // The chunks of a resource of `total` bytes, 0, 1, 2, ..., all cached
+    fn chunked(total: u64, chunk: u64) -> BTreeMap<u64, CachedResponse> {
+        let body: Bytes = (0..total).map(|b| b as u8).collect::<Vec<u8>>().into();
+        let headers = [
+            ("content-type".to_string(), b"video/mp4".to_vec()),
+            ("content-length".to_string(), total.to_string().into_bytes()),
+        ];
+        split(&body, 0, total, chunk, &headers, Duration::from_secs(60))
+            .into_iter()
+            .collect()
+    }
+
+    fn header<'a>(response: &'a CachedResponse, name: &str) -> Option<&'a str> {
+        let (_, value) = response.headers.iter().find(|(k, _)| k == name)?;
+        std::str::from_utf8(value).ok()
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert!(matches!(
+            parse("bytes=0-499"),
+            Some(ByteRange::From(0, Some(499)))
+        ));
+        assert!(matches!(
+            parse(" bytes= 500 - "),
+            Some(ByteRange::From(500, None))
+        ));
+        assert!(matches!(parse("bytes=-500"), Some(ByteRange::Suffix(500))));
+        assert!(matches!(
+            parse("bytes=7-7"),
+            Some(ByteRange::From(7, Some(7)))
+        ));
+        for value in [
+            "bytes=500-499",
+            "bytes=-",
+            "bytes=",
+            "bytes=a-b",
+            "bytes=0-1,2-3",
+            "items=0-1",
+            "0-1",
+            "bytes=--1",
+            "bytes=18446744073709551616-",
+        ] {
+            assert!(parse(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parses_every_range_or_none() {
+        assert_eq!(parse_all("bytes=0-1, 5-,-3").map(|r| r.len()), Some(3));
+        for value in [
+            "bytes=0-1,",
+            "bytes=0-1,,2-3",
+            "bytes=0-1,3-2",
+            "bytes=",
+            "0-1",
+        ] {
+            assert!(parse_all(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn merges_ranges_within_the_body() {
+        // Overlapping, then adjacent, then an open end that takes in the suffix
+        let merged = normalized("bytes=50-149,0-99,150-199,500-,-100", Some(1000));
+        assert_eq!(merged, specs(&["0-199", "500-999"]));
+        assert_eq!(normalized("bytes=0-9,0-9,0-9", Some(1000)), specs(&["0-9"]));
+        // Clamped to the body, with the parts past it dropped
+        let clamped = normalized("bytes=990-2000,5000-6000,-5000", Some(1000));
+        assert_eq!(clamped, specs(&["0-999"]));
+        // Not adjacent: a byte between them
+        assert_eq!(
+            normalized("bytes=0-9,11-19", Some(100)),
+            specs(&["0-9", "11-19"])
+        );
+    }
+
+    #[test]
+    fn spots_unsatisfiable_ranges() {
+        assert_eq!(normalized("bytes=1000-,2000-3000", Some(1000)), None);
+        assert_eq!(normalized("bytes=-0", Some(1000)), None);
+        assert_eq!(normalized("bytes=0-", Some(0)), None);
+        assert_eq!(normalized("bytes=-10", Some(0)), None);
+    }
+
+    #[test]
+    fn merges_ranges_of_unknown_length() {
+        // Suffixes can't be placed, so only the longest is kept
+        let merged = normalized("bytes=-20,5-,0-10,-50", None);
+        assert_eq!(merged, specs(&["0-", "-50"]));
+        let merged = normalized("bytes=100-199,0-99,300-", None);
+        assert_eq!(merged, specs(&["0-199", "300-"]));
+        // Adjacent at the largest offset there is, without overflowing
+        let merged = normalized("bytes=0-18446744073709551614,18446744073709551615-", None);
+        assert_eq!(merged, specs(&["0-"]));
+        let merged = normalized("bytes=0-18446744073709551615,5-6", None);
+        assert_eq!(merged, specs(&["0-18446744073709551615"]));
+    }
+
+    #[test]
+    fn resolves_ranges() {
+        assert_eq!(ByteRange::From(0, Some(499)).resolve(100), Some((0, 99)));
+        assert_eq!(ByteRange::From(99, None).resolve(100), Some((99, 99)));
+        assert_eq!(ByteRange::From(100, None).resolve(100), None);
+        assert_eq!(ByteRange::Suffix(500).resolve(100), Some((0, 99)));
+        assert_eq!(ByteRange::Suffix(1).resolve(100), Some((99, 99)));
+        assert_eq!(ByteRange::Suffix(0).resolve(100), None);
+    }
+
+    #[test]
+    fn reads_content_range() {
+        assert_eq!(content_range("bytes 0-1023/4096"), Some((0, 1023, 4096)));
+        assert_eq!(content_range("bytes */4096"), None);
+        assert_eq!(content_range("bytes 0-1023/*"), None);
+        assert_eq!(content_range("0-1023/4096"), None);
+    }
+
+    #[test]
+    fn finds_runs_of_missing_chunks() {
+        assert_eq!(runs(&[1, 2, 3, 7, 9, 10]), [(1, 3), (7, 7), (9, 10)]);
+        assert!(runs(&[]).is_empty());
+    }
+
+    #[test]
+    fn splits_bodies_into_chunks() {
+        let chunks = chunked(10, 4);
+        assert_eq!(chunks.keys().copied().collect::<Vec<_>>(), [0, 1, 2]);
+        // The short last chunk is the end of the resource, so it's kept
+        assert_eq!(chunks[&2].body.as_ref(), [8, 9]);
+        assert_eq!(header(&chunks[&2], "content-range"), Some("bytes 8-9/10"));
+        assert_eq!(header(&chunks[&0], "content-length"), None);
+        assert_eq!(consistent_total(&chunks), Some(10));
+
+        // A short piece in the middle isn't
+        let body = Bytes::from_static(&[0; 6]);
+        let middle = split(&body, 4, 20, 4, &[], Duration::from_secs(60));
+        assert_eq!(middle.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn chunks_that_disagree_are_not_used() {
+        let mut chunks = chunked(10, 4);
+        let first = chunks.get_mut(&0).unwrap();
+        first.headers.push(("etag".to_string(), b"\"v2\"".to_vec()));
+        assert_eq!(consistent_total(&chunks), None);
+    }
+
+    #[test]
+    fn assembles_ranges_from_chunks() {
+        let chunks = chunked(10, 4);
+        let response = assemble(&chunks, 2, 9, 10, 4).unwrap();
+        assert_eq!(response.body.as_ref(), [2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(header(&response, "content-range"), Some("bytes 2-9/10"));
+        let one = assemble(&chunks, 5, 5, 10, 4).unwrap();
+        assert_eq!(one.body.as_ref(), [5]);
+
+        // Past the end of the last chunk, or with one missing
+        assert!(assemble(&chunks, 8, 12, 10, 4).is_none());
+        let mut gap = chunks.clone();
+        gap.remove(&1);
+        assert!(assemble(&gap, 2, 9, 10, 4).is_none());
+    }
+
+    #[test]
+    fn assembles_multipart_ranges() {
+        let chunks = chunked(10, 4);
+        let (head, pieces) = multipart(&chunks, &[(0, 1), (7, 9)], 10, 4, "b").unwrap();
+        assert_eq!(
+            header(&head, "content-type"),
+            Some("multipart/byteranges; boundary=b")
+        );
+        let body: Vec<u8> = pieces.iter().flat_map(|piece| piece.to_vec()).collect();
+        let mut expected =
+            b"\r\n--b\r\ncontent-type: video/mp4\r\ncontent-range: bytes 0-1/10\r\n\r\n".to_vec();
+        expected.extend_from_slice(&[0, 1]);
+        expected.extend_from_slice(
+            b"\r\n--b\r\ncontent-type: video/mp4\r\ncontent-range: bytes 7-9/10\r\n\r\n",
+        );
+        expected.extend_from_slice(&[7, 8, 9]);
+        expected.extend_from_slice(b"\r\n--b--\r\n");
+        assert_eq!(body, expected);
+
+        let mut gap = chunks.clone();
+        gap.remove(&2);
+        assert!(multipart(&gap, &[(0, 1), (7, 9)], 10, 4, "b").is_none());
+    }
+}
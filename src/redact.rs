@@ -0,0 +1,41 @@
+use crate::config::{RedactionConfig, RouteConfig};
+use actix_web::http::header::{HeaderName, HeaderValue};
+
+// Masks sensitive header values before they are logged anywhere
+#[derive(Clone)]
+pub struct Redactor {
+    headers: Vec<HeaderName>,
+    partial: bool,
+}
+
+impl Redactor {
+    // Routes' secret headers are always masked, in case a client sends them too
+    pub fn new(config: &RedactionConfig, routes: &[RouteConfig]) -> Self {
+        let secrets = routes.iter().flat_map(|r| &r.compiled.secret_headers);
+        Redactor {
+            headers: config
+                .headers
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .chain(secrets.map(|(name, _)| name.clone()))
+                .collect(),
+            partial: config.partial,
+        }
+    }
+
+    pub fn value(&self, name: &HeaderName, value: &HeaderValue) -> String {
+        let text = String::from_utf8_lossy(value.as_bytes());
+        if !self.headers.contains(name) {
+            return text.into_owned();
+        }
+
+        // Partial masking keeps the last 4 characters of long enough values
+        let chars: Vec<char> = text.chars().collect();
+        if self.partial && chars.len() > 8 {
+            let tail: String = chars[chars.len() - 4..].iter().collect();
+            format!("****{}", tail)
+        } else {
+            "[redacted]".to_string()
+        }
+    }
+}
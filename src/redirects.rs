@@ -0,0 +1,82 @@
+use crate::config::{self, RedirectRule};
+use crate::metrics;
+use crate::routes;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use regex::Regex;
+use std::io::Error;
+
+struct Rule {
+    // The rule's from (or from_regex), for the metrics
+    name: String,
+    pattern: Regex,
+    to: String,
+    status: StatusCode,
+    preserve_query: bool,
+}
+
+// Old paths and vanity URLs sent elsewhere without troubling the gateway
+pub struct Redirects {
+    rules: Vec<Rule>,
+}
+
+impl Redirects {
+    pub fn new(config: &[RedirectRule]) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+        for rule in config {
+            let (name, pattern) = match (&rule.from_regex, rule.from.is_empty()) {
+                (Some(regex), true) => (regex.clone(), regex.clone()),
+                (None, false) => (rule.from.clone(), config::template_regex(&rule.from)),
+                _ => {
+                    return Err(Error::other(format!(
+                        "Redirect to {} needs one of from or from_regex",
+                        rule.to
+                    )))
+                }
+            };
+            let pattern = Regex::new(&pattern)
+                .map_err(|e| Error::other(format!("Redirect {} is invalid: {}", name, e)))?;
+            if !matches!(rule.status, 301 | 302 | 303 | 307 | 308) {
+                return Err(Error::other(format!(
+                    "Redirect {} has status {}; use 301, 302, 303, 307 or 308",
+                    name, rule.status
+                )));
+            }
+            if header::HeaderValue::from_str(&rule.to).is_err() {
+                return Err(Error::other(format!(
+                    "Redirect {} has an invalid target {}",
+                    name, rule.to
+                )));
+            }
+            rules.push(Rule {
+                name,
+                pattern,
+                to: rule.to.clone(),
+                status: StatusCode::from_u16(rule.status).unwrap_or(StatusCode::FOUND),
+                preserve_query: rule.preserve_query,
+            });
+        }
+        Ok(Redirects { rules })
+    }
+
+    pub fn respond(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        let (rule, captures) = self
+            .rules
+            .iter()
+            .find_map(|rule| Some((rule, rule.pattern.captures(req.path())?)))?;
+        let mut location = routes::expand(&rule.to, &captures);
+        if rule.preserve_query && !req.query_string().is_empty() {
+            location.push(if location.contains('?') { '&' } else { '?' });
+            location.push_str(req.query_string());
+        }
+        metrics::inc(
+            "redirects_total",
+            &[("from", &rule.name), ("status", rule.status.as_str())],
+        );
+        Some(
+            HttpResponse::build(rule.status)
+                .insert_header((header::LOCATION, location))
+                .finish(),
+        )
+    }
+}
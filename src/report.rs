@@ -0,0 +1,363 @@
+use crate::access_log;
+use crate::config::{ReportConfig, ReportFormat};
+use crate::metrics;
+use crate::schedule::Cron;
+use actix_web::http::StatusCode;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use time::OffsetDateTime;
+
+// Upper bounds of the latency buckets in milliseconds, with one more past
+// the last
+const BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000];
+
+// Response latencies counted into buckets, for percentiles that are cheap
+// to keep
+#[derive(Default, Clone, Copy)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS_MS.len() + 1],
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        let bucket = BUCKETS_MS.iter().position(|bound| ms <= *bound);
+        self.buckets[bucket.unwrap_or(BUCKETS_MS.len())] += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (mine, theirs) in self.buckets.iter_mut().zip(other.buckets) {
+            *mine += theirs;
+        }
+    }
+
+    // The bound of the bucket the q-th quantile falls in, so an estimate
+    // from above; None when nothing was recorded or it's past the last bound
+    pub fn quantile_ms(&self, q: f64) -> Option<u64> {
+        let total: u64 = self.buckets.iter().sum();
+        let rank = ((total as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKETS_MS.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+#[derive(Default)]
+struct RouteStats {
+    requests: u64,
+    client_errors: u64,
+    server_errors: u64,
+    latency_micros: u64,
+    latency: Histogram,
+}
+
+impl RouteStats {
+    fn mean_cmp(&self, other: &RouteStats) -> std::cmp::Ordering {
+        let mean = |stats: &RouteStats| stats.latency_micros / stats.requests.max(1);
+        mean(self).cmp(&mean(other))
+    }
+
+    fn json(&self, route: &str) -> Value {
+        let rate = |n: u64| n as f64 / self.requests.max(1) as f64;
+        json!({
+            "route": route,
+            "requests": self.requests,
+            "client_errors": self.client_errors,
+            "server_errors": self.server_errors,
+            "error_rate": rate(self.server_errors),
+            "mean_ms": self.latency_micros / self.requests.max(1) / 1000,
+            // null when over the largest bound
+            "p50_ms": self.latency.quantile_ms(0.5),
+            "p95_ms": self.latency.quantile_ms(0.95),
+            "p99_ms": self.latency.quantile_ms(0.99),
+        })
+    }
+}
+
+struct Period {
+    start: SystemTime,
+    routes: HashMap<String, RouteStats>,
+    // cache_requests_total by result when the period started
+    cache: BTreeMap<String, f64>,
+}
+
+// Counts the traffic of each period and reports on it when the [report]
+// schedule comes round
+pub struct Reporter {
+    config: ReportConfig,
+    cron: Cron,
+    client: Client,
+    period: Mutex<Period>,
+}
+
+// cache_requests_total summed over routes, by result
+pub fn cache_counts() -> BTreeMap<String, f64> {
+    let mut counts = BTreeMap::new();
+    for (labels, value) in metrics::series("cache_requests_total") {
+        if let Some((_, result)) = labels.iter().find(|(k, _)| k == "result") {
+            *counts.entry(result.clone()).or_insert(0.0) += value;
+        }
+    }
+    counts
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html(report: &Value) -> String {
+    let cell = |value: &Value| match value {
+        Value::String(s) => escape(s),
+        Value::Null => "-".to_string(),
+        Value::Number(n) if n.is_f64() => format!("{:.4}", n.as_f64().unwrap_or_default()),
+        other => other.to_string(),
+    };
+    let columns = [
+        "route",
+        "requests",
+        "client_errors",
+        "server_errors",
+        "error_rate",
+        "mean_ms",
+        "p50_ms",
+        "p95_ms",
+        "p99_ms",
+    ];
+    let table = |out: &mut String, rows: &Value| {
+        out.push_str("<table>\n<tr>");
+        for column in columns {
+            let _ = write!(out, "<th>{}</th>", column);
+        }
+        out.push_str("</tr>\n");
+        for row in rows.as_array().into_iter().flatten() {
+            out.push_str("<tr>");
+            for column in columns {
+                let _ = write!(out, "<td>{}</td>", cell(&row[column]));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    };
+
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Gateway report</title>\n\
+         <style>body{font-family:sans-serif}table{border-collapse:collapse}\
+         td,th{border:1px solid #ccc;padding:2px 8px;text-align:right}</style>\n\
+         </head><body>\n",
+    );
+    let _ = writeln!(
+        out,
+        "<h1>Gateway report</h1>\n<p>{} to {}</p>",
+        cell(&report["period_start"]),
+        cell(&report["period_end"])
+    );
+    out.push_str("<h2>Traffic</h2>\n<table>\n");
+    for key in [
+        "requests",
+        "requests_per_second",
+        "client_errors",
+        "server_errors",
+        "error_rate",
+    ] {
+        let _ = writeln!(
+            out,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            key,
+            cell(&report[key])
+        );
+    }
+    out.push_str("</table>\n<h2>Slowest routes</h2>\n");
+    table(&mut out, &report["slowest_routes"]);
+    out.push_str("<h2>Cache</h2>\n<table>\n");
+    for (key, value) in report["cache"].as_object().into_iter().flatten() {
+        let _ = writeln!(
+            out,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            escape(key),
+            cell(value)
+        );
+    }
+    out.push_str("</table>\n<h2>Routes</h2>\n");
+    table(&mut out, &report["routes"]);
+    out.push_str("</body></html>\n");
+    out
+}
+
+impl Reporter {
+    pub fn new(config: ReportConfig, client: Client) -> Result<Self, Error> {
+        let cron = Cron::parse(&config.cron)
+            .map_err(|e| Error::other(format!("Invalid [report] cron: {}", e)))?;
+        if config.dir.is_none() && config.webhook_url.is_none() {
+            return Err(Error::other("[report] needs a dir or a webhook_url"));
+        }
+        Ok(Reporter {
+            config,
+            cron,
+            client,
+            period: Mutex::new(Period {
+                start: SystemTime::now(),
+                routes: HashMap::new(),
+                cache: cache_counts(),
+            }),
+        })
+    }
+
+    // Every response, under "-" when no route served it
+    pub fn observe(&self, route: &str, status: StatusCode, latency: Duration) {
+        let mut period = self.period.lock().unwrap();
+        let stats = match period.routes.get_mut(route) {
+            Some(stats) => stats,
+            None => period.routes.entry(route.to_string()).or_default(),
+        };
+        stats.requests += 1;
+        stats.latency_micros += latency.as_micros() as u64;
+        if status.is_client_error() {
+            stats.client_errors += 1;
+        } else if status.is_server_error() {
+            stats.server_errors += 1;
+        }
+        stats.latency.record(latency);
+    }
+
+    // The report on the period so far, which starts a new one
+    fn take(&self) -> Value {
+        let (start, routes, cache_before) = {
+            let mut period = self.period.lock().unwrap();
+            let start = std::mem::replace(&mut period.start, SystemTime::now());
+            let cache = std::mem::replace(&mut period.cache, cache_counts());
+            (start, std::mem::take(&mut period.routes), cache)
+        };
+        let end = SystemTime::now();
+        let secs = end.duration_since(start).unwrap_or_default().as_secs_f64();
+
+        let mut totals = RouteStats::default();
+        for stats in routes.values() {
+            totals.requests += stats.requests;
+            totals.client_errors += stats.client_errors;
+            totals.server_errors += stats.server_errors;
+        }
+        let mut by_requests: Vec<(&String, &RouteStats)> = routes.iter().collect();
+        by_requests.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(b.0)));
+        let mut slowest: Vec<(&String, &RouteStats)> = by_requests
+            .iter()
+            .filter(|(_, stats)| stats.requests >= self.config.min_requests.max(1))
+            .copied()
+            .collect();
+        let p95 = |stats: &RouteStats| stats.latency.quantile_ms(0.95).unwrap_or(u64::MAX);
+        slowest.sort_by(|a, b| p95(b.1).cmp(&p95(a.1)).then(b.1.mean_cmp(a.1)));
+        slowest.truncate(self.config.slowest_routes);
+
+        let now = cache_counts();
+        let mut cache = serde_json::Map::new();
+        for (result, count) in &now {
+            let count = count - cache_before.get(result).copied().unwrap_or(0.0);
+            cache.insert(result.clone(), json!(count as u64));
+        }
+        let lookups: f64 = cache.values().filter_map(Value::as_f64).sum();
+        let hits = cache.get("hit").and_then(Value::as_f64).unwrap_or(0.0);
+        let bytes: f64 = metrics::series("cache_bytes").iter().map(|(_, v)| v).sum();
+        cache.insert(
+            "hit_ratio".to_string(),
+            json!(match lookups > 0.0 {
+                true => Some(hits / lookups),
+                false => None,
+            }),
+        );
+        cache.insert("bytes".to_string(), json!(bytes as u64));
+
+        json!({
+            "period_start": access_log::iso8601(start),
+            "period_end": access_log::iso8601(end),
+            "requests": totals.requests,
+            "requests_per_second": totals.requests as f64 / secs.max(1.0),
+            "client_errors": totals.client_errors,
+            "server_errors": totals.server_errors,
+            "error_rate": totals.server_errors as f64 / totals.requests.max(1) as f64,
+            "slowest_routes": slowest.iter().map(|(route, stats)| stats.json(route)).collect::<Vec<_>>(),
+            "cache": cache,
+            "routes": by_requests.iter().map(|(route, stats)| stats.json(route)).collect::<Vec<_>>(),
+        })
+    }
+
+    // Make the report and deliver it wherever [report] says
+    pub async fn send(&self) {
+        let report = self.take();
+        let (body, extension, content_type) = match self.config.format {
+            ReportFormat::Json => (
+                serde_json::to_string_pretty(&report).unwrap_or_default(),
+                "json",
+                "application/json",
+            ),
+            ReportFormat::Html => (html(&report), "html", "text/html; charset=utf-8"),
+        };
+
+        if let Some(dir) = &self.config.dir {
+            // 2024-05-01T00:00:00.000Z becomes report-2024-05-01T0000.json
+            let end = report["period_end"].as_str().unwrap_or_default();
+            let stamp: String = end.get(..16).unwrap_or(end).replace(':', "");
+            let path = Path::new(dir).join(format!("report-{}.{}", stamp, extension));
+            let result = std::fs::create_dir_all(dir).and_then(|_| std::fs::write(&path, &body));
+            match result {
+                Ok(()) => {
+                    println!("Wrote report to {}", path.display());
+                    metrics::inc("reports_total", &[("sink", "file"), ("result", "ok")]);
+                }
+                Err(e) => {
+                    eprintln!("Failed to write report to {}: {}", path.display(), e);
+                    metrics::inc("reports_total", &[("sink", "file"), ("result", "error")]);
+                }
+            }
+        }
+        if let Some(url) = &self.config.webhook_url {
+            let result = self
+                .client
+                .post(url)
+                .timeout(Duration::from_millis(self.config.timeout_ms))
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(body)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            match result {
+                Ok(_) => metrics::inc("reports_total", &[("sink", "webhook"), ("result", "ok")]),
+                Err(e) => {
+                    eprintln!("Failed to send report to {}: {}", url, e);
+                    metrics::inc("reports_total", &[("sink", "webhook"), ("result", "error")]);
+                }
+            }
+        }
+    }
+
+    // Check the schedule at the start of every minute until the process
+    // exits
+    pub fn spawn(self: &Arc<Self>) {
+        let reporter = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let now = OffsetDateTime::now_utc();
+                let into_minute = Duration::new(now.second().into(), now.nanosecond())
+                    .min(Duration::from_secs(59));
+                tokio::time::sleep(Duration::from_secs(60) - into_minute).await;
+                // A little past the minute, so it's the one that just began
+                let at = OffsetDateTime::now_utc() + Duration::from_millis(500);
+                if reporter.cron.matches(at) {
+                    reporter.send().await;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,111 @@
+use crate::config::RetryBudgetConfig;
+use crate::metrics;
+use actix_web::http::header::HttpDate;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Requests and retries seen during one second
+struct Bucket {
+    second: u64,
+    requests: u64,
+    retries: u64,
+}
+
+// Caps retries to a fraction of recent traffic per upstream, so an outage
+// doesn't get amplified by every client request being sent several times
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    upstreams: Mutex<HashMap<String, VecDeque<Bucket>>>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        RetryBudget {
+            config,
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn with_current<T>(&self, upstream: &str, f: impl FnOnce(&mut VecDeque<Bucket>) -> T) -> T {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let buckets = upstreams.entry(upstream.to_string()).or_default();
+        let now = now_secs();
+
+        while let Some(oldest) = buckets.front() {
+            if oldest.second + self.config.window_secs.max(1) <= now {
+                buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+        if buckets.back().map(|b| b.second) != Some(now) {
+            buckets.push_back(Bucket {
+                second: now,
+                requests: 0,
+                retries: 0,
+            });
+        }
+
+        f(buckets)
+    }
+
+    pub fn record_request(&self, upstream: &str) {
+        self.with_current(upstream, |buckets| {
+            if let Some(current) = buckets.back_mut() {
+                current.requests += 1;
+            }
+        });
+    }
+
+    // Spend one retry from the budget; false means the retry must be skipped
+    pub fn try_retry(&self, upstream: &str) -> bool {
+        let allowed = self.with_current(upstream, |buckets| {
+            let requests: u64 = buckets.iter().map(|b| b.requests).sum();
+            let retries: u64 = buckets.iter().map(|b| b.retries).sum();
+            let budget = (requests as f64 * self.config.ratio) as u64 + self.config.min_retries;
+
+            if retries < budget {
+                if let Some(current) = buckets.back_mut() {
+                    current.retries += 1;
+                }
+                true
+            } else {
+                false
+            }
+        });
+
+        if allowed {
+            metrics::inc("upstream_retries_total", &[("upstream", upstream)]);
+        } else {
+            metrics::inc(
+                "upstream_retries_suppressed_total",
+                &[("upstream", upstream)],
+            );
+        }
+        allowed
+    }
+}
+
+// How long a response's Retry-After asks to wait, given in seconds or as a
+// date
+pub fn retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date: HttpDate = value.parse().ok()?;
+    let wait = SystemTime::from(date).duration_since(SystemTime::now());
+    Some(wait.unwrap_or_default())
+}
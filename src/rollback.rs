@@ -0,0 +1,137 @@
+use crate::cluster;
+use crate::config::{RollbackConfig, RouteConfig};
+use crate::events::{self, Event};
+use crate::metrics;
+use crate::proxy::AppState;
+use crate::routes::Change;
+use actix_web::http::StatusCode;
+use actix_web::{rt, web};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Responses a route served since its watch started
+#[derive(Default)]
+pub struct Counts {
+    total: AtomicU64,
+    errors: AtomicU64,
+    latency_micros: AtomicU64,
+}
+
+impl Counts {
+    // Why the watched change should be undone, if it should
+    fn breached(&self, config: &RollbackConfig) -> Option<String> {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 || total < config.min_requests {
+            return None;
+        }
+        let rate = self.errors.load(Ordering::Relaxed) as f64 / total as f64;
+        let mean_ms = self.latency_micros.load(Ordering::Relaxed) / total / 1000;
+        if rate > config.max_error_rate {
+            Some(format!(
+                "{:.1}% errors over {} requests",
+                rate * 100.0,
+                total
+            ))
+        } else if config.max_latency_ms > 0 && mean_ms > config.max_latency_ms {
+            Some(format!(
+                "mean latency {}ms over {} requests",
+                mean_ms, total
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+// Routes being watched after a change, so it can be undone if it goes badly
+#[derive(Default)]
+pub struct Watches {
+    routes: RwLock<HashMap<String, Arc<Counts>>>,
+}
+
+impl Watches {
+    // Start (or restart) watching a route. A newer watch replaces an older
+    // one, whose result then no longer counts.
+    fn start(&self, route: &str) -> Arc<Counts> {
+        let counts = Arc::new(Counts::default());
+        self.routes
+            .write()
+            .unwrap()
+            .insert(route.to_string(), counts.clone());
+        counts
+    }
+
+    // Stop watching; false when a newer watch took over in the meantime
+    fn finish(&self, route: &str, counts: &Arc<Counts>) -> bool {
+        let mut routes = self.routes.write().unwrap();
+        match routes.get(route) {
+            Some(current) if Arc::ptr_eq(current, counts) => {
+                routes.remove(route);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn observe(&self, route: &str, status: StatusCode, latency: Duration) {
+        let routes = self.routes.read().unwrap();
+        if let Some(counts) = routes.get(route) {
+            counts.total.fetch_add(1, Ordering::Relaxed);
+            counts
+                .latency_micros
+                .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+            if status.is_server_error() {
+                counts.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn route_json(table: &[RouteConfig], name: &str) -> Option<serde_json::Value> {
+    serde_json::to_value(table.iter().find(|r| r.name == name)?).ok()
+}
+
+// Watch the route for the window after it was changed, and put it back the
+// way it was if it fails or slows down. Nothing is undone if the route has
+// been changed again since.
+pub fn watch(state: web::Data<AppState>, route: String, config: RollbackConfig, change: Change) {
+    let counts = state.watches.start(&route);
+    rt::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(config.window_secs)).await;
+        if !state.watches.finish(&route, &counts) {
+            return;
+        }
+        let reason = match counts.breached(&config) {
+            Some(reason) => reason,
+            None => {
+                println!("Change to route {} passed validation", route);
+                return;
+            }
+        };
+        match state.routes.restore(&state.config, &route, &change) {
+            Ok(true) => {
+                println!("Rolled back the change to route {}: {}", route, reason);
+                metrics::inc("route_rollbacks_total", &[("route", &route)]);
+                state.audit.record(
+                    "rollback",
+                    "route_rollback",
+                    &route,
+                    route_json(&change.1, &route),
+                    route_json(&change.0, &route),
+                );
+                events::publish(Event::RouteChange {
+                    action: "updated",
+                    route,
+                });
+                cluster::routes_changed(&state);
+            }
+            Ok(false) => println!(
+                "Not rolling back route {} ({}): it has changed again since",
+                route, reason
+            ),
+            Err(e) => eprintln!("Rollback of route {} failed: {}", route, e),
+        }
+    });
+}
@@ -0,0 +1,315 @@
+use crate::config::{Config, RouteConfig};
+use crate::diagnostics;
+use crate::history::History;
+use crate::language;
+use crate::metrics;
+use crate::store::Store;
+use actix_web::http::{header, Method};
+use actix_web::HttpRequest;
+use regex::Captures;
+use serde_json::Value;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+// The live routing table. Requests take a cheap snapshot; admin edits swap in a
+// new table atomically and persist it to the config store or the state file.
+pub struct RouteTable {
+    routes: RwLock<Arc<Vec<RouteConfig>>>,
+    state_path: String,
+    store: Option<Arc<Store>>,
+    history: Option<History>,
+}
+
+impl RouteTable {
+    pub fn load(config: &Config, store: Option<Arc<Store>>) -> Result<Self, Error> {
+        let state_path = config.admin.state_path.clone();
+        let stored = match &store {
+            Some(store) => store.routes()?,
+            None => None,
+        };
+
+        // Routes saved by the admin API win over the config file
+        let routes = if let Some(mut routes) = stored {
+            diagnostics::unredact_routes(&mut routes, &[&config.routes])?;
+            config.prepare_routes(&mut routes)?;
+            println!("Loaded {} routes from the config store", routes.len());
+            routes
+        } else if store.is_none() && !state_path.is_empty() && Path::new(&state_path).exists() {
+            let raw = fs::read_to_string(&state_path)?;
+            let mut routes: Vec<RouteConfig> = serde_json::from_str(&raw)
+                .map_err(|e| Error::other(format!("Invalid route state {}: {}", state_path, e)))?;
+            diagnostics::unredact_routes(&mut routes, &[&config.routes])?;
+            config.prepare_routes(&mut routes)?;
+            println!("Loaded {} routes from {}", routes.len(), state_path);
+            routes
+        } else {
+            config.routes.clone()
+        };
+
+        let history = History::new(&config.admin)?;
+        if let Some(history) = &history {
+            history.record(&routes)?;
+        }
+        metrics::set_route_tags(&routes);
+
+        Ok(RouteTable {
+            routes: RwLock::new(Arc::new(routes)),
+            state_path,
+            store,
+            history,
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<Vec<RouteConfig>> {
+        self.routes.read().unwrap().clone()
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // Saved with secret header values redacted; loading puts them back from
+    // the config file's routes, and edits from the live ones
+    fn persist(&self, routes: &[RouteConfig]) -> Result<(), Error> {
+        if let Some(store) = &self.store {
+            store.save_routes(&diagnostics::redact_routes(routes))?;
+        } else if !self.state_path.is_empty() {
+            let raw = serde_json::to_string_pretty(&diagnostics::redact_routes(routes))
+                .map_err(Error::other)?;
+            let tmp = format!("{}.tmp", self.state_path);
+            fs::write(&tmp, raw)?;
+            fs::rename(&tmp, &self.state_path)?;
+        }
+        // The edit itself has been saved by now, so this doesn't fail it
+        if let Err(e) = self.history.as_ref().map_or(Ok(()), |h| h.record(routes)) {
+            eprintln!("Can't save the config history: {}", e);
+        }
+        Ok(())
+    }
+
+    // Saved copies can only put back a literal secret header value the
+    // config file has, so any other must be env:NAME to survive a restart
+    fn check_kept(&self, config: &Config, routes: &[RouteConfig]) -> Result<(), Error> {
+        if self.store.is_none() && self.state_path.is_empty() {
+            return Ok(());
+        }
+        for route in routes {
+            let configured = config.routes.iter().find(|r| r.name == route.name);
+            for (name, value) in &route.secret_headers {
+                let kept = value.is_empty()
+                    || value.starts_with("env:")
+                    || configured.and_then(|r| r.secret_headers.get(name)) == Some(value);
+                if !kept {
+                    return Err(Error::other(format!(
+                        "Route {} secret header {} must be set as env:NAME, as saved routes keep secret headers redacted",
+                        route.name, name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Apply an edit to a copy of the table, validate it and make it live.
+    // Returns the table it replaced and the new one.
+    pub fn update<F>(&self, config: &Config, edit: F) -> Result<Change, Error>
+    where
+        F: FnOnce(&mut Vec<RouteConfig>) -> Result<(), Error>,
+    {
+        let mut routes = self.routes.write().unwrap();
+        let mut next = routes.as_ref().clone();
+        edit(&mut next)?;
+        // A route read back from the admin API or the history has them redacted
+        diagnostics::unredact_routes(&mut next, &[&routes, &config.routes])?;
+        self.check_kept(config, &next)?;
+        config.prepare_routes(&mut next)?;
+        self.persist(&next)?;
+        metrics::set_route_tags(&next);
+
+        let before = std::mem::replace(&mut *routes, Arc::new(next));
+        Ok((before, routes.clone()))
+    }
+
+    // Put a route back the way it was before a change, or remove it if the
+    // change created it. Nothing happens if the route has been changed
+    // again since; returns whether it was put back.
+    pub fn restore(&self, config: &Config, name: &str, change: &Change) -> Result<bool, Error> {
+        let find = |table: &[RouteConfig]| {
+            let route = table.iter().find(|r| r.name == name)?;
+            serde_json::to_value(route).ok()
+        };
+        let mut routes = self.routes.write().unwrap();
+        if find(&routes).is_none() || find(&routes) != find(&change.1) {
+            return Ok(false);
+        }
+        let mut next = routes.as_ref().clone();
+        let index = next.iter().position(|r| r.name == name);
+        match (index, change.0.iter().find(|r| r.name == name)) {
+            (Some(index), Some(before)) => next[index] = before.clone(),
+            (Some(index), None) => {
+                next.remove(index);
+            }
+            (None, _) => return Ok(false),
+        }
+        config.prepare_routes(&mut next)?;
+        self.persist(&next)?;
+        metrics::set_route_tags(&next);
+        *routes = Arc::new(next);
+        Ok(true)
+    }
+}
+
+// A table before and after an edit
+pub type Change = (Arc<Vec<RouteConfig>>, Arc<Vec<RouteConfig>>);
+
+// The first route whose predicates all match, with the path to request upstream
+pub fn route_for<'a>(
+    routes: &'a [RouteConfig],
+    req: &HttpRequest,
+) -> Option<(&'a RouteConfig, String)> {
+    route_for_body(routes, req, None)
+}
+
+// Like route_for, with the parsed JSON body for routes with body predicates.
+// Without a body those routes never match.
+pub fn route_for_body<'a>(
+    routes: &'a [RouteConfig],
+    req: &HttpRequest,
+    body: Option<&Value>,
+) -> Option<(&'a RouteConfig, String)> {
+    routes
+        .iter()
+        .find_map(|route| upstream_path(route, req, body).map(|path| (route, path)))
+}
+
+// Whether a route with body predicates could win, so the body has to be read
+// before routing. Routes that match outright further up make it unnecessary.
+pub fn inspects_body(routes: &[RouteConfig], req: &HttpRequest) -> bool {
+    for route in routes {
+        if upstream_path(route, req, None).is_some() {
+            return false;
+        }
+        if !route.compiled.body.is_empty() && head_matches(route, req) {
+            return true;
+        }
+    }
+    false
+}
+
+// The field at a dotted path, as the string a predicate is matched against
+fn body_field(body: &Value, path: &[String]) -> Option<String> {
+    let mut value = body;
+    for key in path {
+        value = match value {
+            Value::Object(fields) => fields.get(key)?,
+            Value::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// A preflight is routed as the request it asks about
+fn requested_method(req: &HttpRequest) -> &str {
+    match req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD) {
+        Some(method) if req.method() == Method::OPTIONS => method.to_str().unwrap_or(""),
+        _ => req.method().as_str(),
+    }
+}
+
+// Whether the route's allowed_methods let the request through
+pub fn method_allowed(route: &RouteConfig, req: &HttpRequest) -> bool {
+    let method = requested_method(req);
+    route.allowed_methods.is_empty() || route.allowed_methods.iter().any(|m| m == method)
+}
+
+fn head_matches(route: &RouteConfig, req: &HttpRequest) -> bool {
+    if !route.matches(req.path()) {
+        return false;
+    }
+    let method = requested_method(req);
+    if !route.methods.is_empty() && !route.methods.iter().any(|m| m == method) {
+        return false;
+    }
+    if !route.accept_languages.is_empty()
+        && language::pick(req, &route.accept_languages, route).is_none()
+    {
+        return false;
+    }
+    route.compiled.headers.iter().all(|(name, pattern)| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|value| pattern.is_match(value))
+    })
+}
+
+fn upstream_path(route: &RouteConfig, req: &HttpRequest, body: Option<&Value>) -> Option<String> {
+    let path = req.path();
+    if !head_matches(route, req) {
+        return None;
+    }
+    for (field, pattern) in &route.compiled.body {
+        if !pattern.is_match(&body_field(body?, field)?) {
+            return None;
+        }
+    }
+
+    let captures = match &route.compiled.path {
+        Some(pattern) => Some(pattern.captures(path)?),
+        None => None,
+    };
+    match (&route.rewrite, captures) {
+        (Some(rewrite), Some(captures)) => Some(expand(rewrite, &captures)),
+        // Without a path pattern the rewrite replaces the prefix
+        (Some(rewrite), None) => Some(format!(
+            "{}{}",
+            rewrite.trim_end_matches('/'),
+            &path[route.prefix.trim_end_matches('/').len()..]
+        )),
+        (None, _) => Some(path.to_string()),
+    }
+}
+
+// Fill {name} placeholders in a response template from the request's path
+// captures. Only the captured names are replaced, so other braces, such as
+// a JSON body's, are left alone.
+pub fn render(route: &RouteConfig, req: &HttpRequest, template: &str) -> String {
+    let (pattern, captures) = match &route.compiled.path {
+        Some(pattern) => match pattern.captures(req.path()) {
+            Some(captures) => (pattern, captures),
+            None => return template.to_string(),
+        },
+        None => return template.to_string(),
+    };
+    let mut out = template.to_string();
+    for name in pattern.capture_names().flatten() {
+        let value = captures.name(name).map_or("", |m| m.as_str());
+        out = out.replace(&format!("{{{}}}", name), value);
+    }
+    out
+}
+
+// Fill {name} placeholders in a rewrite template from the path captures
+pub fn expand(template: &str, captures: &Captures) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => break,
+        };
+        out.push_str(&rest[..open]);
+        let name = &rest[open + 1..close];
+        out.push_str(captures.name(name).map_or("", |m| m.as_str()));
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+    out
+}
@@ -0,0 +1,59 @@
+use crate::admin::same_token;
+use crate::config::SamplingConfig;
+use actix_web::HttpRequest;
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Decision {
+    Skip,
+    Sample,
+    // The caller sent the debug header with the right secret
+    Debug,
+}
+
+// Decides which requests get logged. Every Nth and/or a percentage of requests
+// are picked up front; errors and slow requests are kept regardless.
+pub struct Sampler {
+    config: SamplingConfig,
+    counter: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig) -> Self {
+        Sampler {
+            config,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn decide(&self, req: &HttpRequest) -> Decision {
+        let debug = !self.config.debug_secret.is_empty()
+            && req
+                .headers()
+                .get(self.config.debug_header.as_str())
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|secret| same_token(secret, &self.config.debug_secret));
+        if debug {
+            return Decision::Debug;
+        }
+
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let nth = n.is_multiple_of(self.config.every_n.max(1));
+        let picked = self.config.percent >= 100.0
+            || rand::thread_rng().gen::<f64>() * 100.0 < self.config.percent;
+        if nth && picked {
+            Decision::Sample
+        } else {
+            Decision::Skip
+        }
+    }
+
+    // Whether the finished request should be logged after all
+    pub fn keep(&self, decision: Decision, status: u16, elapsed: Duration) -> bool {
+        decision != Decision::Skip
+            || (self.config.error_status > 0 && status >= self.config.error_status)
+            || (self.config.slow_ms > 0 && elapsed >= Duration::from_millis(self.config.slow_ms))
+    }
+}
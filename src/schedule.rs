@@ -0,0 +1,173 @@
+use crate::config::{RouteConfig, ScheduleRule};
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+// Recurring windows can't be longer than a day; use start and end instead
+const MAX_DURATION_MINS: u32 = 24 * 60;
+
+// A cron expression, one bit per allowed value of each field
+#[derive(Debug, Clone)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // Unrestricted day fields; when both are restricted either may match
+    any_day: bool,
+    any_weekday: bool,
+}
+
+// "*", "5", "1-5", "*/15", "0-30/10" and comma-separated lists of them
+fn field(spec: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| part.to_string())?),
+            None => (part, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (
+                first.parse().map_err(|_| part.to_string())?,
+                last.parse().map_err(|_| part.to_string())?,
+            ),
+            None => {
+                let value = range.parse().map_err(|_| part.to_string())?;
+                (value, value)
+            }
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(format!("{} is out of range", part));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(spec: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("cron {} needs five fields", spec));
+        };
+        let mut weekday_bits = field(weekdays, 0, 7)?;
+        // 7 is Sunday too
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Cron {
+            minutes: field(minutes, 0, 59)?,
+            hours: field(hours, 0, 23)?,
+            days: field(days, 1, 31)?,
+            months: field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    pub fn matches(&self, at: OffsetDateTime) -> bool {
+        let set = |bits: u64, value: u8| bits & (1 << value) != 0;
+        let day = set(self.days, at.day());
+        let weekday = set(self.weekdays, at.weekday().number_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+        set(self.minutes, at.minute())
+            && set(self.hours, at.hour())
+            && set(self.months, at.month() as u8)
+            && day
+    }
+}
+
+// A schedule rule's window, parsed when the route is compiled
+#[derive(Debug, Clone)]
+pub struct Window {
+    start: Option<OffsetDateTime>,
+    end: Option<OffsetDateTime>,
+    cron: Option<Cron>,
+    duration: Duration,
+}
+
+fn timestamp(value: &Option<String>) -> Result<Option<OffsetDateTime>, String> {
+    value
+        .as_deref()
+        .map(|v| OffsetDateTime::parse(v, &Rfc3339).map_err(|e| format!("{}: {}", v, e)))
+        .transpose()
+}
+
+impl Window {
+    pub fn parse(rule: &ScheduleRule) -> Result<Window, String> {
+        if rule.upstream.is_some() == rule.maintenance.is_some() {
+            return Err("each rule needs either an upstream or a maintenance message".to_string());
+        }
+        if rule.start.is_none() && rule.end.is_none() && rule.cron.is_none() {
+            return Err("each rule needs a start, an end or a cron expression".to_string());
+        }
+        if rule.cron.is_some() && !(1..=MAX_DURATION_MINS).contains(&rule.duration_mins) {
+            return Err(format!(
+                "duration_mins must be between 1 and {}",
+                MAX_DURATION_MINS
+            ));
+        }
+        let window = Window {
+            start: timestamp(&rule.start)?,
+            end: timestamp(&rule.end)?,
+            cron: rule.cron.as_deref().map(Cron::parse).transpose()?,
+            duration: Duration::minutes(rule.duration_mins.into()),
+        };
+        if let (Some(start), Some(end)) = (window.start, window.end) {
+            if start >= end {
+                return Err("start must be before end".to_string());
+            }
+        }
+        Ok(window)
+    }
+
+    // When the window closes, if it's open at `now`. None inside an open
+    // window means it never closes.
+    fn closes(&self, now: OffsetDateTime) -> Option<Option<OffsetDateTime>> {
+        if self.start.is_some_and(|start| now < start) || self.end.is_some_and(|end| now >= end) {
+            return None;
+        }
+        let recurring = match &self.cron {
+            // The latest matching minute that's still within the duration
+            Some(cron) => {
+                let minute = now.replace_second(0).ok()?.replace_nanosecond(0).ok()?;
+                let opened = (0..self.duration.whole_minutes())
+                    .map(|back| minute - Duration::minutes(back))
+                    .find(|at| cron.matches(*at))?;
+                Some(opened + self.duration)
+            }
+            None => None,
+        };
+        Some(match (recurring, self.end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        })
+    }
+}
+
+// The route's first open rule, and how many seconds until it closes if it
+// ever does
+pub fn active(route: &RouteConfig) -> Option<(&ScheduleRule, Option<u64>)> {
+    if route.schedule.is_empty() {
+        return None;
+    }
+    let now = OffsetDateTime::now_utc();
+    route
+        .schedule
+        .iter()
+        .zip(&route.compiled.schedule)
+        .find_map(|(rule, window)| {
+            let closes = window.closes(now)?;
+            let remaining = closes.map(|at| (at - now).whole_seconds().max(1) as u64);
+            Some((rule, remaining))
+        })
+}
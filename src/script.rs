@@ -0,0 +1,118 @@
+use crate::config::ScriptConfig;
+use crate::middleware::Message;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use std::fs;
+use std::io::Error;
+
+// Rhai hooks attached to routes. A script defines `on_request()` and/or
+// `on_response()`; `this` is a map with method, path, headers, body and
+// upstream (requests) or status (responses) that the hook can change.
+// Returning a status code from on_request answers the client directly with
+// `this.body`.
+//
+//   fn on_request() {
+//       if this.headers["x-beta"] == "1" { this.upstream = "beta-pool"; }
+//       if this.path.starts_with("/internal") { this.body = "Forbidden"; return 403; }
+//   }
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+fn headers_map(headers: &HeaderMap) -> Map {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            Some((name.as_str().into(), Dynamic::from(value.to_string())))
+        })
+        .collect()
+}
+
+// Apply what the script changed in its header map, leaving untouched headers
+// (and their repeated values) as they were
+fn apply_headers(headers: &mut HeaderMap, before: &Map, after: &Map) {
+    for name in before.keys().filter(|name| !after.contains_key(*name)) {
+        if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in after {
+        let value = value.to_string();
+        if before.get(name).map(|v| v.to_string()).as_ref() == Some(&value) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+impl Script {
+    pub fn load(name: &str, config: &ScriptConfig) -> Result<Self, Error> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.set_max_call_levels(32);
+        engine.set_max_string_size(config.max_string_bytes);
+        engine.on_print(|line| println!("Script: {}", line));
+
+        let source = fs::read_to_string(&config.path)?;
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| Error::other(format!("Script {} ({}): {}", name, config.path, e)))?;
+
+        Ok(Script { engine, ast })
+    }
+
+    // Run one hook and return the (possibly modified) message and the status
+    // the hook returned, 0 if none. Scripts without the hook return 0.
+    pub fn call(&self, hook: &str, mut message: Message) -> Result<(Message, i32), String> {
+        if !self.ast.iter_functions().any(|f| f.name == hook) {
+            return Ok((message, 0));
+        }
+
+        let headers = headers_map(&message.headers);
+        let body = String::from_utf8_lossy(&message.body).into_owned();
+        let mut this = Map::new();
+        this.insert("method".into(), message.method.clone().into());
+        this.insert("path".into(), message.path.clone().into());
+        this.insert("headers".into(), headers.clone().into());
+        this.insert("body".into(), body.clone().into());
+        this.insert("status".into(), (message.status as i64).into());
+        this.insert("upstream".into(), message.upstream.clone().into());
+        let mut this = Dynamic::from_map(this);
+
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut this);
+        let result: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut Scope::new(), &self.ast, hook, ())
+            .map_err(|e| e.to_string())?;
+
+        let this = this.cast::<Map>();
+        let text = |key: &str| this.get(key).map(|v| v.to_string());
+        if let Some(path) = text("path").filter(|p| p.starts_with('/')) {
+            message.path = path;
+        }
+        if let Some(upstream) = text("upstream") {
+            message.upstream = upstream;
+        }
+        if let Some(new_body) = text("body").filter(|b| *b != body) {
+            message.body = new_body.into_bytes();
+        }
+        if let Some(status) = this.get("status").and_then(|s| s.as_int().ok()) {
+            message.status = u16::try_from(status).unwrap_or(message.status);
+        }
+        if let Some(after) = this.get("headers").and_then(|h| h.read_lock::<Map>()) {
+            apply_headers(&mut message.headers, &headers, &after);
+        }
+
+        let status = result.as_int().unwrap_or(0);
+        Ok((message, i32::try_from(status).unwrap_or(0)))
+    }
+}
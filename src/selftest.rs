@@ -0,0 +1,225 @@
+use crate::access_log::Served;
+use crate::config::{ProbeConfig, RouteConfig};
+use crate::keys;
+use crate::proxy::{self, AppState};
+use actix_web::dev::Service;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{web, HttpMessage, HttpRequest, HttpServer};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::Error;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+// Put on requests sent by the self-test
+#[derive(Clone, Copy)]
+struct Probe {
+    authenticate: bool,
+}
+
+// Whether authentication (authenticators, jwt and csrf) lets the request
+// through unchecked: self-test probes do unless told to authenticate
+pub fn skips_auth(req: &HttpRequest) -> bool {
+    req.extensions()
+        .get::<Probe>()
+        .is_some_and(|probe| !probe.authenticate)
+}
+
+// A GET to each route that is only a prefix, so every upstream a route
+// sends to is reached; routes with other predicates need probes of their
+// own. So does a catch-all route, as the gateway answers / itself.
+fn default_probes(routes: &[RouteConfig]) -> Vec<ProbeConfig> {
+    let takes_get = |methods: &[String]| {
+        methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case("GET"))
+    };
+    routes
+        .iter()
+        .filter(|route| {
+            !route.grpc
+                && !route.prefix.trim_matches('/').is_empty()
+                && route.path.is_none()
+                && route.path_regex.is_none()
+                && route.headers.is_empty()
+                && route.body.is_empty()
+                && route.accept_languages.is_empty()
+                && takes_get(&route.methods)
+                && takes_get(&route.allowed_methods)
+        })
+        .map(|route| ProbeConfig {
+            path: route.prefix.clone(),
+            route: Some(route.name.clone()),
+            ..Default::default()
+        })
+        .collect()
+}
+
+// Carries the per-run token that marks the self-test's own requests, so
+// nothing else reaching its listener can skip authentication
+const PROBE_HEADER: &str = "x-gateway-self-test";
+// Where a probe went, from its Served record
+const ROUTE_HEADER: &str = "x-gateway-self-test-route";
+const UPSTREAM_HEADER: &str = "x-gateway-self-test-upstream";
+
+// The probe a request is, if it carries this run's token, without the header
+fn take_probe(token: &str, headers: &mut HeaderMap) -> Option<Probe> {
+    let value = headers.remove(PROBE_HEADER).next()?;
+    let (sent, authenticate) = value.to_str().ok()?.split_once(':')?;
+    (sent == token).then_some(Probe {
+        authenticate: authenticate == "1",
+    })
+}
+
+// Send the [self_test] probes through the same pipeline as client requests,
+// over a loopback listener of its own, and report on each. Any failure is
+// an error, so a deploy can stop before the gateway takes traffic.
+pub async fn run(state: web::Data<AppState>) -> Result<(), Error> {
+    let probes = match state.config.self_test.probes.is_empty() {
+        true => default_probes(&state.routes.snapshot()),
+        false => state.config.self_test.probes.clone(),
+    };
+    let methods = (probes.iter())
+        .map(|probe| {
+            Method::from_bytes(probe.method.to_uppercase().as_bytes())
+                .map_err(|_| Error::other(format!("Invalid self-test method {}", probe.method)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::other("Can't make a self-test token"))?;
+    let token = keys::hex(&bytes);
+
+    let app_state = state.clone();
+    let app_token = token.clone();
+    let app = move || {
+        let token = app_token.clone();
+        proxy::app(app_state.clone()).wrap_fn(move |mut req, srv| {
+            let probe = take_probe(&token, req.headers_mut());
+            if let Some(probe) = probe {
+                req.extensions_mut().insert(probe);
+            }
+            let response = srv.call(req);
+            async move {
+                let mut response = response.await?;
+                let served = (probe.is_some())
+                    .then(|| response.response().extensions().get::<Served>().cloned())
+                    .flatten();
+                if let Some(served) = served {
+                    let headers = response.headers_mut();
+                    for (name, value) in [
+                        (ROUTE_HEADER, served.route),
+                        (UPSTREAM_HEADER, served.upstream),
+                    ] {
+                        if let Ok(value) = HeaderValue::from_str(&value) {
+                            headers.insert(HeaderName::from_static(name), value);
+                        }
+                    }
+                }
+                Ok(response)
+            }
+        })
+    };
+    let listener = TcpListener::bind(("127.0.0.1", 0))?;
+    let address = listener.local_addr()?;
+    let server = HttpServer::new(app)
+        .workers(1)
+        .disable_signals()
+        .listen(listener)?
+        .run();
+    let handle = server.handle();
+    let serving = tokio::spawn(server);
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(Error::other)?;
+
+    let timeout = Duration::from_millis(state.config.self_test.timeout_ms);
+    let mut failed = 0;
+    for (probe, method) in probes.iter().zip(methods) {
+        let mut req = client
+            .request(method.clone(), format!("http://{}{}", address, probe.path))
+            .header(header::USER_AGENT, "gateway-self-test")
+            .header(
+                PROBE_HEADER,
+                format!("{}:{}", token, probe.authenticate as u8),
+            );
+        for (name, value) in &probe.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let req = req.body(probe.body.clone());
+
+        let started = Instant::now();
+        let (served, result) = match tokio::time::timeout(timeout, req.send()).await {
+            Err(_) => (
+                None,
+                Err(format!("no response in {}ms", timeout.as_millis())),
+            ),
+            Ok(Err(e)) => (None, Err(e.to_string())),
+            Ok(Ok(resp)) => {
+                let status = resp.status();
+                let header = |name| {
+                    (resp.headers().get(name))
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                let served = header(ROUTE_HEADER).map(|route| Served {
+                    route,
+                    upstream: header(UPSTREAM_HEADER).unwrap_or_default(),
+                    operation: None,
+                    region: None,
+                });
+                let passed = match probe.expect_status.is_empty() {
+                    true => !status.is_server_error(),
+                    false => probe.expect_status.contains(&status.as_u16()),
+                };
+                let result = match (&probe.route, &served) {
+                    _ if !passed => Err(format!("got {}", status)),
+                    (Some(route), Some(served)) if &served.route != route => {
+                        Err(format!("went to route {}, not {}", served.route, route))
+                    }
+                    (Some(route), None) => {
+                        Err(format!("got {} without reaching route {}", status, route))
+                    }
+                    _ => Ok(status),
+                };
+                (served, result)
+            }
+        };
+        let elapsed = started.elapsed().as_millis();
+        let target = served.map_or("no route".to_string(), |served| {
+            format!("route {}, upstream {}", served.route, served.upstream)
+        });
+        match result {
+            Ok(status) => println!(
+                "Self-test ok: {} {} ({}): {} in {}ms",
+                method, probe.path, target, status, elapsed
+            ),
+            Err(problem) => {
+                failed += 1;
+                println!(
+                    "Self-test FAILED: {} {} ({}) after {}ms: {}",
+                    method, probe.path, target, elapsed, problem
+                );
+            }
+        }
+    }
+
+    handle.stop(true).await;
+    let _ = serving.await;
+
+    println!(
+        "Self-test: {} of {} probes passed",
+        probes.len() - failed,
+        probes.len()
+    );
+    match failed {
+        0 => Ok(()),
+        _ => Err(Error::other(format!(
+            "Self-test failed: {} of {} probes failed",
+            failed,
+            probes.len()
+        ))),
+    }
+}
@@ -0,0 +1,50 @@
+use crate::config::{CorsConfig, LoadSheddingConfig, Priority, RouteConfig};
+use crate::cors;
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::HttpResponse;
+
+pub fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Critical => "critical",
+        Priority::Normal => "normal",
+        Priority::BestEffort => "best_effort",
+    }
+}
+
+// Requests in flight (this one included) the route's class tolerates
+fn threshold(config: &LoadSheddingConfig, priority: Priority) -> usize {
+    let share = |percent: f64| (config.max_in_flight as f64 * percent / 100.0) as usize;
+    match priority {
+        Priority::Critical => config.max_in_flight,
+        Priority::Normal => share(config.normal_percent),
+        Priority::BestEffort => share(config.best_effort_percent),
+    }
+}
+
+// A 503 when the proxy is too busy for the route's class, so the lower
+// classes go before playback does
+pub fn check(
+    config: &LoadSheddingConfig,
+    route: &RouteConfig,
+    in_flight: usize,
+    cors: &CorsConfig,
+) -> Option<HttpResponse> {
+    if in_flight <= threshold(config, route.priority) {
+        return None;
+    }
+    metrics::inc(
+        "requests_shed_total",
+        &[
+            ("route", &route.name),
+            ("priority", priority_name(route.priority)),
+        ],
+    );
+    let mut response = HttpResponse::ServiceUnavailable();
+    cors::apply(cors, &mut response);
+    Some(
+        response
+            .insert_header((header::RETRY_AFTER, config.retry_after_secs))
+            .body("Overloaded, try again later"),
+    )
+}
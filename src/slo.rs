@@ -0,0 +1,189 @@
+use crate::alerts;
+use crate::config::{BurnAlertConfig, SloConfig};
+use crate::metrics;
+use actix_web::http::StatusCode;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Service level objectives such as "99% of catalog requests within 300ms".
+// Requests are counted per minute for the length of the budget window;
+// from those come the budget left and how fast it's burning over each
+// alert's windows, alerted on when both windows burn too fast.
+
+// Good and total requests in one minute
+struct Minute {
+    minute: u64,
+    good: u64,
+    total: u64,
+}
+
+struct Tracked {
+    config: SloConfig,
+    minutes: Mutex<VecDeque<Minute>>,
+    // Per burn alert, whether it has fired and not yet stopped burning
+    burning: Mutex<Vec<bool>>,
+}
+
+pub struct Slos {
+    slos: Vec<Tracked>,
+}
+
+fn now_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 60
+}
+
+impl Tracked {
+    // Bad and total requests in the last `secs`, rounded up to whole
+    // minutes past the current one
+    fn window(&self, secs: u64) -> (u64, u64) {
+        let since = now_minute().saturating_sub(secs.div_ceil(60));
+        let minutes = self.minutes.lock().unwrap();
+        minutes
+            .iter()
+            .rev()
+            .take_while(|m| m.minute >= since)
+            .fold((0, 0), |(bad, total), m| {
+                (bad + m.total - m.good, total + m.total)
+            })
+    }
+
+    // How many times faster than the objective affords the budget went
+    fn burn_rate(&self, bad: u64, total: u64) -> f64 {
+        match total {
+            0 => 0.0,
+            _ => bad as f64 / total as f64 / (1.0 - self.config.objective),
+        }
+    }
+
+    fn evaluate(&self) {
+        let slo = &self.config;
+        let (bad, total) = self.window(slo.budget_days * 86400);
+        let remaining = 1.0 - self.burn_rate(bad, total);
+        metrics::set(
+            "slo_error_budget_remaining",
+            &[("slo", &slo.name)],
+            remaining,
+        );
+
+        let mut burning = self.burning.lock().unwrap();
+        for (alert, burning) in slo.burn_alerts.iter().zip(burning.iter_mut()) {
+            let (long_bad, long_total) = self.window(alert.long_secs);
+            let (short_bad, short_total) = self.window(alert.short_secs);
+            let (long, short) = (
+                self.burn_rate(long_bad, long_total),
+                self.burn_rate(short_bad, short_total),
+            );
+            for (secs, rate) in [(alert.long_secs, long), (alert.short_secs, short)] {
+                metrics::set(
+                    "slo_burn_rate",
+                    &[("slo", &slo.name), ("window", &format!("{}s", secs))],
+                    rate,
+                );
+            }
+            if !*burning
+                && long >= alert.burn_rate
+                && short >= alert.burn_rate
+                && short_total >= slo.min_requests
+            {
+                *burning = true;
+                fire(slo, alert, long, remaining);
+            } else if *burning && short < alert.burn_rate {
+                // The short window says it's over
+                *burning = false;
+                println!(
+                    "SLO {} is no longer burning its budget {}x over {}s",
+                    slo.name, alert.burn_rate, alert.long_secs
+                );
+            }
+        }
+    }
+}
+
+fn fire(slo: &SloConfig, alert: &BurnAlertConfig, rate: f64, remaining: f64) {
+    alerts::fire(
+        "slo_burn_rate",
+        format!(
+            "SLO {} on route {} is burning its error budget {:.1}x too fast over {}s, {:.0}% of it spent",
+            slo.name,
+            slo.route,
+            rate,
+            alert.long_secs,
+            (1.0 - remaining) * 100.0
+        ),
+        json!({
+            "slo": slo.name,
+            "route": slo.route,
+            "objective": slo.objective,
+            "latency_ms": slo.latency_ms,
+            "burn_rate": rate,
+            "threshold": alert.burn_rate,
+            "long_secs": alert.long_secs,
+            "short_secs": alert.short_secs,
+            "budget_remaining": remaining,
+        }),
+    );
+}
+
+impl Slos {
+    pub fn new(configs: &[SloConfig]) -> Self {
+        Slos {
+            slos: configs
+                .iter()
+                .map(|config| Tracked {
+                    config: config.clone(),
+                    minutes: Mutex::new(VecDeque::new()),
+                    burning: Mutex::new(vec![false; config.burn_alerts.len()]),
+                })
+                .collect(),
+        }
+    }
+
+    // Count a response against the objectives of its route
+    pub fn observe(&self, route: &str, status: StatusCode, elapsed: Duration) {
+        let minute = now_minute();
+        for tracked in self.slos.iter().filter(|t| t.config.route == route) {
+            let slo = &tracked.config;
+            let good = !status.is_server_error()
+                && (slo.latency_ms == 0 || elapsed.as_millis() as u64 <= slo.latency_ms);
+            let mut minutes = tracked.minutes.lock().unwrap();
+            if minutes.back().is_none_or(|last| last.minute != minute) {
+                minutes.push_back(Minute {
+                    minute,
+                    good: 0,
+                    total: 0,
+                });
+            }
+            let oldest = minute.saturating_sub(slo.budget_days * 1440);
+            while minutes.front().is_some_and(|first| first.minute <= oldest) {
+                minutes.pop_front();
+            }
+            if let Some(last) = minutes.back_mut() {
+                last.total += 1;
+                last.good += good as u64;
+            }
+        }
+    }
+
+    // Work out budgets and burn rates every half minute
+    pub fn spawn(self: &Arc<Self>) {
+        if self.slos.is_empty() {
+            return;
+        }
+        let slos = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                ticker.tick().await;
+                for tracked in &slos.slos {
+                    tracked.evaluate();
+                }
+            }
+        });
+    }
+}
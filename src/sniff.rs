@@ -0,0 +1,199 @@
+use crate::config::{BufferingMode, ContentTypeConfig, MismatchAction, RouteConfig};
+use crate::metrics;
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::HttpResponse;
+
+// Leading bytes of the binary formats we serve, and their media types
+const SIGNATURES: [(&[u8], &str); 12] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+];
+
+// Tags that make a browser render a body as a page
+const HTML_TAGS: [&str; 8] = [
+    "<!doctype html",
+    "<html",
+    "<head",
+    "<body",
+    "<script",
+    "<iframe",
+    "<title",
+    "<!--",
+];
+
+// The media type a body looks like, for the formats that can be told apart
+pub fn sniff(body: &[u8]) -> Option<&'static str> {
+    if let Some((_, media)) = SIGNATURES.iter().find(|(magic, _)| body.starts_with(magic)) {
+        return Some(media);
+    }
+    if body.len() >= 12 && &body[..4] == b"RIFF" && &body[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if body.len() >= 8 && &body[4..8] == b"ftyp" {
+        return Some("video/mp4");
+    }
+
+    let text = body.strip_prefix(b"\xef\xbb\xbf").unwrap_or(body);
+    let start = text.iter().position(|b| !b.is_ascii_whitespace())?;
+    let head =
+        String::from_utf8_lossy(&text[start..text.len().min(start + 64)]).to_ascii_lowercase();
+    if HTML_TAGS.iter().any(|tag| head.starts_with(tag)) {
+        Some("text/html")
+    } else if head.starts_with("<?xml") {
+        Some("application/xml")
+    } else if head.starts_with('{') || head.starts_with('[') {
+        Some("application/json")
+    } else {
+        None
+    }
+}
+
+// image/* matches image/png; anything else has to match exactly
+pub fn allowed(pattern: &str, media: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => media.split('/').next() == Some(kind),
+        None => pattern.eq_ignore_ascii_case(media),
+    }
+}
+
+// Whether a declared type is a fair description of what was sniffed
+fn compatible(declared: &str, sniffed: &str) -> bool {
+    declared == sniffed
+        || declared == "application/octet-stream" && sniffed != "text/html"
+        || match sniffed {
+            "application/json" => declared.ends_with("+json") || declared == "text/plain",
+            "application/xml" => declared == "text/xml" || declared.ends_with("+xml"),
+            // ftyp and Matroska containers carry audio as well as video
+            "video/mp4" | "video/webm" => {
+                declared.starts_with("video/")
+                    || declared.starts_with("audio/")
+                    || declared == "application/mp4"
+            }
+            "image/jpeg" => declared == "image/jpg",
+            "application/gzip" => declared == "application/x-gzip",
+            _ => false,
+        }
+}
+
+fn media_type(response: &HttpResponse<()>) -> Option<String> {
+    let value = response
+        .headers()
+        .get(header::CONTENT_TYPE)?
+        .to_str()
+        .ok()?;
+    let media = value.split(';').next()?.trim().to_ascii_lowercase();
+    (!media.is_empty()).then_some(media)
+}
+
+fn mismatch(route: &RouteConfig, reason: &str, action: &str) {
+    metrics::inc(
+        "content_type_mismatches_total",
+        &[
+            ("route", &route.name),
+            ("reason", reason),
+            ("action", action),
+        ],
+    );
+}
+
+// Apply the route's content type policy to a successful response. Only
+// buffered bodies are sniffed; streamed and content-encoded ones only have
+// their declared type checked.
+pub async fn enforce(route: &RouteConfig, mut response: HttpResponse) -> HttpResponse {
+    let config: &ContentTypeConfig = match &route.content_type {
+        Some(config) => config,
+        None => return response,
+    };
+    if config.nosniff {
+        response.headers_mut().insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+    }
+    let status = response.status();
+    // A range, or several ranges in multipart/byteranges, can't be judged
+    // by its first bytes or its declared type
+    if !status.is_success() || matches!(status.as_u16(), 204 | 206) {
+        return response;
+    }
+
+    let encoded = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|v| v.as_bytes() != b"identity");
+    let (mut head, body) = response.into_parts();
+    let declared = media_type(&head);
+    let unexpected = !config.expected.is_empty()
+        && !declared
+            .as_deref()
+            .is_some_and(|d| config.expected.iter().any(|e| allowed(e, d)));
+    if !unexpected && !config.sniff {
+        return head.set_body(body);
+    }
+
+    // Like encoded ones, bodies the route streams aren't held back to be looked at
+    let streams = route.buffering.mode == BufferingMode::Streaming;
+    let (body, sniffed) = match body.size() {
+        BodySize::Sized(_) if !encoded && !streams => match body::to_bytes(body).await {
+            Ok(bytes) => {
+                let sniffed = sniff(&bytes);
+                (BoxBody::new(bytes), sniffed)
+            }
+            Err(_) => return HttpResponse::BadGateway().body("Bad gateway"),
+        },
+        _ => (body, None),
+    };
+    let misdeclared = config.sniff
+        && sniffed.is_some_and(|s| !declared.as_deref().is_some_and(|d| compatible(d, s)));
+    let reason = match (unexpected, misdeclared) {
+        (true, _) => "unexpected",
+        (false, true) => "sniffed",
+        (false, false) => return head.set_body(body),
+    };
+
+    let declared = declared.unwrap_or_else(|| "none".to_string());
+    let sniffed_name = sniffed.unwrap_or("unknown");
+    let correction = sniffed.filter(|s| {
+        config.on_mismatch == MismatchAction::Correct
+            && (config.expected.is_empty() || config.expected.iter().any(|e| allowed(e, s)))
+    });
+    match (config.on_mismatch, correction) {
+        (MismatchAction::Log, _) => {
+            println!(
+                "Route {} served {} that looks like {} ({})",
+                route.name, declared, sniffed_name, reason
+            );
+            mismatch(route, reason, "log");
+            head.set_body(body)
+        }
+        (_, Some(corrected)) => {
+            println!(
+                "Route {} served {} that looks like {}, corrected",
+                route.name, declared, corrected
+            );
+            mismatch(route, reason, "correct");
+            head.headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(corrected));
+            head.set_body(body)
+        }
+        _ => {
+            println!(
+                "Route {} refused a {} response that looks like {} ({})",
+                route.name, declared, sniffed_name, reason
+            );
+            mismatch(route, reason, "reject");
+            HttpResponse::BadGateway().body("Unexpected upstream content type")
+        }
+    }
+}
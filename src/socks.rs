@@ -0,0 +1,273 @@
+use crate::config::ProxyTransportConfig;
+use crate::metrics;
+use reqwest::Url;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+// Upstream pools with transport = socks5. reqwest is built without SOCKS
+// support, so the pool's client is pointed at a bridge on loopback instead,
+// as if it were an HTTP proxy. Each connection to the bridge is opened on
+// through the SOCKS5 proxy to the host the client asked for: CONNECT for
+// https targets, which then carry TLS end to end, and absolute-form
+// requests for http ones. Those are rewritten to the origin form and sent
+// with Connection: close, their connections being kept out of the client's
+// pool, so each carries just the one request.
+
+// Longest request head a client sends the bridge
+const MAX_HEAD: usize = 16 * 1024;
+// For the proxy to answer each step of its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Proxy {
+    upstream: String,
+    address: String,
+    // Username and password, when the proxy wants them
+    auth: Option<(String, String)>,
+}
+
+// Start the pool's bridge; returns the proxy URL for its client
+pub fn bridge(
+    upstream: &str,
+    config: &ProxyTransportConfig,
+    password: String,
+) -> Result<String, Error> {
+    if config.username.len() > 255 || password.len() > 255 {
+        return Err(Error::other(format!(
+            "Upstream {} SOCKS5 username and password can't be over 255 bytes",
+            upstream
+        )));
+    }
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let listener = TcpListener::from_std(listener)?;
+    let proxy = Arc::new(Proxy {
+        upstream: upstream.to_string(),
+        address: config.address.clone(),
+        auth: (!config.username.is_empty()).then(|| (config.username.clone(), password)),
+    });
+    println!(
+        "Upstream {} connects through SOCKS5 proxy {}",
+        upstream, config.address
+    );
+    tokio::spawn(async move {
+        loop {
+            let (client, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!(
+                        "SOCKS5 bridge for {} failed to accept: {}",
+                        proxy.upstream, e
+                    );
+                    continue;
+                }
+            };
+            let proxy = proxy.clone();
+            tokio::spawn(async move {
+                let result = relay(&proxy, client).await;
+                let outcome = if result.is_ok() { "ok" } else { "error" };
+                metrics::inc(
+                    "socks_connections_total",
+                    &[("upstream", &proxy.upstream), ("result", outcome)],
+                );
+                if let Err(e) = result {
+                    eprintln!("SOCKS5 connection for upstream {}: {}", proxy.upstream, e);
+                }
+            });
+        }
+    });
+    Ok(url)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+// Where the client's request goes, and whether it was a CONNECT
+fn target(head: &str) -> Result<(String, u16, bool), Error> {
+    let line = head.lines().next().unwrap_or_default();
+    let mut parts = line.split(' ');
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+    let target = target.ok_or_else(|| invalid("malformed request line"))?;
+    if method == "CONNECT" {
+        let (host, port) = target
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("CONNECT without a port"))?;
+        let port = port
+            .parse()
+            .map_err(|_| invalid("CONNECT with a bad port"))?;
+        return Ok((host.trim_matches(['[', ']']).to_string(), port, true));
+    }
+    let url = Url::parse(target).map_err(|_| invalid("request isn't in absolute form"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid("request URL has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    Ok((host.trim_matches(['[', ']']).to_string(), port, false))
+}
+
+// An absolute-form request head as the origin expects it
+fn origin_form(head: &[u8]) -> Result<String, Error> {
+    let head = std::str::from_utf8(head).map_err(|_| invalid("request head isn't UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let line = lines.next().unwrap_or_default();
+    let mut parts = line.splitn(3, ' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(invalid("malformed request line")),
+    };
+    let url = Url::parse(target).map_err(|_| invalid("request isn't in absolute form"))?;
+    let path = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let mut out = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        let hop = [
+            "connection",
+            "proxy-connection",
+            "proxy-authorization",
+            "keep-alive",
+        ];
+        if !hop.iter().any(|hop| name.eq_ignore_ascii_case(hop)) {
+            out.push_str(line);
+            out.push_str("\r\n");
+        }
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    Ok(out)
+}
+
+async fn relay(proxy: &Proxy, mut client: TcpStream) -> Result<(), Error> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    let end = loop {
+        if let Some(i) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if head.len() > MAX_HEAD {
+            return Err(invalid("request head too large"));
+        }
+        let n = client.read(&mut buf).await?;
+        if n == 0 {
+            // The client gave up on a pooled connection it never used
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    };
+    let (host, port, connect) = target(&String::from_utf8_lossy(&head[..end]))?;
+
+    let mut upstream = match timeout(HANDSHAKE_TIMEOUT, open(proxy, &host, port)).await {
+        Ok(Ok(upstream)) => upstream,
+        Ok(Err(e)) => {
+            let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+            return Err(e);
+        }
+        Err(_) => {
+            let _ = client
+                .write_all(b"HTTP/1.1 504 Gateway Timeout\r\n\r\n")
+                .await;
+            return Err(ErrorKind::TimedOut.into());
+        }
+    };
+    match connect {
+        true => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+            upstream.write_all(&head[end..]).await?;
+        }
+        false => {
+            upstream
+                .write_all(origin_form(&head[..end])?.as_bytes())
+                .await?;
+            upstream.write_all(&head[end..]).await?;
+        }
+    }
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+// A connection to host:port through the proxy (RFC 1928, with RFC 1929
+// username and password)
+async fn open(proxy: &Proxy, host: &str, port: u16) -> Result<TcpStream, Error> {
+    let mut io = TcpStream::connect(&proxy.address).await?;
+    let method = if proxy.auth.is_some() { 2 } else { 0 };
+    io.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    io.read_exact(&mut reply).await?;
+    match reply {
+        [5, m] if m == method => {}
+        [5, 0xff] => return Err(Error::other("the SOCKS5 proxy refused our auth method")),
+        _ => return Err(invalid("not a SOCKS5 proxy")),
+    }
+    if let Some((username, password)) = &proxy.auth {
+        let mut request = vec![1, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        io.write_all(&request).await?;
+        io.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(Error::other(
+                "the SOCKS5 proxy refused the username and password",
+            ));
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        // Left for the proxy to resolve, as the tunnel's far end knows the name
+        Err(_) if host.len() <= 255 => {
+            request.extend_from_slice(&[3, host.len() as u8]);
+            request.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => return Err(invalid("host name too long for SOCKS5")),
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    io.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    io.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        let reason = match reply[1] {
+            1 => "general failure",
+            2 => "not allowed by its rules",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            7 => "command not supported",
+            8 => "address type not supported",
+            _ => "unknown error",
+        };
+        return Err(Error::other(format!(
+            "SOCKS5 proxy couldn't connect to {}:{}: {}",
+            host, port, reason
+        )));
+    }
+    // The address the proxy bound, which is of no use here
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => io.read_u8().await? as usize,
+        _ => return Err(invalid("bad SOCKS5 reply")),
+    };
+    let mut bound = vec![0u8; len + 2];
+    io.read_exact(&mut bound).await?;
+    Ok(io)
+}
@@ -0,0 +1,233 @@
+use crate::config::{ApiKeyRecord, Config, RouteConfig, UpstreamConfig};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+
+// Routes, upstream pools and tenant API keys kept in an embedded SQLite
+// database. Routes are edited live; upstream and API key changes are picked
+// up on the next start. Partner keys of sqlite-backed authenticators are
+// looked up on every request, so their changes apply at once.
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+fn db_error(e: rusqlite::Error) -> Error {
+    Error::other(format!("Config store: {}", e))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(what: &str, raw: &str) -> Result<T, Error> {
+    serde_json::from_str(raw).map_err(|e| Error::other(format!("Config store {}: {}", what, e)))
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let conn = Connection::open(path).map_err(db_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS routes (
+                 position INTEGER NOT NULL,
+                 name TEXT PRIMARY KEY,
+                 definition TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS upstreams (name TEXT PRIMARY KEY, definition TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS api_keys (key TEXT PRIMARY KEY, tenant TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS partner_keys (
+                 authenticator TEXT NOT NULL,
+                 hash TEXT NOT NULL,
+                 record TEXT NOT NULL,
+                 PRIMARY KEY (authenticator, hash)
+             );",
+        )
+        .map_err(db_error)?;
+
+        Ok(Store {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Stored upstreams replace the config file's pools of the same name, and
+    // stored API keys are added to their tenants
+    pub fn apply(&self, config: &mut Config) -> Result<(), Error> {
+        for (name, upstream) in self.upstreams()? {
+            config.upstreams.insert(name, upstream);
+        }
+        for (key, tenant) in self.api_keys()? {
+            match config.tenants.get_mut(&tenant) {
+                Some(tenant) if !tenant.api_keys.contains(&key) => tenant.api_keys.push(key),
+                Some(_) => {}
+                None => eprintln!("Config store: API key for unknown tenant {}", tenant),
+            }
+        }
+        Ok(())
+    }
+
+    // None until routes have been saved for the first time
+    pub fn routes(&self) -> Result<Option<Vec<RouteConfig>>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let saved: Option<String> = conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'routes_saved'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)?;
+        if saved.is_none() {
+            return Ok(None);
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT definition FROM routes ORDER BY position")
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(db_error)?;
+        let mut routes = Vec::new();
+        for raw in rows {
+            routes.push(decode("route", &raw.map_err(db_error)?)?);
+        }
+        Ok(Some(routes))
+    }
+
+    pub fn save_routes(&self, routes: &[RouteConfig]) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(db_error)?;
+        tx.execute("DELETE FROM routes", []).map_err(db_error)?;
+        for (position, route) in routes.iter().enumerate() {
+            let definition = serde_json::to_string(route).map_err(Error::other)?;
+            tx.execute(
+                "INSERT INTO routes (position, name, definition) VALUES (?1, ?2, ?3)",
+                params![position as i64, route.name, definition],
+            )
+            .map_err(db_error)?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('routes_saved', '1')",
+            [],
+        )
+        .map_err(db_error)?;
+        tx.commit().map_err(db_error)
+    }
+
+    pub fn upstreams(&self) -> Result<HashMap<String, UpstreamConfig>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name, definition FROM upstreams")
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(db_error)?;
+        let mut upstreams = HashMap::new();
+        for row in rows {
+            let (name, raw) = row.map_err(db_error)?;
+            upstreams.insert(name, decode("upstream", &raw)?);
+        }
+        Ok(upstreams)
+    }
+
+    pub fn put_upstream(&self, name: &str, upstream: &UpstreamConfig) -> Result<(), Error> {
+        let definition = serde_json::to_string(upstream).map_err(Error::other)?;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO upstreams (name, definition) VALUES (?1, ?2)",
+                params![name, definition],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    // Returns whether there was anything to delete
+    pub fn delete_upstream(&self, name: &str) -> Result<bool, Error> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM upstreams WHERE name = ?1", params![name])
+            .map(|deleted| deleted > 0)
+            .map_err(db_error)
+    }
+
+    // API key -> tenant name
+    pub fn api_keys(&self) -> Result<Vec<(String, String)>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT key, tenant FROM api_keys ORDER BY tenant, key")
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    pub fn put_api_key(&self, key: &str, tenant: &str) -> Result<(), Error> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO api_keys (key, tenant) VALUES (?1, ?2)",
+                params![key, tenant],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    pub fn delete_api_key(&self, key: &str) -> Result<bool, Error> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM api_keys WHERE key = ?1", params![key])
+            .map(|deleted| deleted > 0)
+            .map_err(db_error)
+    }
+
+    pub fn partner_keys(&self, authenticator: &str) -> Result<Vec<ApiKeyRecord>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT record FROM partner_keys WHERE authenticator = ?1 ORDER BY rowid")
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map(params![authenticator], |row| row.get::<_, String>(0))
+            .map_err(db_error)?;
+        let mut records = Vec::new();
+        for raw in rows {
+            records.push(decode("partner key", &raw.map_err(db_error)?)?);
+        }
+        Ok(records)
+    }
+
+    pub fn partner_key(
+        &self,
+        authenticator: &str,
+        hash: &str,
+    ) -> Result<Option<ApiKeyRecord>, Error> {
+        let raw: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT record FROM partner_keys WHERE authenticator = ?1 AND hash = ?2",
+                params![authenticator, hash],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(db_error)?;
+        raw.map(|raw| decode("partner key", &raw)).transpose()
+    }
+
+    pub fn put_partner_key(&self, authenticator: &str, record: &ApiKeyRecord) -> Result<(), Error> {
+        let raw = serde_json::to_string(record).map_err(Error::other)?;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO partner_keys (authenticator, hash, record) VALUES (?1, ?2, ?3)",
+                params![authenticator, record.hash, raw],
+            )
+            .map(|_| ())
+            .map_err(db_error)
+    }
+}
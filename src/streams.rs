@@ -0,0 +1,273 @@
+use crate::auth::{self, Claims, JwtValidator};
+use crate::config::StreamsConfig;
+use crate::metrics;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use redis::aio::ConnectionManager;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Drop expired sessions, then admit the session if it's already open or
+// there's room for it. Returns the user's open sessions, or -1 when over the
+// cap. Every session is also kept in one set for the active count.
+const ADMIT: &str = r"
+local key, all, session = KEYS[1], KEYS[2], ARGV[1]
+local now, idle, max = tonumber(ARGV[2]), tonumber(ARGV[3]), tonumber(ARGV[4])
+redis.call('ZREMRANGEBYSCORE', key, '-inf', now - idle)
+if not redis.call('ZSCORE', key, session) and redis.call('ZCARD', key) >= max then
+  return -1
+end
+redis.call('ZADD', key, now, session)
+redis.call('PEXPIRE', key, idle)
+redis.call('ZADD', all, now, ARGV[5])
+return redis.call('ZCARD', key)
+";
+
+const ALL_SESSIONS: &str = "streams:all";
+
+enum Backend {
+    // User -> session id -> last request
+    Memory(Mutex<HashMap<String, HashMap<String, Instant>>>),
+    Redis(ConnectionManager, redis::Script),
+}
+
+// Concurrent playback sessions per user. A session is open from its first
+// request until the player sends a DELETE with the session header, or until
+// it has been idle for idle_secs.
+pub struct Streams {
+    config: StreamsConfig,
+    backend: Backend,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Streams {
+    pub async fn new(config: StreamsConfig) -> Result<Self, Error> {
+        let backend = match &config.redis_url {
+            Some(url) => {
+                let client = redis::Client::open(url.as_str())
+                    .map_err(|e| Error::other(format!("Invalid streams redis_url: {}", e)))?;
+                let manager = ConnectionManager::new(client)
+                    .await
+                    .map_err(|e| Error::other(format!("Streams Redis unavailable: {}", e)))?;
+                Backend::Redis(manager, redis::Script::new(ADMIT))
+            }
+            None => Backend::Memory(Mutex::new(HashMap::new())),
+        };
+
+        Ok(Streams { config, backend })
+    }
+
+    fn session<'a>(&self, req: &'a HttpRequest) -> Option<&'a str> {
+        req.headers()
+            .get(self.config.session_header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .filter(|s| !s.is_empty())
+    }
+
+    fn rejection(&self, user: &str, session: &str) -> HttpResponse {
+        println!(
+            "Rejected playback session {} for {}: {} streams already open",
+            session, user, self.config.max_sessions
+        );
+        metrics::inc("stream_limit_rejections_total", &[]);
+        let status = StatusCode::from_u16(self.config.status).unwrap_or(StatusCode::CONFLICT);
+        HttpResponse::build(status).body("Too many concurrent streams")
+    }
+
+    // Count the request's session against the caller's cap. Returns the
+    // rejection when the user already has max_sessions other sessions open.
+    pub async fn check(&self, req: &HttpRequest, claims: &Claims) -> Result<(), HttpResponse> {
+        let user = match claims.get(&self.config.user_claim).and_then(|v| v.as_str()) {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let session = match self.session(req) {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+
+        if req.method() == Method::DELETE {
+            if let Err(e) = self.end(user, session).await {
+                eprintln!("Streams backend error: {}", e);
+            }
+            return Ok(());
+        }
+
+        match self.admit(user, session).await {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(self.rejection(user, session)),
+            Err(e) => {
+                // Fail open rather than stopping playback when Redis is down
+                eprintln!("Streams backend error: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    // The built-in heartbeat endpoint: players POST to keep their session
+    // open and DELETE when playback stops. Answered here, never forwarded.
+    pub async fn heartbeat(&self, req: &HttpRequest, jwt: Option<&JwtValidator>) -> HttpResponse {
+        let claims = match (jwt, auth::bearer_token(req)) {
+            (Some(jwt), Some(token)) => match jwt.validate(token) {
+                Ok(claims) => claims,
+                Err(_) => return HttpResponse::Unauthorized().body("Invalid token"),
+            },
+            _ => return HttpResponse::Unauthorized().body("Missing bearer token"),
+        };
+        let (user, session) = match (
+            claims.get(&self.config.user_claim).and_then(|v| v.as_str()),
+            self.session(req),
+        ) {
+            (Some(user), Some(session)) => (user, session),
+            (None, _) => return HttpResponse::Forbidden().body("Token has no user"),
+            (_, None) => {
+                return HttpResponse::BadRequest()
+                    .body(format!("Missing {} header", self.config.session_header))
+            }
+        };
+
+        if !matches!(*req.method(), Method::POST | Method::DELETE) {
+            return HttpResponse::MethodNotAllowed().finish();
+        }
+        metrics::inc(
+            "playback_heartbeats_total",
+            &[("method", req.method().as_str())],
+        );
+        let result = match *req.method() {
+            Method::DELETE => self.end(user, session).await.map(|_| None),
+            _ => self.admit(user, session).await.map(Some),
+        };
+        match result {
+            Ok(None) => HttpResponse::NoContent().finish(),
+            Ok(Some(Some(open))) => HttpResponse::Ok().json(json!({
+                "sessions": open,
+                "max_sessions": self.config.max_sessions,
+            })),
+            Ok(Some(None)) => self.rejection(user, session),
+            Err(e) => {
+                eprintln!("Streams backend error: {}", e);
+                HttpResponse::ServiceUnavailable().body("Session store unavailable")
+            }
+        }
+    }
+
+    pub fn heartbeat_path(&self) -> &str {
+        &self.config.heartbeat_path
+    }
+
+    // Open sessions across all users, for dashboards
+    pub async fn active(&self) -> Result<u64, String> {
+        let idle = Duration::from_secs(self.config.idle_secs.max(1));
+        match &self.backend {
+            Backend::Memory(users) => {
+                let now = Instant::now();
+                let users = users.lock().unwrap();
+                let open = users
+                    .values()
+                    .flat_map(|sessions| sessions.values())
+                    .filter(|seen| now.duration_since(**seen) < idle)
+                    .count();
+                Ok(open as u64)
+            }
+            Backend::Redis(manager, _) => {
+                let cutoff = unix_millis() - idle.as_millis() as u64;
+                let mut conn = manager.clone();
+                let (open,): (u64,) = redis::pipe()
+                    .atomic()
+                    .cmd("ZREMRANGEBYSCORE")
+                    .arg(ALL_SESSIONS)
+                    .arg("-inf")
+                    .arg(cutoff)
+                    .ignore()
+                    .cmd("ZCARD")
+                    .arg(ALL_SESSIONS)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(open)
+            }
+        }
+    }
+
+    // The user's open sessions once this one is counted, None if over the cap
+    async fn admit(&self, user: &str, session: &str) -> Result<Option<u64>, String> {
+        let idle = Duration::from_secs(self.config.idle_secs.max(1));
+        match &self.backend {
+            Backend::Memory(users) => {
+                let mut users = users.lock().unwrap();
+                let now = Instant::now();
+                let sessions = users.entry(user.to_string()).or_default();
+                sessions.retain(|_, seen| now.duration_since(*seen) < idle);
+                let open = sessions.contains_key(session);
+                if !open && sessions.len() as u64 >= self.config.max_sessions {
+                    return Ok(None);
+                }
+                sessions.insert(session.to_string(), now);
+                let count = sessions.len() as u64;
+
+                // Forget users whose sessions have all expired
+                if users.len() > 100_000 {
+                    users.retain(|_, sessions| {
+                        sessions.retain(|_, seen| now.duration_since(*seen) < idle);
+                        !sessions.is_empty()
+                    });
+                }
+                Ok(Some(count))
+            }
+            Backend::Redis(manager, script) => {
+                let mut conn = manager.clone();
+                let open: i64 = script
+                    .key(format!("streams:{}", user))
+                    .key(ALL_SESSIONS)
+                    .arg(session)
+                    .arg(unix_millis())
+                    .arg(idle.as_millis() as u64)
+                    .arg(self.config.max_sessions)
+                    .arg(format!("{}\n{}", user, session))
+                    .invoke_async(&mut conn)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok(u64::try_from(open).ok())
+            }
+        }
+    }
+
+    async fn end(&self, user: &str, session: &str) -> Result<(), String> {
+        match &self.backend {
+            Backend::Memory(users) => {
+                let mut users = users.lock().unwrap();
+                if let Some(sessions) = users.get_mut(user) {
+                    sessions.remove(session);
+                    if sessions.is_empty() {
+                        users.remove(user);
+                    }
+                }
+                Ok(())
+            }
+            Backend::Redis(manager, _) => {
+                let mut conn = manager.clone();
+                redis::pipe()
+                    .cmd("ZREM")
+                    .arg(format!("streams:{}", user))
+                    .arg(session)
+                    .ignore()
+                    .cmd("ZREM")
+                    .arg(ALL_SESSIONS)
+                    .arg(format!("{}\n{}", user, session))
+                    .ignore()
+                    .query_async::<_, ()>(&mut conn)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
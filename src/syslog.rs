@@ -0,0 +1,137 @@
+use crate::config::SyslogConfig;
+use std::fs;
+use std::io::{self, Error, Write};
+use std::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv",
+    "ftp", "ntp", "audit", "alert", "clock", "local0", "local1", "local2", "local3", "local4",
+    "local5", "local6", "local7",
+];
+
+// Informational: access log lines aren't errors
+const SEVERITY_INFO: u8 = 6;
+
+enum Transport {
+    Udp(UdpSocket),
+    // Reconnected on the next message after a failure
+    Tcp(String, Mutex<Option<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixDatagram, String),
+}
+
+// RFC 5424 messages over UDP, TCP (octet-counted framing) or a unix socket
+pub struct Syslog {
+    transport: Transport,
+    priority: u8,
+    hostname: String,
+    app_name: String,
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> Result<Self, Error> {
+        let facility = FACILITIES
+            .iter()
+            .position(|f| *f == config.facility)
+            .ok_or_else(|| Error::other(format!("Unknown syslog facility {}", config.facility)))?;
+
+        let transport = match config.address.split_once("://") {
+            Some(("udp", addr)) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                Transport::Udp(socket)
+            }
+            Some(("tcp", addr)) => Transport::Tcp(addr.to_string(), Mutex::new(None)),
+            #[cfg(unix)]
+            Some(("unix", path)) => Transport::Unix(UnixDatagram::unbound()?, path.to_string()),
+            _ => {
+                return Err(Error::other(format!(
+                    "Syslog address {} must be udp://host:port, tcp://host:port or unix:///path",
+                    config.address
+                )))
+            }
+        };
+
+        Ok(Syslog {
+            transport,
+            priority: facility as u8 * 8 + SEVERITY_INFO,
+            hostname: hostname(),
+            app_name: config.app_name.clone(),
+        })
+    }
+
+    pub fn send(&self, message: &str, timestamp: &str) -> io::Result<()> {
+        let line = format!(
+            "<{}>1 {} {} {} {} - - {}",
+            self.priority,
+            timestamp,
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            message
+        );
+
+        match &self.transport {
+            Transport::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Transport::Tcp(addr, stream) => {
+                let mut stream = stream.lock().unwrap();
+                if stream.is_none() {
+                    *stream = Some(TcpStream::connect(addr)?);
+                }
+                let framed = format!("{} {}", line.len(), line);
+                let result = stream.as_mut().unwrap().write_all(framed.as_bytes());
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket, path) => socket.send_to(line.as_bytes(), path).map(|_| ()),
+        }
+    }
+}
+
+// systemd-journald's native protocol, one datagram per entry
+#[cfg(unix)]
+pub struct Journald {
+    socket: UnixDatagram,
+    identifier: String,
+}
+
+#[cfg(unix)]
+impl Journald {
+    const SOCKET: &'static str = "/run/systemd/journal/socket";
+
+    pub fn new(identifier: &str) -> Result<Self, Error> {
+        Ok(Journald {
+            socket: UnixDatagram::unbound()?,
+            identifier: identifier.to_string(),
+        })
+    }
+
+    pub fn send(&self, message: &str) -> io::Result<()> {
+        let mut entry = format!(
+            "PRIORITY={}\nSYSLOG_IDENTIFIER={}\n",
+            SEVERITY_INFO, self.identifier
+        )
+        .into_bytes();
+        // Length-prefixed so the message may contain anything
+        entry.extend_from_slice(b"MESSAGE\n");
+        entry.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        entry.extend_from_slice(message.as_bytes());
+        entry.push(b'\n');
+        self.socket.send_to(&entry, Self::SOCKET).map(|_| ())
+    }
+}
@@ -0,0 +1,133 @@
+use std::env;
+use std::net::TcpListener;
+
+// Sockets handed over by systemd (LISTEN_FDS), optionally named through
+// FileDescriptorName= in the .socket unit, or by the process we're taking
+// over from (see handoff.rs), which names them all. Unnamed sockets are
+// taken in order: the first is the public listener, the second the admin
+// listener.
+pub struct InheritedSockets {
+    sockets: Vec<(Option<String>, Option<TcpListener>)>,
+}
+
+impl InheritedSockets {
+    pub fn from_env() -> Self {
+        let sockets = listen_fds();
+        if !sockets.is_empty() {
+            println!("Using {} socket(s) passed by systemd", sockets.len());
+        }
+        InheritedSockets {
+            sockets: sockets.into_iter().map(|(n, l)| (n, Some(l))).collect(),
+        }
+    }
+
+    pub fn handed_over(sockets: Vec<(String, TcpListener)>) -> Self {
+        InheritedSockets {
+            sockets: sockets
+                .into_iter()
+                .map(|(n, l)| (Some(n), Some(l)))
+                .collect(),
+        }
+    }
+
+    // Every socket with the name, or the one at the position when none are
+    // named
+    pub fn take(&mut self, name: &str, position: Option<usize>) -> Vec<TcpListener> {
+        let unnamed = self.sockets.iter().all(|(n, _)| n.is_none());
+        if unnamed {
+            let slot = position.and_then(|position| self.sockets.get_mut(position));
+            return slot
+                .and_then(|(_, listener)| listener.take())
+                .into_iter()
+                .collect();
+        }
+        self.sockets
+            .iter_mut()
+            .filter(|(n, _)| n.as_deref() == Some(name))
+            .filter_map(|(_, listener)| listener.take())
+            .collect()
+    }
+}
+
+#[cfg(unix)]
+fn listen_fds() -> Vec<(Option<String>, TcpListener)> {
+    use std::os::unix::io::FromRawFd;
+
+    // The sockets are only meant for us if LISTEN_PID matches our pid
+    let pid_matches = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map(|pid| pid == std::process::id())
+        .unwrap_or(false);
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+        .unwrap_or(0);
+    if !pid_matches || count <= 0 {
+        return Vec::new();
+    }
+
+    let names: Vec<String> = env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+
+    // Don't pass the sockets on to anything we spawn
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    const SD_LISTEN_FDS_START: i32 = 3;
+    (0..count)
+        .map(|i| {
+            // SAFETY: systemd guarantees these descriptors are open and owned by us
+            let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START + i) };
+            let _ = listener.set_nonblocking(true);
+            let name = names
+                .get(i as usize)
+                .filter(|n| !n.is_empty() && n.as_str() != "unknown")
+                .cloned();
+            (name, listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn listen_fds() -> Vec<(Option<String>, TcpListener)> {
+    Vec::new()
+}
+
+// Report a state change (READY=1, STOPPING=1, ...) to the service manager
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("sd_notify: {}", e);
+            return;
+        }
+    };
+
+    let result = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return,
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(e) = result {
+        eprintln!("sd_notify {}: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
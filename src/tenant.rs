@@ -0,0 +1,141 @@
+use crate::config::{Config, CorsConfig, RateLimitConfig, RouteConfig, TenancyConfig};
+use crate::metrics;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Token bucket limiting the request rate of a whole tenant
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            bucket: Mutex::new((config.burst, Instant::now())),
+            config,
+        }
+    }
+
+    pub fn allow(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens
+            + now.duration_since(*last).as_secs_f64() * self.config.requests_per_second)
+            .min(self.config.burst);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct Tenant {
+    pub name: String,
+    upstream: Option<String>,
+    routes: HashMap<String, String>,
+    cors: Option<CorsConfig>,
+    limiter: Option<RateLimiter>,
+}
+
+impl Tenant {
+    // Upstream to use for a route, honouring tenant overrides
+    pub fn upstream_for<'a>(&'a self, route: &'a RouteConfig) -> &'a str {
+        self.routes
+            .get(&route.name)
+            .or(self.upstream.as_ref())
+            .map(|url| url.trim_end_matches('/'))
+            .unwrap_or(&route.upstream)
+    }
+
+    pub fn cors(&self) -> Option<&CorsConfig> {
+        self.cors.as_ref()
+    }
+
+    pub fn allow(&self) -> bool {
+        match &self.limiter {
+            Some(limiter) if !limiter.allow() => {
+                metrics::inc("tenant_rate_limited_total", &[("tenant", &self.name)]);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+// Resolves which white-label tenant a request belongs to
+pub struct Tenants {
+    config: TenancyConfig,
+    tenants: HashMap<String, Tenant>,
+    by_host: HashMap<String, String>,
+    by_key: HashMap<String, String>,
+}
+
+impl Tenants {
+    pub fn new(config: &Config) -> Self {
+        let mut tenants = HashMap::new();
+        let mut by_host = HashMap::new();
+        let mut by_key = HashMap::new();
+
+        for (name, tenant) in &config.tenants {
+            for host in &tenant.hosts {
+                by_host.insert(host.to_ascii_lowercase(), name.clone());
+            }
+            for key in &tenant.api_keys {
+                by_key.insert(key.clone(), name.clone());
+            }
+            tenants.insert(
+                name.clone(),
+                Tenant {
+                    name: name.clone(),
+                    upstream: tenant.upstream.clone(),
+                    routes: tenant.routes.clone(),
+                    cors: tenant.cors.clone(),
+                    limiter: tenant.rate_limit.clone().map(RateLimiter::new),
+                },
+            );
+        }
+
+        Tenants {
+            config: config.tenancy.clone(),
+            tenants,
+            by_host,
+            by_key,
+        }
+    }
+
+    pub fn resolve(&self, req: &HttpRequest) -> Option<&Tenant> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+
+        let header_value = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+
+        let name = header_value(&self.config.tenant_header)
+            .filter(|name| self.tenants.contains_key(name))
+            .or_else(|| {
+                header_value(&self.config.api_key_header)
+                    .and_then(|key| self.by_key.get(&key).cloned())
+            })
+            .or_else(|| {
+                let host = header_value(header::HOST.as_str())?;
+                let host = host.split(':').next()?.to_ascii_lowercase();
+                self.by_host.get(&host).cloned()
+            })?;
+
+        self.tenants.get(&name)
+    }
+}
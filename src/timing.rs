@@ -0,0 +1,61 @@
+use actix_web::http::header::HeaderValue;
+use actix_web::{HttpMessage, HttpRequest};
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+// Where a request's time went, kept in its extensions while it's handled
+// and sent back as Server-Timing. Requests without one aren't timed.
+struct Timing {
+    started: Instant,
+    // End of the last marked phase
+    last: Cell<Instant>,
+    phases: RefCell<Vec<(&'static str, Option<Duration>)>>,
+}
+
+pub fn start(req: &HttpRequest, started: Instant) {
+    req.extensions_mut().insert(Timing {
+        started,
+        last: Cell::new(started),
+        phases: RefCell::new(Vec::new()),
+    });
+}
+
+// End a phase that started where the last one ended
+pub fn mark(req: &HttpRequest, phase: &'static str) {
+    if let Some(timing) = req.extensions().get::<Timing>() {
+        let now = Instant::now();
+        let took = now - timing.last.replace(now);
+        timing.phases.borrow_mut().push((phase, Some(took)));
+    }
+}
+
+// Record a phase timed elsewhere, replacing an earlier one of the same name
+// (a retried upstream call counts the attempt that answered). None shows the
+// phase happened without saying how long it took.
+pub fn record(req: &HttpRequest, phase: &'static str, took: Option<Duration>) {
+    if let Some(timing) = req.extensions().get::<Timing>() {
+        let mut phases = timing.phases.borrow_mut();
+        phases.retain(|(name, _)| *name != phase);
+        phases.push((phase, took));
+    }
+}
+
+// The Server-Timing value, in milliseconds, with the total at the end
+pub fn header(req: &HttpRequest) -> Option<HeaderValue> {
+    let extensions = req.extensions();
+    let timing = extensions.get::<Timing>()?;
+    let mut phases: Vec<String> = timing
+        .phases
+        .borrow()
+        .iter()
+        .map(|(name, took)| match took {
+            Some(took) => format!("{};dur={:.2}", name, took.as_secs_f64() * 1000.0),
+            None => name.to_string(),
+        })
+        .collect();
+    phases.push(format!(
+        "total;dur={:.2}",
+        timing.started.elapsed().as_secs_f64() * 1000.0
+    ));
+    HeaderValue::from_str(&phases.join(", ")).ok()
+}
@@ -0,0 +1,410 @@
+use crate::config::TlsConfig;
+use crate::metrics;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+    ServerSessionMemoryCache,
+};
+use rustls::sign::CertifiedKey;
+use rustls::version::{TLS12, TLS13};
+use rustls::{ServerConfig, SupportedProtocolVersion};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+// Shared by the TCP and QUIC listeners, so the directory is watched once
+static CERTS: OnceLock<Arc<Certs>> = OnceLock::new();
+
+// Name the fallback certificate goes by in metrics and alerts
+pub const FALLBACK: &str = "fallback";
+
+// The certificates the listener serves. With a certs_dir they're picked by
+// the SNI name the client asked for, so white-label partners can bring their
+// own domains; names with no pair of their own get the *.parent wildcard if
+// there is one, then the cert_path certificate.
+#[derive(Debug)]
+struct Certs {
+    provider: Arc<CryptoProvider>,
+    fallback: RwLock<Option<Arc<CertifiedKey>>>,
+    by_name: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let found = hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            let by_name = self.by_name.read().unwrap_or_else(|e| e.into_inner());
+            let wildcard = name
+                .split_once('.')
+                .map(|(_, parent)| format!("*.{}", parent));
+            by_name
+                .get_key_value(&name)
+                .or_else(|| wildcard.and_then(|w| by_name.get_key_value(&w)))
+                .map(|(name, key)| (name.clone(), key.clone()))
+        });
+        let fallback = || {
+            self.fallback
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+        };
+        let (label, key) = match found {
+            Some((name, key)) => (name, Some(key)),
+            None => match fallback() {
+                Some(key) => (FALLBACK.to_string(), Some(key)),
+                None => ("none".to_string(), None),
+            },
+        };
+        metrics::inc(
+            "tls_certificate_selections_total",
+            &[("certificate", &label)],
+        );
+        key
+    }
+}
+
+// Every certificate being served, by name
+pub fn served() -> Vec<(String, Arc<CertifiedKey>)> {
+    let Some(certs) = CERTS.get() else {
+        return Vec::new();
+    };
+    let mut served: Vec<_> = certs
+        .by_name
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(name, key)| (name.clone(), key.clone()))
+        .collect();
+    if let Some(key) = certs
+        .fallback
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+    {
+        served.push((FALLBACK.to_string(), key.clone()));
+    }
+    served
+}
+
+// Staple an OCSP response to a served certificate (or take its staple
+// away), unless it has been replaced or removed since `current` was looked up
+pub fn staple(name: &str, current: &Arc<CertifiedKey>, ocsp: Option<Vec<u8>>) -> bool {
+    let Some(certs) = CERTS.get() else {
+        return false;
+    };
+    let stapled = Arc::new(CertifiedKey {
+        ocsp,
+        ..(**current).clone()
+    });
+    let replace = |slot: &mut Arc<CertifiedKey>| {
+        let same = Arc::ptr_eq(slot, current);
+        if same {
+            *slot = stapled.clone();
+        }
+        same
+    };
+    if name == FALLBACK {
+        let mut fallback = certs.fallback.write().unwrap_or_else(|e| e.into_inner());
+        fallback.as_mut().is_some_and(replace)
+    } else {
+        let mut by_name = certs.by_name.write().unwrap_or_else(|e| e.into_inner());
+        by_name.get_mut(name).is_some_and(replace)
+    }
+}
+
+fn load_key(
+    provider: &CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<CertifiedKey, Error> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::other(format!("TLS certificate {}: {}", cert_path.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| Error::other(format!("TLS key {}: {}", key_path.display(), e)))?;
+    CertifiedKey::from_der(certs, key, provider)
+        .map_err(|e| Error::other(format!("TLS certificate {}: {}", cert_path.display(), e)))
+}
+
+// The .crt and .key files of the directory with their modification times,
+// to notice pairs being added, renewed or removed
+fn listing(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "crt" || ext == "key")
+        })
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+impl Certs {
+    // Load every pair in the directory. One that won't load keeps what was
+    // served for its name before, so a half-copied renewal doesn't take a
+    // partner's domain down; one that hasn't changed keeps its staple.
+    fn load(&self, dir: &Path) -> Result<(), Error> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| Error::other(format!("TLS certs_dir {}: {}", dir.display(), e)))?;
+        let previous = self
+            .by_name
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let mut by_name = HashMap::new();
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_none_or(|ext| ext != "crt") {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let stem = stem.to_ascii_lowercase();
+            let name = match stem.strip_prefix("_.") {
+                Some(parent) => format!("*.{}", parent),
+                None => stem,
+            };
+            match load_key(&self.provider, &path, &path.with_extension("key")) {
+                Ok(key) => {
+                    let key = match previous.get(&name) {
+                        Some(old) if old.cert == key.cert => old.clone(),
+                        _ => Arc::new(key),
+                    };
+                    by_name.insert(name, key);
+                }
+                Err(e) => {
+                    eprintln!("Skipping TLS certificate for {}: {}", name, e);
+                    if let Some(key) = previous.get(&name) {
+                        by_name.insert(name, key.clone());
+                    }
+                }
+            }
+        }
+        metrics::set("tls_sni_certificates", &[], by_name.len() as f64);
+        println!(
+            "Loaded {} TLS certificates from {}",
+            by_name.len(),
+            dir.display()
+        );
+        *self.by_name.write().unwrap_or_else(|e| e.into_inner()) = by_name;
+        Ok(())
+    }
+
+    // Rescan the directory for changes every interval
+    fn watch(self: Arc<Self>, dir: PathBuf, interval: Duration) {
+        std::thread::spawn(move || {
+            let mut seen = listing(&dir);
+            loop {
+                std::thread::sleep(interval);
+                let now = listing(&dir);
+                if now == seen {
+                    continue;
+                }
+                seen = now;
+                if let Err(e) = self.load(&dir) {
+                    eprintln!("Failed to reload TLS certificates: {}", e);
+                }
+            }
+        });
+    }
+}
+
+fn certs(config: &TlsConfig, provider: Arc<CryptoProvider>) -> Result<Arc<Certs>, Error> {
+    if let Some(certs) = CERTS.get() {
+        return Ok(certs.clone());
+    }
+    if config.cert_path.is_empty() && config.certs_dir.is_empty() {
+        return Err(Error::other("[tls] needs a cert_path or a certs_dir"));
+    }
+    let fallback = match config.cert_path.is_empty() {
+        true => None,
+        false => Some(Arc::new(load_key(
+            &provider,
+            Path::new(&config.cert_path),
+            Path::new(&config.key_path),
+        )?)),
+    };
+    let dir = (!config.certs_dir.is_empty()).then(|| PathBuf::from(&config.certs_dir));
+    let certs = Arc::new(Certs {
+        provider,
+        fallback: RwLock::new(fallback),
+        by_name: RwLock::new(HashMap::new()),
+    });
+    if let Some(dir) = dir {
+        certs.load(&dir)?;
+        if config.reload_secs > 0 {
+            certs
+                .clone()
+                .watch(dir, Duration::from_secs(config.reload_secs));
+        }
+    }
+    Ok(CERTS.get_or_init(|| certs).clone())
+}
+
+fn version(raw: &str) -> Result<u8, Error> {
+    match raw {
+        "1.2" => Ok(2),
+        "1.3" => Ok(3),
+        _ => Err(Error::other(format!(
+            "Unsupported TLS version {} (use 1.2 or 1.3)",
+            raw
+        ))),
+    }
+}
+
+// Build the public listener's rustls config. Cipher suites are given by their
+// IANA names (TLS13_AES_128_GCM_SHA256, TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+// ...); an empty list keeps rustls' defaults, which are all AEAD with forward
+// secrecy.
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, Error> {
+    let (min, max) = (version(&config.min_version)?, version(&config.max_version)?);
+    let versions: Vec<&'static SupportedProtocolVersion> = [(2, &TLS12), (3, &TLS13)]
+        .into_iter()
+        .filter(|(v, _)| (min..=max).contains(v))
+        .map(|(_, version)| version)
+        .collect();
+    if versions.is_empty() {
+        return Err(Error::other("TLS min_version is above max_version"));
+    }
+
+    let mut provider = ring::default_provider();
+    if !config.cipher_suites.is_empty() {
+        for name in &config.cipher_suites {
+            if !provider
+                .cipher_suites
+                .iter()
+                .any(|s| format!("{:?}", s.suite()) == *name)
+            {
+                return Err(Error::other(format!("Unknown TLS cipher suite {}", name)));
+            }
+        }
+        provider
+            .cipher_suites
+            .retain(|s| config.cipher_suites.contains(&format!("{:?}", s.suite())));
+    }
+
+    let provider = Arc::new(provider);
+    let mut server = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&versions)
+        .map_err(|e| Error::other(format!("TLS config: {}", e)))?
+        .with_no_client_auth()
+        .with_cert_resolver(certs(config, provider)?);
+    server.alpn_protocols = config
+        .alpn_protocols
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
+
+    server.session_storage = match config.session_cache_size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    if config.session_tickets {
+        if !(1..=MAX_TICKET_LIFETIME).contains(&config.ticket_lifetime_secs) {
+            return Err(Error::other(format!(
+                "TLS ticket_lifetime_secs must be between 1 and {}",
+                MAX_TICKET_LIFETIME
+            )));
+        }
+        server.ticketer = Arc::new(Tickets::new(config.ticket_lifetime_secs)?);
+    }
+    if config.early_data {
+        if config.session_tickets || config.session_cache_size == 0 {
+            return Err(Error::other(
+                "TLS early_data needs session_tickets off and a session cache",
+            ));
+        }
+        server.max_early_data_size = config.early_data_max_bytes;
+        // The response to an early request goes out without waiting for
+        // the client's Finished, or 0-RTT would save nothing
+        server.send_half_rtt_data = true;
+    }
+
+    Ok(server)
+}
+
+// rustls's ticketer renews its key every 6 hours
+const MAX_TICKET_LIFETIME: u32 = 6 * 3600;
+
+// Session tickets under a key replaced every `lifetime` seconds. Tickets
+// under the one before are still taken, so a ticket is good for at least
+// `lifetime` and at most twice that.
+#[derive(Debug)]
+struct Tickets {
+    lifetime: u32,
+    keys: RwLock<TicketKeys>,
+}
+
+#[derive(Debug)]
+struct TicketKeys {
+    since: Instant,
+    current: Arc<dyn ProducesTickets>,
+    previous: Option<Arc<dyn ProducesTickets>>,
+}
+
+impl Tickets {
+    fn new(lifetime: u32) -> Result<Self, Error> {
+        Ok(Tickets {
+            lifetime,
+            keys: RwLock::new(TicketKeys {
+                since: Instant::now(),
+                current: Self::key()?,
+                previous: None,
+            }),
+        })
+    }
+
+    fn key() -> Result<Arc<dyn ProducesTickets>, Error> {
+        ring::Ticketer::new().map_err(|e| Error::other(format!("TLS session tickets: {}", e)))
+    }
+
+    // The keys, renewed first if the current one has served its time
+    fn keys(&self) -> RwLockReadGuard<'_, TicketKeys> {
+        let due = |keys: &TicketKeys| keys.since.elapsed().as_secs() >= self.lifetime as u64;
+        if due(&self.keys.read().unwrap()) {
+            let mut keys = self.keys.write().unwrap();
+            if due(&keys) {
+                if let Ok(key) = Self::key() {
+                    let previous = std::mem::replace(&mut keys.current, key);
+                    keys.previous = Some(previous);
+                    keys.since = Instant::now();
+                }
+            }
+        }
+        self.keys.read().unwrap()
+    }
+}
+
+impl ProducesTickets for Tickets {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.keys().current.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys();
+        keys.current
+            .decrypt(cipher)
+            .or_else(|| keys.previous.as_ref()?.decrypt(cipher))
+    }
+}
@@ -0,0 +1,320 @@
+use crate::config::{Config, CorsConfig, RouteConfig};
+use crate::cors;
+use crate::deadline;
+use crate::error::ProxyError;
+use crate::grpc;
+use crate::metrics;
+use crate::middleware::{self, RequestBody};
+use crate::proxy::{self, cut_off, AppState, Destination};
+use actix_http::error::PayloadError;
+use actix_http::{BoxedPayloadStream, HttpMessage, Payload, Request};
+use actix_service::IntoServiceFactory;
+use actix_web::body::MessageBody;
+use actix_web::dev::{AppConfig, Service, ServiceFactory, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{StatusCode, Version};
+use actix_web::{web, HttpRequest, HttpResponse};
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::future::poll_fn;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use tokio::sync::{mpsc, oneshot};
+
+// actix can't send trailers, so only clients on the [grpc] listener (h2c)
+// get a trailers route's. Their requests are handed to the public App, on a
+// thread of its own since actix requests aren't Send, and go through the
+// same pipeline as everyone else's; the upstream's trailers come back
+// through a Sink left in the request's extensions.
+
+// Hop-by-hop headers, and TE, which we set ourselves; the rest of a
+// trailers route's request and response goes through as it is
+const HOP_BY_HOP: [&str; 6] = [
+    "host",
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "upgrade",
+    "te",
+];
+
+// Where the upstream's trailers go once its body has been relayed
+pub struct Sink(oneshot::Sender<hyper::HeaderMap>);
+
+// A request off the [grpc] listener, waiting for the App's response
+struct Call {
+    parts: hyper::http::request::Parts,
+    body: Body,
+    peer: SocketAddr,
+    respond: oneshot::Sender<hyper::Response<Body>>,
+}
+
+static CALLS: OnceLock<mpsc::UnboundedSender<Call>> = OnceLock::new();
+
+// The HTTP listeners drop trailers, which routes that set `trailers` want
+// their clients to hear about
+pub fn warn(config: &Config, routes: &[RouteConfig]) {
+    for route in routes.iter().filter(|route| route.trailers) {
+        match config.grpc {
+            Some(_) => eprintln!(
+                "Route {} relays trailers on the [grpc] listener only; the HTTP listeners drop them",
+                route.name
+            ),
+            None => eprintln!(
+                "Route {} relays trailers on the [grpc] listener only, and there is none",
+                route.name
+            ),
+        }
+    }
+}
+
+// TE: trailers, possibly among other transfer codings
+pub fn wanted(headers: &hyper::HeaderMap) -> bool {
+    (headers.get_all(header::TE).iter())
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let name = coding.split(';').next().unwrap_or_default();
+            name.trim().eq_ignore_ascii_case("trailers")
+        })
+}
+
+// Start the App that serves trailers routes for the [grpc] listener
+pub fn spawn(state: web::Data<AppState>) {
+    let (calls, mut queue) = mpsc::unbounded_channel::<Call>();
+    if CALLS.set(calls).is_err() {
+        return;
+    }
+    std::thread::spawn(move || {
+        actix_rt::System::new().block_on(async move {
+            let service = proxy::app(state)
+                .into_factory()
+                .new_service(AppConfig::default())
+                .await;
+            let Ok(service) = service.map(Rc::new) else {
+                eprintln!("Failed to start the trailers listener: the app didn't start");
+                return;
+            };
+            while let Some(call) = queue.recv().await {
+                let service = service.clone();
+                actix_rt::spawn(async move {
+                    let response = serve(&service, call.parts, call.body, call.peer).await;
+                    let _ = call.respond.send(response);
+                });
+            }
+        })
+    });
+}
+
+fn error(status: StatusCode, message: &'static str) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(message));
+    *response.status_mut() = status;
+    response
+}
+
+// Hand a request for a trailers route to the App and wait for its response
+pub async fn relay(
+    peer: SocketAddr,
+    parts: hyper::http::request::Parts,
+    body: Body,
+) -> hyper::Response<Body> {
+    let (respond, response) = oneshot::channel();
+    let call = Call {
+        parts,
+        body,
+        peer,
+        respond,
+    };
+    if CALLS.get().is_none_or(|calls| calls.send(call).is_err()) {
+        return error(StatusCode::SERVICE_UNAVAILABLE, "Service unavailable");
+    }
+    response
+        .await
+        .unwrap_or_else(|_| error(StatusCode::BAD_GATEWAY, "Bad gateway"))
+}
+
+// One request through the App, with its body streaming in as the handler
+// reads it, and the response and then the trailers streamed back
+async fn serve<S, B>(
+    service: &S,
+    parts: hyper::http::request::Parts,
+    body: Body,
+    peer: SocketAddr,
+) -> hyper::Response<Body>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + 'static,
+{
+    let body = futures_util::stream::unfold(body, |mut body| async move {
+        match body.data().await? {
+            Ok(chunk) => Some((Ok(chunk), body)),
+            Err(e) => Some((Err(PayloadError::Io(Error::other(e))), body)),
+        }
+    });
+    let body: BoxedPayloadStream = Box::pin(body);
+    let mut req = Request::with_payload(Payload::from(body));
+    let head = req.head_mut();
+    head.method = parts.method;
+    head.uri = match parts.uri.path_and_query() {
+        Some(path) => path.as_str().parse().unwrap_or_default(),
+        None => "/".parse().unwrap_or_default(),
+    };
+    head.version = Version::HTTP_2;
+    head.peer_addr = Some(peer);
+    if let Some(authority) = parts.uri.authority() {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            head.headers.insert(header::HOST, host);
+        }
+    }
+    for (name, value) in &parts.headers {
+        head.headers.append(name.clone(), value.clone());
+    }
+    // The upstream leg is h2c either way, but only clients that asked get
+    // the trailers
+    let wanted = wanted(&parts.headers);
+    let (sink, trailers) = oneshot::channel();
+    req.extensions_mut().insert(Sink(sink));
+
+    let response = match service.call(req).await {
+        Ok(response) => response.into_parts().1.map_into_boxed_body(),
+        Err(e) => e.error_response(),
+    };
+    let (head, body) = response.into_parts();
+    let mut response = hyper::Response::new(Body::empty());
+    *response.status_mut() = head.status();
+    for (name, value) in head.headers() {
+        if !HOP_BY_HOP.contains(&name.as_str()) {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+
+    let (mut sender, relayed) = Body::channel();
+    actix_rt::spawn(async move {
+        let mut body = Box::pin(body);
+        while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+            let Ok(chunk) = chunk else {
+                return sender.abort();
+            };
+            // The client is gone
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let (true, Ok(trailers)) = (wanted, trailers.await) {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    *response.body_mut() = relayed;
+    response
+}
+
+// The request's Sink, if it came in on the [grpc] listener for a route that
+// sends trailers
+pub fn sink(req: &HttpRequest, route: &RouteConfig) -> Option<Sink> {
+    match route.trailers {
+        true => req.extensions_mut().remove::<Sink>(),
+        false => None,
+    }
+}
+
+// Send a trailers route's request on over h2c, the only way hyper reads
+// trailers, and stream the response back. The trailers go to the sink once
+// the body has gone through; past the route's limit the body is cut off and
+// there are none.
+pub async fn forward(
+    state: &AppState,
+    req: &HttpRequest,
+    route: &RouteConfig,
+    dest: Destination<'_>,
+    cors: &CorsConfig,
+    body: RequestBody,
+    sink: Sink,
+) -> Result<HttpResponse, ProxyError> {
+    metrics::inc("trailer_requests_total", &[("route", &route.name)]);
+    let mut request = hyper::Request::builder()
+        .method(req.method())
+        .header(header::TE, "trailers");
+    for (name, value) in dest.headers {
+        if !HOP_BY_HOP.contains(&name.as_str()) {
+            request = request.header(name, value);
+        }
+    }
+    let (body, aborted) = match body {
+        RequestBody::Unread(payload) => {
+            let verifier = middleware::request_verifier(state, req);
+            let (body, reason) = middleware::stream_body(&state.config.server, payload, verifier);
+            (body, Some(reason))
+        }
+        RequestBody::Read(body) => (Body::from(body), None),
+    };
+
+    let send = grpc::send(state, &route.name, dest.upstream, request, dest.path, body);
+    let result = match deadline::remaining(req) {
+        Some(left) => match tokio::time::timeout(left, send).await {
+            Ok(result) => result,
+            Err(_) => {
+                deadline::exceeded(route, "upstream");
+                return Err(ProxyError::Deadline);
+            }
+        },
+        None => send.await,
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            // The client broke a rule sending the body, which is the cause
+            let reason = aborted.and_then(|reason| reason.lock().unwrap().take());
+            return Err(reason.unwrap_or_else(|| grpc::proxy_error(e)));
+        }
+    };
+
+    let (parts, body) = response.into_parts();
+    if parts.status.is_server_error() && state.errors.maps_upstream(parts.status) {
+        return Err(ProxyError::Upstream(parts.status));
+    }
+    let mut response = HttpResponse::build(parts.status);
+    cors::apply(cors, &mut response);
+    for (name, value) in &parts.headers {
+        if !HOP_BY_HOP.contains(&name.as_str()) && !cors::is_cors(name.as_str()) {
+            response.append_header((name.clone(), value.clone()));
+        }
+    }
+    let theirs = parts.headers.iter();
+    cors::copy_upstream(
+        cors,
+        &mut response,
+        theirs.map(|(k, v)| (k.as_str(), v.as_bytes())),
+    );
+
+    let limit = route
+        .response_limit(&state.config.server)
+        .unwrap_or(usize::MAX);
+    let name = route.name.clone();
+    let stream = futures_util::stream::unfold(Some((body, sink, 0)), move |body| {
+        let name = name.clone();
+        async move {
+            let (mut body, sink, sent) = body?;
+            match body.data().await {
+                Some(Ok(chunk)) if sent + chunk.len() > limit => {
+                    cut_off(&name, limit);
+                    let e = format!("upstream response exceeds {} bytes", limit);
+                    Some((Err(Error::other(e)), None))
+                }
+                Some(Ok(chunk)) => {
+                    let sent = sent + chunk.len();
+                    Some((Ok(chunk), Some((body, sink, sent))))
+                }
+                Some(Err(e)) => Some((Err(Error::other(e)), None)),
+                None => {
+                    if let Ok(Some(trailers)) = body.trailers().await {
+                        let _ = sink.0.send(trailers);
+                    }
+                    None
+                }
+            }
+        }
+    });
+    Ok(response.streaming(stream))
+}
@@ -0,0 +1,121 @@
+use crate::config::{TuningConfig, TuningPreset};
+use std::sync::OnceLock;
+
+// Runtime settings that only make sense together, picked as a preset and
+// overridable one by one in [tuning]. Balanced keeps the defaults of actix
+// and reqwest.
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    pub preset: TuningPreset,
+    // HTTP worker threads, and threads of the runtime behind them
+    pub workers: usize,
+    // Pending connections the listeners queue, and open ones per worker
+    pub backlog: u32,
+    pub max_connections: usize,
+    // Idle upstream connections kept per host (0 = none)
+    pub pool_max_idle_per_host: usize,
+    pub gzip_level: u32,
+    pub min_compress_bytes: usize,
+    // Bodies declaring more are streamed on auto-mode routes without a
+    // buffering max_bytes of their own (0 = no limit)
+    pub stream_threshold_bytes: u64,
+}
+
+static TUNING: OnceLock<Tuning> = OnceLock::new();
+
+fn cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn preset(preset: TuningPreset) -> Tuning {
+    let tuning = Tuning {
+        preset,
+        workers: cpus(),
+        backlog: 1024,
+        max_connections: 25_000,
+        pool_max_idle_per_host: usize::MAX,
+        gzip_level: 6,
+        min_compress_bytes: 1024,
+        stream_threshold_bytes: 0,
+    };
+    match preset {
+        TuningPreset::Balanced => tuning,
+        // Warm connections kept to every upstream, cheap compression only
+        // where it pays, and large bodies passed on as they arrive
+        TuningPreset::Latency => Tuning {
+            gzip_level: 1,
+            min_compress_bytes: 4096,
+            stream_threshold_bytes: 256 * 1024,
+            ..tuning
+        },
+        // Deep accept queues, many connections, thorough compression
+        TuningPreset::Throughput => Tuning {
+            backlog: 4096,
+            max_connections: 50_000,
+            pool_max_idle_per_host: 512,
+            stream_threshold_bytes: 8 * 1024 * 1024,
+            ..tuning
+        },
+        // Few threads and connections, and little held in memory at once
+        TuningPreset::LowMemory => Tuning {
+            workers: cpus().min(2),
+            backlog: 256,
+            max_connections: 1024,
+            pool_max_idle_per_host: 4,
+            gzip_level: 1,
+            stream_threshold_bytes: 64 * 1024,
+            ..tuning
+        },
+    }
+}
+
+// The preset with the overrides applied, from now on what get() returns
+pub fn init(config: &TuningConfig) -> Tuning {
+    let base = preset(config.preset);
+    let tuning = Tuning {
+        preset: config.preset,
+        workers: config.workers.unwrap_or(base.workers).max(1),
+        backlog: config.backlog.unwrap_or(base.backlog),
+        max_connections: config.max_connections.unwrap_or(base.max_connections),
+        pool_max_idle_per_host: (config.pool_max_idle_per_host)
+            .unwrap_or(base.pool_max_idle_per_host),
+        gzip_level: config.gzip_level.unwrap_or(base.gzip_level).min(9),
+        min_compress_bytes: config.min_compress_bytes.unwrap_or(base.min_compress_bytes),
+        stream_threshold_bytes: (config.stream_threshold_bytes)
+            .unwrap_or(base.stream_threshold_bytes),
+    };
+    let _ = TUNING.set(tuning.clone());
+    tuning
+}
+
+pub fn get() -> &'static Tuning {
+    TUNING.get_or_init(|| preset(TuningPreset::Balanced))
+}
+
+pub fn print(tuning: &Tuning) {
+    let preset = match tuning.preset {
+        TuningPreset::Balanced => "balanced",
+        TuningPreset::Latency => "latency",
+        TuningPreset::Throughput => "throughput",
+        TuningPreset::LowMemory => "low_memory",
+    };
+    let idle = match tuning.pool_max_idle_per_host {
+        usize::MAX => "unlimited".to_string(),
+        n => n.to_string(),
+    };
+    let threshold = match tuning.stream_threshold_bytes {
+        0 => "off".to_string(),
+        n => format!("{} bytes", n),
+    };
+    println!(
+        "Tuning ({}): workers {}, backlog {}, max connections per worker {}, idle upstream connections per host {}, gzip level {}, compress from {} bytes, stream bodies over {}",
+        preset,
+        tuning.workers,
+        tuning.backlog,
+        tuning.max_connections,
+        idle,
+        tuning.gzip_level,
+        tuning.min_compress_bytes,
+        threshold
+    );
+}
@@ -0,0 +1,289 @@
+use crate::authn;
+use crate::config::ForwardProxyConfig;
+use crate::egress_policy::Policy;
+use crate::metrics;
+use actix_web::http::{header, Version};
+use actix_web::web::{self, Bytes};
+use actix_web::{HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::{stream, StreamExt};
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+// CONNECT tunnels ([forward_proxy]), for clients on the inside that need a
+// way out to a few partner hosts. Off unless configured; every tunnel needs
+// a user, goes only to an allowed host and port, and passes the egress
+// policy like any upstream. The bytes are counted, not looked at.
+pub struct ForwardProxy {
+    config: ForwardProxyConfig,
+    // Passwords by user, with env: ones read
+    users: HashMap<String, String>,
+}
+
+// One open tunnel's traffic
+struct Tunnel {
+    host: String,
+    port: u16,
+    // The allowlist entry it went through, as the metrics label
+    allowed: String,
+    user: String,
+    client_ip: String,
+    started: Instant,
+    idle: Duration,
+    // Milliseconds after started that bytes last passed either way
+    active_ms: AtomicU64,
+    upstream: AtomicU64,
+    downstream: AtomicU64,
+}
+
+fn count(result: &str) {
+    metrics::inc("connect_requests_total", &[("result", result)]);
+}
+
+impl ForwardProxy {
+    pub fn new(config: &ForwardProxyConfig) -> Result<Self, Error> {
+        let mut users = HashMap::new();
+        for (user, password) in &config.users {
+            let password = match password.strip_prefix("env:") {
+                Some(var) => env::var(var).map_err(|_| {
+                    Error::other(format!(
+                        "Forward proxy user {} needs the {} environment variable",
+                        user, var
+                    ))
+                })?,
+                None => password.clone(),
+            };
+            users.insert(user.clone(), password);
+        }
+        println!(
+            "CONNECT tunnels allowed to {} for {} users",
+            config.allowed_hosts.join(", "),
+            users.len()
+        );
+        Ok(ForwardProxy {
+            config: config.clone(),
+            users,
+        })
+    }
+
+    // The user named in Proxy-Authorization, when the password is theirs
+    fn user(&self, req: &HttpRequest) -> Option<String> {
+        let value = req
+            .headers()
+            .get(header::PROXY_AUTHORIZATION)?
+            .to_str()
+            .ok()?;
+        let (scheme, rest) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(STANDARD.decode(rest.trim()).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let expected = self.users.get(user)?;
+        authn::constant_eq(expected.as_bytes(), password.as_bytes()).then(|| user.to_string())
+    }
+
+    // The allowlist entry covering the host: itself, or "*." and a parent
+    fn allowed(&self, host: &str) -> Option<&str> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        (self.config.allowed_hosts)
+            .iter()
+            .find(|allowed| match allowed.strip_prefix("*.") {
+                Some(parent) => host.ends_with(&format!(".{}", parent.to_ascii_lowercase())),
+                None => allowed.eq_ignore_ascii_case(&host),
+            })
+            .map(String::as_str)
+    }
+
+    // Answer a CONNECT: open the tunnel and carry bytes both ways until
+    // either side closes or it goes idle
+    pub async fn connect(
+        &self,
+        req: &HttpRequest,
+        payload: web::Payload,
+        egress: Option<&Policy>,
+        client_ip: &str,
+    ) -> HttpResponse {
+        // HTTP/2 tunnels (RFC 8441 and friends) aren't supported
+        if req.version() > Version::HTTP_11 {
+            count("failed");
+            return HttpResponse::MethodNotAllowed().body("CONNECT needs HTTP/1.1");
+        }
+        let Some(user) = self.user(req) else {
+            println!("Refusing CONNECT from {}: no valid credentials", client_ip);
+            count("unauthorized");
+            return HttpResponse::build(actix_web::http::StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                .insert_header((header::PROXY_AUTHENTICATE, "Basic realm=\"gateway\""))
+                .body("Proxy authentication required");
+        };
+        let (host, port) = match (req.uri().host(), req.uri().port_u16()) {
+            (Some(host), Some(port)) => (host.trim_matches(['[', ']']).to_string(), port),
+            _ => {
+                count("failed");
+                return HttpResponse::BadRequest().body("CONNECT needs host:port");
+            }
+        };
+        let allowed = match self.allowed(&host) {
+            Some(allowed) if self.config.allowed_ports.contains(&port) => allowed.to_string(),
+            _ => {
+                println!(
+                    "Refusing CONNECT from {} ({}) to {}:{}: not allowed",
+                    client_ip, user, host, port
+                );
+                count("not_allowed");
+                return HttpResponse::Forbidden().body("Destination not allowed");
+            }
+        };
+
+        let io = match self.open(&host, port, egress).await {
+            Ok(io) => io,
+            Err(e) => {
+                println!(
+                    "CONNECT from {} ({}) to {}:{} failed: {}",
+                    client_ip, user, host, port, e
+                );
+                count("failed");
+                return HttpResponse::BadGateway().body("Couldn't reach the destination");
+            }
+        };
+        count("tunneled");
+        println!(
+            "Tunnel from {} ({}) to {}:{} open",
+            client_ip, user, host, port
+        );
+        let tunnel = Arc::new(Tunnel {
+            host,
+            port,
+            allowed,
+            user,
+            client_ip: client_ip.to_string(),
+            started: Instant::now(),
+            idle: Duration::from_secs(self.config.idle_timeout_secs),
+            active_ms: AtomicU64::new(0),
+            upstream: AtomicU64::new(0),
+            downstream: AtomicU64::new(0),
+        });
+        let (read, mut write) = io.into_split();
+
+        // Client to upstream; the payload isn't Send, so it stays on this
+        // worker's thread
+        let up = tunnel.clone();
+        actix_web::rt::spawn(async move {
+            let mut payload = payload;
+            while let Some(Ok(chunk)) = up.next_or_idle(payload.next()).await.flatten() {
+                if write.write_all(&chunk).await.is_err() {
+                    break;
+                }
+                up.moved(&up.upstream, chunk.len());
+            }
+            let _ = write.shutdown().await;
+        });
+
+        // Upstream to client, as the response body
+        let body = stream::unfold((read, tunnel), |(mut read, tunnel)| async move {
+            let mut buf = vec![0u8; 16 * 1024];
+            match tunnel.next_or_idle(read.read(&mut buf)).await {
+                Some(Ok(n)) if n > 0 => {
+                    buf.truncate(n);
+                    tunnel.moved(&tunnel.downstream, n);
+                    Some((Ok::<_, Error>(Bytes::from(buf)), (read, tunnel)))
+                }
+                _ => None,
+            }
+        });
+        // The tunnel runs to the end of the connection: no framing, and no
+        // content type for what isn't a body
+        let mut response = HttpResponse::Ok().streaming(Box::pin(body));
+        response.head_mut().no_chunking(true);
+        response.headers_mut().remove(header::CONTENT_TYPE);
+        response
+    }
+
+    // A connection to the destination, by the addresses the egress policy
+    // leaves, so a name can't resolve somewhere else between check and use
+    async fn open(
+        &self,
+        host: &str,
+        port: u16,
+        egress: Option<&Policy>,
+    ) -> Result<TcpStream, String> {
+        if let Some(policy) = egress {
+            policy.check(&format!("https://{}:{}", host, port))?;
+        }
+        let mut addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("{}: {}", host, e))?
+            .collect();
+        if let Some(policy) = egress {
+            policy.filter(host, &mut addrs).map_err(|e| e.to_string())?;
+        }
+        let wait = Duration::from_millis(self.config.connect_timeout_ms);
+        match timeout(wait, TcpStream::connect(&addrs[..])).await {
+            Ok(Ok(io)) => Ok(io),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        }
+    }
+}
+
+impl Tunnel {
+    fn moved(&self, counter: &AtomicU64, n: usize) {
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        let now = self.started.elapsed().as_millis() as u64;
+        self.active_ms.store(now, Ordering::Relaxed);
+    }
+
+    // Wait on one side, giving up once neither side has moved for the idle
+    // timeout; the other side passing bytes keeps this one waiting
+    async fn next_or_idle<F: Future>(&self, next: F) -> Option<F::Output> {
+        tokio::pin!(next);
+        loop {
+            let active = Duration::from_millis(self.active_ms.load(Ordering::Relaxed));
+            let quiet = self.started.elapsed().saturating_sub(active);
+            let wait = self
+                .idle
+                .checked_sub(quiet)
+                .filter(|wait| !wait.is_zero())?;
+            if let Ok(output) = timeout(wait, &mut next).await {
+                return Some(output);
+            }
+        }
+    }
+}
+
+// Both halves are done once the last reference goes
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let (up, down) = (
+            self.upstream.load(Ordering::Relaxed),
+            self.downstream.load(Ordering::Relaxed),
+        );
+        println!(
+            "Tunnel from {} ({}) to {}:{} closed after {:.1}s: {} bytes up, {} down",
+            self.client_ip,
+            self.user,
+            self.host,
+            self.port,
+            self.started.elapsed().as_secs_f64(),
+            up,
+            down
+        );
+        for (direction, bytes) in [("upstream", up), ("downstream", down)] {
+            metrics::add(
+                "connect_bytes_total",
+                &[("host", &self.allowed), ("direction", direction)],
+                bytes as f64,
+            );
+        }
+    }
+}
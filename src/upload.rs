@@ -0,0 +1,299 @@
+use crate::access_log;
+use crate::blocklist;
+use crate::config::{PresignConfig, RouteConfig, UploadConfig};
+use crate::keys;
+use crate::metrics;
+use crate::middleware::Outcome;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{HttpRequest, HttpResponse};
+use reqwest::Url;
+use ring::{digest, hmac};
+use std::env;
+use std::io::Error;
+use std::time::SystemTime;
+
+// Uploads that go straight through to object storage. Only PUTs and POSTs
+// whose Content-Length and Content-Type the route allows get as far as the
+// upstream, and with presign they go there signed the way a pre-signed URL
+// is (AWS Signature V4 in the query string), with credentials that only we
+// hold. Whatever the client sent for credentials of its own is dropped, and
+// so is its query string.
+
+pub struct Uploader {
+    config: UploadConfig,
+    // Resolved from env: when the middleware was loaded
+    secret_access_key: String,
+    session_token: String,
+}
+
+fn secret(name: &str, what: &str, value: &str) -> Result<String, Error> {
+    match value.strip_prefix("env:") {
+        Some(var) => env::var(var).map_err(|_| {
+            Error::other(format!(
+                "Upload middleware {} {} needs the {} environment variable",
+                name, what, var
+            ))
+        }),
+        None => Ok(value.to_string()),
+    }
+}
+
+fn reject(req: &HttpRequest, route: &RouteConfig, reason: &str) {
+    println!(
+        "Rejected upload {} {} on route {}: {}",
+        req.method(),
+        req.path(),
+        route.name,
+        reason
+    );
+    metrics::inc(
+        "uploads_rejected_total",
+        &[("route", &route.name), ("reason", reason)],
+    );
+}
+
+// Percent-encoding as Signature V4 wants it: everything but the unreserved
+// characters, and slashes too unless they separate path segments
+fn aws_encode(value: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    keys::hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+impl Uploader {
+    pub fn load(name: &str, config: &UploadConfig) -> Result<Self, Error> {
+        let (secret_access_key, session_token) = match &config.presign {
+            Some(presign) => {
+                if presign.access_key_id.is_empty() {
+                    return Err(Error::other(format!(
+                        "Upload middleware {} needs presign.access_key_id",
+                        name
+                    )));
+                }
+                let key = secret(
+                    name,
+                    "presign.secret_access_key",
+                    &presign.secret_access_key,
+                )?;
+                if key.is_empty() {
+                    return Err(Error::other(format!(
+                        "Upload middleware {} needs presign.secret_access_key",
+                        name
+                    )));
+                }
+                let token = secret(name, "presign.session_token", &presign.session_token)?;
+                (key, token)
+            }
+            None => (String::new(), String::new()),
+        };
+        Ok(Uploader {
+            config: config.clone(),
+            secret_access_key,
+            session_token,
+        })
+    }
+
+    pub fn check(
+        &self,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        outcome: &mut Outcome,
+    ) -> Result<(), Box<HttpResponse>> {
+        if req.method() != Method::PUT && req.method() != Method::POST {
+            reject(req, route, "method");
+            return Err(Box::new(
+                HttpResponse::MethodNotAllowed()
+                    .insert_header((header::ALLOW, "PUT, POST"))
+                    .body("Only uploads are accepted here"),
+            ));
+        }
+        // Chunked uploads can't be sized up front, and storage services
+        // want the length anyway
+        let length = (req.headers().get(header::CONTENT_LENGTH))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<usize>().ok());
+        let Some(length) = length else {
+            reject(req, route, "no_length");
+            return Err(Box::new(
+                HttpResponse::LengthRequired().body("Content-Length required"),
+            ));
+        };
+        if self.config.max_bytes > 0 && length > self.config.max_bytes {
+            reject(req, route, "too_large");
+            return Err(Box::new(HttpResponse::PayloadTooLarge().body(format!(
+                "Uploads are limited to {} bytes",
+                self.config.max_bytes
+            ))));
+        }
+        let media_type = (req.headers().get(header::CONTENT_TYPE))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty());
+        let Some(media_type) = media_type else {
+            reject(req, route, "no_type");
+            return Err(Box::new(
+                HttpResponse::UnsupportedMediaType().body("Content-Type required"),
+            ));
+        };
+        if !self.allows(&media_type) {
+            reject(req, route, "type");
+            return Err(Box::new(
+                HttpResponse::UnsupportedMediaType()
+                    .body(format!("Uploads of type {} aren't accepted", media_type)),
+            ));
+        }
+
+        outcome.headers.remove(header::AUTHORIZATION);
+        let amz: Vec<_> = (outcome.headers.keys())
+            .filter(|name| name.as_str().starts_with("x-amz-"))
+            .cloned()
+            .collect();
+        for name in amz {
+            outcome.headers.remove(name);
+        }
+        if let Some(presign) = &self.config.presign {
+            self.presign(presign, req, route, outcome)?;
+        }
+        metrics::inc("uploads_accepted_total", &[("route", &route.name)]);
+        Ok(())
+    }
+
+    fn allows(&self, media_type: &str) -> bool {
+        if self.config.allowed_types.is_empty() {
+            return true;
+        }
+        self.config.allowed_types.iter().any(|allowed| {
+            let allowed = allowed.trim().to_ascii_lowercase();
+            match allowed.strip_suffix("/*") {
+                Some(kind) => media_type
+                    .split_once('/')
+                    .is_some_and(|(found, _)| found == kind || kind == "*"),
+                None => allowed == media_type,
+            }
+        })
+    }
+
+    // Point the request at the storage host and add the signed query
+    fn presign(
+        &self,
+        presign: &PresignConfig,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        outcome: &mut Outcome,
+    ) -> Result<(), Box<HttpResponse>> {
+        // The upstream URL's own path comes before ours in what it receives
+        let base = (outcome.upstream.contains("://"))
+            .then(|| Url::parse(&outcome.upstream).ok())
+            .flatten();
+        let host = match (&presign.host, &base) {
+            (host, _) if !host.is_empty() => host.clone(),
+            (_, Some(base)) => match (base.host_str(), base.port()) {
+                (Some(host), Some(port)) => format!("{}:{}", host, port),
+                (Some(host), None) => host.to_string(),
+                (None, _) => String::new(),
+            },
+            _ => String::new(),
+        };
+        let host_value = (!host.is_empty())
+            .then(|| HeaderValue::from_str(&host).ok())
+            .flatten();
+        let Some(host_value) = host_value else {
+            eprintln!(
+                "Route {}: no storage host to sign the upload for {}",
+                route.name, outcome.upstream
+            );
+            metrics::inc(
+                "uploads_rejected_total",
+                &[("route", &route.name), ("reason", "presign")],
+            );
+            return Err(Box::new(HttpResponse::BadGateway().body("Bad gateway")));
+        };
+        let prefix = base
+            .as_ref()
+            .map(|base| base.path().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+
+        // The path goes out exactly as it was signed
+        let path = outcome.path.split('?').next().unwrap_or_default();
+        let path = aws_encode(&blocklist::percent_decode(path), true);
+        let canonical_uri = format!(
+            "{}{}",
+            aws_encode(&blocklist::percent_decode(&prefix), true),
+            path
+        );
+
+        // 20240501T123456Z
+        let stamp: String = access_log::iso8601(SystemTime::now())
+            .chars()
+            .filter(|c| *c != '-' && *c != ':')
+            .take(15)
+            .chain(std::iter::once('Z'))
+            .collect();
+        let date = &stamp[..8];
+        let scope = format!(
+            "{}/{}/{}/aws4_request",
+            date, presign.region, presign.service
+        );
+        let mut query = vec![
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            (
+                "X-Amz-Credential",
+                format!("{}/{}", presign.access_key_id, scope),
+            ),
+            ("X-Amz-Date", stamp.clone()),
+            ("X-Amz-Expires", presign.expires_secs.to_string()),
+            ("X-Amz-SignedHeaders", "host".to_string()),
+        ];
+        if !self.session_token.is_empty() {
+            query.push(("X-Amz-Security-Token", self.session_token.clone()));
+        }
+        query.sort();
+        let query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, aws_encode(value, false)))
+            .collect();
+        let query = query.join("&");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            req.method(),
+            canonical_uri,
+            query,
+            host
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            stamp,
+            scope,
+            sha256_hex(canonical_request.as_bytes())
+        );
+        let sign = |key: &[u8], data: &str| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+            hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+        };
+        let key = format!("AWS4{}", self.secret_access_key);
+        let key = sign(key.as_bytes(), date);
+        let key = sign(&key, &presign.region);
+        let key = sign(&key, &presign.service);
+        let key = sign(&key, "aws4_request");
+        let signature = keys::hex(&sign(&key, &string_to_sign));
+
+        outcome.headers.insert(header::HOST, host_value);
+        outcome.path = format!("{}?{}&X-Amz-Signature={}", path, query, signature);
+        Ok(())
+    }
+}
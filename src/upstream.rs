@@ -0,0 +1,618 @@
+use crate::balancer::{self, Candidate, LoadBalancer};
+use crate::config::{
+    Config, DnsConfig, IpFamily, OutlierConfig, ProxyTransportConfig, TransportConfig,
+    UpstreamConfig,
+};
+use crate::dns;
+use crate::egress_policy::Policy;
+use crate::events::{self, Event};
+use crate::limiter::{Limiter, Permit};
+use crate::metrics;
+use crate::pinning;
+use crate::socks;
+use rand::Rng;
+use reqwest::{Certificate, Client, Proxy};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Default)]
+struct TargetHealth {
+    consecutive_errors: u32,
+    consecutive_slow: u32,
+    ejected_until: Option<Instant>,
+    ejections: u32,
+}
+
+// What the balancer is told about a replica
+#[derive(Default)]
+struct Load {
+    in_flight: AtomicUsize,
+    // Smoothed response latency, 0 until the first response
+    latency_micros: AtomicU64,
+}
+
+// Weight of the newest response in the smoothed latency
+const SMOOTHING: f64 = 0.3;
+
+// A pool's replicas, replaced whole when discovery finds a change
+struct Members {
+    // All replicas, group by group; `groups` indexes into this in priority order
+    targets: Vec<String>,
+    groups: Vec<Range<usize>>,
+    load: Vec<Arc<Load>>,
+    health: Mutex<Vec<TargetHealth>>,
+}
+
+struct Pool {
+    members: RwLock<Arc<Members>>,
+    failover_threshold: f64,
+    balancer: Box<dyn LoadBalancer>,
+    outlier: Option<OutlierConfig>,
+    // Its own client when the pool has TLS, address family, header case,
+    // connection or transport settings
+    client: Option<Client>,
+    idle_timeout: Duration,
+    limiter: Option<Limiter>,
+}
+
+// How long the shared client keeps idle connections
+pub const DEFAULT_IDLE: Duration = Duration::from_secs(90);
+
+// A client trusting the pool's CAs (or nothing at all when told to),
+// connecting over its address family, casing header names and keeping
+// connections as asked; None when the shared one will do
+fn pool_client(
+    name: &str,
+    pool: &UpstreamConfig,
+    dns: &DnsConfig,
+    egress: Option<Arc<Policy>>,
+) -> Result<Option<Client>, Error> {
+    let idle = Duration::from_secs(pool.idle_timeout_secs);
+    if pool.tls.is_none()
+        && pool.ip_family == IpFamily::Any
+        && !pool.title_case_headers
+        && idle == DEFAULT_IDLE
+        && pool.tcp_keepalive_secs == 0
+        && pool.transport == TransportConfig::Direct
+    {
+        return Ok(None);
+    }
+    // The client's pool sweeps out connections past the idle timeout on its
+    // own, and never hands one out once it's expired
+    let mut builder = dns::builder(dns, pool.ip_family, egress).pool_idle_timeout(idle);
+    if pool.tcp_keepalive_secs > 0 {
+        builder = builder.tcp_keepalive(Duration::from_secs(pool.tcp_keepalive_secs));
+    }
+    if pool.title_case_headers {
+        builder = builder.http1_title_case_headers();
+    }
+    let proxy = match &pool.transport {
+        TransportConfig::Direct => None,
+        TransportConfig::HttpConnect(proxy) => {
+            let url = format!("http://{}", proxy.address);
+            let password = password(name, proxy)?;
+            let mut proxy = Proxy::all(&url)
+                .map_err(|e| Error::other(format!("Upstream {} proxy {}: {}", name, url, e)))?;
+            if let Some((username, password)) = password {
+                proxy = proxy.basic_auth(&username, &password);
+            }
+            Some(proxy)
+        }
+        TransportConfig::Socks5(proxy) => {
+            let password = password(name, proxy)?.map(|(_, p)| p).unwrap_or_default();
+            let url = socks::bridge(name, proxy, password)?;
+            // The bridge sends each request to an http target on a
+            // connection of its own, see socks.rs
+            let discovered = pool.discovery.as_ref().map(|d| d.scheme.as_str());
+            if discovered == Some("http") || pool.targets.iter().any(|t| t.starts_with("http://")) {
+                builder = builder.pool_max_idle_per_host(0);
+            }
+            Some(Proxy::all(&url).map_err(|e| Error::other(format!("Upstream {}: {}", name, e)))?)
+        }
+    };
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy);
+    }
+    let tls = match &pool.tls {
+        Some(tls) => tls,
+        None => {
+            return builder
+                .build()
+                .map(Some)
+                .map_err(|e| Error::other(format!("Upstream {} client: {}", name, e)))
+        }
+    };
+    if !tls.pins.is_empty() {
+        return builder
+            .use_preconfigured_tls(pinning::client_config(name, tls)?)
+            .build()
+            .map(Some)
+            .map_err(|e| Error::other(format!("Upstream {} client: {}", name, e)));
+    }
+    builder = builder.tls_built_in_root_certs(!tls.only_ca_file);
+    if let Some(ca_file) = &tls.ca_file {
+        let pem = fs::read(ca_file)
+            .map_err(|e| Error::other(format!("Upstream {} CA file {}: {}", name, ca_file, e)))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| Error::other(format!("Upstream {} CA file {}: {}", name, ca_file, e)))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if !tls.verify_hostname {
+        eprintln!(
+            "WARNING: upstream {} certificates are not checked against the hostname",
+            name
+        );
+        builder = builder.danger_accept_invalid_hostnames(true);
+    }
+    if tls.insecure_skip_verify {
+        eprintln!(
+            "WARNING: TLS verification is OFF for upstream {}; anyone on the path can impersonate it",
+            name
+        );
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| Error::other(format!("Upstream {} client: {}", name, e)))
+}
+
+// The proxy's username and password, None without a username
+fn password(name: &str, proxy: &ProxyTransportConfig) -> Result<Option<(String, String)>, Error> {
+    if proxy.address.is_empty() {
+        return Err(Error::other(format!(
+            "Upstream {} transport needs the proxy's address",
+            name
+        )));
+    }
+    if proxy.username.is_empty() {
+        return Ok(None);
+    }
+    let password = match proxy.password.strip_prefix("env:") {
+        Some(var) => env::var(var).map_err(|_| {
+            Error::other(format!(
+                "Upstream {} proxy password needs the {} environment variable",
+                name, var
+            ))
+        })?,
+        None => proxy.password.clone(),
+    };
+    Ok(Some((proxy.username.clone(), password)))
+}
+
+// Resolves a route's upstream (a URL or the name of a pool) to a concrete replica
+pub struct Upstreams {
+    pools: HashMap<String, Pool>,
+}
+
+impl Upstreams {
+    pub fn new(config: &Config, egress: Option<Arc<Policy>>) -> Result<Self, Error> {
+        let mut pools = HashMap::new();
+        for (name, pool) in &config.upstreams {
+            let mut targets = Vec::new();
+            let mut groups = Vec::new();
+            for group in std::iter::once(&pool.targets).chain(&pool.priority_groups) {
+                let start = targets.len();
+                targets.extend(group.iter().map(|t| t.trim_end_matches('/').to_string()));
+                groups.push(start..targets.len());
+            }
+            let health = targets.iter().map(|_| TargetHealth::default()).collect();
+            let load = targets.iter().map(|_| Arc::default()).collect();
+            let client = pool_client(name, pool, &config.dns, egress.clone())?;
+            let balancer = balancer::build(&pool.balancer).ok_or_else(|| {
+                Error::other(format!(
+                    "Upstream {} has an unknown balancer {} (use one of {})",
+                    name,
+                    pool.balancer,
+                    balancer::names().join(", ")
+                ))
+            })?;
+            pools.insert(
+                name.clone(),
+                Pool {
+                    members: RwLock::new(Arc::new(Members {
+                        targets,
+                        groups,
+                        load,
+                        health: Mutex::new(health),
+                    })),
+                    failover_threshold: pool.failover_threshold,
+                    balancer,
+                    outlier: pool.outlier_detection.clone(),
+                    client,
+                    idle_timeout: Duration::from_secs(pool.idle_timeout_secs),
+                    limiter: pool
+                        .adaptive_concurrency
+                        .clone()
+                        .map(|limits| Limiter::new(name, limits)),
+                },
+            );
+        }
+
+        Ok(Upstreams { pools })
+    }
+
+    // The client requests to this upstream go through
+    pub fn client<'a>(&'a self, upstream: &str, default: &'a Client) -> &'a Client {
+        self.pools
+            .get(upstream)
+            .and_then(|pool| pool.client.as_ref())
+            .unwrap_or(default)
+    }
+
+    // Every replica of every pool and how it's doing, for the dashboard
+    pub fn health(&self) -> Vec<serde_json::Value> {
+        let mut names: Vec<&String> = self.pools.keys().collect();
+        names.sort();
+        let mut out = Vec::new();
+        for name in names {
+            let members = self.pools[name].members();
+            let health = members.health.lock().unwrap();
+            for (i, target) in members.targets.iter().enumerate() {
+                let latency = members.load[i].latency_micros.load(Ordering::Relaxed);
+                out.push(serde_json::json!({
+                    "upstream": name,
+                    "target": target,
+                    "healthy": health[i].ejected_until.is_none(),
+                    "in_flight": members.load[i].in_flight.load(Ordering::Relaxed),
+                    "latency_ms": (latency > 0).then(|| latency as f64 / 1000.0),
+                }));
+            }
+        }
+        out
+    }
+
+    // How long the upstream's idle connections are kept
+    pub fn idle_timeout(&self, upstream: &str) -> Duration {
+        self.pools
+            .get(upstream)
+            .map_or(DEFAULT_IDLE, |pool| pool.idle_timeout)
+    }
+
+    // A place under the upstream's concurrency limit, or None when it's full
+    pub fn acquire(&self, upstream: &str) -> Option<Permit<'_>> {
+        match self
+            .pools
+            .get(upstream)
+            .and_then(|pool| pool.limiter.as_ref())
+        {
+            Some(limiter) => limiter.acquire(),
+            None => Some(Permit::unlimited()),
+        }
+    }
+
+    // The replica for the next attempt: the balancer's choice within the
+    // priority group getting the request, avoiding ejected replicas and the
+    // ones in `exclude` when possible
+    pub fn pick(&self, upstream: &str, exclude: &[String]) -> String {
+        let pool = match self.pools.get(upstream) {
+            Some(pool) => pool,
+            None => return upstream.to_string(),
+        };
+        let members = pool.members();
+        // Discovery hasn't found any yet; the request fails as for an
+        // unknown upstream
+        if members.targets.is_empty() {
+            return upstream.to_string();
+        }
+
+        let ejected = members.readmit(upstream);
+        let chosen = members.choose_group(pool.failover_threshold, &ejected);
+
+        // The chosen group first, then the rest in priority order; the first
+        // with anything to offer
+        let order =
+            std::iter::once(chosen).chain((0..members.groups.len()).filter(|&g| g != chosen));
+        let eligible = |avoid_tried: bool| {
+            order
+                .clone()
+                .map(|g| {
+                    members.groups[g]
+                        .clone()
+                        .filter(|&i| !ejected[i])
+                        .filter(|&i| !avoid_tried || !exclude.contains(&members.targets[i]))
+                        .collect::<Vec<_>>()
+                })
+                .find(|group| !group.is_empty())
+        };
+        let indices = eligible(true)
+            .or_else(|| eligible(false))
+            // Everything ejected: better to try something than nothing
+            .unwrap_or_else(|| (0..members.targets.len()).collect());
+
+        let candidates: Vec<Candidate> = indices
+            .iter()
+            .map(|&i| {
+                let load = &members.load[i];
+                let micros = load.latency_micros.load(Ordering::Relaxed);
+                Candidate {
+                    target: &members.targets[i],
+                    in_flight: load.in_flight.load(Ordering::Relaxed),
+                    latency: (micros > 0).then(|| Duration::from_micros(micros)),
+                }
+            })
+            .collect();
+        let picked = pool.balancer.pick(&candidates, !exclude.is_empty());
+        members.targets[indices[picked.min(indices.len() - 1)]].clone()
+    }
+
+    // Count a request to the replica as in flight until the guard is dropped
+    pub fn begin(&self, upstream: &str, target: &str) -> Outstanding {
+        let load = self.pools.get(upstream).and_then(|pool| {
+            let members = pool.members();
+            let index = members.targets.iter().position(|t| t == target)?;
+            Some(members.load[index].clone())
+        });
+        if let Some(load) = &load {
+            load.in_flight.fetch_add(1, Ordering::Relaxed);
+        }
+        Outstanding { load }
+    }
+
+    // A 429 from the upstream: it shrinks the concurrency limit like a
+    // failure, but says nothing about the replica's health or latency
+    pub fn throttled(&self, upstream: &str, latency: Duration) {
+        metrics::inc("upstream_throttled_total", &[("upstream", upstream)]);
+        let limiter = self
+            .pools
+            .get(upstream)
+            .and_then(|pool| pool.limiter.as_ref());
+        if let Some(limiter) = limiter {
+            limiter.sample(false, latency);
+        }
+    }
+
+    // Feed the outcome of a request into the concurrency limit and outlier
+    // detection
+    // latency is None for requests whose time says nothing about the target
+    pub fn report(&self, upstream: &str, target: &str, success: bool, latency: Option<Duration>) {
+        let pool = match self.pools.get(upstream) {
+            Some(pool) => pool,
+            None => return,
+        };
+        if let (Some(limiter), Some(latency)) = (&pool.limiter, latency) {
+            limiter.sample(success, latency);
+        }
+        let members = pool.members();
+        let index = match members.targets.iter().position(|t| t == target) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Some(latency) = latency {
+            members.load[index].observe(success, latency);
+        }
+        let outlier = match &pool.outlier {
+            Some(outlier) => outlier,
+            None => return,
+        };
+
+        let mut health = members.health.lock().unwrap();
+        let target_health = &mut health[index];
+        if success {
+            target_health.consecutive_errors = 0;
+        } else {
+            target_health.consecutive_errors += 1;
+        }
+        match latency {
+            Some(latency)
+                if outlier.latency_threshold_ms > 0
+                    && latency >= Duration::from_millis(outlier.latency_threshold_ms) =>
+            {
+                target_health.consecutive_slow += 1;
+            }
+            Some(_) => target_health.consecutive_slow = 0,
+            None => {}
+        }
+
+        let reason = if target_health.consecutive_errors >= outlier.consecutive_errors {
+            "errors"
+        } else if outlier.latency_threshold_ms > 0
+            && target_health.consecutive_slow >= outlier.consecutive_slow
+        {
+            "latency"
+        } else {
+            return;
+        };
+        if target_health.ejected_until.is_some() {
+            return;
+        }
+
+        // Never eject more than the allowed share of the pool
+        let ejected = health.iter().filter(|h| h.ejected_until.is_some()).count();
+        let max = (members.targets.len() * outlier.max_ejection_percent as usize / 100).max(1);
+        if ejected + 1 > max {
+            return;
+        }
+
+        let target_health = &mut health[index];
+        target_health.ejections += 1;
+        target_health.consecutive_errors = 0;
+        target_health.consecutive_slow = 0;
+        let cooldown =
+            Duration::from_secs(outlier.base_ejection_secs * target_health.ejections as u64);
+        target_health.ejected_until = Some(Instant::now() + cooldown);
+
+        println!(
+            "Ejecting {} from {} for {}s ({})",
+            target,
+            upstream,
+            cooldown.as_secs(),
+            reason
+        );
+        metrics::inc(
+            "upstream_ejections_total",
+            &[
+                ("upstream", upstream),
+                ("target", target),
+                ("reason", reason),
+            ],
+        );
+        metrics::set(
+            "upstream_ejected",
+            &[("upstream", upstream), ("target", target)],
+            1.0,
+        );
+        events::publish(Event::UpstreamHealth {
+            upstream: upstream.to_string(),
+            target: target.to_string(),
+            healthy: false,
+        });
+    }
+
+    // Put discovered targets in place of the pool's first group. Replicas
+    // that stay keep their load and outlier state. Returns whether
+    // anything changed.
+    pub fn set_targets(&self, upstream: &str, targets: Vec<String>) -> bool {
+        let Some(pool) = self.pools.get(upstream) else {
+            return false;
+        };
+        let mut current = pool.members.write().unwrap();
+        let first = current.groups[0].clone();
+        if current.targets[first.clone()] == targets[..] {
+            return false;
+        }
+
+        let n = targets.len();
+        // The priority groups after the discovered ones keep their shape
+        let groups: Vec<Range<usize>> = std::iter::once(0..n)
+            .chain(
+                current.groups[1..]
+                    .iter()
+                    .map(|g| g.start - first.end + n..g.end - first.end + n),
+            )
+            .collect();
+        let all: Vec<String> = targets
+            .into_iter()
+            .chain(current.targets[first.end..].iter().cloned())
+            .collect();
+        let old_health = current.health.lock().unwrap().clone();
+        let mut load = Vec::with_capacity(all.len());
+        let mut health = Vec::with_capacity(all.len());
+        for target in &all {
+            match current.targets.iter().position(|t| t == target) {
+                Some(i) => {
+                    load.push(current.load[i].clone());
+                    health.push(old_health[i].clone());
+                }
+                None => {
+                    load.push(Arc::default());
+                    health.push(TargetHealth::default());
+                }
+            }
+        }
+        *current = Arc::new(Members {
+            targets: all,
+            groups,
+            load,
+            health: Mutex::new(health),
+        });
+        true
+    }
+}
+
+// A request in flight to a replica, see Upstreams::begin
+pub struct Outstanding {
+    load: Option<Arc<Load>>,
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        if let Some(load) = &self.load {
+            load.in_flight.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Load {
+    // A failure counts as at least twice the usual latency, so a replica
+    // failing fast doesn't look like the quickest
+    fn observe(&self, success: bool, latency: Duration) {
+        let old = self.latency_micros.load(Ordering::Relaxed) as f64;
+        let mut sample = latency.as_micros() as f64;
+        if !success {
+            sample = sample.max(old * 2.0);
+        }
+        let new = match old > 0.0 {
+            true => old + SMOOTHING * (sample - old),
+            false => sample,
+        };
+        self.latency_micros
+            .store((new as u64).max(1), Ordering::Relaxed);
+    }
+}
+
+impl Pool {
+    fn members(&self) -> Arc<Members> {
+        self.members.read().unwrap().clone()
+    }
+}
+
+impl Members {
+    // A group keeps all of the traffic while its healthy share is at least
+    // the failover threshold. Below that, the shortfall spills over to the
+    // next group, proportionally to how unhealthy this one is.
+    fn choose_group(&self, failover_threshold: f64, ejected: &[bool]) -> usize {
+        if self.groups.len() == 1 {
+            return 0;
+        }
+
+        let mut roll: f64 = rand::thread_rng().gen();
+        for (g, range) in self.groups.iter().enumerate() {
+            let healthy = ejected[range.clone()].iter().filter(|e| !**e).count();
+            let fraction = healthy as f64 / range.len() as f64;
+            let share = if failover_threshold > 0.0 {
+                (fraction / failover_threshold).min(1.0)
+            } else if healthy > 0 {
+                1.0
+            } else {
+                0.0
+            };
+
+            if roll < share {
+                return g;
+            }
+            roll = (roll - share) / (1.0 - share);
+        }
+
+        // Nothing healthy anywhere: stay with the preferred group
+        0
+    }
+
+    // Bring back replicas whose cooldown has passed; returns which are still out
+    fn readmit(&self, upstream: &str) -> Vec<bool> {
+        let mut health = self.health.lock().unwrap();
+        let now = Instant::now();
+
+        health
+            .iter_mut()
+            .zip(&self.targets)
+            .map(|(h, target)| match h.ejected_until {
+                Some(until) if until <= now => {
+                    h.ejected_until = None;
+                    println!("Re-admitting {} to {}", target, upstream);
+                    metrics::set(
+                        "upstream_ejected",
+                        &[("upstream", upstream), ("target", target)],
+                        0.0,
+                    );
+                    events::publish(Event::UpstreamHealth {
+                        upstream: upstream.to_string(),
+                        target: target.clone(),
+                        healthy: true,
+                    });
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            })
+            .collect()
+    }
+}
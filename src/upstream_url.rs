@@ -0,0 +1,217 @@
+use crate::blocklist::percent_decode;
+use crate::config::{RouteConfig, VarSource};
+use crate::metrics;
+use actix_web::HttpRequest;
+use regex::Regex;
+
+// Upstream URLs with {name} placeholders, such as
+// https://{region}.gateway.internal, filled in per request from the
+// sources in the route's upstream_vars. Values are checked before they go
+// in, so a header can't turn the host into another one or climb out of
+// the path: one in the host has to be a plain label, one in the path can't
+// hold ?, # or a .. segment.
+
+// A placeholder's source, checked when the route is loaded
+#[derive(Debug, Clone)]
+pub struct Var {
+    name: String,
+    source: VarSource,
+    pattern: Option<Regex>,
+    default: Option<String>,
+}
+
+// What a placeholder may hold besides what its pattern allows
+fn fits(value: &str, in_host: bool) -> bool {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_.~".contains(c);
+    match in_host {
+        true => !value.is_empty() && value.chars().all(|c| safe(c) && c != '.' && c != '~'),
+        false => {
+            !value.is_empty()
+                && value.chars().all(|c| safe(c) || c == '/')
+                && !value.split('/').any(|segment| segment == "..")
+        }
+    }
+}
+
+// Everything upstream URLs of the route can hold placeholders in
+fn templates(route: &RouteConfig) -> impl Iterator<Item = &String> {
+    std::iter::once(&route.upstream)
+        .chain(route.regions.values())
+        .chain(route.languages.values())
+        .chain(route.blue_green.iter().flat_map(|b| b.groups.values()))
+        .chain(route.schedule.iter().filter_map(|r| r.upstream.as_ref()))
+        .chain(route.fallbacks.iter().filter_map(|f| f.upstream.as_ref()))
+        .filter(|upstream| upstream.contains('{'))
+}
+
+fn names(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+}
+
+// The route's placeholders and where each comes from. A placeholder not in
+// upstream_vars is taken from the path capture of the same name.
+pub fn compile(route: &RouteConfig, path: Option<&Regex>) -> Result<Vec<Var>, String> {
+    let captures: Vec<&str> = path
+        .iter()
+        .flat_map(|p| p.capture_names().flatten())
+        .collect();
+    let mut vars = Vec::new();
+    for (name, var) in &route.upstream_vars {
+        if matches!(var.source, VarSource::Path) && !captures.contains(&name.as_str()) {
+            return Err(format!(
+                "upstream_vars {} comes from the path, which doesn't capture it",
+                name
+            ));
+        }
+        let empty = match &var.source {
+            VarSource::Header { name } | VarSource::Cookie { name } | VarSource::Query { name } => {
+                name.is_empty()
+            }
+            VarSource::Path | VarSource::Region => false,
+        };
+        if empty {
+            return Err(format!("upstream_vars {} needs a name to read", name));
+        }
+        let pattern = match &var.pattern {
+            Some(pattern) => Some(
+                Regex::new(pattern)
+                    .map_err(|e| format!("upstream_vars {} has an invalid pattern: {}", name, e))?,
+            ),
+            None => None,
+        };
+        if let (Some(pattern), Some(default)) = (&pattern, &var.default) {
+            if !pattern.is_match(default) {
+                return Err(format!(
+                    "upstream_vars {} default {} doesn't match its pattern",
+                    name, default
+                ));
+            }
+        }
+        vars.push(Var {
+            name: name.clone(),
+            source: var.source.clone(),
+            pattern,
+            default: var.default.clone(),
+        });
+    }
+    for template in templates(route) {
+        if !template.starts_with("http://") && !template.starts_with("https://") {
+            return Err(format!(
+                "templated upstream {} must be an http(s) URL",
+                template
+            ));
+        }
+        for name in names(template) {
+            if vars.iter().any(|var| var.name == name) {
+                continue;
+            }
+            if !captures.contains(&name) {
+                return Err(format!(
+                    "upstream {} uses {{{}}}, which is neither in upstream_vars nor captured by the path",
+                    template, name
+                ));
+            }
+            vars.push(Var {
+                name: name.to_string(),
+                source: VarSource::Path,
+                pattern: None,
+                default: None,
+            });
+        }
+    }
+    Ok(vars)
+}
+
+// Whether any variable comes from the [geo] region
+pub fn uses_region(route: &RouteConfig) -> bool {
+    (route.compiled.upstream_vars)
+        .iter()
+        .any(|var| matches!(var.source, VarSource::Region))
+}
+
+fn value(
+    var: &Var,
+    route: &RouteConfig,
+    req: &HttpRequest,
+    region: Option<&str>,
+) -> Option<String> {
+    match &var.source {
+        VarSource::Header { name } => {
+            Some(req.headers().get(name)?.to_str().ok()?.trim().to_string())
+        }
+        VarSource::Cookie { name } => Some(req.cookie(name)?.value().to_string()),
+        VarSource::Query { name } => req.query_string().split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key) == *name).then(|| percent_decode(&value.replace('+', " ")))
+        }),
+        VarSource::Path => {
+            let captures = route.compiled.path.as_ref()?.captures(req.path())?;
+            Some(captures.name(&var.name)?.as_str().to_string())
+        }
+        VarSource::Region => region.map(str::to_string),
+    }
+}
+
+// Fill in a templated upstream. Returns the upstream and the path to send
+// there: the request's, unless the template's path has placeholders, in
+// which case the template is the whole URL. Err names the placeholder that
+// had no usable value.
+pub fn expand(
+    route: &RouteConfig,
+    req: &HttpRequest,
+    region: Option<&str>,
+    template: &str,
+    path: String,
+) -> Result<(String, String), String> {
+    // Where the authority ends; placeholders before it are in the host
+    let scheme = template.find("://").map_or(0, |i| i + 3);
+    let host_end = template[scheme..]
+        .find('/')
+        .map_or(template.len(), |i| scheme + i);
+
+    let mut out = String::new();
+    let mut rest = template;
+    let mut in_path = false;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+            break;
+        };
+        out.push_str(&rest[..open]);
+        let in_host = template.len() - rest.len() + open < host_end;
+        in_path |= !in_host;
+        let name = &rest[open + 1..close];
+        let var = (route.compiled.upstream_vars)
+            .iter()
+            .find(|var| var.name == name)
+            .ok_or_else(|| name.to_string())?;
+        let found = value(var, route, req, region).filter(|value| {
+            fits(value, in_host) && var.pattern.as_ref().is_none_or(|p| p.is_match(value))
+        });
+        let source = match (&found, &var.default) {
+            (Some(_), _) => "request",
+            (None, Some(_)) => "default",
+            (None, None) => "missing",
+        };
+        metrics::inc(
+            "upstream_template_values_total",
+            &[("route", &route.name), ("var", name), ("source", source)],
+        );
+        let value = found
+            .or_else(|| var.default.clone().filter(|value| fits(value, in_host)))
+            .ok_or_else(|| name.to_string())?;
+        out.push_str(&value);
+        rest = &rest[close + 1..];
+    }
+    out.push_str(rest);
+
+    if !in_path {
+        return Ok((out.trim_end_matches('/').to_string(), path));
+    }
+    // Split where the filled-in authority ends
+    let authority_end = out[scheme..].find('/').map_or(out.len(), |i| scheme + i);
+    let (upstream, path) = out.split_at(authority_end);
+    Ok((upstream.to_string(), path.to_string()))
+}
@@ -0,0 +1,185 @@
+use crate::config::{RouteConfig, VcrConfig, VcrMode};
+use crate::keys;
+use crate::metrics;
+use crate::middleware;
+use crate::proxy::AppState;
+use actix_web::body::BoxBody;
+use actix_web::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::HttpResponse;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::path::PathBuf;
+
+// Framing is redone when the response is served again, and Date should be
+// the time it's served
+const SKIPPED: [HeaderName; 4] = [
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::CONNECTION,
+    header::DATE,
+];
+
+// What's saved next to the body, as {signature}.json
+#[derive(Serialize, Deserialize)]
+struct Cassette {
+    method: String,
+    path: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+}
+
+// Saves upstream responses keyed by request signature, or serves them back
+// without touching the network
+pub struct Vcr {
+    config: VcrConfig,
+}
+
+impl Vcr {
+    pub fn new(config: VcrConfig) -> Result<Self, Error> {
+        if config.mode == VcrMode::Record {
+            std::fs::create_dir_all(&config.directory)
+                .map_err(|e| Error::other(format!("VCR directory {}: {}", config.directory, e)))?;
+        }
+        let mode = match config.mode {
+            VcrMode::Record => "recording to",
+            VcrMode::Replay => "replaying from",
+        };
+        println!("VCR {} {}", mode, config.directory);
+        Ok(Vcr { config })
+    }
+
+    pub fn applies(&self, route: &RouteConfig) -> bool {
+        self.config.routes.is_empty() || self.config.routes.contains(&route.name)
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.config.mode == VcrMode::Replay
+    }
+
+    // Hash of the method, upstream path and query, the matched headers and
+    // the body
+    pub fn signature(
+        &self,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> String {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(format!("{}\n{}\n", method, path).as_bytes());
+        for name in &self.config.match_headers {
+            for value in headers.get_all(name.as_str()) {
+                context.update(name.to_ascii_lowercase().as_bytes());
+                context.update(b":");
+                context.update(value.as_bytes());
+                context.update(b"\n");
+            }
+        }
+        context.update(body);
+        keys::hex(context.finish().as_ref())
+    }
+
+    fn files(&self, route: &RouteConfig, signature: &str) -> (PathBuf, PathBuf) {
+        let dir = PathBuf::from(&self.config.directory).join(&route.name);
+        (
+            dir.join(format!("{}.json", signature)),
+            dir.join(format!("{}.body", signature)),
+        )
+    }
+
+    // The recorded response for this request, or a 504 when there is none
+    pub async fn replay(
+        &self,
+        route: &RouteConfig,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> HttpResponse {
+        let signature = self.signature(method, path, headers, body);
+        let (meta, body) = self.files(route, &signature);
+        let cassette = tokio::fs::read(&meta)
+            .await
+            .ok()
+            .and_then(|raw| serde_json::from_slice::<Cassette>(&raw).ok());
+        let recorded = match (cassette, tokio::fs::read(&body).await) {
+            (Some(cassette), Ok(body)) => (cassette, body),
+            _ => {
+                println!(
+                    "VCR: no recording of {} {} on route {} ({})",
+                    method, path, route.name, signature
+                );
+                metrics::inc("vcr_total", &[("route", &route.name), ("result", "miss")]);
+                return HttpResponse::GatewayTimeout().body("No recording of this request");
+            }
+        };
+
+        metrics::inc("vcr_total", &[("route", &route.name), ("result", "hit")]);
+        let (cassette, body) = recorded;
+        let mut response =
+            HttpResponse::build(StatusCode::from_u16(cassette.status).unwrap_or(StatusCode::OK));
+        for (name, value) in &cassette.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                response.append_header((name, value));
+            }
+        }
+        response.body(body)
+    }
+
+    // Save the upstream's response and pass it on. The body is buffered, so
+    // streamed responses arrive all at once while recording, and one past
+    // the route's response limit or the memory budget is refused.
+    pub async fn record(
+        &self,
+        state: &AppState,
+        route: &RouteConfig,
+        method: &Method,
+        path: &str,
+        signature: &str,
+        response: HttpResponse,
+    ) -> HttpResponse {
+        let (head, body) = response.into_parts();
+        let (body, _reservation) =
+            match middleware::read_response(state, route, body, "recorded").await {
+                Ok(read) => read,
+                Err(response) => return *response,
+            };
+        let cassette = Cassette {
+            method: method.to_string(),
+            path: path.to_string(),
+            status: head.status().as_u16(),
+            headers: head
+                .headers()
+                .iter()
+                .filter(|(name, _)| !SKIPPED.contains(name))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+        };
+
+        let (meta, body_file) = self.files(route, signature);
+        let saved = async {
+            if let Some(dir) = meta.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(&body_file, &body).await?;
+            tokio::fs::write(&meta, serde_json::to_vec_pretty(&cassette)?).await
+        };
+        match saved.await {
+            Ok(()) => {
+                metrics::inc(
+                    "vcr_total",
+                    &[("route", &route.name), ("result", "recorded")],
+                );
+            }
+            Err(e) => eprintln!("VCR: can't save {}: {}", meta.display(), e),
+        }
+        head.set_body(BoxBody::new(body))
+    }
+}
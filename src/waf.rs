@@ -0,0 +1,164 @@
+use crate::blocklist::percent_decode;
+use crate::config::{WafConfig, WafMode};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use regex::Regex;
+use serde_json::Value;
+use std::io::Error;
+
+// The common shapes of SQL injection and XSS. They're meant to catch the
+// scanners and copy-pasted payloads, not a determined attacker; anything
+// they get wrong on a route can be switched off by name.
+const BUILTIN: &[(&str, &str)] = &[
+    ("sqli_union", r"(?i)\bunion\b[\s(]+(all\s+)?select\b"),
+    (
+        "sqli_tautology",
+        r#"(?i)['"]\s*(or|and)\s+['"]?\w+['"]?\s*=\s*['"]?\w+"#,
+    ),
+    ("sqli_comment", r#"(?i)['"]\s*(--|#|/\*)"#),
+    (
+        "sqli_stacked",
+        r"(?i);\s*(drop|delete|insert|update|alter|create|exec)\s",
+    ),
+    (
+        "sqli_delay",
+        r"(?i)\b(sleep|benchmark|pg_sleep)\s*\(|\bwaitfor\s+delay\b",
+    ),
+    ("xss_script", r"(?i)<\s*script\b"),
+    ("xss_handler", r"(?i)<[^>]*\bon[a-z]+\s*="),
+    ("xss_uri", r"(?i)\b(javascript|vbscript)\s*:"),
+    ("xss_embed", r"(?i)<\s*(iframe|object|embed)\b"),
+];
+
+struct Rule {
+    name: String,
+    pattern: Regex,
+}
+
+// One waf middleware's rule set
+pub struct Waf {
+    mode: WafMode,
+    rules: Vec<Rule>,
+    max_body_bytes: usize,
+}
+
+// A rule that matched, and whether in the query or the body
+pub struct Match<'a> {
+    pub rule: &'a str,
+    pub location: &'static str,
+}
+
+// Keys and values of a query string or form body, decoded
+fn form_fields(form: &str) -> Vec<String> {
+    form.split('&')
+        .flat_map(|pair| pair.splitn(2, '='))
+        .filter(|part| !part.is_empty())
+        .map(|part| percent_decode(&part.replace('+', " ")))
+        .collect()
+}
+
+// Every key and string in a JSON document
+fn json_fields(value: &Value, out: &mut Vec<String>) {
+    match value {
+        Value::String(s) => out.push(s.clone()),
+        Value::Array(items) => items.iter().for_each(|item| json_fields(item, out)),
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                out.push(key.clone());
+                json_fields(value, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Waf {
+    pub fn load(name: &str, config: &WafConfig) -> Result<Self, Error> {
+        let mut rules = Vec::new();
+        if config.builtin_rules {
+            for (rule, pattern) in BUILTIN {
+                if !config.disabled_rules.iter().any(|d| d == rule) {
+                    rules.push(Rule {
+                        name: rule.to_string(),
+                        pattern: Regex::new(pattern).map_err(Error::other)?,
+                    });
+                }
+            }
+        }
+        for (i, rule) in config.rules.iter().enumerate() {
+            let rule_name = match rule.name.is_empty() {
+                true => format!("rule{}", i + 1),
+                false => rule.name.clone(),
+            };
+            let pattern = Regex::new(&rule.pattern).map_err(|e| {
+                Error::other(format!(
+                    "WAF middleware {} rule {} is invalid: {}",
+                    name, rule_name, e
+                ))
+            })?;
+            rules.push(Rule {
+                name: rule_name,
+                pattern,
+            });
+        }
+        Ok(Waf {
+            mode: config.mode,
+            rules,
+            max_body_bytes: config.max_body_bytes,
+        })
+    }
+
+    pub fn blocks(&self) -> bool {
+        self.mode == WafMode::Block
+    }
+
+    // Whether the body is small enough to be read and scanned
+    pub fn scans_body(&self, req: &HttpRequest) -> bool {
+        let declared = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        declared.is_some_and(|len| len > 0 && len <= self.max_body_bytes)
+    }
+
+    fn find(&self, fields: &[String]) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| fields.iter().any(|field| rule.pattern.is_match(field)))
+            .map(|rule| rule.name.as_str())
+    }
+
+    // The first rule the query string or the body (when it was read, and is
+    // within the size limit) trips
+    pub fn scan(&self, req: &HttpRequest, body: &[u8]) -> Option<Match<'_>> {
+        if let Some(rule) = self.find(&form_fields(req.query_string())) {
+            return Some(Match {
+                rule,
+                location: "query",
+            });
+        }
+        if body.is_empty() || body.len() > self.max_body_bytes {
+            return None;
+        }
+        let media = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let text = String::from_utf8_lossy(body);
+        let fields = if media.starts_with("application/x-www-form-urlencoded") {
+            form_fields(&text)
+        } else if let Ok(json) = serde_json::from_slice::<Value>(body) {
+            let mut fields = Vec::new();
+            json_fields(&json, &mut fields);
+            fields
+        } else {
+            vec![text.into_owned()]
+        };
+        self.find(&fields).map(|rule| Match {
+            rule,
+            location: "body",
+        })
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::{Config, RouteConfig, WarmupConfig};
+use crate::metrics;
+use crate::upstream::Upstreams;
+use futures_util::future::join_all;
+use reqwest::{Client, Url};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+// Every target the HTTP routes can send to, with the upstream it belongs to.
+// gRPC routes go through their own HTTP/2 client, so they aren't warmed here.
+pub fn targets(config: &Config, routes: &[RouteConfig]) -> Vec<(String, String)> {
+    let mut targets = BTreeSet::new();
+    for route in routes.iter().filter(|r| !r.grpc) {
+        let scheduled = route
+            .schedule
+            .iter()
+            .filter_map(|rule| rule.upstream.as_ref());
+        let upstreams = std::iter::once(&route.upstream)
+            .chain(route.regions.values())
+            .chain(route.languages.values())
+            .chain(scheduled)
+            .chain(route.blue_green.iter().flat_map(|b| b.groups.values()))
+            .chain(route.fallbacks.iter().filter_map(|f| f.upstream.as_ref()));
+        for upstream in upstreams {
+            match config.upstreams.get(upstream) {
+                Some(pool) => {
+                    let groups = std::iter::once(&pool.targets).chain(&pool.priority_groups);
+                    for target in groups.flatten() {
+                        let target = target.trim_end_matches('/').to_string();
+                        targets.insert((upstream.clone(), target));
+                    }
+                }
+                None => {
+                    targets.insert((upstream.clone(), upstream.clone()));
+                }
+            }
+        }
+    }
+    targets.into_iter().collect()
+}
+
+// Resolve the target's host and open the connections, TLS handshakes
+// included. Returns how many connected.
+async fn warm(client: &Client, config: &WarmupConfig, target: &str) -> usize {
+    let url = match Url::parse(target).and_then(|url| url.join(&config.path)) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("Warm-up: invalid target {}: {}", target, e);
+            return 0;
+        }
+    };
+    let timeout = Duration::from_millis(config.timeout_ms);
+    if let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) {
+        let lookup = tokio::time::timeout(timeout, tokio::net::lookup_host((host, port))).await;
+        if !matches!(lookup, Ok(Ok(_))) {
+            eprintln!("Warm-up: can't resolve {}", host);
+            return 0;
+        }
+    }
+
+    // Concurrent, so each request needs a connection of its own
+    let probes =
+        (0..config.connections.max(1)).map(|_| client.head(url.clone()).timeout(timeout).send());
+    let mut connected = 0;
+    for result in join_all(probes).await {
+        match result {
+            Ok(_) => connected += 1,
+            Err(e) => eprintln!("Warm-up: {} failed: {}", target, e),
+        }
+    }
+    connected
+}
+
+// Warm every target at once, each through its upstream's client; readiness
+// waits for this to finish
+pub async fn run(
+    upstreams: &Upstreams,
+    client: &Client,
+    config: &WarmupConfig,
+    targets: &[(String, String)],
+) {
+    let started = Instant::now();
+    let warming = targets
+        .iter()
+        .map(|(upstream, target)| warm(upstreams.client(upstream, client), config, target));
+    let results = join_all(warming).await;
+    for ((_, target), connected) in targets.iter().zip(&results) {
+        let result = if *connected > 0 { "ok" } else { "failed" };
+        metrics::inc(
+            "upstream_warmup_total",
+            &[("target", target), ("result", result)],
+        );
+        println!("Warm-up: {} connection(s) to {}", connected, target);
+    }
+    println!(
+        "Warm-up of {} upstream target(s) finished in {}ms",
+        targets.len(),
+        started.elapsed().as_millis()
+    );
+}
@@ -0,0 +1,214 @@
+use crate::config::WasmFilterConfig;
+use crate::middleware::Message;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use std::io::Error;
+use std::sync::OnceLock;
+use wasmtime::{Caller, Engine, InstancePre, Linker, Memory, Module, Store, StoreLimits};
+
+// Host ABI for filters. Everything is imported from the "proxy" module and
+// passed as (pointer, length) into the filter's exported `memory`. Getters
+// take an output buffer (out, cap) and return the full length, or -1 when
+// the value is absent; nothing is written if it doesn't fit, so the filter
+// can retry with a bigger buffer.
+//
+//   get_method(out, cap) -> len
+//   get_path(out, cap) -> len
+//   set_path(ptr, len)                        request only
+//   get_header(name, name_len, out, cap) -> len
+//   set_header(name, name_len, value, value_len)
+//   remove_header(name, name_len)
+//   get_body(out, cap) -> len
+//   set_body(ptr, len)
+//   get_status() -> status                    0 during on_request
+//   log(ptr, len)
+//
+// A filter exports `on_request` and/or `on_response`, both () -> i32.
+// Returning 0 carries on. Anything else from on_request is sent straight to
+// the client as the status, with the body the filter set; from on_response
+// it replaces the upstream status.
+
+struct Context {
+    message: Message,
+    limits: StoreLimits,
+}
+
+pub struct WasmFilter {
+    pre: InstancePre<Context>,
+    fuel: u64,
+    max_memory_bytes: usize,
+}
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("WASM engine")
+    })
+}
+
+fn memory(caller: &mut Caller<'_, Context>) -> Option<Memory> {
+    caller.get_export("memory")?.into_memory()
+}
+
+fn read(caller: &mut Caller<'_, Context>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = memory(caller)?;
+    let mut buf = vec![0; usize::try_from(len).ok()?];
+    memory
+        .read(caller, usize::try_from(ptr).ok()?, &mut buf)
+        .ok()?;
+    Some(buf)
+}
+
+fn write(caller: &mut Caller<'_, Context>, data: Option<Vec<u8>>, out: i32, cap: i32) -> i32 {
+    let data = match data {
+        Some(data) => data,
+        None => return -1,
+    };
+    if data.len() <= cap.max(0) as usize {
+        if let (Some(memory), Ok(out)) = (memory(caller), usize::try_from(out)) {
+            if memory.write(&mut *caller, out, &data).is_err() {
+                return -1;
+            }
+        }
+    }
+    data.len() as i32
+}
+
+fn header_name(caller: &mut Caller<'_, Context>, ptr: i32, len: i32) -> Option<HeaderName> {
+    HeaderName::from_bytes(&read(caller, ptr, len)?).ok()
+}
+
+fn linker() -> Result<Linker<Context>, wasmtime::Error> {
+    let mut linker = Linker::new(engine());
+
+    linker.func_wrap(
+        "proxy",
+        "get_method",
+        |mut caller: Caller<'_, Context>, out: i32, cap: i32| {
+            let method = caller.data().message.method.clone().into_bytes();
+            write(&mut caller, Some(method), out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_path",
+        |mut caller: Caller<'_, Context>, out: i32, cap: i32| {
+            let path = caller.data().message.path.clone().into_bytes();
+            write(&mut caller, Some(path), out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_path",
+        |mut caller: Caller<'_, Context>, ptr: i32, len: i32| {
+            let path = read(&mut caller, ptr, len).and_then(|p| String::from_utf8(p).ok());
+            if let Some(path) = path.filter(|p| p.starts_with('/')) {
+                caller.data_mut().message.path = path;
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_header",
+        |mut caller: Caller<'_, Context>, name: i32, name_len: i32, out: i32, cap: i32| {
+            let value = header_name(&mut caller, name, name_len).and_then(|name| {
+                let headers = &caller.data().message.headers;
+                headers.get(name).map(|v| v.as_bytes().to_vec())
+            });
+            write(&mut caller, value, out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_header",
+        |mut caller: Caller<'_, Context>, name: i32, name_len: i32, value: i32, value_len: i32| {
+            let name = header_name(&mut caller, name, name_len);
+            let value =
+                read(&mut caller, value, value_len).and_then(|v| HeaderValue::from_bytes(&v).ok());
+            if let (Some(name), Some(value)) = (name, value) {
+                caller.data_mut().message.headers.insert(name, value);
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "remove_header",
+        |mut caller: Caller<'_, Context>, name: i32, name_len: i32| {
+            if let Some(name) = header_name(&mut caller, name, name_len) {
+                caller.data_mut().message.headers.remove(name);
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "get_body",
+        |mut caller: Caller<'_, Context>, out: i32, cap: i32| {
+            let body = caller.data().message.body.clone();
+            write(&mut caller, Some(body), out, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "proxy",
+        "set_body",
+        |mut caller: Caller<'_, Context>, ptr: i32, len: i32| {
+            if let Some(body) = read(&mut caller, ptr, len) {
+                caller.data_mut().message.body = body;
+            }
+        },
+    )?;
+    linker.func_wrap("proxy", "get_status", |caller: Caller<'_, Context>| {
+        caller.data().message.status as i32
+    })?;
+    linker.func_wrap(
+        "proxy",
+        "log",
+        |mut caller: Caller<'_, Context>, ptr: i32, len: i32| {
+            if let Some(line) = read(&mut caller, ptr, len) {
+                println!("WASM filter: {}", String::from_utf8_lossy(&line));
+            }
+        },
+    )?;
+
+    Ok(linker)
+}
+
+impl WasmFilter {
+    pub fn load(name: &str, config: &WasmFilterConfig) -> Result<Self, Error> {
+        let invalid = |e: wasmtime::Error| {
+            Error::other(format!("WASM filter {} ({}): {}", name, config.path, e))
+        };
+
+        // Accepts both binary modules and the text format
+        let module = Module::from_file(engine(), &config.path).map_err(invalid)?;
+        let pre = linker()
+            .and_then(|linker| linker.instantiate_pre(&module))
+            .map_err(invalid)?;
+
+        Ok(WasmFilter {
+            pre,
+            fuel: config.fuel,
+            max_memory_bytes: config.max_memory_bytes,
+        })
+    }
+
+    // Run one hook in a fresh instance. Returns the (possibly modified)
+    // message and the hook's result; filters without the hook return 0.
+    pub fn call(&self, hook: &str, message: Message) -> Result<(Message, i32), wasmtime::Error> {
+        let limits = wasmtime::StoreLimitsBuilder::new()
+            .memory_size(self.max_memory_bytes)
+            .instances(1)
+            .build();
+        let mut store = Store::new(engine(), Context { message, limits });
+        store.limiter(|ctx| &mut ctx.limits);
+        store.set_fuel(self.fuel)?;
+
+        let instance = self.pre.instantiate(&mut store)?;
+        let result = match instance.get_typed_func::<(), i32>(&mut store, hook) {
+            Ok(hook) => hook.call(&mut store, ())?,
+            Err(_) => 0,
+        };
+
+        Ok((store.into_data().message, result))
+    }
+}
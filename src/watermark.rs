@@ -0,0 +1,222 @@
+use crate::auth::Claims;
+use crate::client_ip;
+use crate::config::{RouteConfig, WatermarkConfig};
+use crate::metrics;
+use actix_web::body::{self, BodySize, MessageBody};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
+use ring::digest;
+use serde_json::Value;
+use std::env;
+use std::io::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Leaves JSON bodies bigger than this without the field
+const MAX_JSON_BYTES: usize = 1024 * 1024;
+// Leading byte of a mark, so the format can change later
+const VERSION: u8 = 1;
+
+// Marks responses with an opaque token naming who they were served to. The
+// user, time, route and client address are sealed with an AEAD cipher under
+// the configured secret: the mark reveals nothing without it, can't be
+// forged, and `watermark decode` opens it again.
+pub struct Watermarker {
+    key: LessSafeKey,
+    header: HeaderName,
+    json_field: Option<String>,
+    user_claim: String,
+}
+
+// Who a response was marked for
+pub struct Origin {
+    pub user: String,
+    pub issued_ms: u64,
+    pub route: String,
+    pub client_ip: String,
+}
+
+// Marks made for the request by each watermark middleware, by name
+#[derive(Default)]
+struct Marks(Vec<(String, String)>);
+
+impl Watermarker {
+    pub fn load(name: &str, config: &WatermarkConfig) -> Result<Self, Error> {
+        let secret = match config.secret.strip_prefix("env:") {
+            Some(var) => env::var(var).map_err(|_| {
+                Error::other(format!(
+                    "Watermark middleware {} needs the {} environment variable",
+                    name, var
+                ))
+            })?,
+            None => config.secret.clone(),
+        };
+        if secret.is_empty() {
+            return Err(Error::other(format!(
+                "Watermark middleware {} needs a secret",
+                name
+            )));
+        }
+        let digest = digest::digest(&digest::SHA256, secret.as_bytes());
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, digest.as_ref())
+            .map_err(|_| Error::other("Watermark key"))?;
+        let header = HeaderName::from_bytes(config.header.as_bytes()).map_err(|_| {
+            Error::other(format!(
+                "Watermark middleware {} has an invalid header {}",
+                name, config.header
+            ))
+        })?;
+        Ok(Watermarker {
+            key: LessSafeKey::new(key),
+            header,
+            json_field: config.json_field.clone(),
+            user_claim: config.user_claim.clone(),
+        })
+    }
+
+    pub fn seal(&self, origin: &Origin) -> String {
+        let mut plain = serde_json::to_vec(&(
+            &origin.user,
+            origin.issued_ms,
+            &origin.route,
+            &origin.client_ip,
+        ))
+        .unwrap_or_default();
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        // Can only fail for inputs far bigger than these
+        let sealed = self.key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from([VERSION]),
+            &mut plain,
+        );
+        if sealed.is_err() {
+            return String::new();
+        }
+        let mut token = vec![VERSION];
+        token.extend_from_slice(&nonce);
+        token.extend_from_slice(&plain);
+        URL_SAFE_NO_PAD.encode(token)
+    }
+
+    // None unless the mark was sealed under this middleware's secret
+    pub fn open(&self, token: &str) -> Option<Origin> {
+        let token = URL_SAFE_NO_PAD.decode(token.trim()).ok()?;
+        let (&version, rest) = token.split_first()?;
+        if version != VERSION || rest.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from([VERSION]), &mut sealed)
+            .ok()?;
+        let (user, issued_ms, route, client_ip) =
+            serde_json::from_slice::<(String, u64, String, String)>(plain).ok()?;
+        Some(Origin {
+            user,
+            issued_ms,
+            route,
+            client_ip,
+        })
+    }
+
+    // Make the request's mark once the chain has its claims; it goes on the
+    // response in apply()
+    pub fn mark(
+        &self,
+        name: &str,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        claims: Option<&Claims>,
+    ) {
+        let user = match claims.and_then(|claims| claims.get(&self.user_claim)) {
+            Some(Value::String(user)) => user.clone(),
+            Some(other) => other.to_string(),
+            None => "-".to_string(),
+        };
+        let issued_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let origin = Origin {
+            user,
+            issued_ms,
+            route: route.name.clone(),
+            client_ip: client_ip::resolved(req).unwrap_or_else(|| "-".to_string()),
+        };
+        let token = self.seal(&origin);
+        let mut extensions = req.extensions_mut();
+        if !extensions.contains::<Marks>() {
+            extensions.insert(Marks::default());
+        }
+        if let Some(marks) = extensions.get_mut::<Marks>() {
+            marks.0.push((name.to_string(), token));
+        }
+    }
+
+    pub async fn apply(
+        &self,
+        name: &str,
+        req: &HttpRequest,
+        route: &RouteConfig,
+        mut response: HttpResponse,
+    ) -> HttpResponse {
+        let token = req.extensions().get::<Marks>().and_then(|marks| {
+            marks
+                .0
+                .iter()
+                .find(|(marked_by, _)| marked_by == name)
+                .map(|(_, token)| token.clone())
+        });
+        let Some(token) = token else {
+            return response;
+        };
+        if let Ok(value) = HeaderValue::from_str(&token) {
+            response.headers_mut().insert(self.header.clone(), value);
+        }
+        metrics::inc("watermarks_total", &[("route", &route.name)]);
+        match &self.json_field {
+            Some(field) => insert_field(response, field, token).await,
+            None => response,
+        }
+    }
+}
+
+// The mark as a field of a JSON object body. Streamed, compressed and large
+// bodies keep only the header.
+async fn insert_field(response: HttpResponse, field: &str, token: String) -> HttpResponse {
+    let json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|media| {
+            let media = media.trim();
+            media.eq_ignore_ascii_case("application/json") || media.ends_with("+json")
+        });
+    let encoded = response.headers().contains_key(header::CONTENT_ENCODING);
+    let small =
+        matches!(response.body().size(), BodySize::Sized(n) if n as usize <= MAX_JSON_BYTES);
+    if !json || encoded || !small {
+        return response;
+    }
+    let (head, body) = response.into_parts();
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => return HttpResponse::BadGateway().body("Bad gateway"),
+    };
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(mut fields)) => {
+            fields.insert(field.to_string(), Value::String(token));
+            let body = serde_json::to_vec(&fields).unwrap_or_else(|_| bytes.to_vec());
+            head.set_body(body).map_into_boxed_body()
+        }
+        _ => head.set_body(bytes).map_into_boxed_body(),
+    }
+}
@@ -0,0 +1,291 @@
+use ring::digest;
+use time::{Date, Month, PrimitiveDateTime, Time};
+
+// Just enough DER to read a certificate's expiry and OCSP responder, ask
+// the responder about it and check the answer. Anything unexpected in the
+// input gives None rather than a guess.
+
+pub const SEQUENCE: u8 = 0x30;
+pub const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+pub const ENUMERATED: u8 = 0x0a;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+// id-pe-authorityInfoAccess and id-ad-ocsp
+const AUTHORITY_INFO_ACCESS: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+const AD_OCSP: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+// SHA-1, which the CertID of an OCSP request is still normally hashed with
+const SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+// What the gateway needs to know about a certificate
+pub struct Certificate {
+    serial: Vec<u8>,
+    // The whole encoding of the issuer Name, as that is what gets hashed
+    issuer: Vec<u8>,
+    subject_public_key: Vec<u8>,
+    // The whole SubjectPublicKeyInfo, as key pins hash it
+    pub subject_public_key_info: Vec<u8>,
+    pub not_after: i64,
+    pub ocsp_url: Option<String>,
+}
+
+// A responder's answer about one certificate
+pub enum OcspStatus {
+    Good { next_update: Option<i64> },
+    Revoked,
+    Unknown,
+}
+
+// One element: its tag, its contents and whatever follows it, along with
+// the element's own encoding
+pub struct Element<'a> {
+    pub tag: u8,
+    pub contents: &'a [u8],
+    pub whole: &'a [u8],
+}
+
+// Also used for LDAP, whose BER only ever has definite lengths
+pub fn element(input: &[u8]) -> Option<(Element<'_>, &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        n if n < 0x80 => (n as usize, rest),
+        n => {
+            let count = (n & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, &b| (len << 8) | b as usize);
+            (len, &rest[count..])
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = input.len() - rest.len();
+    Some((
+        Element {
+            tag,
+            contents: &rest[..len],
+            whole: &input[..header + len],
+        },
+        &rest[len..],
+    ))
+}
+
+// The next element, which must have the given tag
+pub fn expect(input: &[u8], tag: u8) -> Option<(Element<'_>, &[u8])> {
+    element(input).filter(|(element, _)| element.tag == tag)
+}
+
+// Every element of a SEQUENCE's contents
+pub fn elements(mut input: &[u8]) -> Option<Vec<Element<'_>>> {
+    let mut out = Vec::new();
+    while !input.is_empty() {
+        let (element, rest) = element(input)?;
+        out.push(element);
+        input = rest;
+    }
+    Some(out)
+}
+
+pub fn encode(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+// Seconds since the epoch of a UTCTime or GeneralizedTime in UTC
+fn timestamp(element: &Element) -> Option<i64> {
+    let text = std::str::from_utf8(element.contents)
+        .ok()?
+        .strip_suffix('Z')?;
+    let text = match element.tag {
+        // Two-digit years, 50 and up in the 1900s
+        UTC_TIME if text.len() == 12 => {
+            let century = if text[..2].parse::<u8>().ok()? >= 50 {
+                "19"
+            } else {
+                "20"
+            };
+            format!("{}{}", century, text)
+        }
+        GENERALIZED_TIME if text.len() == 14 => text.to_string(),
+        _ => return None,
+    };
+    let field = |range: std::ops::Range<usize>| text.get(range)?.parse::<u32>().ok();
+    let date = Date::from_calendar_date(
+        field(0..4)? as i32,
+        Month::try_from(field(4..6)? as u8).ok()?,
+        field(6..8)? as u8,
+    )
+    .ok()?;
+    let time = Time::from_hms(
+        field(8..10)? as u8,
+        field(10..12)? as u8,
+        field(12..14)? as u8,
+    )
+    .ok()?;
+    Some(
+        PrimitiveDateTime::new(date, time)
+            .assume_utc()
+            .unix_timestamp(),
+    )
+}
+
+// The OCSP responder URL from an authorityInfoAccess extension's value
+fn ocsp_url(value: &[u8]) -> Option<String> {
+    let (access, _) = expect(value, SEQUENCE)?;
+    elements(access.contents)?
+        .into_iter()
+        .find_map(|description| {
+            let (method, rest) = expect(description.contents, OID)?;
+            // accessLocation is a GeneralName; [6] is a URI
+            let (location, _) = element(rest)?;
+            (method.contents == AD_OCSP && location.tag == 0x86)
+                .then(|| String::from_utf8(location.contents.to_vec()).ok())
+                .flatten()
+        })
+}
+
+pub fn parse(der: &[u8]) -> Option<Certificate> {
+    let (certificate, _) = expect(der, SEQUENCE)?;
+    let (tbs, _) = expect(certificate.contents, SEQUENCE)?;
+    let mut fields = elements(tbs.contents)?.into_iter().peekable();
+    // [0] version, absent for v1
+    fields.next_if(|field| field.tag == 0xa0);
+    let serial = fields.next().filter(|f| f.tag == INTEGER)?;
+    let _signature = fields.next()?;
+    let issuer = fields.next().filter(|f| f.tag == SEQUENCE)?;
+    let validity = fields.next().filter(|f| f.tag == SEQUENCE)?;
+    let _subject = fields.next()?;
+    let key_info = fields.next().filter(|f| f.tag == SEQUENCE)?;
+
+    let (_, rest) = element(validity.contents)?;
+    let (not_after, _) = element(rest)?;
+    let (_, rest) = expect(key_info.contents, SEQUENCE)?;
+    let (key, _) = expect(rest, BIT_STRING)?;
+    // [3] extensions
+    let ocsp_url = fields.find(|f| f.tag == 0xa3).and_then(|extensions| {
+        let (extensions, _) = expect(extensions.contents, SEQUENCE)?;
+        elements(extensions.contents)?
+            .into_iter()
+            .find_map(|extension| {
+                let (id, rest) = expect(extension.contents, OID)?;
+                if id.contents != AUTHORITY_INFO_ACCESS {
+                    return None;
+                }
+                // The critical flag is optional
+                let (value, _) = element(rest)?;
+                let value = match value.tag {
+                    OCTET_STRING => value,
+                    _ => expect(element(rest)?.1, OCTET_STRING)?.0,
+                };
+                ocsp_url(value.contents)
+            })
+    });
+
+    Some(Certificate {
+        serial: serial.contents.to_vec(),
+        issuer: issuer.whole.to_vec(),
+        // Less the unused-bits byte
+        subject_public_key: key.contents.get(1..)?.to_vec(),
+        subject_public_key_info: key_info.whole.to_vec(),
+        not_after: timestamp(&not_after)?,
+        ocsp_url,
+    })
+}
+
+// The CertID both the request and the response name the certificate by
+fn cert_id(certificate: &Certificate, issuer: &Certificate) -> Vec<u8> {
+    let sha1 = |data: &[u8]| {
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data)
+            .as_ref()
+            .to_vec()
+    };
+    let algorithm = [encode(OID, SHA1), vec![0x05, 0x00]].concat();
+    encode(
+        SEQUENCE,
+        &[
+            encode(SEQUENCE, &algorithm),
+            encode(OCTET_STRING, &sha1(&certificate.issuer)),
+            encode(OCTET_STRING, &sha1(&issuer.subject_public_key)),
+            encode(INTEGER, &certificate.serial),
+        ]
+        .concat(),
+    )
+}
+
+// An OCSPRequest for the certificate, without a nonce so responders can
+// answer from their caches
+pub fn ocsp_request(certificate: &Certificate, issuer: &Certificate) -> Vec<u8> {
+    let request = encode(SEQUENCE, &cert_id(certificate, issuer));
+    let request_list = encode(SEQUENCE, &request);
+    let tbs_request = encode(SEQUENCE, &request_list);
+    encode(SEQUENCE, &tbs_request)
+}
+
+// What the responder said about the certificate, if the response is a
+// successful basic response that answers for it. Its signature isn't
+// checked here; clients check the staple themselves.
+pub fn ocsp_status(
+    response: &[u8],
+    certificate: &Certificate,
+    issuer: &Certificate,
+) -> Option<OcspStatus> {
+    let (response, _) = expect(response, SEQUENCE)?;
+    let (status, rest) = expect(response.contents, ENUMERATED)?;
+    if status.contents != [0] {
+        return None;
+    }
+    // [0] responseBytes: type and the BasicOCSPResponse
+    let (bytes, _) = expect(rest, 0xa0)?;
+    let (bytes, _) = expect(bytes.contents, SEQUENCE)?;
+    let (_, rest) = expect(bytes.contents, OID)?;
+    let (basic, _) = expect(rest, OCTET_STRING)?;
+    let (basic, _) = expect(basic.contents, SEQUENCE)?;
+    let (data, _) = expect(basic.contents, SEQUENCE)?;
+    let mut fields = elements(data.contents)?.into_iter().peekable();
+    fields.next_if(|field| field.tag == 0xa0);
+    let _responder = fields.next()?;
+    let _produced_at = fields.next().filter(|f| f.tag == GENERALIZED_TIME)?;
+    let responses = fields.next().filter(|f| f.tag == SEQUENCE)?;
+
+    let wanted = cert_id(certificate, issuer);
+    elements(responses.contents)?
+        .into_iter()
+        .find_map(|single| {
+            let mut parts = elements(single.contents)?.into_iter();
+            if parts.next()?.whole != wanted.as_slice() {
+                return None;
+            }
+            let status = parts.next()?;
+            let _this_update = parts.next()?;
+            let next_update = parts
+                .next()
+                .filter(|part| part.tag == 0xa0)
+                .and_then(|part| timestamp(&element(part.contents)?.0));
+            Some(match status.tag {
+                0x80 => OcspStatus::Good { next_update },
+                0xa1 => OcspStatus::Revoked,
+                _ => OcspStatus::Unknown,
+            })
+        })
+}